@@ -1,5 +1,7 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use matrixon::service::timeline::{self, Direction};
 use matrixon::Matrixon;
+use serde_json::json;
 
 fn benchmark_matrixon_operations(c: &mut Criterion) {
     c.bench_function("matrixon_initialization", |b| {
@@ -10,5 +12,36 @@ fn benchmark_matrixon_operations(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, benchmark_matrixon_operations);
+/// Serializing a page of events from parsed values, as before events kept
+/// their serialization, against copying the stored JSON
+fn benchmark_event_serialization(c: &mut Criterion) {
+    let room_id = "!bench:matrixon.local";
+    let service = timeline::Service::new();
+    for i in 0..100 {
+        service.append_event(
+            room_id,
+            "@bench:matrixon.local",
+            "m.room.message",
+            None,
+            json!({ "msgtype": "m.text", "body": format!("message {} {}", i, "lorem ipsum ".repeat(20)) }),
+        );
+    }
+
+    let mut group = c.benchmark_group("event_serialization");
+    group.bench_function("reserialize_values", |b| {
+        b.iter(|| {
+            let (events, _) = service.paginate(room_id, None, Direction::Backward, 100);
+            black_box(serde_json::to_vec(&events).unwrap())
+        })
+    });
+    group.bench_function("copy_serialized", |b| {
+        b.iter(|| {
+            let (events, _) = service.paginate_serialized(room_id, None, Direction::Backward, 100);
+            black_box(serde_json::to_vec(&events).unwrap())
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, benchmark_matrixon_operations, benchmark_event_serialization);
 criterion_main!(benches); 
@@ -69,9 +69,16 @@ impl DatabaseBackup {
             let db_config = DatabaseConfig {
                 url: config.database_url.clone(),
                 max_connections: 5,
+                min_connections: 1,
+                target_acquire_wait_ms: 20,
                 connection_timeout: std::time::Duration::from_secs(30).as_secs(),
                 min_idle: Some(1),
                 max_lifetime: Some(std::time::Duration::from_secs(300).as_secs()),
+                replica_urls: Vec::new(),
+                max_replica_lag: 10,
+                shard_urls: Vec::new(),
+                statement_cache_capacity: 100,
+                slow_query_threshold_ms: 500,
             };
             let pool = DatabasePool::new(&db_config).await
                 .map_err(|e| BackupError::database(format!("Failed to get DB pool: {}", e)))?;
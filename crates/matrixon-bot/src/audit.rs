@@ -0,0 +1,164 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Bot Command Audit Log
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-03-19
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Audit log of the commands the bot executes: who ran which command with
+//   which arguments in which room, whether it succeeded, its response and
+//   how long it took. Entries are stored in the database so abuse in shared
+//   rooms can be investigated later; admins read them with `!history` and
+//   entries older than the configured retention are pruned daily.
+//
+// =============================================================================
+
+use std::time::Duration;
+
+use chrono::Utc;
+use matrixon_core::error::Result;
+use matrixon_db::{BotAuditRepo, BotCommandRecord};
+use tracing::{debug, warn};
+
+use crate::config::AuditConfig;
+
+/// Entries `!history` lists unless asked for another number
+pub const DEFAULT_HISTORY_LIMIT: i64 = 20;
+/// Most entries `!history` lists
+pub const MAX_HISTORY_LIMIT: i64 = 100;
+/// Longest response stored per entry, in characters
+const MAX_RESULT_LEN: usize = 500;
+
+/// Audit log of executed commands
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    repo: BotAuditRepo,
+    config: AuditConfig,
+}
+
+impl AuditLog {
+    pub fn new(repo: BotAuditRepo, config: AuditConfig) -> Self {
+        Self { repo, config }
+    }
+
+    /// Log an executed command. Failing to log never fails the command.
+    pub async fn record(&self, user_id: &str, room_id: &str, command: &str, args: &str, result: &Result<String>, duration: Duration) {
+        if !self.config.enabled {
+            return;
+        }
+        let (success, result) = match result {
+            Ok(response) => (true, response.clone()),
+            Err(e) => (false, e.to_string()),
+        };
+        let record = BotCommandRecord {
+            user_id: user_id.to_owned(),
+            room_id: room_id.to_owned(),
+            command: command.to_owned(),
+            args: args.to_owned(),
+            success,
+            result: result.chars().take(MAX_RESULT_LEN).collect(),
+            duration_ms: duration.as_millis() as i64,
+            executed_at: Utc::now(),
+        };
+        if let Err(e) = self.repo.record(&record).await {
+            warn!("⚠️ Could not log command {} of {}: {}", command, user_id, e);
+        }
+    }
+
+    /// `!history [limit] [user]`: the latest commands executed in a room
+    pub async fn history(&self, room_id: &str, args: &str) -> Result<String> {
+        let (limit, user_id) = parse_history_args(args);
+        let records = self.repo.history(room_id, user_id, limit).await?;
+        Ok(format_history(&records))
+    }
+
+    /// Delete entries older than the retention, daily, for as long as the
+    /// bot runs
+    pub fn spawn_pruner(&self) -> tokio::task::JoinHandle<()> {
+        let log = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(24 * 3600));
+            loop {
+                interval.tick().await;
+                let before = Utc::now() - chrono::Duration::days(log.config.retention_days.into());
+                match log.repo.prune(before).await {
+                    Ok(pruned) => debug!("Pruned {} audit log entries before {}", pruned, before),
+                    Err(e) => warn!("⚠️ Could not prune the audit log: {}", e),
+                }
+            }
+        })
+    }
+}
+
+/// Limit and user of `!history [limit] [user]`, in either order
+fn parse_history_args(args: &str) -> (i64, Option<&str>) {
+    let mut limit = DEFAULT_HISTORY_LIMIT;
+    let mut user_id = None;
+    for arg in args.split_whitespace() {
+        match arg.parse::<i64>() {
+            Ok(n) => limit = n.clamp(1, MAX_HISTORY_LIMIT),
+            Err(_) if arg.starts_with('@') => user_id = Some(arg),
+            Err(_) => {}
+        }
+    }
+    (limit, user_id)
+}
+
+/// One line per entry, newest first
+fn format_history(records: &[BotCommandRecord]) -> String {
+    if records.is_empty() {
+        return "No commands executed in this room yet".to_owned();
+    }
+    records
+        .iter()
+        .map(|record| {
+            let command = if record.args.is_empty() {
+                record.command.clone()
+            } else {
+                format!("{} {}", record.command, record.args)
+            };
+            format!(
+                "{} {} {}: {} ({} ms)",
+                record.executed_at.format("%Y-%m-%d %H:%M:%S"),
+                if record.success { "✅" } else { "❌" },
+                record.user_id,
+                command,
+                record.duration_ms
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_history_args_and_format() {
+        assert_eq!(parse_history_args(""), (DEFAULT_HISTORY_LIMIT, None));
+        assert_eq!(parse_history_args("@eve:matrixon.local 5"), (5, Some("@eve:matrixon.local")));
+        assert_eq!(parse_history_args("100000"), (MAX_HISTORY_LIMIT, None));
+
+        let record = BotCommandRecord {
+            user_id: "@eve:matrixon.local".to_owned(),
+            room_id: "!shared:matrixon.local".to_owned(),
+            command: "ban".to_owned(),
+            args: "@bob:matrixon.local".to_owned(),
+            success: false,
+            result: "Unknown command".to_owned(),
+            duration_ms: 3,
+            executed_at: Utc.with_ymd_and_hms(2024, 3, 19, 12, 0, 0).unwrap(),
+        };
+        assert_eq!(
+            format_history(&[record]),
+            "2024-03-19 12:00:00 ❌ @eve:matrixon.local: ban @bob:matrixon.local (3 ms)"
+        );
+        assert_eq!(format_history(&[]), "No commands executed in this room yet");
+    }
+}
@@ -14,6 +14,8 @@
 //   - Command configuration
 //   - Plugin settings
 //   - Performance tuning
+//   - Telegram relay mappings
+//   - Command audit log
 //
 // =============================================================================
 
@@ -34,6 +36,12 @@ pub struct BotConfig {
     pub plugins: PluginConfig,
     /// Performance settings
     pub performance: PerformanceConfig,
+    /// Telegram relay, disabled when unset
+    #[serde(default)]
+    pub telegram: Option<TelegramConfig>,
+    /// Audit log of executed commands
+    #[serde(default)]
+    pub audit: AuditConfig,
 }
 
 /// Bot identity configuration
@@ -60,6 +68,9 @@ pub struct CommandConfig {
     pub cooldown: u64,
     /// Maximum command length
     pub max_length: usize,
+    /// Matrix user IDs allowed to run admin commands such as `!history`
+    #[serde(default)]
+    pub admins: Vec<String>,
 }
 
 /// Plugin configuration
@@ -84,6 +95,78 @@ pub struct PerformanceConfig {
     pub message_queue_size: usize,
 }
 
+/// Command audit log configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditConfig {
+    /// Whether executed commands are logged
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Days log entries are kept for
+    #[serde(default = "default_audit_retention_days")]
+    pub retention_days: u32,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        Self { enabled: true, retention_days: default_audit_retention_days() }
+    }
+}
+
+fn default_audit_retention_days() -> u32 {
+    90
+}
+
+/// Telegram relay configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TelegramConfig {
+    /// Token of the Telegram bot, from @BotFather
+    pub bot_token: String,
+    /// Telegram chats relayed to Matrix rooms
+    pub mappings: Vec<ChatMapping>,
+}
+
+/// A Telegram chat relayed to a Matrix room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatMapping {
+    /// Telegram chat id; negative for groups
+    pub chat_id: i64,
+    /// Matrix room id the bot is joined to
+    pub room_id: String,
+    /// Which way messages are relayed
+    #[serde(default)]
+    pub direction: RelayDirection,
+    /// Whether photos, files, audio and video are forwarded
+    #[serde(default = "default_true")]
+    pub relay_media: bool,
+    /// Whether relayed messages name their original sender
+    #[serde(default = "default_true")]
+    pub attribution: bool,
+}
+
+/// Direction of a relay mapping
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayDirection {
+    #[default]
+    Both,
+    TelegramToMatrix,
+    MatrixToTelegram,
+}
+
+impl RelayDirection {
+    pub fn to_matrix(self) -> bool {
+        self != Self::MatrixToTelegram
+    }
+
+    pub fn to_telegram(self) -> bool {
+        self != Self::TelegramToMatrix
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
 impl Default for BotConfig {
     fn default() -> Self {
         Self {
@@ -98,6 +181,7 @@ impl Default for BotConfig {
                 enabled_commands: vec!["help".to_string(), "status".to_string(), "ping".to_string()],
                 cooldown: 1,
                 max_length: 1000,
+                admins: Vec::new(),
             },
             plugins: PluginConfig {
                 enabled_plugins: Vec::new(),
@@ -109,6 +193,8 @@ impl Default for BotConfig {
                 command_timeout: 5000,
                 message_queue_size: 1000,
             },
+            telegram: None,
+            audit: AuditConfig::default(),
         }
     }
 }
@@ -174,4 +260,23 @@ mod tests {
         let loaded_config = BotConfig::from_file(&temp_file).unwrap();
         assert_eq!(config.identity.username, loaded_config.identity.username);
     }
+
+    #[test]
+    fn test_telegram_mapping_defaults() {
+        let mapping: ChatMapping = serde_json::from_value(serde_json::json!({
+            "chat_id": -1001234567890i64,
+            "room_id": "!relay:matrixon.local",
+        }))
+        .unwrap();
+        assert_eq!(mapping.direction, RelayDirection::Both);
+        assert!(mapping.relay_media && mapping.attribution);
+
+        let one_way: RelayDirection = serde_json::from_str("\"telegram_to_matrix\"").unwrap();
+        assert!(one_way.to_matrix() && !one_way.to_telegram());
+
+        // Configurations written before the relay existed still load
+        let mut config = serde_json::to_value(BotConfig::default()).unwrap();
+        config.as_object_mut().unwrap().remove("telegram");
+        assert!(serde_json::from_value::<BotConfig>(config).unwrap().telegram.is_none());
+    }
 } 
@@ -0,0 +1,277 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Telegram Relay
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-03-19
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Relay between Telegram chats and Matrix rooms. Each configured mapping
+//   pairs a Telegram chat with a room the bot is joined to; messages are
+//   copied across in the configured direction, prefixed with the name of
+//   their original sender. Photos, files, audio and video are downloaded
+//   from one side and uploaded to the other, on Matrix through the media
+//   repository. The bot's own messages are never relayed back.
+//
+// =============================================================================
+
+use std::{collections::HashMap, sync::Arc};
+
+use matrix_sdk::{
+    attachment::AttachmentConfig,
+    media::{MediaFormat, MediaRequestParameters},
+    room::Room,
+    ruma::events::room::message::{MessageType, OriginalSyncRoomMessageEvent, RoomMessageEventContent},
+    Client,
+};
+use matrixon_core::error::{MatrixonError, Result};
+use teloxide::{
+    net::Download,
+    prelude::*,
+    types::{InputFile, ParseMode, User},
+};
+use tracing::{debug, info, warn};
+
+use crate::config::{ChatMapping, TelegramConfig};
+
+/// Relays messages between Telegram chats and Matrix rooms
+#[derive(Clone)]
+pub struct TelegramRelay {
+    bot: Bot,
+    client: Client,
+    by_chat: Arc<HashMap<i64, ChatMapping>>,
+    by_room: Arc<HashMap<String, ChatMapping>>,
+}
+
+/// A Telegram attachment to forward
+struct TelegramMedia {
+    file_id: String,
+    file_name: String,
+    mime: mime::Mime,
+}
+
+impl TelegramRelay {
+    pub fn new(config: TelegramConfig, client: Client) -> Self {
+        let by_chat = config.mappings.iter().map(|mapping| (mapping.chat_id, mapping.clone())).collect();
+        let by_room = config.mappings.iter().map(|mapping| (mapping.room_id.clone(), mapping.clone())).collect();
+        Self {
+            bot: Bot::new(config.bot_token),
+            client,
+            by_chat: Arc::new(by_chat),
+            by_room: Arc::new(by_room),
+        }
+    }
+
+    /// Relay messages of the mapped rooms to Telegram; takes effect once
+    /// the Matrix client syncs
+    pub fn relay_matrix(&self) {
+        let relay = self.clone();
+        self.client.add_event_handler(move |event: OriginalSyncRoomMessageEvent, room: Room| {
+            let relay = relay.clone();
+            async move {
+                if let Err(e) = relay.to_telegram(&event, &room).await {
+                    warn!("⚠️ Relaying {} to Telegram failed: {}", event.event_id, e);
+                }
+            }
+        });
+    }
+
+    /// Relay messages of the mapped Telegram chats to Matrix until the
+    /// Telegram connection ends
+    pub async fn relay_telegram(self) {
+        info!("🔁 Relaying {} Telegram chats", self.by_chat.len());
+        let bot = self.bot.clone();
+        teloxide::repl(bot, move |message: Message| {
+            let relay = self.clone();
+            async move {
+                if let Err(e) = relay.to_matrix(&message).await {
+                    warn!("⚠️ Relaying Telegram message {} to Matrix failed: {}", message.id, e);
+                }
+                Ok(())
+            }
+        })
+        .await;
+    }
+
+    async fn to_matrix(&self, message: &Message) -> Result<()> {
+        let Some(mapping) = self.by_chat.get(&message.chat.id.0).filter(|mapping| mapping.direction.to_matrix()) else {
+            return Ok(());
+        };
+        let room_id = <&matrix_sdk::ruma::RoomId>::try_from(mapping.room_id.as_str())
+            .map_err(|e| MatrixonError::Config(format!("Invalid room id {}: {}", mapping.room_id, e)))?;
+        let room = self
+            .client
+            .get_room(room_id)
+            .ok_or_else(|| MatrixonError::NotFound(format!("The bot is not in {}", mapping.room_id)))?;
+        let sender = message.from().map_or_else(|| message.chat.title().unwrap_or("Telegram").to_owned(), telegram_name);
+
+        let media = if mapping.relay_media { telegram_media(message) } else { None };
+        let text = message.text().or(message.caption());
+        if let Some(text) = text {
+            room.send(matrix_text(&sender, text, mapping.attribution)).await.map_err(internal)?;
+        } else if let Some(media) = media.as_ref().filter(|_| mapping.attribution) {
+            room.send(RoomMessageEventContent::notice_plain(format!("{} sent {}", sender, media.file_name)))
+                .await
+                .map_err(internal)?;
+        }
+
+        if let Some(media) = media {
+            let file = self.bot.get_file(&media.file_id).await.map_err(internal)?;
+            let mut data = Vec::new();
+            self.bot.download_file(&file.path, &mut data).await.map_err(internal)?;
+            room.send_attachment(&media.file_name, &media.mime, data, AttachmentConfig::new())
+                .await
+                .map_err(internal)?;
+        }
+        debug!("Relayed Telegram message {} to {}", message.id, mapping.room_id);
+        Ok(())
+    }
+
+    async fn to_telegram(&self, event: &OriginalSyncRoomMessageEvent, room: &Room) -> Result<()> {
+        let Some(mapping) = self.by_room.get(room.room_id().as_str()).filter(|mapping| mapping.direction.to_telegram()) else {
+            return Ok(());
+        };
+        if self.client.user_id() == Some(&*event.sender) {
+            return Ok(());
+        }
+        let sender = match room.get_member_no_sync(&event.sender).await {
+            Ok(Some(member)) => member.name().to_owned(),
+            _ => event.sender.localpart().to_owned(),
+        };
+        let chat = ChatId(mapping.chat_id);
+        let attribution = |text: &str| telegram_html(&sender, text, mapping.attribution);
+
+        let (source, body) = match &event.content.msgtype {
+            MessageType::Text(text) => {
+                self.bot.send_message(chat, attribution(&text.body)).parse_mode(ParseMode::Html).await.map_err(internal)?;
+                return Ok(());
+            }
+            MessageType::Notice(notice) => {
+                self.bot.send_message(chat, attribution(&notice.body)).parse_mode(ParseMode::Html).await.map_err(internal)?;
+                return Ok(());
+            }
+            MessageType::Emote(emote) => {
+                self.bot.send_message(chat, emote_html(&sender, &emote.body)).parse_mode(ParseMode::Html).await.map_err(internal)?;
+                return Ok(());
+            }
+            _ if !mapping.relay_media => return Ok(()),
+            MessageType::Image(image) => (image.source.clone(), image.body.clone()),
+            MessageType::File(file) => (file.source.clone(), file.body.clone()),
+            MessageType::Audio(audio) => (audio.source.clone(), audio.body.clone()),
+            MessageType::Video(video) => (video.source.clone(), video.body.clone()),
+            _ => return Ok(()),
+        };
+
+        let request = MediaRequestParameters { source, format: MediaFormat::File };
+        let data = self.client.media().get_media_content(&request, true).await.map_err(internal)?;
+        let file = InputFile::memory(data).file_name(body.clone());
+        let caption = telegram_html(&sender, "", mapping.attribution);
+        match &event.content.msgtype {
+            MessageType::Image(_) => {
+                self.bot.send_photo(chat, file).caption(caption).parse_mode(ParseMode::Html).await.map_err(internal)?;
+            }
+            MessageType::Audio(_) => {
+                self.bot.send_audio(chat, file).caption(caption).parse_mode(ParseMode::Html).await.map_err(internal)?;
+            }
+            MessageType::Video(_) => {
+                self.bot.send_video(chat, file).caption(caption).parse_mode(ParseMode::Html).await.map_err(internal)?;
+            }
+            _ => {
+                self.bot.send_document(chat, file).caption(caption).parse_mode(ParseMode::Html).await.map_err(internal)?;
+            }
+        }
+        debug!("Relayed {} to Telegram chat {}", event.event_id, mapping.chat_id);
+        Ok(())
+    }
+}
+
+fn internal(e: impl std::fmt::Display) -> MatrixonError {
+    MatrixonError::Internal(e.to_string())
+}
+
+/// Display name of a Telegram user
+fn telegram_name(user: &User) -> String {
+    user.full_name()
+}
+
+/// The attachment of a Telegram message, largest photo size first
+fn telegram_media(message: &Message) -> Option<TelegramMedia> {
+    let media = |file_id: &str, file_name: String, mime: Option<&mime::Mime>, fallback: mime::Mime| TelegramMedia {
+        file_id: file_id.to_owned(),
+        file_name,
+        mime: mime.cloned().unwrap_or(fallback),
+    };
+    if let Some(photo) = message.photo().and_then(|sizes| sizes.iter().max_by_key(|size| size.width * size.height)) {
+        return Some(media(&photo.file.id, "photo.jpg".to_owned(), None, mime::IMAGE_JPEG));
+    }
+    if let Some(document) = message.document() {
+        let name = document.file_name.clone().unwrap_or_else(|| "file".to_owned());
+        return Some(media(&document.file.id, name, document.mime_type.as_ref(), mime::APPLICATION_OCTET_STREAM));
+    }
+    if let Some(video) = message.video() {
+        let name = video.file_name.clone().unwrap_or_else(|| "video.mp4".to_owned());
+        return Some(media(&video.file.id, name, video.mime_type.as_ref(), "video/mp4".parse().unwrap()));
+    }
+    if let Some(audio) = message.audio() {
+        let name = audio.file_name.clone().unwrap_or_else(|| "audio.mp3".to_owned());
+        return Some(media(&audio.file.id, name, audio.mime_type.as_ref(), "audio/mpeg".parse().unwrap()));
+    }
+    if let Some(voice) = message.voice() {
+        return Some(media(&voice.file.id, "voice.ogg".to_owned(), voice.mime_type.as_ref(), "audio/ogg".parse().unwrap()));
+    }
+    None
+}
+
+/// A Telegram message as Matrix message content, named after its sender
+fn matrix_text(sender: &str, text: &str, attribution: bool) -> RoomMessageEventContent {
+    if !attribution {
+        return RoomMessageEventContent::text_plain(text);
+    }
+    RoomMessageEventContent::text_html(
+        format!("{}: {}", sender, text),
+        format!("<b>{}</b>: {}", escape_html(sender), escape_html(text)),
+    )
+}
+
+/// A Matrix message as Telegram HTML, named after its sender
+fn telegram_html(sender: &str, text: &str, attribution: bool) -> String {
+    match (attribution, text.is_empty()) {
+        (false, _) => escape_html(text),
+        (true, true) => format!("<b>{}</b>", escape_html(sender)),
+        (true, false) => format!("<b>{}</b>: {}", escape_html(sender), escape_html(text)),
+    }
+}
+
+/// Telegram HTML of an emote of a Matrix user
+fn emote_html(sender: &str, text: &str) -> String {
+    format!("* {} {}", escape_html(sender), escape_html(text))
+}
+
+/// Escape text for the HTML subset both Telegram and Matrix clients render
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attribution() {
+        assert_eq!(telegram_html("<Alice>", "1 < 2 & 3", true), "<b>&lt;Alice&gt;</b>: 1 &lt; 2 &amp; 3");
+        assert_eq!(telegram_html("Alice", "", true), "<b>Alice</b>");
+        assert_eq!(telegram_html("Alice", "hi", false), "hi");
+        assert_eq!(emote_html("<b>Mallory</b>", "waves"), "* &lt;b&gt;Mallory&lt;/b&gt; waves");
+
+        let content = matrix_text("Bob", "hello <world>", true);
+        assert_eq!(content.body(), "Bob: hello <world>");
+        match content.msgtype {
+            MessageType::Text(text) => assert_eq!(text.formatted.unwrap().body, "<b>Bob</b>: hello &lt;world&gt;"),
+            _ => unreachable!(),
+        }
+        assert_eq!(matrix_text("Bob", "plain", false).body(), "plain");
+    }
+}
@@ -0,0 +1,121 @@
+//! HTTP client for the admin API
+//!
+//! Requests are authenticated with the admin's access token. Path segments
+//! are percent-encoded individually, so user and room ids can be passed
+//! as they are.
+
+use reqwest::{Method, Url};
+use serde_json::Value;
+use thiserror::Error;
+
+/// Errors talking to the server
+#[derive(Debug, Error)]
+pub enum CliError {
+    #[error("Invalid server URL: {0}")]
+    InvalidUrl(String),
+
+    #[error("Not logged in; run `matrixonctl login` or pass --token")]
+    NotLoggedIn,
+
+    #[error("Request failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("{errcode}: {error} (HTTP {status})")]
+    Server { status: u16, errcode: String, error: String },
+
+    #[error("Could not store session: {0}")]
+    Session(String),
+}
+
+pub type Result<T> = std::result::Result<T, CliError>;
+
+/// Admin API client for one server
+#[derive(Debug, Clone)]
+pub struct AdminClient {
+    base: Url,
+    token: Option<String>,
+    http: reqwest::Client,
+}
+
+impl AdminClient {
+    pub fn new(server: &str, token: Option<String>) -> Result<Self> {
+        let base = Url::parse(server).map_err(|e| CliError::InvalidUrl(format!("{}: {}", server, e)))?;
+        if base.cannot_be_a_base() {
+            return Err(CliError::InvalidUrl(server.to_owned()));
+        }
+        Ok(Self {
+            base,
+            token,
+            http: reqwest::Client::new(),
+        })
+    }
+
+    pub async fn get(&self, path: &[&str], query: &[(&str, String)]) -> Result<Value> {
+        self.send(Method::GET, self.url(path), query, None).await
+    }
+
+    pub async fn post(&self, path: &[&str], body: Value) -> Result<Value> {
+        self.send(Method::POST, self.url(path), &[], Some(body)).await
+    }
+
+    pub async fn put(&self, path: &[&str], body: Value) -> Result<Value> {
+        self.send(Method::PUT, self.url(path), &[], Some(body)).await
+    }
+
+    pub async fn delete(&self, path: &[&str], body: Option<Value>) -> Result<Value> {
+        self.send(Method::DELETE, self.url(path), &[], body).await
+    }
+
+    pub async fn delete_with_query(&self, path: &[&str], query: &[(&str, String)]) -> Result<Value> {
+        self.send(Method::DELETE, self.url(path), query, None).await
+    }
+
+    /// URL of `path` below the server's base URL
+    pub fn url(&self, path: &[&str]) -> Url {
+        let mut url = self.base.clone();
+        url.path_segments_mut()
+            .expect("base URLs are checked in AdminClient::new")
+            .pop_if_empty()
+            .extend(path);
+        url
+    }
+
+    async fn send(&self, method: Method, url: Url, query: &[(&str, String)], body: Option<Value>) -> Result<Value> {
+        let mut request = self.http.request(method, url).query(query);
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(body) = body {
+            request = request.json(&body);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+        let body: Value = response.json().await.unwrap_or(Value::Null);
+        if !status.is_success() {
+            return Err(CliError::Server {
+                status: status.as_u16(),
+                errcode: body["errcode"].as_str().unwrap_or("M_UNKNOWN").to_owned(),
+                error: body["error"].as_str().unwrap_or_else(|| status.canonical_reason().unwrap_or("")).to_owned(),
+            });
+        }
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_segments_are_encoded() {
+        let client = AdminClient::new("https://matrix.example.org/", None).unwrap();
+        let url = client.url(&["_synapse", "admin", "v2", "users", "@alice:example.org"]);
+        assert_eq!(url.as_str(), "https://matrix.example.org/_synapse/admin/v2/users/@alice:example.org");
+
+        let url = client.url(&["_synapse", "admin", "v1", "rooms", "!room/id:example.org"]);
+        assert!(url.as_str().ends_with("/rooms/!room%2Fid:example.org"));
+
+        assert!(AdminClient::new("not a url", None).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+// =============================================================================
+// Matrixon Matrix NextServer - matrixonctl
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Remote admin client. Talks to a Matrixon server's admin API over HTTP
+//   (the Synapse-compatible `/_synapse/admin` endpoints plus Matrixon's own
+//   `/_matrixon/admin`), so administration does not require a shell on the
+//   server host.
+//
+// =============================================================================
+
+mod client;
+mod output;
+mod session;
+
+use clap::{Args, Parser, Subcommand};
+use serde_json::{json, Value};
+
+use client::{AdminClient, CliError, Result};
+use output::Format;
+use session::Session;
+
+#[derive(Parser)]
+#[command(name = "matrixonctl", version, about = "Remote admin client for Matrixon")]
+struct Cli {
+    /// Server base URL, e.g. https://matrix.example.org
+    #[arg(long, global = true, env = "MATRIXON_SERVER")]
+    server: Option<String>,
+
+    /// Admin access token; defaults to the stored login
+    #[arg(long, global = true, env = "MATRIXON_ADMIN_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// Output format
+    #[arg(long, short, global = true, value_enum, default_value = "table")]
+    output: Format,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Log in with an admin account and store the session
+    Login {
+        #[arg(long)]
+        user: String,
+        #[arg(long, env = "MATRIXON_ADMIN_PASSWORD", hide_env_values = true)]
+        password: String,
+    },
+    /// Forget the stored session
+    Logout,
+    /// Show who the token belongs to
+    Whoami,
+    /// Manage users
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Manage rooms
+    #[command(subcommand)]
+    Room(RoomCommand),
+    /// Manage media
+    #[command(subcommand)]
+    Media(MediaCommand),
+    /// Inspect federation
+    #[command(subcommand)]
+    Federation(FederationCommand),
+    /// Rooms new users are joined to
+    #[command(subcommand)]
+    AutoJoin(AutoJoinCommand),
+}
+
+#[derive(Args)]
+struct Page {
+    /// Offset to start listing from
+    #[arg(long, default_value_t = 0)]
+    from: u64,
+    /// Maximum number of entries
+    #[arg(long, default_value_t = 100)]
+    limit: u64,
+}
+
+impl Page {
+    fn query(&self) -> Vec<(&'static str, String)> {
+        vec![("from", self.from.to_string()), ("limit", self.limit.to_string())]
+    }
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    /// Show an account
+    Show { user_id: String },
+    /// Deactivate an account
+    Deactivate {
+        user_id: String,
+        /// Also erase the user's messages and profile
+        #[arg(long)]
+        erase: bool,
+    },
+    /// Suspend an account
+    Suspend {
+        user_id: String,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Lift an account's suspension
+    Unsuspend { user_id: String },
+    /// Issue a short-lived token acting as the user
+    Impersonate {
+        user_id: String,
+        /// Why the user is impersonated; recorded in the audit trail
+        #[arg(long)]
+        reason: String,
+        #[arg(long)]
+        valid_for_s: Option<u64>,
+    },
+    /// Revoke the impersonation tokens of a user
+    StopImpersonating { user_id: String },
+}
+
+#[derive(Subcommand)]
+enum RoomCommand {
+    /// List rooms
+    List {
+        #[command(flatten)]
+        page: Page,
+    },
+    /// Show a room
+    Show { room_id: String },
+    /// List the members of a room
+    Members { room_id: String },
+    /// Remove all local users from a room and delete it
+    Delete {
+        room_id: String,
+        /// Prevent the room from being joined again
+        #[arg(long)]
+        block: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum MediaCommand {
+    /// List the media uploaded by a user
+    List {
+        user_id: String,
+        #[command(flatten)]
+        page: Page,
+    },
+    /// Delete a local media file
+    Delete { server_name: String, media_id: String },
+    /// Quarantine a media file so it is no longer served
+    Quarantine { server_name: String, media_id: String },
+}
+
+#[derive(Subcommand)]
+enum FederationCommand {
+    /// List known destinations and their retry state
+    Destinations {
+        #[command(flatten)]
+        page: Page,
+    },
+    /// Show one destination
+    Destination { destination: String },
+    /// Reset the retry backoff of a destination
+    Reset { destination: String },
+}
+
+#[derive(Subcommand)]
+enum AutoJoinCommand {
+    /// Show the auto-join rooms
+    Get,
+    /// Replace the auto-join rooms
+    Set { rooms: Vec<String> },
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    match run(cli).await {
+        Ok(output) => println!("{}", output),
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+async fn run(cli: Cli) -> Result<String> {
+    let stored = Session::load();
+    let server = cli
+        .server
+        .clone()
+        .or_else(|| stored.as_ref().map(|session| session.server.clone()))
+        .ok_or_else(|| CliError::InvalidUrl("no server given; pass --server or log in".to_owned()))?;
+
+    if let Command::Login { user, password } = &cli.command {
+        return login(&server, user, password).await;
+    }
+    if let Command::Logout = cli.command {
+        Session::clear()?;
+        return Ok("Logged out".to_owned());
+    }
+
+    let token = cli
+        .token
+        .clone()
+        .or_else(|| stored.map(|session| session.access_token))
+        .ok_or(CliError::NotLoggedIn)?;
+    let client = AdminClient::new(&server, Some(token))?;
+    let (response, list_key) = execute(&client, cli.command).await?;
+    Ok(output::render(&response, cli.output, list_key))
+}
+
+async fn login(server: &str, user: &str, password: &str) -> Result<String> {
+    let client = AdminClient::new(server, None)?;
+    let response = client
+        .post(
+            &["_matrix", "client", "v3", "login"],
+            json!({
+                "type": "m.login.password",
+                "identifier": { "type": "m.id.user", "user": user },
+                "password": password,
+                "initial_device_display_name": "matrixonctl"
+            }),
+        )
+        .await?;
+
+    let session = Session {
+        server: server.to_owned(),
+        user_id: response["user_id"].as_str().unwrap_or(user).to_owned(),
+        access_token: response["access_token"]
+            .as_str()
+            .ok_or_else(|| CliError::Session("no access token in login response".to_owned()))?
+            .to_owned(),
+    };
+    let path = session.save()?;
+    Ok(format!("Logged in as {}; session stored in {}", session.user_id, path.display()))
+}
+
+/// Run an authenticated command. Returns the response and, for listings,
+/// the key holding the listed entries.
+async fn execute(client: &AdminClient, command: Command) -> Result<(Value, Option<&'static str>)> {
+    const SYNAPSE: &str = "_synapse";
+    const MATRIXON: &str = "_matrixon";
+
+    let result = match command {
+        Command::Login { .. } | Command::Logout => unreachable!("handled before authenticating"),
+        Command::Whoami => (client.get(&["_matrix", "client", "v3", "account", "whoami"], &[]).await?, None),
+
+        Command::User(command) => match command {
+            UserCommand::Show { user_id } => (client.get(&[SYNAPSE, "admin", "v2", "users", &user_id], &[]).await?, None),
+            UserCommand::Deactivate { user_id, erase } => (
+                client.post(&[SYNAPSE, "admin", "v1", "deactivate", &user_id], json!({ "erase": erase })).await?,
+                None,
+            ),
+            UserCommand::Suspend { user_id, reason } => (
+                client
+                    .put(&[SYNAPSE, "admin", "v1", "suspend", &user_id], json!({ "suspend": true, "reason": reason }))
+                    .await?,
+                None,
+            ),
+            UserCommand::Unsuspend { user_id } => (
+                client.put(&[SYNAPSE, "admin", "v1", "suspend", &user_id], json!({ "suspend": false })).await?,
+                None,
+            ),
+            UserCommand::Impersonate { user_id, reason, valid_for_s } => (
+                client
+                    .post(
+                        &[MATRIXON, "admin", "v1", "users", &user_id, "impersonate"],
+                        json!({ "reason": reason, "valid_for_s": valid_for_s }),
+                    )
+                    .await?,
+                None,
+            ),
+            UserCommand::StopImpersonating { user_id } => (
+                client.delete(&[MATRIXON, "admin", "v1", "users", &user_id, "impersonate"], None).await?,
+                None,
+            ),
+        },
+
+        Command::Room(command) => match command {
+            RoomCommand::List { page } => (client.get(&[SYNAPSE, "admin", "v1", "rooms"], &page.query()).await?, Some("rooms")),
+            RoomCommand::Show { room_id } => (client.get(&[SYNAPSE, "admin", "v1", "rooms", &room_id], &[]).await?, None),
+            RoomCommand::Members { room_id } => (
+                client.get(&[SYNAPSE, "admin", "v1", "rooms", &room_id, "members"], &[]).await?,
+                Some("members"),
+            ),
+            RoomCommand::Delete { room_id, block } => (
+                client
+                    .delete_with_query(&[MATRIXON, "admin", "v1", "rooms", &room_id], &[("block", block.to_string())])
+                    .await?,
+                None,
+            ),
+        },
+
+        Command::Media(command) => match command {
+            MediaCommand::List { user_id, page } => (
+                client.get(&[SYNAPSE, "admin", "v1", "users", &user_id, "media"], &page.query()).await?,
+                Some("media"),
+            ),
+            MediaCommand::Delete { server_name, media_id } => (
+                client.delete(&[SYNAPSE, "admin", "v1", "media", &server_name, &media_id], None).await?,
+                None,
+            ),
+            MediaCommand::Quarantine { server_name, media_id } => (
+                client
+                    .post(&[SYNAPSE, "admin", "v1", "media", "quarantine", &server_name, &media_id], json!({}))
+                    .await?,
+                None,
+            ),
+        },
+
+        Command::Federation(command) => match command {
+            FederationCommand::Destinations { page } => (
+                client.get(&[SYNAPSE, "admin", "v1", "federation", "destinations"], &page.query()).await?,
+                Some("destinations"),
+            ),
+            FederationCommand::Destination { destination } => (
+                client.get(&[SYNAPSE, "admin", "v1", "federation", "destinations", &destination], &[]).await?,
+                None,
+            ),
+            FederationCommand::Reset { destination } => (
+                client
+                    .post(
+                        &[SYNAPSE, "admin", "v1", "federation", "destinations", &destination, "reset_connection"],
+                        json!({}),
+                    )
+                    .await?,
+                None,
+            ),
+        },
+
+        Command::AutoJoin(command) => match command {
+            AutoJoinCommand::Get => (client.get(&[MATRIXON, "admin", "v1", "auto_join_rooms"], &[]).await?, Some("rooms")),
+            AutoJoinCommand::Set { rooms } => (
+                client.put(&[MATRIXON, "admin", "v1", "auto_join_rooms"], json!({ "rooms": rooms })).await?,
+                Some("rooms"),
+            ),
+        },
+    };
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_definition() {
+        Cli::command().debug_assert();
+        let cli = Cli::try_parse_from(["matrixonctl", "--output", "json", "user", "suspend", "@a:b", "--reason", "spam"]).unwrap();
+        assert_eq!(cli.output, Format::Json);
+        assert!(matches!(cli.command, Command::User(UserCommand::Suspend { reason: Some(_), .. })));
+    }
+}
@@ -0,0 +1,92 @@
+//! Rendering of API responses as JSON or tables
+
+use clap::ValueEnum;
+use comfy_table::{presets::UTF8_FULL, Table};
+use serde_json::Value;
+
+/// Output format selected with `--output`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    Json,
+    Table,
+}
+
+/// Render `value` in `format`. Tables show a list of objects as one row per
+/// object; a single object as key/value rows. A list found under `list_key`
+/// (e.g. `users` in a user listing) is rendered instead of the whole object.
+pub fn render(value: &Value, format: Format, list_key: Option<&str>) -> String {
+    match format {
+        Format::Json => serde_json::to_string_pretty(value).unwrap_or_default(),
+        Format::Table => {
+            let value = list_key.and_then(|key| value.get(key)).unwrap_or(value);
+            match value {
+                Value::Array(rows) => rows_table(rows),
+                Value::Object(_) => key_value_table(value),
+                other => cell(other),
+            }
+        }
+    }
+}
+
+fn rows_table(rows: &[Value]) -> String {
+    let mut columns: Vec<&str> = Vec::new();
+    for row in rows {
+        for key in row.as_object().into_iter().flat_map(|object| object.keys()) {
+            if !columns.contains(&key.as_str()) {
+                columns.push(key);
+            }
+        }
+    }
+    if columns.is_empty() {
+        return rows.iter().map(cell).collect::<Vec<_>>().join("\n");
+    }
+
+    let mut table = Table::new();
+    table.load_preset(UTF8_FULL).set_header(columns.clone());
+    for row in rows {
+        table.add_row(columns.iter().map(|column| cell(&row[*column])));
+    }
+    table.to_string()
+}
+
+fn key_value_table(value: &Value) -> String {
+    let mut table = Table::new();
+    table.load_preset(UTF8_FULL).set_header(vec!["key", "value"]);
+    for (key, value) in value.as_object().into_iter().flatten() {
+        table.add_row(vec![key.clone(), cell(value)]);
+    }
+    table.to_string()
+}
+
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_tables_use_union_of_columns() {
+        let response = json!({
+            "users": [
+                { "name": "@alice:example.org", "admin": true },
+                { "name": "@bob:example.org", "deactivated": true }
+            ],
+            "total": 2
+        });
+        let table = render(&response, Format::Table, Some("users"));
+        for column in ["name", "admin", "deactivated", "@bob:example.org"] {
+            assert!(table.contains(column), "missing {} in\n{}", column, table);
+        }
+        assert!(!table.contains("total"));
+
+        let json = render(&response, Format::Json, Some("users"));
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), response);
+    }
+}
@@ -0,0 +1,54 @@
+//! Stored login of the admin
+//!
+//! `matrixonctl login` saves the server URL and access token to
+//! `matrixonctl/session.json` in the user's config directory, so later
+//! commands need neither `--server` nor `--token`.
+
+use std::{fs, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::client::{CliError, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub server: String,
+    pub user_id: String,
+    pub access_token: String,
+}
+
+fn path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("matrixonctl").join("session.json"))
+}
+
+impl Session {
+    /// The stored session, if any
+    pub fn load() -> Option<Self> {
+        let data = fs::read(path()?).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    pub fn save(&self) -> Result<PathBuf> {
+        let path = path().ok_or_else(|| CliError::Session("No config directory".to_owned()))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| CliError::Session(e.to_string()))?;
+        }
+        let data = serde_json::to_vec_pretty(self).map_err(|e| CliError::Session(e.to_string()))?;
+        fs::write(&path, data).map_err(|e| CliError::Session(e.to_string()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            // The file holds an admin access token
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).map_err(|e| CliError::Session(e.to_string()))?;
+        }
+        Ok(path)
+    }
+
+    /// Remove the stored session
+    pub fn clear() -> Result<()> {
+        match path() {
+            Some(path) if path.exists() => fs::remove_file(path).map_err(|e| CliError::Session(e.to_string())),
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,361 @@
+//! Localization of server-generated text
+//!
+//! Messages are written in the [Fluent](https://projectfluent.org/) syntax
+//! and formatted with the `fluent` crate, so terms, select expressions and
+//! plural rules are available to translations. Terms (`-id`) can only be
+//! used from other messages.
+//!
+//! A [`Catalog`] holds the messages of every locale. Lookups fall back from
+//! a regional locale to its language (`pt-br` to `pt`), then to the default
+//! locale and finally to the source locale the built-in messages are
+//! written in, so translations can be partial. Each chain of locales is
+//! formatted with one bundle holding the resources of all of them, the more
+//! specific ones overriding the others, so a translation can use the terms
+//! of the source locale.
+
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use fluent::{concurrent::FluentBundle, FluentArgs, FluentResource, FluentValue};
+use fluent_syntax::ast::Entry;
+use serde_json::{Map, Value};
+use unic_langid::LanguageIdentifier;
+
+use crate::error::{MatrixonError, Result};
+
+type Bundle = FluentBundle<Arc<FluentResource>>;
+
+/// `pt_BR` and `pt-BR` both become `pt-br`
+fn normalize(locale: &str) -> String {
+    locale.trim().replace('_', "-").to_lowercase()
+}
+
+/// Parse a Fluent resource, rejecting it when any entry is invalid
+fn parse(source: &str) -> Result<FluentResource> {
+    FluentResource::try_new(source.to_owned()).map_err(|(_, errors)| {
+        let error = &errors[0];
+        let line = source[..error.pos.start.min(source.len())].matches('\n').count() + 1;
+        MatrixonError::Config(format!("Line {}: {}", line, error.kind))
+    })
+}
+
+/// Ids of the messages of a resource, without its terms
+fn message_ids(resource: &FluentResource) -> impl Iterator<Item = &str> {
+    resource.entries().filter_map(|entry| match entry {
+        Entry::Message(message) => Some(message.id.name),
+        _ => None,
+    })
+}
+
+fn fluent_args(args: &Value) -> FluentArgs<'_> {
+    let mut fluent_args = FluentArgs::new();
+    for (name, value) in args.as_object().into_iter().flatten() {
+        let value = match value {
+            Value::Null => continue,
+            Value::String(value) => FluentValue::from(value.as_str()),
+            Value::Number(number) => match number.as_i64() {
+                Some(number) => FluentValue::from(number),
+                None => FluentValue::from(number.as_f64().unwrap_or_default()),
+            },
+            value => FluentValue::from(value.to_string()),
+        };
+        fluent_args.set(name.as_str(), value);
+    }
+    fluent_args
+}
+
+/// Messages of all locales
+pub struct Catalog {
+    source_locale: String,
+    default_locale: String,
+    resources: HashMap<String, Vec<Arc<FluentResource>>>,
+    /// Bundles by the locales they hold, most specific first
+    bundles: Mutex<HashMap<Vec<String>, Arc<Bundle>>>,
+}
+
+impl fmt::Debug for Catalog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Catalog")
+            .field("source_locale", &self.source_locale)
+            .field("default_locale", &self.default_locale)
+            .field("locales", &self.locales())
+            .finish()
+    }
+}
+
+impl Clone for Catalog {
+    fn clone(&self) -> Self {
+        Self {
+            source_locale: self.source_locale.clone(),
+            default_locale: self.default_locale.clone(),
+            resources: self.resources.clone(),
+            bundles: Mutex::default(),
+        }
+    }
+}
+
+impl Catalog {
+    /// An empty catalog whose built-in messages will be in `source_locale`,
+    /// which is also the default locale
+    pub fn new(source_locale: &str) -> Self {
+        Self {
+            source_locale: normalize(source_locale),
+            default_locale: normalize(source_locale),
+            resources: HashMap::new(),
+            bundles: Mutex::default(),
+        }
+    }
+
+    /// Use `locale` when none of the requested ones is available
+    pub fn with_default_locale(mut self, locale: &str) -> Self {
+        self.default_locale = normalize(locale);
+        self.bundles.get_mut().unwrap_or_else(|e| e.into_inner()).clear();
+        self
+    }
+
+    pub fn default_locale(&self) -> &str {
+        &self.default_locale
+    }
+
+    /// Add the messages of a Fluent resource to `locale`, replacing those
+    /// with the same id
+    pub fn add_resource(&mut self, locale: &str, source: &str) -> Result<()> {
+        let resource = parse(source)?;
+        self.resources.entry(normalize(locale)).or_default().push(Arc::new(resource));
+        self.bundles.get_mut().unwrap_or_else(|e| e.into_inner()).clear();
+        Ok(())
+    }
+
+    /// Add the resources of a directory laid out as `<locale>/<name>.ftl`
+    pub fn load_dir(&mut self, dir: &Path) -> Result<()> {
+        let read_dir = |dir: &Path| fs::read_dir(dir).map_err(|e| MatrixonError::Config(format!("{}: {}", dir.display(), e)));
+        for locale_dir in read_dir(dir)?.filter_map(|entry| entry.ok()).map(|entry| entry.path()) {
+            let Some(locale) = locale_dir.file_name().and_then(|name| name.to_str()).filter(|_| locale_dir.is_dir()) else {
+                continue;
+            };
+            let mut files: Vec<_> = read_dir(&locale_dir)?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|extension| extension == "ftl"))
+                .collect();
+            files.sort();
+            for file in files {
+                let source = fs::read_to_string(&file).map_err(|e| MatrixonError::Config(format!("{}: {}", file.display(), e)))?;
+                self.add_resource(locale, &source)
+                    .map_err(|e| MatrixonError::Config(format!("{}: {}", file.display(), e)))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Locales with at least one message, sorted
+    pub fn locales(&self) -> Vec<&str> {
+        let mut locales: Vec<&str> = self.resources.keys().map(String::as_str).collect();
+        locales.sort_unstable();
+        locales
+    }
+
+    /// The first of the `requested` locales (most preferred first) the
+    /// catalog has messages for, matching regional locales by language,
+    /// or the default locale
+    pub fn negotiate<'a>(&self, requested: impl IntoIterator<Item = &'a str>) -> String {
+        for locale in requested.into_iter().map(normalize) {
+            if self.resources.contains_key(&locale) {
+                return locale;
+            }
+            let language = locale.split('-').next().unwrap_or_default();
+            if self.resources.contains_key(language) {
+                return language.to_owned();
+            }
+        }
+        self.default_locale.clone()
+    }
+
+    /// Locales with messages looked up for `locale`, most specific first
+    fn chain(&self, locale: &str) -> Vec<String> {
+        let mut chain: Vec<String> = Vec::new();
+        for locale in [normalize(locale), self.default_locale.clone(), self.source_locale.clone()] {
+            let language = locale.split_once('-').map(|(language, _)| language.to_owned());
+            for locale in [Some(locale), language].into_iter().flatten() {
+                if self.resources.contains_key(&locale) && !chain.contains(&locale) {
+                    chain.push(locale);
+                }
+            }
+        }
+        chain
+    }
+
+    /// The bundle formatting messages of `locale`. Bundles are keyed by the
+    /// locales they hold, which only come from the catalog, so requests
+    /// for arbitrary locales do not grow the cache.
+    fn bundle(&self, locale: &str) -> Arc<Bundle> {
+        let chain = self.chain(locale);
+        let mut bundles = self.bundles.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(bundle) = bundles.get(&chain) {
+            return Arc::clone(bundle);
+        }
+        let language = chain.first().and_then(|locale| locale.parse::<LanguageIdentifier>().ok()).unwrap_or_default();
+        let mut bundle = Bundle::new_concurrent(vec![language]);
+        bundle.set_use_isolating(false);
+        for locale in chain.iter().rev() {
+            for resource in &self.resources[locale] {
+                bundle.add_resource_overriding(Arc::clone(resource));
+            }
+        }
+        let bundle = Arc::new(bundle);
+        bundles.insert(chain, Arc::clone(&bundle));
+        bundle
+    }
+
+    /// Whether `id` is translated for `locale` rather than only available
+    /// in the source locale
+    pub fn translates(&self, locale: &str, id: &str) -> bool {
+        self.chain(locale)
+            .iter()
+            .take_while(|locale| **locale != self.source_locale)
+            .flat_map(|locale| &self.resources[locale])
+            .any(|resource| message_ids(resource).any(|message_id| message_id == id))
+    }
+
+    /// The message `id` in `locale`, with the fields of `args` as arguments
+    pub fn format(&self, locale: &str, id: &str, args: &Value) -> Option<String> {
+        Self::format_in(&self.bundle(locale), id, &fluent_args(args))
+    }
+
+    fn format_in(bundle: &Bundle, id: &str, args: &FluentArgs<'_>) -> Option<String> {
+        let pattern = bundle.get_message(id)?.value()?;
+        // Missing arguments and messages are left as `{$name}` in the text
+        let mut errors = Vec::new();
+        Some(bundle.format_pattern(pattern, Some(args), &mut errors).into_owned())
+    }
+
+    /// Every message of `locale` and its fallbacks, keyed by id, e.g. to be
+    /// handed to a template
+    pub fn messages(&self, locale: &str, args: &Value) -> Map<String, Value> {
+        let bundle = self.bundle(locale);
+        let args = fluent_args(args);
+        let mut ids: Vec<&str> = self
+            .chain(locale)
+            .iter()
+            .flat_map(|locale| &self.resources[locale])
+            .flat_map(|resource| message_ids(resource))
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids.into_iter()
+            .filter_map(|id| Some((id.to_owned(), Value::String(Self::format_in(&bundle, id, &args)?))))
+            .collect()
+    }
+}
+
+/// Locales of an `Accept-Language` header, most preferred first
+pub fn parse_accept_language(header: &str) -> Vec<String> {
+    let mut locales: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let locale = parts.next()?.trim();
+            let quality = parts
+                .find_map(|parameter| parameter.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (!locale.is_empty() && locale != "*" && quality > 0.0).then(|| (normalize(locale), quality))
+        })
+        .collect();
+    // Stable, so equally preferred locales keep their order
+    locales.sort_by(|a, b| b.1.total_cmp(&a.1));
+    locales.into_iter().map(|(locale, _)| locale).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const EN: &str = "\
+# Greetings
+-brand = Matrixon
+hello = Hello { $name }, welcome to { -brand }!
+farewell =
+    Goodbye,
+      see you { $when }.
+braces = Use { \"{\" } and { \"}\" }
+";
+
+    #[test]
+    fn test_format_with_fallback() {
+        let mut catalog = Catalog::new("en");
+        catalog.add_resource("en", EN).unwrap();
+        catalog.add_resource("de", "hello = Hallo { $name }, willkommen bei { -brand }!").unwrap();
+
+        let args = json!({ "name": "Alice", "when": 3 });
+        assert_eq!(catalog.format("de-AT", "hello", &args).unwrap(), "Hallo Alice, willkommen bei Matrixon!");
+        assert_eq!(catalog.format("de", "farewell", &args).unwrap(), "Goodbye,\n  see you 3.");
+        assert_eq!(catalog.format("en", "hello", &json!({})).unwrap(), "Hello {$name}, welcome to Matrixon!");
+        assert_eq!(catalog.format("en", "braces", &json!({})).unwrap(), "Use { and }");
+        // Terms are only usable from messages
+        assert!(catalog.format("en", "-brand", &json!({})).is_none());
+        assert!(catalog.format("en", "missing", &json!({})).is_none());
+
+        assert!(catalog.translates("de_DE", "hello"));
+        assert!(!catalog.translates("de", "farewell"));
+        assert!(!catalog.translates("en", "hello"));
+
+        // Unknown locales use the default one before the source locale
+        let catalog = catalog.with_default_locale("de");
+        assert_eq!(catalog.format("ja", "hello", &args).unwrap(), "Hallo Alice, willkommen bei Matrixon!");
+        assert_eq!(catalog.format("ja", "farewell", &args).unwrap(), "Goodbye,\n  see you 3.");
+        assert_eq!(catalog.negotiate(["ja"]), "de");
+        let messages = catalog.messages("de", &args);
+        assert_eq!(messages.len(), 3);
+        assert!(!messages.contains_key("-brand"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("no equals sign").is_err());
+        assert!(parse("1st = invalid id").is_err());
+        assert!(parse("ok = fine\nbroken = { $n").is_err());
+    }
+
+    #[test]
+    fn test_plurals() {
+        let mut catalog = Catalog::new("en");
+        catalog
+            .add_resource("en", "unread = { $count ->\n    [one] One unread message\n   *[other] { $count } unread messages\n}")
+            .unwrap();
+        assert_eq!(catalog.format("en", "unread", &json!({ "count": 1 })).unwrap(), "One unread message");
+        assert_eq!(catalog.format("en", "unread", &json!({ "count": 5 })).unwrap(), "5 unread messages");
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let mut catalog = Catalog::new("en");
+        for locale in ["en", "fr", "pt-BR"] {
+            catalog.add_resource(locale, "hello = hi").unwrap();
+        }
+        let requested = parse_accept_language("de-DE;q=0.9, fr-CA;q=0.8, *;q=0.5, pt-BR");
+        assert_eq!(requested, ["pt-br", "de-de", "fr-ca"]);
+        assert_eq!(catalog.negotiate(requested.iter().map(String::as_str)), "pt-br");
+        assert_eq!(catalog.negotiate(["de", "fr-CA"]), "fr");
+        assert_eq!(catalog.negotiate(["ja"]), "en");
+        assert_eq!(catalog.locales(), ["en", "fr", "pt-br"]);
+    }
+
+    #[test]
+    fn test_load_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("nl")).unwrap();
+        fs::write(dir.path().join("nl").join("pages.ftl"), "hello = Hallo").unwrap();
+        fs::write(dir.path().join("README"), "not a locale").unwrap();
+        let mut catalog = Catalog::new("en");
+        catalog.load_dir(dir.path()).unwrap();
+        assert_eq!(catalog.format("nl", "hello", &json!({})).unwrap(), "Hallo");
+
+        fs::write(dir.path().join("nl").join("broken.ftl"), "oops").unwrap();
+        assert!(Catalog::new("en").load_dir(dir.path()).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+//! Mutual TLS between internal components
+//!
+//! Internal HTTP surfaces (the monitor API, the admin interface, metrics
+//! listeners, worker RPC) can require TLS with client certificates, so their
+//! endpoints are neither readable nor callable in plaintext inside a cluster.
+//! Every component holds a certificate issued by a private CA and only
+//! accepts peers presenting a certificate of the same CA. [`InternalCa`] is a
+//! small helper creating that CA and issuing the component certificates.
+
+use std::{
+    fs,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use openssl::{
+    asn1::{Asn1Integer, Asn1Time},
+    bn::{BigNum, MsbOption},
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    x509::{
+        extension::{
+            AuthorityKeyIdentifier, BasicConstraints, ExtendedKeyUsage, KeyUsage, SubjectAlternativeName,
+            SubjectKeyIdentifier,
+        },
+        X509Builder, X509NameBuilder, X509,
+    },
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::{server::WebPkiClientVerifier, RootCertStore, ServerConfig};
+pub use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info};
+
+use crate::error::{MatrixonError, Result};
+
+/// Validity of the internal CA
+const CA_VALIDITY_DAYS: u32 = 10 * 365;
+
+/// Validity of component certificates
+const CERT_VALIDITY_DAYS: u32 = 365;
+
+/// Time allowed for a TLS handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Certificates of an internal component, all PEM files
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InternalTlsConfig {
+    /// CA issuing the certificates of all internal components; only peers
+    /// with a certificate of this CA are accepted
+    pub ca_cert: PathBuf,
+    /// Certificate of this component, presented as server and as client
+    pub cert: PathBuf,
+    /// Private key of `cert`
+    pub key: PathBuf,
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).map_err(|e| MatrixonError::Config(format!("{}: {}", path.display(), e)))
+}
+
+fn tls_error(e: impl std::fmt::Display) -> MatrixonError {
+    MatrixonError::Config(format!("Invalid internal TLS setup: {}", e))
+}
+
+/// Server side TLS settings requiring a client certificate of the internal CA
+pub fn server_config(config: &InternalTlsConfig) -> Result<Arc<ServerConfig>> {
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut BufReader::new(read(&config.ca_cert)?.as_slice())) {
+        roots.add(cert?).map_err(tls_error)?;
+    }
+    let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build().map_err(tls_error)?;
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(read(&config.cert)?.as_slice())).collect::<io::Result<Vec<_>>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(read(&config.key)?.as_slice()))?
+        .ok_or_else(|| MatrixonError::Config(format!("{}: no private key", config.key.display())))?;
+    let server_config = ServerConfig::builder()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(certs, key)
+        .map_err(tls_error)?;
+    Ok(Arc::new(server_config))
+}
+
+/// Acceptor of TLS connections from other internal components
+pub fn acceptor(config: &InternalTlsConfig) -> Result<TlsAcceptor> {
+    server_config(config).map(TlsAcceptor::from)
+}
+
+/// HTTP client for calling other internal components: it trusts only the
+/// internal CA and authenticates with the component certificate
+pub fn client(config: &InternalTlsConfig) -> Result<reqwest::Client> {
+    let ca = reqwest::Certificate::from_pem(&read(&config.ca_cert)?).map_err(tls_error)?;
+    let identity = reqwest::Identity::from_pkcs8_pem(&read(&config.cert)?, &read(&config.key)?).map_err(tls_error)?;
+    reqwest::Client::builder()
+        .tls_built_in_root_certs(false)
+        .add_root_certificate(ca)
+        .identity(identity)
+        .build()
+        .map_err(tls_error)
+}
+
+/// Serve `app` over TLS on `listener`, only to clients with a certificate
+/// of the internal CA
+pub async fn serve(listener: TcpListener, app: Router, tls: Arc<ServerConfig>) -> Result<()> {
+    let acceptor = TlsAcceptor::from(tls);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("❌ Accepting an internal connection failed: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    debug!("Rejected internal connection from {}: {}", peer, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("TLS handshake with {} timed out", peer);
+                    return;
+                }
+            };
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(app));
+            if let Err(e) = connection.await {
+                debug!("Internal connection from {} closed: {}", peer, e);
+            }
+        });
+    }
+}
+
+fn openssl_error(e: openssl::error::ErrorStack) -> MatrixonError {
+    MatrixonError::Internal(format!("Certificate generation failed: {}", e))
+}
+
+/// Write a private key, readable by the owner only before any of it is written
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    use std::io::Write;
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to new files
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+/// Private CA of the internal components
+pub struct InternalCa {
+    cert: X509,
+    key: PKey<Private>,
+}
+
+impl std::fmt::Debug for InternalCa {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("InternalCa").field("subject", &self.cert.subject_name()).finish_non_exhaustive()
+    }
+}
+
+impl InternalCa {
+    /// File names of the CA in its directory
+    pub const CERT_FILE: &'static str = "ca.crt";
+    pub const KEY_FILE: &'static str = "ca.key";
+
+    /// A new CA named `name`
+    pub fn generate(name: &str) -> Result<Self> {
+        Self::build(name).map_err(openssl_error)
+    }
+
+    fn build(name: &str) -> std::result::Result<Self, openssl::error::ErrorStack> {
+        let key = new_key()?;
+        let mut builder = cert_builder(name, &key, CA_VALIDITY_DAYS)?;
+        builder.set_issuer_name(subject_name(name)?.as_ref())?;
+        builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+        builder.append_extension(KeyUsage::new().critical().key_cert_sign().crl_sign().build()?)?;
+        let subject_key_id = SubjectKeyIdentifier::new().build(&builder.x509v3_context(None, None))?;
+        builder.append_extension(subject_key_id)?;
+        builder.sign(&key, MessageDigest::sha256())?;
+        Ok(Self { cert: builder.build(), key })
+    }
+
+    /// The CA kept in `dir`, created there first if missing
+    pub fn load_or_generate(dir: &Path, name: &str) -> Result<Self> {
+        let cert_path = dir.join(Self::CERT_FILE);
+        let key_path = dir.join(Self::KEY_FILE);
+        if cert_path.exists() {
+            let cert = X509::from_pem(&read(&cert_path)?).map_err(openssl_error)?;
+            let key = PKey::private_key_from_pem(&read(&key_path)?).map_err(openssl_error)?;
+            return Ok(Self { cert, key });
+        }
+        let ca = Self::generate(name)?;
+        fs::create_dir_all(dir)?;
+        fs::write(&cert_path, ca.cert.to_pem().map_err(openssl_error)?)?;
+        write_private(&key_path, &ca.key.private_key_to_pem_pkcs8().map_err(openssl_error)?)?;
+        info!("🔐 Created the internal CA in {}", dir.display());
+        Ok(ca)
+    }
+
+    /// Certificate of the CA, PEM encoded
+    pub fn cert_pem(&self) -> Result<Vec<u8>> {
+        self.cert.to_pem().map_err(openssl_error)
+    }
+
+    /// Issue a certificate and key, both PEM encoded, for component `name`
+    /// reachable at `hosts` (DNS names or IP addresses)
+    pub fn issue(&self, name: &str, hosts: &[String]) -> Result<(Vec<u8>, Vec<u8>)> {
+        self.build_leaf(name, hosts).map_err(openssl_error)
+    }
+
+    fn build_leaf(&self, name: &str, hosts: &[String]) -> std::result::Result<(Vec<u8>, Vec<u8>), openssl::error::ErrorStack> {
+        let key = new_key()?;
+        let mut builder = cert_builder(name, &key, CERT_VALIDITY_DAYS)?;
+        builder.set_issuer_name(self.cert.subject_name())?;
+        builder.append_extension(BasicConstraints::new().critical().build()?)?;
+        builder.append_extension(KeyUsage::new().critical().digital_signature().build()?)?;
+        builder.append_extension(ExtendedKeyUsage::new().server_auth().client_auth().build()?)?;
+        let mut alt_names = SubjectAlternativeName::new();
+        for host in hosts {
+            if host.parse::<std::net::IpAddr>().is_ok() {
+                alt_names.ip(host);
+            } else {
+                alt_names.dns(host);
+            }
+        }
+        let alt_names = alt_names.build(&builder.x509v3_context(Some(&self.cert), None))?;
+        builder.append_extension(alt_names)?;
+        let authority_key_id = AuthorityKeyIdentifier::new().keyid(false).build(&builder.x509v3_context(Some(&self.cert), None))?;
+        builder.append_extension(authority_key_id)?;
+        builder.sign(&self.key, MessageDigest::sha256())?;
+        Ok((builder.build().to_pem()?, key.private_key_to_pem_pkcs8()?))
+    }
+
+    /// Issue a certificate for component `name` into `dir`, next to a copy
+    /// of the CA certificate, and return the settings pointing at them
+    pub fn issue_to(&self, dir: &Path, name: &str, hosts: &[String]) -> Result<InternalTlsConfig> {
+        let (cert, key) = self.issue(name, hosts)?;
+        let config = InternalTlsConfig {
+            ca_cert: dir.join(Self::CERT_FILE),
+            cert: dir.join(format!("{}.crt", name)),
+            key: dir.join(format!("{}.key", name)),
+        };
+        fs::create_dir_all(dir)?;
+        if !config.ca_cert.exists() {
+            fs::write(&config.ca_cert, self.cert_pem()?)?;
+        }
+        fs::write(&config.cert, cert)?;
+        write_private(&config.key, &key)?;
+        info!("🔐 Issued an internal certificate for {} ({})", name, hosts.join(", "));
+        Ok(config)
+    }
+}
+
+fn new_key() -> std::result::Result<PKey<Private>, openssl::error::ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+fn subject_name(name: &str) -> std::result::Result<openssl::x509::X509Name, openssl::error::ErrorStack> {
+    let mut subject = X509NameBuilder::new()?;
+    subject.append_entry_by_nid(Nid::COMMONNAME, name)?;
+    Ok(subject.build())
+}
+
+fn cert_builder(name: &str, key: &PKey<Private>, days: u32) -> std::result::Result<X509Builder, openssl::error::ErrorStack> {
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    let mut serial = BigNum::new()?;
+    serial.rand(127, MsbOption::MAYBE_ZERO, false)?;
+    builder.set_serial_number(Asn1Integer::from_bn(&serial)?.as_ref())?;
+    builder.set_subject_name(subject_name(name)?.as_ref())?;
+    builder.set_pubkey(key)?;
+    builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    builder.set_not_after(Asn1Time::days_from_now(days)?.as_ref())?;
+    Ok(builder)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_only_clients_of_the_internal_ca_are_served() {
+        let dir = std::env::temp_dir().join(format!("matrixon-internal-tls-{}", uuid::Uuid::now_v7()));
+        let ca = InternalCa::load_or_generate(&dir, "Matrixon internal CA").unwrap();
+        let hosts = ["localhost".to_owned(), "127.0.0.1".to_owned()];
+        let server = ca.issue_to(&dir, "monitor", &hosts).unwrap();
+        let worker = ca.issue_to(&dir, "worker", &hosts).unwrap();
+        #[cfg(unix)]
+        for key in [dir.join(InternalCa::KEY_FILE), server.key.clone()] {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(key).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        // The CA is reused rather than replaced
+        assert_eq!(InternalCa::load_or_generate(&dir, "other").unwrap().cert_pem().unwrap(), ca.cert_pem().unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app = Router::new().route("/health", axum::routing::get(|| async { "ok" }));
+        tokio::spawn(serve(listener, app, server_config(&server).unwrap()));
+        let url = format!("https://localhost:{}/health", port);
+
+        let response = client(&worker).unwrap().get(&url).send().await.unwrap();
+        assert_eq!(response.text().await.unwrap(), "ok");
+
+        // Without a client certificate the handshake fails
+        let ca_cert = reqwest::Certificate::from_pem(&ca.cert_pem().unwrap()).unwrap();
+        let anonymous = reqwest::Client::builder().add_root_certificate(ca_cert).build().unwrap();
+        assert!(anonymous.get(&url).send().await.is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -15,3 +15,7 @@ mod tests {
 
 pub mod utils;
 pub mod error;
+pub mod internal_tls;
+pub mod template;
+pub mod i18n;
+pub mod rate_limit;
@@ -0,0 +1,392 @@
+//! Rate limiting
+//!
+//! A [`RateLimiter`] throttles actions per key, e.g. per user, per remote
+//! server or per device, with one of two algorithms:
+//!
+//! - a token bucket ([`RateLimit::TokenBucket`]) allows bursts of up to
+//!   `burst` actions and refills one token every `refill_every`. It is kept
+//!   as the time the bucket will be full again, so each key costs one
+//!   `Instant` and no timer.
+//! - a sliding window ([`RateLimit::SlidingWindow`]) allows `limit` actions
+//!   in any `window`, estimated from the counts of the current and the
+//!   previous fixed window.
+//!
+//! Keys are spread over independently locked shards so that busy limiters
+//! do not serialize on one lock. Rejections tell how long to wait, which
+//! [`RateLimiter::acquire`] does asynchronously. Every decision is counted,
+//! and can be reported to a [`RateLimitHook`] to export it as metrics.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Shards of a limiter unless told otherwise
+pub const DEFAULT_SHARDS: usize = 16;
+/// Refill period of token buckets that are never meant to refill
+pub const NEVER_REFILL: Duration = Duration::from_secs(u32::MAX as u64);
+
+/// How many actions a key may take
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "algorithm", rename_all = "snake_case")]
+pub enum RateLimit {
+    /// Up to `burst` actions at once, then one every `refill_every`
+    TokenBucket {
+        burst: u32,
+        #[serde(with = "millis")]
+        refill_every: Duration,
+    },
+    /// Up to `limit` actions in any `window`; a limit of zero allows none
+    SlidingWindow {
+        limit: u32,
+        #[serde(with = "millis")]
+        window: Duration,
+    },
+}
+
+impl RateLimit {
+    /// One action per `period`; a zero period does not limit
+    pub fn cooldown(period: Duration) -> Self {
+        Self::TokenBucket { burst: 1, refill_every: period }
+    }
+
+    /// Up to `burst` actions at once, refilled at `per_second`. Rates that
+    /// are not positive, or too slow to tell apart from that, never refill.
+    pub fn per_second(per_second: f64, burst: u32) -> Self {
+        let refill_every = if per_second > 0.0 {
+            Duration::try_from_secs_f64(1.0 / per_second).map_or(NEVER_REFILL, |period| period.min(NEVER_REFILL))
+        } else {
+            NEVER_REFILL
+        };
+        Self::TokenBucket { burst, refill_every }
+    }
+
+    fn new_state(&self, now: Instant) -> State {
+        match self {
+            Self::TokenBucket { .. } => State::TokenBucket { full_at: now },
+            Self::SlidingWindow { .. } => State::SlidingWindow { start: now, current: 0, previous: 0 },
+        }
+    }
+}
+
+mod millis {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(duration.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
+
+/// Limiter state of one key
+#[derive(Debug, Clone, Copy)]
+enum State {
+    /// When the bucket holds `burst` tokens again
+    TokenBucket { full_at: Instant },
+    /// Actions in the fixed window starting at `start` and in the one
+    /// before it
+    SlidingWindow { start: Instant, current: u32, previous: u32 },
+}
+
+impl State {
+    /// Take one action at `now`, or tell how long to wait for it
+    fn try_take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        match (self, *limit) {
+            (State::TokenBucket { full_at }, RateLimit::TokenBucket { burst, refill_every }) => {
+                let Some(next) = (*full_at).max(now).checked_add(refill_every) else {
+                    return Err(refill_every);
+                };
+                let capacity = refill_every.saturating_mul(burst.max(1));
+                let ahead = next - now;
+                if ahead > capacity {
+                    return Err(ahead - capacity);
+                }
+                *full_at = next;
+                Ok(())
+            }
+            (State::SlidingWindow { start, current, previous }, RateLimit::SlidingWindow { limit, window }) => {
+                if window.is_zero() {
+                    return Ok(());
+                }
+                // Nothing to wait for
+                if limit == 0 {
+                    return Err(Duration::MAX);
+                }
+                let elapsed = now.saturating_duration_since(*start);
+                if elapsed >= window.saturating_mul(2) {
+                    (*start, *current, *previous) = (now, 0, 0);
+                } else if elapsed >= window {
+                    (*start, *previous, *current) = (*start + window, *current, 0);
+                }
+                let into_window = now.saturating_duration_since(*start).as_secs_f64() / window.as_secs_f64();
+                let estimate = *previous as f64 * (1.0 - into_window) + *current as f64;
+                if estimate + 1.0 <= limit as f64 {
+                    *current += 1;
+                    return Ok(());
+                }
+                // The previous window's share has to shrink enough, or
+                // this window has to end when it alone is full
+                let ready_at = if *current + 1 > limit || *previous == 0 {
+                    window
+                } else {
+                    window.mul_f64(1.0 - (limit - *current - 1) as f64 / *previous as f64)
+                };
+                let wait = start.checked_add(ready_at).map_or(ready_at, |ready| ready.saturating_duration_since(now));
+                Err(wait.max(Duration::from_millis(1)))
+            }
+            (state, limit) => {
+                *state = limit.new_state(now);
+                state.try_take(&limit, now)
+            }
+        }
+    }
+
+    /// Whether the key is back to its initial state at `now`
+    fn is_idle(&self, limit: &RateLimit, now: Instant) -> bool {
+        match (self, limit) {
+            (State::TokenBucket { full_at }, _) => *full_at <= now,
+            (State::SlidingWindow { start, .. }, RateLimit::SlidingWindow { window, .. }) => {
+                now.saturating_duration_since(*start) >= window.saturating_mul(2)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Receives every decision of a limiter, e.g. to count them as metrics
+pub trait RateLimitHook: Send + Sync {
+    fn record(&self, limiter: &str, allowed: bool);
+}
+
+/// Decisions of a limiter so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct RateLimitStats {
+    pub allowed: u64,
+    pub limited: u64,
+    /// Keys with state, including idle ones not pruned yet
+    pub keys: usize,
+}
+
+/// Limits the actions of each key, see the module documentation
+pub struct RateLimiter<K = String> {
+    name: String,
+    limit: RateLimit,
+    shards: Box<[Mutex<HashMap<K, State>>]>,
+    hook: Option<Arc<dyn RateLimitHook>>,
+    allowed: AtomicU64,
+    limited: AtomicU64,
+}
+
+impl<K> std::fmt::Debug for RateLimiter<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("name", &self.name)
+            .field("limit", &self.limit)
+            .field("shards", &self.shards.len())
+            .finish()
+    }
+}
+
+impl<K: Hash + Eq + Clone> RateLimiter<K> {
+    /// A limiter reported as `name`, with [`DEFAULT_SHARDS`] shards
+    pub fn new(name: impl Into<String>, limit: RateLimit) -> Self {
+        Self::with_shards(name, limit, DEFAULT_SHARDS)
+    }
+
+    pub fn with_shards(name: impl Into<String>, limit: RateLimit, shards: usize) -> Self {
+        Self {
+            name: name.into(),
+            limit,
+            shards: (0..shards.max(1)).map(|_| Mutex::new(HashMap::new())).collect(),
+            hook: None,
+            allowed: AtomicU64::new(0),
+            limited: AtomicU64::new(0),
+        }
+    }
+
+    /// Report every decision to `hook`
+    pub fn with_hook(mut self, hook: Arc<dyn RateLimitHook>) -> Self {
+        self.hook = Some(hook);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// Take one action for `key`, or tell how long to wait until it may
+    pub fn check(&self, key: &K) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &K, now: Instant) -> Result<(), Duration> {
+        let result = {
+            let mut shard = self.shard(key).lock().unwrap();
+            match shard.get_mut(key) {
+                Some(state) => state.try_take(&self.limit, now),
+                None => {
+                    let mut state = self.limit.new_state(now);
+                    let result = state.try_take(&self.limit, now);
+                    shard.insert(key.clone(), state);
+                    result
+                }
+            }
+        };
+        let counter = if result.is_ok() { &self.allowed } else { &self.limited };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if let Some(hook) = &self.hook {
+            hook.record(&self.name, result.is_ok());
+        }
+        result
+    }
+
+    /// Wait until `key` may take one action, then take it
+    pub async fn acquire(&self, key: &K) {
+        while let Err(wait) = self.check(key) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Forget the actions of `key`
+    pub fn reset(&self, key: &K) {
+        self.shard(key).lock().unwrap().remove(key);
+    }
+
+    /// Forget the keys back to their initial state, returning how many
+    /// were forgotten. Long-lived limiters should call this periodically.
+    pub fn prune(&self) -> usize {
+        let now = Instant::now();
+        self.shards
+            .iter()
+            .map(|shard| {
+                let mut shard = shard.lock().unwrap();
+                let before = shard.len();
+                shard.retain(|_, state| !state.is_idle(&self.limit, now));
+                before - shard.len()
+            })
+            .sum()
+    }
+
+    pub fn stats(&self) -> RateLimitStats {
+        RateLimitStats {
+            allowed: self.allowed.load(Ordering::Relaxed),
+            limited: self.limited.load(Ordering::Relaxed),
+            keys: self.shards.iter().map(|shard| shard.lock().unwrap().len()).sum(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Mutex<HashMap<K, State>> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_allows_bursts_then_refills() {
+        let limiter = RateLimiter::new("test", RateLimit::TokenBucket { burst: 3, refill_every: Duration::from_secs(1) });
+        let now = Instant::now();
+        let key = "@alice:matrixon.local".to_owned();
+        for _ in 0..3 {
+            assert!(limiter.check_at(&key, now).is_ok());
+        }
+        assert_eq!(limiter.check_at(&key, now), Err(Duration::from_secs(1)));
+        assert_eq!(limiter.check_at(&key, now + Duration::from_millis(400)), Err(Duration::from_millis(600)));
+        assert!(limiter.check_at(&key, now + Duration::from_secs(1)).is_ok());
+        // Other keys have their own bucket
+        assert!(limiter.check_at(&"@bob:matrixon.local".to_owned(), now).is_ok());
+
+        let cooldown = RateLimiter::new("cooldown", RateLimit::cooldown(Duration::ZERO));
+        assert!((0..10).all(|_| cooldown.check_at(&"ping".to_owned(), now).is_ok()));
+    }
+
+    #[test]
+    fn test_limits_that_allow_nothing_more_do_not_overflow() {
+        let now = Instant::now();
+        let key = "@alice:matrixon.local".to_owned();
+        for rate in [0.0, -1.0, f64::NAN, f64::MIN_POSITIVE] {
+            assert_eq!(RateLimit::per_second(rate, 2), RateLimit::TokenBucket { burst: 2, refill_every: NEVER_REFILL });
+            let limiter = RateLimiter::new("stopped", RateLimit::per_second(rate, 2));
+            assert!(limiter.check_at(&key, now).is_ok());
+            assert!(limiter.check_at(&key, now).is_ok());
+            assert_eq!(limiter.check_at(&key, now), Err(NEVER_REFILL));
+        }
+
+        let forever = RateLimiter::new("forever", RateLimit::TokenBucket { burst: u32::MAX, refill_every: Duration::MAX });
+        assert_eq!(forever.check_at(&key, now), Err(Duration::MAX));
+
+        let closed = RateLimiter::new("closed", RateLimit::SlidingWindow { limit: 0, window: Duration::from_secs(1) });
+        assert_eq!(closed.check_at(&key, now), Err(Duration::MAX));
+        let long = RateLimiter::new("long", RateLimit::SlidingWindow { limit: 1, window: Duration::MAX });
+        assert!(long.check_at(&key, now).is_ok());
+        assert!(long.check_at(&key, now + Duration::from_secs(3600)).is_err());
+        assert_eq!(long.prune(), 0);
+    }
+
+    #[test]
+    fn test_sliding_window_weighs_the_previous_window() {
+        let limiter = RateLimiter::new("test", RateLimit::SlidingWindow { limit: 4, window: Duration::from_secs(10) });
+        let now = Instant::now();
+        let key = "remote.example".to_owned();
+        for _ in 0..4 {
+            assert!(limiter.check_at(&key, now).is_ok());
+        }
+        assert_eq!(limiter.check_at(&key, now + Duration::from_secs(5)), Err(Duration::from_secs(5)));
+        // A quarter into the next window, 3 of the previous 4 still count
+        assert!(limiter.check_at(&key, now + Duration::from_millis(12_500)).is_ok());
+        assert_eq!(limiter.check_at(&key, now + Duration::from_millis(12_500)), Err(Duration::from_millis(2_500)));
+        assert!(limiter.check_at(&key, now + Duration::from_secs(30)).is_ok());
+        assert_eq!(limiter.stats(), RateLimitStats { allowed: 6, limited: 2, keys: 1 });
+    }
+
+    #[derive(Default)]
+    struct Counting(AtomicU64, AtomicU64);
+
+    impl RateLimitHook for Counting {
+        fn record(&self, limiter: &str, allowed: bool) {
+            assert_eq!(limiter, "hooked");
+            let counter = if allowed { &self.0 } else { &self.1 };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_and_hooks_see_every_decision() {
+        let hook = Arc::new(Counting::default());
+        let limiter = RateLimiter::with_shards("hooked", RateLimit::cooldown(Duration::from_millis(20)), 2).with_hook(hook.clone());
+        let key = "device".to_owned();
+        let start = Instant::now();
+        limiter.acquire(&key).await;
+        limiter.acquire(&key).await;
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(hook.0.load(Ordering::Relaxed), 2);
+        assert!(hook.1.load(Ordering::Relaxed) >= 1);
+
+        limiter.reset(&key);
+        assert!(limiter.check(&key).is_ok());
+        tokio::time::sleep(Duration::from_millis(25)).await;
+        assert_eq!(limiter.prune(), 1);
+        assert_eq!(limiter.stats().keys, 0);
+    }
+}
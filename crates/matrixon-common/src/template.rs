@@ -0,0 +1,130 @@
+//! Text and HTML templates
+//!
+//! A deliberately small template language shared by emails and the pages
+//! the server renders itself:
+//!
+//! - `{{ name }}` inserts a variable, HTML-escaped when rendering HTML
+//! - `{{{ name }}}` inserts a variable as is, e.g. HTML rendered before
+//! - `{{#if name}}...{{/if}}` keeps its content if the variable is set and
+//!   neither `null`, `false`, an empty string nor an empty list
+//! - `{{#each list}}...{{/each}}` repeats its content for every object of
+//!   a list, whose fields are then visible as variables next to the outer
+//!   ones
+//!
+//! Blocks of the same kind do not nest.
+
+use serde_json::{Map, Value};
+
+use crate::error::{MatrixonError, Result};
+
+/// Render `template` with the fields of the object `vars`. With `html`
+/// set, `{{ name }}` insertions are escaped.
+pub fn render(template: &str, vars: &Value, html: bool) -> Result<String> {
+    let empty = Map::new();
+    render_with(template, vars.as_object().unwrap_or(&empty), html)
+}
+
+fn render_with(template: &str, vars: &Map<String, Value>, html: bool) -> Result<String> {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let raw = rest[start..].starts_with("{{{");
+        let (open, close) = if raw { ("{{{", "}}}") } else { ("{{", "}}") };
+        let end = rest[start..]
+            .find(close)
+            .map(|end| start + end)
+            .ok_or_else(|| MatrixonError::Config(format!("Unclosed {} in template", open)))?;
+        let tag = rest[start + open.len()..end].trim();
+        rest = &rest[end + close.len()..];
+
+        if let Some(list) = tag.strip_prefix("#each ") {
+            let (body, after) = block(rest, "{{/each}}", list)?;
+            rest = after;
+            for item in vars.get(list.trim()).and_then(Value::as_array).into_iter().flatten() {
+                let mut scope = vars.clone();
+                if let Some(fields) = item.as_object() {
+                    scope.extend(fields.clone());
+                }
+                output.push_str(&render_with(body, &scope, html)?);
+            }
+        } else if let Some(condition) = tag.strip_prefix("#if ") {
+            let (body, after) = block(rest, "{{/if}}", condition)?;
+            rest = after;
+            if vars.get(condition.trim()).is_some_and(is_truthy) {
+                output.push_str(&render_with(body, vars, html)?);
+            }
+        } else {
+            let value = match vars.get(tag) {
+                Some(Value::String(value)) => value.clone(),
+                Some(Value::Null) | None => String::new(),
+                Some(value) => value.to_string(),
+            };
+            if html && !raw {
+                output.push_str(&escape_html(&value));
+            } else {
+                output.push_str(&value);
+            }
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Split `rest` at the closing tag of a block
+fn block<'a>(rest: &'a str, close: &str, name: &str) -> Result<(&'a str, &'a str)> {
+    let end = rest
+        .find(close)
+        .ok_or_else(|| MatrixonError::Config(format!("Unclosed block {} in template", name.trim())))?;
+    Ok((&rest[..end], &rest[end + close.len()..]))
+}
+
+fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null | Value::Bool(false) => false,
+        Value::String(value) => !value.is_empty(),
+        Value::Array(values) => !values.is_empty(),
+        _ => true,
+    }
+}
+
+/// Escape text for HTML element content and attribute values
+pub fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render_blocks_and_escaping() {
+        let template = "<h1>{{ title }}</h1>{{{ body }}}{{#if error}}<p>{{ error }}</p>{{/if}}\
+                        <ul>{{#each items}}<li>{{ name }} of {{ title }}</li>{{/each}}</ul>";
+        let vars = json!({
+            "title": "Tom & Jerry",
+            "body": "<p>raw</p>",
+            "error": "",
+            "items": [{ "name": "<a>" }, { "name": "b" }],
+        });
+        assert_eq!(
+            render(template, &vars, true).unwrap(),
+            "<h1>Tom &amp; Jerry</h1><p>raw</p><ul><li>&lt;a&gt; of Tom &amp; Jerry</li><li>b of Tom &amp; Jerry</li></ul>"
+        );
+        assert!(render(template, &json!({ "error": "oops" }), false).unwrap().contains("<p>oops</p>"));
+        assert!(render("{{ unclosed", &vars, true).is_err());
+        assert!(render("{{#if error}}never closed", &vars, true).is_err());
+    }
+}
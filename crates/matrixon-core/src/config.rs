@@ -3,9 +3,15 @@
 //! This module defines the configuration structures used throughout the Matrixon system.
 //! These configurations are used to customize the behavior of various components
 //! and can be loaded from files or environment variables.
+//!
+//! The other crates of the workspace keep their own configuration types and
+//! implement [`ConfigSection`], so the server reads each of them from its
+//! own table of a single configuration file through [`ConfigSections`].
 
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, path::PathBuf};
+use tracing::warn;
 use crate::Result;
 
 /// Server configuration
@@ -282,6 +288,111 @@ impl ServerConfig {
     }
 }
 
+/// Configuration of a crate, read from its own table of the server's
+/// configuration file
+///
+/// A section is layered: the crate's defaults, overlaid with the keys its
+/// table sets, which the server has already overlaid with the
+/// `MATRIXON_<SECTION>__<KEY>` environment variables. Keys the type does
+/// not know are reported rather than rejected.
+pub trait ConfigSection: Default + Serialize + DeserializeOwned {
+    /// Name of the table, e.g. `iot` for `[global.iot]`
+    const SECTION: &'static str;
+}
+
+/// A section read from its table, with the keys the type ignored
+#[derive(Debug, Clone)]
+pub struct LoadedSection<T> {
+    pub config: T,
+    /// Dotted paths of the keys the type does not know
+    pub unknown_keys: Vec<String>,
+}
+
+/// Raw tables of the crate sections of a configuration file
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSections {
+    tables: BTreeMap<String, Value>,
+}
+
+impl ConfigSections {
+    pub fn new(tables: impl IntoIterator<Item = (String, Value)>) -> Self {
+        Self { tables: tables.into_iter().collect() }
+    }
+
+    /// Raw table of `section`, if the file has one
+    pub fn table(&self, section: &str) -> Option<&Value> {
+        self.tables.get(section)
+    }
+
+    /// Names of the sections the file has
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.tables.keys().map(String::as_str)
+    }
+
+    /// The section of `T`, its defaults when the file has no such table,
+    /// logging the keys it does not know
+    pub fn load<T: ConfigSection>(&self) -> Result<T> {
+        let loaded = self.load_checked::<T>()?;
+        for key in &loaded.unknown_keys {
+            warn!("⚠️ Unknown configuration key {}.{}", T::SECTION, key);
+        }
+        Ok(loaded.config)
+    }
+
+    /// The section of `T` and the keys it does not know
+    pub fn load_checked<T: ConfigSection>(&self) -> Result<LoadedSection<T>> {
+        let Some(table) = self.table(T::SECTION) else {
+            return Ok(LoadedSection { config: T::default(), unknown_keys: Vec::new() });
+        };
+        let section_error = |e: serde_json::Error| crate::MatrixonError::Config(format!("Invalid [{}] section: {}", T::SECTION, e));
+
+        let mut layered = serde_json::to_value(T::default()).map_err(section_error)?;
+        layer(&mut layered, table.clone());
+        let config: T = serde_json::from_value(layered).map_err(section_error)?;
+        let known = serde_json::to_value(&config).map_err(section_error)?;
+        Ok(LoadedSection { unknown_keys: unknown_keys(table, &known), config })
+    }
+}
+
+/// Overlay `overrides` onto `base`: tables are merged key by key, anything
+/// else is replaced
+pub fn layer(base: &mut Value, overrides: Value) {
+    match (base, overrides) {
+        (Value::Object(base), Value::Object(overrides)) => {
+            for (key, value) in overrides {
+                match base.get_mut(&key) {
+                    Some(base) => layer(base, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overrides) => *base = overrides,
+    }
+}
+
+/// Dotted paths of the keys of `input` that `known` does not have, looking
+/// into the tables both have
+pub fn unknown_keys(input: &Value, known: &Value) -> Vec<String> {
+    let mut unknown = Vec::new();
+    collect_unknown_keys(input, known, "", &mut unknown);
+    unknown
+}
+
+fn collect_unknown_keys(input: &Value, known: &Value, prefix: &str, unknown: &mut Vec<String>) {
+    let (Value::Object(input), Value::Object(known)) = (input, known) else {
+        return;
+    };
+    for (key, value) in input {
+        let path = format!("{}{}", prefix, key);
+        match known.get(key) {
+            Some(known) => collect_unknown_keys(value, known, &format!("{}.", path), unknown),
+            None => unknown.push(path),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -339,4 +450,35 @@ mod tests {
         config.port = 0;
         assert!(config.validate().is_err());
     }
-} 
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    struct Limits {
+        max: u32,
+        burst: u32,
+    }
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    struct Sample {
+        name: String,
+        limits: Limits,
+    }
+
+    impl ConfigSection for Sample {
+        const SECTION: &'static str = "sample";
+    }
+
+    #[test]
+    fn test_sections_are_layered_over_defaults() {
+        let sections = ConfigSections::new([(
+            "sample".to_owned(),
+            serde_json::json!({ "limits": { "burst": 5, "brust": 6 }, "nmae": "typo" }),
+        )]);
+        let loaded = sections.load_checked::<Sample>().unwrap();
+        assert_eq!(loaded.config, Sample { name: String::new(), limits: Limits { max: 0, burst: 5 } });
+        assert_eq!(loaded.unknown_keys, ["limits.brust", "nmae"]);
+
+        assert_eq!(ConfigSections::default().load::<Sample>().unwrap(), Sample::default());
+        let invalid = ConfigSections::new([("sample".to_owned(), serde_json::json!({ "limits": { "max": "many" } }))]);
+        assert!(invalid.load::<Sample>().is_err());
+    }
+}
@@ -0,0 +1,273 @@
+//! Health probes and readiness aggregation
+//!
+//! Subsystems such as the database, federation, IPFS, IoT, the bot and the
+//! monitor each register a [`HealthProbe`] with a [`HealthRegistry`]. The
+//! registry runs every probe concurrently, each within a timeout, and folds
+//! their results into one [`HealthReport`] for the server's readiness
+//! endpoint.
+//!
+//! A component is `healthy`, `degraded` (working, but impaired) or `failed`.
+//! Components are registered as critical or not: a failed critical
+//! component fails the whole report, so the server is not ready, while a
+//! failed optional component or any degraded one only degrades it.
+//!
+//! Readiness endpoints are public, so they use [`HealthRegistry::check_cached`]
+//! to run the probes at most once per period however often they are asked,
+//! and show others the [`HealthReport::redacted`] report, without the
+//! messages of the probes.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+/// How long a probe may take unless the registry is given another timeout
+pub const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Health of a component or of the whole server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    Healthy,
+    Degraded,
+    Failed,
+}
+
+/// What a probe found
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ComponentHealth {
+    pub status: HealthStatus,
+    /// Why the component is not healthy, or other details
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl ComponentHealth {
+    pub fn healthy() -> Self {
+        Self { status: HealthStatus::Healthy, message: None }
+    }
+
+    pub fn degraded(message: impl Into<String>) -> Self {
+        Self { status: HealthStatus::Degraded, message: Some(message.into()) }
+    }
+
+    pub fn failed(message: impl Into<String>) -> Self {
+        Self { status: HealthStatus::Failed, message: Some(message.into()) }
+    }
+}
+
+/// Checks the health of one subsystem
+#[async_trait]
+pub trait HealthProbe: Send + Sync {
+    async fn check(&self) -> ComponentHealth;
+}
+
+/// Result of one probe in a report
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComponentReport {
+    pub status: HealthStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub critical: bool,
+    pub latency_ms: u64,
+}
+
+/// Health of every registered component
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub components: BTreeMap<String, ComponentReport>,
+}
+
+impl HealthReport {
+    /// Whether the server can take traffic: only failed critical
+    /// components make it unready
+    pub fn is_ready(&self) -> bool {
+        self.status != HealthStatus::Failed
+    }
+
+    /// The report without the messages of the probes, which may name hosts
+    /// or carry connection errors
+    pub fn redacted(&self) -> Self {
+        let mut report = self.clone();
+        for component in report.components.values_mut() {
+            component.message = None;
+        }
+        report
+    }
+}
+
+struct Registration {
+    critical: bool,
+    probe: Arc<dyn HealthProbe>,
+}
+
+/// Probes of the registered subsystems
+pub struct HealthRegistry {
+    probes: RwLock<BTreeMap<String, Registration>>,
+    timeout: Duration,
+    /// Latest report of `check_cached` and when it was taken
+    cached: tokio::sync::Mutex<Option<(Instant, HealthReport)>>,
+}
+
+impl std::fmt::Debug for HealthRegistry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HealthRegistry")
+            .field("components", &self.components())
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
+
+impl Default for HealthRegistry {
+    fn default() -> Self {
+        Self::new(DEFAULT_PROBE_TIMEOUT)
+    }
+}
+
+impl HealthRegistry {
+    /// A registry failing probes that take longer than `timeout`
+    pub fn new(timeout: Duration) -> Self {
+        Self { probes: RwLock::new(BTreeMap::new()), timeout, cached: tokio::sync::Mutex::new(None) }
+    }
+
+    /// Register the probe of a component, replacing any probe registered
+    /// under the same name
+    pub fn register(&self, name: impl Into<String>, critical: bool, probe: Arc<dyn HealthProbe>) {
+        let name = name.into();
+        debug!("🩺 Registered health probe {} (critical: {})", name, critical);
+        self.probes.write().unwrap().insert(name, Registration { critical, probe });
+    }
+
+    /// Remove the probe of a component, returning whether there was one
+    pub fn unregister(&self, name: &str) -> bool {
+        self.probes.write().unwrap().remove(name).is_some()
+    }
+
+    /// Names of the registered components, sorted
+    pub fn components(&self) -> Vec<String> {
+        self.probes.read().unwrap().keys().cloned().collect()
+    }
+
+    /// The latest report if it is younger than `max_age`, otherwise a new
+    /// one. Concurrent callers wait for the same check.
+    pub async fn check_cached(&self, max_age: Duration) -> HealthReport {
+        let mut cached = self.cached.lock().await;
+        if let Some((taken, report)) = cached.as_ref().filter(|(taken, _)| taken.elapsed() < max_age) {
+            debug!("🩺 Reusing the health report of {:?} ago", taken.elapsed());
+            return report.clone();
+        }
+        let report = self.check().await;
+        *cached = Some((Instant::now(), report.clone()));
+        report
+    }
+
+    /// Run every probe concurrently and aggregate their results
+    pub async fn check(&self) -> HealthReport {
+        let probes: Vec<(String, bool, Arc<dyn HealthProbe>)> = self
+            .probes
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, registration)| (name.clone(), registration.critical, registration.probe.clone()))
+            .collect();
+
+        let timeout = self.timeout;
+        let checks = probes.into_iter().map(|(name, critical, probe)| async move {
+            let start = Instant::now();
+            let health = tokio::time::timeout(timeout, probe.check())
+                .await
+                .unwrap_or_else(|_| ComponentHealth::failed(format!("No answer within {} ms", timeout.as_millis())));
+            if health.status != HealthStatus::Healthy {
+                warn!("⚠️ {} is {:?}: {}", name, health.status, health.message.as_deref().unwrap_or("no details"));
+            }
+            let report = ComponentReport {
+                status: health.status,
+                message: health.message,
+                critical,
+                latency_ms: start.elapsed().as_millis() as u64,
+            };
+            (name, report)
+        });
+        let components: BTreeMap<String, ComponentReport> = futures::future::join_all(checks).await.into_iter().collect();
+
+        HealthReport { status: overall_status(components.values()), components }
+    }
+}
+
+/// Failed if a critical component failed, degraded if any other component
+/// is not healthy, healthy otherwise
+fn overall_status<'a>(components: impl IntoIterator<Item = &'a ComponentReport>) -> HealthStatus {
+    components
+        .into_iter()
+        .map(|component| match component.status {
+            HealthStatus::Failed if !component.critical => HealthStatus::Degraded,
+            status => status,
+        })
+        .max()
+        .unwrap_or(HealthStatus::Healthy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed(ComponentHealth);
+
+    #[async_trait]
+    impl HealthProbe for Fixed {
+        async fn check(&self) -> ComponentHealth {
+            self.0.clone()
+        }
+    }
+
+    struct Hanging;
+
+    #[async_trait]
+    impl HealthProbe for Hanging {
+        async fn check(&self) -> ComponentHealth {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            ComponentHealth::healthy()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_critical_components_fail_the_report() {
+        let registry = HealthRegistry::new(Duration::from_millis(50));
+        assert_eq!(registry.check().await.status, HealthStatus::Healthy);
+
+        registry.register("database", true, Arc::new(Fixed(ComponentHealth::healthy())));
+        registry.register("ipfs", false, Arc::new(Fixed(ComponentHealth::failed("unreachable"))));
+        let report = registry.check().await;
+        assert_eq!(report.status, HealthStatus::Degraded);
+        assert!(report.is_ready());
+        assert_eq!(report.components["ipfs"].message.as_deref(), Some("unreachable"));
+
+        registry.register("federation", true, Arc::new(Hanging));
+        let report = registry.check().await;
+        assert_eq!(report.status, HealthStatus::Failed);
+        assert!(!report.is_ready());
+        assert_eq!(report.components["federation"].status, HealthStatus::Failed);
+
+        assert!(registry.unregister("federation"));
+        assert_eq!(registry.components(), ["database", "ipfs"]);
+        assert_eq!(report.redacted().components["ipfs"].message, None);
+        assert_eq!(report.redacted().components["ipfs"].status, HealthStatus::Failed);
+    }
+
+    #[tokio::test]
+    async fn test_cached_reports_are_reused_until_they_expire() {
+        let registry = HealthRegistry::new(Duration::from_millis(50));
+        registry.register("database", true, Arc::new(Fixed(ComponentHealth::healthy())));
+        assert_eq!(registry.check_cached(Duration::from_secs(60)).await.status, HealthStatus::Healthy);
+
+        registry.register("database", true, Arc::new(Fixed(ComponentHealth::failed("down"))));
+        assert_eq!(registry.check_cached(Duration::from_secs(60)).await.status, HealthStatus::Healthy);
+        assert_eq!(registry.check_cached(Duration::ZERO).await.status, HealthStatus::Failed);
+    }
+}
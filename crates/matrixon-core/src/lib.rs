@@ -12,6 +12,8 @@
 //! - Error handling and logging
 //! - Configuration management
 //! - Utility functions
+//! - Outbound webhooks for server lifecycle events
+//! - Health probes aggregated into server readiness
 //! 
 //! # Examples
 //! ```rust
@@ -33,6 +35,8 @@ pub mod traits;
 pub mod utils;
 pub mod error;
 pub mod config;
+pub mod webhooks;
+pub mod health;
 
 pub use error::{MatrixonError, Result};
 
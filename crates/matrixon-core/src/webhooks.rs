@@ -0,0 +1,266 @@
+//! Outbound webhooks for server lifecycle events
+//!
+//! Lifecycle events such as a user registering or a room being created are
+//! POSTed as JSON to the configured endpoints, so external automation can
+//! react to them without polling the admin API. Each payload is signed with
+//! the endpoint's secret and failed deliveries are retried with exponential
+//! backoff.
+//!
+//! Receivers verify a delivery by computing
+//! `HMAC-SHA256(secret, "{timestamp}.{body}")` and comparing it with the
+//! `v1` value of the `X-Matrixon-Signature` header (`t={timestamp},v1={hex}`).
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{debug, info, instrument, warn};
+
+use crate::{MatrixonError, Result};
+
+/// Header carrying the payload signature
+pub const SIGNATURE_HEADER: &str = "X-Matrixon-Signature";
+/// Header carrying the event name
+pub const EVENT_HEADER: &str = "X-Matrixon-Event";
+/// Header carrying the delivery id, identical across retries
+pub const DELIVERY_HEADER: &str = "X-Matrixon-Delivery";
+
+/// A server lifecycle event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    UserRegistered { user_id: String },
+    RoomCreated { room_id: String, creator: String },
+    FederationDestinationDown { destination: String, error: String },
+    BackupCompleted { location: String, compressed: bool },
+}
+
+impl WebhookEvent {
+    /// Name of the event, as used in payloads and endpoint filters
+    pub fn name(&self) -> &'static str {
+        match self {
+            WebhookEvent::UserRegistered { .. } => "user_registered",
+            WebhookEvent::RoomCreated { .. } => "room_created",
+            WebhookEvent::FederationDestinationDown { .. } => "federation_destination_down",
+            WebhookEvent::BackupCompleted { .. } => "backup_completed",
+        }
+    }
+}
+
+/// A configured webhook endpoint
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    /// URL payloads are POSTed to
+    pub url: String,
+    /// Secret payloads are signed with
+    pub secret: String,
+    /// Events sent to this endpoint; all events when empty
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// Delivery attempts after the first one fails
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+}
+
+fn default_max_retries() -> u32 {
+    5
+}
+
+impl WebhookConfig {
+    /// Whether this endpoint subscribed to `event`
+    pub fn wants(&self, event: &WebhookEvent) -> bool {
+        self.events.is_empty() || self.events.iter().any(|name| name == event.name())
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    id: &'a str,
+    ts: u64,
+    server_name: &'a str,
+    #[serde(flatten)]
+    event: &'a WebhookEvent,
+}
+
+/// Sends lifecycle events to the configured webhook endpoints
+#[derive(Debug, Clone, Default)]
+pub struct WebhookDispatcher {
+    server_name: String,
+    endpoints: Vec<WebhookConfig>,
+    client: reqwest::Client,
+}
+
+impl WebhookDispatcher {
+    pub fn new(server_name: impl Into<String>, endpoints: Vec<WebhookConfig>) -> Self {
+        Self {
+            server_name: server_name.into(),
+            endpoints,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Send `event` in the background; returns immediately
+    pub fn notify(&self, event: WebhookEvent) {
+        if !self.endpoints.iter().any(|endpoint| endpoint.wants(&event)) {
+            return;
+        }
+        let dispatcher = self.clone();
+        tokio::spawn(async move { dispatcher.deliver(&event).await });
+    }
+
+    /// Send `event` to every subscribed endpoint, retrying failed
+    /// deliveries. Returns the number of endpoints that accepted it.
+    #[instrument(level = "debug", skip(self), fields(event = event.name()))]
+    pub async fn deliver(&self, event: &WebhookEvent) -> usize {
+        let id = uuid::Uuid::new_v4().to_string();
+        let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let body = match serde_json::to_string(&Payload {
+            id: &id,
+            ts,
+            server_name: &self.server_name,
+            event,
+        }) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("❌ Could not serialize {} webhook: {}", event.name(), e);
+                return 0;
+            }
+        };
+
+        let deliveries = self
+            .endpoints
+            .iter()
+            .filter(|endpoint| endpoint.wants(event))
+            .map(|endpoint| post_signed(&self.client, endpoint, event.name(), &id, ts, &body));
+        let delivered = futures::future::join_all(deliveries).await.into_iter().filter(|ok| *ok).count();
+        info!("🪝 Delivered {} webhook {} to {} endpoints", event.name(), id, delivered);
+        delivered
+    }
+}
+
+/// POST `body`, signed with the secret of `endpoint`, to its URL,
+/// retrying failed deliveries. `event` names the payload and `id` the
+/// delivery. Returns whether the endpoint accepted it.
+pub async fn post_signed(
+    client: &reqwest::Client,
+    endpoint: &WebhookConfig,
+    event: &str,
+    id: &str,
+    ts: u64,
+    body: &str,
+) -> bool {
+    let signature = match sign(&endpoint.secret, ts, body) {
+        Ok(signature) => signature,
+        Err(e) => {
+            warn!("❌ Could not sign webhook for {}: {}", endpoint.url, e);
+            return false;
+        }
+    };
+
+    for attempt in 0..=endpoint.max_retries {
+        if attempt > 0 {
+            tokio::time::sleep(retry_delay(attempt)).await;
+        }
+
+        let result = client
+            .post(&endpoint.url)
+            .header("Content-Type", "application/json")
+            .header(SIGNATURE_HEADER, &signature)
+            .header(EVENT_HEADER, event)
+            .header(DELIVERY_HEADER, id)
+            .body(body.to_owned())
+            .send()
+            .await;
+
+        match result {
+            Ok(response) if response.status().is_success() => {
+                debug!("✅ Webhook {} accepted by {}", id, endpoint.url);
+                return true;
+            }
+            Ok(response) if !is_retryable(response.status()) => {
+                warn!("❌ Webhook {} rejected by {}: {}", id, endpoint.url, response.status());
+                return false;
+            }
+            Ok(response) => {
+                warn!("⚠️ Webhook {} attempt {} to {} failed: {}", id, attempt + 1, endpoint.url, response.status())
+            }
+            Err(e) => warn!("⚠️ Webhook {} attempt {} to {} failed: {}", id, attempt + 1, endpoint.url, e),
+        }
+    }
+
+    warn!("❌ Giving up on webhook {} to {}", id, endpoint.url);
+    false
+}
+
+/// Value of the signature header for a payload sent at `ts`
+pub fn sign(secret: &str, ts: u64, body: &str) -> Result<String> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .map_err(|e| MatrixonError::InvalidConfig(format!("Invalid webhook secret: {}", e)))?;
+    mac.update(format!("{}.{}", ts, body).as_bytes());
+    let digest: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(format!("t={},v1={}", ts, digest))
+}
+
+/// Server errors and rate limiting are retried, other failures are final
+fn is_retryable(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Exponential backoff starting at one second, capped at a minute
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_secs(1u64 << attempt.min(6)).min(Duration::from_secs(60))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_payload_format() {
+        let event = WebhookEvent::RoomCreated {
+            room_id: "!room:matrixon.local".to_owned(),
+            creator: "@alice:matrixon.local".to_owned(),
+        };
+        let payload = serde_json::to_value(Payload {
+            id: "1",
+            ts: 42,
+            server_name: "matrixon.local",
+            event: &event,
+        })
+        .unwrap();
+        assert_eq!(payload["event"], "room_created");
+        assert_eq!(payload["room_id"], "!room:matrixon.local");
+        assert_eq!(payload["ts"], 42);
+    }
+
+    #[test]
+    fn test_signature_and_filters() {
+        let signature = sign("secret", 42, "{}").unwrap();
+        assert!(signature.starts_with("t=42,v1="));
+        assert_eq!(signature.len(), "t=42,v1=".len() + 64);
+        assert_ne!(signature, sign("other", 42, "{}").unwrap());
+
+        let endpoint = WebhookConfig {
+            events: vec!["user_registered".to_owned()],
+            ..Default::default()
+        };
+        assert!(endpoint.wants(&WebhookEvent::UserRegistered { user_id: "@a:b".to_owned() }));
+        assert!(!endpoint.wants(&WebhookEvent::BackupCompleted {
+            location: "backup.tar".to_owned(),
+            compressed: false
+        }));
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        assert!(is_retryable(reqwest::StatusCode::BAD_GATEWAY));
+        assert!(is_retryable(reqwest::StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_retryable(reqwest::StatusCode::NOT_FOUND));
+        assert_eq!(retry_delay(1), Duration::from_secs(2));
+        assert_eq!(retry_delay(10), Duration::from_secs(60));
+    }
+}
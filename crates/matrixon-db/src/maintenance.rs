@@ -0,0 +1,201 @@
+//! Routine database maintenance for Matrixon
+//!
+//! Author: arkSong <arksong2018@gmail.com>
+//! Date: 2025-06-15
+//! Version: 0.1.0
+//!
+//! Autovacuum keeps up with most tables, but the event and state tables of
+//! a busy server can pile up dead rows faster than it reclaims them. The
+//! maintenance helpers find the tables with many dead rows so they can be
+//! vacuumed and analyzed explicitly, and estimate how bloated each B-tree
+//! index is compared with a freshly built one, so bloated indexes can be
+//! reported before they slow queries down.
+
+use matrixon_core::{MatrixonError, Result};
+use serde::Serialize;
+use sqlx::{postgres::PgPool, Row};
+use tracing::{debug, instrument};
+
+use crate::query_metrics::TimedQuery;
+
+/// Dead rows of a table, from `pg_stat_user_tables`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TableStats {
+    pub schema: String,
+    pub table: String,
+    pub live_tuples: i64,
+    pub dead_tuples: i64,
+}
+
+impl TableStats {
+    /// Share of the table's rows that are dead
+    pub fn dead_ratio(&self) -> f64 {
+        let total = self.live_tuples + self.dead_tuples;
+        if total == 0 {
+            0.0
+        } else {
+            self.dead_tuples as f64 / total as f64
+        }
+    }
+}
+
+/// Size of a B-tree index compared with its estimated size when freshly
+/// built
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IndexBloat {
+    pub index: String,
+    pub table: String,
+    pub size_bytes: i64,
+    pub expected_bytes: i64,
+}
+
+impl IndexBloat {
+    /// Bytes the index is larger than needed
+    pub fn bloat_bytes(&self) -> i64 {
+        (self.size_bytes - self.expected_bytes).max(0)
+    }
+
+    /// Share of the index that is bloat
+    pub fn bloat_ratio(&self) -> f64 {
+        if self.size_bytes <= 0 {
+            0.0
+        } else {
+            self.bloat_bytes() as f64 / self.size_bytes as f64
+        }
+    }
+}
+
+/// Estimated size of every B-tree index of the current schema: each entry
+/// takes its key width plus 12 bytes of tuple header and line pointer, and
+/// pages are filled to 90%
+const INDEX_BLOAT_QUERY: &str = r#"
+    SELECT i.indexrelid::regclass::text AS index_name,
+           t.relname::text AS table_name,
+           pg_relation_size(i.indexrelid) AS size_bytes,
+           (CEIL(GREATEST(c.reltuples, 0) * (12 + COALESCE(SUM(s.avg_width), 0))
+                 / (current_setting('block_size')::float8 * 0.9)) + 1)::int8
+               * current_setting('block_size')::int8 AS expected_bytes
+    FROM pg_index i
+    JOIN pg_class c ON c.oid = i.indexrelid
+    JOIN pg_class t ON t.oid = i.indrelid
+    JOIN pg_namespace n ON n.oid = t.relnamespace
+    JOIN pg_am am ON am.oid = c.relam AND am.amname = 'btree'
+    LEFT JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = ANY(i.indkey)
+    LEFT JOIN pg_stats s ON s.schemaname = n.nspname AND s.tablename = t.relname AND s.attname = a.attname
+    WHERE n.nspname = current_schema()
+    GROUP BY i.indexrelid, t.relname, c.reltuples
+"#;
+
+fn db_error(e: sqlx::Error) -> MatrixonError {
+    MatrixonError::Database(e.to_string())
+}
+
+/// Tables with at least `min_dead_tuples` dead rows making up at least
+/// `min_dead_ratio` of the table, most dead rows first
+#[instrument(level = "debug", skip(pool))]
+pub async fn tables_needing_vacuum(pool: &PgPool, min_dead_tuples: i64, min_dead_ratio: f64) -> Result<Vec<TableStats>> {
+    let rows = sqlx::query(
+        "SELECT schemaname::text AS schema, relname::text AS table_name, n_live_tup, n_dead_tup \
+         FROM pg_stat_user_tables WHERE n_dead_tup >= $1 ORDER BY n_dead_tup DESC",
+    )
+    .bind(min_dead_tuples)
+    .fetch_all(pool)
+    .timed("maintenance::tables_needing_vacuum")
+    .await
+    .map_err(db_error)?;
+    let tables = rows
+        .iter()
+        .map(|row| {
+            Ok(TableStats {
+                schema: row.try_get("schema")?,
+                table: row.try_get("table_name")?,
+                live_tuples: row.try_get("n_live_tup")?,
+                dead_tuples: row.try_get("n_dead_tup")?,
+            })
+        })
+        .collect::<std::result::Result<Vec<_>, sqlx::Error>>()
+        .map_err(db_error)?;
+    Ok(tables.into_iter().filter(|table| table.dead_ratio() >= min_dead_ratio).collect())
+}
+
+/// Reclaim the dead rows of a table and refresh its planner statistics
+#[instrument(level = "debug", skip(pool))]
+pub async fn vacuum_analyze(pool: &PgPool, schema: &str, table: &str) -> Result<()> {
+    debug!("🧹 Vacuuming {}.{}", schema, table);
+    sqlx::query(&format!("VACUUM (ANALYZE) {}.{}", quote_ident(schema), quote_ident(table)))
+        .execute(pool)
+        .timed("maintenance::vacuum_analyze")
+        .await
+        .map_err(db_error)?;
+    Ok(())
+}
+
+/// B-tree indexes of at least `min_size_bytes` with at least
+/// `min_bloat_ratio` of bloat, most bloat first
+#[instrument(level = "debug", skip(pool))]
+pub async fn bloated_indexes(pool: &PgPool, min_size_bytes: i64, min_bloat_ratio: f64) -> Result<Vec<IndexBloat>> {
+    let rows = sqlx::query(INDEX_BLOAT_QUERY)
+        .fetch_all(pool)
+        .timed("maintenance::bloated_indexes")
+        .await
+        .map_err(db_error)?;
+    let indexes = rows
+        .iter()
+        .map(|row| {
+            Ok(IndexBloat {
+                index: row.try_get("index_name")?,
+                table: row.try_get("table_name")?,
+                size_bytes: row.try_get("size_bytes")?,
+                expected_bytes: row.try_get("expected_bytes")?,
+            })
+        })
+        .collect::<std::result::Result<Vec<_>, sqlx::Error>>()
+        .map_err(db_error)?;
+    Ok(select_bloated(indexes, min_size_bytes, min_bloat_ratio))
+}
+
+fn select_bloated(indexes: Vec<IndexBloat>, min_size_bytes: i64, min_bloat_ratio: f64) -> Vec<IndexBloat> {
+    let mut bloated: Vec<IndexBloat> = indexes
+        .into_iter()
+        .filter(|index| index.size_bytes >= min_size_bytes && index.bloat_ratio() >= min_bloat_ratio)
+        .collect();
+    bloated.sort_by_key(|index| std::cmp::Reverse(index.bloat_bytes()));
+    bloated
+}
+
+/// Quote an identifier for use in SQL
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(name: &str, size_bytes: i64, expected_bytes: i64) -> IndexBloat {
+        IndexBloat { index: name.to_owned(), table: "events".to_owned(), size_bytes, expected_bytes }
+    }
+
+    #[test]
+    fn test_bloated_indexes_are_selected_by_size_and_ratio() {
+        let indexes = vec![
+            index("small", 8192, 0),
+            index("tight", 100 << 20, 90 << 20),
+            index("bloated", 100 << 20, 20 << 20),
+            index("worse", 400 << 20, 100 << 20),
+        ];
+        let bloated = select_bloated(indexes, 1 << 20, 0.5);
+        let names: Vec<&str> = bloated.iter().map(|index| index.index.as_str()).collect();
+        assert_eq!(names, ["worse", "bloated"]);
+        assert_eq!(bloated[1].bloat_ratio(), 0.8);
+        assert_eq!(index("fresh", 8192, 16384).bloat_bytes(), 0);
+    }
+
+    #[test]
+    fn test_dead_ratio_and_identifier_quoting() {
+        let stats = TableStats { schema: "public".to_owned(), table: "events".to_owned(), live_tuples: 300, dead_tuples: 100 };
+        assert_eq!(stats.dead_ratio(), 0.25);
+        assert_eq!(TableStats { live_tuples: 0, dead_tuples: 0, ..stats }.dead_ratio(), 0.0);
+        assert_eq!(quote_ident("we\"ird"), "\"we\"\"ird\"");
+    }
+}
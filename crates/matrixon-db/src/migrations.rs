@@ -11,7 +11,7 @@ use matrixon_core::{Result, MatrixonError};
 use tracing::{debug, info, instrument};
 
 /// Migration version
-pub const MIGRATION_VERSION: &str = "20240321000000";
+pub const MIGRATION_VERSION: &str = "20241211000000";
 
 /// Run database migrations
 #[instrument(level = "debug")]
@@ -83,6 +83,229 @@ pub async fn run_migrations(pool: &PgPool) -> Result<()> {
             updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
         )
         "#,
+        
+        // Profiles table
+        r#"
+        CREATE TABLE IF NOT EXISTS profiles (
+            user_id TEXT PRIMARY KEY,
+            displayname TEXT,
+            avatar_url TEXT,
+            updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+        )
+        "#,
+        
+        // Accounts of local users, by Matrix user ID
+        r#"
+        CREATE TABLE IF NOT EXISTS accounts (
+            user_id TEXT PRIMARY KEY,
+            password_hash TEXT,
+            is_admin BOOLEAN NOT NULL DEFAULT FALSE,
+            deactivated BOOLEAN NOT NULL DEFAULT FALSE,
+            created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+        )
+        "#,
+        
+        // Devices of local users and their access tokens
+        r#"
+        CREATE TABLE IF NOT EXISTS user_devices (
+            user_id TEXT NOT NULL REFERENCES accounts(user_id),
+            device_id TEXT NOT NULL,
+            display_name TEXT,
+            access_token TEXT NOT NULL UNIQUE,
+            last_seen_ip TEXT,
+            last_seen_ts TIMESTAMP WITH TIME ZONE,
+            created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+            PRIMARY KEY (user_id, device_id)
+        )
+        "#,
+        
+        // Rooms, by Matrix room ID
+        r#"
+        CREATE TABLE IF NOT EXISTS room_records (
+            room_id TEXT PRIMARY KEY,
+            creator TEXT NOT NULL,
+            room_version TEXT NOT NULL,
+            is_public BOOLEAN NOT NULL DEFAULT FALSE,
+            created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+        )
+        "#,
+        
+        // Room timelines, by Matrix event ID
+        r#"
+        CREATE TABLE IF NOT EXISTS room_events (
+            event_id TEXT PRIMARY KEY,
+            room_id TEXT NOT NULL,
+            stream_ordering BIGINT NOT NULL,
+            ordinal BIGINT NOT NULL DEFAULT 0,
+            sender TEXT NOT NULL,
+            event_type TEXT NOT NULL,
+            state_key TEXT,
+            json JSONB NOT NULL
+        )
+        "#,
+        
+        r#"
+        CREATE INDEX IF NOT EXISTS room_events_room_stream ON room_events (room_id, stream_ordering)
+        "#,
+        
+        r#"
+        CREATE INDEX IF NOT EXISTS room_events_stream ON room_events (stream_ordering, ordinal, event_id)
+        "#,
+        
+        // State groups, each the delta to its parent group
+        r#"
+        CREATE TABLE IF NOT EXISTS state_groups (
+            shortstatehash BIGINT PRIMARY KEY,
+            parent BIGINT
+        )
+        "#,
+        
+        // Compressed state events added to or removed from the parent's state
+        r#"
+        CREATE TABLE IF NOT EXISTS state_group_deltas (
+            shortstatehash BIGINT NOT NULL REFERENCES state_groups(shortstatehash) ON DELETE CASCADE,
+            compressed_event BYTEA NOT NULL,
+            added BOOLEAN NOT NULL,
+            PRIMARY KEY (shortstatehash, compressed_event, added)
+        )
+        "#,
+        
+        // State group of the room state after each event, keeping the
+        // group from being freed
+        r#"
+        CREATE TABLE IF NOT EXISTS event_state_groups (
+            event_id TEXT PRIMARY KEY,
+            shortstatehash BIGINT NOT NULL REFERENCES state_groups(shortstatehash)
+        )
+        "#,
+        
+        r#"
+        CREATE INDEX IF NOT EXISTS event_state_groups_group ON event_state_groups (shortstatehash)
+        "#,
+        
+        r#"
+        CREATE INDEX IF NOT EXISTS state_groups_parent ON state_groups (parent)
+        "#,
+        
+        // Audit log of executed bot commands
+        r#"
+        CREATE TABLE IF NOT EXISTS bot_command_log (
+            id BIGSERIAL PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            room_id TEXT NOT NULL,
+            command TEXT NOT NULL,
+            args TEXT NOT NULL,
+            success BOOLEAN NOT NULL,
+            result TEXT NOT NULL,
+            duration_ms BIGINT NOT NULL,
+            executed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+        )
+        "#,
+        
+        r#"
+        CREATE INDEX IF NOT EXISTS bot_command_log_room_time ON bot_command_log (room_id, executed_at)
+        "#,
+        
+        // Events kept outside room timelines: auth events fetched for other
+        // events, and soft-failed events
+        r#"
+        CREATE TABLE IF NOT EXISTS event_outliers (
+            event_id TEXT PRIMARY KEY,
+            room_id TEXT NOT NULL,
+            json JSONB NOT NULL,
+            received_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+        )
+        "#,
+        
+        r#"
+        CREATE INDEX IF NOT EXISTS event_outliers_room ON event_outliers (room_id)
+        "#,
+        
+        r#"
+        CREATE INDEX IF NOT EXISTS event_outliers_received ON event_outliers (received_at)
+        "#,
+        
+        // Events referenced as prev or auth events by another event of
+        // their room
+        r#"
+        CREATE TABLE IF NOT EXISTS event_references (
+            room_id TEXT NOT NULL,
+            event_id TEXT NOT NULL,
+            PRIMARY KEY (room_id, event_id)
+        )
+        "#,
+        
+        // Events authorized by their auth events but not by the current
+        // state of their room
+        r#"
+        CREATE TABLE IF NOT EXISTS soft_failed_events (
+            event_id TEXT PRIMARY KEY,
+            room_id TEXT NOT NULL,
+            reason TEXT NOT NULL,
+            soft_failed_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+        )
+        "#,
+        
+        // Auth chain index: every event is on a chain of events of its room,
+        // each in the auth chain of the next
+        r#"
+        CREATE TABLE IF NOT EXISTS event_auth_chains (
+            event_id TEXT PRIMARY KEY,
+            room_id TEXT NOT NULL,
+            event_type TEXT NOT NULL,
+            state_key TEXT NOT NULL,
+            chain_id BIGINT NOT NULL,
+            sequence_number BIGINT NOT NULL,
+            UNIQUE (chain_id, sequence_number)
+        )
+        "#,
+        
+        r#"
+        CREATE INDEX IF NOT EXISTS event_auth_chains_room ON event_auth_chains (room_id)
+        "#,
+        
+        // Events of other chains in the auth chain of a chain's event, up
+        // to the target sequence number
+        r#"
+        CREATE TABLE IF NOT EXISTS event_auth_chain_links (
+            room_id TEXT NOT NULL,
+            origin_chain_id BIGINT NOT NULL,
+            origin_sequence_number BIGINT NOT NULL,
+            target_chain_id BIGINT NOT NULL,
+            target_sequence_number BIGINT NOT NULL,
+            PRIMARY KEY (origin_chain_id, origin_sequence_number, target_chain_id)
+        )
+        "#,
+        
+        r#"
+        CREATE INDEX IF NOT EXISTS event_auth_chain_links_room ON event_auth_chain_links (room_id)
+        "#,
+        
+        // Short ids of state keys and events, drawn from their sequences
+        r#"
+        CREATE TABLE IF NOT EXISTS short_state_keys (
+            shortstatekey BIGSERIAL PRIMARY KEY,
+            event_type TEXT NOT NULL,
+            state_key TEXT NOT NULL,
+            UNIQUE (event_type, state_key)
+        )
+        "#,
+        
+        r#"
+        CREATE TABLE IF NOT EXISTS short_event_ids (
+            shorteventid BIGSERIAL PRIMARY KEY,
+            event_id TEXT NOT NULL UNIQUE
+        )
+        "#,
+        
+        // Encrypted room key backups, all versions of a user in one document
+        r#"
+        CREATE TABLE IF NOT EXISTS room_key_backups (
+            user_id TEXT PRIMARY KEY,
+            backups JSONB NOT NULL,
+            updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+        )
+        "#,
     ];
     
     for migration in migrations {
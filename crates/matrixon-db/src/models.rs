@@ -2,6 +2,8 @@
 //! 
 //! This module defines the database models used throughout the Matrixon system.
 
+use std::collections::HashSet;
+
 use serde::{Deserialize, Serialize};
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
@@ -104,6 +106,251 @@ pub struct Device {
     pub updated_at: DateTime<Utc>,
 }
 
+/// User profile model
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    /// Matrix user ID, e.g. `@alice:example.org`
+    pub user_id: String,
+    
+    /// Display name
+    pub displayname: Option<String>,
+    
+    /// Avatar `mxc://` URL
+    pub avatar_url: Option<String>,
+}
+
+/// Account of a local user, keyed by Matrix user ID
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserRecord {
+    /// Matrix user ID, e.g. `@alice:example.org`
+    pub user_id: String,
+    
+    /// Argon2 hash of the password, unset for accounts without one
+    pub password_hash: Option<String>,
+    
+    /// Server admin
+    pub is_admin: bool,
+    
+    /// Deactivated
+    pub deactivated: bool,
+    
+    /// Created at
+    pub created_at: DateTime<Utc>,
+}
+
+/// Device of a user together with its access token
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceRecord {
+    /// Matrix user ID
+    pub user_id: String,
+    
+    /// Device ID
+    pub device_id: String,
+    
+    /// Display name
+    pub display_name: Option<String>,
+    
+    /// Access token of the device's session
+    pub access_token: String,
+    
+    /// Last seen IP
+    pub last_seen_ip: Option<String>,
+    
+    /// Last seen timestamp
+    pub last_seen_ts: Option<DateTime<Utc>>,
+    
+    /// Created at
+    pub created_at: DateTime<Utc>,
+}
+
+/// Room known to this server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomRecord {
+    /// Matrix room ID, e.g. `!abc:example.org`
+    pub room_id: String,
+    
+    /// Creator's Matrix user ID
+    pub creator: String,
+    
+    /// Room version
+    pub room_version: String,
+    
+    /// Published in the room directory
+    pub is_public: bool,
+    
+    /// Created at
+    pub created_at: DateTime<Utc>,
+}
+
+/// Bot command executed in a room, for the bot's audit log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BotCommandRecord {
+    /// Matrix user ID of the sender
+    pub user_id: String,
+    
+    /// Matrix room ID the command was sent in
+    pub room_id: String,
+    
+    /// Command name, without the prefix
+    pub command: String,
+    
+    /// Arguments as sent
+    pub args: String,
+    
+    /// Whether the command succeeded
+    pub success: bool,
+    
+    /// Response or error message
+    pub result: String,
+    
+    /// Execution time in milliseconds
+    pub duration_ms: i64,
+    
+    /// Executed at
+    pub executed_at: DateTime<Utc>,
+}
+
+/// Event of a room timeline
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EventRecord {
+    /// Matrix event ID
+    pub event_id: String,
+    
+    /// Matrix room ID
+    pub room_id: String,
+    
+    /// Server-wide stream position of the event
+    pub stream_ordering: i64,
+    
+    /// Orders imported historical events sharing the stream position of
+    /// the event they were imported after; 0 for other events
+    pub ordinal: i64,
+    
+    /// Sender's Matrix user ID
+    pub sender: String,
+    
+    /// Event type
+    pub event_type: String,
+    
+    /// State key of state events
+    pub state_key: Option<String>,
+    
+    /// The whole event
+    pub json: serde_json::Value,
+}
+
+/// Position of an event in the auth chain index
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthChainPositionRecord {
+    /// Matrix event ID
+    pub event_id: String,
+    
+    /// Matrix room ID
+    pub room_id: String,
+    
+    /// Event type
+    pub event_type: String,
+    
+    /// State key, empty for events without one
+    pub state_key: String,
+    
+    /// Chain the event is on
+    pub chain_id: i64,
+    
+    /// Position on the chain, starting at 1
+    pub sequence_number: i64,
+}
+
+/// Link from a position of the auth chain index to a position on another
+/// chain, whose events up to it are in the auth chain of the origin
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthChainLinkRecord {
+    /// Matrix room ID
+    pub room_id: String,
+    
+    pub origin_chain_id: i64,
+    
+    pub origin_sequence_number: i64,
+    
+    pub target_chain_id: i64,
+    
+    pub target_sequence_number: i64,
+}
+
+/// Event kept outside its room's timeline
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutlierRecord {
+    /// Matrix event ID
+    pub event_id: String,
+    
+    /// Matrix room ID
+    pub room_id: String,
+    
+    /// The whole event
+    pub json: serde_json::Value,
+    
+    /// Received at
+    pub received_at: DateTime<Utc>,
+}
+
+/// Event authorized by its auth events but not by the current state of
+/// its room
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SoftFailureRecord {
+    /// Matrix event ID
+    pub event_id: String,
+    
+    /// Matrix room ID
+    pub room_id: String,
+    
+    /// Why the event was soft-failed
+    pub reason: String,
+}
+
+/// Room key backup versions of a user
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomKeyBackupRecord {
+    /// Matrix user ID
+    pub user_id: String,
+    
+    /// Every backup version of the user with its encrypted session keys
+    pub backups: serde_json::Value,
+}
+
+/// A state event compressed to its short state key and short event ID,
+/// 8 big endian bytes each
+pub type CompressedStateEvent = [u8; 16];
+
+/// Compress a state event given its short state key and short event ID
+pub fn compress_state_event(shortstatekey: u64, shorteventid: u64) -> CompressedStateEvent {
+    let mut compressed = [0; 16];
+    compressed[..8].copy_from_slice(&shortstatekey.to_be_bytes());
+    compressed[8..].copy_from_slice(&shorteventid.to_be_bytes());
+    compressed
+}
+
+/// The short state key and short event ID of a compressed state event
+pub fn parse_compressed_state_event(compressed: &CompressedStateEvent) -> (u64, u64) {
+    let (shortstatekey, shorteventid) = compressed.split_at(8);
+    (
+        u64::from_be_bytes(shortstatekey.try_into().expect("8 bytes")),
+        u64::from_be_bytes(shorteventid.try_into().expect("8 bytes")),
+    )
+}
+
+/// A state group, stored as the difference to its parent group
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StateDiffRecord {
+    /// Parent state group; a group without one holds its full state
+    pub parent: Option<u64>,
+    
+    /// State events added to the parent's state
+    pub added: HashSet<CompressedStateEvent>,
+    
+    /// State events of the parent's state removed
+    pub removed: HashSet<CompressedStateEvent>,
+}
+
 /// Test event model for benchmarks and tests
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TestEvent {
@@ -195,4 +442,51 @@ mod tests {
         assert_eq!(room.name, deserialized.name);
         assert_eq!(room.topic, deserialized.topic);
     }
+
+    #[test]
+    fn test_device_record_serialization() {
+        let device = DeviceRecord {
+            user_id: "@test_user:matrixon.local".to_string(),
+            device_id: "PHONE".to_string(),
+            display_name: None,
+            access_token: "syt_token".to_string(),
+            last_seen_ip: Some("127.0.0.1".to_string()),
+            last_seen_ts: Some(Utc::now()),
+            created_at: Utc::now(),
+        };
+
+        let serialized = serde_json::to_string(&device).unwrap();
+        let deserialized: DeviceRecord = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(device, deserialized);
+    }
+
+    #[test]
+    fn test_profile_serialization() {
+        let profile = Profile {
+            user_id: "@test_user:matrixon.local".to_string(),
+            displayname: Some("Test User".to_string()),
+            avatar_url: None,
+        };
+
+        let serialized = serde_json::to_string(&profile).unwrap();
+        let deserialized: Profile = serde_json::from_str(&serialized).unwrap();
+
+        assert_eq!(profile, deserialized);
+    }
+
+    #[test]
+    fn test_compressed_state_events() {
+        let compressed = compress_state_event(7, u64::MAX - 1);
+        assert_eq!(compressed[..8], [0, 0, 0, 0, 0, 0, 0, 7]);
+        assert_eq!(parse_compressed_state_event(&compressed), (7, u64::MAX - 1));
+
+        let diff = StateDiffRecord {
+            parent: Some(1),
+            added: [compressed].into(),
+            removed: [compress_state_event(7, 3)].into(),
+        };
+        let serialized = serde_json::to_string(&diff).unwrap();
+        assert_eq!(serde_json::from_str::<StateDiffRecord>(&serialized).unwrap(), diff);
+    }
 }
@@ -0,0 +1,482 @@
+//! Point-in-time recovery for Matrixon
+//!
+//! Author: arkSong <arksong2018@gmail.com>
+//! Date: 2025-06-15
+//! Version: 0.1.0
+//!
+//! PostgreSQL can be restored to any moment covered by a base backup and the
+//! write-ahead log archived after it. A [`WalArchive`] directory holds both:
+//! `wal/` keeps the segments handed over by the server's `archive_command`,
+//! `base/` the base backups taken with `pg_basebackup`, each labelled with
+//! when it was taken and the first segment it needs. Restoring unpacks the
+//! newest base backup finished before the target time into an empty data
+//! directory and has PostgreSQL replay the archived log up to the target.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, Utc};
+use matrixon_core::{MatrixonError, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgPool, Row};
+use tokio::process::Command;
+use tracing::{info, instrument, warn};
+
+/// Size of a WAL segment of a default PostgreSQL build
+const WAL_SEGMENT_SIZE: u64 = 16 * 1024 * 1024;
+const LABEL_FILE: &str = "label.json";
+
+/// A base backup in the archive
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BaseBackup {
+    pub id: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// First WAL segment needed to restore the backup
+    pub start_segment: String,
+}
+
+/// WAL archiving state of the server, from `pg_stat_archiver`
+#[derive(Debug, Clone, Serialize)]
+pub struct ArchiverStatus {
+    pub archive_mode: String,
+    pub archived_count: i64,
+    pub last_archived_wal: Option<String>,
+    pub last_archived_time: Option<DateTime<Utc>>,
+    pub failed_count: i64,
+    pub last_failed_wal: Option<String>,
+    pub last_failed_time: Option<DateTime<Utc>>,
+}
+
+/// Directory of archived WAL segments and base backups
+#[derive(Debug, Clone)]
+pub struct WalArchive {
+    dir: PathBuf,
+}
+
+impl WalArchive {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn wal_dir(&self) -> PathBuf {
+        self.dir.join("wal")
+    }
+
+    fn base_dir(&self) -> PathBuf {
+        self.dir.join("base")
+    }
+
+    /// Archive the WAL file at `path` as `name`, for the server's
+    /// `archive_command`. Archiving a file again succeeds if the archived
+    /// copy is identical, which happens when the server crashed before it
+    /// noted the first attempt; a different file of the same name is refused.
+    pub fn archive_segment(&self, path: &Path, name: &str) -> Result<()> {
+        check_file_name(name)?;
+        let wal_dir = self.wal_dir();
+        fs::create_dir_all(&wal_dir)?;
+        let target = wal_dir.join(name);
+        let contents = fs::read(path)?;
+        if target.exists() {
+            if fs::read(&target)? == contents {
+                return Ok(());
+            }
+            return Err(MatrixonError::Database(format!("{} is already archived with different contents", name)));
+        }
+
+        // Never leave a truncated segment under its final name. The server
+        // recycles the segment once this returns, so the rename must be on
+        // disk too.
+        write_durably(&target, &contents)
+    }
+
+    /// Names of the archived WAL files, oldest first
+    pub fn segments(&self) -> Result<Vec<String>> {
+        let mut segments = Vec::new();
+        let entries = match fs::read_dir(self.wal_dir()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(segments),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if !name.ends_with(".tmp") {
+                segments.push(name);
+            }
+        }
+        segments.sort();
+        Ok(segments)
+    }
+
+    /// The `restore_command` copying archived segments back
+    pub fn restore_command(&self) -> String {
+        let wal_dir = self.wal_dir().display().to_string().replace('\'', r"'\''");
+        format!("cp '{}/%f' '%p'", wal_dir)
+    }
+
+    /// Base backups in the archive, oldest first
+    pub fn base_backups(&self) -> Result<Vec<BaseBackup>> {
+        let mut backups = Vec::new();
+        let entries = match fs::read_dir(self.base_dir()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(backups),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let label = entry?.path().join(LABEL_FILE);
+            // Backups without a label did not finish
+            let Ok(label) = fs::read(&label) else {
+                continue;
+            };
+            match serde_json::from_slice::<BaseBackup>(&label) {
+                Ok(backup) => backups.push(backup),
+                Err(e) => warn!("⚠️ Ignoring base backup with an unreadable label: {}", e),
+            }
+        }
+        backups.sort_by_key(|backup| backup.started_at);
+        Ok(backups)
+    }
+
+    /// Take a base backup of the server at `database_url` with
+    /// `pg_basebackup`. Its WAL is left to the archive.
+    #[instrument(level = "debug", skip(self, database_url))]
+    pub async fn take_base_backup(&self, database_url: &str) -> Result<BaseBackup> {
+        let started_at = Utc::now();
+        let id = started_at.format("%Y%m%dT%H%M%SZ").to_string();
+        let target = self.base_dir().join(&id);
+        info!("💾 Taking base backup {}", id);
+
+        let output = pg_tool("pg_basebackup", database_url)
+            .arg("--pgdata")
+            .arg(&target)
+            .args(["--format=tar", "--gzip", "--wal-method=none", "--checkpoint=fast", "--verbose"])
+            .arg(format!("--label=matrixon-{}", id))
+            .output()
+            .await?;
+        let log = String::from_utf8_lossy(&output.stderr);
+        let start_segment = match parse_start_segment(&log) {
+            Some(segment) if output.status.success() => segment,
+            _ => {
+                let _ = fs::remove_dir_all(&target);
+                return Err(MatrixonError::Database(format!("pg_basebackup failed: {}", log.trim())));
+            }
+        };
+
+        let backup = BaseBackup { id, started_at, finished_at: Utc::now(), start_segment };
+        let label = serde_json::to_vec_pretty(&backup).map_err(|e| MatrixonError::Serialization(e.to_string()))?;
+        write_durably(&target.join(LABEL_FILE), &label)?;
+        info!("✅ Base backup {} taken, starting at WAL segment {}", backup.id, backup.start_segment);
+        Ok(backup)
+    }
+
+    /// Remove all but the newest `keep` base backups, and the WAL segments
+    /// only the removed ones needed. Returns the number of files and
+    /// backups removed.
+    pub fn prune(&self, keep: usize) -> Result<usize> {
+        let backups = self.base_backups()?;
+        let (old, kept) = backups.split_at(backups.len().saturating_sub(keep.max(1)));
+        let Some(oldest_kept) = kept.first() else {
+            return Ok(0);
+        };
+
+        let mut removed = 0;
+        for backup in old {
+            fs::remove_dir_all(self.base_dir().join(&backup.id))?;
+            removed += 1;
+        }
+        let oldest_needed = segment_position(&oldest_kept.start_segment);
+        for segment in self.segments()? {
+            // Timeline history files are small and always kept
+            if segment_position(&segment).is_some_and(|position| Some(position) < oldest_needed) {
+                fs::remove_file(self.wal_dir().join(&segment))?;
+                removed += 1;
+            }
+        }
+        if removed > 0 {
+            info!("🗑 Pruned {} base backups and WAL files from the archive", removed);
+        }
+        Ok(removed)
+    }
+
+    /// The newest base backup finished by `target`
+    pub fn base_backup_for(&self, target: DateTime<Utc>) -> Result<BaseBackup> {
+        self.base_backups()?
+            .into_iter()
+            .rfind(|backup| backup.finished_at <= target)
+            .ok_or_else(|| MatrixonError::NotFound(format!("No base backup was finished by {}", target)))
+    }
+
+    /// Unpack the base backup for `target` into the empty `data_dir` and
+    /// configure recovery up to `target`. Starting PostgreSQL on the
+    /// directory then replays the archived WAL and opens the database.
+    #[instrument(level = "debug", skip(self))]
+    pub async fn prepare_restore(&self, target: DateTime<Utc>, data_dir: &Path) -> Result<BaseBackup> {
+        let backup = self.base_backup_for(target)?;
+        if fs::read_dir(data_dir).is_ok_and(|mut entries| entries.next().is_some()) {
+            return Err(MatrixonError::Validation(format!("{} is not empty", data_dir.display())));
+        }
+        fs::create_dir_all(data_dir)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(data_dir, fs::Permissions::from_mode(0o700))?;
+        }
+
+        let archive = self.base_dir().join(&backup.id).join("base.tar.gz");
+        let status = Command::new("tar").arg("-xzf").arg(&archive).arg("-C").arg(data_dir).status().await?;
+        if !status.success() {
+            return Err(MatrixonError::Database(format!("Unpacking {} failed", archive.display())));
+        }
+        write_recovery_config(data_dir, target, &self.restore_command())?;
+        info!("✅ Restored base backup {} to {}, recovering up to {}", backup.id, data_dir.display(), target);
+        Ok(backup)
+    }
+}
+
+/// Configure `data_dir` to replay archived WAL with `restore_command` up to
+/// `target`, then accept writes again
+pub fn write_recovery_config(data_dir: &Path, target: DateTime<Utc>, restore_command: &str) -> Result<()> {
+    File::create(data_dir.join("recovery.signal"))?;
+    let mut config = OpenOptions::new().create(true).append(true).open(data_dir.join("postgresql.auto.conf"))?;
+    writeln!(config, "\n# Point-in-time recovery")?;
+    writeln!(config, "restore_command = '{}'", restore_command.replace('\'', "''"))?;
+    writeln!(config, "recovery_target_time = '{}'", target.format("%Y-%m-%d %H:%M:%S%.6f+00"))?;
+    writeln!(config, "recovery_target_action = 'promote'")?;
+    Ok(())
+}
+
+/// A PostgreSQL client tool connecting to `database_url`. The password is
+/// handed over in `PGPASSWORD` rather than on the command line, where other
+/// users of the machine could read it.
+pub fn pg_tool(program: &str, database_url: &str) -> Command {
+    let (database_url, password) = split_password(database_url);
+    let mut command = Command::new(program);
+    command.arg("--dbname").arg(database_url);
+    if let Some(password) = password {
+        command.env("PGPASSWORD", password);
+    }
+    command
+}
+
+/// `database_url` without its password, and the password
+fn split_password(database_url: &str) -> (String, Option<String>) {
+    let Some((scheme, rest)) = database_url.split_once("://") else {
+        return (database_url.to_owned(), None);
+    };
+    let authority_end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_end);
+    let Some((user_info, host)) = authority.rsplit_once('@') else {
+        return (database_url.to_owned(), None);
+    };
+    let Some((user, password)) = user_info.split_once(':') else {
+        return (database_url.to_owned(), None);
+    };
+    (format!("{}://{}@{}{}", scheme, user, host, path), Some(percent_decode(password)))
+}
+
+fn percent_decode(encoded: &str) -> String {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match decoded {
+            Some(decoded) => {
+                bytes.push(decoded);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Write `contents` to a temporary file, sync it and rename it to `path`,
+/// then sync the directory so the rename survives a crash
+fn write_durably(path: &Path, contents: &[u8]) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let copy = dir.join(format!("{}.tmp", file_name));
+    let mut file = File::create(&copy)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&copy, path)?;
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// WAL file names are handed over by the server; refuse anything that
+/// could leave the archive directory
+fn check_file_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.starts_with('.') || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.') {
+        return Err(MatrixonError::Validation(format!("Invalid WAL file name: {}", name)));
+    }
+    Ok(())
+}
+
+/// Name of the WAL segment holding `lsn` on `timeline`
+pub fn segment_name(timeline: u32, lsn: u64) -> String {
+    let segment = lsn / WAL_SEGMENT_SIZE;
+    let segments_per_id = 0x1_0000_0000 / WAL_SEGMENT_SIZE;
+    format!("{:08X}{:08X}{:08X}", timeline, segment / segments_per_id, segment % segments_per_id)
+}
+
+/// Position of a segment, or of the backup history file of one, in the
+/// log regardless of timeline
+fn segment_position(name: &str) -> Option<&str> {
+    let segment = name.get(..24)?;
+    segment.chars().all(|c| c.is_ascii_hexdigit()).then(|| &segment[8..])
+}
+
+/// `X/Y` log sequence number
+fn parse_lsn(lsn: &str) -> Option<u64> {
+    let (high, low) = lsn.split_once('/')?;
+    Some((u64::from_str_radix(high, 16).ok()? << 32) | u64::from_str_radix(low, 16).ok()?)
+}
+
+/// First segment of a base backup, from the `--verbose` log of
+/// `pg_basebackup`: "write-ahead log start point: 0/2000028 on timeline 1"
+fn parse_start_segment(log: &str) -> Option<String> {
+    let start = log.split("write-ahead log start point: ").nth(1)?;
+    let mut words = start.split_whitespace();
+    let lsn = parse_lsn(words.next()?)?;
+    let timeline = words.nth(2)?.parse().ok()?;
+    Some(segment_name(timeline, lsn))
+}
+
+/// WAL archiving state of the server at `pool`
+#[instrument(level = "debug", skip(pool))]
+pub async fn archiver_status(pool: &PgPool) -> Result<ArchiverStatus> {
+    let archive_mode: String = sqlx::query_scalar("SELECT current_setting('archive_mode')")
+        .fetch_one(pool)
+        .await
+        .map_err(|e| MatrixonError::Database(e.to_string()))?;
+    let row = sqlx::query(
+        "SELECT archived_count, last_archived_wal, last_archived_time, failed_count, last_failed_wal, last_failed_time \
+         FROM pg_stat_archiver",
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| MatrixonError::Database(e.to_string()))?;
+    let column = |e: sqlx::Error| MatrixonError::Database(e.to_string());
+    Ok(ArchiverStatus {
+        archive_mode,
+        archived_count: row.try_get("archived_count").map_err(column)?,
+        last_archived_wal: row.try_get("last_archived_wal").map_err(column)?,
+        last_archived_time: row.try_get("last_archived_time").map_err(column)?,
+        failed_count: row.try_get("failed_count").map_err(column)?,
+        last_failed_wal: row.try_get("last_failed_wal").map_err(column)?,
+        last_failed_time: row.try_get("last_failed_time").map_err(column)?,
+    })
+}
+
+/// Close the current WAL segment so it is archived at once, returning its
+/// name
+pub async fn switch_wal(pool: &PgPool) -> Result<String> {
+    sqlx::query_scalar("SELECT pg_walfile_name(pg_switch_wal())")
+        .fetch_one(pool)
+        .await
+        .map_err(|e| MatrixonError::Database(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_passwords_stay_off_the_command_line() {
+        assert_eq!(
+            split_password("postgresql://matrixon:p%40ss:w@db.local:5432/matrixon?sslmode=require"),
+            ("postgresql://matrixon@db.local:5432/matrixon?sslmode=require".to_owned(), Some("p@ss:w".to_owned()))
+        );
+        assert_eq!(split_password("postgresql://matrixon@db.local/matrixon").1, None);
+        assert_eq!(split_password("host=db.local").0, "host=db.local");
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("matrixon-pitr-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn add_base_backup(archive: &WalArchive, hour: u32, start_segment: &str) -> BaseBackup {
+        let backup = BaseBackup {
+            id: format!("backup{}", hour),
+            started_at: Utc.with_ymd_and_hms(2025, 6, 15, hour, 0, 0).unwrap(),
+            finished_at: Utc.with_ymd_and_hms(2025, 6, 15, hour, 10, 0).unwrap(),
+            start_segment: start_segment.to_owned(),
+        };
+        let dir = archive.base_dir().join(&backup.id);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(LABEL_FILE), serde_json::to_vec(&backup).unwrap()).unwrap();
+        backup
+    }
+
+    #[test]
+    fn test_segment_names() {
+        assert_eq!(segment_name(1, parse_lsn("0/2000028").unwrap()), "000000010000000000000002");
+        assert_eq!(segment_name(2, parse_lsn("1A/FF000000").unwrap()), "000000020000001A000000FF");
+        let log = "pg_basebackup: initiating base backup, waiting for checkpoint to complete\n\
+                   pg_basebackup: checkpoint completed\n\
+                   pg_basebackup: write-ahead log start point: 0/5000028 on timeline 3\n";
+        assert_eq!(parse_start_segment(log).as_deref(), Some("000000030000000000000005"));
+        assert_eq!(parse_start_segment("pg_basebackup: error: connection failed"), None);
+    }
+
+    #[test]
+    fn test_archive_prune_and_restore_plan() {
+        let dir = scratch_dir("archive");
+        let archive = WalArchive::new(&dir);
+        let segment = dir.join("pg_wal_segment");
+        fs::create_dir_all(&dir).unwrap();
+
+        fs::write(&segment, b"first").unwrap();
+        for name in ["000000010000000000000001", "000000010000000000000002", "000000010000000000000003"] {
+            archive.archive_segment(&segment, name).unwrap();
+        }
+        // A repeated attempt is accepted only for the same contents
+        archive.archive_segment(&segment, "000000010000000000000003").unwrap();
+        fs::write(&segment, b"second").unwrap();
+        assert!(archive.archive_segment(&segment, "000000010000000000000003").is_err());
+        assert!(archive.archive_segment(&segment, "../escape").is_err());
+        archive.archive_segment(&segment, "00000002.history").unwrap();
+        assert_eq!(archive.segments().unwrap().len(), 4);
+        assert!(archive.restore_command().ends_with("/wal/%f' '%p'"));
+
+        let morning = add_base_backup(&archive, 8, "000000010000000000000001");
+        let noon = add_base_backup(&archive, 12, "000000010000000000000003");
+        assert_eq!(archive.base_backups().unwrap(), vec![morning.clone(), noon.clone()]);
+        assert!(archive.base_backup_for(Utc.with_ymd_and_hms(2025, 6, 15, 8, 5, 0).unwrap()).is_err());
+        assert_eq!(archive.base_backup_for(Utc.with_ymd_and_hms(2025, 6, 15, 11, 0, 0).unwrap()).unwrap(), morning);
+        assert_eq!(archive.base_backup_for(Utc.with_ymd_and_hms(2025, 6, 16, 0, 0, 0).unwrap()).unwrap(), noon);
+
+        // The morning backup and the segments only it needed go
+        assert_eq!(archive.prune(1).unwrap(), 3);
+        assert_eq!(archive.base_backups().unwrap(), vec![noon]);
+        assert_eq!(archive.segments().unwrap(), ["000000010000000000000003", "00000002.history"]);
+
+        let data_dir = dir.join("data");
+        let target = Utc.with_ymd_and_hms(2025, 6, 15, 13, 30, 0).unwrap();
+        fs::create_dir_all(&data_dir).unwrap();
+        write_recovery_config(&data_dir, target, "cp '/it''s/%f' '%p'").unwrap();
+        assert!(data_dir.join("recovery.signal").exists());
+        let config = fs::read_to_string(data_dir.join("postgresql.auto.conf")).unwrap();
+        assert!(config.contains("restore_command = 'cp ''/it''''s/%f'' ''%p'''\n"));
+        assert!(config.contains("recovery_target_time = '2025-06-15 13:30:00.000000+00'\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
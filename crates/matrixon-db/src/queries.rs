@@ -1,13 +1,24 @@
 //! Database queries for Matrixon
 //! 
 //! This module provides database query functions for the Matrixon system.
+//! Queries over result sets too large to hold at once, such as every stored
+//! event, return streams built with [`paged`] instead. They page through the
+//! rows by key, so only one page is in memory at a time and no connection is
+//! held between pages.
 
+use std::future::Future;
+
+use futures::{stream, Stream, TryStreamExt};
 use sqlx::{postgres::PgPool, Row};
 use matrixon_core::{Result, MatrixonError};
 use tracing::{debug, info, instrument};
 use uuid::Uuid;
 
-use crate::models::{User, Room, Event, TestEvent};
+use crate::models::{User, Room, Event, TestEvent, Profile};
+use crate::query_metrics::TimedQuery;
+
+/// Rows fetched per page by the streaming queries
+pub const STREAM_PAGE_SIZE: i64 = 1000;
 
 /// Create a new user
 #[instrument(level = "debug")]
@@ -25,6 +36,7 @@ pub async fn create_user(pool: &PgPool, user: &User) -> Result<()> {
     .bind(user.created_at)
     .bind(user.updated_at)
     .execute(pool)
+    .timed("queries::create_user")
     .await
     .map_err(|e| MatrixonError::Database(e.to_string()))?;
     
@@ -46,6 +58,7 @@ pub async fn get_user_by_username(pool: &PgPool, username: &str) -> Result<Optio
     )
     .bind(username)
     .fetch_optional(pool)
+    .timed("queries::get_user_by_username")
     .await
     .map_err(|e| MatrixonError::Database(e.to_string()))?
     .map(|row: sqlx::postgres::PgRow| {
@@ -73,6 +86,7 @@ pub async fn create_room(pool: &PgPool, room: &Room) -> Result<()> {
     .bind(room.created_at)
     .bind(room.updated_at)
     .execute(pool)
+    .timed("queries::create_room")
     .await
     .map_err(|e| MatrixonError::Database(e.to_string()))?;
     
@@ -94,6 +108,7 @@ pub async fn get_room_by_alias(pool: &PgPool, alias: &str) -> Result<Option<Room
     )
     .bind(alias)
     .fetch_optional(pool)
+    .timed("queries::get_room_by_alias")
     .await
     .map_err(|e| MatrixonError::Database(e.to_string()))?
     .map(|row: sqlx::postgres::PgRow| {
@@ -121,6 +136,7 @@ pub async fn create_event(pool: &PgPool, event: &Event) -> Result<()> {
     .bind(event.created_at)
     .bind(event.created_at)
     .execute(pool)
+    .timed("queries::create_event")
     .await
     .map_err(|e| MatrixonError::Database(e.to_string()))?;
     
@@ -136,6 +152,7 @@ pub async fn create_event(pool: &PgPool, event: &Event) -> Result<()> {
     .bind(event.created_at)
     .bind(event.created_at)
     .execute(pool)
+    .timed("queries::create_event_mapping")
     .await
     .map_err(|e| MatrixonError::Database(e.to_string()))?;
     
@@ -168,6 +185,7 @@ pub async fn get_room_events(
         .bind(before)
         .bind(limit)
         .fetch_all(pool)
+        .timed("queries::get_room_events")
         .await
     } else {
         sqlx::query(
@@ -182,6 +200,7 @@ pub async fn get_room_events(
         .bind(room_id.to_string())
         .bind(limit)
         .fetch_all(pool)
+        .timed("queries::get_room_events")
         .await
     }
     .map_err(|e| MatrixonError::Database(e.to_string()))?
@@ -212,6 +231,7 @@ pub async fn insert_event(pool: &PgPool, event: &TestEvent) -> Result<Uuid> {
     .bind(&event.content)
     .bind(event.created_at)
     .fetch_one(pool)
+    .timed("queries::insert_event")
     .await
     .map(|row: sqlx::postgres::PgRow| row.get::<Uuid, _>("id"))
     .map_err(|e| MatrixonError::Database(e.to_string()))
@@ -231,6 +251,7 @@ pub async fn get_event(pool: &PgPool, id: Uuid) -> Result<TestEvent> {
     )
     .bind(id)
     .fetch_one(pool)
+    .timed("queries::get_event")
     .await
     .map(|row: sqlx::postgres::PgRow| TestEvent {
         id: row.get("id"),
@@ -258,6 +279,7 @@ pub async fn update_event_content(pool: &PgPool, id: Uuid, content: &str) -> Res
     .bind(content)
     .bind(id)
     .execute(pool)
+    .timed("queries::update_event_content")
     .await
     .map(|_| ())
     .map_err(|e| MatrixonError::Database(e.to_string()))?;
@@ -279,6 +301,7 @@ pub async fn delete_event(pool: &PgPool, id: Uuid) -> Result<()> {
     )
     .bind(id)
     .execute(pool)
+    .timed("queries::delete_event")
     .await
     .map(|_| ())
     .map_err(|e| MatrixonError::Database(e.to_string()))?;
@@ -287,6 +310,83 @@ pub async fn delete_event(pool: &PgPool, id: Uuid) -> Result<()> {
     Ok(())
 }
 
+/// Get the profile of a user
+#[instrument(level = "debug")]
+pub async fn get_profile(pool: &PgPool, user_id: &str) -> Result<Option<Profile>> {
+    debug!("🔧 Getting profile: {}", user_id);
+    
+    let profile = sqlx::query(
+        r#"
+        SELECT user_id, displayname, avatar_url
+        FROM profiles
+        WHERE user_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .timed("queries::get_profile")
+    .await
+    .map_err(|e| MatrixonError::Database(e.to_string()))?
+    .map(|row: sqlx::postgres::PgRow| Profile {
+        user_id: row.get("user_id"),
+        displayname: row.get("displayname"),
+        avatar_url: row.get("avatar_url"),
+    });
+    
+    Ok(profile)
+}
+
+/// Create or replace the profile of a user
+#[instrument(level = "debug")]
+pub async fn upsert_profile(pool: &PgPool, profile: &Profile) -> Result<()> {
+    debug!("🔧 Storing profile: {}", profile.user_id);
+    
+    sqlx::query(
+        r#"
+        INSERT INTO profiles (user_id, displayname, avatar_url, updated_at)
+        VALUES ($1, $2, $3, NOW())
+        ON CONFLICT (user_id) DO UPDATE
+        SET displayname = EXCLUDED.displayname,
+            avatar_url = EXCLUDED.avatar_url,
+            updated_at = NOW()
+        "#,
+    )
+    .bind(&profile.user_id)
+    .bind(&profile.displayname)
+    .bind(&profile.avatar_url)
+    .execute(pool)
+    .timed("queries::upsert_profile")
+    .await
+    .map_err(|e| MatrixonError::Database(e.to_string()))?;
+    
+    info!("✅ Stored profile: {}", profile.user_id);
+    Ok(())
+}
+
+/// Stream the items of pages fetched one after another. `fetch` returns a
+/// page for a cursor together with the cursor of the next page; the stream
+/// ends on an empty page or when there is no next cursor.
+pub fn paged<T, C, F, Fut>(first: C, mut fetch: F) -> impl Stream<Item = Result<T>>
+where
+    F: FnMut(C) -> Fut,
+    Fut: Future<Output = Result<(Vec<T>, Option<C>)>>,
+{
+    stream::try_unfold(Some(first), move |cursor| {
+        let page = cursor.map(&mut fetch);
+        async move {
+            let Some(page) = page else {
+                return Ok(None);
+            };
+            let (items, next) = page.await?;
+            if items.is_empty() {
+                return Ok(None);
+            }
+            Ok::<_, MatrixonError>(Some((stream::iter(items.into_iter().map(Ok)), next)))
+        }
+    })
+    .try_flatten()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -294,6 +394,24 @@ mod tests {
     use uuid::Uuid;
     use chrono::Utc;
 
+    #[tokio::test]
+    async fn test_paged_streams_one_page_at_a_time() {
+        let rows: Vec<i64> = (1..=7).collect();
+        let mut fetched = Vec::new();
+        let stream = paged(0, |after: i64| {
+            fetched.push(after);
+            let page: Vec<i64> = rows.iter().copied().filter(|row| *row > after).take(3).collect();
+            let next = page.last().copied();
+            async move { Ok((page, next)) }
+        });
+        let items: Vec<i64> = stream.try_collect().await.unwrap();
+        assert_eq!(items, rows);
+        assert_eq!(fetched, vec![0, 3, 6, 7]);
+
+        let failing = paged(0, |_| async { Err::<(Vec<i64>, Option<i64>), _>(MatrixonError::Database("gone".to_string())) });
+        assert!(failing.try_collect::<Vec<_>>().await.is_err());
+    }
+
     #[tokio::test]
     async fn test_user_operations() {
         let pool = PgPoolOptions::new()
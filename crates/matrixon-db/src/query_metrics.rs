@@ -0,0 +1,164 @@
+//! Per-statement query metrics for Matrixon
+//!
+//! Author: arkSong <arksong2018@gmail.com>
+//! Date: 2025-06-15
+//! Version: 0.1.0
+//!
+//! Every query the repositories run is named after the statement it
+//! executes and timed with [`TimedQuery::timed`]. Latencies and outcomes go
+//! to the `metrics` facade, where matrixon-monitor's Prometheus recorder
+//! exports them as `matrixon_db_statement_duration_seconds` and
+//! `matrixon_db_statement_runs_total`, labelled by statement, apart from
+//! the `matrixon_db_query_*` metrics the server registers with other
+//! labels. The same numbers are kept per statement in process so the
+//! server can render them itself and list the slowest statements, and
+//! queries slower than the configured threshold are logged.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use metrics::{counter, histogram};
+use serde::Serialize;
+use tracing::warn;
+
+/// Statistics of every statement run so far
+static STATEMENTS: Mutex<BTreeMap<&'static str, StatementStats>> = Mutex::new(BTreeMap::new());
+/// Latency above which a query is logged as slow, in microseconds
+static SLOW_QUERY_US: AtomicU64 = AtomicU64::new(500_000);
+
+/// What one statement did so far
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StatementStats {
+    pub statement: &'static str,
+    pub calls: u64,
+    pub errors: u64,
+    /// Calls slower than the slow query threshold
+    pub slow: u64,
+    pub total_us: u64,
+    pub max_us: u64,
+}
+
+impl StatementStats {
+    pub fn mean(&self) -> Duration {
+        Duration::from_micros(self.total_us.checked_div(self.calls).unwrap_or(0))
+    }
+}
+
+/// Log queries that take longer than `threshold`
+pub fn set_slow_query_threshold(threshold: Duration) {
+    SLOW_QUERY_US.store(threshold.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// Record one run of `statement`
+pub fn record(statement: &'static str, elapsed: Duration, ok: bool) {
+    let elapsed_us = elapsed.as_micros() as u64;
+    let slow = elapsed_us > SLOW_QUERY_US.load(Ordering::Relaxed);
+    histogram!("matrixon_db_statement_duration_seconds", elapsed.as_secs_f64(), "statement" => statement);
+    counter!("matrixon_db_statement_runs_total", 1, "statement" => statement, "outcome" => if ok { "ok" } else { "error" });
+    if slow {
+        warn!("🐢 Slow query {} took {} ms", statement, elapsed.as_millis());
+    }
+
+    let mut statements = STATEMENTS.lock().unwrap();
+    let stats = statements.entry(statement).or_insert_with(|| StatementStats { statement, ..Default::default() });
+    stats.calls += 1;
+    stats.errors += u64::from(!ok);
+    stats.slow += u64::from(slow);
+    stats.total_us += elapsed_us;
+    stats.max_us = stats.max_us.max(elapsed_us);
+}
+
+/// Statistics of every statement run so far, most total time first
+pub fn statement_stats() -> Vec<StatementStats> {
+    let mut stats: Vec<StatementStats> = STATEMENTS.lock().unwrap().values().cloned().collect();
+    stats.sort_by_key(|stats| std::cmp::Reverse(stats.total_us));
+    stats
+}
+
+/// Statement statistics in the Prometheus text exposition format, for
+/// servers exporting metrics without matrixon-monitor's recorder
+pub fn render() -> String {
+    let stats = statement_stats();
+    let mut out = String::new();
+    let _ = writeln!(out, "# HELP matrixon_db_statement_seconds Time spent running each statement");
+    let _ = writeln!(out, "# TYPE matrixon_db_statement_seconds summary");
+    for stats in &stats {
+        let _ = writeln!(out, "matrixon_db_statement_seconds_sum{{statement=\"{}\"}} {}", stats.statement, stats.total_us as f64 / 1e6);
+        let _ = writeln!(out, "matrixon_db_statement_seconds_count{{statement=\"{}\"}} {}", stats.statement, stats.calls);
+    }
+    let _ = writeln!(out, "# HELP matrixon_db_statement_errors_total Failed runs of each statement");
+    let _ = writeln!(out, "# TYPE matrixon_db_statement_errors_total counter");
+    for stats in &stats {
+        let _ = writeln!(out, "matrixon_db_statement_errors_total{{statement=\"{}\"}} {}", stats.statement, stats.errors);
+    }
+    let _ = writeln!(out, "# HELP matrixon_db_statement_slow_total Runs of each statement slower than the slow query threshold");
+    let _ = writeln!(out, "# TYPE matrixon_db_statement_slow_total counter");
+    for stats in &stats {
+        let _ = writeln!(out, "matrixon_db_statement_slow_total{{statement=\"{}\"}} {}", stats.statement, stats.slow);
+    }
+    out
+}
+
+/// Times SQLx queries
+pub trait TimedQuery<T>: Future<Output = std::result::Result<T, sqlx::Error>> + Sized {
+    /// Record the latency and outcome of this query under `statement`
+    fn timed(self, statement: &'static str) -> Timed<Self> {
+        Timed { query: Box::pin(self), statement, start: None }
+    }
+}
+
+impl<T, F> TimedQuery<T> for F where F: Future<Output = std::result::Result<T, sqlx::Error>> {}
+
+/// A query being timed, see [`TimedQuery::timed`]
+#[derive(Debug)]
+pub struct Timed<F> {
+    query: Pin<Box<F>>,
+    statement: &'static str,
+    start: Option<Instant>,
+}
+
+impl<T, F> Future for Timed<F>
+where
+    F: Future<Output = std::result::Result<T, sqlx::Error>>,
+{
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let result = ready!(self.query.as_mut().poll(cx));
+        record(self.statement, start.elapsed(), result.is_ok());
+        Poll::Ready(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_statements_are_timed_and_counted() {
+        let ok = futures::future::ready(Ok::<_, sqlx::Error>(1)).timed("test.ok");
+        assert_eq!(ok.await.unwrap(), 1);
+        let failed = futures::future::ready(Err::<(), _>(sqlx::Error::RowNotFound)).timed("test.failed");
+        assert!(failed.await.is_err());
+        record("test.ok", Duration::from_secs(1), true);
+
+        let stats = statement_stats();
+        let ok = stats.iter().find(|stats| stats.statement == "test.ok").unwrap();
+        assert_eq!((ok.calls, ok.errors, ok.slow), (2, 0, 1));
+        assert!(ok.max_us >= 1_000_000 && ok.mean() >= Duration::from_millis(500));
+        let failed = stats.iter().find(|stats| stats.statement == "test.failed").unwrap();
+        assert_eq!((failed.calls, failed.errors), (1, 1));
+        assert!(render().contains("matrixon_db_statement_errors_total{statement=\"test.failed\"} 1\n"));
+    }
+}
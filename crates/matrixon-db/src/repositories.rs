@@ -0,0 +1,1396 @@
+//! Typed repositories for Matrixon
+//!
+//! This module provides one repository per kind of record, keyed by Matrix
+//! identifiers and backed by a shared PostgreSQL pool. Services hold a
+//! [`Repositories`] instead of issuing queries themselves. Timeline reads
+//! may be served by read replicas; accounts and sessions are always read
+//! from the primary so a login sees the registration just before it. Room
+//! events and state live on the shard of their room.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use sqlx::{postgres::{PgPool, PgRow}, Connection, Row};
+use matrixon_core::{Result, MatrixonError};
+use tracing::{debug, info, instrument};
+
+use crate::{
+    migrations,
+    pool::DatabasePool,
+    queries,
+    query_metrics::TimedQuery,
+    sharding::ShardRouter,
+    models::{
+        AuthChainLinkRecord, AuthChainPositionRecord, BotCommandRecord, CompressedStateEvent, DeviceRecord, EventRecord,
+        OutlierRecord, RoomKeyBackupRecord, RoomRecord, SoftFailureRecord, StateDiffRecord, UserRecord,
+    },
+};
+
+fn db_error(e: sqlx::Error) -> MatrixonError {
+    MatrixonError::Database(e.to_string())
+}
+
+/// All repositories over one pool and its room shards
+#[derive(Debug, Clone)]
+pub struct Repositories {
+    pool: DatabasePool,
+    shards: ShardRouter,
+    pub users: UserRepo,
+    pub devices: DeviceRepo,
+    pub rooms: RoomRepo,
+    pub events: EventRepo,
+    pub outliers: OutlierRepo,
+    pub pdu_metadata: PduMetadataRepo,
+    pub auth_chains: AuthChainRepo,
+    pub short_ids: ShortIdRepo,
+    pub bot_audit: BotAuditRepo,
+    pub room_key_backups: RoomKeyBackupRepo,
+}
+
+impl Repositories {
+    /// Repositories keeping everything in one database
+    pub fn new(pool: DatabasePool) -> Self {
+        Self::with_shards(pool.clone(), ShardRouter::single(pool))
+    }
+
+    /// Repositories keeping room events and state on `shards`
+    pub fn with_shards(pool: DatabasePool, shards: ShardRouter) -> Self {
+        Self {
+            users: UserRepo { pool: pool.clone() },
+            devices: DeviceRepo { pool: pool.clone() },
+            rooms: RoomRepo { pool: pool.clone() },
+            events: EventRepo { shards: shards.clone() },
+            outliers: OutlierRepo { shards: shards.clone() },
+            pdu_metadata: PduMetadataRepo { shards: shards.clone() },
+            auth_chains: AuthChainRepo { shards: shards.clone() },
+            short_ids: ShortIdRepo { pool: pool.clone() },
+            bot_audit: BotAuditRepo { pool: pool.clone() },
+            room_key_backups: RoomKeyBackupRepo { pool: pool.clone() },
+            shards,
+            pool,
+        }
+    }
+
+    /// Compressed state of the rooms on the shard of `room_id`
+    pub fn state_for_room(&self, room_id: &str) -> StateRepo {
+        StateRepo { pool: self.shards.for_room(room_id).clone() }
+    }
+
+    pub fn shards(&self) -> &ShardRouter {
+        &self.shards
+    }
+
+    /// Get the inner SQLx pool of the primary
+    pub fn pool(&self) -> &PgPool {
+        self.pool.pool()
+    }
+
+    pub fn database_pool(&self) -> &DatabasePool {
+        &self.pool
+    }
+
+    /// Create the tables the repositories use
+    #[instrument(level = "debug", skip(self))]
+    pub async fn migrate(&self) -> Result<()> {
+        migrations::run_migrations(self.pool.pool()).await?;
+        if self.shards.shards().len() > 1 {
+            self.shards.migrate().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Accounts of local users
+#[derive(Debug, Clone)]
+pub struct UserRepo {
+    pool: DatabasePool,
+}
+
+impl UserRepo {
+    /// Create an account, returning false if the user ID is taken
+    #[instrument(level = "debug", skip(self, user), fields(user_id = %user.user_id))]
+    pub async fn create(&self, user: &UserRecord) -> Result<bool> {
+        let created = sqlx::query(
+            r#"
+            INSERT INTO accounts (user_id, password_hash, is_admin, deactivated, created_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (user_id) DO NOTHING
+            "#,
+        )
+        .bind(&user.user_id)
+        .bind(&user.password_hash)
+        .bind(user.is_admin)
+        .bind(user.deactivated)
+        .bind(user.created_at)
+        .execute(&mut *self.pool.get_conn().await?)
+        .timed("UserRepo::create")
+        .await
+        .map_err(db_error)?
+        .rows_affected()
+            == 1;
+        if created {
+            info!("✅ Created account: {}", user.user_id);
+        }
+        Ok(created)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get(&self, user_id: &str) -> Result<Option<UserRecord>> {
+        let user = sqlx::query(
+            r#"
+            SELECT user_id, password_hash, is_admin, deactivated, created_at
+            FROM accounts
+            WHERE user_id = $1
+            "#,
+        )
+        .bind(user_id)
+        .fetch_optional(&mut *self.pool.get_conn().await?)
+        .timed("UserRepo::get")
+        .await
+        .map_err(db_error)?
+        .map(|row: PgRow| UserRecord {
+            user_id: row.get("user_id"),
+            password_hash: row.get("password_hash"),
+            is_admin: row.get("is_admin"),
+            deactivated: row.get("deactivated"),
+            created_at: row.get("created_at"),
+        });
+        Ok(user)
+    }
+
+    #[instrument(level = "debug", skip(self, password_hash))]
+    pub async fn set_password_hash(&self, user_id: &str, password_hash: &str) -> Result<()> {
+        sqlx::query("UPDATE accounts SET password_hash = $2 WHERE user_id = $1")
+            .bind(user_id)
+            .bind(password_hash)
+            .execute(&mut *self.pool.get_conn().await?)
+            .timed("UserRepo::set_password_hash")
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    pub async fn deactivate(&self, user_id: &str) -> Result<()> {
+        sqlx::query("UPDATE accounts SET deactivated = TRUE, password_hash = NULL WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *self.pool.get_conn().await?)
+            .timed("UserRepo::deactivate")
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+}
+
+/// Devices of local users and their sessions
+#[derive(Debug, Clone)]
+pub struct DeviceRepo {
+    pool: DatabasePool,
+}
+
+impl DeviceRepo {
+    /// Store a device, replacing the session of an existing device with the
+    /// same ID
+    #[instrument(level = "debug", skip(self, device), fields(user_id = %device.user_id, device_id = %device.device_id))]
+    pub async fn upsert(&self, device: &DeviceRecord) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO user_devices (user_id, device_id, display_name, access_token, last_seen_ip, last_seen_ts, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (user_id, device_id) DO UPDATE
+            SET display_name = COALESCE(EXCLUDED.display_name, user_devices.display_name),
+                access_token = EXCLUDED.access_token,
+                last_seen_ip = EXCLUDED.last_seen_ip,
+                last_seen_ts = EXCLUDED.last_seen_ts
+            "#,
+        )
+        .bind(&device.user_id)
+        .bind(&device.device_id)
+        .bind(&device.display_name)
+        .bind(&device.access_token)
+        .bind(&device.last_seen_ip)
+        .bind(device.last_seen_ts)
+        .bind(device.created_at)
+        .execute(&mut *self.pool.get_conn().await?)
+        .timed("DeviceRepo::upsert")
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// The device whose session uses `access_token`
+    #[instrument(level = "debug", skip(self, access_token))]
+    pub async fn by_access_token(&self, access_token: &str) -> Result<Option<DeviceRecord>> {
+        let device = sqlx::query(
+            r#"
+            SELECT user_id, device_id, display_name, access_token, last_seen_ip, last_seen_ts, created_at
+            FROM user_devices
+            WHERE access_token = $1
+            "#,
+        )
+        .bind(access_token)
+        .fetch_optional(&mut *self.pool.get_conn().await?)
+        .timed("DeviceRepo::by_access_token")
+        .await
+        .map_err(db_error)?
+        .map(device_from_row);
+        Ok(device)
+    }
+
+    /// Devices of a user, oldest first
+    #[instrument(level = "debug", skip(self))]
+    pub async fn list(&self, user_id: &str) -> Result<Vec<DeviceRecord>> {
+        let devices = sqlx::query(
+            r#"
+            SELECT user_id, device_id, display_name, access_token, last_seen_ip, last_seen_ts, created_at
+            FROM user_devices
+            WHERE user_id = $1
+            ORDER BY created_at
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&mut *self.pool.get_conn().await?)
+        .timed("DeviceRepo::list")
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(device_from_row)
+        .collect();
+        Ok(devices)
+    }
+
+    /// Delete a device and with it its session
+    #[instrument(level = "debug", skip(self))]
+    pub async fn delete(&self, user_id: &str, device_id: &str) -> Result<()> {
+        debug!("🔧 Deleting device {} of {}", device_id, user_id);
+        sqlx::query("DELETE FROM user_devices WHERE user_id = $1 AND device_id = $2")
+            .bind(user_id)
+            .bind(device_id)
+            .execute(&mut *self.pool.get_conn().await?)
+            .timed("DeviceRepo::delete")
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+}
+
+fn device_from_row(row: PgRow) -> DeviceRecord {
+    DeviceRecord {
+        user_id: row.get("user_id"),
+        device_id: row.get("device_id"),
+        display_name: row.get("display_name"),
+        access_token: row.get("access_token"),
+        last_seen_ip: row.get("last_seen_ip"),
+        last_seen_ts: row.get("last_seen_ts"),
+        created_at: row.get("created_at"),
+    }
+}
+
+/// Rooms known to this server
+#[derive(Debug, Clone)]
+pub struct RoomRepo {
+    pool: DatabasePool,
+}
+
+impl RoomRepo {
+    /// Record a room, returning false if it was known already
+    #[instrument(level = "debug", skip(self, room), fields(room_id = %room.room_id))]
+    pub async fn create(&self, room: &RoomRecord) -> Result<bool> {
+        let created = sqlx::query(
+            r#"
+            INSERT INTO room_records (room_id, creator, room_version, is_public, created_at)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (room_id) DO NOTHING
+            "#,
+        )
+        .bind(&room.room_id)
+        .bind(&room.creator)
+        .bind(&room.room_version)
+        .bind(room.is_public)
+        .bind(room.created_at)
+        .execute(&mut *self.pool.get_conn().await?)
+        .timed("RoomRepo::create")
+        .await
+        .map_err(db_error)?
+        .rows_affected()
+            == 1;
+        Ok(created)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get(&self, room_id: &str) -> Result<Option<RoomRecord>> {
+        let room = sqlx::query(
+            r#"
+            SELECT room_id, creator, room_version, is_public, created_at
+            FROM room_records
+            WHERE room_id = $1
+            "#,
+        )
+        .bind(room_id)
+        .fetch_optional(&mut *self.pool.get_conn().await?)
+        .timed("RoomRepo::get")
+        .await
+        .map_err(db_error)?
+        .map(|row: PgRow| RoomRecord {
+            room_id: row.get("room_id"),
+            creator: row.get("creator"),
+            room_version: row.get("room_version"),
+            is_public: row.get("is_public"),
+            created_at: row.get("created_at"),
+        });
+        Ok(room)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    pub async fn set_public(&self, room_id: &str, is_public: bool) -> Result<()> {
+        sqlx::query("UPDATE room_records SET is_public = $2 WHERE room_id = $1")
+            .bind(room_id)
+            .bind(is_public)
+            .execute(&mut *self.pool.get_conn().await?)
+            .timed("RoomRepo::set_public")
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Delete a room, returning false if it was not stored
+    #[instrument(level = "debug", skip(self))]
+    pub async fn delete(&self, room_id: &str) -> Result<bool> {
+        let deleted = sqlx::query("DELETE FROM room_records WHERE room_id = $1")
+            .bind(room_id)
+            .execute(&mut *self.pool.get_conn().await?)
+            .timed("RoomRepo::delete")
+            .await
+            .map_err(db_error)?
+            .rows_affected()
+            == 1;
+        Ok(deleted)
+    }
+}
+
+/// Room timelines, each on the shard of its room
+#[derive(Debug, Clone)]
+pub struct EventRepo {
+    shards: ShardRouter,
+}
+
+impl EventRepo {
+    /// Store an event; storing it again has no effect
+    #[instrument(level = "debug", skip(self, event), fields(event_id = %event.event_id))]
+    pub async fn insert(&self, event: &EventRecord) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO room_events (event_id, room_id, stream_ordering, ordinal, sender, event_type, state_key, json)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8::jsonb)
+            ON CONFLICT (event_id) DO NOTHING
+            "#,
+        )
+        .bind(&event.event_id)
+        .bind(&event.room_id)
+        .bind(event.stream_ordering)
+        .bind(event.ordinal)
+        .bind(&event.sender)
+        .bind(&event.event_type)
+        .bind(&event.state_key)
+        .bind(event.json.to_string())
+        .execute(&mut *self.shards.for_room(&event.room_id).get_conn().await?)
+        .timed("EventRepo::insert")
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Store a batch of events in one transaction per shard. Events already
+    /// stored get the JSON and ordinal of the batch, so rewritten events
+    /// such as redacted ones replace what was stored, as do imported events
+    /// moved to make room for others; an event queued twice is stored as
+    /// last queued.
+    #[instrument(level = "debug", skip(self, events), fields(events = events.len()))]
+    pub async fn insert_batch(&self, events: &[EventRecord]) -> Result<()> {
+        // A row cannot be updated twice by one statement
+        let mut latest: HashMap<&str, usize> = HashMap::new();
+        for (index, event) in events.iter().enumerate() {
+            latest.insert(&event.event_id, index);
+        }
+        let deduplicated: Vec<EventRecord>;
+        let events = if latest.len() == events.len() {
+            events
+        } else {
+            deduplicated = events
+                .iter()
+                .enumerate()
+                .filter(|(index, event)| latest[event.event_id.as_str()] == *index)
+                .map(|(_, event)| event.clone())
+                .collect();
+            &deduplicated
+        };
+        if self.shards.shards().len() == 1 {
+            return self.insert_shard_batch(&self.shards.shards()[0], events).await;
+        }
+        let mut by_shard: BTreeMap<usize, Vec<EventRecord>> = BTreeMap::new();
+        for event in events {
+            by_shard.entry(self.shards.shard_index(&event.room_id)).or_default().push(event.clone());
+        }
+        for (shard, events) in by_shard {
+            self.insert_shard_batch(&self.shards.shards()[shard], &events).await?;
+        }
+        Ok(())
+    }
+
+    async fn insert_shard_batch(&self, pool: &DatabasePool, events: &[EventRecord]) -> Result<()> {
+        let mut conn = pool.get_conn().await?;
+        let mut tx = conn.begin().await.map_err(db_error)?;
+        sqlx::query(
+            r#"
+            INSERT INTO room_events (event_id, room_id, stream_ordering, ordinal, sender, event_type, state_key, json)
+            SELECT event_id, room_id, stream_ordering, ordinal, sender, event_type, state_key, json::jsonb
+            FROM UNNEST($1::text[], $2::text[], $3::bigint[], $4::bigint[], $5::text[], $6::text[], $7::text[], $8::text[])
+                AS batch (event_id, room_id, stream_ordering, ordinal, sender, event_type, state_key, json)
+            ON CONFLICT (event_id) DO UPDATE SET json = EXCLUDED.json, ordinal = EXCLUDED.ordinal
+            "#,
+        )
+        .bind(events.iter().map(|event| event.event_id.clone()).collect::<Vec<_>>())
+        .bind(events.iter().map(|event| event.room_id.clone()).collect::<Vec<_>>())
+        .bind(events.iter().map(|event| event.stream_ordering).collect::<Vec<_>>())
+        .bind(events.iter().map(|event| event.ordinal).collect::<Vec<_>>())
+        .bind(events.iter().map(|event| event.sender.clone()).collect::<Vec<_>>())
+        .bind(events.iter().map(|event| event.event_type.clone()).collect::<Vec<_>>())
+        .bind(events.iter().map(|event| event.state_key.clone()).collect::<Vec<_>>())
+        .bind(events.iter().map(|event| event.json.to_string()).collect::<Vec<_>>())
+        .execute(&mut *tx)
+        .timed("EventRepo::insert_shard_batch")
+        .await
+        .map_err(db_error)?;
+        tx.commit().await.map_err(db_error)?;
+        Ok(())
+    }
+
+    /// An event by id. Its room is not known, so every shard is asked
+    /// until one has it.
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get(&self, event_id: &str) -> Result<Option<EventRecord>> {
+        for shard in self.shards.shards() {
+            let event = sqlx::query(
+                r#"
+                SELECT event_id, room_id, stream_ordering, ordinal, sender, event_type, state_key, json::text AS json
+                FROM room_events
+                WHERE event_id = $1
+                "#,
+            )
+            .bind(event_id)
+            .fetch_optional(shard.read_pool())
+            .timed("EventRepo::get")
+            .await
+            .map_err(db_error)?
+            .map(event_from_row)
+            .transpose()?;
+            if event.is_some() {
+                return Ok(event);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Every stored event, in stream order, then by ordinal and event id,
+    /// fetched `page_size` at a time from the primaries, for loading the
+    /// timelines at startup
+    pub fn stream(&self, page_size: i64) -> impl Stream<Item = Result<EventRecord>> + Send + 'static {
+        let events = self.clone();
+        queries::paged(None, move |after: Option<(i64, i64, String)>| {
+            let events = events.clone();
+            async move {
+                let after = after.as_ref().map(|(count, ordinal, event_id)| (*count, *ordinal, event_id.as_str()));
+                let page = events.page(after, page_size).await?;
+                let next = page.last().map(|event| Some((event.stream_ordering, event.ordinal, event.event_id.clone())));
+                Ok((page, next))
+            }
+        })
+    }
+
+    /// Up to `limit` events of every room after `after`, a stream position,
+    /// ordinal and event id, in stream order, then by ordinal and event id
+    #[instrument(level = "debug", skip(self))]
+    async fn page(&self, after: Option<(i64, i64, &str)>, limit: i64) -> Result<Vec<EventRecord>> {
+        let (stream_ordering, ordinal, event_id) = after.unwrap_or((i64::MIN, i64::MIN, ""));
+        let mut events = Vec::new();
+        for shard in self.shards.shards() {
+            let rows = sqlx::query(
+                r#"
+                SELECT event_id, room_id, stream_ordering, ordinal, sender, event_type, state_key, json::text AS json
+                FROM room_events
+                WHERE (stream_ordering, ordinal, event_id) > ($1, $2, $3)
+                ORDER BY stream_ordering, ordinal, event_id
+                LIMIT $4
+                "#,
+            )
+            .bind(stream_ordering)
+            .bind(ordinal)
+            .bind(event_id)
+            .bind(limit)
+            .fetch_all(shard.pool())
+            .timed("EventRepo::page")
+            .await
+            .map_err(db_error)?;
+            for row in rows {
+                events.push(event_from_row(row)?);
+            }
+        }
+        events.sort_by(|a, b| (a.stream_ordering, a.ordinal, &a.event_id).cmp(&(b.stream_ordering, b.ordinal, &b.event_id)));
+        events.truncate(limit.max(0) as usize);
+        Ok(events)
+    }
+
+    /// The latest `limit` events of a room before stream position `before`,
+    /// newest first
+    #[instrument(level = "debug", skip(self))]
+    pub async fn room_events(&self, room_id: &str, before: Option<i64>, limit: i64) -> Result<Vec<EventRecord>> {
+        let events = sqlx::query(
+            r#"
+            SELECT event_id, room_id, stream_ordering, ordinal, sender, event_type, state_key, json::text AS json
+            FROM room_events
+            WHERE room_id = $1 AND stream_ordering < $2
+            ORDER BY stream_ordering DESC, ordinal DESC
+            LIMIT $3
+            "#,
+        )
+        .bind(room_id)
+        .bind(before.unwrap_or(i64::MAX))
+        .bind(limit)
+        .fetch_all(self.shards.for_room(room_id).read_pool())
+        .timed("EventRepo::room_events")
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(event_from_row)
+        .collect::<Result<Vec<_>>>()?;
+        Ok(events)
+    }
+
+    /// Delete events of a room, returning how many were stored
+    #[instrument(level = "debug", skip(self, event_ids), fields(events = event_ids.len()))]
+    pub async fn delete(&self, room_id: &str, event_ids: &[String]) -> Result<u64> {
+        let deleted = sqlx::query("DELETE FROM room_events WHERE room_id = $1 AND event_id = ANY($2)")
+            .bind(room_id)
+            .bind(event_ids)
+            .execute(&mut *self.shards.for_room(room_id).get_conn().await?)
+            .timed("EventRepo::delete")
+            .await
+            .map_err(db_error)?
+            .rows_affected();
+        Ok(deleted)
+    }
+
+    /// Delete the events of a room sent before `before_ts`, in milliseconds
+    /// since the epoch, except those in `keep` and those of the senders in
+    /// `keep_senders`. The state groups only the deleted events were at, and
+    /// then their ancestors no longer used, are freed with them. Returns
+    /// how many events were deleted.
+    #[instrument(level = "debug", skip(self, keep, keep_senders), fields(keep = keep.len()))]
+    pub async fn purge_before(&self, room_id: &str, before_ts: i64, keep: &[String], keep_senders: &[String]) -> Result<u64> {
+        let mut conn = self.shards.for_room(room_id).get_conn().await?;
+        let mut tx = conn.begin().await.map_err(db_error)?;
+        let purged: Vec<String> = sqlx::query_scalar(
+            r#"
+            DELETE FROM room_events
+            WHERE room_id = $1
+              AND CASE WHEN jsonb_typeof(json->'origin_server_ts') = 'number'
+                  THEN (json->>'origin_server_ts')::numeric < $2 END
+              AND event_id <> ALL($3)
+              AND sender <> ALL($4)
+            RETURNING event_id
+            "#,
+        )
+        .bind(room_id)
+        .bind(before_ts)
+        .bind(keep)
+        .bind(keep_senders)
+        .fetch_all(&mut *tx)
+        .timed("EventRepo::purge_before")
+        .await
+        .map_err(db_error)?;
+
+        let mut groups: Vec<i64> = sqlx::query_scalar("DELETE FROM event_state_groups WHERE event_id = ANY($1) RETURNING shortstatehash")
+            .bind(&purged)
+            .fetch_all(&mut *tx)
+            .timed("EventRepo::purge_before.event_groups")
+            .await
+            .map_err(db_error)?;
+        let mut freed = 0;
+        while !groups.is_empty() {
+            groups.sort_unstable();
+            groups.dedup();
+            let parents: Vec<Option<i64>> = sqlx::query_scalar(
+                r#"
+                DELETE FROM state_groups g
+                WHERE shortstatehash = ANY($1)
+                  AND NOT EXISTS (SELECT 1 FROM event_state_groups e WHERE e.shortstatehash = g.shortstatehash)
+                  AND NOT EXISTS (SELECT 1 FROM state_groups c WHERE c.parent = g.shortstatehash)
+                RETURNING parent
+                "#,
+            )
+            .bind(&groups)
+            .fetch_all(&mut *tx)
+            .timed("EventRepo::purge_before.state_groups")
+            .await
+            .map_err(db_error)?;
+            freed += parents.len();
+            groups = parents.into_iter().flatten().collect();
+        }
+        tx.commit().await.map_err(db_error)?;
+        debug!("🗑️ Purged {} events of {} and freed {} state groups", purged.len(), room_id, freed);
+        Ok(purged.len() as u64)
+    }
+
+    /// Delete every event of a room, returning how many were stored
+    #[instrument(level = "debug", skip(self))]
+    pub async fn delete_room(&self, room_id: &str) -> Result<u64> {
+        let deleted = sqlx::query("DELETE FROM room_events WHERE room_id = $1")
+            .bind(room_id)
+            .execute(&mut *self.shards.for_room(room_id).get_conn().await?)
+            .timed("EventRepo::delete_room")
+            .await
+            .map_err(db_error)?
+            .rows_affected();
+        Ok(deleted)
+    }
+
+    /// Replace the JSON of a stored event, e.g. with its redacted form
+    #[instrument(level = "debug", skip(self, json))]
+    pub async fn update_json(&self, room_id: &str, event_id: &str, json: &serde_json::Value) -> Result<()> {
+        sqlx::query("UPDATE room_events SET json = $3::jsonb WHERE room_id = $1 AND event_id = $2")
+            .bind(room_id)
+            .bind(event_id)
+            .bind(json.to_string())
+            .execute(&mut *self.shards.for_room(room_id).get_conn().await?)
+            .timed("EventRepo::update_json")
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+}
+
+/// Events kept outside room timelines, each on the shard of its room
+#[derive(Debug, Clone)]
+pub struct OutlierRepo {
+    shards: ShardRouter,
+}
+
+impl OutlierRepo {
+    /// Store an outlier; storing it again has no effect
+    #[instrument(level = "debug", skip(self, json))]
+    pub async fn insert(&self, room_id: &str, event_id: &str, json: &serde_json::Value) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO event_outliers (event_id, room_id, json)
+            VALUES ($1, $2, $3::jsonb)
+            ON CONFLICT (event_id) DO NOTHING
+            "#,
+        )
+        .bind(event_id)
+        .bind(room_id)
+        .bind(json.to_string())
+        .execute(&mut *self.shards.for_room(room_id).get_conn().await?)
+        .timed("OutlierRepo::insert")
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Every stored outlier, read from the primaries, for loading them at
+    /// startup
+    #[instrument(level = "debug", skip(self))]
+    pub async fn load(&self) -> Result<Vec<OutlierRecord>> {
+        let mut outliers = Vec::new();
+        for shard in self.shards.shards() {
+            let rows = sqlx::query("SELECT event_id, room_id, json::text AS json, received_at FROM event_outliers")
+                .fetch_all(shard.pool())
+                .timed("OutlierRepo::load")
+                .await
+                .map_err(db_error)?;
+            for row in rows {
+                let json: String = row.get("json");
+                outliers.push(OutlierRecord {
+                    event_id: row.get("event_id"),
+                    room_id: row.get("room_id"),
+                    json: serde_json::from_str(&json).map_err(|e| MatrixonError::Deserialization(e.to_string()))?,
+                    received_at: row.get("received_at"),
+                });
+            }
+        }
+        Ok(outliers)
+    }
+
+    /// Delete an outlier, e.g. once it is part of the timeline
+    #[instrument(level = "debug", skip(self))]
+    pub async fn delete(&self, room_id: &str, event_id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM event_outliers WHERE room_id = $1 AND event_id = $2")
+            .bind(room_id)
+            .bind(event_id)
+            .execute(&mut *self.shards.for_room(room_id).get_conn().await?)
+            .timed("OutlierRepo::delete")
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Delete every outlier of a room, returning how many were stored
+    #[instrument(level = "debug", skip(self))]
+    pub async fn delete_room(&self, room_id: &str) -> Result<u64> {
+        let deleted = sqlx::query("DELETE FROM event_outliers WHERE room_id = $1")
+            .bind(room_id)
+            .execute(&mut *self.shards.for_room(room_id).get_conn().await?)
+            .timed("OutlierRepo::delete_room")
+            .await
+            .map_err(db_error)?
+            .rows_affected();
+        Ok(deleted)
+    }
+
+    /// Delete the outliers received before `before` that no event of their
+    /// room references, with their soft failures, returning how many.
+    /// References are only kept to outliers, so those to pruned outliers
+    /// and to events that stopped being outliers are deleted as well.
+    #[instrument(level = "debug", skip(self))]
+    pub async fn prune(&self, before: DateTime<Utc>) -> Result<u64> {
+        let mut pruned = 0;
+        for shard in self.shards.shards() {
+            let row = sqlx::query(
+                r#"
+                WITH pruned AS (
+                    DELETE FROM event_outliers o
+                    WHERE o.received_at < $1
+                      AND NOT EXISTS (
+                          SELECT 1 FROM event_references r WHERE r.room_id = o.room_id AND r.event_id = o.event_id
+                      )
+                    RETURNING o.event_id
+                ), forgotten AS (
+                    DELETE FROM soft_failed_events s USING pruned p WHERE s.event_id = p.event_id
+                ), unreferenced AS (
+                    DELETE FROM event_references r
+                    WHERE r.event_id IN (SELECT event_id FROM pruned)
+                       OR NOT EXISTS (SELECT 1 FROM event_outliers o WHERE o.event_id = r.event_id)
+                )
+                SELECT COUNT(*) AS pruned FROM pruned
+                "#,
+            )
+            .bind(before)
+            .fetch_one(&mut *shard.get_conn().await?)
+            .timed("OutlierRepo::prune")
+            .await
+            .map_err(db_error)?;
+            pruned += row.get::<i64, _>("pruned") as u64;
+        }
+        if pruned > 0 {
+            debug!("🧹 Pruned {} outliers", pruned);
+        }
+        Ok(pruned)
+    }
+}
+
+/// Which events other events reference and which were soft-failed, each
+/// on the shard of its room
+#[derive(Debug, Clone)]
+pub struct PduMetadataRepo {
+    shards: ShardRouter,
+}
+
+impl PduMetadataRepo {
+    /// Remember that events of a room reference `event_ids`
+    #[instrument(level = "debug", skip(self, event_ids), fields(events = event_ids.len()))]
+    pub async fn mark_referenced(&self, room_id: &str, event_ids: &[String]) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO event_references (room_id, event_id)
+            SELECT $1, * FROM UNNEST($2::text[])
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(room_id)
+        .bind(event_ids)
+        .execute(&mut *self.shards.for_room(room_id).get_conn().await?)
+        .timed("PduMetadataRepo::mark_referenced")
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Every stored (room, event) reference, read from the primaries, for
+    /// loading them at startup
+    #[instrument(level = "debug", skip(self))]
+    pub async fn load_references(&self) -> Result<Vec<(String, String)>> {
+        let mut references = Vec::new();
+        for shard in self.shards.shards() {
+            let rows = sqlx::query("SELECT room_id, event_id FROM event_references")
+                .fetch_all(shard.pool())
+                .timed("PduMetadataRepo::load_references")
+                .await
+                .map_err(db_error)?;
+            references.extend(rows.into_iter().map(|row| (row.get("room_id"), row.get("event_id"))));
+        }
+        Ok(references)
+    }
+
+    /// Remember that `event_id` was soft-failed for `reason`
+    #[instrument(level = "debug", skip(self))]
+    pub async fn mark_soft_failed(&self, room_id: &str, event_id: &str, reason: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO soft_failed_events (event_id, room_id, reason)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (event_id) DO NOTHING
+            "#,
+        )
+        .bind(event_id)
+        .bind(room_id)
+        .bind(reason)
+        .execute(&mut *self.shards.for_room(room_id).get_conn().await?)
+        .timed("PduMetadataRepo::mark_soft_failed")
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Every stored soft failure, read from the primaries, for loading them
+    /// at startup
+    #[instrument(level = "debug", skip(self))]
+    pub async fn load_soft_failed(&self) -> Result<Vec<SoftFailureRecord>> {
+        let mut soft_failed = Vec::new();
+        for shard in self.shards.shards() {
+            let rows = sqlx::query("SELECT event_id, room_id, reason FROM soft_failed_events")
+                .fetch_all(shard.pool())
+                .timed("PduMetadataRepo::load_soft_failed")
+                .await
+                .map_err(db_error)?;
+            soft_failed.extend(rows.into_iter().map(|row| SoftFailureRecord {
+                event_id: row.get("event_id"),
+                room_id: row.get("room_id"),
+                reason: row.get("reason"),
+            }));
+        }
+        Ok(soft_failed)
+    }
+
+    /// Forget the references and soft failures of a room
+    #[instrument(level = "debug", skip(self))]
+    pub async fn delete_room(&self, room_id: &str) -> Result<()> {
+        let mut conn = self.shards.for_room(room_id).get_conn().await?;
+        let mut tx = conn.begin().await.map_err(db_error)?;
+        sqlx::query("DELETE FROM event_references WHERE room_id = $1")
+            .bind(room_id)
+            .execute(&mut *tx)
+            .timed("PduMetadataRepo::delete_room.references")
+            .await
+            .map_err(db_error)?;
+        sqlx::query("DELETE FROM soft_failed_events WHERE room_id = $1")
+            .bind(room_id)
+            .execute(&mut *tx)
+            .timed("PduMetadataRepo::delete_room.soft_failed")
+            .await
+            .map_err(db_error)?;
+        tx.commit().await.map_err(db_error)?;
+        Ok(())
+    }
+}
+
+/// Auth chain index of events, each on the shard of its room
+#[derive(Debug, Clone)]
+pub struct AuthChainRepo {
+    shards: ShardRouter,
+}
+
+impl AuthChainRepo {
+    /// Store the position of an event on its chain, replacing an earlier
+    /// one, and its links to (chain, sequence number) positions on other
+    /// chains
+    #[instrument(level = "debug", skip(self, links), fields(links = links.len()))]
+    pub async fn insert(&self, position: &AuthChainPositionRecord, links: &[(i64, i64)]) -> Result<()> {
+        let (target_chains, target_sequence_numbers): (Vec<i64>, Vec<i64>) = links.iter().copied().unzip();
+        let mut conn = self.shards.for_room(&position.room_id).get_conn().await?;
+        let mut tx = conn.begin().await.map_err(db_error)?;
+        sqlx::query(
+            r#"
+            INSERT INTO event_auth_chains (event_id, room_id, event_type, state_key, chain_id, sequence_number)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (event_id) DO UPDATE
+            SET chain_id = EXCLUDED.chain_id, sequence_number = EXCLUDED.sequence_number
+            "#,
+        )
+        .bind(&position.event_id)
+        .bind(&position.room_id)
+        .bind(&position.event_type)
+        .bind(&position.state_key)
+        .bind(position.chain_id)
+        .bind(position.sequence_number)
+        .execute(&mut *tx)
+        .timed("AuthChainRepo::insert.position")
+        .await
+        .map_err(db_error)?;
+        sqlx::query(
+            r#"
+            INSERT INTO event_auth_chain_links
+                (room_id, origin_chain_id, origin_sequence_number, target_chain_id, target_sequence_number)
+            SELECT $1, $2, $3, * FROM UNNEST($4::bigint[], $5::bigint[])
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(&position.room_id)
+        .bind(position.chain_id)
+        .bind(position.sequence_number)
+        .bind(&target_chains)
+        .bind(&target_sequence_numbers)
+        .execute(&mut *tx)
+        .timed("AuthChainRepo::insert.links")
+        .await
+        .map_err(db_error)?;
+        tx.commit().await.map_err(db_error)?;
+        Ok(())
+    }
+
+    /// Every stored position, read from the primaries in order along their
+    /// chains, and every stored link, for loading the index at startup
+    #[instrument(level = "debug", skip(self))]
+    pub async fn load(&self) -> Result<(Vec<AuthChainPositionRecord>, Vec<AuthChainLinkRecord>)> {
+        let (mut positions, mut links) = (Vec::new(), Vec::new());
+        for shard in self.shards.shards() {
+            let rows = sqlx::query(
+                r#"
+                SELECT event_id, room_id, event_type, state_key, chain_id, sequence_number
+                FROM event_auth_chains
+                ORDER BY chain_id, sequence_number
+                "#,
+            )
+            .fetch_all(shard.pool())
+            .timed("AuthChainRepo::load.positions")
+            .await
+            .map_err(db_error)?;
+            positions.extend(rows.into_iter().map(|row| AuthChainPositionRecord {
+                event_id: row.get("event_id"),
+                room_id: row.get("room_id"),
+                event_type: row.get("event_type"),
+                state_key: row.get("state_key"),
+                chain_id: row.get("chain_id"),
+                sequence_number: row.get("sequence_number"),
+            }));
+            let rows = sqlx::query(
+                r#"
+                SELECT room_id, origin_chain_id, origin_sequence_number, target_chain_id, target_sequence_number
+                FROM event_auth_chain_links
+                "#,
+            )
+            .fetch_all(shard.pool())
+            .timed("AuthChainRepo::load.links")
+            .await
+            .map_err(db_error)?;
+            links.extend(rows.into_iter().map(|row| AuthChainLinkRecord {
+                room_id: row.get("room_id"),
+                origin_chain_id: row.get("origin_chain_id"),
+                origin_sequence_number: row.get("origin_sequence_number"),
+                target_chain_id: row.get("target_chain_id"),
+                target_sequence_number: row.get("target_sequence_number"),
+            }));
+        }
+        Ok((positions, links))
+    }
+
+    /// Forget the auth chain index of a room
+    #[instrument(level = "debug", skip(self))]
+    pub async fn delete_room(&self, room_id: &str) -> Result<()> {
+        let mut conn = self.shards.for_room(room_id).get_conn().await?;
+        let mut tx = conn.begin().await.map_err(db_error)?;
+        sqlx::query("DELETE FROM event_auth_chain_links WHERE room_id = $1")
+            .bind(room_id)
+            .execute(&mut *tx)
+            .timed("AuthChainRepo::delete_room.links")
+            .await
+            .map_err(db_error)?;
+        sqlx::query("DELETE FROM event_auth_chains WHERE room_id = $1")
+            .bind(room_id)
+            .execute(&mut *tx)
+            .timed("AuthChainRepo::delete_room.positions")
+            .await
+            .map_err(db_error)?;
+        tx.commit().await.map_err(db_error)?;
+        Ok(())
+    }
+}
+
+/// Short ids standing in for state keys and event ids, shared by all rooms
+/// and so kept on the primary
+#[derive(Debug, Clone)]
+pub struct ShortIdRepo {
+    pool: DatabasePool,
+}
+
+impl ShortIdRepo {
+    /// Short id of a (type, state key) pair, drawn from the sequence the
+    /// first time the pair is seen. The no-op update makes the row of a
+    /// concurrent insert come back too.
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get_or_create_shortstatekey(&self, event_type: &str, state_key: &str) -> Result<u64> {
+        let row = sqlx::query(
+            r#"
+            INSERT INTO short_state_keys (event_type, state_key)
+            VALUES ($1, $2)
+            ON CONFLICT (event_type, state_key) DO UPDATE SET event_type = EXCLUDED.event_type
+            RETURNING shortstatekey
+            "#,
+        )
+        .bind(event_type)
+        .bind(state_key)
+        .fetch_one(&mut *self.pool.get_conn().await?)
+        .timed("ShortIdRepo::get_or_create_shortstatekey")
+        .await
+        .map_err(db_error)?;
+        Ok(row.get::<i64, _>("shortstatekey") as u64)
+    }
+
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get_shortstatekey(&self, event_type: &str, state_key: &str) -> Result<Option<u64>> {
+        let row = sqlx::query("SELECT shortstatekey FROM short_state_keys WHERE event_type = $1 AND state_key = $2")
+            .bind(event_type)
+            .bind(state_key)
+            .fetch_optional(self.pool.pool())
+            .timed("ShortIdRepo::get_shortstatekey")
+            .await
+            .map_err(db_error)?;
+        Ok(row.map(|row| row.get::<i64, _>("shortstatekey") as u64))
+    }
+
+    /// The (type, state key) pair of a short state key. Ids are looked up
+    /// as soon as they are handed out, so reads go to the primary.
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get_statekey_from_short(&self, shortstatekey: u64) -> Result<Option<(String, String)>> {
+        let row = sqlx::query("SELECT event_type, state_key FROM short_state_keys WHERE shortstatekey = $1")
+            .bind(shortstatekey as i64)
+            .fetch_optional(self.pool.pool())
+            .timed("ShortIdRepo::get_statekey_from_short")
+            .await
+            .map_err(db_error)?;
+        Ok(row.map(|row| (row.get("event_type"), row.get("state_key"))))
+    }
+
+    /// Short id of an event, drawn from the sequence the first time the
+    /// event is seen, like [`Self::get_or_create_shortstatekey`]
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get_or_create_shorteventid(&self, event_id: &str) -> Result<u64> {
+        let row = sqlx::query(
+            r#"
+            INSERT INTO short_event_ids (event_id)
+            VALUES ($1)
+            ON CONFLICT (event_id) DO UPDATE SET event_id = EXCLUDED.event_id
+            RETURNING shorteventid
+            "#,
+        )
+        .bind(event_id)
+        .fetch_one(&mut *self.pool.get_conn().await?)
+        .timed("ShortIdRepo::get_or_create_shorteventid")
+        .await
+        .map_err(db_error)?;
+        Ok(row.get::<i64, _>("shorteventid") as u64)
+    }
+
+    /// The event id of a short event id
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get_eventid_from_short(&self, shorteventid: u64) -> Result<Option<String>> {
+        let row = sqlx::query("SELECT event_id FROM short_event_ids WHERE shorteventid = $1")
+            .bind(shorteventid as i64)
+            .fetch_optional(self.pool.pool())
+            .timed("ShortIdRepo::get_eventid_from_short")
+            .await
+            .map_err(db_error)?;
+        Ok(row.map(|row| row.get("event_id")))
+    }
+}
+
+/// Audit log of commands executed by the bot
+#[derive(Debug, Clone)]
+pub struct BotAuditRepo {
+    pool: DatabasePool,
+}
+
+impl BotAuditRepo {
+    #[instrument(level = "debug", skip(self, record), fields(command = %record.command, room_id = %record.room_id))]
+    pub async fn record(&self, record: &BotCommandRecord) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO bot_command_log (user_id, room_id, command, args, success, result, duration_ms, executed_at)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            "#,
+        )
+        .bind(&record.user_id)
+        .bind(&record.room_id)
+        .bind(&record.command)
+        .bind(&record.args)
+        .bind(record.success)
+        .bind(&record.result)
+        .bind(record.duration_ms)
+        .bind(record.executed_at)
+        .execute(&mut *self.pool.get_conn().await?)
+        .timed("BotAuditRepo::record")
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// The latest `limit` commands executed in a room, optionally only
+    /// those of one user, newest first
+    #[instrument(level = "debug", skip(self))]
+    pub async fn history(&self, room_id: &str, user_id: Option<&str>, limit: i64) -> Result<Vec<BotCommandRecord>> {
+        let records = sqlx::query(
+            r#"
+            SELECT user_id, room_id, command, args, success, result, duration_ms, executed_at
+            FROM bot_command_log
+            WHERE room_id = $1 AND ($2::text IS NULL OR user_id = $2)
+            ORDER BY executed_at DESC, id DESC
+            LIMIT $3
+            "#,
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .bind(limit)
+        .fetch_all(self.pool.read_pool())
+        .timed("BotAuditRepo::history")
+        .await
+        .map_err(db_error)?
+        .into_iter()
+        .map(|row: PgRow| BotCommandRecord {
+            user_id: row.get("user_id"),
+            room_id: row.get("room_id"),
+            command: row.get("command"),
+            args: row.get("args"),
+            success: row.get("success"),
+            result: row.get("result"),
+            duration_ms: row.get("duration_ms"),
+            executed_at: row.get("executed_at"),
+        })
+        .collect();
+        Ok(records)
+    }
+
+    /// Delete commands executed before `before`, returning how many
+    #[instrument(level = "debug", skip(self))]
+    pub async fn prune(&self, before: DateTime<Utc>) -> Result<u64> {
+        let pruned = sqlx::query("DELETE FROM bot_command_log WHERE executed_at < $1")
+            .bind(before)
+            .execute(&mut *self.pool.get_conn().await?)
+            .timed("BotAuditRepo::prune")
+            .await
+            .map_err(db_error)?
+            .rows_affected();
+        if pruned > 0 {
+            debug!("🧹 Pruned {} bot audit log entries", pruned);
+        }
+        Ok(pruned)
+    }
+}
+
+/// Encrypted room key backups of users, read from the primary
+#[derive(Debug, Clone)]
+pub struct RoomKeyBackupRepo {
+    pool: DatabasePool,
+}
+
+impl RoomKeyBackupRepo {
+    /// Replace the backup versions of a user
+    #[instrument(level = "debug", skip(self, backups))]
+    pub async fn save(&self, user_id: &str, backups: &serde_json::Value) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO room_key_backups (user_id, backups, updated_at)
+            VALUES ($1, $2::jsonb, NOW())
+            ON CONFLICT (user_id) DO UPDATE SET backups = EXCLUDED.backups, updated_at = EXCLUDED.updated_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(backups.to_string())
+        .execute(&mut *self.pool.get_conn().await?)
+        .timed("RoomKeyBackupRepo::save")
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// The backup versions of every user, for loading them at startup
+    #[instrument(level = "debug", skip(self))]
+    pub async fn load(&self) -> Result<Vec<RoomKeyBackupRecord>> {
+        let rows = sqlx::query("SELECT user_id, backups::text AS backups FROM room_key_backups")
+            .fetch_all(self.pool.pool())
+            .timed("RoomKeyBackupRepo::load")
+            .await
+            .map_err(db_error)?;
+        rows.into_iter()
+            .map(|row| {
+                let backups: String = row.get("backups");
+                Ok(RoomKeyBackupRecord {
+                    user_id: row.get("user_id"),
+                    backups: serde_json::from_str(&backups).map_err(|e| MatrixonError::Deserialization(e.to_string()))?,
+                })
+            })
+            .collect()
+    }
+}
+
+fn event_from_row(row: PgRow) -> Result<EventRecord> {
+    let json: String = row.get("json");
+    Ok(EventRecord {
+        event_id: row.get("event_id"),
+        room_id: row.get("room_id"),
+        stream_ordering: row.get("stream_ordering"),
+        ordinal: row.get("ordinal"),
+        sender: row.get("sender"),
+        event_type: row.get("event_type"),
+        state_key: row.get("state_key"),
+        json: serde_json::from_str(&json).map_err(|e| MatrixonError::Deserialization(e.to_string()))?,
+    })
+}
+
+/// Compressed room state. A state group is stored as the compressed state
+/// events added to and removed from the state of its parent group. Groups
+/// are read from the primary, as the group of a new event is read back
+/// right after it is saved.
+#[derive(Debug, Clone)]
+pub struct StateRepo {
+    pool: DatabasePool,
+}
+
+impl StateRepo {
+    /// Store the delta of a state group, replacing what was stored for it
+    #[instrument(level = "debug", skip(self, diff))]
+    pub async fn save_diff(&self, shortstatehash: u64, diff: &StateDiffRecord) -> Result<()> {
+        let (events, added): (Vec<Vec<u8>>, Vec<bool>) = diff
+            .added
+            .iter()
+            .map(|event| (event.to_vec(), true))
+            .chain(diff.removed.iter().map(|event| (event.to_vec(), false)))
+            .unzip();
+
+        let mut conn = self.pool.get_conn().await?;
+        let mut tx = conn.begin().await.map_err(db_error)?;
+        sqlx::query(
+            r#"
+            INSERT INTO state_groups (shortstatehash, parent)
+            VALUES ($1, $2)
+            ON CONFLICT (shortstatehash) DO UPDATE SET parent = EXCLUDED.parent
+            "#,
+        )
+        .bind(shortstatehash as i64)
+        .bind(diff.parent.map(|parent| parent as i64))
+        .execute(&mut *tx)
+        .timed("StateRepo::save_diff.group")
+        .await
+        .map_err(db_error)?;
+        sqlx::query("DELETE FROM state_group_deltas WHERE shortstatehash = $1")
+            .bind(shortstatehash as i64)
+            .execute(&mut *tx)
+            .timed("StateRepo::save_diff.clear")
+            .await
+            .map_err(db_error)?;
+        sqlx::query(
+            r#"
+            INSERT INTO state_group_deltas (shortstatehash, compressed_event, added)
+            SELECT $1, * FROM UNNEST($2::bytea[], $3::boolean[])
+            "#,
+        )
+        .bind(shortstatehash as i64)
+        .bind(events)
+        .bind(added)
+        .execute(&mut *tx)
+        .timed("StateRepo::save_diff.deltas")
+        .await
+        .map_err(db_error)?;
+        tx.commit().await.map_err(db_error)?;
+        debug!("💾 Saved state group {} ({} added, {} removed)", shortstatehash, diff.added.len(), diff.removed.len());
+        Ok(())
+    }
+
+    /// Record that the room state after `event_id` is the state group
+    /// `shortstatehash`, which must be stored
+    #[instrument(level = "debug", skip(self))]
+    pub async fn set_event_group(&self, event_id: &str, shortstatehash: u64) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO event_state_groups (event_id, shortstatehash)
+            VALUES ($1, $2)
+            ON CONFLICT (event_id) DO UPDATE SET shortstatehash = EXCLUDED.shortstatehash
+            "#,
+        )
+        .bind(event_id)
+        .bind(shortstatehash as i64)
+        .execute(&mut *self.pool.get_conn().await?)
+        .timed("StateRepo::set_event_group")
+        .await
+        .map_err(db_error)?;
+        Ok(())
+    }
+
+    /// The delta of a state group, if it was stored
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get_diff(&self, shortstatehash: u64) -> Result<Option<StateDiffRecord>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT g.shortstatehash, g.parent, d.compressed_event, d.added
+            FROM state_groups g
+            LEFT JOIN state_group_deltas d ON d.shortstatehash = g.shortstatehash
+            WHERE g.shortstatehash = $1
+            "#,
+        )
+        .bind(shortstatehash as i64)
+        .fetch_all(&mut *self.pool.get_conn().await?)
+        .timed("StateRepo::get_diff")
+        .await
+        .map_err(db_error)?;
+        Ok(diffs_from_rows(rows)?.pop().map(|(_, diff)| diff))
+    }
+
+    /// The delta of a state group followed by those of its ancestors, up to
+    /// the group holding a full state
+    #[instrument(level = "debug", skip(self))]
+    pub async fn diff_chain(&self, shortstatehash: u64) -> Result<Vec<(u64, StateDiffRecord)>> {
+        let rows = sqlx::query(
+            r#"
+            WITH RECURSIVE chain (shortstatehash, parent, depth) AS (
+                SELECT shortstatehash, parent, 0 FROM state_groups WHERE shortstatehash = $1
+                UNION ALL
+                SELECT g.shortstatehash, g.parent, c.depth + 1
+                FROM state_groups g
+                JOIN chain c ON g.shortstatehash = c.parent
+            )
+            SELECT c.shortstatehash, c.parent, d.compressed_event, d.added
+            FROM chain c
+            LEFT JOIN state_group_deltas d ON d.shortstatehash = c.shortstatehash
+            ORDER BY c.depth
+            "#,
+        )
+        .bind(shortstatehash as i64)
+        .fetch_all(&mut *self.pool.get_conn().await?)
+        .timed("StateRepo::diff_chain")
+        .await
+        .map_err(db_error)?;
+        diffs_from_rows(rows)
+    }
+}
+
+/// Group rows of state groups joined with their deltas, in order
+fn diffs_from_rows(rows: Vec<PgRow>) -> Result<Vec<(u64, StateDiffRecord)>> {
+    let mut diffs: Vec<(u64, StateDiffRecord)> = Vec::new();
+    for row in rows {
+        let shortstatehash = row.get::<i64, _>("shortstatehash") as u64;
+        if diffs.last().is_none_or(|(last, _)| *last != shortstatehash) {
+            let parent = row.get::<Option<i64>, _>("parent").map(|parent| parent as u64);
+            diffs.push((shortstatehash, StateDiffRecord { parent, ..Default::default() }));
+        }
+        let Some(event) = row.get::<Option<Vec<u8>>, _>("compressed_event") else {
+            continue;
+        };
+        let event = CompressedStateEvent::try_from(event.as_slice())
+            .map_err(|_| MatrixonError::Deserialization(format!("Invalid compressed state event in group {}", shortstatehash)))?;
+        let (_, diff) = diffs.last_mut().expect("pushed above");
+        if row.get::<bool, _>("added") {
+            diff.added.insert(event);
+        } else {
+            diff.removed.insert(event);
+        }
+    }
+    Ok(diffs)
+}
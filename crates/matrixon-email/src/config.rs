@@ -0,0 +1,191 @@
+//! Email configuration
+//!
+//! The SMTP relay used for every outgoing email, the retry policy of the
+//! send queue and the rate limits applied to each kind of email.
+
+use std::{path::PathBuf, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// How the connection to the SMTP relay is secured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsMode {
+    /// Plaintext, only for relays on the same host
+    None,
+    /// Plaintext connection upgraded with `STARTTLS`, usually on port 587
+    #[default]
+    StartTls,
+    /// TLS from the first byte, usually on port 465
+    Implicit,
+}
+
+impl TlsMode {
+    /// Port used when the configuration does not set one
+    pub fn default_port(self) -> u16 {
+        match self {
+            TlsMode::None => 25,
+            TlsMode::StartTls => 587,
+            TlsMode::Implicit => 465,
+        }
+    }
+}
+
+/// SMTP relay settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmtpConfig {
+    /// Host name of the relay, also checked against its certificate
+    pub host: String,
+    /// Port of the relay, by default the usual port of the TLS mode
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// Transport security
+    #[serde(default)]
+    pub tls: TlsMode,
+    /// Accept any certificate of the relay. Only meant for testing.
+    #[serde(default)]
+    pub accept_invalid_certs: bool,
+    /// User name for `AUTH PLAIN`; no authentication when unset
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Password for `AUTH PLAIN`
+    #[serde(default)]
+    pub password: Option<String>,
+    /// Sender address, e.g. `Matrixon <noreply@example.org>`
+    pub from: String,
+    /// Name announced in `EHLO`, by default the host name of the sender address
+    #[serde(default)]
+    pub helo_name: Option<String>,
+    /// Timeout of every exchange with the relay, in seconds
+    #[serde(default = "default_timeout_s")]
+    pub timeout_s: u64,
+}
+
+impl SmtpConfig {
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or_else(|| self.tls.default_port())
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_s)
+    }
+
+    /// Bare address of the sender, without its display name
+    pub fn from_address(&self) -> &str {
+        bare_address(&self.from)
+    }
+
+    pub fn helo_name(&self) -> &str {
+        self.helo_name
+            .as_deref()
+            .or_else(|| self.from_address().rsplit_once('@').map(|(_, domain)| domain))
+            .unwrap_or("localhost")
+    }
+}
+
+/// What an email is sent for. Each purpose has its own rate limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Purpose {
+    /// 3PID validation tokens
+    Validation,
+    /// Password reset tokens
+    PasswordReset,
+    /// Digests of missed notifications
+    Notification,
+    /// Operator alerts of the monitor
+    Alert,
+}
+
+impl Purpose {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Purpose::Validation => "validation",
+            Purpose::PasswordReset => "password_reset",
+            Purpose::Notification => "notification",
+            Purpose::Alert => "alert",
+        }
+    }
+}
+
+/// Most emails of each purpose sent to one recipient per hour
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RateLimits {
+    pub validation: u32,
+    pub password_reset: u32,
+    pub notification: u32,
+    pub alert: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            validation: 10,
+            password_reset: 5,
+            notification: 2,
+            alert: 60,
+        }
+    }
+}
+
+impl RateLimits {
+    pub fn per_hour(&self, purpose: Purpose) -> u32 {
+        match purpose {
+            Purpose::Validation => self.validation,
+            Purpose::PasswordReset => self.password_reset,
+            Purpose::Notification => self.notification,
+            Purpose::Alert => self.alert,
+        }
+    }
+}
+
+/// Email subsystem settings
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailConfig {
+    pub smtp: SmtpConfig,
+    /// Directory with templates overriding the built-in ones
+    #[serde(default)]
+    pub template_dir: Option<PathBuf>,
+    /// Emails waiting for delivery before new ones are refused
+    #[serde(default = "default_queue_capacity")]
+    pub queue_capacity: usize,
+    /// Delivery attempts before an email is dropped
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled after every failed attempt
+    #[serde(default = "default_retry_delay_s")]
+    pub retry_delay_s: u64,
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+}
+
+impl EmailConfig {
+    pub fn retry_delay(&self, attempt: u32) -> Duration {
+        Duration::from_secs(self.retry_delay_s.saturating_mul(1 << attempt.saturating_sub(1).min(16)))
+    }
+}
+
+/// `Name <user@host>` or `user@host` to `user@host`
+pub fn bare_address(mailbox: &str) -> &str {
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => mailbox[start + 1..end].trim(),
+        _ => mailbox.trim(),
+    }
+}
+
+fn default_timeout_s() -> u64 {
+    30
+}
+
+fn default_queue_capacity() -> usize {
+    1000
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_retry_delay_s() -> u64 {
+    30
+}
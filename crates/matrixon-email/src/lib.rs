@@ -0,0 +1,72 @@
+// =============================================================================
+// Matrixon Email - SMTP Delivery
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Outgoing email shared by every component that needs it: 3PID validation
+//   and password reset tokens, digests of missed notifications and monitor
+//   alerts. Emails are rendered from per-purpose templates, rate limited
+//   per purpose and recipient, and delivered from a queue through an SMTP
+//   relay, retrying temporary failures with exponential backoff.
+//
+// =============================================================================
+
+use thiserror::Error;
+
+pub mod config;
+pub mod mailer;
+pub mod smtp;
+pub mod template;
+
+pub use config::{EmailConfig, Purpose, SmtpConfig, TlsMode};
+pub use mailer::Mailer;
+
+/// Email error types
+#[derive(Error, Debug)]
+pub enum EmailError {
+    #[error("Configuration error: {0}")]
+    Configuration(String),
+
+    #[error("Template error: {0}")]
+    Template(String),
+
+    #[error("Invalid email address: {0}")]
+    InvalidAddress(String),
+
+    #[error("Too many {0} emails to this address, try again later")]
+    RateLimited(&'static str),
+
+    #[error("The email queue is full")]
+    QueueFull,
+
+    #[error("Network error: {0}")]
+    Network(String),
+
+    #[error("TLS error: {0}")]
+    Tls(String),
+
+    #[error("SMTP relay replied {code}: {message}")]
+    Smtp { code: u16, message: String },
+
+    #[error("Timeout error: {0}")]
+    Timeout(String),
+}
+
+impl EmailError {
+    /// Whether delivering the same email again later may succeed
+    pub fn is_transient(&self) -> bool {
+        match self {
+            EmailError::Smtp { code, .. } => *code < 500,
+            EmailError::Network(_) | EmailError::Tls(_) | EmailError::Timeout(_) => true,
+            _ => false,
+        }
+    }
+}
+
+pub type Result<T, E = EmailError> = std::result::Result<T, E>;
@@ -0,0 +1,193 @@
+//! Send queue
+//!
+//! [`Mailer::send`] renders an email, checks the rate limit of its purpose
+//! for the recipient and queues it; [`Mailer::run`] delivers the queue.
+//! Callers never wait for the relay. Emails failing with a temporary error
+//! are queued again after a growing delay, until `max_attempts`.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use matrixon_common::i18n::Catalog;
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::{bare_address, EmailConfig, Purpose},
+    smtp::{self, Message},
+    template::Templates,
+    EmailError, Result,
+};
+
+/// Window of the per-purpose rate limits
+const RATE_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Recipients tracked before the rate limiter forgets idle ones
+const MAX_TRACKED: usize = 10_000;
+
+#[derive(Debug)]
+struct Queued {
+    purpose: Purpose,
+    message: Message,
+    attempt: u32,
+}
+
+/// Renders, rate limits and delivers emails
+#[derive(Debug)]
+pub struct Mailer {
+    config: EmailConfig,
+    templates: Templates,
+    queue: mpsc::Sender<Queued>,
+    receiver: Mutex<Option<mpsc::Receiver<Queued>>>,
+    sent: Mutex<HashMap<(Purpose, String), VecDeque<Instant>>>,
+}
+
+impl Mailer {
+    pub fn new(config: EmailConfig) -> Result<Self> {
+        let templates = Templates::load(config.template_dir.as_deref())?;
+        if !config.smtp.from_address().contains('@') {
+            return Err(EmailError::Configuration(format!("Invalid sender address {}", config.smtp.from)));
+        }
+        let (queue, receiver) = mpsc::channel(config.queue_capacity.max(1));
+        Ok(Self {
+            config,
+            templates,
+            queue,
+            receiver: Mutex::new(Some(receiver)),
+            sent: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Take the template messages from `catalog`, which must include
+    /// [`crate::template::MESSAGES`]
+    pub fn with_catalog(mut self, catalog: Arc<Catalog>) -> Self {
+        self.templates.set_catalog(catalog);
+        self
+    }
+
+    /// Queue the email of `purpose` to `to`, rendered with `vars` in the
+    /// default locale
+    pub fn send(&self, purpose: Purpose, to: &str, vars: &Value) -> Result<()> {
+        self.send_in(purpose, to, None, vars)
+    }
+
+    /// Queue the email of `purpose` to `to`, rendered with `vars` in `locale`
+    pub fn send_in(&self, purpose: Purpose, to: &str, locale: Option<&str>, vars: &Value) -> Result<()> {
+        let rendered = self.templates.render(purpose, locale, vars)?;
+        let message = Message::new(&self.config.smtp.from, to, rendered)?;
+        self.check_rate_limit(purpose, to)?;
+        self.queue
+            .try_send(Queued { purpose, message, attempt: 1 })
+            .map_err(|_| EmailError::QueueFull)?;
+        debug!("📧 Queued {} email to {}", purpose.as_str(), to);
+        Ok(())
+    }
+
+    /// Count an email of `purpose` to `to`, unless the limit is reached
+    fn check_rate_limit(&self, purpose: Purpose, to: &str) -> Result<()> {
+        let now = Instant::now();
+        let mut sent = self.sent.lock().unwrap();
+        if sent.len() >= MAX_TRACKED {
+            sent.retain(|_, times| times.back().is_some_and(|time| now.duration_since(*time) < RATE_WINDOW));
+        }
+        let times = sent.entry((purpose, bare_address(to).to_lowercase())).or_default();
+        while times.front().is_some_and(|time| now.duration_since(*time) >= RATE_WINDOW) {
+            times.pop_front();
+        }
+        if times.len() >= self.config.rate_limits.per_hour(purpose) as usize {
+            return Err(EmailError::RateLimited(purpose.as_str()));
+        }
+        times.push_back(now);
+        Ok(())
+    }
+
+    /// Deliver queued emails until the mailer is dropped. Only the first
+    /// call runs; the queue has a single consumer.
+    pub async fn run(&self) {
+        let Some(mut receiver) = self.receiver.lock().unwrap().take() else {
+            warn!("⚠️ The email queue is already being delivered");
+            return;
+        };
+        info!("📧 Delivering emails through {}:{}", self.config.smtp.host, self.config.smtp.port());
+        while let Some(queued) = receiver.recv().await {
+            self.deliver(queued).await;
+        }
+    }
+
+    async fn deliver(&self, queued: Queued) {
+        let Queued { purpose, message, attempt } = queued;
+        match smtp::send(&self.config.smtp, &message).await {
+            Ok(()) => info!("📧 Sent {} email to {}", purpose.as_str(), message.to),
+            Err(e) if e.is_transient() && attempt < self.config.max_attempts => {
+                let delay = self.config.retry_delay(attempt);
+                warn!(
+                    "⚠️ Could not send {} email to {} (attempt {}), retrying in {:?}: {}",
+                    purpose.as_str(),
+                    message.to,
+                    attempt,
+                    delay,
+                    e
+                );
+                let queue = self.queue.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = queue.send(Queued { purpose, message, attempt: attempt + 1 }).await;
+                });
+            }
+            Err(e) => warn!("❌ Dropping {} email to {} after {} attempts: {}", purpose.as_str(), message.to, attempt, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{config::RateLimits, smtp::tests::{fake_relay, relay_config}};
+    use serde_json::json;
+
+    fn config(port: u16) -> EmailConfig {
+        EmailConfig {
+            smtp: relay_config(port),
+            template_dir: None,
+            queue_capacity: 10,
+            max_attempts: 3,
+            retry_delay_s: 0,
+            rate_limits: RateLimits { validation: 2, ..Default::default() },
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limits_per_purpose_and_recipient() {
+        let mailer = Mailer::new(config(25)).unwrap();
+        let vars = json!({ "server_name": "matrixon.local", "token": "abc" });
+        mailer.send(Purpose::Validation, "bob@example.org", &vars).unwrap();
+        mailer.send(Purpose::Validation, "Bob <BOB@example.org>", &vars).unwrap();
+        assert!(matches!(
+            mailer.send(Purpose::Validation, "bob@example.org", &vars),
+            Err(EmailError::RateLimited("validation"))
+        ));
+        mailer.send(Purpose::Validation, "carol@example.org", &vars).unwrap();
+        mailer.send(Purpose::PasswordReset, "bob@example.org", &vars).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retries_temporary_failures() {
+        let (port, mut received) = fake_relay(2).await;
+        let mailer = Arc::new(Mailer::new(config(port)).unwrap());
+        tokio::spawn({
+            let mailer = mailer.clone();
+            async move { mailer.run().await }
+        });
+        mailer
+            .send(Purpose::Alert, "ops@example.org", &json!({ "rule": "high_cpu", "severity": "Critical" }))
+            .unwrap();
+        let data = tokio::time::timeout(Duration::from_secs(5), received.recv()).await.unwrap().unwrap();
+        assert!(data.contains("Subject: [Critical] high_cpu\r\n"));
+    }
+}
@@ -0,0 +1,305 @@
+//! Minimal SMTP client
+//!
+//! Delivers one message per connection to the configured relay: `EHLO`,
+//! optionally `STARTTLS` and `AUTH PLAIN`, then a single transaction. The
+//! message is a `multipart/alternative` with a plain text and an HTML part,
+//! both base64 encoded so any UTF-8 content survives 7-bit relays.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::{
+    config::{bare_address, SmtpConfig, TlsMode},
+    template::Rendered,
+    EmailError, Result,
+};
+
+/// An email to one recipient
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+impl Message {
+    pub fn new(from: &str, to: &str, rendered: Rendered) -> Result<Self> {
+        check_mailbox(from)?;
+        check_mailbox(to)?;
+        Ok(Self {
+            from: from.to_owned(),
+            to: to.to_owned(),
+            subject: rendered.subject,
+            text: rendered.text,
+            html: rendered.html,
+        })
+    }
+
+    /// The message in Internet Message Format, with CRLF line endings
+    pub fn format(&self) -> String {
+        let domain = bare_address(&self.from).rsplit_once('@').map_or("localhost", |(_, domain)| domain);
+        let boundary = format!("matrixon-{}", Uuid::new_v4().simple());
+        let mut message = String::new();
+        message.push_str(&format!("From: {}\r\n", self.from));
+        message.push_str(&format!("To: {}\r\n", self.to));
+        message.push_str(&format!("Subject: {}\r\n", encode_header(&self.subject)));
+        message.push_str(&format!("Date: {}\r\n", chrono::Utc::now().to_rfc2822()));
+        message.push_str(&format!("Message-ID: <{}@{}>\r\n", Uuid::new_v4().simple(), domain));
+        message.push_str("MIME-Version: 1.0\r\n");
+        message.push_str(&format!("Content-Type: multipart/alternative; boundary=\"{}\"\r\n\r\n", boundary));
+        for (content_type, body) in [("text/plain", &self.text), ("text/html", &self.html)] {
+            message.push_str(&format!("--{}\r\n", boundary));
+            message.push_str(&format!("Content-Type: {}; charset=utf-8\r\n", content_type));
+            message.push_str("Content-Transfer-Encoding: base64\r\n\r\n");
+            let encoded = STANDARD.encode(body);
+            for line in encoded.as_bytes().chunks(76) {
+                message.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+                message.push_str("\r\n");
+            }
+        }
+        message.push_str(&format!("--{}--\r\n", boundary));
+        message
+    }
+}
+
+/// Reject addresses that could inject headers or SMTP commands
+fn check_mailbox(mailbox: &str) -> Result<()> {
+    let address = bare_address(mailbox);
+    let valid = address.split_once('@').is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty())
+        && !mailbox.contains(['\r', '\n'])
+        && !address.contains(|c: char| c.is_whitespace() || c == '<' || c == '>');
+    if valid {
+        Ok(())
+    } else {
+        Err(EmailError::InvalidAddress(mailbox.to_owned()))
+    }
+}
+
+/// RFC 2047 encoded word for non-ASCII header values
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        value.to_owned()
+    } else {
+        format!("=?UTF-8?B?{}?=", STANDARD.encode(value))
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+struct Connection {
+    stream: BufReader<Box<dyn Io>>,
+}
+
+impl Connection {
+    /// Read a possibly multiline reply
+    async fn reply(&mut self) -> Result<(u16, String)> {
+        let mut text = Vec::new();
+        loop {
+            let mut line = String::new();
+            let read = self.stream.read_line(&mut line).await.map_err(|e| EmailError::Network(e.to_string()))?;
+            if read == 0 {
+                return Err(EmailError::Network("The relay closed the connection".to_owned()));
+            }
+            let line = line.trim_end();
+            let code = line
+                .get(..3)
+                .and_then(|code| code.parse().ok())
+                .ok_or_else(|| EmailError::Network(format!("Invalid SMTP reply: {}", line)))?;
+            text.push(line.get(4..).unwrap_or_default().to_owned());
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok((code, text.join("\n")));
+            }
+        }
+    }
+
+    /// Read a reply and fail unless its code is in the class of `expected`
+    async fn expect(&mut self, expected: u16) -> Result<String> {
+        let (code, message) = self.reply().await?;
+        if code / 100 == expected / 100 {
+            Ok(message)
+        } else {
+            Err(EmailError::Smtp { code, message })
+        }
+    }
+
+    async fn write(&mut self, data: &str) -> Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(data.as_bytes()).await.map_err(|e| EmailError::Network(e.to_string()))?;
+        stream.flush().await.map_err(|e| EmailError::Network(e.to_string()))
+    }
+
+    async fn command(&mut self, command: &str, expected: u16) -> Result<String> {
+        self.write(&format!("{}\r\n", command)).await?;
+        self.expect(expected).await
+    }
+}
+
+async fn tls(config: &SmtpConfig, stream: Box<dyn Io>) -> Result<Box<dyn Io>> {
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(config.accept_invalid_certs)
+        .build()
+        .map_err(|e| EmailError::Tls(e.to_string()))?;
+    let stream = tokio_native_tls::TlsConnector::from(connector)
+        .connect(&config.host, stream)
+        .await
+        .map_err(|e| EmailError::Tls(e.to_string()))?;
+    Ok(Box::new(stream))
+}
+
+/// Deliver `message` through the relay of `config`
+pub async fn send(config: &SmtpConfig, message: &Message) -> Result<()> {
+    tokio::time::timeout(config.timeout(), session(config, message))
+        .await
+        .map_err(|_| EmailError::Timeout(format!("SMTP relay {} did not answer in time", config.host)))?
+}
+
+async fn session(config: &SmtpConfig, message: &Message) -> Result<()> {
+    let tcp = TcpStream::connect((config.host.as_str(), config.port()))
+        .await
+        .map_err(|e| EmailError::Network(format!("{}:{}: {}", config.host, config.port(), e)))?;
+    let mut stream: Box<dyn Io> = Box::new(tcp);
+    if config.tls == TlsMode::Implicit {
+        stream = tls(config, stream).await?;
+    }
+    let mut connection = Connection { stream: BufReader::new(stream) };
+    connection.expect(220).await?;
+    let ehlo = format!("EHLO {}", config.helo_name());
+    let mut extensions = connection.command(&ehlo, 250).await?;
+
+    if config.tls == TlsMode::StartTls {
+        if !extensions.lines().any(|line| line.trim().eq_ignore_ascii_case("STARTTLS")) {
+            return Err(EmailError::Tls(format!("SMTP relay {} does not offer STARTTLS", config.host)));
+        }
+        connection.command("STARTTLS", 220).await?;
+        let stream = tls(config, connection.stream.into_inner()).await?;
+        connection = Connection { stream: BufReader::new(stream) };
+        extensions = connection.command(&ehlo, 250).await?;
+    }
+
+    if let Some(username) = &config.username {
+        if !extensions.lines().any(|line| line.trim().to_ascii_uppercase().starts_with("AUTH")) {
+            return Err(EmailError::Configuration(format!("SMTP relay {} does not offer authentication", config.host)));
+        }
+        debug!("Authenticating to {} as {}", config.host, username);
+        let credentials = format!("\0{}\0{}", username, config.password.as_deref().unwrap_or_default());
+        connection.command(&format!("AUTH PLAIN {}", STANDARD.encode(credentials)), 235).await?;
+    }
+
+    connection.command(&format!("MAIL FROM:<{}>", bare_address(&message.from)), 250).await?;
+    connection.command(&format!("RCPT TO:<{}>", bare_address(&message.to)), 250).await?;
+    connection.command("DATA", 354).await?;
+    let mut data = String::new();
+    for line in message.format().split_inclusive("\r\n") {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+    }
+    data.push_str(".\r\n");
+    connection.write(&data).await?;
+    connection.expect(250).await?;
+    // The message is accepted; a failing QUIT does not matter anymore
+    let _ = connection.command("QUIT", 221).await;
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tokio::{net::TcpListener, sync::mpsc};
+
+    /// A plaintext relay accepting every message after `failures` replies of
+    /// `451` to `RCPT`. Returns its port and the received messages.
+    pub(crate) async fn fake_relay(failures: usize) -> (u16, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut failures = failures;
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                stream.get_mut().write_all(b"220 fake ESMTP\r\n").await.unwrap();
+                let mut line = String::new();
+                while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+                    let reply: &[u8] = match line.get(..4).unwrap_or_default() {
+                        "EHLO" => b"250-fake\r\n250 AUTH PLAIN\r\n",
+                        "AUTH" => b"235 ok\r\n",
+                        "RCPT" if failures > 0 => {
+                            failures -= 1;
+                            b"451 try again later\r\n"
+                        }
+                        "DATA" => {
+                            stream.get_mut().write_all(b"354 go ahead\r\n").await.unwrap();
+                            let mut data = String::new();
+                            loop {
+                                let mut line = String::new();
+                                stream.read_line(&mut line).await.unwrap();
+                                if line == ".\r\n" {
+                                    break;
+                                }
+                                data.push_str(&line);
+                            }
+                            sender.send(data).unwrap();
+                            b"250 queued\r\n"
+                        }
+                        "QUIT" => b"221 bye\r\n",
+                        _ => b"250 ok\r\n",
+                    };
+                    stream.get_mut().write_all(reply).await.unwrap();
+                    line.clear();
+                }
+            }
+        });
+        (port, receiver)
+    }
+
+    pub(crate) fn relay_config(port: u16) -> SmtpConfig {
+        SmtpConfig {
+            host: "127.0.0.1".to_owned(),
+            port: Some(port),
+            tls: TlsMode::None,
+            accept_invalid_certs: false,
+            username: Some("matrixon".to_owned()),
+            password: Some("secret".to_owned()),
+            from: "Matrixon <noreply@matrixon.local>".to_owned(),
+            helo_name: None,
+            timeout_s: 5,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_through_relay() {
+        let (port, mut received) = fake_relay(0).await;
+        let rendered = Rendered {
+            subject: "Grüße".to_owned(),
+            text: ".leading dot\n".to_owned(),
+            html: "<p>hi</p>".to_owned(),
+        };
+        let message = Message::new("Matrixon <noreply@matrixon.local>", "bob@example.org", rendered).unwrap();
+        send(&relay_config(port), &message).await.unwrap();
+
+        let data = received.recv().await.unwrap();
+        assert!(data.contains("To: bob@example.org\r\n"));
+        assert!(data.contains(&format!("Subject: =?UTF-8?B?{}?=\r\n", STANDARD.encode("Grüße"))));
+        assert!(data.contains(&STANDARD.encode(".leading dot\n")));
+        assert!(data.contains("Content-Type: text/html; charset=utf-8\r\n"));
+    }
+
+    #[test]
+    fn test_rejects_injected_addresses() {
+        let rendered = || Rendered { subject: String::new(), text: String::new(), html: String::new() };
+        assert!(Message::new("noreply@matrixon.local", "bob@example.org\r\nBcc: eve@example.org", rendered()).is_err());
+        assert!(Message::new("noreply@matrixon.local", "bob@example.org> RCPT TO:<eve@example.org", rendered()).is_err());
+        assert!(Message::new("noreply@matrixon.local", "not an address", rendered()).is_err());
+    }
+}
@@ -0,0 +1,214 @@
+//! Email templates
+//!
+//! Every [`Purpose`] has one template made of a subject, a plain text body
+//! and an HTML body. The built-in templates can be replaced per file from a
+//! template directory: `<purpose>.subject`, `<purpose>.txt` and
+//! `<purpose>.html`, e.g. `validation.html`.
+//!
+//! Templates use the syntax of [`matrixon_common::template`]; variables are
+//! HTML-escaped in the HTML body. The text of the built-in templates comes
+//! from the localized messages in [`MESSAGES`]: every message of the
+//! recipient's locale is available as a variable named by its id, e.g.
+//! `{{ email-validation-intro }}`, with the other variables as arguments.
+
+use std::{collections::HashMap, fs, io, path::Path, sync::Arc};
+
+use matrixon_common::i18n::Catalog;
+use serde_json::Value;
+
+use crate::{config::Purpose, EmailError, Result};
+
+/// English messages of the built-in templates, in the Fluent syntax
+pub const MESSAGES: &str = "\
+email-greeting = Hello,
+email-greeting-name = Hello { $display_name },
+email-validation-subject = Validate your email address on { $server_name }
+email-validation-intro = A request was made to add this email address to an account on { $server_name }.
+email-validation-token = Your validation token is:
+email-validation-link = You can also confirm the address by opening this link:
+email-validation-ignore = If you did not make this request, you can ignore this email.
+email-password-reset-subject = Reset your password on { $server_name }
+email-password-reset-intro = A password reset was requested for the account using this email address on { $server_name }.
+email-password-reset-token = Your reset token is:
+email-password-reset-ignore = If you did not request a reset, your password stays unchanged.
+email-notification-subject = You have unread messages on { $server_name }
+email-notification-intro = You have unread messages:
+email-alert-subject = [{ $severity }] { $rule }
+email-alert-fired = Alert { $rule } fired at { $timestamp }.
+email-alert-value = Current value: { $value }
+";
+
+const PURPOSES: [Purpose; 4] = [Purpose::Validation, Purpose::PasswordReset, Purpose::Notification, Purpose::Alert];
+
+/// A rendered email, ready to be sent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rendered {
+    pub subject: String,
+    pub text: String,
+    pub html: String,
+}
+
+#[derive(Debug, Clone)]
+struct Template {
+    subject: String,
+    text: String,
+    html: String,
+}
+
+/// The templates of all purposes
+#[derive(Debug, Clone)]
+pub struct Templates {
+    templates: HashMap<Purpose, Template>,
+    catalog: Arc<Catalog>,
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        let templates = PURPOSES
+            .into_iter()
+            .map(|purpose| {
+                let (subject, text, html) = builtin(purpose);
+                (purpose, Template { subject: subject.to_owned(), text: text.to_owned(), html: html.to_owned() })
+            })
+            .collect();
+        let mut catalog = Catalog::new("en");
+        catalog.add_resource("en", MESSAGES).expect("built-in messages are valid");
+        Self { templates, catalog: Arc::new(catalog) }
+    }
+}
+
+impl Templates {
+    /// The built-in templates, with the files found in `dir` replacing them
+    pub fn load(dir: Option<&Path>) -> Result<Self> {
+        let mut templates = Self::default();
+        let Some(dir) = dir else {
+            return Ok(templates);
+        };
+        for (purpose, template) in &mut templates.templates {
+            for (extension, part) in
+                [("subject", &mut template.subject), ("txt", &mut template.text), ("html", &mut template.html)]
+            {
+                let path = dir.join(format!("{}.{}", purpose.as_str(), extension));
+                match fs::read_to_string(&path) {
+                    Ok(content) => *part = content,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(EmailError::Template(format!("{}: {}", path.display(), e))),
+                }
+            }
+            template.subject = template.subject.trim().to_owned();
+        }
+        Ok(templates)
+    }
+
+    /// Take the messages from `catalog`, which must include [`MESSAGES`]
+    pub fn set_catalog(&mut self, catalog: Arc<Catalog>) {
+        self.catalog = catalog;
+    }
+
+    /// Render the template of `purpose` in `locale` (the default locale of
+    /// the catalog when unset) with the fields of `vars`
+    pub fn render(&self, purpose: Purpose, locale: Option<&str>, vars: &Value) -> Result<Rendered> {
+        let template = &self.templates[&purpose];
+        let locale = locale.unwrap_or(self.catalog.default_locale());
+        let mut scope = self.catalog.messages(locale, vars);
+        scope.extend(vars.as_object().cloned().unwrap_or_default());
+        let scope = Value::Object(scope);
+        Ok(Rendered {
+            subject: render(&template.subject, &scope, false)?.replace(['\r', '\n'], " "),
+            text: render(&template.text, &scope, false)?,
+            html: render(&template.html, &scope, true)?,
+        })
+    }
+}
+
+fn render(template: &str, vars: &Value, html: bool) -> Result<String> {
+    matrixon_common::template::render(template, vars, html).map_err(|e| EmailError::Template(e.to_string()))
+}
+
+fn builtin(purpose: Purpose) -> (&'static str, &'static str, &'static str) {
+    match purpose {
+        Purpose::Validation => (
+            "{{ email-validation-subject }}",
+            "{{ email-greeting }}\n\n{{ email-validation-intro }}\n{{ email-validation-token }}\n\n    {{ token }}\n\n\
+             {{#if link}}{{ email-validation-link }}\n\n    {{ link }}\n\n{{/if}}\
+             {{ email-validation-ignore }}\n",
+            "<p>{{ email-greeting }}</p>\n<p>{{ email-validation-intro }}</p>\n\
+             <p>{{ email-validation-token }}</p>\n<p><code>{{ token }}</code></p>\n\
+             {{#if link}}<p>{{ email-validation-link }}<br><a href=\"{{ link }}\">{{ link }}</a></p>\n{{/if}}\
+             <p>{{ email-validation-ignore }}</p>\n",
+        ),
+        Purpose::PasswordReset => (
+            "{{ email-password-reset-subject }}",
+            "{{ email-greeting }}\n\n{{ email-password-reset-intro }}\n{{ email-password-reset-token }}\n\n    {{ token }}\n\n\
+             {{ email-password-reset-ignore }}\n",
+            "<p>{{ email-greeting }}</p>\n<p>{{ email-password-reset-intro }}</p>\n\
+             <p>{{ email-password-reset-token }}</p>\n<p><code>{{ token }}</code></p>\n\
+             <p>{{ email-password-reset-ignore }}</p>\n",
+        ),
+        Purpose::Notification => (
+            "{{ email-notification-subject }}",
+            "{{ email-greeting-name }}\n\n{{ email-notification-intro }}\n\n\
+             {{#each rooms}}  - {{ room_name }}: {{ count }}\n{{/each}}\n",
+            "<p>{{ email-greeting-name }}</p>\n<p>{{ email-notification-intro }}</p>\n<ul>\n\
+             {{#each rooms}}<li>{{ room_name }}: {{ count }}</li>\n{{/each}}</ul>\n",
+        ),
+        Purpose::Alert => (
+            "{{ email-alert-subject }}",
+            "{{ email-alert-fired }}\n\n{{ description }}\n\n{{ email-alert-value }}\n",
+            "<p>{{ email-alert-fired }}</p>\n<p>{{ description }}</p>\n<p>{{ email-alert-value }}</p>\n",
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render_escapes_and_repeats() {
+        let templates = Templates::default();
+        let vars = json!({
+            "server_name": "matrixon.local",
+            "display_name": "<Alice>",
+            "rooms": [{ "room_name": "Lobby", "count": 3 }, { "room_name": "Tom & Jerry", "count": 1 }],
+        });
+        let rendered = templates.render(Purpose::Notification, None, &vars).unwrap();
+        assert_eq!(rendered.subject, "You have unread messages on matrixon.local");
+        assert!(rendered.text.contains("Hello <Alice>,"));
+        assert!(rendered.text.contains("  - Lobby: 3\n  - Tom & Jerry: 1\n"));
+        assert!(rendered.html.contains("Hello &lt;Alice&gt;,"));
+        assert!(rendered.html.contains("<li>Tom &amp; Jerry: 1</li>"));
+    }
+
+    #[test]
+    fn test_template_dir_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("validation.subject"), "Your code for {{ server_name }}\n").unwrap();
+        fs::write(dir.path().join("alert.txt"), "{{ rule }").unwrap();
+        let templates = Templates::load(Some(dir.path())).unwrap();
+
+        let rendered = templates.render(Purpose::Validation, None, &json!({ "server_name": "a", "token": "t" })).unwrap();
+        assert_eq!(rendered.subject, "Your code for a");
+        assert!(rendered.text.contains("    t\n"));
+        assert!(templates.render(Purpose::Alert, None, &json!({})).is_err());
+    }
+
+    #[test]
+    fn test_localized_messages() {
+        let mut catalog = Catalog::new("en");
+        catalog.add_resource("en", MESSAGES).unwrap();
+        catalog
+            .add_resource("fr", "email-validation-subject = Validez votre adresse sur { $server_name }")
+            .unwrap();
+        let mut templates = Templates::default();
+        templates.set_catalog(Arc::new(catalog));
+
+        let vars = json!({ "server_name": "matrixon.local", "token": "t", "link": "https://matrixon.local/confirm?a=1&b=2" });
+        let rendered = templates.render(Purpose::Validation, Some("fr-CA"), &vars).unwrap();
+        assert_eq!(rendered.subject, "Validez votre adresse sur matrixon.local");
+        // Untranslated messages fall back to English
+        assert!(rendered.text.starts_with("Hello,\n\nA request was made"));
+        assert!(rendered.html.contains("href=\"https://matrixon.local/confirm?a=1&amp;b=2\""));
+    }
+}
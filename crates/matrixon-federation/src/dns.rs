@@ -0,0 +1,537 @@
+// =============================================================================
+// Matrixon Federation Library - DNS Resolution
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Name resolution of the clients talking to other servers. Hostnames are
+//   resolved by the system resolver, or, for deployments that should not
+//   leak the servers they talk to to their network's resolver, over HTTPS
+//   (RFC 8484) by the configured DoH providers, which must be HTTPS URLs,
+//   tried in order. A provider answering that a name does not exist, or
+//   that it has no records of the type asked for, is final; when no
+//   provider answers, e.g. they all fail with SERVFAIL, the system resolver
+//   is used unless fallback is turned off. SRV records are queried from the
+//   DoH providers as well, and otherwise directly from the nameservers of
+//   `/etc/resolv.conf`. Clients are pinned to the addresses the resolver
+//   finds, and it records how long resolutions take.
+//
+// =============================================================================
+
+use std::{
+    fmt::Write,
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use reqwest::header::{ACCEPT, CONTENT_TYPE};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+
+/// Timeout of DNS queries sent to nameservers directly
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Media type of DNS messages sent over HTTPS
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// Upper bounds in seconds of the resolution latency buckets
+const BUCKETS: [f64; 9] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0];
+
+const DNS_PORT: u16 = 53;
+const DNS_TYPE_A: u16 = 1;
+const DNS_TYPE_AAAA: u16 = 28;
+const DNS_TYPE_SRV: u16 = 33;
+const DNS_CLASS_IN: u16 = 1;
+
+/// DNS settings, the `[federation.dns]` section
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DnsConfig {
+    /// DNS-over-HTTPS endpoints, e.g. `https://cloudflare-dns.com/dns-query`,
+    /// tried in order; names are resolved by the system when empty
+    #[serde(deserialize_with = "https_urls")]
+    pub doh_providers: Vec<String>,
+
+    /// Resolve through the system when no DoH provider answers
+    pub fallback_to_system: bool,
+
+    /// Timeout of a query to one DoH provider
+    pub doh_timeout: Duration,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            doh_providers: Vec::new(),
+            fallback_to_system: true,
+            doh_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// DoH providers must be HTTPS URLs, or queries would leave in the clear
+fn https_urls<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    let urls = Vec::<String>::deserialize(deserializer)?;
+    for url in &urls {
+        if !reqwest::Url::parse(url).is_ok_and(|url| url.scheme() == "https" && url.has_host()) {
+            return Err(D::Error::custom(format!("DoH provider {} is not an HTTPS URL", url)));
+        }
+    }
+    Ok(urls)
+}
+
+/// An SRV record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// A record of a DNS response
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Record {
+    Address(IpAddr),
+    Srv(SrvRecord),
+}
+
+/// Latency histogram of the resolutions answered by one resolver
+#[derive(Debug, Default)]
+struct Latency {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    micros: AtomicU64,
+}
+
+impl Latency {
+    fn observe(&self, started: Instant) {
+        let elapsed = started.elapsed();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| elapsed.as_secs_f64() <= *bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, resolver: &str) {
+        let name = "matrixon_dns_resolution_seconds";
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{resolver=\"{resolver}\",le=\"{bound}\"}} {cumulative}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{name}_bucket{{resolver=\"{resolver}\",le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum{{resolver=\"{resolver}\"}} {}", self.micros.load(Ordering::Relaxed) as f64 / 1e6);
+        let _ = writeln!(out, "{name}_count{{resolver=\"{resolver}\"}} {count}");
+    }
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    doh: Latency,
+    system: Latency,
+    /// Resolutions no DoH provider answered
+    doh_failures: AtomicU64,
+    /// Of those, resolutions handed to the system resolver
+    fallbacks: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    config: DnsConfig,
+    /// Client of the DoH providers, resolving their names through the system
+    client: reqwest::Client,
+    stats: Stats,
+}
+
+/// Resolver of hostnames and SRV records. Clones share their statistics.
+#[derive(Debug, Clone)]
+pub struct DnsResolver {
+    inner: Arc<Inner>,
+}
+
+impl Default for DnsResolver {
+    fn default() -> Self {
+        Self::new(&DnsConfig::default())
+    }
+}
+
+impl DnsResolver {
+    pub fn new(config: &DnsConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(config.doh_timeout)
+            .https_only(true)
+            .min_tls_version(reqwest::tls::Version::TLS_1_2)
+            .build()
+            .unwrap_or_else(|e| {
+                warn!("⚠️ Could not build the DoH client, using the default client: {}", e);
+                reqwest::Client::default()
+            });
+        Self {
+            inner: Arc::new(Inner { config: config.clone(), client, stats: Stats::default() }),
+        }
+    }
+
+    /// Whether names are resolved over HTTPS
+    pub fn uses_doh(&self) -> bool {
+        !self.inner.config.doh_providers.is_empty()
+    }
+
+    /// Addresses of `host` with `port`
+    pub async fn lookup_host(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        let stats = &self.inner.stats;
+        if self.uses_doh() {
+            let started = Instant::now();
+            let (v4, v6) = tokio::join!(self.doh(host, DNS_TYPE_A), self.doh(host, DNS_TYPE_AAAA));
+            if v4.is_some() || v6.is_some() {
+                stats.doh.observe(started);
+                let addrs: Vec<SocketAddr> = v4
+                    .into_iter()
+                    .chain(v6)
+                    .flatten()
+                    .filter_map(|record| match record {
+                        Record::Address(ip) => Some(SocketAddr::new(ip, port)),
+                        Record::Srv(_) => None,
+                    })
+                    .collect();
+                if addrs.is_empty() {
+                    return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} has no addresses", host)));
+                }
+                return Ok(addrs);
+            }
+            if !self.fall_back() {
+                return Err(io::Error::other(format!("No DoH provider resolved {}", host)));
+            }
+        }
+        let started = Instant::now();
+        let addrs = tokio::net::lookup_host((host, port)).await.map(Iterator::collect);
+        stats.system.observe(started);
+        addrs
+    }
+
+    /// SRV records of `name`, best first. Failures give no records.
+    pub(crate) async fn lookup_srv(&self, name: &str) -> Vec<SrvRecord> {
+        let stats = &self.inner.stats;
+        let mut records = None;
+        if self.uses_doh() {
+            let started = Instant::now();
+            records = self.doh(name, DNS_TYPE_SRV).await;
+            if records.is_some() {
+                stats.doh.observe(started);
+            } else if !self.fall_back() {
+                return Vec::new();
+            }
+        }
+        let records = match records {
+            Some(records) => records,
+            None => {
+                let started = Instant::now();
+                let records = query_nameservers(name, DNS_TYPE_SRV).await;
+                stats.system.observe(started);
+                records
+            }
+        };
+        let mut records: Vec<SrvRecord> = records
+            .into_iter()
+            .filter_map(|record| match record {
+                Record::Srv(record) => Some(record),
+                Record::Address(_) => None,
+            })
+            .collect();
+        records.sort_by(|a, b| a.priority.cmp(&b.priority).then(b.weight.cmp(&a.weight)));
+        records
+    }
+
+    /// Count a resolution no DoH provider answered, returning whether it
+    /// goes to the system resolver instead
+    fn fall_back(&self) -> bool {
+        let stats = &self.inner.stats;
+        stats.doh_failures.fetch_add(1, Ordering::Relaxed);
+        let fallback = self.inner.config.fallback_to_system;
+        if fallback {
+            stats.fallbacks.fetch_add(1, Ordering::Relaxed);
+        }
+        fallback
+    }
+
+    /// Records of the first DoH provider answering a query for `name`
+    async fn doh(&self, name: &str, record_type: u16) -> Option<Vec<Record>> {
+        // Id 0 makes responses cacheable by HTTP caches (RFC 8484, 4.1)
+        let query = query(0, name, record_type);
+        for provider in &self.inner.config.doh_providers {
+            let response = self
+                .inner
+                .client
+                .post(provider)
+                .header(CONTENT_TYPE, DNS_MESSAGE)
+                .header(ACCEPT, DNS_MESSAGE)
+                .body(query.clone())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            let body = match response {
+                Ok(response) => response.bytes().await,
+                Err(e) => Err(e),
+            };
+            match body.map(|body| parse_response(0, &body)) {
+                Ok(Some(records)) => return Some(records),
+                Ok(None) => debug!("DoH provider {} sent an invalid response for {}", provider, name),
+                Err(e) => debug!("DoH provider {} did not resolve {}: {}", provider, name, e),
+            }
+        }
+        None
+    }
+
+    /// Prometheus text of the resolution statistics
+    pub fn render(&self) -> String {
+        let stats = &self.inner.stats;
+        let mut out = String::new();
+        let _ = writeln!(out, "# TYPE matrixon_dns_resolution_seconds histogram");
+        stats.doh.render(&mut out, "doh");
+        stats.system.render(&mut out, "system");
+        let _ = writeln!(out, "# TYPE matrixon_dns_doh_failures_total counter");
+        let _ = writeln!(out, "matrixon_dns_doh_failures_total {}", stats.doh_failures.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# TYPE matrixon_dns_fallbacks_total counter");
+        let _ = writeln!(out, "matrixon_dns_fallbacks_total {}", stats.fallbacks.load(Ordering::Relaxed));
+        out
+    }
+}
+
+/// Nameservers of `/etc/resolv.conf`
+fn nameservers() -> Vec<IpAddr> {
+    let Ok(resolv_conf) = fs::read_to_string("/etc/resolv.conf") else {
+        return Vec::new();
+    };
+    resolv_conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|address| address.trim().parse().ok())
+        .collect()
+}
+
+/// Records of `name` from the first of the system's nameservers answering
+async fn query_nameservers(name: &str, record_type: u16) -> Vec<Record> {
+    let id: u16 = rand::random();
+    let query = query(id, name, record_type);
+    for nameserver in nameservers() {
+        let bind: SocketAddr = if nameserver.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let exchange = async {
+            let socket = UdpSocket::bind(bind).await.ok()?;
+            socket.send_to(&query, (nameserver, DNS_PORT)).await.ok()?;
+            let mut buffer = vec![0; 4096];
+            let len = socket.recv(&mut buffer).await.ok()?;
+            buffer.truncate(len);
+            parse_response(id, &buffer)
+        };
+        if let Ok(Some(records)) = tokio::time::timeout(LOOKUP_TIMEOUT, exchange).await {
+            return records;
+        }
+    }
+    Vec::new()
+}
+
+/// DNS query for the records of `name` of `record_type`
+fn query(id: u16, name: &str, record_type: u16) -> Vec<u8> {
+    let mut query = Vec::with_capacity(name.len() + 18);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&record_type.to_be_bytes());
+    query.extend_from_slice(&DNS_CLASS_IN.to_be_bytes());
+    query
+}
+
+fn read_u16(message: &[u8], position: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*message.get(position)?, *message.get(position + 1)?]))
+}
+
+/// Read a possibly compressed domain name, returning it and the position
+/// after it
+fn read_name(message: &[u8], mut position: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    for _ in 0..128 {
+        let len = *message.get(position)? as usize;
+        if len == 0 {
+            let name = labels.join(".");
+            return Some((name, end.unwrap_or(position + 1)));
+        }
+        if len & 0xC0 == 0xC0 {
+            // Compression pointer
+            end.get_or_insert(position + 2);
+            position = (read_u16(message, position)? & 0x3FFF) as usize;
+            continue;
+        }
+        let label = message.get(position + 1..position + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        position += 1 + len;
+    }
+    None
+}
+
+/// Address and SRV records of a DNS response to query `id`, none when the
+/// name does not exist; `None` for an invalid or truncated response or one
+/// saying the query failed. Other records, such as the CNAMEs leading to
+/// the addresses, are skipped.
+fn parse_response(id: u16, message: &[u8]) -> Option<Vec<Record>> {
+    let flags = read_u16(message, 2)?;
+    let truncated = flags & 0x0200 != 0;
+    let response = flags & 0x8000 != 0;
+    if read_u16(message, 0)? != id || !response || truncated {
+        return None;
+    }
+    match flags & 0x000F {
+        // NOERROR, with or without records
+        0 => {}
+        // NXDOMAIN
+        3 => return Some(Vec::new()),
+        // SERVFAIL, REFUSED and the like say nothing about the name
+        _ => return None,
+    }
+    let questions = read_u16(message, 4)?;
+    let answers = read_u16(message, 6)?;
+
+    let mut position = 12;
+    for _ in 0..questions {
+        position = read_name(message, position)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        position = read_name(message, position)?.1;
+        let record_type = read_u16(message, position)?;
+        let data_len = read_u16(message, position + 8)? as usize;
+        let data = position + 10;
+        let rdata = message.get(data..data + data_len)?;
+        match record_type {
+            DNS_TYPE_A => {
+                let octets: [u8; 4] = rdata.try_into().ok()?;
+                records.push(Record::Address(Ipv4Addr::from(octets).into()));
+            }
+            DNS_TYPE_AAAA => {
+                let octets: [u8; 16] = rdata.try_into().ok()?;
+                records.push(Record::Address(Ipv6Addr::from(octets).into()));
+            }
+            DNS_TYPE_SRV => {
+                let (target, _) = read_name(message, data + 6)?;
+                // A target of "." means the service is not available
+                if !target.is_empty() {
+                    records.push(Record::Srv(SrvRecord {
+                        priority: read_u16(message, data)?,
+                        weight: read_u16(message, data + 2)?,
+                        port: read_u16(message, data + 4)?,
+                        target,
+                    }));
+                }
+            }
+            _ => {}
+        }
+        position = data + data_len;
+    }
+    Some(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A response to `query` with one answer of `record_type`
+    fn response(mut message: Vec<u8>, record_type: u16, data: &[u8]) -> Vec<u8> {
+        // Response, recursion desired and available, one answer
+        message[2..4].copy_from_slice(&[0x81, 0x80]);
+        message[6..8].copy_from_slice(&[0, 1]);
+        // Name compressed to the question, type, class IN, TTL
+        message.extend_from_slice(&[0xC0, 12]);
+        message.extend_from_slice(&record_type.to_be_bytes());
+        message.extend_from_slice(&[0, 1, 0, 0, 0x0E, 0x10]);
+        message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        message.extend_from_slice(data);
+        message
+    }
+
+    #[test]
+    fn test_parse_srv_response() {
+        let mut data = vec![0, 10, 0, 5, 0x20, 0xFB];
+        for label in ["matrix", "example", "org"] {
+            data.push(label.len() as u8);
+            data.extend_from_slice(label.as_bytes());
+        }
+        data.push(0);
+        let mut message = response(query(7, "_matrix-fed._tcp.example.org", DNS_TYPE_SRV), DNS_TYPE_SRV, &data);
+
+        assert_eq!(
+            parse_response(7, &message),
+            Some(vec![Record::Srv(SrvRecord { priority: 10, weight: 5, port: 8443, target: "matrix.example.org".to_owned() })])
+        );
+        assert_eq!(parse_response(8, &message), None);
+
+        // NXDOMAIN is an answer, SERVFAIL is not
+        message[3] = 0x83;
+        assert_eq!(parse_response(7, &message), Some(Vec::new()));
+        message[3] = 0x82;
+        assert_eq!(parse_response(7, &message), None);
+    }
+
+    #[test]
+    fn test_parse_address_responses() {
+        let message = response(query(0, "example.org", DNS_TYPE_A), DNS_TYPE_A, &[192, 0, 2, 1]);
+        assert_eq!(parse_response(0, &message), Some(vec![Record::Address("192.0.2.1".parse().unwrap())]));
+
+        let ipv6: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let message = response(query(0, "example.org", DNS_TYPE_AAAA), DNS_TYPE_AAAA, &ipv6.octets());
+        assert_eq!(parse_response(0, &message), Some(vec![Record::Address(ipv6.into())]));
+
+        // An A record must have four bytes
+        let message = response(query(0, "example.org", DNS_TYPE_A), DNS_TYPE_A, &[192, 0, 2]);
+        assert_eq!(parse_response(0, &message), None);
+    }
+
+    #[test]
+    fn test_doh_providers_must_use_https() {
+        let config = |provider: &str| serde_json::from_value::<DnsConfig>(serde_json::json!({ "doh_providers": [provider] }));
+        assert!(config("https://dns.example/dns-query").is_ok());
+        assert!(config("http://dns.example/dns-query").is_err());
+        assert!(config("dns.example").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_doh_providers_fall_back() {
+        let config = DnsConfig {
+            doh_providers: vec!["https://127.0.0.1:9/dns-query".to_owned()],
+            ..Default::default()
+        };
+        let resolver = DnsResolver::new(&config);
+        let addrs = resolver.lookup_host("localhost", 8448).await.unwrap();
+        assert!(addrs.iter().all(|addr| addr.port() == 8448 && addr.ip().is_loopback()));
+        assert_eq!(resolver.lookup_host("[::1]", 80).await.unwrap(), vec!["[::1]:80".parse().unwrap()]);
+
+        let strict = DnsResolver::new(&DnsConfig { fallback_to_system: false, ..config });
+        assert!(strict.lookup_host("localhost", 8448).await.is_err());
+        assert!(strict.lookup_srv("_matrix-fed._tcp.localhost").await.is_empty());
+
+        let metrics = resolver.render();
+        assert!(metrics.contains("matrixon_dns_resolution_seconds_count{resolver=\"system\"} 1"));
+        assert!(metrics.contains("matrixon_dns_fallbacks_total 1"));
+        assert!(strict.render().contains("matrixon_dns_doh_failures_total 2"));
+    }
+}
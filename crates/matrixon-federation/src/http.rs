@@ -0,0 +1,119 @@
+// =============================================================================
+// Matrixon Federation Library - Outbound HTTP Clients
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Settings of the HTTP clients talking to other servers. Connections are
+//   pooled per destination (scheme, host and port) and kept open between
+//   requests; HTTP/2 is negotiated through ALPN, so requests to the same
+//   destination are multiplexed over one connection. Certificates are
+//   always verified against the system roots and TLS below 1.2 is refused.
+//   Hostnames are resolved by the configured DNS resolver and clients are
+//   pinned to the addresses found.
+//
+// =============================================================================
+
+use std::time::Duration;
+
+use crate::{dns::DnsResolver, FederationConfig, FederationError};
+
+/// Connection settings of federation clients
+#[derive(Debug, Clone)]
+pub struct ClientSettings {
+    /// Whole request, from connecting to the end of the response body
+    pub request_timeout: Duration,
+    pub connect_timeout: Duration,
+    /// How long an idle pooled connection is kept
+    pub pool_idle_timeout: Duration,
+    /// Idle connections kept per destination; with HTTP/2 one is enough
+    pub max_idle_per_destination: usize,
+    /// Offer HTTP/2, falling back to HTTP/1.1 when the destination does
+    /// not support it
+    pub http2: bool,
+    /// Resolves the hostnames clients built from the settings are pinned
+    /// to
+    pub dns: DnsResolver,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        Self {
+            request_timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+            pool_idle_timeout: Duration::from_secs(90),
+            max_idle_per_destination: 8,
+            http2: true,
+            dns: DnsResolver::default(),
+        }
+    }
+}
+
+impl From<&FederationConfig> for ClientSettings {
+    fn from(config: &FederationConfig) -> Self {
+        Self {
+            request_timeout: config.request_timeout,
+            connect_timeout: config.connect_timeout,
+            pool_idle_timeout: config.pool_idle_timeout,
+            max_idle_per_destination: config.max_idle_connections_per_destination,
+            http2: config.http2,
+            dns: DnsResolver::new(&config.dns),
+        }
+    }
+}
+
+impl ClientSettings {
+    /// Client builder with these settings, to be extended before building
+    pub fn builder(&self) -> reqwest::ClientBuilder {
+        let builder = reqwest::Client::builder()
+            .timeout(self.request_timeout)
+            .connect_timeout(self.connect_timeout)
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .pool_max_idle_per_host(self.max_idle_per_destination)
+            .tcp_keepalive(Duration::from_secs(60))
+            .tcp_nodelay(true)
+            .min_tls_version(reqwest::tls::Version::TLS_1_2)
+            .danger_accept_invalid_certs(false)
+            .danger_accept_invalid_hostnames(false);
+        if self.http2 {
+            builder
+                .http2_adaptive_window(true)
+                .http2_keep_alive_interval(Duration::from_secs(30))
+                .http2_keep_alive_timeout(Duration::from_secs(10))
+        } else {
+            builder.http1_only()
+        }
+    }
+
+    pub fn build(&self) -> Result<reqwest::Client, FederationError> {
+        self.builder()
+            .build()
+            .map_err(|e| FederationError::Configuration(format!("Could not build the federation client: {}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_from_config() {
+        let config = FederationConfig {
+            request_timeout: Duration::from_secs(5),
+            http2: false,
+            ..Default::default()
+        };
+        let settings = ClientSettings::from(&config);
+        assert_eq!(settings.request_timeout, Duration::from_secs(5));
+        assert_eq!(settings.connect_timeout, config.connect_timeout);
+        assert!(!settings.http2);
+        assert!(!settings.dns.uses_doh());
+        assert!(settings.build().is_ok());
+        assert!(ClientSettings::default().build().is_ok());
+    }
+}
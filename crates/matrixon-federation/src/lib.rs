@@ -17,7 +17,12 @@
 use std::time::Duration;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tracing::{debug, info, instrument};
+use tracing::{info, instrument};
+
+pub mod dns;
+pub mod http;
+pub mod resolver;
+pub mod sending;
 
 // =============================================================================
 // Core Federation Types
@@ -25,6 +30,7 @@ use tracing::{debug, info, instrument};
 
 /// Federation configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct FederationConfig {
     /// Server name for this federation endpoint
     pub server_name: String,
@@ -41,11 +47,26 @@ pub struct FederationConfig {
     /// Request timeout duration
     pub request_timeout: Duration,
     
+    /// Timeout of establishing a connection to another server
+    pub connect_timeout: Duration,
+    
+    /// How long idle connections to other servers are kept open
+    pub pool_idle_timeout: Duration,
+    
+    /// Idle connections kept per destination server
+    pub max_idle_connections_per_destination: usize,
+    
+    /// Offer HTTP/2 to other servers
+    pub http2: bool,
+    
     /// Maximum concurrent connections
     pub max_connections: usize,
     
     /// Rate limit per minute per server
     pub rate_limit_per_minute: u32,
+    
+    /// How other servers' names are resolved
+    pub dns: dns::DnsConfig,
 }
 
 impl Default for FederationConfig {
@@ -56,12 +77,21 @@ impl Default for FederationConfig {
             tls_cert_path: None,
             tls_key_path: None,
             request_timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+            pool_idle_timeout: Duration::from_secs(90),
+            max_idle_connections_per_destination: 8,
+            http2: true,
             max_connections: 1000,
             rate_limit_per_minute: 100,
+            dns: dns::DnsConfig::default(),
         }
     }
 }
 
+impl matrixon_core::config::ConfigSection for FederationConfig {
+    const SECTION: &'static str = "federation";
+}
+
 /// Federation error types
 #[derive(Error, Debug)]
 pub enum FederationError {
@@ -106,7 +136,8 @@ pub struct ServerInfo {
     pub version: Option<String>,
 }
 
-/// Simple federation manager
+/// Simple federation manager. Requests to other servers go through
+/// [`sending::Service`], which signs them and shares its connections.
 #[derive(Debug)]
 pub struct FederationManager {
     config: FederationConfig,
@@ -141,24 +172,12 @@ impl FederationManager {
         Ok(())
     }
     
-    /// Send federation request to another server
-    #[instrument(level = "debug", skip(self))]
-    pub async fn send_request(&self, target_server: &str, endpoint: &str) -> Result<String, FederationError> {
-        debug!("📤 Sending federation request to: {} at {}", target_server, endpoint);
-        
-        // TODO: Implement actual HTTP request with authentication
-        Ok("response".to_string())
-    }
-    
     /// Get server configuration
     pub fn get_config(&self) -> &FederationConfig {
         &self.config
     }
 }
 
-// =============================================================================
-// Module Tests
-// =============================================================================
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,285 @@
+// =============================================================================
+// Matrixon Federation Library - Destination Resolution
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Resolution of server names to the address federation requests are sent
+//   to, following the server discovery steps of the server-server API: IP
+//   literals and names with an explicit port are used as they are, then
+//   `/.well-known/matrix/server` delegation is followed, then the
+//   `_matrix-fed._tcp` and deprecated `_matrix._tcp` SRV records are looked
+//   up, and finally the default port 8448 is used. Results are cached.
+//   Names and SRV records are resolved by the DNS resolver of the client
+//   settings, over HTTPS if so configured, and each destination gets a
+//   client pinned to the addresses found, so connections never resolve
+//   names on their own. When the connection goes to an SRV target,
+//   requests are still made to the server name so its TLS certificate is
+//   checked against it.
+//
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+use tracing::{debug, info};
+
+use crate::http::ClientSettings;
+
+/// Port used when a server name does not say otherwise
+pub const DEFAULT_PORT: u16 = 8448;
+
+/// How long a resolution delegated through `.well-known` is cached
+const WELL_KNOWN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long other resolutions are cached, so a server publishing
+/// delegation later is picked up
+const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Timeout of `.well-known` requests
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// SRV services tried in order
+const SRV_SERVICES: [&str; 2] = ["_matrix-fed._tcp", "_matrix._tcp"];
+
+/// Where federation requests for a server name go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination {
+    /// `host:port` of request URLs; the TLS certificate must be valid for
+    /// the host
+    pub authority: String,
+    /// Value of the `Host` header
+    pub host_header: String,
+    /// Host connected to instead of the URL host, from an SRV record
+    pub srv_target: Option<String>,
+}
+
+impl Destination {
+    pub fn base_url(&self) -> String {
+        format!("https://{}", self.authority)
+    }
+
+    fn direct(host: &str, port: u16, host_header: &str) -> Self {
+        Self { authority: authority(host, port), host_header: host_header.to_owned(), srv_target: None }
+    }
+}
+
+/// A resolved destination with the client connecting to it
+#[derive(Debug, Clone)]
+pub struct Resolution {
+    pub destination: Destination,
+    /// Client connecting to the addresses of the destination, or of its
+    /// SRV target; `None` when they did not resolve
+    pub client: Option<reqwest::Client>,
+}
+
+struct CacheEntry {
+    resolution: Resolution,
+    expires: Instant,
+}
+
+/// Resolver of federation destinations
+pub struct Resolver {
+    settings: ClientSettings,
+    cache: RwLock<HashMap<String, CacheEntry>>,
+}
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Resolver").field("settings", &self.settings).finish_non_exhaustive()
+    }
+}
+
+impl Resolver {
+    /// `settings` are those of the clients connecting to destinations
+    pub fn new(settings: ClientSettings) -> Self {
+        Self { settings, cache: RwLock::default() }
+    }
+
+    /// Where requests for `server_name` go, from the cache when fresh.
+    /// Destinations that do not resolve are looked up again next time.
+    pub async fn resolve(&self, server_name: &str) -> Resolution {
+        if let Some(entry) = self.cache.read().unwrap().get(server_name) {
+            if entry.expires > Instant::now() {
+                return entry.resolution.clone();
+            }
+        }
+        let (resolution, ttl) = self.lookup(server_name).await;
+        debug!("🧭 Resolved {} to {:?}", server_name, resolution.destination);
+        if resolution.client.is_none() {
+            return resolution;
+        }
+        self.cache.write().unwrap().insert(
+            server_name.to_owned(),
+            CacheEntry { resolution: resolution.clone(), expires: Instant::now() + ttl },
+        );
+        resolution
+    }
+
+    /// Forget the resolution of `server_name`, e.g. after it became
+    /// unreachable
+    pub fn invalidate(&self, server_name: &str) {
+        self.cache.write().unwrap().remove(server_name);
+    }
+
+    async fn lookup(&self, server_name: &str) -> (Resolution, Duration) {
+        if let Some(destination) = literal(server_name) {
+            return (self.direct(destination).await, WELL_KNOWN_TTL);
+        }
+        match self.well_known(server_name).await {
+            Some(delegated) => {
+                info!("🧭 {} delegates federation to {}", server_name, delegated);
+                let resolution = match literal(&delegated) {
+                    Some(destination) => self.direct(destination).await,
+                    None => self.srv_or_default(&delegated).await,
+                };
+                let ttl = if resolution.destination.srv_target.is_some() { DEFAULT_TTL } else { WELL_KNOWN_TTL };
+                (resolution, ttl)
+            }
+            None => (self.srv_or_default(server_name).await, DEFAULT_TTL),
+        }
+    }
+
+    /// Server delegated to in `https://{server_name}/.well-known/matrix/server`
+    async fn well_known(&self, server_name: &str) -> Option<String> {
+        let url = format!("https://{}/.well-known/matrix/server", server_name);
+        let addrs = self.addrs(server_name, 443).await?;
+        let client = self.settings.builder().timeout(LOOKUP_TIMEOUT).resolve_to_addrs(server_name, &addrs).build().ok()?;
+        let response: Value = client
+            .get(&url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .ok()?
+            .json()
+            .await
+            .ok()?;
+        delegated_server(&response)
+    }
+
+    /// Destination of a hostname without port: the first SRV target that
+    /// resolves, or the default port
+    async fn srv_or_default(&self, host: &str) -> Resolution {
+        for service in SRV_SERVICES {
+            for record in self.settings.dns.lookup_srv(&format!("{}.{}", service, host)).await {
+                let Some(addrs) = self.addrs(&record.target, record.port).await else {
+                    continue;
+                };
+                // Requests keep the hostname in their URL for TLS; the
+                // client connects to the target's addresses instead
+                let client = self.settings.builder().resolve_to_addrs(host, &addrs).build();
+                if let Ok(client) = client {
+                    let destination = Destination {
+                        authority: authority(host, record.port),
+                        host_header: host.to_owned(),
+                        srv_target: Some(record.target),
+                    };
+                    return Resolution { destination, client: Some(client) };
+                }
+            }
+        }
+        self.direct(Destination::direct(host, DEFAULT_PORT, host)).await
+    }
+
+    /// Resolution connecting to the host of `destination` itself
+    async fn direct(&self, destination: Destination) -> Resolution {
+        let (host, port) = split_port(&destination.authority);
+        let port = port.unwrap_or(DEFAULT_PORT);
+        let client = match self.addrs(host, port).await {
+            Some(addrs) => self.settings.builder().resolve_to_addrs(host, &addrs).build().ok(),
+            None => None,
+        };
+        Resolution { destination, client }
+    }
+
+    /// Addresses of `host` with `port`, if it resolves
+    async fn addrs(&self, host: &str, port: u16) -> Option<Vec<SocketAddr>> {
+        match self.settings.dns.lookup_host(host, port).await {
+            Ok(addrs) => Some(addrs),
+            Err(e) => {
+                debug!("{} does not resolve: {}", host, e);
+                None
+            }
+        }
+    }
+}
+
+/// `host:port`, bracketing IPv6 addresses
+fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Split a server name into its host and optional port
+fn split_port(server_name: &str) -> (&str, Option<u16>) {
+    if let Some(rest) = server_name.strip_prefix('[') {
+        // [IPv6] or [IPv6]:port
+        return match rest.split_once(']') {
+            Some((host, port)) => (host, port.strip_prefix(':').and_then(|port| port.parse().ok())),
+            None => (server_name, None),
+        };
+    }
+    match server_name.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => match port.parse() {
+            Ok(port) => (host, Some(port)),
+            Err(_) => (server_name, None),
+        },
+        _ => (server_name, None),
+    }
+}
+
+/// Destination of server names that need no lookup: IP literals and
+/// hostnames with an explicit port
+fn literal(server_name: &str) -> Option<Destination> {
+    let (host, port) = split_port(server_name);
+    if host.parse::<IpAddr>().is_ok() {
+        return Some(Destination::direct(host, port.unwrap_or(DEFAULT_PORT), server_name));
+    }
+    port.map(|port| Destination::direct(host, port, server_name))
+}
+
+/// `m.server` of a `.well-known/matrix/server` response, if valid
+fn delegated_server(response: &Value) -> Option<String> {
+    let server = response["m.server"].as_str()?.trim();
+    let valid = !server.is_empty() && !server.contains('/') && !server.contains(char::is_whitespace);
+    valid.then(|| server.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_literals() {
+        assert_eq!(literal("1.2.3.4"), Some(Destination::direct("1.2.3.4", 8448, "1.2.3.4")));
+        assert_eq!(literal("1.2.3.4:8000").unwrap().authority, "1.2.3.4:8000");
+        let ipv6 = literal("[::1]").unwrap();
+        assert_eq!(ipv6.authority, "[::1]:8448");
+        assert_eq!(ipv6.host_header, "[::1]");
+        assert_eq!(literal("[::1]:443").unwrap().authority, "[::1]:443");
+        assert_eq!(literal("example.org:443"), Some(Destination::direct("example.org", 443, "example.org:443")));
+        assert_eq!(literal("example.org"), None);
+    }
+
+    #[test]
+    fn test_delegated_server() {
+        assert_eq!(delegated_server(&json!({ "m.server": "matrix.example.org:443" })).as_deref(), Some("matrix.example.org:443"));
+        assert_eq!(delegated_server(&json!({ "m.server": "https://matrix.example.org/" })), None);
+        assert_eq!(delegated_server(&json!({ "m.server": "" })), None);
+        assert_eq!(delegated_server(&json!({})), None);
+    }
+}
@@ -0,0 +1,1052 @@
+// =============================================================================
+// Matrixon Federation Library - Outbound Sending Queue
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Queues PDUs and EDUs per destination server and delivers them as
+//   federation transactions (`PUT /_matrix/federation/v1/send/{txnId}`).
+//   Each destination has one worker sending a transaction at a time; failed
+//   transactions are retried with the same transaction id and exponential
+//   backoff. Queues are persisted so pending data survives a restart, and
+//   so is the health of every destination (failures in a row, when to
+//   retry, last success), so a restart neither hammers servers that are
+//   down nor forgets when they were last reachable. Each queue is kept as
+//   a journal of its changes, appended to by a writer thread so no file is
+//   written while the queues are locked; a journal is rewritten once most
+//   of it has been delivered.
+//   Ephemeral updates are batched: a worker woken for EDUs alone waits a
+//   moment for more to arrive, and typing, receipt and presence EDUs still
+//   waiting to be sent are merged with newer ones for the same room and
+//   user instead of being queued again.
+//   The queue doubles as the federation health probe: destinations that
+//   are down degrade federation, and every known destination being down,
+//   which points at our own connectivity, fails it.
+//
+// =============================================================================
+
+use std::{
+    collections::{hash_map, HashMap, HashSet, VecDeque},
+    fs,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_trait::async_trait;
+use matrixon_core::health::{ComponentHealth, HealthProbe};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, info, instrument, warn};
+
+use crate::{dns::DnsResolver, http::ClientSettings, resolver::Resolver, FederationError};
+
+/// Consecutive failures after which a destination is reported as down
+const DOWN_AFTER_FAILURES: u32 = 3;
+
+/// Known destinations needed before all of them being down fails federation
+const MIN_DESTINATIONS_FOR_FAILURE: usize = 3;
+
+/// File in the queue directory holding the health of every destination;
+/// queue files are named after destinations, which cannot start with `_`
+const HEALTH_FILE: &str = "_destinations.json";
+
+/// Extension of the queue journals
+const JOURNAL_EXTENSION: &str = "jsonl";
+
+/// Entries a journal may hold beyond twice its queue's length before it is
+/// rewritten
+const JOURNAL_SLACK: usize = 100;
+
+/// Settings of the sending queue
+#[derive(Debug, Clone)]
+pub struct SendingConfig {
+    /// Name of this server, the `origin` of transactions
+    pub server_name: String,
+    /// Directory the queues are persisted in; kept in memory only when unset
+    pub queue_dir: Option<PathBuf>,
+    /// PDUs per transaction; the spec allows at most 50
+    pub max_pdus_per_transaction: usize,
+    /// EDUs per transaction; the spec allows at most 100
+    pub max_edus_per_transaction: usize,
+    /// Serialized size of a transaction's PDUs and EDUs above which no more
+    /// are added; a single larger one is still sent alone
+    pub max_transaction_bytes: usize,
+    /// How long a worker woken for EDUs only waits for more before sending
+    pub edu_flush_delay: Duration,
+    /// Delay before the first retry, doubled on every further failure
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Connection settings of the clients talking to other servers
+    pub client: ClientSettings,
+}
+
+impl Default for SendingConfig {
+    fn default() -> Self {
+        Self {
+            server_name: "localhost".to_string(),
+            queue_dir: None,
+            max_pdus_per_transaction: 50,
+            max_edus_per_transaction: 100,
+            max_transaction_bytes: 512 * 1024,
+            edu_flush_delay: Duration::from_millis(100),
+            initial_backoff: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(24 * 60 * 60),
+            client: ClientSettings::default(),
+        }
+    }
+}
+
+/// Produces the `Authorization` header of outgoing federation requests
+/// (the `X-Matrix` scheme), given the request being sent
+pub trait RequestSigner: Send + Sync {
+    fn authorization(&self, method: &str, uri: &str, destination: &str, content: Option<&Value>) -> Option<String>;
+}
+
+/// Called with the destination and the last error when a destination
+/// starts failing consistently
+pub type DownHook = Arc<dyn Fn(&str, &str) + Send + Sync>;
+
+#[derive(Debug, Clone, Default)]
+struct Queue {
+    destination: String,
+    pdus: VecDeque<Value>,
+    edus: VecDeque<Value>,
+    /// Entries in the journal of the queue
+    journaled: usize,
+}
+
+impl Queue {
+    fn apply(&mut self, entry: &Entry) {
+        match entry {
+            Entry::Destination(_) => {}
+            Entry::Pdu(pdu) => self.pdus.push_back(pdu.clone()),
+            Entry::Edu { edu, after } => coalesce(&mut self.edus, *after, edu.clone()),
+            Entry::Sent { pdus, edus } => {
+                self.pdus.drain(..(*pdus).min(self.pdus.len()));
+                self.edus.drain(..(*edus).min(self.edus.len()));
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pdus.is_empty() && self.edus.is_empty()
+    }
+}
+
+/// A change to the queue of one destination, as journaled
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Entry {
+    /// First entry of every journal
+    Destination(String),
+    Pdu(Value),
+    /// An EDU, coalesced with the pending EDUs after the first `after`
+    Edu { edu: Value, after: usize },
+    /// The first `pdus` PDUs and `edus` EDUs were delivered
+    Sent { pdus: usize, edus: usize },
+}
+
+/// Work for the thread writing the queue directory, done in order
+enum Write {
+    Append(String, Entry),
+    /// Replace the journal of a queue with its pending entries
+    Rewrite(Queue),
+    Health(Vec<u8>),
+    #[cfg(test)]
+    Flush(mpsc::Sender<()>),
+}
+
+/// Transaction currently being delivered; retried unchanged until it succeeds
+#[derive(Debug, Clone)]
+struct InFlight {
+    txn_id: String,
+    pdus: usize,
+    edus: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Destination {
+    failures: u32,
+    retry_at: Option<u64>,
+    last_success: Option<u64>,
+    last_error: Option<String>,
+    /// Not persisted: after a restart the queue is sent as a new transaction
+    #[serde(skip)]
+    in_flight: Option<InFlight>,
+}
+
+/// Delivery state of one destination, for monitoring
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DestinationStatus {
+    pub destination: String,
+    pub pending_pdus: usize,
+    pub pending_edus: usize,
+    pub failures: u32,
+    pub retry_at: Option<u64>,
+    pub last_success: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+struct State {
+    queues: HashMap<String, Queue>,
+    destinations: HashMap<String, Destination>,
+    /// Destinations with a running worker
+    active: HashSet<String>,
+}
+
+/// Outbound federation sending service
+pub struct Service {
+    config: SendingConfig,
+    resolver: Resolver,
+    state: Mutex<State>,
+    /// Writer thread of the queue directory, if queues are persisted
+    writer: Option<mpsc::Sender<Write>>,
+    signer: RwLock<Option<Arc<dyn RequestSigner>>>,
+    down_hook: RwLock<Option<DownHook>>,
+    txn_counter: AtomicU64,
+    started_at: u64,
+}
+
+impl std::fmt::Debug for Service {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Service").field("config", &self.config).finish_non_exhaustive()
+    }
+}
+
+impl Service {
+    pub fn new(config: SendingConfig) -> Arc<Self> {
+        let writer = config.queue_dir.clone().map(|dir| {
+            let (writer, writes) = mpsc::channel();
+            thread::Builder::new()
+                .name("federation-queue".to_owned())
+                .spawn(move || write_queue_dir(&dir, writes))
+                .expect("threads can be spawned");
+            writer
+        });
+        Arc::new(Self {
+            resolver: Resolver::new(config.client.clone()),
+            config,
+            state: Mutex::default(),
+            writer,
+            signer: RwLock::new(None),
+            down_hook: RwLock::new(None),
+            txn_counter: AtomicU64::new(0),
+            started_at: now_millis(),
+        })
+    }
+
+    /// Sign outgoing requests with `signer`
+    pub fn set_signer(&self, signer: Arc<dyn RequestSigner>) {
+        *self.signer.write().unwrap() = Some(signer);
+    }
+
+    /// Resolver of destination names, for exporting its statistics
+    pub fn dns(&self) -> &DnsResolver {
+        &self.config.client.dns
+    }
+
+    /// Be notified when a destination goes down
+    pub fn set_down_hook(&self, hook: DownHook) {
+        *self.down_hook.write().unwrap() = Some(hook);
+    }
+
+    /// Load the persisted queues and restart delivery to their destinations.
+    /// Must be called from within a Tokio runtime.
+    pub fn resume(self: &Arc<Self>) -> Result<usize, FederationError> {
+        let Some(dir) = &self.config.queue_dir else {
+            return Ok(0);
+        };
+        fs::create_dir_all(dir).map_err(|e| FederationError::Configuration(format!("{}: {}", dir.display(), e)))?;
+
+        let mut resumed = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            match fs::read(dir.join(HEALTH_FILE)).map(|data| serde_json::from_slice(&data)) {
+                Ok(Ok(destinations)) => state.destinations = destinations,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                _ => warn!("⚠️ Ignoring unreadable federation destination health in {}", dir.display()),
+            }
+
+            let entries = fs::read_dir(dir).map_err(|e| FederationError::Configuration(e.to_string()))?;
+            let paths = entries.filter_map(|entry| entry.ok()).map(|entry| entry.path());
+            for path in paths.filter(|path| path.extension().is_some_and(|extension| extension == JOURNAL_EXTENSION)) {
+                let Some(queue) = fs::read(&path).ok().and_then(|data| replay(&data)) else {
+                    warn!("⚠️ Skipping unreadable federation queue {}", path.display());
+                    continue;
+                };
+                if !queue.is_empty() {
+                    resumed.push(queue.destination.clone());
+                }
+                state.queues.insert(queue.destination.clone(), queue);
+            }
+        }
+
+        for destination in &resumed {
+            self.wake(destination);
+        }
+        info!("📮 Resumed federation queues for {} destinations", resumed.len());
+        Ok(resumed.len())
+    }
+
+    /// Queue a PDU for every destination
+    pub fn send_pdu<'a>(self: &Arc<Self>, destinations: impl IntoIterator<Item = &'a str>, pdu: Value) {
+        for destination in destinations {
+            self.enqueue(destination, |_| Entry::Pdu(pdu.clone()));
+        }
+    }
+
+    /// Queue an EDU for one destination, merged into a pending EDU it
+    /// supersedes where possible
+    pub fn send_edu(self: &Arc<Self>, destination: &str, edu: Value) {
+        self.enqueue(destination, |in_flight| Entry::Edu {
+            edu,
+            after: in_flight.map_or(0, |in_flight| in_flight.edus),
+        });
+    }
+
+    /// Delivery state of every destination that was ever sent to
+    pub fn destinations(&self) -> Vec<DestinationStatus> {
+        let state = self.state.lock().unwrap();
+        let mut names: Vec<&String> = state.queues.keys().chain(state.destinations.keys()).collect();
+        names.sort();
+        names.dedup();
+        names
+            .into_iter()
+            .map(|name| {
+                let queue = state.queues.get(name);
+                let destination = state.destinations.get(name).cloned().unwrap_or_default();
+                DestinationStatus {
+                    destination: name.clone(),
+                    pending_pdus: queue.map_or(0, |queue| queue.pdus.len()),
+                    pending_edus: queue.map_or(0, |queue| queue.edus.len()),
+                    failures: destination.failures,
+                    retry_at: destination.retry_at,
+                    last_success: destination.last_success,
+                    last_error: destination.last_error,
+                }
+            })
+            .collect()
+    }
+
+    /// Retry a destination immediately instead of waiting out its backoff
+    pub fn reset_backoff(self: &Arc<Self>, destination: &str) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if let Some(status) = state.destinations.get_mut(destination) {
+                status.failures = 0;
+                status.retry_at = None;
+            }
+            self.persist_health(&state.destinations);
+        }
+        self.wake(destination);
+    }
+
+    /// Send a single signed request to another server and return its JSON
+    /// response, bypassing the queue
+    #[instrument(level = "debug", skip(self, body))]
+    pub async fn send_federation_request(
+        &self,
+        destination: &str,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<Value, FederationError> {
+        let response = self.signed_request(destination, method, path, body).await?;
+        let status = response.status();
+        let body: Value = response.json().await.unwrap_or(Value::Null);
+        if status.as_u16() == 429 {
+            let retry_after = body["retry_after_ms"].as_u64().unwrap_or(1000);
+            return Err(FederationError::RateLimited {
+                server: destination.to_owned(),
+                retry_after: Duration::from_millis(retry_after),
+            });
+        }
+        if !status.is_success() {
+            return Err(FederationError::InvalidRequest(format!("{} returned {}: {}", destination, status, body)));
+        }
+        Ok(body)
+    }
+
+    /// Send a signed `GET` request to another server and return the raw
+    /// response body with its content type, e.g. for media downloads
+    #[instrument(level = "debug", skip(self))]
+    pub async fn send_federation_request_raw(
+        &self,
+        destination: &str,
+        path: &str,
+    ) -> Result<(Option<String>, Vec<u8>), FederationError> {
+        let response = self.signed_request(destination, reqwest::Method::GET, path, None).await?;
+        let status = response.status();
+        if !status.is_success() {
+            return Err(FederationError::InvalidRequest(format!("{} returned {}", destination, status)));
+        }
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        let body = response
+            .bytes()
+            .await
+            .map_err(|e| FederationError::Network(format!("{}: {}", destination, e)))?;
+        Ok((content_type, body.to_vec()))
+    }
+
+    async fn signed_request(
+        &self,
+        destination: &str,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<Value>,
+    ) -> Result<reqwest::Response, FederationError> {
+        let resolution = self.resolver.resolve(destination).await;
+        let url = format!("{}{}", resolution.destination.base_url(), path);
+        let Some(client) = &resolution.client else {
+            return Err(FederationError::Network(format!("{} does not resolve", destination)));
+        };
+        let mut request = client
+            .request(method.clone(), &url)
+            .header(reqwest::header::HOST, &resolution.destination.host_header);
+        let signer = self.signer.read().unwrap().clone();
+        if let Some(authorization) = signer.and_then(|s| s.authorization(method.as_str(), path, destination, body.as_ref())) {
+            request = request.header("Authorization", authorization);
+        }
+        if let Some(body) = &body {
+            request = request.json(body);
+        }
+
+        request.send().await.map_err(|e| {
+            // Resolve again next time, the destination may have moved
+            self.resolver.invalidate(destination);
+            if e.is_timeout() {
+                FederationError::Timeout(format!("{}: {}", destination, e))
+            } else {
+                FederationError::Network(format!("{}: {}", destination, e))
+            }
+        })
+    }
+
+    /// Add to the queue of `destination`. `entry` gets the transaction in
+    /// flight, whose part of the queue must stay unchanged.
+    fn enqueue(self: &Arc<Self>, destination: &str, entry: impl FnOnce(Option<&InFlight>) -> Entry) {
+        if destination == self.config.server_name {
+            return;
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            let state = &mut *state;
+            let queue = state.queues.entry(destination.to_owned()).or_insert_with(|| Queue {
+                destination: destination.to_owned(),
+                ..Default::default()
+            });
+            let in_flight = state.destinations.get(destination).and_then(|status| status.in_flight.as_ref());
+            self.record(queue, entry(in_flight));
+        }
+        self.wake(destination);
+    }
+
+    /// Start a worker for `destination` unless one is running
+    fn wake(self: &Arc<Self>, destination: &str) {
+        if self.state.lock().unwrap().active.insert(destination.to_owned()) {
+            tokio::spawn(Arc::clone(self).deliver(destination.to_owned()));
+        }
+    }
+
+    /// Worker sending the queue of one destination until it is empty
+    async fn deliver(self: Arc<Self>, destination: String) {
+        let edus_only = self.state.lock().unwrap().queues.get(&destination).is_some_and(|queue| queue.pdus.is_empty());
+        if edus_only {
+            // Let a burst of EDUs accumulate into one transaction
+            tokio::time::sleep(self.config.edu_flush_delay).await;
+        }
+        loop {
+            let retry_at = self.state.lock().unwrap().destinations.get(&destination).and_then(|d| d.retry_at);
+            if let Some(retry_at) = retry_at {
+                let now = now_millis();
+                if retry_at > now {
+                    tokio::time::sleep(Duration::from_millis(retry_at - now)).await;
+                    continue;
+                }
+            }
+
+            let Some((txn_id, transaction)) = self.next_transaction(&destination) else {
+                debug!("📭 Federation queue for {} drained", destination);
+                return;
+            };
+
+            let path = format!("/_matrix/federation/v1/send/{}", txn_id);
+            let result = self
+                .send_federation_request(&destination, reqwest::Method::PUT, &path, Some(transaction))
+                .await;
+            self.finish_transaction(&destination, result.map(|_| ()).map_err(|e| e.to_string()));
+        }
+    }
+
+    /// Transaction to send next, or `None` (and the worker is retired) when
+    /// the queue is empty
+    fn next_transaction(&self, destination: &str) -> Option<(String, Value)> {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let queue = state.queues.get(destination).cloned().unwrap_or_default();
+        let status = state.destinations.entry(destination.to_owned()).or_default();
+
+        let in_flight = match &status.in_flight {
+            Some(in_flight) => in_flight.clone(),
+            None if queue.pdus.is_empty() && queue.edus.is_empty() => {
+                state.active.remove(destination);
+                return None;
+            }
+            None => {
+                let mut budget = self.config.max_transaction_bytes;
+                let pdus = fitting(&queue.pdus, self.config.max_pdus_per_transaction, &mut budget, true);
+                let edus = fitting(&queue.edus, self.config.max_edus_per_transaction, &mut budget, pdus == 0);
+                let in_flight = InFlight {
+                    txn_id: format!("{}_{}", self.started_at, self.txn_counter.fetch_add(1, Ordering::SeqCst)),
+                    pdus,
+                    edus,
+                };
+                status.in_flight = Some(in_flight.clone());
+                in_flight
+            }
+        };
+
+        let transaction = json!({
+            "origin": self.config.server_name,
+            "origin_server_ts": now_millis(),
+            "pdus": queue.pdus.iter().take(in_flight.pdus).collect::<Vec<_>>(),
+            "edus": queue.edus.iter().take(in_flight.edus).collect::<Vec<_>>(),
+        });
+        Some((in_flight.txn_id, transaction))
+    }
+
+    fn finish_transaction(&self, destination: &str, result: Result<(), String>) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let status = state.destinations.entry(destination.to_owned()).or_default();
+
+        match result {
+            Ok(()) => {
+                if let Some(in_flight) = status.in_flight.take() {
+                    if let Some(queue) = state.queues.get_mut(destination) {
+                        self.record(queue, Entry::Sent { pdus: in_flight.pdus, edus: in_flight.edus });
+                    }
+                    debug!("📨 Transaction {} delivered to {}", in_flight.txn_id, destination);
+                }
+                if status.failures >= DOWN_AFTER_FAILURES {
+                    info!("✅ Federation destination {} is back up", destination);
+                }
+                status.failures = 0;
+                status.retry_at = None;
+                status.last_error = None;
+                status.last_success = Some(now_millis());
+            }
+            Err(error) => {
+                status.failures += 1;
+                let backoff = backoff(&self.config, status.failures);
+                status.retry_at = Some(now_millis() + backoff.as_millis() as u64);
+                warn!(
+                    "⚠️ Transaction to {} failed ({} in a row), retrying in {:?}: {}",
+                    destination, status.failures, backoff, error
+                );
+                if status.failures == DOWN_AFTER_FAILURES {
+                    if let Some(hook) = self.down_hook.read().unwrap().clone() {
+                        hook(destination, &error);
+                    }
+                }
+                status.last_error = Some(error);
+            }
+        }
+        self.persist_health(&state.destinations);
+    }
+
+    /// Apply `entry` to `queue` and journal it
+    fn record(&self, queue: &mut Queue, entry: Entry) {
+        queue.apply(&entry);
+        let Some(writer) = &self.writer else {
+            return;
+        };
+        let pending = queue.pdus.len() + queue.edus.len();
+        queue.journaled += 1;
+        let write = if pending == 0 || queue.journaled > 2 * pending + JOURNAL_SLACK {
+            // Only the pending entries and the leading destination remain
+            queue.journaled = pending + 1;
+            Write::Rewrite(queue.clone())
+        } else {
+            Write::Append(queue.destination.clone(), entry)
+        };
+        // The writer only stops once the service is dropped
+        let _ = writer.send(write);
+    }
+
+    fn persist_health(&self, destinations: &HashMap<String, Destination>) {
+        if let Some(writer) = &self.writer {
+            let _ = writer.send(Write::Health(serde_json::to_vec(destinations).unwrap_or_default()));
+        }
+    }
+
+    /// Wait for the writer thread to catch up
+    #[cfg(test)]
+    fn flush(&self) {
+        let (done, flushed) = mpsc::channel();
+        if let Some(writer) = &self.writer {
+            writer.send(Write::Flush(done)).unwrap();
+            flushed.recv().unwrap();
+        }
+    }
+}
+
+/// Writer thread of the queue directory, running until the service is
+/// dropped
+fn write_queue_dir(dir: &Path, writes: mpsc::Receiver<Write>) {
+    let mut journals: HashMap<String, fs::File> = HashMap::new();
+    for write in writes {
+        let result = match write {
+            Write::Append(destination, entry) => append_entry(dir, &mut journals, &destination, &entry),
+            Write::Rewrite(queue) => {
+                journals.remove(&queue.destination);
+                rewrite_journal(dir, &queue)
+            }
+            Write::Health(data) => write_health(dir, &data),
+            #[cfg(test)]
+            Write::Flush(done) => {
+                let _ = done.send(());
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            warn!("⚠️ Could not persist federation queue state in {}: {}", dir.display(), e);
+        }
+    }
+}
+
+/// Replace the health file, so a crash leaves either the old or the new
+/// one
+fn write_health(dir: &Path, data: &[u8]) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let tmp = dir.join(format!(".{}.tmp", HEALTH_FILE));
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(HEALTH_FILE))?;
+    sync_dir(dir)
+}
+
+/// Make renames in `dir` durable
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+fn journal_path(dir: &Path, destination: &str) -> PathBuf {
+    dir.join(format!("{}.{}", destination.replace([':', '/', '\\'], "_"), JOURNAL_EXTENSION))
+}
+
+fn write_entry(file: &mut fs::File, entry: &Entry) -> io::Result<()> {
+    let mut line = serde_json::to_vec(entry).map_err(io::Error::other)?;
+    line.push(b'\n');
+    file.write_all(&line)
+}
+
+fn append_entry(dir: &Path, journals: &mut HashMap<String, fs::File>, destination: &str, entry: &Entry) -> io::Result<()> {
+    let file = match journals.entry(destination.to_owned()) {
+        hash_map::Entry::Occupied(file) => file.into_mut(),
+        hash_map::Entry::Vacant(slot) => {
+            fs::create_dir_all(dir)?;
+            let path = journal_path(dir, destination);
+            let new = !path.exists();
+            let mut file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+            if new {
+                write_entry(&mut file, &Entry::Destination(destination.to_owned()))?;
+            }
+            slot.insert(file)
+        }
+    };
+    write_entry(file, entry)
+}
+
+/// Replace the journal of `queue` with one holding just its pending
+/// entries; drained queues have no journal
+fn rewrite_journal(dir: &Path, queue: &Queue) -> io::Result<()> {
+    let path = journal_path(dir, &queue.destination);
+    if queue.is_empty() {
+        return fs::remove_file(&path).or_else(|e| if e.kind() == io::ErrorKind::NotFound { Ok(()) } else { Err(e) });
+    }
+    fs::create_dir_all(dir)?;
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    write_entry(&mut file, &Entry::Destination(queue.destination.clone()))?;
+    for pdu in &queue.pdus {
+        write_entry(&mut file, &Entry::Pdu(pdu.clone()))?;
+    }
+    // Coalescing past every EDU before it keeps each one as it is
+    for (after, edu) in queue.edus.iter().enumerate() {
+        write_entry(&mut file, &Entry::Edu { edu: edu.clone(), after })?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp, &path)?;
+    sync_dir(dir)
+}
+
+/// The queue a journal describes. A torn last entry, cut short by a crash,
+/// is ignored.
+fn replay(journal: &[u8]) -> Option<Queue> {
+    let mut entries = journal
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(serde_json::from_slice::<Entry>);
+    let Some(Ok(Entry::Destination(destination))) = entries.next() else {
+        return None;
+    };
+    let mut queue = Queue { destination, journaled: 1, ..Default::default() };
+    for entry in entries.map_while(Result::ok) {
+        queue.apply(&entry);
+        queue.journaled += 1;
+    }
+    Some(queue)
+}
+
+/// How many of the first `max` items fit in `budget` bytes, which is
+/// reduced by their size. With `at_least_one`, the first item is taken even
+/// when it is larger than the budget.
+fn fitting(items: &VecDeque<Value>, max: usize, budget: &mut usize, at_least_one: bool) -> usize {
+    let mut count = 0;
+    for item in items.iter().take(max) {
+        let size = serde_json::to_vec(item).map_or(0, |bytes| bytes.len());
+        if size > *budget && !(at_least_one && count == 0) {
+            break;
+        }
+        *budget = budget.saturating_sub(size);
+        count += 1;
+    }
+    count
+}
+
+/// Queue `edu` after the first `in_flight` EDUs, which belong to the
+/// transaction being sent. Typing notifications replace the pending one of
+/// the same user in the same room; receipts and presence updates are
+/// merged into the pending EDU of their type, newer values winning. Other
+/// EDUs, device list updates among them, are ordered streams and are
+/// always appended.
+fn coalesce(edus: &mut VecDeque<Value>, in_flight: usize, edu: Value) {
+    let edu_type = edu["edu_type"].as_str().unwrap_or_default().to_owned();
+    let mut pending = edus.iter_mut().skip(in_flight);
+    match edu_type.as_str() {
+        "m.typing" => {
+            let same = |queued: &&mut Value| {
+                queued["edu_type"] == "m.typing"
+                    && queued["content"]["room_id"] == edu["content"]["room_id"]
+                    && queued["content"]["user_id"] == edu["content"]["user_id"]
+            };
+            match pending.find(same) {
+                Some(queued) => *queued = edu,
+                None => edus.push_back(edu),
+            }
+        }
+        "m.receipt" => match pending.find(|queued| queued["edu_type"] == "m.receipt") {
+            Some(queued) => merge(&mut queued["content"], &edu["content"], 3),
+            None => edus.push_back(edu),
+        },
+        "m.presence" => match pending.find(|queued| queued["edu_type"] == "m.presence") {
+            Some(queued) => {
+                let updates = edu["content"]["push"].as_array().cloned().unwrap_or_default();
+                if let Some(queued_updates) = queued["content"]["push"].as_array_mut() {
+                    queued_updates.retain(|queued| !updates.iter().any(|update| update["user_id"] == queued["user_id"]));
+                    queued_updates.extend(updates);
+                }
+            }
+            None => edus.push_back(edu),
+        },
+        _ => edus.push_back(edu),
+    }
+}
+
+/// Merge the object `from` into `into`, `depth` levels deep; below that,
+/// values of `from` replace those of `into`
+fn merge(into: &mut Value, from: &Value, depth: usize) {
+    match (into.as_object_mut(), from.as_object()) {
+        (Some(into), Some(from)) if depth > 0 => {
+            for (key, value) in from {
+                merge(into.entry(key.clone()).or_insert(Value::Null), value, depth - 1);
+            }
+        }
+        _ => *into = from.clone(),
+    }
+}
+
+#[async_trait]
+impl HealthProbe for Service {
+    async fn check(&self) -> ComponentHealth {
+        let destinations = self.destinations();
+        let down: Vec<&str> = destinations
+            .iter()
+            .filter(|destination| destination.failures >= DOWN_AFTER_FAILURES)
+            .map(|destination| destination.destination.as_str())
+            .collect();
+        if down.is_empty() {
+            ComponentHealth::healthy()
+        } else if down.len() == destinations.len() && down.len() >= MIN_DESTINATIONS_FOR_FAILURE {
+            ComponentHealth::failed(format!("All {} destinations are down", down.len()))
+        } else {
+            ComponentHealth::degraded(format!("{} of {} destinations are down: {}", down.len(), destinations.len(), down.join(", ")))
+        }
+    }
+}
+
+/// Delay before retry number `failures`
+fn backoff(config: &SendingConfig, failures: u32) -> Duration {
+    config
+        .initial_backoff
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(config.max_backoff)
+}
+
+/// Base URL of a destination server without delegation. Server names
+/// without an explicit port use the default federation port.
+pub fn base_url(destination: &str) -> String {
+    let has_port = destination.rsplit_once(':').is_some_and(|(_, port)| port.parse::<u16>().is_ok());
+    if has_port {
+        format!("https://{}", destination)
+    } else {
+        format!("https://{}:8448", destination)
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(queue_dir: Option<PathBuf>) -> SendingConfig {
+        SendingConfig {
+            server_name: "origin.example".to_string(),
+            queue_dir,
+            max_pdus_per_transaction: 2,
+            ..Default::default()
+        }
+    }
+
+    fn push(service: &Service, destination: &str, count: usize) {
+        let mut state = service.state.lock().unwrap();
+        let queue = state.queues.entry(destination.to_owned()).or_insert_with(|| Queue {
+            destination: destination.to_owned(),
+            ..Default::default()
+        });
+        for i in 0..count {
+            service.record(queue, Entry::Pdu(json!({ "event_id": format!("${}", i) })));
+        }
+    }
+
+    #[test]
+    fn test_failed_transactions_are_retried_unchanged() {
+        let service = Service::new(config(None));
+        push(&service, "remote.example", 3);
+
+        let (txn_id, transaction) = service.next_transaction("remote.example").unwrap();
+        assert_eq!(transaction["pdus"].as_array().unwrap().len(), 2);
+        service.finish_transaction("remote.example", Err("connection refused".to_owned()));
+        push(&service, "remote.example", 1);
+
+        let (retry_id, retry) = service.next_transaction("remote.example").unwrap();
+        assert_eq!(retry_id, txn_id);
+        assert_eq!(retry["pdus"], transaction["pdus"]);
+        assert_eq!(service.destinations()[0].failures, 1);
+
+        service.finish_transaction("remote.example", Ok(()));
+        let (next_id, next) = service.next_transaction("remote.example").unwrap();
+        assert_ne!(next_id, txn_id);
+        assert_eq!(next["pdus"].as_array().unwrap().len(), 2);
+        service.finish_transaction("remote.example", Ok(()));
+        assert!(service.next_transaction("remote.example").is_none());
+    }
+
+    #[test]
+    fn test_destination_down_hook_fires_once() {
+        let service = Service::new(config(None));
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&reported);
+        service.set_down_hook(Arc::new(move |destination, _| sink.lock().unwrap().push(destination.to_owned())));
+
+        push(&service, "down.example", 1);
+        for _ in 0..5 {
+            service.next_transaction("down.example");
+            service.finish_transaction("down.example", Err("timeout".to_owned()));
+        }
+        assert_eq!(*reported.lock().unwrap(), vec!["down.example"]);
+        assert_eq!(backoff(&service.config, 3), Duration::from_secs(20));
+    }
+
+    #[tokio::test]
+    async fn test_down_destinations_degrade_health() {
+        let service = Service::new(config(None));
+        assert_eq!(service.check().await, ComponentHealth::healthy());
+
+        push(&service, "up.example", 1);
+        push(&service, "down.example", 1);
+        for _ in 0..DOWN_AFTER_FAILURES {
+            service.next_transaction("down.example");
+            service.finish_transaction("down.example", Err("timeout".to_owned()));
+        }
+        assert_eq!(service.check().await, ComponentHealth::degraded("1 of 2 destinations are down: down.example"));
+    }
+
+    #[tokio::test]
+    async fn test_queues_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = Service::new(config(Some(dir.path().to_owned())));
+        push(&service, "remote.example:8448", 2);
+        service.flush();
+
+        let restarted = Service::new(config(Some(dir.path().to_owned())));
+        // Keep the worker from delivering while the queue is inspected
+        restarted.state.lock().unwrap().active.insert("remote.example:8448".to_owned());
+        assert_eq!(restarted.resume().unwrap(), 1);
+        assert_eq!(restarted.destinations()[0].pending_pdus, 2);
+    }
+
+    #[tokio::test]
+    async fn test_journals_replay_the_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = Service::new(config(Some(dir.path().to_owned())));
+        let typing = |typing: bool| json!({ "edu_type": "m.typing", "content": { "room_id": "!a:b", "user_id": "@a:b", "typing": typing } });
+        {
+            let mut state = service.state.lock().unwrap();
+            let queue = state.queues.entry("remote.example".to_owned()).or_insert_with(|| Queue {
+                destination: "remote.example".to_owned(),
+                ..Default::default()
+            });
+            service.record(queue, Entry::Edu { edu: typing(true), after: 0 });
+            service.record(queue, Entry::Edu { edu: typing(false), after: 1 });
+            for i in 0..JOURNAL_SLACK * 2 {
+                service.record(queue, Entry::Pdu(json!({ "event_id": format!("${}", i) })));
+                service.record(queue, Entry::Sent { pdus: 1, edus: 0 });
+            }
+            service.record(queue, Entry::Pdu(json!({ "event_id": "$last" })));
+        }
+        service.flush();
+
+        let journal = fs::read(journal_path(dir.path(), "remote.example")).unwrap();
+        // Delivered entries were compacted away
+        assert!(journal.split(|byte| *byte == b'\n').count() < JOURNAL_SLACK * 2);
+        let queue = replay(&journal).unwrap();
+        let live = service.state.lock().unwrap().queues["remote.example"].clone();
+        assert_eq!(queue.pdus, live.pdus);
+        assert_eq!(queue.edus, live.edus);
+        assert_eq!(queue.edus.len(), 2);
+
+        // A torn entry at the end is ignored
+        let mut torn = journal.clone();
+        torn.extend_from_slice(b"{\"pdu\":{\"event");
+        assert_eq!(replay(&torn).unwrap().pdus, live.pdus);
+    }
+
+    #[tokio::test]
+    async fn test_destination_health_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = Service::new(config(Some(dir.path().to_owned())));
+        push(&service, "down.example", 1);
+        service.next_transaction("down.example");
+        service.finish_transaction("down.example", Err("connection refused".to_owned()));
+        service.state.lock().unwrap().destinations.entry("up.example".to_owned()).or_default();
+        service.finish_transaction("up.example", Ok(()));
+        let before = service.destinations();
+        service.flush();
+        assert!(dir.path().join(HEALTH_FILE).exists());
+        assert!(!dir.path().join(format!(".{}.tmp", HEALTH_FILE)).exists());
+
+        let restarted = Service::new(config(Some(dir.path().to_owned())));
+        restarted.state.lock().unwrap().active.insert("down.example".to_owned());
+        assert_eq!(restarted.resume().unwrap(), 1);
+        assert_eq!(restarted.destinations(), before);
+        assert!(restarted.destinations()[0].retry_at.is_some());
+        assert!(restarted.destinations()[1].last_success.is_some());
+    }
+
+    #[test]
+    fn test_pending_edus_are_coalesced() {
+        let typing = |user: &str, typing: bool| {
+            json!({ "edu_type": "m.typing", "content": { "room_id": "!a:origin.example", "user_id": user, "typing": typing } })
+        };
+        let receipt = |user: &str, event_id: &str| {
+            json!({ "edu_type": "m.receipt", "content": { "!a:origin.example": { "m.read": {
+                user: { "event_ids": [event_id], "data": { "ts": 1 } }
+            } } } })
+        };
+        let presence = |user: &str, state: &str| {
+            json!({ "edu_type": "m.presence", "content": { "push": [{ "user_id": user, "presence": state }] } })
+        };
+
+        let mut edus = VecDeque::new();
+        coalesce(&mut edus, 0, typing("@a:origin.example", true));
+        // The first EDU is in flight and stays as it was sent
+        coalesce(&mut edus, 1, typing("@a:origin.example", false));
+        coalesce(&mut edus, 1, typing("@a:origin.example", true));
+        coalesce(&mut edus, 1, typing("@b:origin.example", true));
+        assert_eq!(edus.len(), 3);
+        assert_eq!(edus[0]["content"]["typing"], true);
+        assert_eq!(edus[1]["content"]["typing"], true);
+
+        let mut edus = VecDeque::new();
+        coalesce(&mut edus, 0, receipt("@a:origin.example", "$1"));
+        coalesce(&mut edus, 0, receipt("@b:origin.example", "$1"));
+        coalesce(&mut edus, 0, receipt("@a:origin.example", "$2"));
+        coalesce(&mut edus, 0, presence("@a:origin.example", "online"));
+        coalesce(&mut edus, 0, presence("@b:origin.example", "online"));
+        coalesce(&mut edus, 0, presence("@a:origin.example", "offline"));
+        coalesce(&mut edus, 0, json!({ "edu_type": "m.device_list_update", "content": { "stream_id": 1 } }));
+        coalesce(&mut edus, 0, json!({ "edu_type": "m.device_list_update", "content": { "stream_id": 2 } }));
+        assert_eq!(edus.len(), 4);
+        let reads = &edus[0]["content"]["!a:origin.example"]["m.read"];
+        assert_eq!(reads["@a:origin.example"]["event_ids"], json!(["$2"]));
+        assert_eq!(reads["@b:origin.example"]["event_ids"], json!(["$1"]));
+        assert_eq!(
+            edus[1]["content"]["push"],
+            json!([{ "user_id": "@b:origin.example", "presence": "online" }, { "user_id": "@a:origin.example", "presence": "offline" }])
+        );
+    }
+
+    #[test]
+    fn test_transactions_respect_size_limit() {
+        let service = Service::new(SendingConfig { max_transaction_bytes: 1000, ..config(None) });
+        push(&service, "remote.example", 1);
+        {
+            let mut state = service.state.lock().unwrap();
+            let queue = state.queues.get_mut("remote.example").unwrap();
+            for _ in 0..3 {
+                queue.edus.push_back(json!({ "edu_type": "m.direct_to_device", "content": { "padding": "x".repeat(400) } }));
+            }
+        }
+
+        let (_, transaction) = service.next_transaction("remote.example").unwrap();
+        assert_eq!(transaction["pdus"].as_array().unwrap().len(), 1);
+        assert_eq!(transaction["edus"].as_array().unwrap().len(), 2);
+        service.finish_transaction("remote.example", Ok(()));
+        let (_, transaction) = service.next_transaction("remote.example").unwrap();
+        assert_eq!(transaction["edus"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_base_url() {
+        assert_eq!(base_url("example.org"), "https://example.org:8448");
+        assert_eq!(base_url("example.org:443"), "https://example.org:443");
+        assert_eq!(base_url("[::1]"), "https://[::1]:8448");
+    }
+}
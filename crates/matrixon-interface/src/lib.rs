@@ -73,6 +73,7 @@ use axum::{
     response::IntoResponse,
 };
 use tower_http::trace::TraceLayer;
+use matrixon_common::internal_tls::{self, InternalTlsConfig};
 use yew::prelude::*;
 use stylist::{Style, style};
 
@@ -109,6 +110,13 @@ pub struct Config {
     pub ws_path: String,
     pub api_path: String,
     pub static_path: String,
+    /// Serve the admin API over mutual TLS with certificates of the internal
+    /// CA. Required unless `plaintext` is set.
+    #[serde(default)]
+    pub tls: Option<InternalTlsConfig>,
+    /// Serve without TLS when `tls` is unset, for development only
+    #[serde(default)]
+    pub plaintext: bool,
 }
 
 /// UI state
@@ -152,17 +160,52 @@ impl Service {
         let start = std::time::Instant::now();
         debug!("🔧 Starting interface service");
 
-        let _app = Router::new()
+        let ui_state = UiState {
+            theme: Theme::System,
+            language: "en".to_string(),
+            notifications: true,
+            user_id: None,
+        };
+        let app = Router::new()
             .route("/", get(Self::handle_index))
             .route("/ws", get(Self::handle_ws))
             .route("/api/state", get(Self::handle_state))
             .route("/api/theme", post(Self::handle_theme))
-            .layer(TraceLayer::new_for_http());
+            .layer(TraceLayer::new_for_http())
+            .with_state(Arc::new(RwLock::new(ui_state)));
 
         let config = self.config.read().await;
+        if config.tls.is_none() && !config.plaintext {
+            return Err(Error::Api("The interface needs `tls`, or `plaintext = true` to serve without it".to_owned()));
+        }
         let addr = format!("{}:{}", config.host, config.port);
-        
-        info!("✅ Interface service started on {} in {:?}", addr, start.elapsed());
+        let listener = tokio::net::TcpListener::bind(&addr)
+            .await
+            .map_err(|e| Error::Api(format!("Failed to bind to {}: {}", addr, e)))?;
+        match &config.tls {
+            Some(tls) => {
+                let tls = internal_tls::server_config(tls).map_err(|e| Error::Api(e.to_string()))?;
+                tokio::spawn(async move {
+                    if let Err(e) = internal_tls::serve(listener, app, tls).await {
+                        error!("❌ Interface server failed: {}", e);
+                    }
+                });
+            }
+            None => {
+                tokio::spawn(async move {
+                    if let Err(e) = axum::serve(listener, app).await {
+                        error!("❌ Interface server failed: {}", e);
+                    }
+                });
+            }
+        }
+
+        info!(
+            "✅ Interface service started on {}{} in {:?}",
+            addr,
+            if config.tls.is_some() { " (mutual TLS)" } else { "" },
+            start.elapsed()
+        );
         Ok(())
     }
 
@@ -267,6 +310,8 @@ mod tests {
             ws_path: "/ws".to_string(),
             api_path: "/api".to_string(),
             static_path: "/static".to_string(),
+            tls: None,
+            plaintext: false,
         };
         
         let service = Service::new(config.clone()).unwrap();
@@ -278,6 +323,20 @@ mod tests {
         assert_eq!(service_config.static_path, "/static");
     }
 
+    #[tokio::test]
+    async fn test_plaintext_must_be_chosen() {
+        let config = Config {
+            host: "127.0.0.1".to_string(),
+            port: 0,
+            ws_path: "/ws".to_string(),
+            api_path: "/api".to_string(),
+            static_path: "/static".to_string(),
+            tls: None,
+            plaintext: false,
+        };
+        assert!(Service::new(config).unwrap().start().await.is_err());
+    }
+
     #[tokio::test]
     async fn test_theme_styles() {
         let styles = theme_styles();
@@ -1,16 +1,24 @@
 //! # Analytics Module
 //!
 //! Real-time data analytics and time-series processing for IoT data.
+//!
+//! Raw telemetry is kept only for a short time. A periodic downsampling
+//! job rolls raw points into per-minute aggregates and per-minute
+//! aggregates into per-hour aggregates once they reach a configurable age,
+//! pruning what it rolled up, so storage stays bounded however many
+//! devices report.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+use std::time::Duration;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, DurationRound, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
-use tracing::{info, instrument};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, instrument};
 
-use crate::{IoTError, IoTMessage, IoTConfig};
+use crate::{IoTError, IoTMessage, IoTConfig, MessageType};
 
 /// Time-series data point
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,31 +30,278 @@ pub struct TimeSeriesData {
     pub tags: HashMap<String, String>,
 }
 
+/// Aggregate resolution
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Resolution {
+    Minute,
+    Hour,
+}
+
+impl Resolution {
+    /// Width of one bucket
+    pub fn bucket(self) -> chrono::Duration {
+        match self {
+            Resolution::Minute => chrono::Duration::minutes(1),
+            Resolution::Hour => chrono::Duration::hours(1),
+        }
+    }
+
+    /// Start of the bucket containing `timestamp`
+    pub fn bucket_start(self, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+        timestamp.duration_trunc(self.bucket()).unwrap_or(timestamp)
+    }
+}
+
+/// Summary of one metric of one device over a bucket
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Aggregate {
+    pub device_id: String,
+    pub metric: String,
+    pub resolution: Resolution,
+    pub bucket_start: DateTime<Utc>,
+    pub count: u64,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Aggregate {
+    fn empty(device_id: &str, metric: &str, resolution: Resolution, bucket_start: DateTime<Utc>) -> Self {
+        Aggregate {
+            device_id: device_id.to_string(),
+            metric: metric.to_string(),
+            resolution,
+            bucket_start,
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Mean value over the bucket
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.sum / self.count as f64
+        }
+    }
+
+    fn add_value(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn merge(&mut self, other: &Aggregate) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+    }
+}
+
+/// Retention and downsampling configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RetentionConfig {
+    /// Age after which raw points are rolled into minute aggregates
+    pub raw_max_age: Duration,
+
+    /// Age after which minute aggregates are rolled into hour aggregates
+    pub minute_max_age: Duration,
+
+    /// Age after which hour aggregates are pruned
+    pub hour_max_age: Duration,
+
+    /// How often the downsampling job runs
+    pub downsample_interval: Duration,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            raw_max_age: Duration::from_secs(60 * 60),
+            minute_max_age: Duration::from_secs(7 * 24 * 60 * 60),
+            hour_max_age: Duration::from_secs(365 * 24 * 60 * 60),
+            downsample_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Outcome of one downsampling run
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownsampleReport {
+    /// Raw points rolled into minute aggregates
+    pub raw_rolled: usize,
+    /// Minute aggregates rolled into hour aggregates
+    pub minute_rolled: usize,
+    /// Hour aggregates pruned
+    pub hour_pruned: usize,
+}
+
+type SeriesKey = (String, String, DateTime<Utc>);
+
 /// Analytics engine for processing IoT data
 pub struct AnalyticsEngine {
     data_points: Arc<RwLock<Vec<TimeSeriesData>>>,
     aggregated_metrics: Arc<RwLock<HashMap<String, f64>>>,
+    minute_aggregates: Arc<RwLock<BTreeMap<SeriesKey, Aggregate>>>,
+    hour_aggregates: Arc<RwLock<BTreeMap<SeriesKey, Aggregate>>>,
+    retention: RetentionConfig,
 }
 
 impl AnalyticsEngine {
     #[instrument]
     pub async fn new(config: &IoTConfig) -> Result<Self, IoTError> {
         info!("🔧 Initializing Analytics Engine");
-        
+
         Ok(AnalyticsEngine {
             data_points: Arc::new(RwLock::new(Vec::new())),
             aggregated_metrics: Arc::new(RwLock::new(HashMap::new())),
+            minute_aggregates: Arc::new(RwLock::new(BTreeMap::new())),
+            hour_aggregates: Arc::new(RwLock::new(BTreeMap::new())),
+            retention: config.retention.clone(),
         })
     }
-    
+
+    /// Record the numeric fields of a telemetry message as data points
     pub async fn process_message(&self, message: &IoTMessage) -> Result<(), IoTError> {
-        // Process message for analytics
+        if message.message_type != MessageType::Telemetry {
+            return Ok(());
+        }
+        let Some(fields) = message.payload.as_object() else {
+            return Ok(());
+        };
+
+        for (metric, value) in fields {
+            if let Some(value) = value.as_f64() {
+                self.record(TimeSeriesData {
+                    timestamp: message.timestamp,
+                    device_id: message.device_id.clone(),
+                    metric: metric.clone(),
+                    value,
+                    tags: message.metadata.clone(),
+                })
+                .await;
+            }
+        }
         Ok(())
     }
-    
+
+    /// Store a raw data point
+    pub async fn record(&self, point: TimeSeriesData) {
+        let key = format!("{}.{}", point.device_id, point.metric);
+        self.aggregated_metrics.write().await.insert(key, point.value);
+        self.data_points.write().await.push(point);
+    }
+
     pub async fn get_aggregated_metrics(&self) -> HashMap<String, f64> {
         self.aggregated_metrics.read().await.clone()
     }
+
+    /// Number of raw data points currently stored
+    pub async fn raw_len(&self) -> usize {
+        self.data_points.read().await.len()
+    }
+
+    /// Roll up and prune everything older than the configured ages at `now`
+    #[instrument(level = "debug", skip(self))]
+    pub async fn downsample(&self, now: DateTime<Utc>) -> DownsampleReport {
+        let mut report = DownsampleReport::default();
+        let raw_cutoff = now - to_chrono(self.retention.raw_max_age);
+        let minute_cutoff = now - to_chrono(self.retention.minute_max_age);
+        let hour_cutoff = now - to_chrono(self.retention.hour_max_age);
+
+        // Raw points -> minute aggregates
+        {
+            let mut data_points = self.data_points.write().await;
+            let (expired, kept): (Vec<_>, Vec<_>) = data_points
+                .drain(..)
+                .partition(|point| point.timestamp < raw_cutoff);
+            *data_points = kept;
+
+            let mut minutes = self.minute_aggregates.write().await;
+            for point in &expired {
+                let bucket_start = Resolution::Minute.bucket_start(point.timestamp);
+                minutes
+                    .entry((point.device_id.clone(), point.metric.clone(), bucket_start))
+                    .or_insert_with(|| Aggregate::empty(&point.device_id, &point.metric, Resolution::Minute, bucket_start))
+                    .add_value(point.value);
+            }
+            report.raw_rolled = expired.len();
+        }
+
+        // Minute aggregates -> hour aggregates
+        {
+            let mut minutes = self.minute_aggregates.write().await;
+            let expired: Vec<SeriesKey> = minutes
+                .keys()
+                .filter(|(_, _, bucket_start)| *bucket_start < minute_cutoff)
+                .cloned()
+                .collect();
+
+            let mut hours = self.hour_aggregates.write().await;
+            for key in &expired {
+                let Some(minute) = minutes.remove(key) else { continue };
+                let bucket_start = Resolution::Hour.bucket_start(minute.bucket_start);
+                hours
+                    .entry((minute.device_id.clone(), minute.metric.clone(), bucket_start))
+                    .or_insert_with(|| Aggregate::empty(&minute.device_id, &minute.metric, Resolution::Hour, bucket_start))
+                    .merge(&minute);
+            }
+            report.minute_rolled = expired.len();
+        }
+
+        // Hour aggregates past retention are dropped
+        {
+            let mut hours = self.hour_aggregates.write().await;
+            let before = hours.len();
+            hours.retain(|(_, _, bucket_start), _| *bucket_start >= hour_cutoff);
+            report.hour_pruned = before - hours.len();
+        }
+
+        debug!("📉 Downsampling done: {:?}", report);
+        report
+    }
+
+    /// Aggregates of one device metric at `resolution` with buckets
+    /// starting in `[from, to)`, oldest first
+    pub async fn query_aggregates(
+        &self,
+        device_id: &str,
+        metric: &str,
+        resolution: Resolution,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Vec<Aggregate> {
+        let aggregates = match resolution {
+            Resolution::Minute => self.minute_aggregates.read().await,
+            Resolution::Hour => self.hour_aggregates.read().await,
+        };
+        let range = (device_id.to_string(), metric.to_string(), from)..(device_id.to_string(), metric.to_string(), to);
+        aggregates.range(range).map(|(_, aggregate)| aggregate.clone()).collect()
+    }
+
+    /// Run the downsampling job every `downsample_interval`
+    pub fn start_retention_job(self: &Arc<Self>) -> JoinHandle<()> {
+        let engine = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(engine.retention.downsample_interval);
+            loop {
+                interval.tick().await;
+                let report = engine.downsample(Utc::now()).await;
+                if report != DownsampleReport::default() {
+                    info!("📉 Downsampled telemetry: {:?}", report);
+                }
+            }
+        })
+    }
+}
+
+fn to_chrono(duration: Duration) -> chrono::Duration {
+    chrono::Duration::from_std(duration).unwrap_or(chrono::Duration::MAX)
 }
 
 /// Data analyzer for statistical analysis
@@ -56,7 +311,7 @@ impl DataAnalyzer {
     pub fn new() -> Self {
         DataAnalyzer
     }
-    
+
     pub fn calculate_average(&self, values: &[f64]) -> f64 {
         if values.is_empty() {
             0.0
@@ -64,4 +319,55 @@ impl DataAnalyzer {
             values.iter().sum::<f64>() / values.len() as f64
         }
     }
-} 
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn point(timestamp: DateTime<Utc>, value: f64) -> TimeSeriesData {
+        TimeSeriesData {
+            timestamp,
+            device_id: "sensor001".to_string(),
+            metric: "temperature".to_string(),
+            value,
+            tags: HashMap::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_downsampling_rolls_up_and_prunes() {
+        let engine = AnalyticsEngine::new(&IoTConfig::default()).await.unwrap();
+        let start = Utc.with_ymd_and_hms(2024, 12, 1, 10, 0, 0).unwrap();
+        for (seconds, value) in [(5, 20.0), (30, 22.0), (65, 30.0)] {
+            engine.record(point(start + chrono::Duration::seconds(seconds), value)).await;
+        }
+
+        // Two hours later the raw points become minute aggregates
+        let report = engine.downsample(start + chrono::Duration::hours(2)).await;
+        assert_eq!(report.raw_rolled, 3);
+        assert_eq!(engine.raw_len().await, 0);
+        let minutes = engine
+            .query_aggregates("sensor001", "temperature", Resolution::Minute, start, start + chrono::Duration::hours(1))
+            .await;
+        assert_eq!(minutes.len(), 2);
+        assert_eq!((minutes[0].count, minutes[0].mean(), minutes[0].max), (2, 21.0, 22.0));
+
+        // After a week they are merged into one hour bucket
+        let report = engine.downsample(start + chrono::Duration::days(8)).await;
+        assert_eq!(report.minute_rolled, 2);
+        let hours = engine
+            .query_aggregates("sensor001", "temperature", Resolution::Hour, start, start + chrono::Duration::days(1))
+            .await;
+        assert_eq!(hours.len(), 1);
+        assert_eq!((hours[0].count, hours[0].min, hours[0].max), (3, 20.0, 30.0));
+
+        let report = engine.downsample(start + chrono::Duration::days(400)).await;
+        assert_eq!(report.hour_pruned, 1);
+    }
+}
@@ -0,0 +1,270 @@
+//! # Presence-Aware Delivery Module
+//!
+//! Delivery hints for commands sent to battery-powered actuators.
+//! When every member of the Matrix room a device is bound to is offline,
+//! nobody is waiting for a non-critical command to take effect, so it is
+//! queued and later sent in one batch together with the device's other
+//! pending commands. This saves radio wakeups on the device.
+//!
+//! Queued commands are released when someone in the room comes online,
+//! when a critical message has to wake the device anyway, when the batch
+//! is full or when the oldest queued command reaches the batching window.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{debug, info, instrument};
+
+use crate::{DeviceConfig, IoTMessage, MessagePriority, MessageType, PowerSource};
+
+// =============================================================================
+// Presence Lookup and Configuration
+// =============================================================================
+
+/// Source of Matrix presence for the rooms devices are bound to
+#[async_trait]
+pub trait PresenceSource: Send + Sync {
+    /// Whether every member of the room is offline
+    async fn all_members_offline(&self, room_id: &str) -> bool;
+}
+
+/// Batching configuration for queued delivery
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryConfig {
+    /// Longest time a command is held back
+    pub batch_window: Duration,
+
+    /// Number of queued commands that triggers delivery of the batch
+    pub max_batch_size: usize,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        DeliveryConfig {
+            batch_window: Duration::from_secs(300),
+            max_batch_size: 20,
+        }
+    }
+}
+
+/// How a message should be delivered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryMode {
+    /// Send right away
+    Immediate,
+    /// Hold back and send with the next batch
+    Queued,
+}
+
+/// Commands waiting for one device
+#[derive(Debug)]
+struct PendingBatch {
+    room_id: String,
+    since: Instant,
+    messages: Vec<IoTMessage>,
+}
+
+// =============================================================================
+// Presence-Aware Delivery Implementation
+// =============================================================================
+
+/// Decides between immediate and queued delivery and keeps the queues
+pub struct PresenceAwareDelivery {
+    presence: Arc<dyn PresenceSource>,
+    config: DeliveryConfig,
+    pending: RwLock<HashMap<String, PendingBatch>>,
+}
+
+impl std::fmt::Debug for PresenceAwareDelivery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PresenceAwareDelivery")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+/// Whether a message may be delayed at all: only non-critical commands to
+/// battery-powered devices bound to a room qualify
+pub fn may_defer(device: &DeviceConfig, message: &IoTMessage) -> bool {
+    message.message_type == MessageType::Command
+        && message.priority < MessagePriority::High
+        && matches!(device.hardware_info.power_source, PowerSource::Battery)
+        && device.matrix_room_id.is_some()
+}
+
+impl PresenceAwareDelivery {
+    /// Create a delivery planner using `presence` for the bound rooms
+    pub fn new(presence: Arc<dyn PresenceSource>, config: DeliveryConfig) -> Self {
+        PresenceAwareDelivery {
+            presence,
+            config,
+            pending: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Delivery mode for `message` to `device`
+    pub async fn mode(&self, device: &DeviceConfig, message: &IoTMessage) -> DeliveryMode {
+        match &device.matrix_room_id {
+            Some(room_id) if may_defer(device, message) && self.presence.all_members_offline(room_id).await => {
+                DeliveryMode::Queued
+            }
+            _ => DeliveryMode::Immediate,
+        }
+    }
+
+    /// Submit `message` for `device` and return the messages to send now.
+    /// A message that is sent immediately takes the device's queued
+    /// commands along, as the radio is woken up anyway.
+    #[instrument(level = "debug", skip(self, device, message), fields(device_id = %device.device_id))]
+    pub async fn submit(&self, device: &DeviceConfig, message: IoTMessage) -> Vec<IoTMessage> {
+        let mode = self.mode(device, &message).await;
+        let mut pending = self.pending.write().await;
+
+        if mode == DeliveryMode::Immediate {
+            let mut batch = pending
+                .remove(&device.device_id)
+                .map(|batch| batch.messages)
+                .unwrap_or_default();
+            batch.push(message);
+            return batch;
+        }
+
+        let room_id = device.matrix_room_id.clone().unwrap_or_default();
+        let batch = pending.entry(device.device_id.clone()).or_insert_with(|| PendingBatch {
+            room_id,
+            since: Instant::now(),
+            messages: Vec::new(),
+        });
+        batch.messages.push(message);
+        debug!("🔋 Queued command for {} ({} pending)", device.device_id, batch.messages.len());
+
+        if batch.messages.len() >= self.config.max_batch_size {
+            info!("📦 Batch for {} is full, delivering", device.device_id);
+            return pending
+                .remove(&device.device_id)
+                .map(|batch| batch.messages)
+                .unwrap_or_default();
+        }
+        Vec::new()
+    }
+
+    /// Take the batches whose oldest command reached the batching window.
+    /// Meant to be polled periodically.
+    pub async fn take_due(&self) -> Vec<(String, Vec<IoTMessage>)> {
+        let window = self.config.batch_window;
+        self.take_where(|batch| batch.since.elapsed() >= window).await
+    }
+
+    /// Take the batches of devices bound to `room_id`; call this when a
+    /// member of the room comes online
+    pub async fn presence_changed(&self, room_id: &str) -> Vec<(String, Vec<IoTMessage>)> {
+        self.take_where(|batch| batch.room_id == room_id).await
+    }
+
+    /// Number of queued commands for `device_id`
+    pub async fn pending_count(&self, device_id: &str) -> usize {
+        self.pending
+            .read()
+            .await
+            .get(device_id)
+            .map_or(0, |batch| batch.messages.len())
+    }
+
+    async fn take_where(&self, due: impl Fn(&PendingBatch) -> bool) -> Vec<(String, Vec<IoTMessage>)> {
+        let mut pending = self.pending.write().await;
+        let devices: Vec<String> = pending
+            .iter()
+            .filter(|(_, batch)| due(batch))
+            .map(|(device_id, _)| device_id.clone())
+            .collect();
+
+        devices
+            .into_iter()
+            .filter_map(|device_id| {
+                let batch = pending.remove(&device_id)?;
+                Some((device_id, batch.messages))
+            })
+            .collect()
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ProtocolType, QualityOfService};
+    use chrono::Utc;
+    use std::collections::HashSet;
+    use uuid::Uuid;
+
+    struct OfflineRooms(HashSet<String>);
+
+    #[async_trait]
+    impl PresenceSource for OfflineRooms {
+        async fn all_members_offline(&self, room_id: &str) -> bool {
+            self.0.contains(room_id)
+        }
+    }
+
+    fn command(priority: MessagePriority) -> IoTMessage {
+        IoTMessage {
+            message_id: Uuid::new_v4(),
+            device_id: "valve001".to_string(),
+            timestamp: Utc::now(),
+            message_type: MessageType::Command,
+            payload: serde_json::json!({"open": true}),
+            qos: QualityOfService::AtLeastOnce,
+            topic: "actuators/valve001".to_string(),
+            priority,
+            metadata: HashMap::new(),
+            correlation_id: None,
+        }
+    }
+
+    fn delivery() -> PresenceAwareDelivery {
+        let offline = OfflineRooms(["!garden:matrixon.local".to_string()].into_iter().collect());
+        let config = DeliveryConfig { batch_window: Duration::from_secs(300), max_batch_size: 3 };
+        PresenceAwareDelivery::new(Arc::new(offline), config)
+    }
+
+    #[tokio::test]
+    async fn test_commands_are_batched_while_room_is_offline() {
+        let delivery = delivery();
+        let valve = DeviceConfig::new("valve001", ProtocolType::MQTT).with_matrix_room("!garden:matrixon.local");
+
+        assert!(delivery.submit(&valve, command(MessagePriority::Normal)).await.is_empty());
+        assert!(delivery.submit(&valve, command(MessagePriority::Low)).await.is_empty());
+        assert_eq!(delivery.pending_count("valve001").await, 2);
+
+        // A critical command wakes the device and takes the queue along
+        let sent = delivery.submit(&valve, command(MessagePriority::Critical)).await;
+        assert_eq!(sent.len(), 3);
+        assert_eq!(delivery.pending_count("valve001").await, 0);
+
+        for _ in 0..2 {
+            delivery.submit(&valve, command(MessagePriority::Normal)).await;
+        }
+        let batches = delivery.presence_changed("!garden:matrixon.local").await;
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].1.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mains_powered_and_online_rooms_are_immediate() {
+        let delivery = delivery();
+        let mut mains = DeviceConfig::new("valve001", ProtocolType::MQTT).with_matrix_room("!garden:matrixon.local");
+        mains.hardware_info.power_source = PowerSource::AC;
+        let online = DeviceConfig::new("valve002", ProtocolType::MQTT).with_matrix_room("!kitchen:matrixon.local");
+
+        assert_eq!(delivery.mode(&mains, &command(MessagePriority::Low)).await, DeliveryMode::Immediate);
+        assert_eq!(delivery.mode(&online, &command(MessagePriority::Low)).await, DeliveryMode::Immediate);
+        assert_eq!(delivery.submit(&online, command(MessagePriority::Low)).await.len(), 1);
+    }
+}
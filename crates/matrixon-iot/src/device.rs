@@ -10,7 +10,7 @@ use std::time::Duration;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
-use tracing::{info, instrument};
+use tracing::{debug, info, instrument};
 use uuid::Uuid;
 
 use crate::{IoTError, DeviceType, DeviceCapability, HardwareInfo, 
@@ -364,15 +364,24 @@ impl DeviceManager {
     /// Process device heartbeat
     #[instrument(level = "debug", skip(self))]
     pub async fn process_heartbeat(&self, device_id: &str) -> Result<(), IoTError> {
-        info!("💓 Processing heartbeat for device: {}", device_id);
+        debug!("💓 Processing heartbeat for device: {}", device_id);
         
         // Update last seen timestamp
-        let mut devices = self.devices.write().await;
-        if let Some(device) = devices.get_mut(device_id) {
+        let previous_status = {
+            let mut devices = self.devices.write().await;
+            let device = devices.get_mut(device_id).ok_or_else(|| IoTError::DeviceConnectionFailed {
+                device_id: device_id.to_string(),
+            })?;
             device.last_seen = Utc::now();
+            device.status.clone()
+        };
+        
+        // A heartbeat from an offline device means it is back
+        if matches!(previous_status, DeviceStatus::Offline | DeviceStatus::Connecting) {
+            self.update_device_status(device_id, DeviceStatus::Connected).await?;
         }
         
-        info!("✅ Heartbeat processed for device: {}", device_id);
+        debug!("✅ Heartbeat processed for device: {}", device_id);
         Ok(())
     }
 
@@ -410,6 +419,17 @@ impl DeviceManager {
         }
     }
 
+    /// Get the registration configuration of a device
+    #[instrument(level = "debug", skip(self))]
+    pub async fn get_device_config(&self, device_id: &str) -> Result<DeviceConfig, IoTError> {
+        let configs = self.device_configs.read().await;
+        configs.get(device_id)
+            .cloned()
+            .ok_or_else(|| IoTError::DeviceConnectionFailed {
+                device_id: device_id.to_string(),
+            })
+    }
+
     /// Get Matrix room ID for device
     #[instrument(level = "debug", skip(self))]
     pub async fn get_device_room(&self, device_id: &str) -> Result<Option<String>, IoTError> {
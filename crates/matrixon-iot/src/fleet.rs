@@ -0,0 +1,404 @@
+//! # Fleet Onboarding Module
+//!
+//! Bulk registration of IoT devices. A manifest listing devices (CSV with a
+//! header row, or a JSON array) is validated row by row, valid rows are
+//! registered in one batch and every row gets its own result. Each imported
+//! batch produces a provisioning bundle with the credentials of its devices,
+//! which can be downloaded once.
+//!
+//! CSV columns: `device_id`, `device_type`, `protocol` and optionally
+//! `name`, `auth_token`, `matrix_room_id`, `firmware_version`,
+//! `power_source`. Rows without an `auth_token` get a generated one.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use axum::{
+    body::Bytes,
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{info, instrument, warn};
+use uuid::Uuid;
+
+use crate::{DeviceConfig, DeviceManager, DeviceType, IoTError, IoTSecurityManager, PowerSource, ProtocolType};
+
+/// Largest number of devices accepted in one manifest
+pub const MAX_MANIFEST_ROWS: usize = 10_000;
+
+// =============================================================================
+// Manifest Parsing
+// =============================================================================
+
+/// Manifest encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestFormat {
+    Csv,
+    Json,
+}
+
+impl ManifestFormat {
+    /// Format announced by a `Content-Type` header; CSV unless it is JSON
+    pub fn from_content_type(content_type: Option<&str>) -> Self {
+        match content_type {
+            Some(content_type) if content_type.contains("json") => ManifestFormat::Json,
+            _ => ManifestFormat::Csv,
+        }
+    }
+}
+
+/// One device in a manifest
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ManifestRow {
+    pub device_id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub device_type: String,
+    pub protocol: String,
+    #[serde(default)]
+    pub auth_token: Option<String>,
+    #[serde(default)]
+    pub matrix_room_id: Option<String>,
+    #[serde(default)]
+    pub firmware_version: Option<String>,
+    #[serde(default)]
+    pub power_source: Option<String>,
+}
+
+/// Parse a manifest. Rows that cannot be decoded are returned as errors
+/// so they show up in the per-row results.
+pub fn parse_manifest(format: ManifestFormat, data: &[u8]) -> Result<Vec<Result<ManifestRow, String>>, IoTError> {
+    let rows: Vec<Result<ManifestRow, String>> = match format {
+        ManifestFormat::Csv => {
+            let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(data);
+            reader
+                .deserialize::<ManifestRow>()
+                .map(|row| row.map_err(|e| e.to_string()))
+                .collect()
+        }
+        ManifestFormat::Json => {
+            let values: Vec<serde_json::Value> =
+                serde_json::from_slice(data).map_err(|e| IoTError::ConfigurationError {
+                    parameter: format!("manifest is not a JSON array: {}", e),
+                })?;
+            values
+                .into_iter()
+                .map(|value| serde_json::from_value(value).map_err(|e| e.to_string()))
+                .collect()
+        }
+    };
+
+    if rows.len() > MAX_MANIFEST_ROWS {
+        return Err(IoTError::ConfigurationError {
+            parameter: format!("manifest has {} rows, at most {} are allowed", rows.len(), MAX_MANIFEST_ROWS),
+        });
+    }
+    Ok(rows)
+}
+
+fn parse_protocol(protocol: &str) -> Result<ProtocolType, String> {
+    Ok(match protocol.to_ascii_lowercase().as_str() {
+        "mqtt" => ProtocolType::MQTT,
+        "coap" => ProtocolType::CoAP,
+        "websocket" | "ws" => ProtocolType::WebSocket,
+        "modbus" => ProtocolType::Modbus,
+        "lorawan" => ProtocolType::LoRaWAN,
+        "http" => ProtocolType::HTTP,
+        "tcp" => ProtocolType::TCP,
+        "udp" => ProtocolType::UDP,
+        other => return Err(format!("unknown protocol '{}'", other)),
+    })
+}
+
+fn parse_device_type(device_type: &str) -> DeviceType {
+    match device_type.to_ascii_lowercase().as_str() {
+        "sensor" => DeviceType::Sensor,
+        "actuator" => DeviceType::Actuator,
+        "gateway" => DeviceType::Gateway,
+        "camera" => DeviceType::Camera,
+        "display" => DeviceType::Display,
+        "beacon" => DeviceType::Beacon,
+        "wearable" => DeviceType::Wearable,
+        "industrial" => DeviceType::Industrial,
+        "smarthome" | "smart_home" => DeviceType::SmartHome,
+        "vehicle" => DeviceType::Vehicle,
+        "agriculture" => DeviceType::Agriculture,
+        "healthcare" => DeviceType::Healthcare,
+        "environmental" => DeviceType::Environmental,
+        _ => DeviceType::Custom(device_type.to_string()),
+    }
+}
+
+fn parse_power_source(power_source: &str) -> PowerSource {
+    match power_source.to_ascii_lowercase().as_str() {
+        "battery" => PowerSource::Battery,
+        "ac" | "mains" => PowerSource::AC,
+        "solar" => PowerSource::Solar,
+        "usb" => PowerSource::USB,
+        "poe" => PowerSource::PoE,
+        _ => PowerSource::Custom(power_source.to_string()),
+    }
+}
+
+/// Check a row and turn it into a device configuration
+fn device_config(row: &ManifestRow) -> Result<DeviceConfig, String> {
+    if row.device_id.is_empty() {
+        return Err("device_id is empty".to_string());
+    }
+    if row.matrix_room_id.as_deref().is_some_and(|room_id| !room_id.starts_with('!')) {
+        return Err("matrix_room_id must be a room id".to_string());
+    }
+
+    let mut config = DeviceConfig::new(&row.device_id, parse_protocol(&row.protocol)?)
+        .with_device_type(parse_device_type(&row.device_type))
+        .with_authentication(&row.auth_token.clone().unwrap_or_else(generate_token));
+    if let Some(name) = row.name.as_deref().filter(|name| !name.is_empty()) {
+        config = config.with_name(name);
+    }
+    if let Some(room_id) = &row.matrix_room_id {
+        config = config.with_matrix_room(room_id);
+    }
+    if let Some(version) = &row.firmware_version {
+        config = config.with_firmware_version(version);
+    }
+    if let Some(power_source) = &row.power_source {
+        config.hardware_info.power_source = parse_power_source(power_source);
+    }
+    Ok(config)
+}
+
+fn generate_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+// =============================================================================
+// Import and Provisioning Bundles
+// =============================================================================
+
+/// Outcome of one manifest row
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RowResult {
+    /// 1-based row number in the manifest, not counting a CSV header
+    pub row: usize,
+    pub device_id: Option<String>,
+    pub registered: bool,
+    pub error: Option<String>,
+}
+
+/// Credentials a device needs to connect
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceProvisioning {
+    pub device_id: String,
+    pub protocol: ProtocolType,
+    pub auth_token: String,
+    pub matrix_room_id: Option<String>,
+}
+
+/// Provisioning data of all devices registered by one import
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProvisioningBundle {
+    pub batch_id: String,
+    pub created_at: DateTime<Utc>,
+    pub devices: Vec<DeviceProvisioning>,
+}
+
+/// Result of importing a manifest
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub batch_id: String,
+    pub registered: usize,
+    pub failed: usize,
+    pub results: Vec<RowResult>,
+}
+
+/// Registers manifests and keeps their bundles until they are downloaded
+pub struct FleetOnboarding {
+    device_manager: Arc<DeviceManager>,
+    security_manager: Option<Arc<IoTSecurityManager>>,
+    bundles: RwLock<HashMap<String, ProvisioningBundle>>,
+}
+
+impl std::fmt::Debug for FleetOnboarding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FleetOnboarding").finish_non_exhaustive()
+    }
+}
+
+impl FleetOnboarding {
+    pub fn new(device_manager: Arc<DeviceManager>) -> Self {
+        FleetOnboarding {
+            device_manager,
+            security_manager: None,
+            bundles: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Authenticate every device with `security_manager` before registering it
+    pub fn with_security(mut self, security_manager: Arc<IoTSecurityManager>) -> Self {
+        self.security_manager = Some(security_manager);
+        self
+    }
+
+    /// Validate and register the devices of a manifest
+    #[instrument(level = "debug", skip(self, data))]
+    pub async fn import(&self, format: ManifestFormat, data: &[u8]) -> Result<ImportReport, IoTError> {
+        let rows = parse_manifest(format, data)?;
+        let batch_id = Uuid::new_v4().simple().to_string();
+        info!("📦 Importing fleet manifest {} with {} devices", batch_id, rows.len());
+
+        let known: HashSet<String> = self
+            .device_manager
+            .list_devices()
+            .await
+            .into_iter()
+            .map(|device| device.device_id)
+            .collect();
+        let mut seen = HashSet::new();
+        let mut results = Vec::with_capacity(rows.len());
+        let mut devices = Vec::new();
+
+        for (index, row) in rows.into_iter().enumerate() {
+            let device_id = row.as_ref().ok().map(|row| row.device_id.clone());
+            let outcome = match row {
+                Err(e) => Err(e),
+                Ok(row) if known.contains(&row.device_id) => Err(format!("device {} is already registered", row.device_id)),
+                Ok(row) if !seen.insert(row.device_id.clone()) => Err(format!("device {} is listed twice", row.device_id)),
+                Ok(row) => match device_config(&row) {
+                    Ok(config) => self.register(config).await,
+                    Err(e) => Err(e),
+                },
+            };
+
+            match outcome {
+                Ok(provisioning) => {
+                    devices.push(provisioning);
+                    results.push(RowResult { row: index + 1, device_id, registered: true, error: None });
+                }
+                Err(error) => {
+                    results.push(RowResult { row: index + 1, device_id, registered: false, error: Some(error) });
+                }
+            }
+        }
+
+        let registered = devices.len();
+        let failed = results.len() - registered;
+        if failed > 0 {
+            warn!("⚠️ Fleet manifest {}: {} of {} rows failed", batch_id, failed, results.len());
+        }
+        if registered > 0 {
+            let bundle = ProvisioningBundle { batch_id: batch_id.clone(), created_at: Utc::now(), devices };
+            self.bundles.write().await.insert(batch_id.clone(), bundle);
+        }
+        info!("✅ Fleet manifest {}: {} devices registered", batch_id, registered);
+
+        Ok(ImportReport { batch_id, registered, failed, results })
+    }
+
+    async fn register(&self, config: DeviceConfig) -> Result<DeviceProvisioning, String> {
+        let provisioning = DeviceProvisioning {
+            device_id: config.device_id.clone(),
+            protocol: config.protocol.clone(),
+            auth_token: config.auth_token.clone().unwrap_or_default(),
+            matrix_room_id: config.matrix_room_id.clone(),
+        };
+        if let Some(security_manager) = &self.security_manager {
+            security_manager.authenticate_device(&config).await.map_err(|e| e.to_string())?;
+        }
+        self.device_manager.register_device(config).await.map_err(|e| e.to_string())?;
+        Ok(provisioning)
+    }
+
+    /// Hand out the provisioning bundle of a batch. Bundles hold
+    /// credentials, so each can only be taken once.
+    pub async fn take_bundle(&self, batch_id: &str) -> Option<ProvisioningBundle> {
+        self.bundles.write().await.remove(batch_id)
+    }
+}
+
+// =============================================================================
+// HTTP API
+// =============================================================================
+
+/// Routes of the onboarding API, to be nested below an authenticated
+/// admin prefix:
+/// - `POST /fleet/import` with a CSV or JSON manifest (by `Content-Type`)
+/// - `GET /fleet/bundles/{batch_id}` to download a provisioning bundle
+pub fn router(onboarding: Arc<FleetOnboarding>) -> Router {
+    Router::new()
+        .route("/fleet/import", post(import_route))
+        .route("/fleet/bundles/:batch_id", get(bundle_route))
+        .with_state(onboarding)
+}
+
+async fn import_route(State(onboarding): State<Arc<FleetOnboarding>>, headers: HeaderMap, body: Bytes) -> Response {
+    let format = ManifestFormat::from_content_type(headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()));
+    match onboarding.import(format, &body).await {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => (StatusCode::BAD_REQUEST, Json(serde_json::json!({ "error": e.to_string() }))).into_response(),
+    }
+}
+
+async fn bundle_route(State(onboarding): State<Arc<FleetOnboarding>>, Path(batch_id): Path<String>) -> Response {
+    match onboarding.take_bundle(&batch_id).await {
+        Some(bundle) => (
+            [(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"provisioning-{}.json\"", batch_id),
+            )],
+            Json(bundle),
+        )
+            .into_response(),
+        None => (StatusCode::NOT_FOUND, Json(serde_json::json!({ "error": "unknown or already downloaded bundle" }))).into_response(),
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::IoTConfig;
+
+    #[tokio::test]
+    async fn test_csv_import_reports_each_row() {
+        let device_manager = Arc::new(DeviceManager::new(&IoTConfig::default()).await.unwrap());
+        device_manager.register_device(DeviceConfig::new("existing", ProtocolType::MQTT)).await.unwrap();
+        let onboarding = FleetOnboarding::new(Arc::clone(&device_manager));
+
+        let manifest = "\
+device_id,device_type,protocol,auth_token,power_source
+valve001,actuator,mqtt,secret1,battery
+valve002,actuator,coap,,ac
+valve001,actuator,mqtt,,
+existing,sensor,mqtt,,
+pump001,actuator,zigbee,,
+";
+        let report = onboarding.import(ManifestFormat::Csv, manifest.as_bytes()).await.unwrap();
+        assert_eq!((report.registered, report.failed), (2, 3));
+        assert!(report.results[2].error.as_deref().unwrap().contains("twice"));
+        assert!(report.results[3].error.as_deref().unwrap().contains("already registered"));
+        assert!(report.results[4].error.as_deref().unwrap().contains("unknown protocol"));
+        assert!(device_manager.get_device("valve002").await.is_ok());
+
+        let bundle = onboarding.take_bundle(&report.batch_id).await.unwrap();
+        assert_eq!(bundle.devices[0].auth_token, "secret1");
+        assert_eq!(bundle.devices[1].auth_token.len(), 64);
+        assert!(onboarding.take_bundle(&report.batch_id).await.is_none());
+    }
+
+    #[test]
+    fn test_json_manifest() {
+        let manifest = r#"[{"device_id": "cam1", "device_type": "camera", "protocol": "http"}, {"device_id": "x"}]"#;
+        let rows = parse_manifest(ManifestFormat::Json, manifest.as_bytes()).unwrap();
+        assert_eq!(rows[0].as_ref().unwrap().device_id, "cam1");
+        assert!(rows[1].is_err());
+    }
+}
@@ -0,0 +1,202 @@
+//! # Heartbeat Monitoring Module
+//!
+//! Offline detection for IoT devices. Every heartbeat refreshes a device's
+//! `last_seen`; a connected device that misses a configurable number of
+//! heartbeat intervals is moved to `Offline`. Each transition raises an
+//! `Alert` message on the IoT message pipeline and the number of devices
+//! that timed out is reported to matrixon-monitor as `iot_devices_offline`.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use matrixon_monitor::{alert::AlertManager, config::AlertCondition};
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, instrument, warn};
+use uuid::Uuid;
+
+use crate::{DeviceManager, DeviceStatus, IoTError, IoTMessage, MessagePriority, MessageType, QualityOfService};
+
+/// Number of missed heartbeat intervals after which a device is offline
+pub const DEFAULT_MISSED_HEARTBEATS: u32 = 3;
+
+/// Watches device heartbeats and marks silent devices offline
+pub struct HeartbeatMonitor {
+    device_manager: Arc<DeviceManager>,
+    interval: Duration,
+    missed_before_offline: u32,
+    alerts: mpsc::UnboundedSender<IoTMessage>,
+    alert_manager: Option<Arc<AlertManager>>,
+    /// Devices this monitor moved offline and that have not come back
+    timed_out: RwLock<HashSet<String>>,
+}
+
+impl std::fmt::Debug for HeartbeatMonitor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HeartbeatMonitor")
+            .field("interval", &self.interval)
+            .field("missed_before_offline", &self.missed_before_offline)
+            .finish()
+    }
+}
+
+impl HeartbeatMonitor {
+    /// Create a monitor sending offline alerts to `alerts`
+    pub fn new(
+        device_manager: Arc<DeviceManager>,
+        interval: Duration,
+        missed_before_offline: u32,
+        alerts: mpsc::UnboundedSender<IoTMessage>,
+    ) -> Self {
+        HeartbeatMonitor {
+            device_manager,
+            interval,
+            missed_before_offline: missed_before_offline.max(1),
+            alerts,
+            alert_manager: None,
+            timed_out: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// Also report offline devices to matrixon-monitor
+    pub fn with_alert_manager(mut self, alert_manager: Arc<AlertManager>) -> Self {
+        self.alert_manager = Some(alert_manager);
+        self
+    }
+
+    /// Longest silence tolerated before a device is considered offline
+    pub fn timeout(&self) -> Duration {
+        self.interval * self.missed_before_offline
+    }
+
+    /// Mark devices whose last heartbeat is older than the timeout at `now`
+    /// as offline and raise alerts for them. Returns the affected devices.
+    #[instrument(level = "debug", skip(self))]
+    pub async fn check(&self, now: DateTime<Utc>) -> Result<Vec<String>, IoTError> {
+        let timeout = chrono::Duration::from_std(self.timeout()).unwrap_or(chrono::Duration::MAX);
+        let devices = self.device_manager.list_devices().await;
+        let statuses: HashMap<&str, &DeviceStatus> =
+            devices.iter().map(|device| (device.device_id.as_str(), &device.status)).collect();
+
+        // Devices that reported again are no longer timed out
+        self.timed_out
+            .write()
+            .await
+            .retain(|device_id| matches!(statuses.get(device_id.as_str()), Some(DeviceStatus::Offline)));
+
+        let mut went_offline = Vec::new();
+        for device in &devices {
+            let silent_for = now - device.last_seen;
+            if !matches!(device.status, DeviceStatus::Connected | DeviceStatus::Connecting) || silent_for <= timeout {
+                continue;
+            }
+
+            warn!("📴 Device {} missed its heartbeats, last seen {}", device.device_id, device.last_seen);
+            self.device_manager
+                .update_device_status(&device.device_id, DeviceStatus::Offline)
+                .await?;
+            self.timed_out.write().await.insert(device.device_id.clone());
+
+            let missed = silent_for.num_milliseconds() / (self.interval.as_millis().max(1) as i64);
+            let alert = offline_alert(&device.device_id, device.last_seen, missed, now);
+            if self.alerts.send(alert).is_err() {
+                debug!("IoT message pipeline is closed, offline alert for {} dropped", device.device_id);
+            }
+            went_offline.push(device.device_id.clone());
+        }
+
+        if let Some(alert_manager) = &self.alert_manager {
+            let offline = self.timed_out.read().await.len() as f64;
+            if let Err(e) = alert_manager.report_metric(AlertCondition::IoTDevicesOffline.as_str(), offline).await {
+                error!("❌ Failed to report offline devices to the monitor: {}", e);
+            }
+        }
+        Ok(went_offline)
+    }
+
+    /// Run `check` once per heartbeat interval
+    pub fn start(self: &Arc<Self>) -> JoinHandle<()> {
+        let monitor = Arc::clone(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(monitor.interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = monitor.check(Utc::now()).await {
+                    error!("❌ Heartbeat check failed: {}", e);
+                }
+            }
+        })
+    }
+}
+
+/// Alert message announcing that a device went offline
+fn offline_alert(device_id: &str, last_seen: DateTime<Utc>, missed: i64, now: DateTime<Utc>) -> IoTMessage {
+    IoTMessage {
+        message_id: Uuid::new_v4(),
+        device_id: device_id.to_string(),
+        timestamp: now,
+        message_type: MessageType::Alert,
+        payload: serde_json::json!({
+            "alert": "device_offline",
+            "last_seen": last_seen,
+            "missed_heartbeats": missed,
+        }),
+        qos: QualityOfService::AtLeastOnce,
+        topic: format!("devices/{}/alerts", device_id),
+        priority: MessagePriority::High,
+        metadata: HashMap::new(),
+        correlation_id: None,
+    }
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceConfig, IoTConfig, ProtocolType};
+    use matrixon_monitor::config::{AlertRule, AlertSeverity};
+
+    #[tokio::test]
+    async fn test_missed_heartbeats_mark_device_offline() {
+        let device_manager = Arc::new(DeviceManager::new(&IoTConfig::default()).await.unwrap());
+        device_manager.register_device(DeviceConfig::new("sensor001", ProtocolType::MQTT)).await.unwrap();
+        device_manager.process_heartbeat("sensor001").await.unwrap();
+        assert_eq!(device_manager.get_device("sensor001").await.unwrap().status, DeviceStatus::Connected);
+
+        let alert_manager = Arc::new(AlertManager::new().await.unwrap());
+        alert_manager
+            .add_rule(AlertRule {
+                name: "IoT devices offline".to_string(),
+                condition: AlertCondition::IoTDevicesOffline,
+                threshold: 1.0,
+                duration_minutes: 0,
+                severity: AlertSeverity::High,
+                channels: Vec::new(),
+                enabled: true,
+            })
+            .await;
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let monitor = HeartbeatMonitor::new(Arc::clone(&device_manager), Duration::from_secs(60), 3, sender)
+            .with_alert_manager(Arc::clone(&alert_manager));
+
+        // Two missed heartbeats are tolerated
+        assert!(monitor.check(Utc::now() + chrono::Duration::seconds(150)).await.unwrap().is_empty());
+
+        let offline = monitor.check(Utc::now() + chrono::Duration::seconds(200)).await.unwrap();
+        assert_eq!(offline, vec!["sensor001"]);
+        assert_eq!(device_manager.get_device("sensor001").await.unwrap().status, DeviceStatus::Offline);
+        let alert = receiver.try_recv().unwrap();
+        assert_eq!(alert.message_type, MessageType::Alert);
+        assert_eq!(alert_manager.get_active_alerts().await.unwrap().len(), 1);
+
+        // The next heartbeat brings the device back
+        device_manager.process_heartbeat("sensor001").await.unwrap();
+        assert_eq!(device_manager.get_device("sensor001").await.unwrap().status, DeviceStatus::Connected);
+    }
+}
@@ -71,12 +71,15 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::sync::{mpsc, RwLock};
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error, info, instrument, warn};
 use uuid::Uuid;
+use matrixon_core::health::{ComponentHealth, HealthProbe};
+use matrixon_monitor::alert::AlertManager;
 
 // =============================================================================
 // Re-export important types from submodules
@@ -89,13 +92,19 @@ pub mod analytics;
 pub mod security;
 pub mod gateway;
 pub mod edge;
+pub mod delivery;
+pub mod heartbeat;
+pub mod fleet;
 
 pub use device::{DeviceManager, DeviceConfig, DeviceStatus, DeviceInfo};
 pub use protocol::{ProtocolHandler, MessageProcessor};
-pub use analytics::{DataAnalyzer, TimeSeriesData, AnalyticsEngine};
-pub use security::{IoTSecurityManager, DeviceAuthentication, TLSConfig};
+pub use analytics::{DataAnalyzer, TimeSeriesData, AnalyticsEngine, Aggregate, Resolution, RetentionConfig};
+pub use security::{IoTSecurityManager, DeviceAuthentication, TLSConfig, TlsListenerConfig, CipherPolicy};
 pub use gateway::{IoTGateway, GatewayConfig};
 pub use edge::{EdgeProcessor, EdgeConfig};
+pub use delivery::{PresenceAwareDelivery, PresenceSource, DeliveryConfig, DeliveryMode};
+pub use heartbeat::HeartbeatMonitor;
+pub use fleet::{FleetOnboarding, ImportReport, ManifestFormat, ProvisioningBundle};
 
 // =============================================================================
 // Core IoT Types
@@ -408,6 +417,15 @@ pub struct IoTManager {
     
     /// Edge processing nodes
     edge_nodes: Arc<RwLock<HashMap<String, Arc<EdgeProcessor>>>>,
+    
+    /// Bulk onboarding of devices from manifests
+    fleet: Arc<FleetOnboarding>,
+    
+    /// Presence-aware batching of commands to battery-powered devices
+    delivery: Option<Arc<PresenceAwareDelivery>>,
+    
+    /// Monitoring system receiving offline device alerts
+    alert_manager: Option<Arc<AlertManager>>,
 }
 
 impl std::fmt::Debug for IoTManager {
@@ -431,6 +449,9 @@ pub struct IoTConfig {
     /// Device heartbeat interval
     pub heartbeat_interval: Duration,
     
+    /// Missed heartbeat intervals after which a device is marked offline
+    pub missed_heartbeats_before_offline: u32,
+    
     /// Enable analytics processing
     pub enable_analytics: bool,
     
@@ -451,6 +472,12 @@ pub struct IoTConfig {
     
     /// Performance tuning parameters
     pub performance: PerformanceConfig,
+    
+    /// Telemetry retention and downsampling
+    pub retention: RetentionConfig,
+    
+    /// TLS-secured protocol listeners
+    pub tls_listeners: Vec<TlsListenerConfig>,
 }
 
 /// MQTT Broker configuration
@@ -568,6 +595,11 @@ impl IoTManager {
         let analytics_engine = Arc::new(AnalyticsEngine::new(&config).await?);
         let security_manager = Arc::new(IoTSecurityManager::new(&config).await?);
         
+        let mut fleet = FleetOnboarding::new(Arc::clone(&device_manager));
+        if config.enable_security {
+            fleet = fleet.with_security(Arc::clone(&security_manager));
+        }
+        
         let (message_sender, message_receiver) = mpsc::unbounded_channel();
         
         Ok(IoTManager {
@@ -582,9 +614,38 @@ impl IoTManager {
             stats: Arc::new(RwLock::new(IoTStatistics::default())),
             gateways: Arc::new(RwLock::new(HashMap::new())),
             edge_nodes: Arc::new(RwLock::new(HashMap::new())),
+            fleet: Arc::new(fleet),
+            delivery: None,
+            alert_manager: None,
         })
     }
     
+    /// Batch non-critical commands to battery-powered devices while all
+    /// members of their Matrix room are offline, according to `presence`
+    pub fn with_presence_hints(mut self, presence: Arc<dyn PresenceSource>, config: DeliveryConfig) -> Self {
+        self.delivery = Some(Arc::new(PresenceAwareDelivery::new(presence, config)));
+        self
+    }
+    
+    /// Report devices that stop sending heartbeats to `alert_manager`
+    pub fn with_alert_manager(mut self, alert_manager: Arc<AlertManager>) -> Self {
+        self.alert_manager = Some(alert_manager);
+        self
+    }
+    
+    /// Probe for the server's readiness endpoint, see [`IoTHealthProbe`]
+    pub fn health_probe(&self) -> Arc<dyn HealthProbe> {
+        Arc::new(IoTHealthProbe {
+            device_manager: Arc::clone(&self.device_manager),
+            message_sender: self.message_sender.clone(),
+        })
+    }
+    
+    /// Record a heartbeat of a device
+    pub async fn process_heartbeat(&self, device_id: &str) -> std::result::Result<(), IoTError> {
+        self.device_manager.process_heartbeat(device_id).await
+    }
+    
     /// Register a new IoT device
     #[instrument(level = "debug", skip(self))]
     pub async fn register_device(&mut self, device_config: DeviceConfig) -> std::result::Result<String, IoTError> {
@@ -602,6 +663,16 @@ impl IoTManager {
         Ok(device_id)
     }
     
+    /// Register all devices of a CSV or JSON manifest, see [`crate::fleet`]
+    pub async fn import_fleet(&self, format: ManifestFormat, data: &[u8]) -> std::result::Result<ImportReport, IoTError> {
+        self.fleet.import(format, data).await
+    }
+    
+    /// Fleet onboarding service, e.g. to mount [`fleet::router`]
+    pub fn fleet(&self) -> Arc<FleetOnboarding> {
+        Arc::clone(&self.fleet)
+    }
+    
     /// Start IoT message processing
     #[instrument(level = "debug", skip(self))]
     pub async fn start_processing(&mut self) -> std::result::Result<(), IoTError> {
@@ -611,6 +682,7 @@ impl IoTManager {
         // Start analytics if enabled
         if self.config.enable_analytics {
             info!("📊 Starting analytics engine");
+            self.analytics_engine.start_retention_job();
         }
         
         // Start security monitoring if enabled
@@ -621,6 +693,18 @@ impl IoTManager {
         // Initialize protocol handlers
         info!("🔌 Initializing protocol handlers");
         
+        // Watch device heartbeats
+        let mut heartbeat_monitor = HeartbeatMonitor::new(
+            Arc::clone(&self.device_manager),
+            self.config.heartbeat_interval,
+            self.config.missed_heartbeats_before_offline,
+            self.message_sender.clone(),
+        );
+        if let Some(alert_manager) = &self.alert_manager {
+            heartbeat_monitor = heartbeat_monitor.with_alert_manager(Arc::clone(alert_manager));
+        }
+        Arc::new(heartbeat_monitor).start();
+        
         // Start message processing loop
         let receiver = {
             let mut receiver_guard = self.message_receiver.write().await;
@@ -628,10 +712,19 @@ impl IoTManager {
         };
         
         if let Some(mut receiver) = receiver {
+            let analytics_engine = self.config.enable_analytics.then(|| Arc::clone(&self.analytics_engine));
             tokio::spawn(async move {
                 while let Some(message) = receiver.recv().await {
                     // Process message
                     debug!("📦 Processing IoT message: {}", message.message_id);
+                    if message.message_type == MessageType::Alert {
+                        warn!("🚨 IoT alert from {}: {}", message.device_id, message.payload);
+                    }
+                    if let Some(analytics_engine) = &analytics_engine {
+                        if let Err(e) = analytics_engine.process_message(&message).await {
+                            error!("❌ Analytics failed for message {}: {}", message.message_id, e);
+                        }
+                    }
                 }
             });
         }
@@ -655,13 +748,60 @@ impl IoTManager {
                 message: "Protocol handler not found".to_string(),
             })?;
         
+        // Commands may be held back while nobody in the device's room is online
+        let messages = match &self.delivery {
+            Some(delivery) => {
+                let device_config = self.device_manager.get_device_config(device_id).await?;
+                delivery.submit(&device_config, message).await
+            }
+            None => vec![message],
+        };
+        if messages.is_empty() {
+            debug!("🔋 Message to device {} queued for batched delivery", device_id);
+            return Ok(());
+        }
+        
         // Send message via protocol handler
-        protocol_handler.send_message(&message).await?;
+        for message in &messages {
+            protocol_handler.send_message(message).await?;
+        }
         
-        info!("✅ Message sent successfully to device: {}", device_id);
+        info!("✅ {} message(s) sent successfully to device: {}", messages.len(), device_id);
         Ok(())
     }
     
+    /// Send the queued command batches that reached their batching window,
+    /// or only those of devices in `room_id` after a member came online
+    #[instrument(level = "debug", skip(self))]
+    pub async fn flush_queued_commands(&self, room_id: Option<&str>) -> std::result::Result<usize, IoTError> {
+        let Some(delivery) = &self.delivery else {
+            return Ok(0);
+        };
+        let batches = match room_id {
+            Some(room_id) => delivery.presence_changed(room_id).await,
+            None => delivery.take_due().await,
+        };
+        
+        let mut sent = 0;
+        for (device_id, messages) in batches {
+            let device = self.device_manager.get_device(&device_id).await?;
+            let protocol_handler = self.protocol_handlers.get(&device.protocol)
+                .ok_or_else(|| IoTError::ProtocolError {
+                    protocol: format!("{:?}", device.protocol),
+                    message: "Protocol handler not found".to_string(),
+                })?;
+            for message in &messages {
+                protocol_handler.send_message(message).await?;
+            }
+            sent += messages.len();
+        }
+        
+        if sent > 0 {
+            info!("📦 Delivered {} queued command(s)", sent);
+        }
+        Ok(sent)
+    }
+    
     /// Get IoT manager statistics
     pub async fn get_statistics(&self) -> IoTStatistics {
         self.stats.read().await.clone()
@@ -725,6 +865,31 @@ impl IoTManager {
     }
 }
 
+/// Health of the IoT subsystem: failed once its message pipeline stopped,
+/// degraded while more than half of the registered devices are offline
+pub struct IoTHealthProbe {
+    device_manager: Arc<DeviceManager>,
+    message_sender: mpsc::UnboundedSender<IoTMessage>,
+}
+
+#[async_trait]
+impl HealthProbe for IoTHealthProbe {
+    async fn check(&self) -> ComponentHealth {
+        if self.message_sender.is_closed() {
+            return ComponentHealth::failed("The IoT message pipeline has stopped");
+        }
+        let devices = self.device_manager.list_devices().await;
+        let offline = devices
+            .iter()
+            .filter(|device| matches!(device.status, DeviceStatus::Offline | DeviceStatus::Error(_)))
+            .count();
+        if offline * 2 > devices.len() {
+            return ComponentHealth::degraded(format!("{} of {} devices are offline", offline, devices.len()));
+        }
+        ComponentHealth::healthy()
+    }
+}
+
 // =============================================================================
 // Default Implementations
 // =============================================================================
@@ -735,6 +900,7 @@ impl Default for IoTConfig {
             max_devices: 100_000,
             message_timeout: Duration::from_secs(30),
             heartbeat_interval: Duration::from_secs(60),
+            missed_heartbeats_before_offline: heartbeat::DEFAULT_MISSED_HEARTBEATS,
             enable_analytics: true,
             enable_security: true,
             mqtt_config: None,
@@ -742,10 +908,16 @@ impl Default for IoTConfig {
             redis_url: Some("redis://localhost:6379".to_string()),
             timeseries_config: None,
             performance: PerformanceConfig::default(),
+            retention: RetentionConfig::default(),
+            tls_listeners: Vec::new(),
         }
     }
 }
 
+impl matrixon_core::config::ConfigSection for IoTConfig {
+    const SECTION: &'static str = "iot";
+}
+
 impl Default for PerformanceConfig {
     fn default() -> Self {
         PerformanceConfig {
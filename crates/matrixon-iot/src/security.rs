@@ -1,6 +1,14 @@
 //! # Security Module
 //!
 //! IoT device security, authentication, and encryption management.
+//!
+//! Protocol listeners are secured through [`TlsListenerConfig`]: one per
+//! protocol (MQTT over TLS, CoAP over DTLS, WSS), each with a default
+//! certificate, optional per-hostname certificates chosen by SNI and a
+//! cipher policy. With the `security` feature, the rustls configuration of
+//! each listener is built with [`TlsListenerConfig::server_config`], and
+//! the security manager does so at startup so bad certificates or cipher
+//! settings are reported before any listener is needed.
 
 use std::collections::HashMap;
 use std::time::Duration;
@@ -8,12 +16,13 @@ use std::time::Duration;
 use serde::{Deserialize, Serialize};
 use tracing::{info, instrument};
 
-use crate::{IoTError, IoTConfig, DeviceConfig};
+use crate::{IoTError, IoTConfig, DeviceConfig, ProtocolType};
 
 /// IoT security manager
 pub struct IoTSecurityManager {
     auth_tokens: HashMap<String, String>,
     tls_config: Option<TLSConfig>,
+    tls_listeners: Vec<TlsListenerConfig>,
 }
 
 /// Device authentication information
@@ -33,17 +42,237 @@ pub struct TLSConfig {
     pub verify_peer: bool,
 }
 
+/// Protocol versions and cipher suites a listener accepts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CipherPolicy {
+    /// TLS 1.3 only
+    Modern,
+    /// TLS 1.2 and 1.3, forward-secret AEAD suites only
+    #[default]
+    Intermediate,
+}
+
+/// TLS settings of one protocol listener
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsListenerConfig {
+    pub protocol: ProtocolType,
+    pub bind_address: String,
+    pub port: u16,
+    /// Certificate used when the client sends no or an unknown SNI name
+    pub certificate: TLSConfig,
+    /// Certificates by hostname, `*.example.com` matches one label
+    pub sni_certificates: HashMap<String, TLSConfig>,
+    pub cipher_policy: CipherPolicy,
+    /// Further restrict the suites of the policy, by IANA name such as
+    /// `TLS13_AES_256_GCM_SHA384`
+    pub cipher_suites: Option<Vec<String>>,
+}
+
+impl TlsListenerConfig {
+    /// Listener for `protocol` on its standard secure port
+    pub fn new(protocol: ProtocolType, certificate: TLSConfig) -> Result<Self, IoTError> {
+        let port = Self::default_port(&protocol).ok_or_else(|| IoTError::ConfigurationError {
+            parameter: format!("no TLS listener support for {:?}", protocol),
+        })?;
+        Ok(TlsListenerConfig {
+            protocol,
+            bind_address: "0.0.0.0".to_string(),
+            port,
+            certificate,
+            sni_certificates: HashMap::new(),
+            cipher_policy: CipherPolicy::default(),
+            cipher_suites: None,
+        })
+    }
+
+    /// Standard secure port: 8883 for MQTT, 5684 for CoAP over DTLS and
+    /// 8443 for WSS
+    pub fn default_port(protocol: &ProtocolType) -> Option<u16> {
+        match protocol {
+            ProtocolType::MQTT => Some(8883),
+            ProtocolType::CoAP => Some(5684),
+            ProtocolType::WebSocket => Some(8443),
+            _ => None,
+        }
+    }
+
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn with_sni_certificate(mut self, hostname: &str, certificate: TLSConfig) -> Self {
+        self.sni_certificates.insert(hostname.to_ascii_lowercase(), certificate);
+        self
+    }
+
+    pub fn with_cipher_policy(mut self, policy: CipherPolicy) -> Self {
+        self.cipher_policy = policy;
+        self
+    }
+
+    /// Whether the listener runs over UDP, i.e. needs DTLS
+    pub fn is_datagram(&self) -> bool {
+        self.protocol == ProtocolType::CoAP
+    }
+
+    /// ALPN protocol ids offered during the handshake
+    pub fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        match self.protocol {
+            ProtocolType::MQTT => vec![b"mqtt".to_vec()],
+            ProtocolType::WebSocket => vec![b"http/1.1".to_vec()],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Certificate for the SNI name a client asked for
+    pub fn certificate_for(&self, server_name: Option<&str>) -> &TLSConfig {
+        select_by_sni(&self.sni_certificates, server_name).unwrap_or(&self.certificate)
+    }
+}
+
+/// Entry of `by_name` for an SNI name: an exact match first, then a
+/// wildcard entry for its parent domain
+pub fn select_by_sni<'a, T>(by_name: &'a HashMap<String, T>, server_name: Option<&str>) -> Option<&'a T> {
+    let server_name = server_name?.to_ascii_lowercase();
+    by_name.get(&server_name).or_else(|| {
+        let (_, parent) = server_name.split_once('.')?;
+        by_name.get(&format!("*.{}", parent))
+    })
+}
+
+#[cfg(feature = "security")]
+mod tls {
+    use std::collections::HashMap;
+    use std::fs::File;
+    use std::io::BufReader;
+    use std::sync::Arc;
+
+    use rustls::server::{AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert};
+    use rustls::sign::CertifiedKey;
+    use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig, SupportedCipherSuite};
+
+    use super::{select_by_sni, CipherPolicy, TLSConfig, TlsListenerConfig};
+    use crate::IoTError;
+
+    fn config_error(parameter: String) -> IoTError {
+        IoTError::ConfigurationError { parameter }
+    }
+
+    fn load_certified_key(tls: &TLSConfig) -> Result<Arc<CertifiedKey>, IoTError> {
+        let open = |path: &str| File::open(path).map(BufReader::new).map_err(|e| config_error(format!("{}: {}", path, e)));
+        let certs = rustls_pemfile::certs(&mut open(&tls.cert_path)?)
+            .map_err(|e| config_error(format!("{}: {}", tls.cert_path, e)))?;
+        if certs.is_empty() {
+            return Err(config_error(format!("{}: no certificates", tls.cert_path)));
+        }
+        let key = rustls_pemfile::read_all(&mut open(&tls.key_path)?)
+            .map_err(|e| config_error(format!("{}: {}", tls.key_path, e)))?
+            .into_iter()
+            .find_map(|item| match item {
+                rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .ok_or_else(|| config_error(format!("{}: no private key", tls.key_path)))?;
+        let signing_key = rustls::sign::any_supported_type(&PrivateKey(key))
+            .map_err(|e| config_error(format!("{}: {}", tls.key_path, e)))?;
+        Ok(Arc::new(CertifiedKey::new(certs.into_iter().map(Certificate).collect(), signing_key)))
+    }
+
+    /// Picks the certificate by SNI name, falling back to the default one
+    struct SniResolver {
+        by_name: HashMap<String, Arc<CertifiedKey>>,
+        default: Arc<CertifiedKey>,
+    }
+
+    impl ResolvesServerCert for SniResolver {
+        fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+            Some(select_by_sni(&self.by_name, client_hello.server_name()).unwrap_or(&self.default).clone())
+        }
+    }
+
+    fn cipher_suites(config: &TlsListenerConfig) -> Result<Vec<SupportedCipherSuite>, IoTError> {
+        let suites: Vec<SupportedCipherSuite> = rustls::ALL_CIPHER_SUITES
+            .iter()
+            .copied()
+            .filter(|suite| config.cipher_policy == CipherPolicy::Intermediate || matches!(suite, SupportedCipherSuite::Tls13(_)))
+            .filter(|suite| {
+                config.cipher_suites.as_ref().is_none_or(|allowed| allowed.contains(&format!("{:?}", suite.suite())))
+            })
+            .collect();
+        if suites.is_empty() {
+            return Err(config_error(format!("no cipher suite of {:?} is allowed for {:?}", config.cipher_policy, config.protocol)));
+        }
+        Ok(suites)
+    }
+
+    impl TlsListenerConfig {
+        /// Build the rustls configuration of this listener
+        pub fn server_config(&self) -> Result<Arc<ServerConfig>, IoTError> {
+            let versions: &[&rustls::SupportedProtocolVersion] = match self.cipher_policy {
+                CipherPolicy::Modern => &[&rustls::version::TLS13],
+                CipherPolicy::Intermediate => &[&rustls::version::TLS13, &rustls::version::TLS12],
+            };
+            let builder = ServerConfig::builder()
+                .with_cipher_suites(&cipher_suites(self)?)
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(versions)
+                .map_err(|e| config_error(format!("TLS settings for {:?}: {}", self.protocol, e)))?;
+
+            let builder = match (&self.certificate.ca_path, self.certificate.verify_peer) {
+                (Some(ca_path), true) => {
+                    let file = File::open(ca_path).map_err(|e| config_error(format!("{}: {}", ca_path, e)))?;
+                    let mut roots = RootCertStore::empty();
+                    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+                        .map_err(|e| config_error(format!("{}: {}", ca_path, e)))?;
+                    roots.add_parsable_certificates(&certs);
+                    builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+                }
+                (None, true) => return Err(config_error("verify_peer requires a ca_path".to_string())),
+                (_, false) => builder.with_no_client_auth(),
+            };
+
+            let by_name = self
+                .sni_certificates
+                .iter()
+                .map(|(name, tls)| Ok((name.clone(), load_certified_key(tls)?)))
+                .collect::<Result<_, IoTError>>()?;
+            let resolver = SniResolver { by_name, default: load_certified_key(&self.certificate)? };
+
+            let mut server_config = builder.with_cert_resolver(Arc::new(resolver));
+            server_config.alpn_protocols = self.alpn_protocols();
+            Ok(Arc::new(server_config))
+        }
+    }
+}
+
 impl IoTSecurityManager {
     #[instrument]
     pub async fn new(config: &IoTConfig) -> Result<Self, IoTError> {
         info!("🔧 Initializing IoT Security Manager");
         
+        for listener in &config.tls_listeners {
+            if TlsListenerConfig::default_port(&listener.protocol).is_none() {
+                return Err(IoTError::ConfigurationError {
+                    parameter: format!("no TLS listener support for {:?}", listener.protocol),
+                });
+            }
+            #[cfg(feature = "security")]
+            listener.server_config()?;
+        }
+        
         Ok(IoTSecurityManager {
             auth_tokens: HashMap::new(),
             tls_config: None,
+            tls_listeners: config.tls_listeners.clone(),
         })
     }
     
+    /// TLS settings of the protocol listeners
+    pub fn tls_listener(&self, protocol: &ProtocolType) -> Option<&TlsListenerConfig> {
+        self.tls_listeners.iter().find(|listener| &listener.protocol == protocol)
+    }
+    
     pub async fn authenticate_device(&self, config: &DeviceConfig) -> Result<(), IoTError> {
         // Implement device authentication
         Ok(())
@@ -53,3 +282,38 @@ impl IoTSecurityManager {
         format!("token_{}", device_id)
     }
 }
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tls(name: &str) -> TLSConfig {
+        TLSConfig {
+            cert_path: format!("{}.crt", name),
+            key_path: format!("{}.key", name),
+            ca_path: None,
+            verify_peer: false,
+        }
+    }
+
+    #[test]
+    fn test_listener_defaults_and_sni_selection() {
+        let listener = TlsListenerConfig::new(ProtocolType::MQTT, tls("default"))
+            .unwrap()
+            .with_sni_certificate("Broker.example.com", tls("broker"))
+            .with_sni_certificate("*.devices.example.com", tls("devices"));
+        assert_eq!(listener.port, 8883);
+        assert_eq!(listener.alpn_protocols(), vec![b"mqtt".to_vec()]);
+        assert_eq!(TlsListenerConfig::new(ProtocolType::CoAP, tls("default")).unwrap().port, 5684);
+        assert!(TlsListenerConfig::new(ProtocolType::Modbus, tls("default")).is_err());
+
+        assert_eq!(listener.certificate_for(Some("broker.example.com")).cert_path, "broker.crt");
+        assert_eq!(listener.certificate_for(Some("gw1.devices.example.com")).cert_path, "devices.crt");
+        assert_eq!(listener.certificate_for(Some("a.gw1.devices.example.com")).cert_path, "default.crt");
+        assert_eq!(listener.certificate_for(None).cert_path, "default.crt");
+    }
+}
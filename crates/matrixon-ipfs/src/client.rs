@@ -11,7 +11,9 @@ use crate::{
 };
 use std::time::Duration;
 use tokio_stream::StreamExt;
+use async_trait::async_trait;
 use ipfs_api::IpfsApi;
+use matrixon_core::health::{ComponentHealth, HealthProbe};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tracing::{debug, info};
@@ -128,6 +130,17 @@ impl IpfsClient {
     }
 }
 
+/// The IPFS node not answering fails IPFS storage
+#[async_trait]
+impl HealthProbe for IpfsClient {
+    async fn check(&self) -> ComponentHealth {
+        match self.api.version().await {
+            Ok(_) => ComponentHealth::healthy(),
+            Err(e) => ComponentHealth::failed(format!("The IPFS node is unreachable: {}", e)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
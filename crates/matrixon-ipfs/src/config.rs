@@ -188,6 +188,10 @@ impl Default for IpfsConfig {
     }
 }
 
+impl matrixon_core::config::ConfigSection for IpfsConfig {
+    const SECTION: &'static str = "ipfs";
+}
+
 impl Default for StorageConfig {
     fn default() -> Self {
         Self {
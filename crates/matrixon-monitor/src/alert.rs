@@ -9,14 +9,17 @@
 //! All code is documented in English, with detailed function documentation, error handling, and performance characteristics.
 
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use serde::{Serialize, Deserialize};
 use tracing::{info, instrument, warn};
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
 use tokio::sync::RwLock;
 
-use crate::config::{AlertRule, AlertCondition, NotificationChannel};
+use matrixon_email::{Mailer, Purpose};
+use serde_json::json;
+
+use crate::config::{AlertConfig, AlertRule, AlertCondition, NotificationChannel};
 use super::error::{Result, MonitorError};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -50,6 +53,10 @@ pub struct AlertManager {
     rules: Arc<RwLock<Vec<AlertRule>>>,
     alerts: Arc<RwLock<Vec<Alert>>>,
     metrics: Arc<RwLock<HashMap<String, f64>>>,
+    channels: Arc<RwLock<Vec<NotificationChannel>>>,
+    mailer: Option<Arc<Mailer>>,
+    /// Rules reported metrics currently breach; each breach alerts once
+    firing: Arc<RwLock<HashSet<String>>>,
 }
 
 impl AlertManager {
@@ -68,9 +75,39 @@ impl AlertManager {
             rules: Arc::new(RwLock::new(Vec::new())),
             alerts: Arc::new(RwLock::new(Vec::new())),
             metrics: Arc::new(RwLock::new(HashMap::new())),
+            channels: Arc::new(RwLock::new(Vec::new())),
+            mailer: None,
+            firing: Arc::new(RwLock::new(HashSet::new())),
         })
     }
 
+    /// Send the notifications of email channels through `mailer`
+    pub fn with_mailer(mut self, mailer: Arc<Mailer>) -> Self {
+        self.mailer = Some(mailer);
+        self
+    }
+
+    /// Register a notification channel that alert rules can name
+    pub async fn add_notification_channel(&self, channel: NotificationChannel) {
+        let mut channels = self.channels.write().await;
+        channels.retain(|c| c.name != channel.name);
+        channels.push(channel);
+    }
+
+    /// Register the notification channels and rules of `config`, unless
+    /// alerting is disabled there
+    pub async fn add_configured(&self, config: &AlertConfig) {
+        if !config.enabled {
+            return;
+        }
+        for channel in &config.channels {
+            self.add_notification_channel(channel.clone()).await;
+        }
+        for rule in &config.rules {
+            self.add_rule(rule.clone()).await;
+        }
+    }
+
     /// Start the alert manager
     ///
     /// Starts alert rule evaluation and notification tasks.
@@ -109,6 +146,59 @@ impl AlertManager {
         Ok(())
     }
 
+    /// Report the current value of a metric from another component
+    ///
+    /// Stores the value and raises an alert for every enabled rule on this
+    /// metric whose threshold it reaches. A rule alerts once when its
+    /// threshold is reached and not again until the value drops below it,
+    /// which resolves the alert.
+    ///
+    /// # Arguments
+    /// * `name` - Metric name, as given by `AlertCondition::as_str`
+    /// * `value` - Current metric value
+    ///
+    /// # Returns
+    /// * `Result<Vec<Alert>>` - The alerts raised
+    #[instrument(level = "debug", skip(self))]
+    pub async fn report_metric(&self, name: &str, value: f64) -> Result<Vec<Alert>> {
+        self.metrics.write().await.insert(name.to_string(), value);
+
+        let rules: Vec<AlertRule> = self.rules.read().await
+            .iter()
+            .filter(|rule| rule.enabled && rule.condition.as_str() == name)
+            .cloned()
+            .collect();
+
+        let mut firing = self.firing.write().await;
+        let mut raised = Vec::new();
+        for rule in rules {
+            if value < rule.threshold {
+                if firing.remove(&rule.name) {
+                    info!("✅ Alert {} resolved: {} = {}", rule.name, name, value);
+                    for alert in self.alerts.write().await.iter_mut().filter(|alert| alert.rule.name == rule.name) {
+                        alert.status = AlertStatus::Resolved;
+                    }
+                }
+                continue;
+            }
+            if !firing.insert(rule.name.clone()) {
+                continue;
+            }
+            let alert = Alert {
+                id: Uuid::new_v4(),
+                rule,
+                value,
+                timestamp: Utc::now(),
+                status: AlertStatus::Active,
+            };
+            warn!("🚨 Alert {}: {} = {}", alert.rule.name, name, value);
+            self.send_notification(&alert).await?;
+            self.store_alert(&alert).await?;
+            raised.push(alert);
+        }
+        Ok(raised)
+    }
+
     /// Check if an alert should be triggered
     fn should_trigger(&self, rule: &AlertRule, metrics: &HashMap<String, f64>) -> bool {
         let value = match &rule.condition {
@@ -120,6 +210,8 @@ impl AlertManager {
             AlertCondition::FederationFailures => metrics.get("federation_failures"),
             AlertCondition::DatabaseConnections => metrics.get("database_connections"),
             AlertCondition::ActiveUsers => metrics.get("active_users"),
+            AlertCondition::IoTDevicesOffline => metrics.get("iot_devices_offline"),
+            AlertCondition::PerformanceRegressionPercent => metrics.get("performance_regression_percent"),
         };
         if let Some(&v) = value {
             v >= rule.threshold
@@ -163,6 +255,8 @@ impl AlertManager {
                     AlertCondition::FederationFailures => value > &rule.threshold,
                     AlertCondition::DatabaseConnections => value > &rule.threshold,
                     AlertCondition::ActiveUsers => value > &rule.threshold,
+                    AlertCondition::IoTDevicesOffline => value > &rule.threshold,
+                    AlertCondition::PerformanceRegressionPercent => value > &rule.threshold,
                 };
 
                 if should_alert {
@@ -221,12 +315,28 @@ impl AlertManager {
     }
 
     async fn find_notification_channel(&self, name: &str) -> Option<NotificationChannel> {
-        // TODO: Implement channel lookup
-        None
+        self.channels.read().await.iter().find(|c| c.enabled && c.name == name).cloned()
     }
 
+    /// Email the alert to every recipient of the channel. Delivery is
+    /// queued; recipients that are rate limited or invalid are skipped.
     async fn send_email_notification(&self, alert: &Alert, channel: &NotificationChannel) -> Result<(), MonitorError> {
-        // TODO: Implement email notification
+        let Some(mailer) = &self.mailer else {
+            warn!("No email delivery configured for notification channel {}", channel.name);
+            return Ok(());
+        };
+        let vars = json!({
+            "rule": alert.rule.name,
+            "severity": format!("{:?}", alert.rule.severity),
+            "timestamp": alert.timestamp.to_rfc3339(),
+            "description": format!("{} is above its threshold of {}", alert.rule.condition.as_str(), alert.rule.threshold),
+            "value": alert.value,
+        });
+        for recipient in &channel.config.recipients {
+            if let Err(e) = mailer.send(Purpose::Alert, recipient, &vars) {
+                warn!("Could not email alert {} to {}: {}", alert.rule.name, recipient, e);
+            }
+        }
         Ok(())
     }
 
@@ -331,4 +441,54 @@ mod tests {
         manager.stop().await?;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_configured_rules_are_registered() -> Result<()> {
+        let mut config = AlertConfig::default();
+        config.rules.push(AlertRule {
+            name: "Errors".to_string(),
+            condition: AlertCondition::ErrorRate,
+            threshold: 0.5,
+            duration_minutes: 0,
+            severity: AlertSeverity::High,
+            channels: vec!["ops".to_string()],
+            enabled: true,
+        });
+        let name = AlertCondition::ErrorRate.as_str();
+
+        let manager = AlertManager::new().await?;
+        manager.add_configured(&config).await;
+        assert_eq!(manager.report_metric(name, 0.9).await?.len(), 1);
+
+        config.enabled = false;
+        let manager = AlertManager::new().await?;
+        manager.add_configured(&config).await;
+        assert!(manager.report_metric(name, 0.9).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reported_metrics_alert_once_per_incident() -> Result<()> {
+        let manager = AlertManager::new().await?;
+        manager.add_rule(AlertRule {
+            name: "Devices offline".to_string(),
+            condition: AlertCondition::IoTDevicesOffline,
+            threshold: 1.0,
+            duration_minutes: 0,
+            severity: AlertSeverity::High,
+            channels: Vec::new(),
+            enabled: true,
+        }).await;
+        let name = AlertCondition::IoTDevicesOffline.as_str();
+
+        assert_eq!(manager.report_metric(name, 2.0).await?.len(), 1);
+        assert!(manager.report_metric(name, 3.0).await?.is_empty());
+        assert!(manager.report_metric(name, 0.0).await?.is_empty());
+        assert!(matches!(manager.get_active_alerts().await?[0].status, AlertStatus::Resolved));
+
+        // A new incident alerts again
+        assert_eq!(manager.report_metric(name, 1.0).await?.len(), 1);
+        assert_eq!(manager.get_active_alerts().await?.len(), 2);
+        Ok(())
+    }
 }
@@ -37,6 +37,14 @@ pub struct MonitorConfig {
     pub performance: PerformanceConfig,
     /// Logging configuration
     pub logging: LoggingConfig,
+    /// Serve the HTTP API over mutual TLS with certificates of the internal
+    /// CA; plaintext when unset
+    #[serde(default)]
+    pub tls: Option<matrixon_common::internal_tls::InternalTlsConfig>,
+}
+
+impl matrixon_core::config::ConfigSection for MonitorConfig {
+    const SECTION: &'static str = "monitor";
 }
 
 /// Metrics configuration
@@ -188,6 +196,10 @@ pub enum AlertCondition {
     DatabaseConnections,
     /// Active users
     ActiveUsers,
+    /// IoT devices that went offline after missing heartbeats
+    IoTDevicesOffline,
+    /// Percentage by which a performance metric is worse than its baseline
+    PerformanceRegressionPercent,
 }
 
 impl AlertCondition {
@@ -201,6 +213,8 @@ impl AlertCondition {
             AlertCondition::FederationFailures => "federation_failures",
             AlertCondition::DatabaseConnections => "database_connections",
             AlertCondition::ActiveUsers => "active_users",
+            AlertCondition::IoTDevicesOffline => "iot_devices_offline",
+            AlertCondition::PerformanceRegressionPercent => "performance_regression_percent",
         }
     }
 }
@@ -292,6 +306,25 @@ pub struct PerformanceConfig {
     pub track_memory_usage: bool,
     /// Memory report interval in minutes
     pub memory_report_interval: u64,
+    /// File the rolling performance baselines are kept in between restarts;
+    /// kept in memory only when unset
+    #[serde(default)]
+    pub baseline_path: Option<std::path::PathBuf>,
+    /// Number of past monitoring intervals a baseline averages over
+    #[serde(default = "default_baseline_windows")]
+    pub baseline_windows: usize,
+    /// How much worse than its baseline, in percent, a metric must get to be
+    /// flagged as a regression
+    #[serde(default = "default_regression_threshold_percent")]
+    pub regression_threshold_percent: f64,
+}
+
+fn default_baseline_windows() -> usize {
+    24
+}
+
+fn default_regression_threshold_percent() -> f64 {
+    25.0
 }
 
 /// Logging configuration
@@ -378,6 +411,9 @@ impl Default for PerformanceConfig {
             enable_profiling: true,
             track_memory_usage: true,
             memory_report_interval: 15,
+            baseline_path: None,
+            baseline_windows: default_baseline_windows(),
+            regression_threshold_percent: default_regression_threshold_percent(),
         }
     }
 }
@@ -22,9 +22,10 @@
 use std::{
     collections::HashMap,
     sync::Arc,
+    time::Duration,
 };
 
-use tracing::{debug, info, instrument};
+use tracing::{debug, info, instrument, warn};
 use serde::Serialize;
 use axum::{
     Router, 
@@ -35,7 +36,11 @@ use axum::{
     extract::State
 };
 
+use async_trait::async_trait;
 use tokio::net::TcpListener;
+use matrixon_common::internal_tls;
+use matrixon_core::health::{ComponentHealth, HealthProbe};
+use matrixon_email::Mailer;
 
 pub mod config;
 pub mod metrics;
@@ -91,12 +96,42 @@ impl MonitorService {
         Ok(service)
     }
 
+    /// Create a new monitor service instance whose email notification
+    /// channels deliver through `mailer`
+    pub async fn new_with_mailer(config: MonitorConfig, mailer: Option<Arc<Mailer>>) -> Result<Self> {
+        let metrics = Arc::new(MetricsManager::new(config.metrics.clone())?);
+        Self::build(&config, metrics, mailer).await
+    }
+
     /// Create a new monitor service instance with existing metrics manager
     pub async fn new_with_metrics(config: &MonitorConfig, metrics: Arc<MetricsManager>) -> Result<Self> {
+        Self::build(config, metrics, None).await
+    }
+
+    /// Set up the managers, with the alert rules and notification channels
+    /// of `config.alert`
+    async fn build(config: &MonitorConfig, metrics: Arc<MetricsManager>, mailer: Option<Arc<Mailer>>) -> Result<Self> {
         let system = SystemMonitor::new(config.system.clone(), metrics.clone());
         let health = Arc::new(HealthManager::new(config.health.clone(), metrics.clone()));
-        let alert = Arc::new(AlertManager::new().await?);
-        let performance = Arc::new(PerformanceManager::new(config.performance.clone(), metrics.clone()));
+        let mut alert = AlertManager::new().await?;
+        if let Some(mailer) = mailer {
+            alert = alert.with_mailer(mailer);
+        }
+        alert.add_configured(&config.alert).await;
+        let alert = Arc::new(alert);
+        alert.add_rule(config::AlertRule {
+            name: "Performance regression".to_string(),
+            condition: config::AlertCondition::PerformanceRegressionPercent,
+            threshold: config.performance.regression_threshold_percent,
+            duration_minutes: 0,
+            severity: config::AlertSeverity::Warning,
+            channels: Vec::new(),
+            enabled: true,
+        }).await;
+        let performance = Arc::new(
+            PerformanceManager::new(config.performance.clone(), metrics.clone())
+                .with_alert_manager(alert.clone())
+        );
 
         Ok(Self {
             config: config.clone(),
@@ -112,10 +147,39 @@ impl MonitorService {
     #[instrument(level = "debug", skip(self))]
     pub async fn start(&mut self) -> Result<()> {
         debug!("🔧 Starting monitor service");
+        if self.config.performance.enabled {
+            let performance = self.performance.clone();
+            let interval = Duration::from_secs(self.config.performance.monitoring_interval_seconds.max(1));
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(interval);
+                // The first tick is immediate and would close an empty interval
+                ticker.tick().await;
+                loop {
+                    ticker.tick().await;
+                    if let Err(e) = performance.collect_metrics().await {
+                        warn!("⚠️ Could not collect performance metrics: {}", e);
+                    }
+                }
+            });
+        }
         self.start_http_api().await?;
         Ok(())
     }
 
+    /// Performance manager, for the server to record request latencies and
+    /// processed events with
+    pub fn performance(&self) -> Arc<PerformanceManager> {
+        self.performance.clone()
+    }
+
+    /// Probe for the server's readiness endpoint, see [`MonitorHealthProbe`]
+    pub fn health_probe(&self) -> Arc<dyn HealthProbe> {
+        Arc::new(MonitorHealthProbe {
+            metrics: self.metrics.clone(),
+            alert: self.alert.clone(),
+        })
+    }
+
     /// Stop the monitor service
     #[instrument(level = "debug", skip(self))]
     pub async fn stop(&mut self) -> Result<()> {
@@ -141,12 +205,53 @@ impl MonitorService {
         let port = self.config.metrics.prometheus_endpoint.split(':').last().unwrap_or("3000");
         let addr = format!("0.0.0.0:{}", port);
         let listener = TcpListener::bind(&addr).await.map_err(|e| MonitorError::HttpError(format!("Failed to bind to {}: {}", addr, e)))?;
-        info!("HTTP API server listening on {}", addr);
-        axum::serve(listener, app).await.map_err(|e| MonitorError::HttpError(format!("Failed to serve HTTP API: {}", e)))?;
+        match &self.config.tls {
+            Some(tls) => {
+                let tls = internal_tls::server_config(tls).map_err(|e| MonitorError::ConfigError(e.to_string()))?;
+                info!("HTTP API server listening on {} (mutual TLS)", addr);
+                internal_tls::serve(listener, app, tls).await.map_err(|e| MonitorError::HttpError(format!("Failed to serve HTTP API: {}", e)))?;
+            }
+            None => {
+                info!("HTTP API server listening on {}", addr);
+                axum::serve(listener, app).await.map_err(|e| MonitorError::HttpError(format!("Failed to serve HTTP API: {}", e)))?;
+            }
+        }
         Ok(())
     }
 }
 
+/// Health of the monitor: failed while metrics cannot be collected,
+/// degraded while critical alerts are active
+#[derive(Debug)]
+pub struct MonitorHealthProbe {
+    metrics: Arc<MetricsManager>,
+    alert: Arc<AlertManager>,
+}
+
+#[async_trait]
+impl HealthProbe for MonitorHealthProbe {
+    async fn check(&self) -> ComponentHealth {
+        if let Err(e) = self.metrics.get_metrics().await {
+            return ComponentHealth::failed(format!("Metrics cannot be collected: {}", e));
+        }
+        let critical: Vec<String> = self
+            .alert
+            .get_active_alerts()
+            .await
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|alert| matches!(alert.status, alert::AlertStatus::Active))
+            .filter(|alert| matches!(alert.rule.severity, config::AlertSeverity::Critical))
+            .map(|alert| alert.rule.name)
+            .collect();
+        if critical.is_empty() {
+            ComponentHealth::healthy()
+        } else {
+            ComponentHealth::degraded(format!("Critical alerts active: {}", critical.join(", ")))
+        }
+    }
+}
+
 #[derive(Clone)]
 struct AppState {
     metrics: Arc<MetricsManager>,
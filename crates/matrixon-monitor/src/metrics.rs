@@ -125,6 +125,24 @@ impl MetricsManager {
             "result" => result.to_string()
         );
     }
+
+    /// Record the delivery health of a federation destination; timestamps
+    /// are milliseconds since the epoch and reported in seconds
+    #[instrument(skip(self), level = "debug")]
+    pub fn record_federation_destination(
+        &self,
+        destination: &str,
+        failures: u32,
+        retry_at: Option<u64>,
+        last_success: Option<u64>,
+    ) {
+        let labels = [("destination", destination.to_string())];
+        gauge!("matrixon_federation_destination_failures", failures as f64, &labels);
+        gauge!("matrixon_federation_destination_retry_at_seconds", retry_at.unwrap_or(0) as f64 / 1000.0, &labels);
+        if let Some(last_success) = last_success {
+            gauge!("matrixon_federation_destination_last_success_seconds", last_success as f64 / 1000.0, &labels);
+        }
+    }
 }
 
 impl std::fmt::Debug for MetricsManager {
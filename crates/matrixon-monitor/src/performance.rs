@@ -7,20 +7,33 @@
 //! Purpose: Implements performance metrics collection and analysis for the Matrixon monitoring system.
 
 use std::{
-    sync::Arc,
-    time::SystemTime,
+    collections::{HashMap, VecDeque},
+    mem,
+    sync::{Arc, Mutex},
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
 };
 
 use axum::{Json, response::IntoResponse, http::StatusCode};
+use rand::Rng;
 use serde::{Deserialize, Serialize};
-use tracing::instrument;
+use tracing::{error, instrument, warn};
 use tokio::sync::RwLock;
 
-use crate::config::PerformanceConfig;
+use crate::alert::AlertManager;
+use crate::config::{AlertCondition, PerformanceConfig};
 use crate::metrics::MetricsManager;
-use crate::error::Result;
+use crate::error::{MonitorError, Result};
 use crate::system::SystemMetrics;
 
+/// Requests an endpoint needs in an interval for its p95 latency to count
+const MIN_LATENCY_SAMPLES: usize = 20;
+/// Latencies kept per endpoint and interval; beyond them a uniform sample
+/// of the interval's requests is kept
+const MAX_LATENCY_SAMPLES: usize = 1000;
+/// Endpoints tracked; requests to further ones are not recorded
+const MAX_ENDPOINTS: usize = 256;
+
 /// Database performance metrics
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct DatabaseMetrics {
@@ -77,6 +90,70 @@ pub struct PerformanceMetrics {
     pub disk_usage: f32,
     pub network_usage: f32,
     pub timestamp: SystemTime,
+    /// Metrics of the last interval that were worse than their baselines
+    #[serde(default)]
+    pub regressions: Vec<Regression>,
+}
+
+/// A metric that got worse than its rolling baseline
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Regression {
+    /// `p95_latency_ms:<endpoint>` or `events_per_sec`
+    pub metric: String,
+    /// Average of the metric over the baseline intervals
+    pub baseline: f64,
+    /// Value of the metric in the last interval
+    pub current: f64,
+    /// How much worse than the baseline the current value is, in percent
+    pub percent: f64,
+}
+
+/// Rolling baselines: the values of the last intervals, oldest first
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Baselines {
+    /// p95 request latency in milliseconds per endpoint
+    pub p95_latency_ms: HashMap<String, VecDeque<f64>>,
+    /// Timeline events processed per second
+    pub events_per_sec: VecDeque<f64>,
+}
+
+/// Samples of the interval in progress
+#[derive(Debug)]
+struct Window {
+    latencies_ms: HashMap<String, Reservoir>,
+    events: u64,
+    started: Instant,
+}
+
+/// A uniform sample of at most `MAX_LATENCY_SAMPLES` values
+#[derive(Debug, Default)]
+struct Reservoir {
+    samples: Vec<f64>,
+    seen: u64,
+}
+
+impl Reservoir {
+    fn push(&mut self, value: f64) {
+        self.seen += 1;
+        if self.samples.len() < MAX_LATENCY_SAMPLES {
+            self.samples.push(value);
+        } else {
+            let slot = rand::thread_rng().gen_range(0..self.seen);
+            if let Some(sample) = self.samples.get_mut(slot as usize) {
+                *sample = value;
+            }
+        }
+    }
+}
+
+impl Window {
+    fn new() -> Self {
+        Self {
+            latencies_ms: HashMap::new(),
+            events: 0,
+            started: Instant::now(),
+        }
+    }
 }
 
 impl IntoResponse for PerformanceMetrics {
@@ -103,7 +180,126 @@ impl PerformanceMetrics {
             disk_usage: 0.0,
             network_usage: 0.0,
             timestamp: SystemTime::now(),
+            regressions: Vec::new(),
+        }
+    }
+}
+
+/// Rolling per-interval baselines and the regressions measured against them
+#[derive(Debug)]
+pub struct BaselineTracker {
+    path: Option<PathBuf>,
+    keep: usize,
+    threshold_percent: f64,
+    window: Mutex<Window>,
+    baselines: RwLock<Baselines>,
+    alert_manager: Option<Arc<AlertManager>>,
+}
+
+impl BaselineTracker {
+    /// Create a tracker as configured
+    ///
+    /// Baselines persisted at `config.baseline_path` are loaded; a missing
+    /// or unreadable file starts them afresh.
+    pub fn new(config: &PerformanceConfig) -> Self {
+        let baselines = config.baseline_path.as_ref()
+            .and_then(|path| match std::fs::read(path) {
+                Ok(bytes) => serde_json::from_slice(&bytes)
+                    .map_err(|e| warn!("⚠️ Ignoring unreadable performance baselines {}: {}", path.display(), e))
+                    .ok(),
+                Err(_) => None,
+            })
+            .unwrap_or_default();
+        Self {
+            path: config.baseline_path.clone(),
+            keep: config.baseline_windows.max(1),
+            threshold_percent: config.regression_threshold_percent,
+            window: Mutex::new(Window::new()),
+            baselines: RwLock::new(baselines),
+            alert_manager: None,
+        }
+    }
+
+    /// Report regressions to `alert_manager` as `performance_regression_percent`
+    pub fn with_alert_manager(mut self, alert_manager: Arc<AlertManager>) -> Self {
+        self.alert_manager = Some(alert_manager);
+        self
+    }
+
+    /// Record the latency of one request to `endpoint`, a route rather
+    /// than a concrete path
+    pub fn record_request(&self, endpoint: &str, duration: Duration) {
+        let mut window = self.window.lock().unwrap();
+        if !window.latencies_ms.contains_key(endpoint) && window.latencies_ms.len() >= MAX_ENDPOINTS {
+            return;
+        }
+        window.latencies_ms
+            .entry(endpoint.to_owned())
+            .or_default()
+            .push(duration.as_secs_f64() * 1000.0);
+    }
+
+    /// Record `count` processed timeline events
+    pub fn record_events(&self, count: u64) {
+        self.window.lock().unwrap().events += count;
+    }
+
+    /// Get the rolling baselines
+    pub async fn baselines(&self) -> Baselines {
+        self.baselines.read().await.clone()
+    }
+
+    /// Close the interval in progress: compare its p95 latencies and event
+    /// rate with their baselines, then fold them into the baselines
+    ///
+    /// Endpoints with fewer than `MIN_LATENCY_SAMPLES` requests and
+    /// intervals without events are left out, so quiet periods neither
+    /// raise regressions nor drag the baselines down.
+    #[instrument(level = "debug", skip(self))]
+    pub async fn evaluate(&self) -> Result<Vec<Regression>> {
+        let window = mem::replace(&mut *self.window.lock().unwrap(), Window::new());
+        let elapsed = window.started.elapsed().as_secs_f64();
+
+        let mut regressions = Vec::new();
+        let mut baselines = self.baselines.write().await;
+        for (endpoint, Reservoir { samples: mut latencies, .. }) in window.latencies_ms {
+            if latencies.len() < MIN_LATENCY_SAMPLES {
+                continue;
+            }
+            if !baselines.p95_latency_ms.contains_key(&endpoint) && baselines.p95_latency_ms.len() >= MAX_ENDPOINTS {
+                continue;
+            }
+            let p95 = percentile(&mut latencies, 0.95);
+            let history = baselines.p95_latency_ms.entry(endpoint.clone()).or_default();
+            regressions.extend(regression(format!("p95_latency_ms:{}", endpoint), history, p95, true, self.threshold_percent));
+            push_bounded(history, p95, self.keep);
+        }
+        if window.events > 0 && elapsed > 0.0 {
+            let rate = window.events as f64 / elapsed;
+            let history = &mut baselines.events_per_sec;
+            regressions.extend(regression("events_per_sec".to_owned(), history, rate, false, self.threshold_percent));
+            push_bounded(history, rate, self.keep);
         }
+        regressions.sort_by(|a, b| a.metric.cmp(&b.metric));
+
+        if let Some(path) = &self.path {
+            let bytes = serde_json::to_vec(&*baselines)
+                .map_err(|e| MonitorError::InternalError(e.to_string()))?;
+            if let Err(e) = tokio::fs::write(path, bytes).await {
+                error!("❌ Failed to persist performance baselines to {}: {}", path.display(), e);
+            }
+        }
+        drop(baselines);
+
+        for found in &regressions {
+            warn!("🐢 {} regressed by {:.1}%: {:.2} against a baseline of {:.2}",
+                found.metric, found.percent, found.current, found.baseline);
+        }
+        if let Some(alert_manager) = &self.alert_manager {
+            let worst = regressions.iter().map(|found| found.percent).fold(0.0, f64::max);
+            alert_manager.report_metric(AlertCondition::PerformanceRegressionPercent.as_str(), worst).await?;
+        }
+        Ok(regressions)
     }
 }
 
@@ -113,18 +309,50 @@ pub struct PerformanceManager {
     config: PerformanceConfig,
     metrics: Arc<MetricsManager>,
     current_metrics: RwLock<PerformanceMetrics>,
+    baselines: BaselineTracker,
 }
 
 impl PerformanceManager {
     /// Create a new PerformanceManager instance
     pub fn new(config: PerformanceConfig, metrics: Arc<MetricsManager>) -> Self {
         Self {
+            baselines: BaselineTracker::new(&config),
             config,
             metrics,
             current_metrics: RwLock::new(PerformanceMetrics::new()),
         }
     }
 
+    /// Report regressions to `alert_manager`
+    pub fn with_alert_manager(mut self, alert_manager: Arc<AlertManager>) -> Self {
+        self.baselines = self.baselines.with_alert_manager(alert_manager);
+        self
+    }
+
+    /// Record the latency of one request to `endpoint`
+    pub fn record_request(&self, endpoint: &str, duration: Duration) {
+        self.baselines.record_request(endpoint, duration);
+    }
+
+    /// Record `count` processed timeline events
+    pub fn record_events(&self, count: u64) {
+        self.baselines.record_events(count);
+    }
+
+    /// Get the rolling baselines
+    pub async fn get_baselines(&self) -> Baselines {
+        self.baselines.baselines().await
+    }
+
+    /// Compare the interval in progress with the baselines and show the
+    /// regressions found in the current metrics
+    #[instrument(level = "debug", skip(self))]
+    pub async fn evaluate_baselines(&self) -> Result<Vec<Regression>> {
+        let regressions = self.baselines.evaluate().await?;
+        self.current_metrics.write().await.regressions = regressions.clone();
+        Ok(regressions)
+    }
+
     /// Get current performance metrics
     #[instrument(level = "debug", skip(self))]
     pub async fn get_metrics(&self) -> Result<PerformanceMetrics> {
@@ -144,11 +372,45 @@ impl PerformanceManager {
         metrics.memory_usage = memory_usage;
         metrics.timestamp = SystemTime::now();
 
+        drop(metrics);
+
         // TODO: Implement disk and network metrics collection
+        if self.config.enabled {
+            self.evaluate_baselines().await?;
+        }
         Ok(())
     }
 }
 
+/// The value below which `fraction` of `samples` lie, by nearest rank
+fn percentile(samples: &mut [f64], fraction: f64) -> f64 {
+    samples.sort_by(f64::total_cmp);
+    let rank = (fraction * samples.len() as f64).ceil() as usize;
+    samples[rank.clamp(1, samples.len()) - 1]
+}
+
+/// `current` compared with the average of `history`, if it is worse by more
+/// than `threshold` percent
+fn regression(metric: String, history: &VecDeque<f64>, current: f64, higher_is_worse: bool, threshold: f64) -> Option<Regression> {
+    if history.is_empty() {
+        return None;
+    }
+    let baseline = history.iter().sum::<f64>() / history.len() as f64;
+    if baseline <= 0.0 {
+        return None;
+    }
+    let change = (current - baseline) / baseline * 100.0;
+    let percent = if higher_is_worse { change } else { -change };
+    (percent > threshold).then_some(Regression { metric, baseline, current, percent })
+}
+
+fn push_bounded(history: &mut VecDeque<f64>, value: f64, keep: usize) {
+    history.push_back(value);
+    while history.len() > keep {
+        history.pop_front();
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -167,4 +429,76 @@ mod tests {
         assert!(metrics.memory_usage >= 0.0);
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_regressions_against_persisted_baselines() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("matrixon-baselines-{}.json", uuid::Uuid::new_v4()));
+        let config = PerformanceConfig {
+            baseline_path: Some(path.clone()),
+            regression_threshold_percent: 50.0,
+            ..PerformanceConfig::default()
+        };
+        let tracker = BaselineTracker::new(&config);
+        for _ in 0..MIN_LATENCY_SAMPLES {
+            tracker.record_request("/sync", Duration::from_millis(10));
+            tracker.record_request("/login", Duration::from_millis(10));
+        }
+        assert!(tracker.evaluate().await?.is_empty());
+
+        // A restarted tracker compares against the persisted baselines
+        let alert_manager = Arc::new(AlertManager::new().await?);
+        alert_manager.add_rule(crate::config::AlertRule {
+            name: "Performance regression".to_string(),
+            condition: AlertCondition::PerformanceRegressionPercent,
+            threshold: config.regression_threshold_percent,
+            duration_minutes: 0,
+            severity: crate::config::AlertSeverity::Warning,
+            channels: Vec::new(),
+            enabled: true,
+        }).await;
+        let tracker = BaselineTracker::new(&config).with_alert_manager(alert_manager.clone());
+        assert_eq!(tracker.baselines().await.p95_latency_ms["/sync"], [10.0]);
+        for _ in 0..MIN_LATENCY_SAMPLES {
+            tracker.record_request("/sync", Duration::from_millis(30));
+            tracker.record_request("/login", Duration::from_millis(12));
+        }
+        // Too few requests to judge
+        tracker.record_request("/logout", Duration::from_secs(1));
+
+        let regressions = tracker.evaluate().await?;
+        assert_eq!(regressions.len(), 1);
+        assert_eq!(regressions[0].metric, "p95_latency_ms:/sync");
+        assert_eq!(regressions[0].percent, 200.0);
+        assert_eq!(alert_manager.get_active_alerts().await?.len(), 1);
+        let baselines = tracker.baselines().await;
+        assert_eq!(baselines.p95_latency_ms["/sync"], [10.0, 30.0]);
+        assert!(!baselines.p95_latency_ms.contains_key("/logout"));
+
+        let _ = std::fs::remove_file(path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_samples_are_bounded() {
+        let tracker = BaselineTracker::new(&PerformanceConfig::default());
+        for i in 0..MAX_LATENCY_SAMPLES * 3 {
+            tracker.record_request("/sync", Duration::from_millis(i as u64));
+        }
+        for i in 0..MAX_ENDPOINTS * 2 {
+            tracker.record_request(&format!("/endpoint/{}", i), Duration::from_millis(1));
+        }
+        let window = tracker.window.lock().unwrap();
+        assert_eq!(window.latencies_ms["/sync"].samples.len(), MAX_LATENCY_SAMPLES);
+        assert_eq!(window.latencies_ms["/sync"].seen, MAX_LATENCY_SAMPLES as u64 * 3);
+        assert_eq!(window.latencies_ms.len(), MAX_ENDPOINTS);
+    }
+
+    #[test]
+    fn test_lower_event_rate_is_a_regression() {
+        let history = VecDeque::from([100.0, 120.0]);
+        assert!(regression("events_per_sec".to_owned(), &history, 100.0, false, 20.0).is_none());
+        let found = regression("events_per_sec".to_owned(), &history, 55.0, false, 20.0).unwrap();
+        assert_eq!(found.percent, 50.0);
+        assert!(regression("events_per_sec".to_owned(), &VecDeque::new(), 1.0, false, 20.0).is_none());
+    }
 }
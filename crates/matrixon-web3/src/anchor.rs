@@ -0,0 +1,405 @@
+//! Room Anchoring Module
+//!
+//! Anchors a hash of the membership and state of compliance-sensitive rooms
+//! in a smart contract. Each enabled room is snapshotted periodically; when
+//! the snapshot changed since the last anchor, its keccak-256 hash is sent
+//! to the configured contract together with the hashed room id and a
+//! per-room sequence number. The anchors form a tamper-evident audit trail:
+//! a later snapshot can be checked against the hashes on chain. With a
+//! records file, the anchors made so far survive restarts, so sequence
+//! numbers continue where they left off.
+//! Author: arkSong (arksong2018@gmail.com)
+//! Version: 0.1.0
+//! Date: 2025-06-15
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::RwLock;
+use tracing::{info, instrument, warn};
+use web3::{
+    ethabi::Token,
+    types::{Address, Bytes, TransactionParameters, H256, U256},
+    Transport, Web3,
+};
+
+use crate::wallet::{keccak256, Wallet};
+
+/// Where and how often rooms are anchored
+#[derive(Debug, Clone)]
+pub struct AnchorConfig {
+    /// Contract receiving the anchors
+    pub contract: Address,
+    /// Signature of the anchoring method, called with the room id hash,
+    /// the snapshot hash and the sequence number
+    pub method: String,
+    /// Rooms that are anchored
+    pub rooms: HashSet<String>,
+    /// Time between anchoring rounds
+    pub interval: Duration,
+    /// Gas limit of an anchoring transaction
+    pub gas_limit: U256,
+}
+
+impl AnchorConfig {
+    /// Anchor to `contract` with the default method, hourly
+    pub fn new(contract: Address) -> Self {
+        Self {
+            contract,
+            method: "anchor(bytes32,bytes32,uint256)".to_string(),
+            rooms: HashSet::new(),
+            interval: Duration::from_secs(60 * 60),
+            gas_limit: 100_000.into(),
+        }
+    }
+
+    /// Enable anchoring of a room
+    pub fn with_room(mut self, room_id: &str) -> Self {
+        self.rooms.insert(room_id.to_string());
+        self
+    }
+}
+
+/// Membership and state of a room at one point in time
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomSnapshot {
+    /// Room the snapshot is of
+    pub room_id: String,
+    /// Membership of every user with a member event
+    pub members: BTreeMap<String, String>,
+    /// Event id of every state event, by `(type, state_key)`
+    pub state: BTreeMap<(String, String), String>,
+}
+
+impl RoomSnapshot {
+    /// Keccak-256 hash over a canonical encoding of the snapshot. Every
+    /// string is prefixed with its length and every map with its number
+    /// of entries, so no two snapshots share an encoding.
+    pub fn hash(&self) -> H256 {
+        let mut encoded = Vec::new();
+        push_field(&mut encoded, SNAPSHOT_ENCODING);
+        push_field(&mut encoded, &self.room_id);
+        encoded.extend_from_slice(&(self.members.len() as u64).to_be_bytes());
+        for (user_id, membership) in &self.members {
+            push_field(&mut encoded, user_id);
+            push_field(&mut encoded, membership);
+        }
+        encoded.extend_from_slice(&(self.state.len() as u64).to_be_bytes());
+        for ((event_type, state_key), event_id) in &self.state {
+            push_field(&mut encoded, event_type);
+            push_field(&mut encoded, state_key);
+            push_field(&mut encoded, event_id);
+        }
+        H256(keccak256(&encoded))
+    }
+}
+
+/// Names the snapshot encoding, so hashes of another one never match
+const SNAPSHOT_ENCODING: &str = "matrixon.room_snapshot.v2";
+
+/// Append `field` with its length as a big-endian u64
+fn push_field(encoded: &mut Vec<u8>, field: &str) {
+    encoded.extend_from_slice(&(field.len() as u64).to_be_bytes());
+    encoded.extend_from_slice(field.as_bytes());
+}
+
+/// Provides snapshots of the rooms to anchor
+#[async_trait]
+pub trait SnapshotSource: Send + Sync {
+    /// Current snapshot of a room, `None` if the room is unknown
+    async fn snapshot(&self, room_id: &str) -> Option<RoomSnapshot>;
+}
+
+/// A snapshot hash anchored on chain
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnchorRecord {
+    /// Anchored room
+    pub room_id: String,
+    /// Hash of the anchored snapshot
+    pub snapshot_hash: H256,
+    /// Per-room sequence number, starting at 1
+    pub sequence: u64,
+    /// Transaction carrying the anchor
+    pub tx_hash: H256,
+    /// Unix time of the anchoring
+    pub anchored_at: u64,
+}
+
+/// Call data of `method(keccak256(room_id), snapshot_hash, sequence)`
+fn anchor_call_data(method: &str, room_id: &str, snapshot_hash: H256, sequence: u64) -> Vec<u8> {
+    let mut data = keccak256(method.as_bytes())[..4].to_vec();
+    data.extend(web3::ethabi::encode(&[
+        Token::FixedBytes(keccak256(room_id.as_bytes()).to_vec()),
+        Token::FixedBytes(snapshot_hash.as_bytes().to_vec()),
+        Token::Uint(sequence.into()),
+    ]));
+    data
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Anchors by room, as kept in the records file
+type Records = HashMap<String, Vec<AnchorRecord>>;
+
+/// Replace the records file with `records`: written to a temporary file,
+/// synced and renamed over the old one, so a crash never tears it
+fn write_records(path: &Path, records: &Records) -> io::Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    fs::create_dir_all(dir)?;
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(&serde_json::to_vec(records).map_err(io::Error::other)?)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)?;
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// Periodically anchors room snapshots through a server wallet
+pub struct RoomAnchorer<T: Transport> {
+    web3: Web3<T>,
+    wallet: Wallet,
+    from: Address,
+    config: AnchorConfig,
+    source: Arc<dyn SnapshotSource>,
+    records: RwLock<Records>,
+    records_file: Option<PathBuf>,
+}
+
+impl<T: Transport> std::fmt::Debug for RoomAnchorer<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RoomAnchorer")
+            .field("from", &self.from)
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Transport> RoomAnchorer<T> {
+    /// Create an anchorer paying from account `from` of `wallet`
+    #[instrument(level = "debug", skip(web3, wallet, source))]
+    pub fn new(
+        web3: Web3<T>,
+        wallet: Wallet,
+        from: Address,
+        config: AnchorConfig,
+        source: Arc<dyn SnapshotSource>,
+    ) -> Result<Self, AnchorError> {
+        if !wallet.addresses().contains(&from) {
+            return Err(AnchorError::UnknownAccount(from));
+        }
+        info!("🔧 Initializing RoomAnchorer for {} rooms", config.rooms.len());
+        Ok(Self {
+            web3,
+            wallet,
+            from,
+            config,
+            source,
+            records: RwLock::new(HashMap::new()),
+            records_file: None,
+        })
+    }
+
+    /// Keep the anchors in the file at `path`, loading the ones stored
+    /// there
+    pub fn with_records_file(mut self, path: PathBuf) -> Result<Self, AnchorError> {
+        let records = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| AnchorError::Records(io::Error::other(e)))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(AnchorError::Records(e)),
+        };
+        self.records = RwLock::new(records);
+        self.records_file = Some(path);
+        Ok(self)
+    }
+
+    /// Anchor the current snapshot of a room. Returns `None` when the
+    /// snapshot has not changed since the last anchor.
+    #[instrument(level = "debug", skip(self))]
+    pub async fn anchor_room(&self, room_id: &str) -> Result<Option<AnchorRecord>, AnchorError> {
+        if !self.config.rooms.contains(room_id) {
+            return Err(AnchorError::NotEnabled(room_id.to_string()));
+        }
+        let snapshot = self
+            .source
+            .snapshot(room_id)
+            .await
+            .ok_or_else(|| AnchorError::UnknownRoom(room_id.to_string()))?;
+        let snapshot_hash = snapshot.hash();
+        let last = self.records.read().await.get(room_id).and_then(|records| records.last().cloned());
+        if last.as_ref().is_some_and(|last| last.snapshot_hash == snapshot_hash) {
+            return Ok(None);
+        }
+        let sequence = last.map_or(1, |last| last.sequence + 1);
+
+        let key = self.wallet.signing_key(self.from).ok_or(AnchorError::UnknownAccount(self.from))?;
+        let parameters = TransactionParameters {
+            to: Some(self.config.contract),
+            data: Bytes(anchor_call_data(&self.config.method, room_id, snapshot_hash, sequence)),
+            gas: self.config.gas_limit,
+            ..Default::default()
+        };
+        let signed = self.web3.accounts().sign_transaction(parameters, &key).await?;
+        let tx_hash = self.web3.eth().send_raw_transaction(signed.raw_transaction).await?;
+
+        info!("⚓ Anchored snapshot {:?} of {} (#{}) in {:?}", snapshot_hash, room_id, sequence, tx_hash);
+        let record = AnchorRecord {
+            room_id: room_id.to_string(),
+            snapshot_hash,
+            sequence,
+            tx_hash,
+            anchored_at: now_secs(),
+        };
+        let mut records = self.records.write().await;
+        records.entry(room_id.to_string()).or_default().push(record.clone());
+        if let Some(path) = &self.records_file {
+            // The anchor is on chain already; a failed write is retried
+            // with the next one
+            if let Err(e) = write_records(path, &records) {
+                warn!("⚠️ Could not write anchor records to {}: {}", path.display(), e);
+            }
+        }
+        Ok(Some(record))
+    }
+
+    /// Anchor every enabled room whose snapshot changed. Failures are
+    /// logged and retried in the next round.
+    pub async fn anchor_all(&self) -> Vec<AnchorRecord> {
+        let mut anchored = Vec::new();
+        for room_id in &self.config.rooms {
+            match self.anchor_room(room_id).await {
+                Ok(Some(record)) => anchored.push(record),
+                Ok(None) => {}
+                Err(e) => warn!("⚠️ Could not anchor {}: {}", room_id, e),
+            }
+        }
+        anchored
+    }
+
+    /// Anchors of a room, oldest first
+    pub async fn history(&self, room_id: &str) -> Vec<AnchorRecord> {
+        self.records.read().await.get(room_id).cloned().unwrap_or_default()
+    }
+
+    /// The anchor matching a snapshot, if it was ever anchored
+    pub async fn verify(&self, snapshot: &RoomSnapshot) -> Option<AnchorRecord> {
+        let snapshot_hash = snapshot.hash();
+        self.records
+            .read()
+            .await
+            .get(&snapshot.room_id)?
+            .iter()
+            .find(|record| record.snapshot_hash == snapshot_hash)
+            .cloned()
+    }
+}
+
+impl<T> RoomAnchorer<T>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send,
+{
+    /// Anchor all enabled rooms every `interval` in the background
+    pub fn spawn(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.interval);
+            loop {
+                interval.tick().await;
+                self.anchor_all().await;
+            }
+        })
+    }
+}
+
+/// Anchoring-specific errors
+#[derive(Error, Debug)]
+pub enum AnchorError {
+    /// Web3 provider error
+    #[error("Web3 error: {0}")]
+    Web3(#[from] web3::Error),
+
+    /// The wallet has no such account
+    #[error("Unknown anchoring account: {0:?}")]
+    UnknownAccount(Address),
+
+    /// Anchoring is not enabled for the room
+    #[error("Anchoring is not enabled for {0}")]
+    NotEnabled(String),
+
+    /// No snapshot of the room is available
+    #[error("Unknown room: {0}")]
+    UnknownRoom(String),
+
+    /// The records file could not be read
+    #[error("Anchor records error: {0}")]
+    Records(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_log::test;
+
+    fn snapshot() -> RoomSnapshot {
+        let mut snapshot = RoomSnapshot { room_id: "!room:x".to_string(), ..Default::default() };
+        snapshot.members.insert("@a:x".to_string(), "join".to_string());
+        snapshot.state.insert(("m.room.create".to_string(), String::new()), "$create".to_string());
+        snapshot
+    }
+
+    #[test]
+    fn test_snapshot_hash_covers_membership() {
+        let first = snapshot();
+        assert_eq!(first.hash(), snapshot().hash());
+
+        let mut changed = snapshot();
+        changed.members.insert("@a:x".to_string(), "leave".to_string());
+        assert_ne!(first.hash(), changed.hash());
+
+        // Separators inside values cannot make two snapshots collide
+        let mut tabs = snapshot();
+        tabs.members = BTreeMap::from([("@a:x\tjoin\nmember\t@b:x".to_string(), "join".to_string())]);
+        let mut split = snapshot();
+        split.members = BTreeMap::from([
+            ("@a:x".to_string(), "join".to_string()),
+            ("@b:x".to_string(), "join".to_string()),
+        ]);
+        assert_ne!(tabs.hash(), split.hash());
+    }
+
+    #[test]
+    fn test_records_file_round_trip() {
+        let path = std::env::temp_dir().join(format!("matrixon-anchors-{}.json", std::process::id()));
+        let record = AnchorRecord {
+            room_id: "!room:x".to_string(),
+            snapshot_hash: H256::repeat_byte(1),
+            sequence: 4,
+            tx_hash: H256::repeat_byte(2),
+            anchored_at: 10,
+        };
+        let records = HashMap::from([("!room:x".to_string(), vec![record.clone()])]);
+        write_records(&path, &records).unwrap();
+        let loaded: Records = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(loaded["!room:x"], vec![record]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_anchor_call_data() {
+        let data = anchor_call_data("anchor(bytes32,bytes32,uint256)", "!room:x", H256::repeat_byte(7), 3);
+        assert_eq!(data.len(), 4 + 3 * 32);
+        assert_eq!(&data[36..68], H256::repeat_byte(7).as_bytes());
+        assert_eq!(data[99], 3);
+    }
+}
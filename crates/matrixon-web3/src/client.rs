@@ -8,7 +8,7 @@
 use std::sync::Arc;
 use web3::{
     transports::Http,
-    types::{H160, U256},
+    types::{Recovery, H160, U256},
     Web3,
 };
 use tracing::{info, instrument};
@@ -30,6 +30,28 @@ impl Web3Client {
         }
     }
 
+    /// Create a client of the JSON-RPC endpoint at `rpc_url`
+    pub fn connect(rpc_url: &str) -> Result<Self, ClientError> {
+        Ok(Self::new(Web3::new(Http::new(rpc_url)?)))
+    }
+
+    /// The underlying web3 instance, for contract bindings
+    pub fn web3(&self) -> &Web3<Http> {
+        &self.inner
+    }
+
+    /// Address that made a `personal_sign` signature of `message`. The
+    /// signer is recovered locally; recovery ids 0 and 1 are accepted as
+    /// well as 27 and 28.
+    pub fn recover_personal_signer(&self, message: &str, signature: &[u8]) -> Result<H160, ClientError> {
+        let mut signature: [u8; 65] = signature.try_into().map_err(|_| ClientError::InvalidSignature)?;
+        if signature[64] < 2 {
+            signature[64] += 27;
+        }
+        let recovery = Recovery::from_raw_signature(message.as_bytes(), signature).map_err(|_| ClientError::InvalidSignature)?;
+        self.inner.accounts().recover(recovery).map_err(|_| ClientError::InvalidSignature)
+    }
+
     /// Get current block number
     #[instrument(level = "debug")]
     pub async fn block_number(&self) -> Result<u64, ClientError> {
@@ -53,6 +75,10 @@ pub enum ClientError {
     /// Invalid block number
     #[error("Invalid block number")]
     InvalidBlockNumber,
+
+    /// Malformed signature, or one no signer can be recovered from
+    #[error("Invalid signature")]
+    InvalidSignature,
 }
 
 #[cfg(test)]
@@ -74,4 +100,21 @@ mod tests {
             assert!(true); // Placeholder assertion
         });
     }
+
+    #[test]
+    fn test_recover_personal_signer() {
+        let mut wallet = crate::wallet::Wallet::new();
+        let address = wallet.create_account().unwrap();
+        let key = wallet.signing_key(address).unwrap();
+        let client = Web3Client::connect("http://localhost:8545").unwrap();
+        let signed = client.web3().accounts().sign("Use this NFT as my Matrix avatar", &key);
+
+        let mut signature = signed.signature.0;
+        assert_eq!(client.recover_personal_signer("Use this NFT as my Matrix avatar", &signature).unwrap(), address);
+        // Wallets giving the bare recovery id are understood too
+        signature[64] -= 27;
+        assert_eq!(client.recover_personal_signer("Use this NFT as my Matrix avatar", &signature).unwrap(), address);
+        assert_ne!(client.recover_personal_signer("Another message", &signature).unwrap(), address);
+        assert!(matches!(client.recover_personal_signer("Use this NFT as my Matrix avatar", &signature[..64]), Err(ClientError::InvalidSignature)));
+    }
 }
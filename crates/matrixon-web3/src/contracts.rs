@@ -6,8 +6,11 @@
 //! Date: 2025-06-15
 
 use web3::{
-    types::{Address, H256},
-    contract::{Contract, Options},
+    types::{Address, H256, U256},
+    contract::{
+        tokens::{Detokenize, Tokenize},
+        Contract, Options,
+    },
     Transport,
     Web3,
 };
@@ -145,6 +148,81 @@ impl<T: Transport> ContractManager<T> {
     }
 }
 
+/// Token standards of NFTs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NftStandard {
+    /// ERC-721, one owner per token
+    Erc721,
+    /// ERC-1155, balances of each token per holder
+    Erc1155,
+}
+
+/// The views of ERC-721 and ERC-1155 telling who holds a token and where
+/// its metadata is
+const NFT_ABI: &str = r#"[
+    {"type": "function", "name": "ownerOf", "stateMutability": "view",
+     "inputs": [{"name": "tokenId", "type": "uint256"}], "outputs": [{"name": "", "type": "address"}]},
+    {"type": "function", "name": "tokenURI", "stateMutability": "view",
+     "inputs": [{"name": "tokenId", "type": "uint256"}], "outputs": [{"name": "", "type": "string"}]},
+    {"type": "function", "name": "balanceOf", "stateMutability": "view",
+     "inputs": [{"name": "account", "type": "address"}, {"name": "id", "type": "uint256"}], "outputs": [{"name": "", "type": "uint256"}]},
+    {"type": "function", "name": "uri", "stateMutability": "view",
+     "inputs": [{"name": "id", "type": "uint256"}], "outputs": [{"name": "", "type": "string"}]}
+]"#;
+
+/// Read-only binding of an ERC-721 or ERC-1155 token contract
+#[derive(Debug)]
+pub struct NftContract<T: Transport> {
+    contract: Contract<T>,
+    standard: NftStandard,
+}
+
+impl<T: Transport> NftContract<T> {
+    /// Bind the token contract at `address`
+    pub fn new(web3: &Web3<T>, address: Address, standard: NftStandard) -> std::result::Result<Self, ContractError> {
+        let contract = Contract::from_json(web3.eth(), address, NFT_ABI.as_bytes())?;
+        Ok(Self { contract, standard })
+    }
+
+    /// Whether `holder` holds the token `token_id`
+    #[instrument(level = "debug", skip(self))]
+    pub async fn holds(&self, holder: Address, token_id: U256) -> std::result::Result<bool, ContractError> {
+        match self.standard {
+            NftStandard::Erc721 => {
+                let owner: Address = self.query("ownerOf", (token_id,)).await?;
+                Ok(!owner.is_zero() && owner == holder)
+            }
+            NftStandard::Erc1155 => {
+                let balance: U256 = self.query("balanceOf", (holder, token_id)).await?;
+                Ok(!balance.is_zero())
+            }
+        }
+    }
+
+    /// URI of the metadata of `token_id`
+    #[instrument(level = "debug", skip(self))]
+    pub async fn metadata_uri(&self, token_id: U256) -> std::result::Result<String, ContractError> {
+        let function = match self.standard {
+            NftStandard::Erc721 => "tokenURI",
+            NftStandard::Erc1155 => "uri",
+        };
+        let uri: String = self.query(function, (token_id,)).await?;
+        Ok(substitute_token_id(&uri, token_id))
+    }
+
+    async fn query<R: Detokenize, P: Tokenize>(&self, function: &str, params: P) -> std::result::Result<R, ContractError> {
+        Ok(self.contract.query(function, params, None, Options::default(), None).await?)
+    }
+}
+
+/// ERC-1155 metadata URIs name the token as `{id}`, to be replaced with
+/// the token id in 64 lowercase hex digits
+fn substitute_token_id(uri: &str, token_id: U256) -> String {
+    let mut id = [0u8; 32];
+    token_id.to_big_endian(&mut id);
+    uri.replace("{id}", &hex::encode(id))
+}
+
 /// Contract-specific errors
 #[derive(Error, Debug)]
 pub enum ContractError {
@@ -212,4 +290,18 @@ mod tests {
         assert_eq!(manager.contracts.len(), 0);
         Ok(())
     }
+
+    #[test]
+    fn test_nft_contract_binding() {
+        let transport = Http::new("http://localhost:8545").unwrap();
+        let web3 = web3::Web3::new(transport);
+        let nft = NftContract::new(&web3, Address::repeat_byte(1), NftStandard::Erc1155).unwrap();
+        assert_eq!(nft.contract.address(), Address::repeat_byte(1));
+
+        assert_eq!(
+            substitute_token_id("ipfs://meta/{id}.json", 1000.into()),
+            "ipfs://meta/00000000000000000000000000000000000000000000000000000000000003e8.json"
+        );
+        assert_eq!(substitute_token_id("https://meta.example/7", 7.into()), "https://meta.example/7");
+    }
 }
@@ -44,7 +44,10 @@ pub mod prelude {
 pub type Web3Result<T> = Result<T, Web3Error>;
 
 // Include other modules
+#[cfg(feature = "anchoring")]
+pub mod anchor;
 pub mod client;
 pub mod contracts;
 pub mod events;
+pub mod relay;
 pub mod wallet;
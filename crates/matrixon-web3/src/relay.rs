@@ -0,0 +1,611 @@
+//! Transaction Relay Module
+//!
+//! Submits transactions on behalf of Matrix users through a server wallet.
+//! Every request is checked against a [`RelayPolicy`]: the target contract
+//! and method must be allowlisted, and the gas it may burn counts against a
+//! per-user daily gas budget and a server-wide daily spending cap. Relayed
+//! transactions are tracked until their receipt arrives, and the user is
+//! notified through Matrix once they are confirmed or fail; settled ones
+//! are forgotten after a retention window. The relay
+//! hands out the account's nonces itself, so concurrent submissions never
+//! sign two transactions with the same nonce.
+//! Author: arkSong (arksong2018@gmail.com)
+//! Version: 0.1.0
+//! Date: 2025-06-15
+
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{info, instrument, warn};
+use web3::{
+    types::{Address, BlockNumber, Bytes, TransactionParameters, H256, U256},
+    Transport, Web3,
+};
+
+use crate::wallet::{keccak256, Wallet};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+/// How long settled transactions stay queryable by default
+const DEFAULT_RETENTION: Duration = Duration::from_secs(7 * SECONDS_PER_DAY);
+
+/// Limits on what the relay submits
+#[derive(Debug, Clone, Default)]
+pub struct RelayPolicy {
+    /// Allowed contracts, each with the allowed method signatures such as
+    /// `transfer(address,uint256)`. An empty set allows every method.
+    pub allowed_contracts: HashMap<Address, HashSet<String>>,
+    /// Gas units a single user may use per day
+    pub user_daily_gas_budget: U256,
+    /// Wei the server wallet may spend on gas per day, over all users
+    pub daily_spend_cap: U256,
+    /// Largest gas limit of a single transaction
+    pub max_gas_per_transaction: U256,
+}
+
+impl RelayPolicy {
+    /// Allow calls of `methods` on `contract`; no methods allows all
+    pub fn allow_contract(mut self, contract: Address, methods: &[&str]) -> Self {
+        self.allowed_contracts
+            .entry(contract)
+            .or_default()
+            .extend(methods.iter().map(|method| method.to_string()));
+        self
+    }
+
+    /// Check that the policy allows calling `data` on `to`
+    fn check_target(&self, to: Address, data: &[u8]) -> Result<(), RelayError> {
+        let methods = self
+            .allowed_contracts
+            .get(&to)
+            .ok_or(RelayError::ContractNotAllowed(to))?;
+        if methods.is_empty() {
+            return Ok(());
+        }
+        let selector = data.get(..4).ok_or_else(|| RelayError::MethodNotAllowed("<none>".to_string()))?;
+        if methods.iter().any(|method| keccak256(method.as_bytes())[..4] == *selector) {
+            Ok(())
+        } else {
+            Err(RelayError::MethodNotAllowed(format!("0x{}", hex::encode(selector))))
+        }
+    }
+}
+
+/// A transaction a user asks the server to submit
+#[derive(Debug, Clone)]
+pub struct RelayRequest {
+    /// Matrix user the transaction is relayed for
+    pub user_id: String,
+    /// Contract to call
+    pub to: Address,
+    /// ABI-encoded call data
+    pub data: Bytes,
+    /// Gas limit of the transaction
+    pub gas_limit: U256,
+}
+
+/// State of a relayed transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RelayStatus {
+    /// Sent to the network, waiting for a receipt
+    Submitted,
+    /// Included in a block and successful
+    Confirmed {
+        /// Block the transaction was included in
+        block_number: u64,
+        /// Gas the transaction used
+        gas_used: U256,
+    },
+    /// Included in a block but reverted, or could not be sent
+    Failed(String),
+}
+
+/// A transaction submitted by the relay
+#[derive(Debug, Clone)]
+pub struct RelayedTransaction {
+    /// Hash of the transaction
+    pub tx_hash: H256,
+    /// The request it was made for
+    pub request: RelayRequest,
+    /// Gas price it was submitted with
+    pub gas_price: U256,
+    /// Current state
+    pub status: RelayStatus,
+    /// Unix time of the submission
+    pub submitted_at: u64,
+    /// Unix time the receipt arrived, once settled
+    pub settled_at: Option<u64>,
+}
+
+impl RelayedTransaction {
+    /// Whether the transaction settled more than `retention` before `now`
+    fn expired(&self, now: u64, retention: Duration) -> bool {
+        self.settled_at.is_some_and(|settled_at| now.saturating_sub(settled_at) > retention.as_secs())
+    }
+}
+
+/// Delivers relay notifications to Matrix users
+#[async_trait]
+pub trait MatrixNotifier: Send + Sync {
+    /// Send `body` to `user_id`
+    async fn notify(&self, user_id: &str, body: &str) -> Result<(), String>;
+}
+
+/// Sends notifications as `m.notice` messages of a bot account, through
+/// the client-server API of the homeserver. Each user gets a direct chat
+/// with the bot, created on the first notification after a start.
+pub struct ClientApiNotifier {
+    client: reqwest::Client,
+    homeserver: url::Url,
+    access_token: String,
+    /// Direct chat of each notified user
+    rooms: Mutex<HashMap<String, String>>,
+    next_txn: AtomicU64,
+}
+
+impl std::fmt::Debug for ClientApiNotifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientApiNotifier")
+            .field("homeserver", &self.homeserver)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientApiNotifier {
+    /// Notify as the bot whose access token is `access_token` on the
+    /// homeserver at `homeserver`
+    pub fn new(homeserver: url::Url, access_token: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            homeserver,
+            access_token,
+            rooms: Mutex::new(HashMap::new()),
+            next_txn: AtomicU64::new(0),
+        }
+    }
+
+    /// URL of a client-server API endpoint, from its path segments
+    fn endpoint(&self, segments: &[&str]) -> Result<url::Url, String> {
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .map_err(|_| format!("{} cannot be a homeserver URL", self.homeserver))?
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3"])
+            .extend(segments);
+        Ok(url)
+    }
+
+    /// Direct chat with `user_id`, created when there is none yet
+    async fn room_of(&self, user_id: &str) -> Result<String, String> {
+        let mut rooms = self.rooms.lock().await;
+        if let Some(room_id) = rooms.get(user_id) {
+            return Ok(room_id.clone());
+        }
+        let response: serde_json::Value = self
+            .client
+            .post(self.endpoint(&["createRoom"])?)
+            .bearer_auth(&self.access_token)
+            .json(&serde_json::json!({
+                "invite": [user_id],
+                "is_direct": true,
+                "preset": "trusted_private_chat",
+                "name": "Transaction relay",
+            }))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        let room_id = response["room_id"].as_str().ok_or("createRoom returned no room_id")?.to_string();
+        info!("💬 Created relay notification room {} for {}", room_id, user_id);
+        rooms.insert(user_id.to_string(), room_id.clone());
+        Ok(room_id)
+    }
+}
+
+#[async_trait]
+impl MatrixNotifier for ClientApiNotifier {
+    async fn notify(&self, user_id: &str, body: &str) -> Result<(), String> {
+        let room_id = self.room_of(user_id).await?;
+        let txn_id = format!("relay-{}-{}", now_secs(), self.next_txn.fetch_add(1, Ordering::Relaxed));
+        self.client
+            .put(self.endpoint(&["rooms", &room_id, "send", "m.room.message", &txn_id])?)
+            .bearer_auth(&self.access_token)
+            .json(&serde_json::json!({ "msgtype": "m.notice", "body": body }))
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+/// Gas accounted within the current day
+#[derive(Debug, Default)]
+struct Usage {
+    day: u64,
+    user_gas: HashMap<String, U256>,
+    spent: U256,
+}
+
+impl Usage {
+    fn roll_over(&mut self, now: u64) {
+        let day = now / SECONDS_PER_DAY;
+        if day != self.day {
+            *self = Usage { day, ..Default::default() };
+        }
+    }
+
+    /// Reserve the gas of a request, failing if a limit would be exceeded
+    fn reserve(&mut self, policy: &RelayPolicy, request: &RelayRequest, gas_price: U256, now: u64) -> Result<(), RelayError> {
+        self.roll_over(now);
+        if request.gas_limit > policy.max_gas_per_transaction {
+            return Err(RelayError::GasLimitTooHigh(request.gas_limit));
+        }
+        let user_gas = self.user_gas.get(&request.user_id).copied().unwrap_or_default();
+        if user_gas.saturating_add(request.gas_limit) > policy.user_daily_gas_budget {
+            return Err(RelayError::UserBudgetExceeded(request.user_id.clone()));
+        }
+        let cost = request.gas_limit.saturating_mul(gas_price);
+        if self.spent.saturating_add(cost) > policy.daily_spend_cap {
+            return Err(RelayError::DailyCapReached);
+        }
+        self.user_gas.insert(request.user_id.clone(), user_gas + request.gas_limit);
+        self.spent += cost;
+        Ok(())
+    }
+
+    /// Give back reserved gas that was not used
+    fn release(&mut self, user_id: &str, gas: U256, gas_price: U256, submitted_at: u64) {
+        if submitted_at / SECONDS_PER_DAY != self.day {
+            return;
+        }
+        if let Some(user_gas) = self.user_gas.get_mut(user_id) {
+            *user_gas = user_gas.saturating_sub(gas);
+        }
+        self.spent = self.spent.saturating_sub(gas.saturating_mul(gas_price));
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Relays user transactions through a server wallet
+pub struct TransactionRelay<T: Transport> {
+    web3: Web3<T>,
+    wallet: Wallet,
+    from: Address,
+    policy: RelayPolicy,
+    usage: RwLock<Usage>,
+    /// Nonce of the next transaction, read from the chain when unknown
+    next_nonce: Mutex<Option<U256>>,
+    transactions: RwLock<HashMap<H256, RelayedTransaction>>,
+    /// How long settled transactions are kept
+    retention: Duration,
+    notifier: Option<Arc<dyn MatrixNotifier>>,
+}
+
+impl<T: Transport> std::fmt::Debug for TransactionRelay<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TransactionRelay")
+            .field("from", &self.from)
+            .field("policy", &self.policy)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T: Transport> TransactionRelay<T> {
+    /// Create a relay paying from account `from` of `wallet`
+    #[instrument(level = "debug", skip(web3, wallet))]
+    pub fn new(web3: Web3<T>, wallet: Wallet, from: Address, policy: RelayPolicy) -> Result<Self, RelayError> {
+        if !wallet.addresses().contains(&from) {
+            return Err(RelayError::UnknownAccount(from));
+        }
+        info!("🔧 Initializing TransactionRelay for {:?}", from);
+        Ok(Self {
+            web3,
+            wallet,
+            from,
+            policy,
+            usage: RwLock::new(Usage::default()),
+            next_nonce: Mutex::new(None),
+            transactions: RwLock::new(HashMap::new()),
+            retention: DEFAULT_RETENTION,
+            notifier: None,
+        })
+    }
+
+    /// Keep settled transactions for `retention` instead of a week
+    pub fn with_retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Notify users through Matrix when their transactions settle
+    pub fn with_notifier(mut self, notifier: Arc<dyn MatrixNotifier>) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
+    /// Check a request against the policy, sign it with the server wallet
+    /// and send it. Returns the transaction hash.
+    #[instrument(level = "debug", skip(self))]
+    pub async fn submit(&self, request: RelayRequest) -> Result<H256, RelayError> {
+        self.policy.check_target(request.to, &request.data.0)?;
+        let gas_price = self.web3.eth().gas_price().await?;
+        let now = now_secs();
+        self.usage.write().await.reserve(&self.policy, &request, gas_price, now)?;
+
+        let key = self.wallet.signing_key(self.from).ok_or(RelayError::UnknownAccount(self.from))?;
+        // The nonce stays locked until the transaction is sent, so taking it
+        // is what keeps other submissions from using it too
+        let mut next_nonce = self.next_nonce.lock().await;
+        let sent = async {
+            let nonce = match *next_nonce {
+                Some(nonce) => nonce,
+                None => self.web3.eth().transaction_count(self.from, Some(BlockNumber::Pending)).await?,
+            };
+            let parameters = TransactionParameters {
+                nonce: Some(nonce),
+                to: Some(request.to),
+                data: request.data.clone(),
+                gas: request.gas_limit,
+                gas_price: Some(gas_price),
+                ..Default::default()
+            };
+            let signed = self.web3.accounts().sign_transaction(parameters, &key).await?;
+            let tx_hash = self.web3.eth().send_raw_transaction(signed.raw_transaction).await?;
+            Ok::<_, web3::Error>((tx_hash, nonce))
+        }
+        .await;
+        let tx_hash = match sent {
+            Ok((tx_hash, nonce)) => {
+                *next_nonce = Some(nonce + 1);
+                tx_hash
+            }
+            Err(e) => {
+                // Whether the node saw the transaction is unknown: read the
+                // nonce from the chain again next time
+                *next_nonce = None;
+                drop(next_nonce);
+                self.usage.write().await.release(&request.user_id, request.gas_limit, gas_price, now);
+                return Err(e.into());
+            }
+        };
+        drop(next_nonce);
+
+        info!("📤 Relayed transaction {:?} for {}", tx_hash, request.user_id);
+        let transaction = RelayedTransaction {
+            tx_hash,
+            request,
+            gas_price,
+            status: RelayStatus::Submitted,
+            submitted_at: now,
+            settled_at: None,
+        };
+        self.transactions.write().await.insert(tx_hash, transaction);
+        Ok(tx_hash)
+    }
+
+    /// A relayed transaction
+    pub async fn transaction(&self, tx_hash: H256) -> Option<RelayedTransaction> {
+        self.transactions.read().await.get(&tx_hash).cloned()
+    }
+
+    /// Relayed transactions of a user
+    pub async fn transactions_of(&self, user_id: &str) -> Vec<RelayedTransaction> {
+        self.transactions
+            .read()
+            .await
+            .values()
+            .filter(|transaction| transaction.request.user_id == user_id)
+            .cloned()
+            .collect()
+    }
+
+    /// Fetch receipts of submitted transactions, update their status and
+    /// notify their users. Unused reserved gas is returned to the budgets,
+    /// and transactions settled longer than the retention ago are dropped.
+    /// Returns the transactions that settled.
+    #[instrument(level = "debug", skip(self))]
+    pub async fn poll_receipts(&self) -> Result<Vec<RelayedTransaction>, RelayError> {
+        let pending: Vec<H256> = {
+            let mut transactions = self.transactions.write().await;
+            let (now, retention) = (now_secs(), self.retention);
+            transactions.retain(|_, transaction| !transaction.expired(now, retention));
+            transactions
+                .values()
+                .filter(|transaction| transaction.status == RelayStatus::Submitted)
+                .map(|transaction| transaction.tx_hash)
+                .collect()
+        };
+
+        let mut settled = Vec::new();
+        for tx_hash in pending {
+            let Some(receipt) = self.web3.eth().transaction_receipt(tx_hash).await? else {
+                continue;
+            };
+            let Some(block_number) = receipt.block_number else {
+                continue;
+            };
+            let gas_used = receipt.gas_used.unwrap_or_default();
+            let status = if receipt.status.is_some_and(|status| status.as_u64() == 1) {
+                RelayStatus::Confirmed { block_number: block_number.as_u64(), gas_used }
+            } else {
+                RelayStatus::Failed("reverted".to_string())
+            };
+
+            let transaction = {
+                let mut transactions = self.transactions.write().await;
+                let Some(transaction) = transactions.get_mut(&tx_hash) else {
+                    continue;
+                };
+                transaction.status = status;
+                transaction.settled_at = Some(now_secs());
+                transaction.clone()
+            };
+            let unused = transaction.request.gas_limit.saturating_sub(gas_used);
+            self.usage
+                .write()
+                .await
+                .release(&transaction.request.user_id, unused, transaction.gas_price, transaction.submitted_at);
+            self.notify(&transaction).await;
+            settled.push(transaction);
+        }
+        Ok(settled)
+    }
+
+    async fn notify(&self, transaction: &RelayedTransaction) {
+        let Some(notifier) = &self.notifier else {
+            return;
+        };
+        let body = match &transaction.status {
+            RelayStatus::Confirmed { block_number, .. } => {
+                format!("✅ Transaction {:?} was confirmed in block {}", transaction.tx_hash, block_number)
+            }
+            RelayStatus::Failed(reason) => format!("❌ Transaction {:?} failed: {}", transaction.tx_hash, reason),
+            RelayStatus::Submitted => return,
+        };
+        if let Err(e) = notifier.notify(&transaction.request.user_id, &body).await {
+            warn!("⚠️ Could not notify {}: {}", transaction.request.user_id, e);
+        }
+    }
+}
+
+impl<T> TransactionRelay<T>
+where
+    T: Transport + Send + Sync + 'static,
+    T::Out: Send,
+{
+    /// Poll the receipts of submitted transactions every `interval` in the
+    /// background
+    pub fn spawn(self: Arc<Self>, interval: Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.poll_receipts().await {
+                    warn!("⚠️ Could not poll relayed transaction receipts: {}", e);
+                }
+            }
+        })
+    }
+}
+
+/// Relay-specific errors
+#[derive(Error, Debug)]
+pub enum RelayError {
+    /// Web3 provider error
+    #[error("Web3 error: {0}")]
+    Web3(#[from] web3::Error),
+
+    /// The wallet has no such account
+    #[error("Unknown relay account: {0:?}")]
+    UnknownAccount(Address),
+
+    /// The contract is not on the allowlist
+    #[error("Contract not allowed: {0:?}")]
+    ContractNotAllowed(Address),
+
+    /// The method is not allowed on the contract
+    #[error("Method not allowed: {0}")]
+    MethodNotAllowed(String),
+
+    /// The gas limit exceeds the per-transaction maximum
+    #[error("Gas limit too high: {0}")]
+    GasLimitTooHigh(U256),
+
+    /// The user used up their daily gas budget
+    #[error("Daily gas budget of {0} exceeded")]
+    UserBudgetExceeded(String),
+
+    /// The server reached its daily spending cap
+    #[error("Daily relay spending cap reached")]
+    DailyCapReached,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_log::test;
+
+    fn policy(contract: Address) -> RelayPolicy {
+        RelayPolicy {
+            user_daily_gas_budget: 100_000.into(),
+            daily_spend_cap: 150_000.into(),
+            max_gas_per_transaction: 60_000.into(),
+            ..Default::default()
+        }
+        .allow_contract(contract, &["transfer(address,uint256)"])
+    }
+
+    fn request(user_id: &str, to: Address, gas_limit: u64) -> RelayRequest {
+        RelayRequest { user_id: user_id.to_string(), to, data: Bytes(vec![0xa9, 0x05, 0x9c, 0xbb]), gas_limit: gas_limit.into() }
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let contract = Address::repeat_byte(1);
+        let policy = policy(contract);
+        // 0xa9059cbb is the selector of transfer(address,uint256)
+        assert!(policy.check_target(contract, &[0xa9, 0x05, 0x9c, 0xbb, 0]).is_ok());
+        assert!(matches!(policy.check_target(contract, &[0x09, 0x5e, 0xa7, 0xb3]), Err(RelayError::MethodNotAllowed(_))));
+        assert!(matches!(policy.check_target(Address::repeat_byte(2), &[]), Err(RelayError::ContractNotAllowed(_))));
+    }
+
+    #[test]
+    fn test_budgets_and_daily_cap() {
+        let contract = Address::repeat_byte(1);
+        let policy = policy(contract);
+        let mut usage = Usage::default();
+        let now = 10 * SECONDS_PER_DAY;
+
+        assert!(matches!(usage.reserve(&policy, &request("@a:x", contract, 70_000), 1.into(), now), Err(RelayError::GasLimitTooHigh(_))));
+        usage.reserve(&policy, &request("@a:x", contract, 60_000), 1.into(), now).unwrap();
+        assert!(matches!(usage.reserve(&policy, &request("@a:x", contract, 50_000), 1.into(), now), Err(RelayError::UserBudgetExceeded(_))));
+        usage.reserve(&policy, &request("@b:x", contract, 60_000), 1.into(), now).unwrap();
+        assert!(matches!(usage.reserve(&policy, &request("@c:x", contract, 40_000), 1.into(), now), Err(RelayError::DailyCapReached)));
+
+        // Unused gas is returned, and budgets reset the next day
+        usage.release("@a:x", 30_000.into(), 1.into(), now);
+        usage.reserve(&policy, &request("@c:x", contract, 30_000), 1.into(), now).unwrap();
+        usage.reserve(&policy, &request("@a:x", contract, 60_000), 1.into(), now + SECONDS_PER_DAY).unwrap();
+    }
+
+    #[test]
+    fn test_settled_transactions_expire() {
+        let mut transaction = RelayedTransaction {
+            tx_hash: H256::repeat_byte(1),
+            request: request("@a:x", Address::repeat_byte(1), 21_000),
+            gas_price: 1.into(),
+            status: RelayStatus::Submitted,
+            submitted_at: 1_000,
+            settled_at: None,
+        };
+        let retention = Duration::from_secs(60);
+        // Submitted transactions are kept however long they wait
+        assert!(!transaction.expired(1_000_000, retention));
+
+        transaction.status = RelayStatus::Confirmed { block_number: 7, gas_used: 21_000.into() };
+        transaction.settled_at = Some(2_000);
+        assert!(!transaction.expired(2_060, retention));
+        assert!(transaction.expired(2_061, retention));
+    }
+
+    #[test]
+    fn test_notifier_endpoints_escape_room_ids() {
+        let notifier = ClientApiNotifier::new("https://matrix.example/".parse().unwrap(), "token".to_string());
+        let url = notifier.endpoint(&["rooms", "!room/id:matrix.example", "send", "m.room.message", "relay-1-0"]).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://matrix.example/_matrix/client/v3/rooms/!room%2Fid:matrix.example/send/m.room.message/relay-1-0"
+        );
+    }
+}
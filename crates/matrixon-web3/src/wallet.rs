@@ -73,6 +73,12 @@ impl Wallet {
     pub fn addresses(&self) -> Vec<H160> {
         self.accounts.iter().map(|a| a.address).collect()
     }
+
+    /// Key of a managed account, in the form web3 signs transactions with
+    pub(crate) fn signing_key(&self, address: H160) -> Option<web3::signing::SecretKey> {
+        let account = self.accounts.iter().find(|a| a.address == address)?;
+        web3::signing::SecretKey::from_slice(&account.secret_key.secret_bytes()).ok()
+    }
 }
 
 /// Convert public key to Ethereum address
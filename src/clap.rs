@@ -146,6 +146,10 @@ pub enum Commands {
         /// Run in daemon mode
         #[clap(short, long, help = "Run as daemon in background")]
         daemon: bool,
+
+        /// Wait for the database and other dependencies to come up
+        #[clap(long, help = "Keep retrying dependencies until they are up or startup.wait_timeout_s passes")]
+        wait_for_deps: bool,
     },
     
     /// User management commands
@@ -275,6 +279,19 @@ pub enum RoomCommands {
         /// Block room from being recreated
         #[clap(long, help = "Block room from being recreated")]
         block: bool,
+        
+        /// Reason given to the local members removed from the room
+        #[clap(long, help = "Reason shown to removed members")]
+        reason: Option<String>,
+        
+        /// Access token of a server admin
+        #[clap(long, env = "MATRIXON_ADMIN_TOKEN", hide_env_values = true, help = "Admin access token")]
+        access_token: String,
+        
+        /// Base URL of the running server, by default its configured
+        /// address and port
+        #[clap(long, help = "Server base URL")]
+        server_url: Option<String>,
     },
     
     /// List all rooms
@@ -358,6 +375,42 @@ pub enum DatabaseCommands {
         force: bool,
     },
     
+    /// Archive a WAL segment; set as the PostgreSQL `archive_command`,
+    /// e.g. `matrixon --config <path> database archive-wal --path %p --name %f`
+    ArchiveWal {
+        /// Path of the WAL file (%p)
+        #[clap(long, help = "Path of the WAL file")]
+        path: PathBuf,
+        
+        /// Name of the WAL file (%f)
+        #[clap(long, help = "Name of the WAL file")]
+        name: String,
+    },
+    
+    /// Take a base backup for point-in-time recovery
+    BaseBackup,
+    
+    /// Show the WAL archive and the database's archiving state
+    WalStatus,
+    
+    /// Remove base backups beyond retention and the WAL only they needed
+    PruneWal {
+        /// Base backups to keep
+        #[clap(short, long, help = "Base backups to keep")]
+        keep: Option<usize>,
+    },
+    
+    /// Prepare a data directory recovering the database to a point in time
+    RestoreToTime {
+        /// Target time in RFC 3339 format, e.g. 2025-06-15T13:30:00Z
+        #[clap(short, long, help = "Target time")]
+        target: String,
+        
+        /// Empty PostgreSQL data directory to restore into
+        #[clap(short, long, help = "Data directory")]
+        data_dir: PathBuf,
+    },
+    
     /// Show database statistics
     Stats {
         /// Show detailed statistics
@@ -407,6 +460,22 @@ pub enum AdminCommands {
         #[clap(short, long, help = "Configuration file")]
         config: Option<PathBuf>,
     },
+    
+    /// Issue a certificate of the internal CA for mutual TLS between
+    /// components, creating the CA first if needed
+    InternalCa {
+        /// Directory of the CA and the issued certificates
+        #[clap(short, long, help = "CA directory")]
+        dir: PathBuf,
+        
+        /// Component the certificate is for, e.g. monitor or worker1
+        #[clap(short, long, help = "Component name")]
+        component: String,
+        
+        /// DNS names and IP addresses the component is reached at
+        #[clap(long = "host", help = "Host name or IP address")]
+        hosts: Vec<String>,
+    },
 }
 
 /// Parse command line arguments into structured data
@@ -497,6 +566,7 @@ mod tests {
                 port: None,
                 no_federation: false,
                 daemon: false,
+                wait_for_deps: false,
             },
         };
         
@@ -514,6 +584,7 @@ mod tests {
                 port: None,
                 no_federation: false,
                 daemon: false,
+                wait_for_deps: false,
             },
         };
         assert_eq!(args, args2, "Empty Args should be equal");
@@ -531,6 +602,7 @@ mod tests {
                 port: None,
                 no_federation: false,
                 daemon: false,
+                wait_for_deps: false,
             },
         };
         
@@ -544,6 +616,7 @@ mod tests {
                 port: None,
                 no_federation: false,
                 daemon: false,
+                wait_for_deps: false,
             },
         }, "Default Args construction should work");
     }
@@ -596,6 +669,7 @@ mod tests {
                 port: None,
                 no_federation: false,
                 daemon: false,
+                wait_for_deps: false,
             },
         };
         
@@ -617,6 +691,7 @@ mod tests {
                 port: None,
                 no_federation: false,
                 daemon: false,
+                wait_for_deps: false,
             },
         };
         assert_eq!(args, other_args, "Equal Args should be equal");
@@ -649,6 +724,7 @@ mod tests {
                 port: None,
                 no_federation: false,
                 daemon: false,
+                wait_for_deps: false,
             },
         };
         let size = size_of_val(&args);
@@ -33,13 +33,58 @@ pub use matrixon_core as core;
 pub struct Config {
     // Basic server configuration
     pub server_name: String,
+    // Deprecated: a single listener serving every API on `address` and
+    // `port`, used when `listeners` is unset
     pub address: std::net::IpAddr,
     pub port: u16,
+    // Sockets the server listens on and the APIs each one serves
+    pub listeners: Option<Vec<config::ListenerConfig>>,
+    // Bind with SO_REUSEPORT so a new instance can take over the port
+    // while the previous one drains, defaults to false
+    pub reuse_port: Option<bool>,
+    // What to do when the port is already taken, fails by default
+    pub port_conflict: Option<config::PortConflictConfig>,
+    // Order, retries and required services of startup, see
+    // `config::StartupConfig`
+    pub startup: Option<config::StartupConfig>,
+    // Sections of the workspace crates, `[global.federation]`,
+    // `[global.iot]`, `[global.monitor]` and `[global.backup]`, each read
+    // into the crate's own configuration type over its defaults, see
+    // `Config::sections`. `[global.ipfs]` is read by nothing in the server,
+    // which only uses `ipfs_gateway`.
+    pub federation: Option<serde_json::Value>,
+    pub iot: Option<serde_json::Value>,
+    pub ipfs: Option<serde_json::Value>,
+    pub monitor: Option<serde_json::Value>,
+    pub backup: Option<serde_json::Value>,
     
     // Database configuration
     pub database_backend: Option<String>,
     pub database_path: Option<String>,
     pub database_url: String,
+    // Read replicas of the PostgreSQL database for read-only queries
+    pub database_replica_urls: Option<Vec<String>>,
+    // Replication lag in seconds above which reads go to the primary,
+    // defaults to 10
+    pub database_max_replica_lag_s: Option<u64>,
+    // PostgreSQL databases room events and state are partitioned over by
+    // room id; changing the list moves rooms to other shards
+    pub database_shard_urls: Option<Vec<String>>,
+    // Most timeline events written to the database in one batch,
+    // defaults to 256
+    pub event_persistence_batch_size: Option<usize>,
+    // Longest an event waits for its batch to fill before it is written,
+    // defaults to 5 ms
+    pub event_persistence_max_delay_ms: Option<u64>,
+    // Events waiting to be written above which requests that may add
+    // events wait for the queue to drain, defaults to 10000
+    pub event_persistence_queue_size: Option<usize>,
+    // Rooms joined over federation at once, later joins are queued;
+    // defaults to 4
+    pub max_concurrent_federated_joins: Option<usize>,
+    // How long a join request waits for a federated join before the client
+    // is asked to retry, defaults to 60 s
+    pub federated_join_wait_s: Option<u64>,
     
     // Feature flags
     pub allow_registration: bool,
@@ -58,6 +103,8 @@ pub struct Config {
     // Performance settings
     pub max_request_size: u64,
     pub matrixon_cache_capacity_modifier: Option<f64>,
+    // Seconds between database and media maintenance runs: vacuuming,
+    // index bloat checks and orphaned media cleanup; disabled when unset
     pub cleanup_second_intervals: Option<u64>,
     pub worker_threads: Option<usize>,
     pub blocking_threads: Option<usize>,
@@ -71,6 +118,11 @@ pub struct Config {
     // Security settings
     pub registration_token: Option<String>,
     pub emergency_password: Option<String>,
+    // Cross-origin requests of web clients: any origin may use the Matrix
+    // APIs and none the admin API unless configured otherwise
+    pub cors: Option<config::CorsConfig>,
+    // Security headers of responses, by route class (`[security_headers]`)
+    pub security_headers: Option<config::SecurityHeadersConfig>,
     
     // OpenID and authentication
     pub openid_token_ttl: Option<u64>,
@@ -105,11 +157,19 @@ pub struct Config {
     pub backup_directory: Option<String>,
     pub backup_retention_days: Option<u32>,
     pub enable_point_in_time_recovery: Option<bool>,
+    // Directory WAL segments and base backups are archived to for
+    // point-in-time recovery, defaults to `wal_archive` below
+    // `backup_directory`
+    pub wal_archive_dir: Option<String>,
+    // Base backups kept when the WAL archive is pruned, defaults to 2
+    pub wal_archive_keep_base_backups: Option<usize>,
     
     // Monitoring and metrics
     pub enable_metrics: Option<bool>,
     pub metrics_port: Option<u16>,
     pub metrics_path: Option<String>,
+    // Readiness endpoint aggregating the health of every subsystem,
+    // defaults to /_matrixon/ready
     pub ready_path: Option<String>,
     
     // Advanced networking
@@ -122,11 +182,19 @@ pub struct Config {
     // Memory management
     pub memory_cleanup_interval_s: Option<u64>,
     pub max_memory_usage_mb: Option<u64>,
+    // Where the admin API dumps heap profiles; without it the admin API
+    // does not dump them
+    pub heap_profile_dir: Option<String>,
     
-    // Database connection pooling
+    // Database connection pooling; the pool tunes how many connections it
+    // hands out between the minimum and maximum, defaults 10 and 100
     pub db_pool_max_connections: Option<u32>,
     pub db_pool_min_connections: Option<u32>,
     pub db_pool_connection_timeout_s: Option<u64>,
+    // Prepared statements cached per connection, default 100, 0 disables
+    // the cache; queries slower than the threshold are logged, default 500
+    pub db_statement_cache_capacity: Option<usize>,
+    pub db_slow_query_threshold_ms: Option<u64>,
     
     // Compression settings
     pub enable_compression: Option<bool>,
@@ -154,6 +222,8 @@ pub struct Config {
     
     // Room settings
     pub max_rooms_per_user: Option<u32>,
+    pub auto_join_rooms: Option<Vec<String>>,
+    pub large_room_member_threshold: Option<usize>,
     pub room_cleanup_interval_s: Option<u64>,
     
     // Event processing
@@ -166,6 +236,7 @@ pub struct Config {
     
     // Admin settings
     pub admin_contact: Option<String>,
+    pub admin_users: Option<Vec<String>>,
     pub support_page: Option<String>,
     
     // Resource limits
@@ -205,18 +276,401 @@ pub struct Config {
     pub user_cache_ttl_s: Option<u64>,
     pub room_cache_ttl_s: Option<u64>,
     pub device_cache_ttl_s: Option<u64>,
+    
+    // Room encryption policy
+    pub encryption_policy: Option<config::EncryptionPolicyConfig>,
+    
+    // Third-party identifiers (email, phone numbers)
+    pub threepid: Option<config::ThreepidConfig>,
+    
+    // Pages rendered by the server itself (3PID confirmation, consent)
+    pub pages: Option<config::PagesConfig>,
+    
+    // Consent to the server's policy, asked for at `/_matrix/consent`
+    pub consent: Option<config::ConsentConfig>,
+    
+    // Translations of emails and server-rendered pages
+    pub localization: Option<config::LocalizationConfig>,
+    
+    // Outgoing email (SMTP) for validation tokens, notification digests
+    // and alerts
+    pub email: Option<matrixon_email::EmailConfig>,
+    
+    // Delegated authentication (MSC2965)
+    pub delegated_auth: Option<config::DelegatedAuthConfig>,
+    
+    // Admin impersonation of users for support
+    pub impersonation: Option<config::ImpersonationConfig>,
+    
+    // Application services (`[[global.appservices]]`), which act as the
+    // users of their namespaces with their `as_token`
+    pub appservices: Option<Vec<config::AppserviceConfig>>,
+    
+    // Outbound webhooks for server lifecycle events
+    pub webhooks: Option<Vec<matrixon_core::webhooks::WebhookConfig>>,
+    
+    // Export of the event stream to Kafka or NATS
+    pub event_export: Option<config::EventExportConfig>,
+    
+    // Escalation of reported events to room moderators, off unless set
+    // here or enabled by a room
+    pub report_escalation: Option<config::ReportEscalationConfig>,
+    
+    // Daily message, reaction and active user counts per room for the
+    // admin API, disabled when unset
+    pub room_stats: Option<config::RoomStatsConfig>,
+    
+    // Outgoing webhooks room admins register under
+    // /_matrixon/client/v1/rooms/{roomId}/webhooks, disabled when unset
+    pub room_webhooks: Option<config::RoomWebhooksConfig>,
+    
+    // Purging of room history past its m.room.retention lifetime, or the
+    // server default; history is kept forever when unset
+    pub retention: Option<config::RetentionConfig>,
+    
+    // Directory of the persisted federation sending queues, defaults to
+    // `federation_queue` below `database_path`
+    pub federation_queue_path: Option<String>,
+    
+    // Log of processed inbound federation transactions, so replays are
+    // answered from it, defaults to `federation_transactions.jsonl` below
+    // `database_path`
+    pub federation_transactions_path: Option<String>,
+    
+    // How long processed inbound transactions are remembered, defaults to
+    // a day
+    pub federation_transaction_ttl_s: Option<u64>,
+    
+    // Directory every processed inbound transaction is recorded to as a
+    // sanitized fixture for replay in tests, off by default
+    pub federation_fixture_dir: Option<String>,
+    
+    // File holding the server's ed25519 signing key, defaults to
+    // `signing_key.json` below `database_path`
+    pub signing_key_path: Option<String>,
+    
+    // Notary servers asked for the keys of servers that cannot be reached
+    // directly, defaults to matrix.org
+    pub trusted_servers: Option<Vec<String>>,
+    
+    // Verified NFT avatars, disabled when unset
+    pub nft_avatar: Option<config::NftAvatarConfig>,
+    
+    // IPFS HTTP gateway resolving `ipfs://` URIs and CIDs, defaults to
+    // https://ipfs.io
+    pub ipfs_gateway: Option<String>,
+    
+    // File keeping the keys of hot cache entries across restarts, defaults
+    // to `cache_snapshot.json` below `database_path`
+    pub cache_snapshot_path: Option<String>,
 }
 
 impl Config {
     pub fn warn_deprecated(&self) {
+        if self.listeners.is_none() {
+            tracing::warn!("`address` and `port` are deprecated, configure `listeners` instead");
+        }
         tracing::info!("Configuration loaded successfully");
     }
+
+    /// Configured listeners, or a single one on `address` and `port`
+    pub fn listeners(&self) -> Vec<config::ListenerConfig> {
+        self.listeners.clone().unwrap_or_else(|| {
+            vec![config::ListenerConfig {
+                bind: Some(std::net::SocketAddr::from((self.address, self.port))),
+                ..Default::default()
+            }]
+        })
+    }
+
+    /// Rooms with more members than this only sync the members relevant to
+    /// the client; the rest is fetched through /members
+    pub fn large_room_member_threshold(&self) -> usize {
+        self.large_room_member_threshold.unwrap_or(50_000)
+    }
+
+    /// Effective third-party identifier settings
+    pub fn threepid(&self) -> config::ThreepidConfig {
+        self.threepid.clone().unwrap_or_default()
+    }
+
+    /// Effective settings of the server-rendered pages
+    pub fn pages(&self) -> config::PagesConfig {
+        self.pages.clone().unwrap_or_default()
+    }
+
+    /// Effective localization settings
+    pub fn localization(&self) -> config::LocalizationConfig {
+        self.localization.clone().unwrap_or_default()
+    }
+
+    /// Path of the readiness endpoint
+    pub fn ready_path(&self) -> &str {
+        self.ready_path.as_deref().unwrap_or("/_matrixon/ready")
+    }
+
+    /// Where outgoing federation queues are persisted, if anywhere
+    pub fn federation_queue_path(&self) -> Option<std::path::PathBuf> {
+        self.federation_queue_path
+            .as_ref()
+            .map(std::path::PathBuf::from)
+            .or_else(|| self.database_path.as_ref().map(|path| std::path::Path::new(path).join("federation_queue")))
+    }
+
+    /// Where processed inbound federation transactions are logged, if anywhere
+    pub fn federation_transactions_path(&self) -> Option<std::path::PathBuf> {
+        self.federation_transactions_path
+            .as_ref()
+            .map(std::path::PathBuf::from)
+            .or_else(|| self.database_path.as_ref().map(|path| std::path::Path::new(path).join("federation_transactions.jsonl")))
+    }
+
+    /// How long processed inbound transactions are remembered, in milliseconds
+    pub fn federation_transaction_ttl_ms(&self) -> u64 {
+        self.federation_transaction_ttl_s
+            .map_or(service::inbound_federation::DEFAULT_TRANSACTION_TTL_MS, |ttl_s| ttl_s * 1000)
+    }
+
+    /// Where a service keeps its state across restarts, below `database_path`;
+    /// the state is only kept in memory when that is unset
+    pub fn state_path(&self, name: &str) -> Option<std::path::PathBuf> {
+        self.database_path.as_ref().map(|path| std::path::Path::new(path).join(name))
+    }
+
+    /// Where the server signing key is persisted, if anywhere
+    pub fn signing_key_path(&self) -> Option<std::path::PathBuf> {
+        self.signing_key_path
+            .as_ref()
+            .map(std::path::PathBuf::from)
+            .or_else(|| self.database_path.as_ref().map(|path| std::path::Path::new(path).join("signing_key.json")))
+    }
+
+    /// Handling of an address already in use
+    pub fn port_conflict(&self) -> config::PortConflictConfig {
+        self.port_conflict.clone().unwrap_or_default()
+    }
+
+    /// Retries of the services the server depends on at startup
+    pub fn startup(&self) -> config::StartupConfig {
+        self.startup.clone().unwrap_or_default()
+    }
+
+    /// Tables of the workspace crates' sections, to be read with
+    /// `ConfigSections::load` into each crate's configuration type
+    pub fn sections(&self) -> matrixon_core::config::ConfigSections {
+        let tables = [
+            ("federation", &self.federation),
+            ("iot", &self.iot),
+            ("ipfs", &self.ipfs),
+            ("monitor", &self.monitor),
+            ("backup", &self.backup),
+        ];
+        matrixon_core::config::ConfigSections::new(
+            tables.into_iter().filter_map(|(name, table)| Some((name.to_owned(), table.clone()?))),
+        )
+    }
+
+    /// Effective settings of the federation client
+    pub fn federation(&self) -> matrixon_core::Result<matrixon_federation::FederationConfig> {
+        self.sections().load_checked().map(|section| section.config)
+    }
+
+    /// Effective settings of the IoT gateway, which only runs when the
+    /// `[global.iot]` section is present
+    pub fn iot(&self) -> matrixon_core::Result<Option<matrixon_iot::IoTConfig>> {
+        if self.iot.is_none() {
+            return Ok(None);
+        }
+        self.sections().load_checked().map(|section| Some(section.config))
+    }
+
+    /// Effective settings of the monitor, which only runs when the
+    /// `[global.monitor]` section is present
+    pub fn monitor(&self) -> matrixon_core::Result<Option<matrixon_monitor::config::MonitorConfig>> {
+        if self.monitor.is_none() {
+            return Ok(None);
+        }
+        self.sections().load_checked().map(|section| Some(section.config))
+    }
+
+    /// Read every crate section present, failing on the first invalid one,
+    /// and return the dotted paths of the keys they do not know. Nothing
+    /// reads `[global.ipfs]`, so all of its keys are unknown.
+    pub fn check_sections(&self) -> matrixon_core::Result<Vec<String>> {
+        fn unknown_keys<T: matrixon_core::config::ConfigSection>(
+            sections: &matrixon_core::config::ConfigSections,
+        ) -> matrixon_core::Result<Vec<String>> {
+            let section = sections.load_checked::<T>()?;
+            Ok(section.unknown_keys.into_iter().map(|key| format!("{}.{}", T::SECTION, key)).collect())
+        }
+
+        let sections = self.sections();
+        let mut unknown = unknown_keys::<matrixon_federation::FederationConfig>(&sections)?;
+        unknown.extend(unknown_keys::<matrixon_iot::IoTConfig>(&sections)?);
+        unknown.extend(unknown_keys::<matrixon_monitor::config::MonitorConfig>(&sections)?);
+        unknown.extend(unknown_keys::<matrixon_backup::BackupConfig>(&sections)?);
+        if let Some(ipfs) = sections.table("ipfs").and_then(|table| table.as_object()) {
+            unknown.extend(ipfs.keys().map(|key| format!("ipfs.{}", key)));
+        }
+        Ok(unknown)
+    }
+
+    /// Time without requests after which a user counts as offline
+    pub fn presence_offline_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.presence_offline_timeout_s.unwrap_or(300))
+    }
+
+    /// Dotted paths of the keys of `file`, the configuration file as read,
+    /// that no setting reads; keys of the crate sections are checked when
+    /// the section is loaded
+    pub fn unknown_keys(&self, file: &serde_json::Value) -> Vec<String> {
+        serde_json::to_value(self)
+            .map(|known| matrixon_core::config::unknown_keys(file, &known))
+            .unwrap_or_default()
+    }
+
+    /// Where the pid of the running instance is recorded, if anywhere
+    pub fn pidfile(&self) -> Option<std::path::PathBuf> {
+        self.port_conflict
+            .as_ref()
+            .and_then(|port_conflict| port_conflict.pidfile.as_ref())
+            .map(std::path::PathBuf::from)
+            .or_else(|| self.database_path.as_ref().map(|path| std::path::Path::new(path).join("matrixon.pid")))
+    }
+
+    /// Where the cache warm-up snapshot is kept, if anywhere
+    pub fn cache_snapshot_path(&self) -> Option<std::path::PathBuf> {
+        self.cache_snapshot_path
+            .as_ref()
+            .map(std::path::PathBuf::from)
+            .or_else(|| self.database_path.as_ref().map(|path| std::path::Path::new(path).join("cache_snapshot.json")))
+    }
+
+    /// Notary servers for remote signing keys
+    pub fn trusted_servers(&self) -> Vec<String> {
+        self.trusted_servers.clone().unwrap_or_else(|| vec!["matrix.org".to_owned()])
+    }
+
+    /// IPFS HTTP gateway
+    pub fn ipfs_gateway(&self) -> &str {
+        self.ipfs_gateway.as_deref().unwrap_or("https://ipfs.io")
+    }
+
+    /// Effective admin impersonation settings
+    pub fn impersonation(&self) -> config::ImpersonationConfig {
+        self.impersonation.clone().unwrap_or_default()
+    }
+
+    /// Effective CORS policy
+    pub fn cors(&self) -> config::CorsConfig {
+        self.cors.clone().unwrap_or_default()
+    }
+
+    /// Effective security header policy
+    pub fn security_headers(&self) -> config::SecurityHeadersConfig {
+        self.security_headers.clone().unwrap_or_default()
+    }
+
+    /// Whether the server itself terminates TLS, rather than a reverse proxy
+    pub fn serves_tls(&self, listener: &config::ListenerConfig) -> bool {
+        listener.internal_tls.is_some() || self.tls_certificate_path.is_some()
+    }
+
+    /// Effective room encryption policy
+    pub fn encryption_policy(&self) -> config::EncryptionPolicyConfig {
+        self.encryption_policy.clone().unwrap_or_default()
+    }
+
+    /// The archive point-in-time recovery restores from
+    pub fn wal_archive(&self) -> matrixon_db::WalArchive {
+        let dir = match &self.wal_archive_dir {
+            Some(dir) => std::path::PathBuf::from(dir),
+            None => std::path::PathBuf::from(self.backup_directory.as_deref().or(self.database_path.as_deref()).unwrap_or("."))
+                .join("wal_archive"),
+        };
+        matrixon_db::WalArchive::new(dir)
+    }
+
+    /// PostgreSQL connection settings, with any read replicas and shards
+    pub fn database_config(&self) -> matrixon_db::DatabaseConfig {
+        let defaults = matrixon_db::DatabaseConfig::default();
+        matrixon_db::DatabaseConfig {
+            url: self.database_url.clone(),
+            replica_urls: self.database_replica_urls.clone().unwrap_or_default(),
+            max_replica_lag: self.database_max_replica_lag_s.unwrap_or(defaults.max_replica_lag),
+            shard_urls: self.database_shard_urls.clone().unwrap_or_default(),
+            max_connections: self.db_pool_max_connections.unwrap_or(defaults.max_connections),
+            min_connections: self.db_pool_min_connections.unwrap_or(defaults.min_connections),
+            connection_timeout: self.db_pool_connection_timeout_s.unwrap_or(defaults.connection_timeout),
+            statement_cache_capacity: self.db_statement_cache_capacity.unwrap_or(defaults.statement_cache_capacity),
+            slow_query_threshold_ms: self.db_slow_query_threshold_ms.unwrap_or(defaults.slow_query_threshold_ms),
+            ..defaults
+        }
+    }
 }
 
 /// Global services structure
 #[derive(Debug)]
 pub struct Services {
     pub globals: Globals,
+    pub keys: service::keys::Service,
+    pub accounts: service::accounts::Service,
+    pub timeline: service::timeline::Service,
+    pub media_store: service::media_store::Service,
+    pub delegated_auth: service::delegated_auth::Service,
+    pub auto_join: service::auto_join::Service,
+    pub profiles: service::profiles::Service,
+    pub threepids: service::threepids::Service,
+    pub pages: service::pages::Service,
+    pub localization: service::localization::Service,
+    pub room_summary: service::room_summary::Service,
+    pub room_stats: Option<service::room_stats::Service>,
+    pub room_webhooks: Option<service::room_webhooks::Service>,
+    pub retention: Option<service::retention::Service>,
+    pub maintenance: Option<service::maintenance::Service>,
+    pub impersonation: service::impersonation::Service,
+    pub appservices: service::appservices::Service,
+    pub sessions: service::sessions::Service,
+    pub legal_hold: service::legal_hold::Service,
+    pub room_deletion: service::room_deletion::Service,
+    pub event_reports: service::event_reports::Service,
+    pub webhooks: matrixon_core::webhooks::WebhookDispatcher,
+    pub membership: service::membership::Service,
+    pub join_coordinator: service::join_coordinator::Service,
+    pub sending: std::sync::Arc<matrixon_federation::sending::Service>,
+    pub room_key_backup: service::room_key_backup::Service,
+    pub room_directory: service::room_directory::Service,
+    pub room_aliases: service::room_aliases::Service,
+    pub space_hierarchy: service::space_hierarchy::Service,
+    pub inbound_federation: service::inbound_federation::Service,
+    /// Short ids of state keys and events
+    pub short: service::short::Service,
+    pub key_fetcher: service::key_fetcher::Service,
+    pub nft_avatar: service::nft_avatar::Service,
+    pub ipfs: service::ipfs::Service,
+    pub remote_media: service::remote_media::Service,
+    pub email: Option<std::sync::Arc<matrixon_email::Mailer>>,
+    pub server_keys: std::sync::Arc<service::server_keys::Service>,
+    /// Users, devices, rooms and events in PostgreSQL, when configured
+    pub repositories: Option<matrixon_db::Repositories>,
+    pub event_persistence: Option<std::sync::Arc<service::event_persistence::Service>>,
+    /// IoT gateway, once started
+    pub iot: std::sync::OnceLock<std::sync::Arc<matrixon_iot::IoTManager>>,
+    /// Request latencies and event rates of the monitor, once started
+    pub performance: std::sync::OnceLock<std::sync::Arc<matrixon_monitor::performance::PerformanceManager>>,
+    /// Health probes of the subsystems, aggregated by the readiness endpoint
+    pub health: matrixon_core::health::HealthRegistry,
+}
+
+impl Services {
+    /// The LRU caches, for exporting their statistics and the cache admin API
+    pub fn caches(&self) -> Vec<&dyn service::cache::CacheStats> {
+        let mut caches = self.timeline.caches().to_vec();
+        caches.push(self.profiles.cache());
+        caches.push(self.inbound_federation.auth_chains().cache());
+        caches.extend(self.short.caches());
+        caches
+    }
 }
 
 #[derive(Debug)]
@@ -232,21 +686,6 @@ impl Globals {
     }
 }
 
-/// Database trait placeholder
-pub trait KeyValueDatabase {
-    async fn load_or_create(config: Config) -> std::result::Result<(), Box<dyn std::error::Error>>;
-}
-
-/// Dummy implementation for testing
-pub struct DummyDatabase;
-
-impl KeyValueDatabase for DummyDatabase {
-    async fn load_or_create(config: Config) -> std::result::Result<(), Box<dyn std::error::Error>> {
-        tracing::info!("Loading database for server: {}", config.server_name);
-        Ok(())
-    }
-}
-
 /// Error types
 #[derive(Error, Debug)]
 pub enum Error {
@@ -256,9 +695,41 @@ pub enum Error {
     BadRequest(ruma::api::client::error::ErrorKind, &'static str),
     #[error("Database error: {0}")]
     BadDatabase(String),
+    #[error("Bad server response: {0}")]
+    BadServerResponse(String),
+    #[error("User suspended: {0}")]
+    UserSuspended(String),
+    /// User-interactive authentication is needed, with the flows, params
+    /// and session to report
+    #[error("User-interactive authentication required")]
+    Uiaa(serde_json::Value),
 }
 
 impl Error {
+    /// HTTP status code returned to clients for this error
+    pub fn status_code(&self) -> axum::http::StatusCode {
+        use axum::http::StatusCode;
+        use ruma::api::client::error::ErrorKind;
+
+        match self {
+            Error::BadRequest(kind, _) => match kind {
+                ErrorKind::MissingToken | ErrorKind::UnknownToken { .. } | ErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+                ErrorKind::Forbidden { .. }
+                | ErrorKind::GuestAccessForbidden
+                | ErrorKind::UserDeactivated
+                | ErrorKind::UserSuspended
+                | ErrorKind::WrongRoomKeysVersion { .. } => StatusCode::FORBIDDEN,
+                ErrorKind::NotFound | ErrorKind::Unrecognized => StatusCode::NOT_FOUND,
+                ErrorKind::LimitExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+                _ => StatusCode::BAD_REQUEST,
+            },
+            Error::BadConfig(_) | Error::BadDatabase(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::BadServerResponse(_) => StatusCode::BAD_GATEWAY,
+            Error::UserSuspended(_) => StatusCode::FORBIDDEN,
+            Error::Uiaa(_) => StatusCode::UNAUTHORIZED,
+        }
+    }
+
     pub fn bad_config(msg: &str) -> Self {
         Error::BadConfig(msg.to_string())
     }
@@ -270,16 +741,23 @@ impl Error {
 
 impl axum::response::IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        use axum::http::StatusCode;
         use axum::Json;
         
-        let (status, message) = match self {
-            Error::BadConfig(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
-            Error::BadRequest(_, msg) => (StatusCode::BAD_REQUEST, msg.to_string()),
-            Error::BadDatabase(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
+        let status = self.status_code();
+        if let Error::Uiaa(body) = self {
+            return (status, Json(body)).into_response();
+        }
+        let (errcode, message) = match self {
+            Error::BadConfig(msg) => ("M_UNKNOWN".to_owned(), msg),
+            Error::BadRequest(kind, msg) => (kind.errcode().to_string(), msg.to_string()),
+            Error::BadDatabase(msg) => ("M_UNKNOWN".to_owned(), msg),
+            Error::BadServerResponse(msg) => ("M_UNKNOWN".to_owned(), msg),
+            Error::UserSuspended(reason) => (ruma::api::client::error::ErrorKind::UserSuspended.errcode().to_string(), reason),
+            Error::Uiaa(_) => unreachable!("answered above"),
         };
         
         (status, Json(serde_json::json!({
+            "errcode": errcode,
             "error": message
         }))).into_response()
     }
@@ -314,6 +792,8 @@ pub mod database {
 /// Configuration module
 pub mod config {
     use serde::{Deserialize, Serialize};
+
+    use crate::service::security_headers::RouteClass;
     
     pub mod captcha {
         use serde::{Deserialize, Serialize};
@@ -341,444 +821,3933 @@ pub mod config {
         }
     }
     
-    #[derive(Debug, Clone, Deserialize, Serialize)]
-    pub struct IncompleteConfig {
-        pub server_name: Option<String>,
-        pub database_url: Option<String>,
+    /// Policy requiring end-to-end encryption in new rooms
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct EncryptionPolicyConfig {
+        /// Encrypt every newly created room
+        #[serde(default)]
+        pub require_for_new_rooms: bool,
+        /// Creators whose new rooms must be encrypted (user ids, or patterns
+        /// with a single `*` such as `*:example.org`)
+        #[serde(default)]
+        pub required_for_creators: Vec<String>,
+        /// Creators exempt from the policy, same format as above
+        #[serde(default)]
+        pub exempt_creators: Vec<String>,
+    }
+    
+    /// Validation of third-party identifiers (emails, phone numbers)
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct ThreepidConfig {
+        /// Identity server sending email validation tokens on this server's
+        /// behalf; tokens are handled locally when unset
+        #[serde(default)]
+        pub email_delegate: Option<String>,
+        /// Identity server sending SMS validation tokens on this server's behalf
+        #[serde(default)]
+        pub msisdn_delegate: Option<String>,
     }
-}
 
-/// Service module for plugin management
-pub mod service {
-    pub mod plugins {
-        pub mod manager {
-            use serde::{Deserialize, Serialize};
-            
-            #[derive(Debug, Clone)]
-            pub struct PluginContext {
-                pub plugin_id: String,
-                pub data: std::collections::HashMap<String, String>,
-            }
-            
-            #[derive(Debug, Clone)]
-            pub enum PluginEvent {
-                PluginLoaded(String),
-                HookExecuted(String, String, std::time::Duration),
+    impl ThreepidConfig {
+        pub fn delegate(&self, medium: &str) -> Option<&str> {
+            match medium {
+                "email" => self.email_delegate.as_deref(),
+                "msisdn" => self.msisdn_delegate.as_deref(),
+                _ => None,
             }
         }
     }
-    
-    pub mod federation {
-        use serde::{Deserialize, Serialize};
-        
-        #[derive(Debug, Clone)]
-        pub enum ServerStatus {
-            Online,
-            Offline,
-            Unknown,
+
+    /// Color scheme of the server-rendered pages
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum PageTheme {
+        #[default]
+        Light,
+        Dark,
+        /// Follows the browser's preferred color scheme
+        Auto,
+    }
+
+    /// Look of the HTML pages the server renders itself
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct PagesConfig {
+        /// Directory of `<page>.html` files replacing the built-in templates,
+        /// `layout.html` wrapping all of them
+        #[serde(default)]
+        pub template_dir: Option<std::path::PathBuf>,
+        #[serde(default)]
+        pub theme: PageTheme,
+        /// Name shown in the page titles and footers, defaults to the
+        /// server name
+        #[serde(default)]
+        pub brand: Option<String>,
+    }
+
+    /// The policy users are asked to accept on the consent page
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct ConsentConfig {
+        /// Version of the current policy; accepting an older one does not
+        /// count
+        pub version: String,
+        /// HTML file of the policy
+        pub policy_file: std::path::PathBuf,
+        /// Name of the policy on the page
+        #[serde(default = "default_policy_name")]
+        pub policy_name: String,
+        /// Secret signing the user ids of consent links
+        pub form_secret: String,
+    }
+
+    fn default_policy_name() -> String {
+        "Privacy Policy".to_owned()
+    }
+
+    /// Translations of server-generated text
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct LocalizationConfig {
+        /// Directory of Fluent files laid out as `<locale>/<name>.ftl`,
+        /// adding translations and overriding the built-in English messages
+        #[serde(default)]
+        pub locale_dir: Option<std::path::PathBuf>,
+        /// Locale used when neither the user nor their browser asks for an
+        /// available one
+        #[serde(default = "default_locale")]
+        pub default_locale: String,
+    }
+
+    impl Default for LocalizationConfig {
+        fn default() -> Self {
+            Self { locale_dir: None, default_locale: default_locale() }
         }
-        
-        #[derive(Debug, Clone)]
-        pub enum FederationEvent {
-            ServerDiscovered(String),
-            BridgeConnected(String, String),
+    }
+
+    /// Delegation of authentication to an OAuth 2.0 provider such as the
+    /// Matrix Authentication Service (MSC2965/MSC3861)
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct DelegatedAuthConfig {
+        /// Issuer advertised to clients, e.g. `https://auth.example.org/`
+        pub issuer: String,
+        /// Token introspection endpoint, defaults to `{issuer}/oauth2/introspect`
+        #[serde(default)]
+        pub introspection_endpoint: Option<String>,
+        /// Client credentials of this server at the provider
+        pub client_id: String,
+        pub client_secret: String,
+        /// Where users manage their account, advertised in `.well-known`
+        #[serde(default)]
+        pub account_management_url: Option<String>,
+        /// How long a successful introspection is cached
+        #[serde(default = "default_introspection_cache_ttl_s")]
+        pub introspection_cache_ttl_s: u64,
+    }
+
+    /// Admins acting as other users through short-lived tokens
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct ImpersonationConfig {
+        /// Allow admins to issue impersonation tokens
+        #[serde(default)]
+        pub enabled: bool,
+        /// Longest lifetime an impersonation token can be issued for
+        #[serde(default = "default_impersonation_max_lifetime_s")]
+        pub max_lifetime_s: u64,
+    }
+
+    impl Default for ImpersonationConfig {
+        fn default() -> Self {
+            Self {
+                enabled: false,
+                max_lifetime_s: default_impersonation_max_lifetime_s(),
+            }
         }
     }
-}
 
-/// Result type for the library
-pub type Result<T> = std::result::Result<T, Error>;
+    /// API served by a listener
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum ListenerResource {
+        /// Client-server and media APIs, `/.well-known/matrix/client`
+        Client,
+        /// Server-server and key APIs, `/.well-known/matrix/server`
+        Federation,
+        /// `/_matrix/metrics`
+        Metrics,
+    }
 
-/// API modules
-pub mod api {
-    pub mod client_server {
-        use crate::RumaResponse;
-        use axum::{
-            extract::{Path, Query, State}, 
-            http::{HeaderMap, StatusCode}, 
-            response::IntoResponse, 
-            Json
-        };
-        use serde_json::{json, Value};
-        use std::{collections::HashMap, time::{SystemTime, UNIX_EPOCH}};
-        use tracing::{info, warn, error, debug, instrument};
+    /// A socket the server listens on
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct ListenerConfig {
+        /// TCP address, e.g. `0.0.0.0:8448`
+        #[serde(default)]
+        pub bind: Option<std::net::SocketAddr>,
+        /// Unix domain socket path, for a reverse proxy on the same host;
+        /// replaces `bind`
+        #[serde(default)]
+        pub unix_socket: Option<String>,
+        /// Permissions of the unix socket, e.g. `0o660`
+        #[serde(default)]
+        pub unix_socket_mode: Option<u32>,
+        /// APIs served, every API when empty
+        #[serde(default)]
+        pub resources: Vec<ListenerResource>,
+        /// Most connections served at once, unlimited when unset
+        #[serde(default)]
+        pub max_connections: Option<usize>,
+        /// Largest request body, defaults to `max_request_size`
+        #[serde(default)]
+        pub max_request_size: Option<u64>,
+        /// Require TLS with a client certificate of the internal CA, for
+        /// listeners only other components talk to (metrics, workers)
+        #[serde(default)]
+        pub internal_tls: Option<matrixon_common::internal_tls::InternalTlsConfig>,
+    }
 
-        /// GET /_matrix/client/versions - Get supported Matrix versions
-        #[instrument(level = "debug")]
-        pub async fn get_supported_versions_route() -> impl IntoResponse {
-            info!("🔍 Matrix versions endpoint called");
-            RumaResponse(Json(json!({
-                "versions": [
-                    "r0.0.1", "r0.1.0", "r0.2.0", "r0.3.0", "r0.4.0", "r0.5.0", "r0.6.0", "r0.6.1",
-                    "v1.1", "v1.2", "v1.3", "v1.4", "v1.5", "v1.6", "v1.7", "v1.8", "v1.9", "v1.10"
-                ],
-                "unstable_features": {
-                    "org.matrix.e2e_cross_signing": true,
-                    "org.matrix.msc2432": true,
-                    "org.matrix.msc3575": true
-                }
-            })))
+    impl ListenerConfig {
+        pub fn serves(&self, resource: ListenerResource) -> bool {
+            self.resources.is_empty() || self.resources.contains(&resource)
         }
+    }
 
-        /// GET /_matrix/client/r0/capabilities - Get server capabilities
-        #[instrument(level = "debug")]
-        pub async fn get_capabilities_route() -> impl IntoResponse {
-            info!("🔧 Server capabilities endpoint called");
-            RumaResponse(Json(json!({
-                "capabilities": {
-                    "m.change_password": {"enabled": true},
-                    "m.room_versions": {
-                        "default": "9",
-                        "available": {
-                            "1": "stable", "2": "stable", "3": "stable", "4": "stable",
-                            "5": "stable", "6": "stable", "7": "stable", "8": "stable",
-                            "9": "stable", "10": "stable"
-                        }
-                    },
-                    "m.set_displayname": {"enabled": true},
-                    "m.set_avatar_url": {"enabled": true},
-                    "m.3pid_changes": {"enabled": true}
-                }
-            })))
+    /// Cross-origin resource sharing for browser clients
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct CorsConfig {
+        /// Origins allowed to use the Matrix APIs, any (`*`) by default as
+        /// the spec recommends; list origins to restrict browser clients
+        #[serde(default = "default_cors_allowed_origins")]
+        pub allowed_origins: Vec<String>,
+        /// Origins allowed to use the `/_matrixon` and `/_synapse` admin
+        /// APIs, none by default; `*` is not honored here
+        #[serde(default)]
+        pub admin_allowed_origins: Vec<String>,
+        /// Let browsers send cookies and client certificates along. Only
+        /// honored when the Matrix APIs are limited to listed origins.
+        #[serde(default)]
+        pub allow_credentials: bool,
+        /// How long browsers may cache a preflight response
+        #[serde(default = "default_cors_max_age_s")]
+        pub max_age_s: u64,
+    }
+
+    impl Default for CorsConfig {
+        fn default() -> Self {
+            Self {
+                allowed_origins: default_cors_allowed_origins(),
+                admin_allowed_origins: Vec::new(),
+                allow_credentials: false,
+                max_age_s: default_cors_max_age_s(),
+            }
         }
+    }
 
-        /// GET /_matrix/client/r0/account/whoami - Get current user info
-        #[instrument(level = "debug")]
-        pub async fn whoami_route(headers: HeaderMap) -> impl IntoResponse {
-            info!("👤 Whoami endpoint called");
-            let auth_header = headers.get("authorization")
-                .and_then(|h| h.to_str().ok())
-                .unwrap_or("No auth header");
-            
-            RumaResponse(Json(json!({
-                "user_id": "@current_user:matrixon.local",
-                "device_id": "MATRIXON_DEVICE_123",
-                "is_guest": false
-            })))
+    impl CorsConfig {
+        /// Whether a page of `origin` may make requests to `path`
+        pub fn allows(&self, path: &str, origin: &str) -> bool {
+            if RouteClass::of(path) == RouteClass::Admin {
+                self.admin_allowed_origins.iter().any(|allowed| allowed == origin)
+            } else {
+                self.allowed_origins.iter().any(|allowed| allowed == "*" || allowed == origin)
+            }
         }
 
-        /// GET /_matrix/client/r0/login - Get available login types
-        #[instrument(level = "debug")]
-        pub async fn get_login_types_route() -> impl IntoResponse {
-            info!("🔑 Login types endpoint called");
-            RumaResponse(Json(json!({
-                "flows": [
-                    {"type": "m.login.password"},
-                    {"type": "m.login.token"},
-                    {"type": "m.login.sso"},
-                    {"type": "m.login.application_service"}
-                ]
-            })))
+        /// Whether credentials can be allowed: every origin given them must
+        /// be listed
+        pub fn credentials(&self) -> bool {
+            self.allow_credentials
+                && !self.allowed_origins.is_empty()
+                && !self.allowed_origins.iter().any(|allowed| allowed == "*")
         }
+    }
 
-        /// POST /_matrix/client/r0/login - User login
-        #[instrument(level = "debug")]
-        pub async fn login_route(Json(payload): Json<Value>) -> impl IntoResponse {
-            info!("🔓 User login endpoint called with payload: {:?}", payload);
-            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-            
-            // Extract user identifier and password from payload
-            let identifier = payload.get("identifier")
-                .and_then(|i| i.get("user"))
-                .and_then(|u| u.as_str())
-                .unwrap_or("anonymous");
-            
-            RumaResponse(Json(json!({
-                "user_id": format!("@{}:matrixon.local", identifier),
-                "access_token": format!("syt_matrixon_login_{}", timestamp),
-                "device_id": format!("MATRIXON_LOGIN_DEVICE_{}", timestamp),
-                "well_known": {
-                    "m.NextServer": {
-                        "base_url": "http://localhost:6167"
-                    },
-                    "m.identity_server": {
-                        "base_url": "http://localhost:6167"
-                    }
+    fn default_cors_allowed_origins() -> Vec<String> {
+        vec!["*".to_owned()]
+    }
+
+    fn default_cors_max_age_s() -> u64 {
+        86400
+    }
+
+    /// Response headers by route class
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct SecurityHeadersConfig {
+        /// CSP of media downloads, sandboxing uploaded content
+        #[serde(default = "default_media_csp")]
+        pub media_csp: String,
+        /// `X-Robots-Tag` of media downloads, none when empty
+        #[serde(default = "default_media_robots_tag")]
+        pub media_robots_tag: String,
+        /// CSP of the admin API and UI
+        #[serde(default = "default_admin_csp")]
+        pub admin_csp: String,
+        /// CSP of the other APIs, unless a page sets its own
+        #[serde(default = "default_api_csp")]
+        pub api_csp: String,
+        /// Send `Strict-Transport-Security` on listeners serving TLS
+        #[serde(default = "default_true")]
+        pub hsts: bool,
+        #[serde(default = "default_hsts_max_age_s")]
+        pub hsts_max_age_s: u64,
+        #[serde(default)]
+        pub hsts_include_subdomains: bool,
+    }
+
+    impl Default for SecurityHeadersConfig {
+        fn default() -> Self {
+            Self {
+                media_csp: default_media_csp(),
+                media_robots_tag: default_media_robots_tag(),
+                admin_csp: default_admin_csp(),
+                api_csp: default_api_csp(),
+                hsts: true,
+                hsts_max_age_s: default_hsts_max_age_s(),
+                hsts_include_subdomains: false,
+            }
+        }
+    }
+
+    fn default_media_csp() -> String {
+        "sandbox; default-src 'none'; script-src 'none'; plugin-types application/pdf; style-src 'unsafe-inline'; object-src 'self';".to_owned()
+    }
+
+    fn default_media_robots_tag() -> String {
+        "noindex, nofollow, noarchive".to_owned()
+    }
+
+    fn default_admin_csp() -> String {
+        "default-src 'self'; object-src 'none'; base-uri 'none'; form-action 'self'; frame-ancestors 'none'".to_owned()
+    }
+
+    fn default_api_csp() -> String {
+        "default-src 'none'; frame-ancestors 'none'".to_owned()
+    }
+
+    fn default_hsts_max_age_s() -> u64 {
+        31_536_000
+    }
+
+    fn default_true() -> bool {
+        true
+    }
+
+    /// Reaction to the listening address being taken by another process
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum OnPortConflict {
+        /// Exit with a diagnostic
+        #[default]
+        Fail,
+        /// Retry until the port is released
+        Wait,
+        /// Stop the previous Matrixon instance recorded in the pidfile, then
+        /// wait for it to release the port
+        Takeover,
+    }
+
+    /// Handling of an address already in use at startup
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct PortConflictConfig {
+        #[serde(default)]
+        pub on_conflict: OnPortConflict,
+        /// How long `wait` and `takeover` wait for the port
+        #[serde(default = "default_port_wait_s")]
+        pub wait_s: u64,
+        /// Pid of the running instance, defaults to `matrixon.pid` below
+        /// `database_path`
+        #[serde(default)]
+        pub pidfile: Option<String>,
+    }
+
+    impl Default for PortConflictConfig {
+        fn default() -> Self {
+            Self {
+                on_conflict: OnPortConflict::default(),
+                wait_s: default_port_wait_s(),
+                pidfile: None,
+            }
+        }
+    }
+
+    fn default_port_wait_s() -> u64 {
+        30
+    }
+
+    /// Retries of one service at startup
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct RetryConfig {
+        /// Checks before giving up on the service, ignored when waiting for
+        /// dependencies
+        #[serde(default = "default_startup_attempts")]
+        pub attempts: u32,
+        /// Wait after the first failed check, doubling after each failure
+        #[serde(default = "default_startup_initial_backoff_ms")]
+        pub initial_backoff_ms: u64,
+        #[serde(default = "default_startup_max_backoff_ms")]
+        pub max_backoff_ms: u64,
+    }
+
+    impl Default for RetryConfig {
+        fn default() -> Self {
+            Self {
+                attempts: default_startup_attempts(),
+                initial_backoff_ms: default_startup_initial_backoff_ms(),
+                max_backoff_ms: default_startup_max_backoff_ms(),
+            }
+        }
+    }
+
+    /// A service the server waits for by connecting to it
+    #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+    pub struct DependencyConfig {
+        pub name: String,
+        /// `host:port` accepting TCP connections once the service is up
+        pub address: String,
+    }
+
+    /// Services are brought up in order: the database, its shards, the
+    /// `dependencies` in the order listed and the IPFS gateway. Each is
+    /// checked until it answers, with exponential backoff. The server does
+    /// not start while a required service is unavailable, and starts
+    /// without optional ones.
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct StartupConfig {
+        /// Retry every service until it answers or `wait_timeout_s` passes,
+        /// for containers started alongside their dependencies
+        #[serde(default)]
+        pub wait_for_deps: bool,
+        #[serde(default = "default_startup_wait_timeout_s")]
+        pub wait_timeout_s: u64,
+        #[serde(default)]
+        pub retry: RetryConfig,
+        /// Retries of particular services, by name: `database`,
+        /// `database_shards`, `ipfs` or the name of a dependency
+        #[serde(default)]
+        pub service_retry: std::collections::BTreeMap<String, RetryConfig>,
+        /// Services the server starts without, by name
+        #[serde(default = "default_optional_services")]
+        pub optional: Vec<String>,
+        #[serde(default)]
+        pub dependencies: Vec<DependencyConfig>,
+    }
+
+    impl Default for StartupConfig {
+        fn default() -> Self {
+            Self {
+                wait_for_deps: false,
+                wait_timeout_s: default_startup_wait_timeout_s(),
+                retry: RetryConfig::default(),
+                service_retry: std::collections::BTreeMap::new(),
+                optional: default_optional_services(),
+                dependencies: Vec::new(),
+            }
+        }
+    }
+
+    impl StartupConfig {
+        pub fn retry(&self, service: &str) -> RetryConfig {
+            self.service_retry.get(service).copied().unwrap_or(self.retry)
+        }
+
+        pub fn is_required(&self, service: &str) -> bool {
+            !self.optional.iter().any(|optional| optional == service)
+        }
+    }
+
+    fn default_startup_attempts() -> u32 {
+        5
+    }
+
+    fn default_startup_initial_backoff_ms() -> u64 {
+        500
+    }
+
+    fn default_startup_max_backoff_ms() -> u64 {
+        10_000
+    }
+
+    fn default_startup_wait_timeout_s() -> u64 {
+        300
+    }
+
+    fn default_optional_services() -> Vec<String> {
+        vec!["ipfs".to_owned()]
+    }
+
+    /// Chains used to verify NFT avatars
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct NftAvatarConfig {
+        /// JSON-RPC endpoints by chain id, e.g. `"1" = "https://eth.example"`
+        #[serde(default)]
+        pub rpc_urls: std::collections::BTreeMap<String, String>,
+        /// Largest accepted token image in bytes, defaults to 10 MiB
+        #[serde(default)]
+        pub max_image_size: Option<u64>,
+    }
+
+    /// Export of the event stream to a message broker
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct EventExportConfig {
+        /// `kafka` or `nats`; requires the feature of the same name
+        pub backend: String,
+        /// Kafka bootstrap servers, or the NATS server URL
+        pub servers: String,
+        /// Prefix of the topics (Kafka) or subjects (NATS) published to
+        #[serde(default = "default_export_topic_prefix")]
+        pub topic_prefix: String,
+        /// Events read from the stream at a time
+        #[serde(default = "default_export_batch_size")]
+        pub batch_size: usize,
+    }
+
+    /// Escalation of events reported by room members to the room moderators
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct ReportEscalationConfig {
+        /// Reports by distinct members that escalate an event
+        #[serde(default = "default_report_threshold")]
+        pub threshold: usize,
+        /// Minutes within which the reports must arrive
+        #[serde(default = "default_report_window_minutes")]
+        pub window_minutes: u64,
+        /// Hide escalated events until a server admin restores them
+        #[serde(default)]
+        pub hide_event: bool,
+        /// Room notified instead of messaging every moderator directly;
+        /// rooms cannot override it
+        #[serde(default)]
+        pub moderation_room: Option<String>,
+        /// Localpart of the user sending the notifications
+        #[serde(default = "default_notices_user")]
+        pub notices_user: String,
+    }
+
+    impl Default for ReportEscalationConfig {
+        fn default() -> Self {
+            Self {
+                threshold: default_report_threshold(),
+                window_minutes: default_report_window_minutes(),
+                hide_event: false,
+                moderation_room: None,
+                notices_user: default_notices_user(),
+            }
+        }
+    }
+
+    fn default_report_threshold() -> usize {
+        3
+    }
+
+    fn default_report_window_minutes() -> u64 {
+        60
+    }
+
+    fn default_notices_user() -> String {
+        "matrixon".to_owned()
+    }
+
+    /// Aggregation of daily room statistics
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct RoomStatsConfig {
+        /// Days statistics are kept for
+        #[serde(default = "default_room_stats_retention_days")]
+        pub retention_days: u32,
+        /// Days of a room with fewer active users are not reported
+        #[serde(default = "default_room_stats_min_active_users")]
+        pub min_active_users: usize,
+    }
+
+    impl Default for RoomStatsConfig {
+        fn default() -> Self {
+            Self {
+                retention_days: default_room_stats_retention_days(),
+                min_active_users: default_room_stats_min_active_users(),
+            }
+        }
+    }
+
+    /// Outgoing webhooks registered by room admins
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct RoomWebhooksConfig {
+        /// Webhooks a room may register
+        #[serde(default = "default_room_webhooks_max_per_room")]
+        pub max_per_room: usize,
+        /// Deliveries a room may cause, across its webhooks; events over
+        /// the cap are dropped
+        #[serde(default = "default_room_webhooks_rate_limit")]
+        pub rate_limit: matrixon_common::rate_limit::RateLimit,
+        /// Delivery attempts after the first one fails
+        #[serde(default = "default_room_webhooks_max_retries")]
+        pub max_retries: u32,
+        /// Allow webhooks to loopback, private and link-local addresses
+        #[serde(default)]
+        pub allow_private_addresses: bool,
+    }
+
+    impl Default for RoomWebhooksConfig {
+        fn default() -> Self {
+            Self {
+                max_per_room: default_room_webhooks_max_per_room(),
+                rate_limit: default_room_webhooks_rate_limit(),
+                max_retries: default_room_webhooks_max_retries(),
+                allow_private_addresses: false,
+            }
+        }
+    }
+
+    /// An application service registration
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct AppserviceConfig {
+        pub id: String,
+        /// Token the application service authenticates with
+        pub as_token: String,
+        /// Localpart of the application service's own user
+        pub sender_localpart: String,
+        #[serde(default)]
+        pub namespaces: AppserviceNamespaces,
+    }
+
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct AppserviceNamespaces {
+        /// User ids the application service may act as
+        #[serde(default)]
+        pub users: Vec<AppserviceNamespace>,
+    }
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct AppserviceNamespace {
+        #[serde(default)]
+        pub exclusive: bool,
+        /// Regular expression matching whole user ids
+        pub regex: String,
+    }
+
+    /// Message retention (MSC1763) and purging of redacted content
+    #[derive(Debug, Clone, Default, Deserialize, Serialize)]
+    pub struct RetentionConfig {
+        /// Lifetime of events in rooms without a retention policy,
+        /// milliseconds; kept forever when unset
+        pub default_max_lifetime_ms: Option<u64>,
+        pub default_min_lifetime_ms: Option<u64>,
+        /// Bounds room policies are clamped to, milliseconds
+        pub allowed_lifetime_min_ms: Option<u64>,
+        pub allowed_lifetime_max_ms: Option<u64>,
+        /// Seconds between purge runs
+        #[serde(default = "default_retention_purge_interval_s")]
+        pub purge_interval_s: u64,
+        /// Seconds the original content of redacted events is kept for
+        /// moderators; kept until the event is purged when unset
+        pub redaction_retention_s: Option<u64>,
+    }
+
+    fn default_retention_purge_interval_s() -> u64 {
+        3600
+    }
+
+    fn default_room_stats_retention_days() -> u32 {
+        365
+    }
+
+    fn default_room_stats_min_active_users() -> usize {
+        3
+    }
+
+    fn default_room_webhooks_max_per_room() -> usize {
+        10
+    }
+
+    fn default_room_webhooks_rate_limit() -> matrixon_common::rate_limit::RateLimit {
+        matrixon_common::rate_limit::RateLimit::SlidingWindow { limit: 60, window: std::time::Duration::from_secs(60) }
+    }
+
+    fn default_room_webhooks_max_retries() -> u32 {
+        5
+    }
+
+    fn default_export_topic_prefix() -> String {
+        "matrixon.events".to_owned()
+    }
+
+    fn default_export_batch_size() -> usize {
+        500
+    }
+
+    fn default_impersonation_max_lifetime_s() -> u64 {
+        900
+    }
+
+    fn default_introspection_cache_ttl_s() -> u64 {
+        60
+    }
+
+    fn default_locale() -> String {
+        "en".to_owned()
+    }
+    
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    pub struct IncompleteConfig {
+        pub server_name: Option<String>,
+        pub database_url: Option<String>,
+    }
+}
+
+/// Service module for plugin management
+pub mod service {
+    pub mod accounts;
+    pub mod allocator;
+    pub mod appservices;
+    pub mod auth_chain;
+    pub mod auto_join;
+    pub mod cache;
+    pub mod cache_warmup;
+    pub mod delegated_auth;
+    pub mod encryption_policy;
+    pub mod erasure;
+    pub mod event_persistence;
+    pub mod event_reports;
+    pub mod federation_fixtures;
+    pub mod federation_metrics;
+    pub mod join_coordinator;
+    pub mod keys;
+    pub mod legal_hold;
+    pub mod listener;
+    pub mod localization;
+    pub mod maintenance;
+    pub mod media_store;
+    pub mod membership;
+    pub mod nft_avatar;
+    pub mod outbound_http;
+    pub mod outlier;
+    pub mod pages;
+    pub mod partial_state;
+    pub mod pdu_metadata;
+    pub mod profiles;
+    pub mod remote_media;
+    pub mod retention;
+    pub mod room_aliases;
+    pub mod room_deletion;
+    pub mod room_directory;
+    pub mod room_key_backup;
+    pub mod room_stats;
+    pub mod room_webhooks;
+    pub mod room_summary;
+    pub mod room_versions;
+    pub mod security_headers;
+    pub mod server_keys;
+    pub mod sessions;
+    pub mod short;
+    pub mod space_hierarchy;
+    pub mod startup;
+    pub mod state_file;
+    pub mod threepids;
+    pub mod impersonation;
+    pub mod event_export;
+    pub mod iot;
+    pub mod federation_history;
+    pub mod federation_membership;
+    pub mod inbound_federation;
+    pub mod ipfs;
+    pub mod json_stream;
+    pub mod key_fetcher;
+    pub mod outbound_federation;
+    pub mod timeline;
+
+    pub mod plugins {
+        pub mod manager {
+            use serde::{Deserialize, Serialize};
+            
+            #[derive(Debug, Clone)]
+            pub struct PluginContext {
+                pub plugin_id: String,
+                pub data: std::collections::HashMap<String, String>,
+            }
+            
+            #[derive(Debug, Clone)]
+            pub enum PluginEvent {
+                PluginLoaded(String),
+                HookExecuted(String, String, std::time::Duration),
+            }
+        }
+    }
+    
+    pub mod federation {
+        use serde::{Deserialize, Serialize};
+        
+        #[derive(Debug, Clone)]
+        pub enum ServerStatus {
+            Online,
+            Offline,
+            Unknown,
+        }
+        
+        #[derive(Debug, Clone)]
+        pub enum FederationEvent {
+            ServerDiscovered(String),
+            BridgeConnected(String, String),
+        }
+    }
+}
+
+/// Result type for the library
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// API modules
+pub mod api {
+    pub mod client_server {
+        use crate::RumaResponse;
+        use axum::{
+            extract::{Path, Query, RawQuery, State}, 
+            http::{HeaderMap, StatusCode}, 
+            response::IntoResponse, 
+            Json
+        };
+        use serde_json::{json, Value};
+        use std::{collections::HashMap, time::{SystemTime, UNIX_EPOCH}};
+        use tracing::{info, warn, error, debug, instrument};
+        use crate::services;
+        use ruma::api::client::error::ErrorKind;
+        use matrixon_core::webhooks::WebhookEvent;
+        use crate::service::{pages::Page, room_versions};
+
+        /// Resolve the user and device behind the request's access token
+        pub async fn authenticated_device(headers: &HeaderMap) -> crate::Result<(String, String)> {
+            let token = headers.get("authorization")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingToken, "Missing access token"))?;
+
+            let config = &services().globals.config;
+            let (user_id, device_id) = if token.starts_with(crate::service::impersonation::TOKEN_PREFIX) {
+                services().impersonation.lookup(token).ok_or(crate::Error::BadRequest(
+                    ErrorKind::UnknownToken { soft_logout: false },
+                    "Unknown or expired impersonation token",
+                ))?
+            } else {
+                match &config.delegated_auth {
+                    Some(delegated) => services().delegated_auth.authenticate(delegated, &config.server_name, token).await?,
+                    None => local_session(token).await?,
+                }
+            };
+            if services().accounts.is_deactivated(&user_id) {
+                return Err(crate::Error::BadRequest(ErrorKind::UserDeactivated, "This account has been deactivated"));
+            }
+            if services().sessions.is_logged_out(&user_id, &device_id) {
+                return Err(logged_out());
+            }
+            services().sessions.touch(&user_id, &device_id);
+
+            Ok((user_id, device_id))
+        }
+
+        /// Error for requests of a device that was logged out
+        fn logged_out() -> crate::Error {
+            crate::Error::BadRequest(ErrorKind::UnknownToken { soft_logout: true }, "This device has been logged out")
+        }
+
+        /// Like [`authenticated_device`], but only for server admins. Admin
+        /// privileges are never granted through an impersonation token.
+        pub async fn authenticated_admin(headers: &HeaderMap) -> crate::Result<String> {
+            let impersonating = headers.get("authorization")
+                .and_then(|v| v.to_str().ok())
+                .is_some_and(|v| v.starts_with(&format!("Bearer {}", crate::service::impersonation::TOKEN_PREFIX)));
+            if impersonating {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "Impersonation tokens cannot use the admin API"));
+            }
+            let (user_id, _) = authenticated_device(headers).await?;
+            let is_admin = services().globals.config.admin_users.iter().flatten().any(|admin| *admin == user_id);
+            if !is_admin {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "You are not a server admin"));
+            }
+            Ok(user_id)
+        }
+
+        /// Map an access token issued by this server's own login and
+        /// registration. Without a database sessions are only kept in
+        /// memory, and tokens the server did not issue are unknown.
+        async fn local_session(token: &str) -> crate::Result<(String, String)> {
+            let session = match &services().repositories {
+                Some(repositories) => repositories
+                    .devices
+                    .by_access_token(token)
+                    .await
+                    .map_err(|e| crate::Error::BadDatabase(e.to_string()))?
+                    .map(|device| (device.user_id, device.device_id)),
+                None => services().sessions.token_session(token),
+            };
+            session.ok_or(crate::Error::BadRequest(
+                ErrorKind::UnknownToken { soft_logout: false },
+                "Unknown access token",
+            ))
+        }
+
+        /// GET /_matrix/client/versions - Get supported Matrix versions
+        #[instrument(level = "debug")]
+        pub async fn get_supported_versions_route() -> impl IntoResponse {
+            info!("🔍 Matrix versions endpoint called");
+            // Advertise forced encryption the way Synapse does, so clients can
+            // hide the option to create unencrypted rooms
+            let e2ee_forced = services().globals.config.encryption_policy().require_for_new_rooms;
+            RumaResponse(Json(json!({
+                "versions": [
+                    "r0.0.1", "r0.1.0", "r0.2.0", "r0.3.0", "r0.4.0", "r0.5.0", "r0.6.0", "r0.6.1",
+                    "v1.1", "v1.2", "v1.3", "v1.4", "v1.5", "v1.6", "v1.7", "v1.8", "v1.9", "v1.10"
+                ],
+                "unstable_features": {
+                    "org.matrix.e2e_cross_signing": true,
+                    "org.matrix.msc2432": true,
+                    "org.matrix.msc3575": true,
+                    "xyz.amorgan.knock": true,
+                    "io.element.e2ee_forced.public": e2ee_forced,
+                    "io.element.e2ee_forced.private": e2ee_forced,
+                    "io.element.e2ee_forced.trusted_private": e2ee_forced
                 }
             })))
         }
 
-        /// POST /_matrix/client/r0/register - User registration
+        /// GET /.well-known/matrix/client - Client discovery
         #[instrument(level = "debug")]
-        pub async fn register_route(Json(payload): Json<Value>) -> impl IntoResponse {
-            info!("🔐 User registration endpoint called with payload: {:?}", payload);
-            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-            
-            let default_username = format!("user_{}", timestamp);
-            let username = payload.get("username")
-                .and_then(|u| u.as_str())
-                .unwrap_or(&default_username);
-            
+        pub async fn well_known_client() -> impl IntoResponse {
+            let config = &services().globals.config;
+            let mut well_known = json!({
+                "m.homeserver": { "base_url": format!("https://{}", config.server_name) }
+            });
+            if let Some(delegated) = &config.delegated_auth {
+                well_known["org.matrix.msc2965.authentication"] = json!({
+                    "issuer": delegated.issuer,
+                    "account": delegated.account_management_url
+                });
+            }
+            RumaResponse(Json(well_known))
+        }
+
+        /// GET /_matrix/client/unstable/org.matrix.msc2965/auth_issuer - Delegated auth issuer
+        #[instrument(level = "debug")]
+        pub async fn get_auth_issuer_route() -> crate::Result<RumaResponse<Json<Value>>> {
+            let delegated = services().globals.config.delegated_auth.as_ref()
+                .ok_or(crate::Error::BadRequest(ErrorKind::Unrecognized, "Authentication is not delegated"))?;
+            Ok(RumaResponse(Json(json!({ "issuer": delegated.issuer }))))
+        }
+
+        /// GET /_matrix/client/v1/auth_metadata - OAuth 2.0 server metadata of the auth issuer
+        #[instrument(level = "debug")]
+        pub async fn get_auth_metadata_route() -> crate::Result<RumaResponse<Json<Value>>> {
+            let delegated = services().globals.config.delegated_auth.as_ref()
+                .ok_or(crate::Error::BadRequest(ErrorKind::Unrecognized, "Authentication is not delegated"))?;
+            Ok(RumaResponse(Json(services().delegated_auth.server_metadata(delegated).await?)))
+        }
+
+        /// GET /_matrix/client/r0/capabilities - Get server capabilities
+        #[instrument(level = "debug")]
+        pub async fn get_capabilities_route(headers: HeaderMap) -> impl IntoResponse {
+            info!("🔧 Server capabilities endpoint called");
+            let encryption_required = authenticated_device(&headers)
+                .await
+                .map(|(user_id, _)| services().globals.config.encryption_policy().requires_encryption(&user_id))
+                .unwrap_or(false);
             RumaResponse(Json(json!({
-                "user_id": format!("@{}:matrixon.local", username),
-                "access_token": format!("syt_matrixon_register_{}", timestamp),
-                "device_id": format!("MATRIXON_REG_DEVICE_{}", timestamp),
-                "home_server": "matrixon.local"
+                "capabilities": {
+                    "io.matrixon.room_encryption": {"required": encryption_required},
+                    "m.change_password": {"enabled": true},
+                    "m.room_versions": room_versions::capability(),
+                    "m.set_displayname": {"enabled": true},
+                    "m.set_avatar_url": {"enabled": true},
+                    "m.3pid_changes": {"enabled": true}
+                }
             })))
         }
 
-        /// POST /_matrix/client/r0/logout - User logout
+        /// GET /_matrix/client/r0/account/whoami - Get current user info
+        #[instrument(level = "debug")]
+        pub async fn whoami_route(headers: HeaderMap) -> crate::Result<RumaResponse<Json<Value>>> {
+            info!("👤 Whoami endpoint called");
+            let (user_id, device_id) = authenticated_device(&headers).await?;
+            
+            Ok(RumaResponse(Json(json!({
+                "user_id": user_id,
+                "device_id": device_id,
+                "is_guest": false
+            }))))
+        }
+
+        /// GET /_matrix/client/r0/login - Get available login types
+        #[instrument(level = "debug")]
+        pub async fn get_login_types_route() -> impl IntoResponse {
+            info!("🔑 Login types endpoint called");
+            if services().globals.config.delegated_auth.is_some() {
+                // Logins go through the auth provider; keep SSO for clients
+                // that are not OAuth aware yet (MSC3824)
+                return RumaResponse(Json(json!({
+                    "flows": [
+                        {"type": "m.login.sso", "org.matrix.msc3824.delegated_oidc_compatibility": true}
+                    ]
+                })));
+            }
+            RumaResponse(Json(json!({
+                "flows": [
+                    {"type": "m.login.password"},
+                    {"type": "m.login.token"},
+                    {"type": "m.login.sso"},
+                    {"type": "m.login.application_service"}
+                ]
+            })))
+        }
+
+        /// POST /_matrix/client/r0/login - User login
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn login_route(Json(payload): Json<Value>) -> crate::Result<RumaResponse<Json<Value>>> {
+            info!("🔓 User login endpoint called");
+            let server_name = &services().globals.config.server_name;
+
+            // Extract user identifier and password from payload
+            let identifier = payload.get("identifier")
+                .and_then(|i| i.get("user"))
+                .and_then(|u| u.as_str())
+                .unwrap_or("anonymous");
+            let mut user_id = match identifier.starts_with('@') {
+                true => identifier.to_owned(),
+                false => format!("@{}:{}", identifier.to_lowercase(), server_name),
+            };
+
+            // 3PID login, via `m.id.thirdparty` or the deprecated top-level fields
+            let threepid = match payload.get("identifier") {
+                Some(id) if id["type"] == "m.id.thirdparty" => Some((&id["medium"], &id["address"])),
+                _ => payload.get("medium").zip(payload.get("address")),
+            };
+            if let Some((Some(medium), Some(address))) = threepid.map(|(m, a)| (m.as_str(), a.as_str())) {
+                match services().threepids.find_user(medium, address) {
+                    Some(owner) => user_id = owner,
+                    None => return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "Unknown third-party identifier")),
+                }
+            }
+
+            let password = payload.get("password").and_then(Value::as_str).unwrap_or_default();
+            let (access_token, device_id) = match &services().repositories {
+                Some(repositories) => {
+                    let user = repositories.users.get(&user_id).await.map_err(|e| crate::Error::BadDatabase(e.to_string()))?;
+                    let valid = user.is_some_and(|user| {
+                        !user.deactivated
+                            && user.password_hash.is_some_and(|hash| crate::service::accounts::verify_password(&hash, password))
+                    });
+                    if !valid {
+                        return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "Invalid username or password"));
+                    }
+                    create_device(repositories, &user_id, &payload).await?
+                }
+                None => {
+                    let account = services().accounts.get(&user_id);
+                    let valid = !account.deactivated
+                        && account.password_hash.is_some_and(|hash| crate::service::accounts::verify_password(&hash, password));
+                    if !valid {
+                        return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "Invalid username or password"));
+                    }
+                    let device_id = requested_device_id(&payload);
+                    (services().sessions.issue_token(&user_id, &device_id), device_id)
+                }
+            };
+            
+            Ok(RumaResponse(Json(json!({
+                "user_id": user_id,
+                "access_token": access_token,
+                "device_id": device_id,
+                "well_known": {
+                    "m.NextServer": {
+                        "base_url": "http://localhost:6167"
+                    },
+                    "m.identity_server": {
+                        "base_url": "http://localhost:6167"
+                    }
+                }
+            }))))
+        }
+
+        /// POST /_matrix/client/r0/register - User registration
+        ///
+        /// With a database the account and its first device are stored;
+        /// without one the password hash is kept with the account state and
+        /// the session only in memory.
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn register_route(Json(payload): Json<Value>) -> crate::Result<RumaResponse<Json<Value>>> {
+            info!("🔐 User registration endpoint called");
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            
+            let default_username = format!("user_{}", timestamp);
+            let username = payload.get("username")
+                .and_then(|u| u.as_str())
+                .unwrap_or(&default_username)
+                .to_lowercase();
+            let server_name = &services().globals.config.server_name;
+            let user_id = format!("@{}:{}", username, server_name);
+            if !crate::service::delegated_auth::is_valid_localpart(&username)
+                || user_id.len() > crate::service::delegated_auth::MAX_USER_ID_LENGTH
+            {
+                return Err(crate::Error::BadRequest(ErrorKind::InvalidUsername, "Username is invalid"));
+            }
+            
+            let password = payload
+                .get("password")
+                .and_then(Value::as_str)
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "A password is required"))?;
+            let (user_id, access_token, device_id, home_server) = match &services().repositories {
+                Some(repositories) => {
+                    let user = matrixon_db::UserRecord {
+                        user_id: user_id.clone(),
+                        password_hash: Some(crate::service::accounts::hash_password(password)?),
+                        is_admin: false,
+                        deactivated: false,
+                        created_at: chrono::Utc::now(),
+                    };
+                    if !repositories.users.create(&user).await.map_err(|e| crate::Error::BadDatabase(e.to_string()))? {
+                        return Err(crate::Error::BadRequest(ErrorKind::UserInUse, "User ID already taken"));
+                    }
+                    let (access_token, device_id) = create_device(repositories, &user_id, &payload).await?;
+                    (user_id, access_token, device_id, server_name.clone())
+                }
+                None => {
+                    let password_hash = crate::service::accounts::hash_password(password)?;
+                    if !services().accounts.create_with_password_hash(&user_id, password_hash) {
+                        return Err(crate::Error::BadRequest(ErrorKind::UserInUse, "User ID already taken"));
+                    }
+                    let device_id = requested_device_id(&payload);
+                    let access_token = services().sessions.issue_token(&user_id, &device_id);
+                    (user_id, access_token, device_id, server_name.clone())
+                }
+            };
+            // Joining can wait on remote servers; the account is usable
+            // without it, so registration does not wait for it
+            let joining = user_id.clone();
+            tokio::spawn(async move {
+                services().auto_join.join_new_user(&joining).await;
+            });
+            services().webhooks.notify(WebhookEvent::UserRegistered { user_id: user_id.clone() });
+            
+            Ok(RumaResponse(Json(json!({
+                "user_id": user_id,
+                "access_token": access_token,
+                "device_id": device_id,
+                "home_server": home_server
+            }))))
+        }
+
+        /// Store a new session of `user_id` for the device a login or
+        /// registration asks for, returning its access token and device ID
+        async fn create_device(
+            repositories: &matrixon_db::Repositories,
+            user_id: &str,
+            payload: &Value,
+        ) -> crate::Result<(String, String)> {
+            let now = chrono::Utc::now();
+            let device = matrixon_db::DeviceRecord {
+                user_id: user_id.to_owned(),
+                device_id: requested_device_id(payload),
+                display_name: payload.get("initial_device_display_name").and_then(Value::as_str).map(str::to_owned),
+                access_token: crate::service::sessions::new_access_token(),
+                last_seen_ip: None,
+                last_seen_ts: Some(now),
+                created_at: now,
+            };
+            repositories.devices.upsert(&device).await.map_err(|e| crate::Error::BadDatabase(e.to_string()))?;
+            services().sessions.login(user_id, &device.device_id);
+            Ok((device.access_token, device.device_id))
+        }
+
+        /// The device a login or registration asks for, or a new one
+        fn requested_device_id(payload: &Value) -> String {
+            payload
+                .get("device_id")
+                .and_then(Value::as_str)
+                .map_or_else(crate::service::sessions::new_device_id, str::to_owned)
+        }
+
+        /// POST /_matrix/client/r0/logout - User logout
+        ///
+        /// Invalidates the device's access token and to-device inbox; syncs
+        /// the device is waiting on return at once.
+        #[instrument(level = "debug")]
+        pub async fn logout_route(headers: HeaderMap) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, device_id) = authenticated_device(&headers).await?;
+            info!("🔒 {} logging out device {}", user_id, device_id);
+            services().sessions.logout(&user_id, &device_id);
+            services().keys.remove_device(&user_id, &device_id);
+            if let Some(repositories) = &services().repositories {
+                repositories.devices.delete(&user_id, &device_id).await.map_err(|e| crate::Error::BadDatabase(e.to_string()))?;
+            }
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// POST /_matrix/client/r0/logout/all - Logout all devices
+        #[instrument(level = "debug")]
+        pub async fn logout_all_route(headers: HeaderMap) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, device_id) = authenticated_device(&headers).await?;
+            let mut devices = services().sessions.logout_all(&user_id, &device_id);
+            if let Some(repositories) = &services().repositories {
+                let stored = repositories.devices.list(&user_id).await.map_err(|e| crate::Error::BadDatabase(e.to_string()))?;
+                for device in stored {
+                    if !devices.contains(&device.device_id) {
+                        services().sessions.logout(&user_id, &device.device_id);
+                        devices.push(device.device_id.clone());
+                    }
+                    repositories
+                        .devices
+                        .delete(&user_id, &device.device_id)
+                        .await
+                        .map_err(|e| crate::Error::BadDatabase(e.to_string()))?;
+                }
+            }
+            for device_id in &devices {
+                services().keys.remove_device(&user_id, device_id);
+            }
+            info!("🔒 {} logged out of {} devices", user_id, devices.len());
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// PUT /_matrix/client/v3/sendToDevice/{eventType}/{txnId}
+        ///
+        /// Queue messages for devices of local users; `*` addresses every
+        /// known device of a user. Messages for users of other servers are
+        /// sent to them as one `m.direct_to_device` EDU per server. A
+        /// retried transaction is not delivered again.
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn send_to_device_route(
+            headers: HeaderMap,
+            Path((event_type, txn_id)): Path<(String, String)>,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (sender, sender_device) = authenticated_device(&headers).await?;
+            let messages = payload["messages"]
+                .as_object()
+                .ok_or(crate::Error::BadRequest(ErrorKind::BadJson, "messages must be an object"))?;
+            if !services().sessions.first_to_device_txn(&sender, &sender_device, &txn_id) {
+                debug!("🔁 {} already sent to-device transaction {}", sender, txn_id);
+                return Ok(RumaResponse(Json(json!({}))));
+            }
+            let config = &services().globals.config;
+            let mut remote: std::collections::BTreeMap<&str, serde_json::Map<String, Value>> = std::collections::BTreeMap::new();
+            for (user_id, devices) in messages {
+                let Some((_, server)) = user_id.split_once(':') else {
+                    continue;
+                };
+                if server != config.server_name {
+                    if config.allow_federation {
+                        remote.entry(server).or_default().insert(user_id.clone(), devices.clone());
+                    } else {
+                        debug!("📨 Not sending {} to remote user {}", event_type, user_id);
+                    }
+                    continue;
+                }
+                for (device_id, content) in devices.as_object().into_iter().flatten() {
+                    let device_ids = if device_id == "*" {
+                        services().sessions.devices(user_id)
+                    } else {
+                        vec![device_id.clone()]
+                    };
+                    for device_id in device_ids {
+                        services().sessions.queue_to_device(
+                            &services().timeline,
+                            &sender,
+                            user_id,
+                            &device_id,
+                            &event_type,
+                            content.clone(),
+                        );
+                    }
+                }
+            }
+            for (server, messages) in remote {
+                services().sending.send_edu(server, json!({
+                    "edu_type": "m.direct_to_device",
+                    "content": {
+                        "sender": sender,
+                        "type": event_type,
+                        "message_id": uuid::Uuid::new_v4().simple().to_string(),
+                        "messages": messages
+                    }
+                }));
+            }
+            debug!("📨 {} sent {} to {} users in {}", sender, event_type, messages.len(), txn_id);
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// POST /_matrix/client/r0/createRoom - Create a new room
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn create_room_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            services().accounts.ensure_not_suspended(&user_id)?;
+            info!("🏠 Room creation requested by {}", user_id);
+            let room_id = format!("!{}:matrixon.local", uuid::Uuid::new_v4().simple());
+            let timeline = &services().timeline;
+
+            let room_version = payload
+                .get("room_version")
+                .and_then(Value::as_str)
+                .unwrap_or(room_versions::DEFAULT_ROOM_VERSION);
+            room_versions::check_supported(room_version)?;
+            let room_alias = match payload.get("room_alias_name").and_then(Value::as_str) {
+                Some(name) if name.is_empty() || name.contains(|c: char| c == ':' || c.is_whitespace()) => {
+                    return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "Invalid room_alias_name"));
+                }
+                Some(name) => Some(format!("#{}:{}", name, services().globals.config.server_name)),
+                None => None,
+            };
+            if room_alias.as_ref().is_some_and(|alias| services().room_aliases.local(alias).is_some()) {
+                return Err(crate::Error::BadRequest(ErrorKind::RoomInUse, "Room alias already taken"));
+            }
+            let mut create_content = payload.get("creation_content").cloned().unwrap_or_else(|| json!({}));
+            create_content["creator"] = json!(user_id);
+            create_content["room_version"] = json!(room_version);
+            timeline.append_event(&room_id, &user_id, "m.room.create", Some(""), create_content);
+            timeline.append_event(&room_id, &user_id, "m.room.member", Some(&user_id), json!({ "membership": "join" }));
+
+            let mut power_levels = json!({ "users": { user_id.clone(): 100 } });
+            if let Some(overrides) = payload.get("power_level_content_override").and_then(Value::as_object) {
+                for (key, value) in overrides {
+                    power_levels[key] = value.clone();
+                }
+            }
+            timeline.append_event(&room_id, &user_id, "m.room.power_levels", Some(""), power_levels);
+
+            let is_public = payload.get("visibility").and_then(Value::as_str) == Some("public");
+            if let Some(repositories) = &services().repositories {
+                let room = matrixon_db::RoomRecord {
+                    room_id: room_id.clone(),
+                    creator: user_id.clone(),
+                    room_version: room_version.to_owned(),
+                    is_public,
+                    created_at: chrono::Utc::now(),
+                };
+                repositories.rooms.create(&room).await.map_err(|e| crate::Error::BadDatabase(e.to_string()))?;
+            }
+            let preset = payload
+                .get("preset")
+                .and_then(Value::as_str)
+                .unwrap_or(if is_public { "public_chat" } else { "private_chat" });
+            let join_rule = if preset == "public_chat" { "public" } else { "invite" };
+            timeline.append_event(&room_id, &user_id, "m.room.join_rules", Some(""), json!({ "join_rule": join_rule }));
+            timeline.append_event(&room_id, &user_id, "m.room.history_visibility", Some(""), json!({ "history_visibility": "shared" }));
+
+            let policy = services().globals.config.encryption_policy();
+            let mut has_encryption = false;
+            for event in payload.get("initial_state").and_then(Value::as_array).into_iter().flatten() {
+                let Some(event_type) = event.get("type").and_then(Value::as_str) else {
+                    continue;
+                };
+                let content = event.get("content").cloned().unwrap_or_else(|| json!({}));
+                policy.check_state_event(&user_id, event_type, &content)?;
+                has_encryption |= event_type == "m.room.encryption";
+                let state_key = event.get("state_key").and_then(Value::as_str).unwrap_or("");
+                timeline.append_event(&room_id, &user_id, event_type, Some(state_key), content);
+            }
+            if !has_encryption && policy.requires_encryption(&user_id) {
+                debug!("🔒 Encryption policy applies to {}, encrypting {}", user_id, room_id);
+                timeline.append_event(
+                    &room_id,
+                    &user_id,
+                    "m.room.encryption",
+                    Some(""),
+                    crate::service::encryption_policy::encryption_event_content(),
+                );
+            }
+
+            if let Some(name) = payload.get("name").and_then(Value::as_str) {
+                timeline.append_event(&room_id, &user_id, "m.room.name", Some(""), json!({ "name": name }));
+            }
+            if let Some(topic) = payload.get("topic").and_then(Value::as_str) {
+                timeline.append_event(&room_id, &user_id, "m.room.topic", Some(""), json!({ "topic": topic }));
+            }
+            for invitee in payload.get("invite").and_then(Value::as_array).into_iter().flatten() {
+                if let Some(invitee) = invitee.as_str() {
+                    timeline.append_event(&room_id, &user_id, "m.room.member", Some(invitee), json!({ "membership": "invite" }));
+                }
+            }
+
+            if let Some(alias) = &room_alias {
+                services().room_aliases.create(timeline, &services().globals.config.server_name, alias, &room_id, &user_id)?;
+                timeline.append_event(&room_id, &user_id, "m.room.canonical_alias", Some(""), json!({ "alias": alias }));
+            }
+            if is_public {
+                services().room_directory.publish(&room_id);
+            }
+
+            services().webhooks.notify(WebhookEvent::RoomCreated { room_id: room_id.clone(), creator: user_id });
+            Ok(RumaResponse(Json(json!({
+                "room_id": room_id,
+                "room_alias": room_alias
+            }))))
+        }
+
+        /// GET /_matrix/client/r0/joined_rooms - Get joined rooms
+        #[instrument(level = "debug")]
+        pub async fn joined_rooms_route(headers: HeaderMap) -> impl IntoResponse {
+            info!("🏠 Joined rooms endpoint called");
+            RumaResponse(Json(json!({
+                "joined_rooms": [
+                    "!matrixon_general:matrixon.local",
+                    "!matrixon_test:matrixon.local",
+                    "!matrixon_development:matrixon.local"
+                ]
+            })))
+        }
+
+        /// GET /_matrix/client/r0/sync - Sync events
+        ///
+        /// The response is streamed: joined rooms are computed and
+        /// serialized one at a time while the body is sent. Incremental
+        /// syncs wait up to `timeout` milliseconds for something to happen.
+        #[instrument(level = "debug")]
+        pub async fn sync_events_route(
+            headers: HeaderMap,
+            Query(params): Query<HashMap<String, String>>,
+        ) -> axum::response::Response {
+            const MAX_TIMEOUT_MS: u64 = 60_000;
+            info!("🔄 Sync events endpoint called with params: {:?}", params);
+            // Batch tokens are `s<stream count>`; anything else is an initial sync
+            let since = params.get("since").and_then(|since| since.strip_prefix('s')?.parse::<u64>().ok());
+            let current = services().timeline.current_count();
+            // A token from beyond the stream (the server lost events it had
+            // handed out) cannot be resumed from; start over with full state
+            let since = since.filter(|since| {
+                let known = *since <= current;
+                if !known {
+                    warn!("🔄 Sync token s{} is ahead of the stream at {}, sending a full sync", since, current);
+                }
+                known
+            });
+
+            // Missing, unknown, soft-logged-out and deactivated tokens all
+            // fail the sync, so clients learn to log in again
+            let (user_id, device_id) = match authenticated_device(&headers).await {
+                Ok(device) => device,
+                Err(e) => return e.into_response(),
+            };
+            if let Some(since) = since {
+                let timeout = params.get("timeout").and_then(|timeout| timeout.parse::<u64>().ok()).unwrap_or(0);
+                let timeout = std::time::Duration::from_millis(timeout.min(MAX_TIMEOUT_MS));
+                if let Err(e) = wait_for_sync_updates(&user_id, &device_id, since, timeout).await {
+                    return e.into_response();
+                }
+            }
+            let next_batch = services().timeline.current_count();
+            let filter = params
+                .get("filter")
+                .and_then(|filter| serde_json::from_str::<Value>(filter).ok())
+                .unwrap_or_default();
+            let options = SyncOptions {
+                timeline_limit: filter["room"]["timeline"]["limit"]
+                    .as_u64()
+                    .map_or(SyncOptions::DEFAULT_TIMELINE_LIMIT, |limit| (limit as usize).clamp(1, 100)),
+                full_state: params.get("full_state").is_some_and(|full_state| full_state == "true"),
+                lazy_load_members: filter["room"]["state"]["lazy_load_members"] == true,
+                include_redundant_members: filter["room"]["state"]["include_redundant_members"] == true,
+            };
+            if since.is_none() && options.lazy_load_members {
+                // A client starting over has no members yet
+                services().sessions.reset_lazy_loaded(&user_id, &device_id);
+            }
+
+            let one_time_keys_count = services().keys.one_time_key_counts(&user_id, &device_id);
+            let unused_fallback_key_types = services().keys.unused_fallback_key_types(&user_id, &device_id);
+            let invited_rooms = sync_stripped_rooms(&user_id, "invite", since, next_batch);
+            let knocked_rooms = sync_stripped_rooms(&user_id, "knock", since, next_batch);
+            let to_device = services().sessions.to_device_events(&user_id, &device_id, since, next_batch);
+            let joined_rooms = crate::service::membership::joined_rooms(&user_id).into_iter().filter_map(move |room_id| {
+                sync_joined_room(&user_id, &device_id, &room_id, since, next_batch, options).map(|room| (room_id, room))
+            });
+
+            crate::service::json_stream::response(SyncResponse {
+                next_batch: format!("s{}", next_batch),
+                rooms: SyncRooms {
+                    join: crate::service::json_stream::LazyMap::new(joined_rooms),
+                    invite: invited_rooms,
+                    leave: serde_json::Map::new(),
+                    knock: knocked_rooms,
+                },
+                rest: json!({
+                    "presence": {
+                        "events": []
+                    },
+                    "account_data": {
+                        "events": []
+                    },
+                    "to_device": {
+                        "events": to_device
+                    },
+                    "device_lists": {
+                        "changed": [],
+                        "left": []
+                    },
+                    "device_one_time_keys_count": one_time_keys_count,
+                    "device_unused_fallback_key_types": unused_fallback_key_types,
+                    "org.matrix.msc2732.device_unused_fallback_key_types": unused_fallback_key_types
+                }),
+            })
+        }
+
+        /// Wait until the stream moves past `since` or `timeout` passes.
+        /// Fails as soon as the device is logged out.
+        async fn wait_for_sync_updates(
+            user_id: &str,
+            device_id: &str,
+            since: u64,
+            timeout: std::time::Duration,
+        ) -> crate::Result<()> {
+            let deadline = tokio::time::Instant::now() + timeout;
+            loop {
+                let advanced = services().timeline.advanced();
+                let changed = services().sessions.changed();
+                if services().sessions.is_logged_out(user_id, device_id) {
+                    return Err(logged_out());
+                }
+                if services().timeline.current_count() > since {
+                    return Ok(());
+                }
+                tokio::select! {
+                    _ = advanced => {}
+                    _ = changed => {}
+                    _ = tokio::time::sleep_until(deadline) => return Ok(()),
+                }
+            }
+        }
+
+        #[derive(serde::Serialize)]
+        struct SyncResponse<J> {
+            next_batch: String,
+            rooms: SyncRooms<J>,
+            #[serde(flatten)]
+            rest: Value,
+        }
+
+        #[derive(serde::Serialize)]
+        struct SyncRooms<J> {
+            join: J,
+            invite: serde_json::Map<String, Value>,
+            leave: serde_json::Map<String, Value>,
+            knock: serde_json::Map<String, Value>,
+        }
+
+        /// Per-request options of a sync
+        #[derive(Debug, Clone, Copy)]
+        struct SyncOptions {
+            /// Timeline events per room, from an inline filter's
+            /// `room.timeline.limit`
+            timeline_limit: usize,
+            /// Send the full state of every room, even on incremental syncs
+            full_state: bool,
+            /// Only send the memberships of timeline senders, once per
+            /// device, from the filter's `room.state.lazy_load_members`
+            lazy_load_members: bool,
+            /// Send the memberships of timeline senders even if the device
+            /// has them already
+            include_redundant_members: bool,
+        }
+
+        impl SyncOptions {
+            const DEFAULT_TIMELINE_LIMIT: usize = 10;
+        }
+
+        /// A room of `rooms.join` in a sync response, `None` if nothing
+        /// happened in it since `since`.
+        ///
+        /// `state` is the state at the start of the timeline: all of it on
+        /// initial syncs, after joining and with `full_state`, otherwise only
+        /// what changed in the events skipped over when the timeline is
+        /// `limited`. `prev_batch` paginates back from the first timeline
+        /// event into that gap. Clients lazy-loading members get only the
+        /// memberships of timeline senders their device does not have yet.
+        fn sync_joined_room(
+            user_id: &str,
+            device_id: &str,
+            room_id: &str,
+            since: Option<u64>,
+            next_batch: u64,
+            options: SyncOptions,
+        ) -> Option<SyncJoinedRoom> {
+            let timeline = &services().timeline;
+            let threshold = services().globals.config.large_room_member_threshold();
+
+            let (events, limited, prev_position, prev_batch) =
+                timeline.serialized_events_between(room_id, since.unwrap_or(0), next_batch, options.timeline_limit);
+            if since.is_some() && events.is_empty() && !options.full_state {
+                return None;
+            }
+
+            let summary = services().room_summary.summary(room_id, user_id);
+            let mut room = json!({
+                "state": { "events": [] },
+                "summary": summary.to_sync_json(),
+                "ephemeral": { "events": [] },
+                "account_data": { "events": [] }
+            });
+
+            let joined_since = since.is_some_and(|since| {
+                timeline
+                    .state_events_between(room_id, "m.room.member", since, next_batch)
+                    .0
+                    .iter()
+                    .any(|event| event["state_key"] == user_id && event["content"]["membership"] == "join")
+            });
+            let state = match since {
+                Some(since) if !options.full_state && !joined_since && limited => {
+                    timeline.state_between(room_id, since, prev_position)
+                }
+                // Every state change since is in the timeline
+                Some(_) if !options.full_state && !joined_since => Vec::new(),
+                _ => timeline.state_before(room_id, prev_position),
+            };
+            let heads: Vec<EventHead<'_>> = events.iter().filter_map(|e| serde_json::from_str(e.get()).ok()).collect();
+            if options.lazy_load_members {
+                let mut senders: Vec<&str> = heads.iter().filter_map(|e| e.sender.as_deref()).collect();
+                senders.sort_unstable();
+                senders.dedup();
+                let sessions = &services().sessions;
+                let known_since = since.filter(|_| !options.include_redundant_members);
+                let state = crate::service::room_summary::lazy_member_state(
+                    state,
+                    user_id,
+                    &senders,
+                    |member| known_since.is_some_and(|since| sessions.is_lazy_loaded(user_id, device_id, room_id, member, since)),
+                    |member| timeline.state_event_before(room_id, "m.room.member", member, prev_position),
+                );
+                // Memberships in the timeline reach the client too
+                let sent = state
+                    .iter()
+                    .filter(|event| event["type"] == "m.room.member")
+                    .filter_map(|event| event["state_key"].as_str())
+                    .chain(heads.iter().filter(|e| e.kind.as_deref() == Some("m.room.member")).filter_map(|e| e.state_key.as_deref()));
+                sessions.mark_lazy_loaded(user_id, device_id, room_id, sent, next_batch);
+                room["state"]["events"] = json!(state);
+            } else if !state.is_empty() {
+                let senders: std::collections::HashSet<&str> = heads.iter().filter_map(|e| e.sender.as_deref()).collect();
+                let (state, omitted_members) =
+                    crate::service::room_summary::batch_member_state(state, user_id, &senders, threshold);
+                room["state"]["events"] = json!(state);
+                if omitted_members > 0 {
+                    // Hint that the member list is incomplete and must be paged in via /members
+                    room["io.matrixon.member_list"] = json!({ "complete": false, "omitted": omitted_members });
+                }
+            }
+            Some(SyncJoinedRoom {
+                timeline: SyncTimeline { events, limited, prev_batch: prev_batch.to_string() },
+                rest: room,
+            })
+        }
+
+        /// A room of `rooms.join`; timeline events are copied from their
+        /// stored serialization
+        #[derive(serde::Serialize)]
+        struct SyncJoinedRoom {
+            timeline: SyncTimeline,
+            #[serde(flatten)]
+            rest: Value,
+        }
+
+        #[derive(serde::Serialize)]
+        struct SyncTimeline {
+            events: Vec<crate::service::timeline::SerializedEvent>,
+            limited: bool,
+            prev_batch: String,
+        }
+
+        /// The fields of a serialized event the sync state needs
+        #[derive(serde::Deserialize)]
+        struct EventHead<'a> {
+            #[serde(borrow)]
+            sender: Option<std::borrow::Cow<'a, str>>,
+            #[serde(borrow, rename = "type")]
+            kind: Option<std::borrow::Cow<'a, str>>,
+            #[serde(borrow)]
+            state_key: Option<std::borrow::Cow<'a, str>>,
+        }
+
+        /// `rooms.invite` or `rooms.knock` of a sync response: rooms where the
+        /// user's membership is `membership`, with their stripped state.
+        /// Incremental syncs only include rooms whose membership changed up
+        /// to `next_batch`.
+        fn sync_stripped_rooms(
+            user_id: &str,
+            membership: &str,
+            since: Option<u64>,
+            next_batch: u64,
+        ) -> serde_json::Map<String, Value> {
+            let state_field = format!("{}_state", membership);
+            let mut rooms: serde_json::Map<String, Value> = crate::service::membership::rooms_with_membership(user_id, membership)
+                .into_iter()
+                .filter(|room_id| {
+                    let Some(since) = since else { return true };
+                    let (changes, _) = services().timeline.state_events_between(room_id, "m.room.member", since, next_batch);
+                    changes.iter().any(|event| event["state_key"] == user_id)
+                })
+                .map(|room_id| {
+                    let events = crate::service::membership::stripped_state(&room_id, user_id);
+                    (room_id, json!({ state_field.clone(): { "events": events } }))
+                })
+                .collect();
+            if membership == "invite" {
+                // Invites to rooms on other servers that arrived over federation
+                for (room_id, invite) in services().membership.remote_invites(user_id) {
+                    if since.is_none_or(|since| invite.count > since) && invite.count <= next_batch {
+                        rooms.insert(room_id, json!({ "invite_state": { "events": invite.invite_state } }));
+                    }
+                }
+            } else if membership == "knock" {
+                // Knocks on rooms on other servers, sent over federation
+                for (room_id, knock) in services().membership.remote_knocks(user_id) {
+                    if since.is_none_or(|since| knock.count > since) && knock.count <= next_batch {
+                        rooms.insert(room_id, json!({ "knock_state": { "events": knock.knock_state } }));
+                    }
+                }
+            }
+            rooms
+        }
+
+        /// Servers to join or knock through, from the `via` (or deprecated
+        /// `server_name`) query parameters of the request
+        pub fn via_servers(query: Option<&str>) -> Vec<String> {
+            url::form_urlencoded::parse(query.unwrap_or_default().as_bytes())
+                .filter(|(key, _)| key == "via" || key == "server_name")
+                .map(|(_, server)| server.into_owned())
+                .collect()
+        }
+
+        /// Room id of a `roomIdOrAlias` path parameter and the servers to
+        /// join it through: those of `via`, then the ones an alias resolved to
+        pub async fn resolve_room_id_or_alias(room_id_or_alias: &str, mut via: Vec<String>) -> crate::Result<(String, Vec<String>)> {
+            if room_id_or_alias.starts_with('!') {
+                return Ok((room_id_or_alias.to_owned(), via));
+            }
+            if !room_id_or_alias.starts_with('#') {
+                return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "Not a room ID or alias"));
+            }
+            let (room_id, servers) = services()
+                .room_aliases
+                .resolve(&services().timeline, &services().sending, &services().globals.config.server_name, room_id_or_alias)
+                .await?;
+            for server in servers {
+                if !via.contains(&server) {
+                    via.push(server);
+                }
+            }
+            Ok((room_id, via))
+        }
+
+        /// POST /_matrix/client/v3/knock/{roomIdOrAlias} - Ask to join a room
+        ///
+        /// Room moderators approve a knock by inviting the user, or deny it
+        /// by kicking them. Rooms this server is not in are knocked on
+        /// through the servers of `via` or those of the alias.
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn knock_room_route(
+            Path(room_id_or_alias): Path<String>,
+            RawQuery(query): RawQuery,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            services().accounts.ensure_not_suspended(&user_id)?;
+            let (room_id, via) = resolve_room_id_or_alias(&room_id_or_alias, via_servers(query.as_deref())).await?;
+            let reason = payload.get("reason").and_then(Value::as_str);
+            services().membership.knock(&room_id, &user_id, reason, &via).await?;
+            Ok(RumaResponse(Json(json!({ "room_id": room_id }))))
+        }
+
+        /// GET /_matrix/client/v3/rooms/{roomId}/members - Membership events of a room
+        ///
+        /// Besides the standard `membership` / `not_membership` filters this
+        /// accepts `limit` and `from` so clients of very large rooms can
+        /// complete the member list in pages, following `next_batch`.
+        #[instrument(level = "debug")]
+        pub async fn get_member_events_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+            Query(params): Query<HashMap<String, String>>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            if crate::service::membership::membership(&room_id, &user_id).as_deref() != Some("join") {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "You are not joined to this room"));
+            }
+            services()
+                .inbound_federation
+                .partial_state()
+                .wait_for_full_state(&room_id, crate::service::partial_state::FULL_STATE_WAIT)
+                .await?;
+
+            let members: Vec<Value> = services()
+                .timeline
+                .current_state(&room_id)
+                .into_iter()
+                .filter(|event| event["type"] == "m.room.member")
+                .filter(|event| {
+                    let membership = event["content"]["membership"].as_str();
+                    params.get("membership").is_none_or(|m| membership == Some(m.as_str()))
+                        && params.get("not_membership").is_none_or(|m| membership != Some(m.as_str()))
+                })
+                .collect();
+
+            let from = params.get("from").and_then(|from| from.parse::<usize>().ok()).unwrap_or(0).min(members.len());
+            let limit = params.get("limit").and_then(|limit| limit.parse::<usize>().ok()).unwrap_or(members.len());
+            let end = from.saturating_add(limit).min(members.len());
+
+            let mut response = json!({ "chunk": members[from..end] });
+            if end < members.len() {
+                response["next_batch"] = json!(end.to_string());
+            }
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// GET /_matrix/client/v3/rooms/{roomId}/joined_members - Joined members and their profiles
+        #[instrument(level = "debug")]
+        pub async fn joined_members_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            if crate::service::membership::membership(&room_id, &user_id).as_deref() != Some("join") {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "You are not joined to this room"));
+            }
+            services()
+                .inbound_federation
+                .partial_state()
+                .wait_for_full_state(&room_id, crate::service::partial_state::FULL_STATE_WAIT)
+                .await?;
+
+            let joined: serde_json::Map<String, Value> = services()
+                .timeline
+                .current_state(&room_id)
+                .into_iter()
+                .filter(|event| event["type"] == "m.room.member" && event["content"]["membership"] == "join")
+                .filter_map(|event| {
+                    let member = event["state_key"].as_str()?.to_owned();
+                    Some((member, json!({
+                        "display_name": event["content"]["displayname"],
+                        "avatar_url": event["content"]["avatar_url"]
+                    })))
+                })
+                .collect();
+            Ok(RumaResponse(Json(json!({ "joined": joined }))))
+        }
+
+        /// GET /_matrixon/client/v1/rooms/{roomId}/join_status - Progress of a
+        /// federated join of the user that was answered with a retry
+        #[instrument(level = "debug")]
+        pub async fn join_status_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            if let Some(status) = services().join_coordinator.status(&room_id, &user_id) {
+                return Ok(RumaResponse(Json(status)));
+            }
+            if crate::service::membership::membership(&room_id, &user_id).as_deref() == Some("join") {
+                return Ok(RumaResponse(Json(json!({ "state": "joined" }))));
+            }
+            Err(crate::Error::BadRequest(ErrorKind::NotFound, "No join of this room is in progress"))
+        }
+
+        /// The room webhook service and the authenticated user, who must be
+        /// an admin of the room
+        async fn room_webhook_admin(
+            headers: &HeaderMap,
+            room_id: &str,
+        ) -> crate::Result<(&'static crate::service::room_webhooks::Service, String)> {
+            let (user_id, _) = authenticated_device(headers).await?;
+            let room_webhooks = services().room_webhooks.as_ref()
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Room webhooks are disabled"))?;
+            if !crate::service::membership::is_room_admin(&services().timeline, room_id, &user_id) {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "Only room admins can manage its webhooks"));
+            }
+            Ok((room_webhooks, user_id))
+        }
+
+        /// GET /_matrixon/client/v1/rooms/{roomId}/webhooks - Webhooks of a room
+        #[instrument(level = "debug")]
+        pub async fn get_room_webhooks_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (room_webhooks, _) = room_webhook_admin(&headers, &room_id).await?;
+            Ok(RumaResponse(Json(json!({ "webhooks": room_webhooks.webhooks(&room_id) }))))
+        }
+
+        /// POST /_matrixon/client/v1/rooms/{roomId}/webhooks - Register a
+        /// webhook for the events of a room matching its `types`, `senders`
+        /// and `keywords`. The response carries the `secret` deliveries are
+        /// signed with, generated unless given.
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn register_room_webhook_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (room_webhooks, user_id) = room_webhook_admin(&headers, &room_id).await?;
+            let url = payload["url"].as_str()
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "A url is required to register a webhook"))?;
+            let secret = match payload.get("secret") {
+                None | Some(Value::Null) => None,
+                Some(secret) => Some(secret.as_str()
+                    .ok_or(crate::Error::BadRequest(ErrorKind::InvalidParam, "secret must be a string"))?
+                    .to_owned()),
+            };
+            let filter = serde_json::from_value(payload.clone())
+                .map_err(|_| crate::Error::BadRequest(ErrorKind::InvalidParam, "types, senders and keywords must be lists of strings"))?;
+            let webhook = room_webhooks.register(&room_id, &user_id, url, secret, filter)?;
+            let mut response = json!(webhook);
+            response["secret"] = json!(webhook.secret);
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// DELETE /_matrixon/client/v1/rooms/{roomId}/webhooks/{webhookId} -
+        /// Remove a webhook of a room
+        #[instrument(level = "debug")]
+        pub async fn delete_room_webhook_route(
+            Path((room_id, webhook_id)): Path<(String, String)>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (room_webhooks, user_id) = room_webhook_admin(&headers, &room_id).await?;
+            room_webhooks.remove(&room_id, &webhook_id)
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Unknown webhook"))?;
+            info!("🪝 {} removed webhook {} of {}", user_id, webhook_id, room_id);
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// POST /_matrix/client/unstable/org.matrix.msc2716/rooms/{roomId}/batch_send
+        /// - Import a batch of historical events right after `prev_event_id`,
+        /// for application services and server admins. Batches sent to the
+        /// same anchor go before the ones sent earlier, so history is
+        /// imported going back in time. Application services send as the
+        /// users of their namespaces, by default the one given as `user_id`,
+        /// and server admins as any user of this server; senders must be
+        /// members of the room as of `prev_event_id`. The imported events
+        /// are sent to the other servers in the room.
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn batch_send_route(
+            Path(room_id): Path<String>,
+            Query(params): Query<HashMap<String, String>>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            const MAX_BATCH_EVENTS: usize = 1000;
+
+            let token = headers.get("authorization")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "));
+            let appservice = token.and_then(|token| services().appservices.authenticate(token));
+            let user_id = match appservice {
+                Some(appservice) => params.get("user_id").cloned().unwrap_or_else(|| appservice.sender.clone()),
+                None => authenticated_admin(&headers).await?,
+            };
+            let config = &services().globals.config;
+            let timeline = &services().timeline;
+            let prev_event_id = params.get("prev_event_id")
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "Missing prev_event_id"))?;
+            let anchor = timeline.position(&room_id, prev_event_id)
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Unknown prev_event_id"))?;
+            let events = payload["events"].as_array()
+                .filter(|events| !events.is_empty())
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "A batch needs a list of events"))?;
+            if events.len() > MAX_BATCH_EVENTS {
+                return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "Too many events in one batch"));
+            }
+
+            let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+            let local_suffix = format!(":{}", config.server_name);
+            let mut members = std::collections::HashSet::new();
+            let mut batch = Vec::with_capacity(events.len());
+            for event in events {
+                let event_type = event["type"].as_str()
+                    .ok_or(crate::Error::BadRequest(ErrorKind::BadJson, "Every event needs a type"))?;
+                if event.get("state_key").is_some() {
+                    return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "State events cannot be imported as history"));
+                }
+                let sender = event["sender"].as_str().unwrap_or(&user_id);
+                if !sender.ends_with(&local_suffix) {
+                    return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "Events can only be sent as users of this server"));
+                }
+                if appservice.is_some_and(|appservice| !appservice.is_user_in_namespace(sender)) {
+                    return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "Events can only be sent as users of the application service"));
+                }
+                if !members.contains(sender) {
+                    let membership = timeline.state_event_before(&room_id, "m.room.member", sender, anchor + 1);
+                    if membership.is_none_or(|membership| membership["content"]["membership"] != "join") {
+                        return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "Senders must be members of the room at prev_event_id"));
+                    }
+                    members.insert(sender.to_owned());
+                }
+                let content = event.get("content").filter(|content| content.is_object())
+                    .ok_or(crate::Error::BadRequest(ErrorKind::BadJson, "Every event needs a content object"))?;
+                batch.push(json!({
+                    "type": event_type,
+                    "sender": sender,
+                    "content": content,
+                    "origin_server_ts": event["origin_server_ts"].as_u64().unwrap_or(now_ms),
+                }));
+            }
+
+            let imported = timeline.insert_historical(&room_id, prev_event_id, batch)
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Unknown prev_event_id"))?;
+            // Imported events are not in the event stream outbound federation follows
+            if config.allow_federation {
+                for event in &imported {
+                    crate::service::outbound_federation::federate(event, &config.server_name);
+                }
+            }
+            let event_ids: Vec<&Value> = imported.iter().map(|event| &event["event_id"]).collect();
+            info!("📜 {} imported {} historical events into {} after {}", user_id, event_ids.len(), room_id, prev_event_id);
+            Ok(RumaResponse(Json(json!({ "event_ids": event_ids }))))
+        }
+
+        /// PUT /_matrix/client/r0/rooms/{roomId}/send/{eventType}/{txnId} - Send message
+        #[instrument(level = "debug")]
+        pub async fn send_message_event_route(
+            Path((room_id, event_type, txn_id)): Path<(String, String, String)>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>
+        ) -> impl IntoResponse {
+            info!("💬 Message send endpoint called - Room: {}, Type: {}, TxnId: {}", room_id, event_type, txn_id);
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            let event_id = format!("$matrixon_event_{}:matrixon.local", timestamp);
+            
+            RumaResponse(Json(json!({
+                "event_id": event_id
+            })))
+        }
+
+        /// GET /_matrix/client/r0/publicRooms - Get public rooms
+        ///
+        /// With `server` set to another server, its directory is browsed
+        /// over federation; only authenticated users may do that.
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn get_public_rooms_route(
+            headers: HeaderMap,
+            Query(params): Query<HashMap<String, String>>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let limit = params.get("limit").and_then(|limit| limit.parse::<usize>().ok());
+            let server = params.get("server").map(String::as_str);
+            if server.is_some_and(|server| server != services().globals.config.server_name) {
+                authenticated_device(&headers).await?;
+            }
+            let response = public_rooms(server, limit, params.get("since").map(String::as_str), None).await?;
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// POST /_matrix/client/r0/publicRooms - Search public rooms
+        #[instrument(level = "debug", skip(headers, payload))]
+        pub async fn get_public_rooms_filtered_route(
+            headers: HeaderMap,
+            Query(params): Query<HashMap<String, String>>,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_device(&headers).await?;
+            let limit = payload["limit"].as_u64().map(|limit| limit as usize);
+            let filter = payload.get("filter").filter(|filter| filter.is_object()).cloned();
+            let response = public_rooms(params.get("server").map(String::as_str), limit, payload["since"].as_str(), filter).await?;
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// GET /_matrix/client/v1/rooms/{roomId}/hierarchy - Rooms of a space and its subspaces
+        #[instrument(level = "debug")]
+        pub async fn get_hierarchy_route(
+            Path(room_id): Path<String>,
+            Query(params): Query<HashMap<String, String>>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let number = |name: &str| -> crate::Result<Option<usize>> {
+                params.get(name).map(|value| value.parse::<usize>()).transpose()
+                    .map_err(|_| crate::Error::BadRequest(ErrorKind::InvalidParam, "limit and max_depth must be numbers"))
+            };
+            let request = crate::service::space_hierarchy::HierarchyRequest {
+                limit: number("limit")?,
+                max_depth: number("max_depth")?,
+                from: params.get("from").cloned(),
+                suggested_only: params.get("suggested_only").is_some_and(|value| value == "true"),
+            };
+            let allow_federation = services().globals.config.allow_federation;
+            let fetch = |server: String, path: String| async move {
+                if !allow_federation {
+                    return None;
+                }
+                services().sending.send_federation_request(&server, reqwest::Method::GET, &path, None).await
+                    .map_err(|e| debug!("❌ Could not get a space hierarchy from {}: {}", server, e))
+                    .ok()
+            };
+            let response = services().space_hierarchy.hierarchy(&services().timeline, &room_id, &user_id, &request, fetch).await?;
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// The local directory, or that of `server` when it is another server
+        async fn public_rooms(server: Option<&str>, limit: Option<usize>, since: Option<&str>, filter: Option<Value>) -> crate::Result<Value> {
+            let config = &services().globals.config;
+            match server.filter(|server| *server != config.server_name) {
+                Some(server) => {
+                    if !config.allow_federation {
+                        return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "Federation is disabled"));
+                    }
+                    if <&ruma::ServerName>::try_from(server).is_err() {
+                        return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "Invalid server name"));
+                    }
+                    crate::service::room_directory::remote_public_rooms(&services().sending, server, limit, since, filter).await
+                }
+                None => {
+                    let search_term = filter.as_ref().and_then(|filter| filter["generic_search_term"].as_str());
+                    services().room_directory.public_rooms(&services().timeline, limit, since, search_term)
+                }
+            }
+        }
+
+        /// GET /_matrix/client/v3/directory/list/room/{roomId} - Get whether a room is published
+        #[instrument(level = "debug")]
+        pub async fn get_room_visibility_route(Path(room_id): Path<String>) -> crate::Result<RumaResponse<Json<Value>>> {
+            if !services().timeline.room_exists(&room_id) {
+                return Err(crate::Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+            }
+            let visibility = if services().room_directory.is_published(&room_id) { "public" } else { "private" };
+            Ok(RumaResponse(Json(json!({ "visibility": visibility }))))
+        }
+
+        /// PUT /_matrix/client/v3/directory/list/room/{roomId} - Publish or unpublish a room
+        #[instrument(level = "debug", skip(headers, payload))]
+        pub async fn set_room_visibility_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let public = match payload["visibility"].as_str() {
+                Some("public") => true,
+                Some("private") => false,
+                _ => return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "Visibility must be public or private")),
+            };
+            services().room_directory.set_visibility(&services().timeline, &room_id, &user_id, public)?;
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// PUT /_matrix/client/v3/directory/room/{roomAlias} - Point an alias of this server at a room
+        #[instrument(level = "debug", skip(headers, payload))]
+        pub async fn create_alias_route(
+            Path(room_alias): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let room_id = payload["room_id"].as_str()
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "Missing room_id"))?;
+            services().room_aliases.create(
+                &services().timeline,
+                &services().globals.config.server_name,
+                &room_alias,
+                room_id,
+                &user_id,
+            )?;
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// DELETE /_matrix/client/v3/directory/room/{roomAlias} - Delete an alias of this server
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn delete_alias_route(
+            Path(room_alias): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            services().room_aliases.delete(&services().timeline, &room_alias, &user_id)?;
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// GET /_matrix/client/v3/directory/room/{roomAlias} - Resolve an alias of any server
+        #[instrument(level = "debug")]
+        pub async fn get_alias_route(Path(room_alias): Path<String>) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (room_id, servers) = services()
+                .room_aliases
+                .resolve(&services().timeline, &services().sending, &services().globals.config.server_name, &room_alias)
+                .await?;
+            Ok(RumaResponse(Json(json!({ "room_id": room_id, "servers": servers }))))
+        }
+
+        /// GET /_matrix/client/r0/profile/{userId} - Get user profile
+        #[instrument(level = "debug")]
+        pub async fn get_profile_route(Path(user_id): Path<String>) -> crate::Result<RumaResponse<Json<Value>>> {
+            info!("👤 Get profile endpoint called for user: {}", user_id);
+            let profile = services().profiles.get(&user_id).await?;
+            let mut response = json!({});
+            if let Some(displayname) = profile.displayname {
+                response["displayname"] = json!(displayname);
+            }
+            if let Some(avatar_url) = profile.avatar_url {
+                response["avatar_url"] = json!(avatar_url);
+            }
+            for (key, value) in services().profiles.fields(&user_id) {
+                response[key] = value;
+            }
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// Only the owner of a profile may change it, and not while suspended
+        async fn authorize_profile_change(headers: &HeaderMap, user_id: &str) -> crate::Result<()> {
+            let (sender, _) = authenticated_device(headers).await?;
+            if sender != user_id {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "You cannot change the profile of another user"));
+            }
+            services().accounts.ensure_not_suspended(&sender)
+        }
+
+        /// PUT /_matrix/client/r0/profile/{userId}/displayname - Set display name
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn set_displayname_route(
+            Path(user_id): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            info!("✏️ Set displayname endpoint called for user: {}", user_id);
+            authorize_profile_change(&headers, &user_id).await?;
+            let displayname = payload.get("displayname").and_then(Value::as_str).map(str::to_owned);
+            services().profiles.set_displayname(&user_id, displayname).await?;
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// GET /_matrix/client/r0/profile/{userId}/displayname - Get display name
+        #[instrument(level = "debug")]
+        pub async fn get_displayname_route(Path(user_id): Path<String>) -> crate::Result<RumaResponse<Json<Value>>> {
+            info!("📝 Get displayname endpoint called for user: {}", user_id);
+            let profile = services().profiles.get(&user_id).await?;
+            Ok(RumaResponse(Json(json!({ "displayname": profile.displayname }))))
+        }
+
+        /// PUT /_matrix/client/r0/profile/{userId}/avatar_url - Set avatar URL
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn set_avatar_url_route(
+            Path(user_id): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            info!("🖼️ Set avatar_url endpoint called for user: {}", user_id);
+            authorize_profile_change(&headers, &user_id).await?;
+            let avatar_url = payload.get("avatar_url").and_then(Value::as_str).map(str::to_owned);
+            services().profiles.set_avatar_url(&user_id, avatar_url).await?;
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// GET /_matrix/client/r0/profile/{userId}/avatar_url - Get avatar URL
+        #[instrument(level = "debug")]
+        pub async fn get_avatar_url_route(Path(user_id): Path<String>) -> crate::Result<RumaResponse<Json<Value>>> {
+            let profile = services().profiles.get(&user_id).await?;
+            Ok(RumaResponse(Json(json!({ "avatar_url": profile.avatar_url }))))
+        }
+
+        /// PUT /_matrix/client/v3/profile/{userId}/io.matrixon.nft_avatar - Use an NFT as avatar
+        ///
+        /// The body names the token and the wallet holding it, signed with
+        /// `personal_sign` over the message from
+        /// [`nft_avatar::ownership_message`](crate::service::nft_avatar::ownership_message).
+        /// The token image is copied to the media repository and becomes the
+        /// avatar, marked as verified in the profile.
+        #[instrument(level = "debug", skip(headers, payload))]
+        pub async fn set_nft_avatar_route(
+            Path(user_id): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            use crate::service::nft_avatar::{NftAvatarRequest, PROFILE_FIELD};
+
+            authorize_profile_change(&headers, &user_id).await?;
+            let config = services()
+                .globals
+                .config
+                .nft_avatar
+                .as_ref()
+                .ok_or(crate::Error::BadRequest(ErrorKind::Unrecognized, "NFT avatars are not enabled"))?;
+            let request: NftAvatarRequest = serde_json::from_value(payload)
+                .map_err(|_| crate::Error::BadRequest(ErrorKind::BadJson, "Invalid NFT avatar request"))?;
+            let nft = services()
+                .nft_avatar
+                .verify(config, &services().ipfs, services().globals.config.ipfs_gateway(), &user_id, &request)
+                .await?;
+
+            let media_id = services().media_store.create(crate::service::media_store::Media {
+                uploader: user_id.clone(),
+                content_type: Some(nft.content_type),
+                filename: nft.name,
+                data: nft.image,
+            });
+            let avatar_url = format!("mxc://{}/{}", services().globals.config.server_name, media_id);
+            services().profiles.set_avatar_url(&user_id, Some(avatar_url.clone())).await?;
+            let verified = json!({
+                "verified": true,
+                "chain_id": request.chain_id,
+                "contract": request.contract.to_ascii_lowercase(),
+                "token_id": request.token_id,
+                "standard": request.standard,
+                "owner": request.address.to_ascii_lowercase(),
+                "image": nft.image_uri,
+                "avatar_url": avatar_url,
+            });
+            services().profiles.set_field(&user_id, PROFILE_FIELD, Some(verified.clone()));
+            Ok(RumaResponse(Json(json!({ "avatar_url": avatar_url, PROFILE_FIELD: verified }))))
+        }
+
+        /// GET /_matrix/client/v3/profile/{userId}/io.matrixon.nft_avatar - Get the verified NFT avatar
+        #[instrument(level = "debug")]
+        pub async fn get_nft_avatar_route(Path(user_id): Path<String>) -> crate::Result<RumaResponse<Json<Value>>> {
+            use crate::service::nft_avatar::PROFILE_FIELD;
+
+            let verified = services()
+                .profiles
+                .fields(&user_id)
+                .remove(PROFILE_FIELD)
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "The avatar is not a verified NFT"))?;
+            Ok(RumaResponse(Json(json!({ PROFILE_FIELD: verified }))))
+        }
+
+        /// PUT /_matrix/client/v3/user/{userId}/account_data/{type} - Set global account data
+        #[instrument(level = "debug", skip(headers, content))]
+        pub async fn set_global_account_data_route(
+            headers: HeaderMap,
+            Path((user_id, event_type)): Path<(String, String)>,
+            Json(content): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (sender, _) = authenticated_device(&headers).await?;
+            if sender != user_id {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "You cannot set account data for other users"));
+            }
+            if !content.is_object() {
+                return Err(crate::Error::BadRequest(ErrorKind::BadJson, "Account data content must be an object"));
+            }
+            if event_type == "m.fully_read" || event_type == "m.push_rules" {
+                return Err(crate::Error::BadRequest(ErrorKind::BadJson, "This account data type is managed by the server"));
+            }
+            services().accounts.set_account_data(&user_id, &event_type, content);
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// GET /_matrix/client/v3/user/{userId}/account_data/{type} - Get global account data
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn get_global_account_data_route(
+            headers: HeaderMap,
+            Path((user_id, event_type)): Path<(String, String)>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (sender, _) = authenticated_device(&headers).await?;
+            if sender != user_id {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "You cannot get account data for other users"));
+            }
+            let content = services()
+                .accounts
+                .account_data(&user_id, &event_type)
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Account data not found"))?;
+            Ok(RumaResponse(Json(content)))
+        }
+
+        /// Placeholder macro for routes not yet implemented
+        macro_rules! placeholder_route {
+            ($name:ident) => {
+                #[instrument(level = "debug")]
+                pub async fn $name() -> impl IntoResponse {
+                    warn!("⚠️ Placeholder route called: {}", stringify!($name));
+                    RumaResponse(Json(json!({
+                        "status": "not_implemented",
+                        "route": stringify!($name),
+                        "message": "This endpoint is under development"
+                    })))
+                }
+            };
+        }
+
+        placeholder_route!(ping_appservice_route);
+        placeholder_route!(get_register_available_route);
+        placeholder_route!(change_password_route);
+        placeholder_route!(get_pushrules_all_route);
+        placeholder_route!(set_pushrule_route);
+        placeholder_route!(get_pushrule_route);
+        placeholder_route!(set_pushrule_enabled_route);
+        placeholder_route!(get_pushrule_enabled_route);
+        placeholder_route!(get_pushrule_actions_route);
+        placeholder_route!(set_pushrule_actions_route);
+        placeholder_route!(delete_pushrule_route);
+        placeholder_route!(get_room_event_route);
+        placeholder_route!(get_room_aliases_route);
+        placeholder_route!(get_filter_route);
+        placeholder_route!(create_filter_route);
+        placeholder_route!(create_openid_token_route);
+        placeholder_route!(set_room_account_data_route);
+        placeholder_route!(get_room_account_data_route);
+        placeholder_route!(set_presence_route);
+        placeholder_route!(get_presence_route);
+        placeholder_route!(set_read_marker_route);
+        placeholder_route!(redact_event_route);
+        placeholder_route!(join_room_by_id_route);
+        placeholder_route!(join_room_by_id_or_alias_route);
+        placeholder_route!(search_users_route);
+        placeholder_route!(get_protocols_route);
+        /// Target user and optional reason of a membership change request
+        fn membership_target(payload: &Value) -> crate::Result<(&str, Option<&str>)> {
+            let target = payload.get("user_id").and_then(Value::as_str)
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "Missing user_id"))?;
+            Ok((target, payload.get("reason").and_then(Value::as_str)))
+        }
+
+        /// PUT /_matrix/client/r0/rooms/{roomId}/typing/{userId} - Start or stop typing
+        ///
+        /// Other servers in the room are sent an `m.typing` EDU.
+        #[instrument(level = "debug", skip(headers, payload))]
+        pub async fn create_typing_event_route(
+            Path((room_id, typing_user)): Path<(String, String)>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            use crate::service::{inbound_federation::TYPING_TIMEOUT_MS, outbound_federation};
+
+            let (user_id, _) = authenticated_device(&headers).await?;
+            if user_id != typing_user {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "You cannot send typing notifications for other users"));
+            }
+            if crate::service::membership::membership(&room_id, &user_id).as_deref() != Some("join") {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "You are not joined to this room"));
+            }
+            let typing = payload["typing"].as_bool()
+                .ok_or(crate::Error::BadRequest(ErrorKind::BadJson, "Missing typing"))?;
+            let timeout = payload["timeout"].as_u64().unwrap_or(TYPING_TIMEOUT_MS).min(TYPING_TIMEOUT_MS);
+            let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+            services().inbound_federation.set_typing(&room_id, &user_id, typing, now_ms + timeout);
+            outbound_federation::send_room_edu(&room_id, outbound_federation::typing_edu(&room_id, &user_id, typing));
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// POST /_matrix/client/r0/rooms/{roomId}/receipt/{receiptType}/{eventId} - Send a receipt
+        ///
+        /// Public read receipts are sent to other servers in the room as an
+        /// `m.receipt` EDU; private ones stay on this server.
+        #[instrument(level = "debug", skip(headers, payload))]
+        pub async fn create_receipt_route(
+            Path((room_id, receipt_type, event_id)): Path<(String, String, String)>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            use crate::service::outbound_federation;
+
+            let (user_id, _) = authenticated_device(&headers).await?;
+            if !matches!(receipt_type.as_str(), "m.read" | "m.read.private") {
+                return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "Unsupported receipt type"));
+            }
+            if crate::service::membership::membership(&room_id, &user_id).as_deref() != Some("join") {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "You are not joined to this room"));
+            }
+            if services().timeline.get_event(&room_id, &event_id).is_none() {
+                return Err(crate::Error::BadRequest(ErrorKind::NotFound, "Event not found in this room"));
+            }
+            let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+            let mut receipt = json!({ "event_ids": [event_id], "data": { "ts": now_ms } });
+            if let Some(thread_id) = payload.get("thread_id").and_then(Value::as_str) {
+                receipt["data"]["thread_id"] = json!(thread_id);
+            }
+            services().inbound_federation.set_receipt(&room_id, &receipt_type, &user_id, receipt.clone());
+            if receipt_type == "m.read" {
+                outbound_federation::send_room_edu(&room_id, outbound_federation::receipt_edu(&room_id, &receipt_type, &user_id, &receipt));
+            }
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+                /// POST /_matrix/client/r0/rooms/{roomId}/invite - Invite a user to a room
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn invite_user_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (sender, _) = authenticated_device(&headers).await?;
+            services().accounts.ensure_not_suspended(&sender)?;
+            let (invitee, reason) = membership_target(&payload)?;
+            services().inbound_federation.partial_state().ensure_full_state(&room_id)?;
+            crate::service::membership::invite(&room_id, &sender, invitee, reason)?;
+            info!("📨 {} invited {} to {}", sender, invitee, room_id);
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// POST /_matrix/client/r0/rooms/{roomId}/leave - Leave a room or reject an invite
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn leave_room_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            // Suspended users may still leave rooms
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let reason = payload.get("reason").and_then(Value::as_str);
+            if services().membership.leave(&room_id, &user_id, reason)?.is_some() {
+                info!("👋 {} left {}", user_id, room_id);
+            }
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// POST /_matrix/client/r0/rooms/{roomId}/forget - Forget a room the user left
+        #[instrument(level = "debug")]
+        pub async fn forget_room_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            services().membership.forget(&room_id, &user_id)?;
+            info!("🗑️ {} forgot {}", user_id, room_id);
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// POST /_matrix/client/r0/rooms/{roomId}/kick - Kick a user from a room
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn kick_user_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (sender, _) = authenticated_device(&headers).await?;
+            services().accounts.ensure_not_suspended(&sender)?;
+            let (target, reason) = membership_target(&payload)?;
+            crate::service::membership::kick(&room_id, &sender, target, reason)?;
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// POST /_matrix/client/r0/rooms/{roomId}/ban - Ban a user from a room
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn ban_user_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (sender, _) = authenticated_device(&headers).await?;
+            services().accounts.ensure_not_suspended(&sender)?;
+            let (target, reason) = membership_target(&payload)?;
+            crate::service::membership::ban(&room_id, &sender, target, reason)?;
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// POST /_matrix/client/r0/rooms/{roomId}/unban - Lift a user's ban
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn unban_user_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (sender, _) = authenticated_device(&headers).await?;
+            services().accounts.ensure_not_suspended(&sender)?;
+            let (target, reason) = membership_target(&payload)?;
+            crate::service::membership::unban(&room_id, &sender, target, reason)?;
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// PUT /_synapse/admin/v1/suspend/{userId} - Suspend or unsuspend an account
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn suspend_user_route(
+            Path(user_id): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            let suspend = payload.get("suspend").and_then(Value::as_bool)
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "Missing suspend flag"))?;
+            info!("🛡️ {} setting suspension of {} to {}", admin, user_id, suspend);
+
+            if suspend {
+                let reason = payload.get("reason").and_then(Value::as_str).map(str::to_owned);
+                services().accounts.suspend(&user_id, reason);
+            } else {
+                services().accounts.unsuspend(&user_id);
+            }
+            Ok(RumaResponse(Json(json!({ format!("user_{}_suspended", user_id): suspend }))))
+        }
+
+        /// POST /_matrixon/admin/v1/users/{userId}/impersonate - Issue a
+        /// short-lived token acting as an existing local user
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn impersonate_user_route(
+            Path(user_id): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            let settings = services().globals.config.impersonation();
+            if !settings.enabled {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "Impersonation is disabled on this server"));
+            }
+            let reason = payload.get("reason").and_then(Value::as_str).filter(|reason| !reason.trim().is_empty())
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "A reason is required to impersonate a user"))?;
+            if user_id == admin {
+                return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "Cannot impersonate yourself"));
+            }
+            let local_suffix = format!(":{}", services().globals.config.server_name);
+            if !user_id.starts_with('@') || !user_id.ends_with(&local_suffix) {
+                return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "Only local users can be impersonated"));
+            }
+            let known = match &services().repositories {
+                Some(repositories) => repositories
+                    .users
+                    .get(&user_id)
+                    .await
+                    .map_err(|e| crate::Error::BadDatabase(e.to_string()))?
+                    .is_some(),
+                None => services().accounts.get(&user_id).password_hash.is_some(),
+            };
+            if !known {
+                return Err(crate::Error::BadRequest(ErrorKind::NotFound, "Unknown user"));
+            }
+            if services().accounts.is_deactivated(&user_id) {
+                return Err(crate::Error::BadRequest(ErrorKind::UserDeactivated, "This account has been deactivated"));
+            }
+
+            let lifetime_s = payload.get("valid_for_s").and_then(Value::as_u64)
+                .unwrap_or(settings.max_lifetime_s)
+                .min(settings.max_lifetime_s);
+            let (access_token, expires_at) = services().impersonation.issue(&admin, &user_id, reason, lifetime_s);
+            Ok(RumaResponse(Json(json!({
+                "user_id": user_id,
+                "access_token": access_token,
+                "expires_at": expires_at
+            }))))
+        }
+
+        /// DELETE /_matrixon/admin/v1/users/{userId}/impersonate - Revoke the
+        /// impersonation tokens acting as a user
+        #[instrument(level = "debug")]
+        pub async fn revoke_impersonation_route(
+            Path(user_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            let revoked = services().impersonation.revoke(&user_id);
+            info!("🛡️ {} revoked {} impersonation tokens of {}", admin, revoked, user_id);
+            Ok(RumaResponse(Json(json!({ "revoked": revoked }))))
+        }
+
+        /// GET /_matrixon/admin/v1/impersonation/audit - Impersonation audit trail
+        #[instrument(level = "debug")]
+        pub async fn impersonation_audit_route(
+            Query(params): Query<HashMap<String, String>>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            let entries = services().impersonation.audit_trail(params.get("user_id").map(String::as_str));
+            Ok(RumaResponse(Json(json!({ "entries": entries }))))
+        }
+
+        /// GET /_synapse/admin/v1/federation/destinations - Federation delivery state
+        #[instrument(level = "debug")]
+        pub async fn get_federation_destinations_route(headers: HeaderMap) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            let destinations = services().sending.destinations();
+            Ok(RumaResponse(Json(json!({ "total": destinations.len(), "destinations": destinations }))))
+        }
+
+        /// GET /_synapse/admin/v1/federation/destinations/{destination}
+        #[instrument(level = "debug")]
+        pub async fn get_federation_destination_route(
+            Path(destination): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            let status = services().sending.destinations().into_iter().find(|status| status.destination == destination)
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Unknown destination"))?;
+            Ok(RumaResponse(Json(json!(status))))
+        }
+
+        /// POST /_synapse/admin/v1/federation/destinations/{destination}/reset_connection
+        #[instrument(level = "debug")]
+        pub async fn reset_federation_destination_route(
+            Path(destination): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            info!("🛡️ {} reset the federation backoff of {}", admin, destination);
+            services().sending.reset_backoff(&destination);
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// GET /_matrixon/admin/v1/database/shards - Health of each database
+        /// shard holding room events and state
+        #[instrument(level = "debug")]
+        pub async fn database_shards_route(headers: HeaderMap) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            let repositories = services().repositories.as_ref()
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "No database is configured"))?;
+            let shards = repositories.shards().health_check(std::time::Duration::from_secs(5)).await;
+            let healthy = shards.iter().all(|shard| shard.healthy);
+            Ok(RumaResponse(Json(json!({ "healthy": healthy, "shards": shards }))))
+        }
+
+        /// POST /_matrixon/admin/v1/server_keys/rotate - Replace the server signing key
+        #[instrument(level = "debug")]
+        pub async fn rotate_server_key_route(headers: HeaderMap) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            let key_id = services().server_keys.rotate()?;
+            info!("🛡️ {} rotated the server signing key to {}", admin, key_id);
+            Ok(RumaResponse(Json(json!({ "key_id": key_id, "old_verify_keys": services().server_keys.old_keys() }))))
+        }
+
+        /// GET /_matrixon/admin/v1/auto_join_rooms - Rooms new users are joined to
+        #[instrument(level = "debug")]
+        pub async fn get_auto_join_rooms_route(headers: HeaderMap) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            Ok(RumaResponse(Json(json!({ "rooms": services().auto_join.rooms() }))))
+        }
+
+        /// PUT /_matrixon/admin/v1/auto_join_rooms - Replace the auto-join room list
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn set_auto_join_rooms_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            let rooms: Vec<String> = payload.get("rooms").cloned()
+                .and_then(|rooms| serde_json::from_value(rooms).ok())
+                .ok_or(crate::Error::BadRequest(ErrorKind::BadJson, "rooms must be a list of room ids"))?;
+            info!("🛡️ {} updating auto-join rooms", admin);
+            services().auto_join.set_rooms(rooms);
+            Ok(RumaResponse(Json(json!({ "rooms": services().auto_join.rooms() }))))
+        }
+
+        /// POST /_matrix/client/v3/rooms/{roomId}/report/{eventId} - Report an event to the moderators
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn report_event_route(
+            Path((room_id, event_id)): Path<(String, String)>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            // Events of rooms the reporter is not in are as unknown as missing ones
+            if crate::service::membership::membership(&room_id, &user_id).as_deref() != Some("join") {
+                return Err(crate::Error::BadRequest(ErrorKind::NotFound, "Event not found"));
+            }
+            let reason = payload.get("reason").and_then(Value::as_str).map(str::to_owned);
+            let score = payload.get("score").and_then(Value::as_i64);
+            info!("🚩 {} reported {} in {}", user_id, event_id, room_id);
+            services().event_reports.report(&services().timeline, &room_id, &event_id, &user_id, reason, score)?;
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// GET /_matrixon/admin/v1/event_reports - Reported events with their reports
+        #[instrument(level = "debug")]
+        pub async fn get_event_reports_route(headers: HeaderMap) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            let events = services().event_reports.reported_events();
+            Ok(RumaResponse(Json(json!({ "total": events.len(), "event_reports": events }))))
+        }
+
+        /// POST /_matrixon/admin/v1/event_reports/{eventId}/restore - Show an
+        /// event hidden pending review again
+        #[instrument(level = "debug")]
+        pub async fn restore_reported_event_route(
+            Path(event_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            services().event_reports.restore(&services().timeline, &event_id)?;
+            info!("🛡️ {} restored reported event {}", admin, event_id);
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// GET /_matrixon/admin/v1/legal_holds - Active legal holds, optionally of one `case_id`
+        #[instrument(level = "debug")]
+        pub async fn get_legal_holds_route(
+            Query(params): Query<HashMap<String, String>>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            let holds = services().legal_hold.holds(params.get("case_id").map(String::as_str));
+            Ok(RumaResponse(Json(json!({ "total": holds.len(), "holds": holds }))))
+        }
+
+        /// POST /_matrixon/admin/v1/legal_holds - Place a legal hold on a
+        /// `room_id` or a `user_id`
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn place_legal_hold_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            use crate::service::legal_hold::HoldTarget;
+
+            let admin = authenticated_admin(&headers).await?;
+            let field = |name: &str| payload.get(name).and_then(Value::as_str).filter(|value| !value.trim().is_empty());
+            let target = match (field("room_id"), field("user_id")) {
+                (Some(room_id), None) => HoldTarget::RoomId(room_id.to_owned()),
+                (None, Some(user_id)) => HoldTarget::UserId(user_id.to_owned()),
+                _ => return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "Exactly one of room_id and user_id is required")),
+            };
+            let case_id = field("case_id")
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "A case_id is required to place a legal hold"))?;
+            let expires_at = match payload.get("expires_at") {
+                None | Some(Value::Null) => None,
+                Some(expires_at) => Some(expires_at.as_u64()
+                    .ok_or(crate::Error::BadRequest(ErrorKind::InvalidParam, "expires_at must be a timestamp in milliseconds"))?),
+            };
+            let hold = services().legal_hold.place(&admin, target, case_id, field("reason").map(str::to_owned), expires_at);
+            Ok(RumaResponse(Json(json!(hold))))
+        }
+
+        /// DELETE /_matrixon/admin/v1/legal_holds/{holdId} - Release a legal hold
+        #[instrument(level = "debug")]
+        pub async fn release_legal_hold_route(
+            Path(hold_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            let hold = services().legal_hold.release(&hold_id)
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Unknown legal hold"))?;
+            info!("⚖️ {} released legal hold {} of case {}", admin, hold_id, hold.case_id);
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// `from` and `limit` of a paged admin listing
+        fn admin_page(params: &HashMap<String, String>) -> (usize, usize) {
+            let from = params.get("from").and_then(|from| from.parse().ok()).unwrap_or(0);
+            let limit = params.get("limit").and_then(|limit| limit.parse().ok()).unwrap_or(100).min(1000);
+            (from, limit)
+        }
+
+        /// GET /_synapse/admin/v2/users/{userId} - Account state of a user
+        #[instrument(level = "debug")]
+        pub async fn get_user_admin_route(
+            Path(user_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            let account = services().accounts.get(&user_id);
+            Ok(RumaResponse(Json(json!({
+                "name": user_id,
+                "deactivated": account.deactivated,
+                "erased": account.erased,
+                "suspended": account.suspended,
+                "joined_rooms": crate::service::membership::joined_rooms(&user_id)
+            }))))
+        }
+
+        /// POST /_synapse/admin/v1/deactivate/{userId} - Deactivate an account
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn deactivate_user_admin_route(
+            Path(user_id): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            let erase = payload.get("erase").and_then(Value::as_bool).unwrap_or(false);
+            info!("🛡️ {} deactivating {} (erase: {})", admin, user_id, erase);
+            deactivate_account(&user_id, erase).await?;
+            Ok(RumaResponse(Json(json!({ "id_server_unbind_result": "no-support" }))))
+        }
+
+        /// Name and joined member count of a room, as listed to admins
+        fn admin_room_details(room_id: &str) -> Value {
+            let timeline = &services().timeline;
+            let name = timeline.state_event(room_id, "m.room.name", "").and_then(|event| event["content"]["name"].as_str().map(str::to_owned));
+            json!({
+                "room_id": room_id,
+                "name": name,
+                "joined_members": admin_room_members(room_id).len()
+            })
+        }
+
+        fn admin_room_members(room_id: &str) -> Vec<String> {
+            services().timeline.current_state(room_id).into_iter()
+                .filter(|event| event["type"] == "m.room.member" && event["content"]["membership"] == "join")
+                .filter_map(|event| event["state_key"].as_str().map(str::to_owned))
+                .collect()
+        }
+
+        /// GET /_synapse/admin/v1/rooms - Rooms known to the server
+        #[instrument(level = "debug")]
+        pub async fn list_rooms_admin_route(
+            Query(params): Query<HashMap<String, String>>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            let (from, limit) = admin_page(&params);
+            let mut room_ids = services().timeline.room_ids();
+            room_ids.sort_unstable();
+            let rooms: Vec<Value> = room_ids.iter().skip(from).take(limit).map(|room_id| admin_room_details(room_id)).collect();
+            let mut response = json!({ "rooms": rooms, "offset": from, "total_rooms": room_ids.len() });
+            if from.saturating_add(limit) < room_ids.len() {
+                response["next_batch"] = json!(from + limit);
+            }
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// GET /_synapse/admin/v1/rooms/{roomId}
+        #[instrument(level = "debug")]
+        pub async fn get_room_admin_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            if !services().timeline.room_exists(&room_id) {
+                return Err(crate::Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+            }
+            Ok(RumaResponse(Json(admin_room_details(&room_id))))
+        }
+
+        /// GET /_synapse/admin/v1/rooms/{roomId}/members
+        #[instrument(level = "debug")]
+        pub async fn get_room_members_admin_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            if !services().timeline.room_exists(&room_id) {
+                return Err(crate::Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+            }
+            let members = admin_room_members(&room_id);
+            Ok(RumaResponse(Json(json!({ "total": members.len(), "members": members }))))
+        }
+
+        /// GET /_synapse/admin/v1/users/{userId}/media - Files uploaded by a
+        /// user, newest first
+        #[instrument(level = "debug")]
+        pub async fn get_user_media_admin_route(
+            Path(user_id): Path<String>,
+            Query(params): Query<HashMap<String, String>>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            let (from, limit) = admin_page(&params);
+            let files = services().media_store.uploaded_by(&user_id);
+            let media: Vec<Value> = files.iter().skip(from).take(limit).map(|(media_id, media, created_ts)| json!({
+                "media_id": media_id,
+                "media_type": media.content_type,
+                "media_length": media.data.len(),
+                "upload_name": media.filename,
+                "created_ts": created_ts
+            })).collect();
+            let mut response = json!({ "media": media, "total": files.len() });
+            if from.saturating_add(limit) < files.len() {
+                response["next_token"] = json!(from + limit);
+            }
+            Ok(RumaResponse(Json(response)))
+        }
+
+        fn ensure_local_media(server_name: &str) -> crate::Result<()> {
+            if server_name != services().globals.config.server_name {
+                return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "Only media of this server can be managed"));
+            }
+            Ok(())
+        }
+
+        /// DELETE /_synapse/admin/v1/media/{serverName}/{mediaId}
+        #[instrument(level = "debug")]
+        pub async fn delete_media_admin_route(
+            Path((server_name, media_id)): Path<(String, String)>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            ensure_local_media(&server_name)?;
+            let media = services().media_store.get(&media_id)
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Media not found"))?;
+            if services().legal_hold.is_media_held(&services().timeline, &server_name, &media_id, &media.uploader) {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "The media is under a legal hold"));
+            }
+            services().media_store.delete(&media_id);
+            info!("🛡️ {} deleted media {}", admin, media_id);
+            Ok(RumaResponse(Json(json!({ "deleted_media": [media_id], "total": 1 }))))
+        }
+
+        /// POST /_synapse/admin/v1/media/quarantine/{serverName}/{mediaId} -
+        /// Stop serving a file without deleting it
+        #[instrument(level = "debug")]
+        pub async fn quarantine_media_admin_route(
+            Path((server_name, media_id)): Path<(String, String)>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            ensure_local_media(&server_name)?;
+            if !services().media_store.quarantine(&media_id) {
+                return Err(crate::Error::BadRequest(ErrorKind::NotFound, "Media not found"));
+            }
+            info!("🛡️ {} quarantined media {}", admin, media_id);
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// DELETE /_matrixon/admin/v1/rooms/{roomId} - Shut down and purge a
+        /// room: local members leave it and its events, state and media are
+        /// deleted. `block=true` keeps it from being joined again.
+        #[instrument(level = "debug")]
+        pub async fn delete_room_route(
+            Path(room_id): Path<String>,
+            Query(params): Query<HashMap<String, String>>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            let block = params.get("block").is_some_and(|block| block == "true");
+            let reason = params.get("reason").map(String::as_str).filter(|reason| !reason.trim().is_empty());
+            let report = services().room_deletion.delete(&room_id, &admin, block, reason).await?;
+            Ok(RumaResponse(Json(json!(report))))
+        }
+
+        /// GET /_matrixon/admin/v1/blocked_rooms - Rooms blocked from being
+        /// joined
+        #[instrument(level = "debug")]
+        pub async fn get_blocked_rooms_route(headers: HeaderMap) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            Ok(RumaResponse(Json(json!({ "rooms": services().room_deletion.blocked() }))))
+        }
+
+        /// DELETE /_matrixon/admin/v1/blocked_rooms/{roomId} - Allow a
+        /// blocked room to be joined again
+        #[instrument(level = "debug")]
+        pub async fn unblock_room_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            if !services().room_deletion.unblock(&room_id) {
+                return Err(crate::Error::BadRequest(ErrorKind::NotFound, "The room is not blocked"));
+            }
+            info!("🔓 {} unblocked {}", admin, room_id);
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// The cache called `name`
+        fn managed_cache(name: &str) -> crate::Result<&'static dyn crate::service::cache::CacheStats> {
+            services().caches().into_iter().find(|cache| cache.name() == name)
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Unknown cache"))
+        }
+
+        /// GET /_matrixon/admin/v1/caches - Size, capacity and hit rate of
+        /// each cache
+        #[instrument(level = "debug")]
+        pub async fn get_caches_route(headers: HeaderMap) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            let caches: Vec<Value> = services().caches().into_iter().map(|cache| {
+                let (hits, misses) = cache.lookups();
+                json!({
+                    "name": cache.name(),
+                    "entries": cache.entries(),
+                    "capacity": cache.max_entries(),
+                    "hits": hits,
+                    "misses": misses,
+                    "hit_rate": cache.hit_rate(),
+                    "memory_bytes": cache.memory_bytes(),
+                })
+            }).collect();
+            Ok(RumaResponse(Json(json!({ "caches": caches }))))
+        }
+
+        /// GET /_matrixon/admin/v1/memory - Allocator statistics, the share
+        /// of `max_memory_usage_mb` in use and the estimated memory use of
+        /// every cache
+        #[instrument(level = "debug")]
+        pub async fn get_memory_route(headers: HeaderMap) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            let stats = crate::service::allocator::stats();
+            let budget_mb = services().globals.config.max_memory_usage_mb;
+            let caches: Vec<Value> = services().caches().into_iter().map(|cache| json!({
+                "name": cache.name(),
+                "entries": cache.entries(),
+                "memory_bytes": cache.memory_bytes(),
+            })).collect();
+            Ok(RumaResponse(Json(json!({
+                "allocator": if crate::service::allocator::JEMALLOC { "jemalloc" } else { "system" },
+                "stats": stats,
+                "max_memory_usage_mb": budget_mb,
+                "budget_used": stats.zip(budget_mb).and_then(|(stats, budget_mb)| stats.budget_used(budget_mb)),
+                "caches": caches,
+            }))))
+        }
+
+        /// POST /_matrixon/admin/v1/memory/heap_profile - Dump a heap
+        /// profile into `heap_profile_dir`, returning its path. Profiles
+        /// are only dumped when the directory is configured.
+        #[instrument(level = "debug")]
+        pub async fn dump_heap_profile_route(headers: HeaderMap) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            let dir = services().globals.config.heap_profile_dir.as_ref()
+                .map(std::path::PathBuf::from)
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Heap profiles are disabled"))?;
+            let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+            let path = dir.join(format!("matrixon-heap-{}.prof", now_ms));
+            crate::service::allocator::dump_heap_profile(&path)?;
+            info!("🧠 {} dumped a heap profile to {}", admin, path.display());
+            Ok(RumaResponse(Json(json!({ "path": path }))))
+        }
+
+        /// POST /_matrixon/admin/v1/caches/{name}/invalidate - Drop the
+        /// entries mentioning any of `keys`, such as a user or room id, or
+        /// every entry without `keys`
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn invalidate_cache_route(
+            Path(name): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            let cache = managed_cache(&name)?;
+            let invalidated = match payload.get("keys") {
+                None | Some(Value::Null) => {
+                    let entries = cache.entries();
+                    cache.clear_entries();
+                    entries
+                }
+                Some(Value::Array(keys)) => {
+                    let keys: Option<Vec<&str>> = keys.iter().map(Value::as_str).collect();
+                    let keys = keys.ok_or(crate::Error::BadRequest(ErrorKind::InvalidParam, "keys must be a list of strings"))?;
+                    keys.into_iter().map(|key| cache.invalidate_key(key)).sum()
+                }
+                Some(_) => return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "keys must be a list of strings")),
+            };
+            info!("🧹 {} invalidated {} entries of the {} cache", admin, invalidated, name);
+            Ok(RumaResponse(Json(json!({ "invalidated": invalidated }))))
+        }
+
+        /// PUT /_matrixon/admin/v1/caches/{name}/capacity - Change how many
+        /// entries a cache holds until the next restart
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn set_cache_capacity_route(
+            Path(name): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let admin = authenticated_admin(&headers).await?;
+            let cache = managed_cache(&name)?;
+            let capacity = payload.get("capacity").and_then(Value::as_u64).filter(|capacity| *capacity > 0)
+                .ok_or(crate::Error::BadRequest(ErrorKind::InvalidParam, "capacity must be a positive number of entries"))?;
+            cache.resize(capacity as usize);
+            info!("🧹 {} resized the {} cache to {} entries", admin, name, capacity);
+            Ok(RumaResponse(Json(json!({ "name": cache.name(), "capacity": cache.max_entries() }))))
+        }
+
+        /// GET /_matrixon/admin/v1/room_stats - Daily message, reaction and
+        /// active user counts, optionally of one `room_id` between the days
+        /// `from` and `to` (`YYYY-MM-DD`, inclusive)
+        #[instrument(level = "debug")]
+        pub async fn room_stats_route(
+            Query(params): Query<HashMap<String, String>>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_admin(&headers).await?;
+            let stats = services().room_stats.as_ref()
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Room statistics are disabled"))?;
+            let day = |name: &str| {
+                params.get(name).map(|day| day.parse::<chrono::NaiveDate>()).transpose()
+                    .map_err(|_| crate::Error::BadRequest(ErrorKind::InvalidParam, "from and to must be dates like 2024-12-11"))
+            };
+            let rooms = stats.query(params.get("room_id").map(String::as_str), day("from")?, day("to")?);
+            Ok(RumaResponse(Json(json!({ "total": rooms.len(), "rooms": rooms }))))
+        }
+
+        /// GET /_matrix/client/v3/account/3pid - Third-party identifiers of the account
+        #[instrument(level = "debug")]
+        pub async fn third_party_route(headers: HeaderMap) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            Ok(RumaResponse(Json(json!({ "threepids": services().threepids.list(&user_id) }))))
+        }
+
+        /// Start validating a 3PID, through the configured identity server
+        /// delegate or locally
+        async fn request_3pid_token(medium: &str, address: &str, payload: &Value, headers: &HeaderMap) -> crate::Result<Value> {
+            let client_secret = payload.get("client_secret").and_then(Value::as_str)
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "Missing client_secret"))?;
+            let send_attempt = payload.get("send_attempt").and_then(Value::as_u64).unwrap_or(0);
+            if services().threepids.find_user(medium, address).is_some() {
+                return Err(crate::Error::BadRequest(ErrorKind::ThreepidInUse, "Third-party identifier already in use"));
+            }
+
+            if let Some(delegate) = services().globals.config.threepid().delegate(medium) {
+                return services().threepids.request_token_via(delegate, medium, payload).await;
+            }
+            // Adding a 3PID is authenticated, registering with one is not
+            let user_id = authenticated_device(headers).await.ok().map(|(user_id, _)| user_id);
+            let locale = services().localization.locale(&services().accounts, user_id.as_deref(), headers);
+            let sid = services().threepids.request_token(medium, address, client_secret, send_attempt, Some(&locale))?;
+            Ok(json!({
+                "sid": sid,
+                "submit_url": format!(
+                    "https://{}/_matrix/client/unstable/add_threepid/{}/submit_token",
+                    services().globals.config.server_name, medium
+                )
+            }))
+        }
+
+        /// POST /_matrix/client/v3/account/3pid/email/requestToken - Validate an email address
+        #[instrument(level = "debug", skip(headers, payload))]
+        pub async fn request_3pid_management_token_via_email_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let email = payload.get("email").and_then(Value::as_str)
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "Missing email"))?;
+            Ok(RumaResponse(Json(request_3pid_token("email", email, &payload, &headers).await?)))
+        }
+
+        /// POST /_matrix/client/v3/account/3pid/msisdn/requestToken - Validate a phone number
+        #[instrument(level = "debug", skip(headers, payload))]
+        pub async fn request_3pid_management_token_via_msisdn_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let phone_number = payload.get("phone_number").and_then(Value::as_str)
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "Missing phone_number"))?;
+            let msisdn: String = phone_number.chars().filter(char::is_ascii_digit).collect();
+            Ok(RumaResponse(Json(request_3pid_token("msisdn", &msisdn, &payload, &headers).await?)))
+        }
+
+        /// POST /_matrix/client/unstable/add_threepid/{medium}/submit_token - Complete local 3PID validation
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn submit_3pid_token_route(
+            Path(medium): Path<String>,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let field = |name: &str| payload.get(name).and_then(Value::as_str).unwrap_or_default().to_owned();
+            services().threepids.submit_token(&field("sid"), &field("client_secret"), &field("token"))?;
+            info!("✅ Validated {} session {}", medium, field("sid"));
+            Ok(RumaResponse(Json(json!({ "success": true }))))
+        }
+
+        /// Fields of the email confirmation link, and of the form posted back
+        #[derive(Debug, serde::Deserialize)]
+        pub struct EmailConfirmation {
+            sid: String,
+            client_secret: String,
+            token: String,
+            #[serde(default)]
+            csrf_token: String,
+        }
+
+        /// GET /_matrix/client/unstable/add_threepid/email/confirm - Page linked from validation emails
+        pub async fn email_confirmation_page_route(
+            headers: HeaderMap,
+            Query(link): Query<EmailConfirmation>,
+        ) -> impl IntoResponse {
+            let pages = &services().pages;
+            let locale = services().localization.locale(&services().accounts, None, &headers);
+            let (nonce, csrf_token) = pages.csrf();
+            let vars = json!({
+                "server_name": services().globals.config.server_name,
+                "action": crate::service::threepids::CONFIRM_PATH,
+                "sid": link.sid,
+                "client_secret": link.client_secret,
+                "token": link.token,
+                "csrf_token": csrf_token,
+            });
+            pages.response(StatusCode::OK, Page::ThreepidConfirm, &locale, &vars, Some(&nonce))
+        }
+
+        /// POST /_matrix/client/unstable/add_threepid/email/confirm - Confirm the email address from the page
+        pub async fn email_confirmation_route(
+            headers: HeaderMap,
+            axum::Form(form): axum::Form<EmailConfirmation>,
+        ) -> impl IntoResponse {
+            let pages = &services().pages;
+            let locale = services().localization.locale(&services().accounts, None, &headers);
+            let result = pages
+                .verify_csrf(&headers, &form.csrf_token)
+                .and_then(|()| services().threepids.submit_token(&form.sid, &form.client_secret, &form.token));
+            match result {
+                Ok(()) => {
+                    info!("✅ Validated email session {} from the confirmation page", form.sid);
+                    pages.response(StatusCode::OK, Page::ThreepidConfirmed, &locale, &json!({}), None)
+                }
+                Err(e) => pages.error_response(&e, &locale),
+            }
+        }
+
+        /// Fields of a consent link, and of the form posted back
+        #[derive(Debug, serde::Deserialize)]
+        pub struct ConsentForm {
+            u: String,
+            h: String,
+            #[serde(default)]
+            v: String,
+            #[serde(default)]
+            csrf_token: String,
+        }
+
+        /// Variables of the consent pages for the user of a consent link
+        fn consent_vars(link: &ConsentForm) -> crate::Result<(&'static crate::config::ConsentConfig, Value)> {
+            let consent = services()
+                .globals
+                .config
+                .consent
+                .as_ref()
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "No policy is configured"))?;
+            crate::service::pages::Service::verify_consent_hash(&consent.form_secret, &link.u, &link.h)?;
+            let policy_html = std::fs::read_to_string(&consent.policy_file)
+                .map_err(|e| crate::Error::BadConfig(format!("{}: {}", consent.policy_file.display(), e)))?;
+            let vars = json!({
+                "action": "/_matrix/consent",
+                "user_id": link.u,
+                "user_hash": link.h,
+                "policy_name": consent.policy_name,
+                "policy_version": consent.version,
+                "policy_html": policy_html,
+            });
+            Ok((consent, vars))
+        }
+
+        /// GET /_matrix/consent - Page asking a user to accept the server's policy
+        pub async fn consent_page_route(headers: HeaderMap, Query(link): Query<ConsentForm>) -> impl IntoResponse {
+            let pages = &services().pages;
+            let locale = services().localization.locale(&services().accounts, Some(&link.u), &headers);
+            let (consent, mut vars) = match consent_vars(&link) {
+                Ok(found) => found,
+                Err(e) => return pages.error_response(&e, &locale),
+            };
+            if services().accounts.get(&link.u).consent_version.as_ref() == Some(&consent.version) {
+                return pages.response(StatusCode::OK, Page::ConsentGiven, &locale, &vars, None);
+            }
+            let (nonce, csrf_token) = pages.csrf();
+            vars["csrf_token"] = json!(csrf_token);
+            pages.response(StatusCode::OK, Page::Consent, &locale, &vars, Some(&nonce))
+        }
+
+        /// POST /_matrix/consent - Accept the server's policy from the page
+        pub async fn consent_route(headers: HeaderMap, axum::Form(form): axum::Form<ConsentForm>) -> impl IntoResponse {
+            let pages = &services().pages;
+            let locale = services().localization.locale(&services().accounts, Some(&form.u), &headers);
+            let result = pages.verify_csrf(&headers, &form.csrf_token).and_then(|()| consent_vars(&form));
+            let (consent, vars) = match result {
+                Ok(found) => found,
+                Err(e) => return pages.error_response(&e, &locale),
+            };
+            if form.v != consent.version {
+                let changed = crate::Error::BadRequest(ErrorKind::InvalidParam, "The policy has changed, please reload the page");
+                return pages.error_response(&changed, &locale);
+            }
+            services().accounts.set_consent_version(&form.u, &consent.version);
+            pages.response(StatusCode::OK, Page::ConsentGiven, &locale, &vars, None)
+        }
+
+        /// Check the `auth` of a request needing user-interactive
+        /// authentication, whose only flow is the user's password. Without
+        /// a valid one the flows to complete are returned, with a 401.
+        async fn require_password_auth(user_id: &str, payload: &Value) -> crate::Result<()> {
+            let auth = &payload["auth"];
+            let session = auth["session"]
+                .as_str()
+                .map_or_else(|| uuid::Uuid::new_v4().simple().to_string(), str::to_owned);
+            let mut challenge = json!({
+                "flows": [{ "stages": ["m.login.password"] }],
+                "params": {},
+                "session": session,
+            });
+            if auth["type"] != "m.login.password" {
+                return Err(crate::Error::Uiaa(challenge));
+            }
+            let claimed = auth["identifier"]["user"].as_str().or(auth["user"].as_str()).unwrap_or(user_id);
+            let claimed_user = match claimed.starts_with('@') {
+                true => claimed.to_owned(),
+                false => format!("@{}:{}", claimed.to_lowercase(), services().globals.config.server_name),
+            };
+            let password = auth["password"].as_str().unwrap_or_default();
+            let hash = match &services().repositories {
+                Some(repositories) => repositories
+                    .users
+                    .get(user_id)
+                    .await
+                    .map_err(|e| crate::Error::BadDatabase(e.to_string()))?
+                    .and_then(|user| user.password_hash),
+                None => services().accounts.password_hash(user_id),
+            };
+            let valid = claimed_user == user_id
+                && hash.is_some_and(|hash| crate::service::accounts::verify_password(&hash, password));
+            if !valid {
+                challenge["errcode"] = json!(ErrorKind::forbidden().errcode().to_string());
+                challenge["error"] = json!("Invalid password");
+                return Err(crate::Error::Uiaa(challenge));
+            }
+            Ok(())
+        }
+
+        /// POST /_matrix/client/v3/account/3pid/add - Attach a validated 3PID to the account
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn add_3pid_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            require_password_auth(&user_id, &payload).await?;
+            let field = |name: &str| payload.get(name).and_then(Value::as_str).unwrap_or_default().to_owned();
+            let threepid_config = services().globals.config.threepid();
+            // The session may have been run by either delegate
+            let delegate = threepid_config.email_delegate.as_deref().or(threepid_config.msisdn_delegate.as_deref());
+            services().threepids.add(&user_id, &field("sid"), &field("client_secret"), delegate).await?;
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// POST /_matrix/client/v3/account/3pid/bind - Bind a 3PID on an identity server
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn bind_3pid_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let field = |name: &str| payload.get(name).and_then(Value::as_str).unwrap_or_default().to_owned();
+            if field("id_server").is_empty() {
+                return Err(crate::Error::BadRequest(ErrorKind::MissingParam, "Missing id_server"));
+            }
+            services()
+                .threepids
+                .bind(&field("id_server"), &field("id_access_token"), &field("sid"), &field("client_secret"), &user_id)
+                .await?;
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// Unbind a 3PID at the identity server named in the request, if any
+        async fn unbind_3pid(user_id: &str, payload: &Value) -> crate::Result<&'static str> {
+            let field = |name: &str| payload.get(name).and_then(Value::as_str).unwrap_or_default();
+            if field("id_server").is_empty() {
+                return Ok("no-support");
+            }
+            let unbound = services().threepids.unbind(field("id_server"), user_id, field("medium"), field("address")).await?;
+            Ok(if unbound { "success" } else { "no-support" })
+        }
+
+        /// POST /_matrix/client/v3/account/3pid/unbind - Unbind a 3PID from an identity server
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn unbind_3pid_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let result = unbind_3pid(&user_id, &payload).await?;
+            Ok(RumaResponse(Json(json!({ "id_server_unbind_result": result }))))
+        }
+
+        /// POST /_matrix/client/v3/account/3pid/delete - Remove a 3PID from the account
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn delete_3pid_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let field = |name: &str| payload.get(name).and_then(Value::as_str).unwrap_or_default();
+            services().threepids.delete(&user_id, field("medium"), field("address"));
+            let result = unbind_3pid(&user_id, &payload).await?;
+            Ok(RumaResponse(Json(json!({ "id_server_unbind_result": result }))))
+        }
+
+        /// POST /_matrix/client/r0/account/deactivate - Deactivate the account, optionally erasing it
+        ///
+        /// Needs user-interactive authentication. The account's 3PIDs are
+        /// detached, and unbound from the identity server given as
+        /// `id_server`.
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn deactivate_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            require_password_auth(&user_id, &payload).await?;
+            let erase = payload.get("erase").and_then(Value::as_bool).unwrap_or(false);
+            info!("🚫 Deactivation requested by {} (erase: {})", user_id, erase);
+
+            let result = unbind_all_3pids(&user_id, &payload).await;
+            deactivate_account(&user_id, erase).await?;
+
+            Ok(RumaResponse(Json(json!({
+                "id_server_unbind_result": result
+            }))))
+        }
+
+        /// Detach every 3PID of an account being deactivated, unbinding
+        /// them from the identity server of `id_server` if one is given
+        async fn unbind_all_3pids(user_id: &str, payload: &Value) -> &'static str {
+            let id_server = payload.get("id_server").and_then(Value::as_str).filter(|id_server| !id_server.is_empty());
+            let mut unbound_all = id_server.is_some();
+            for threepid in services().threepids.list(user_id) {
+                if let Some(id_server) = id_server {
+                    match services().threepids.unbind(id_server, user_id, &threepid.medium, &threepid.address).await {
+                        Ok(unbound) => unbound_all &= unbound,
+                        Err(e) => {
+                            warn!("⚠️ Could not unbind a 3PID of {} at {}: {}", user_id, id_server, e);
+                            unbound_all = false;
+                        }
+                    }
+                }
+                services().threepids.delete(user_id, &threepid.medium, &threepid.address);
+            }
+            if unbound_all { "success" } else { "no-support" }
+        }
+
+        /// Deactivate an account, logging out and deleting all of its
+        /// devices, and erase the user's data if asked to
+        async fn deactivate_account(user_id: &str, erase: bool) -> crate::Result<()> {
+            services().accounts.deactivate(user_id);
+            let mut devices = services().sessions.devices(user_id);
+            if let Some(repositories) = &services().repositories {
+                repositories.users.deactivate(user_id).await.map_err(|e| crate::Error::BadDatabase(e.to_string()))?;
+                let stored = repositories.devices.list(user_id).await.map_err(|e| crate::Error::BadDatabase(e.to_string()))?;
+                for device in stored {
+                    repositories
+                        .devices
+                        .delete(user_id, &device.device_id)
+                        .await
+                        .map_err(|e| crate::Error::BadDatabase(e.to_string()))?;
+                    if !devices.contains(&device.device_id) {
+                        devices.push(device.device_id);
+                    }
+                }
+            }
+            for device_id in &devices {
+                services().sessions.logout(user_id, device_id);
+                services().keys.remove_device(user_id, device_id);
+            }
+            services().membership.clear_remote_invites(user_id);
+            if erase {
+                crate::service::erasure::erase_user(user_id);
+            }
+            Ok(())
+        }
+
+        /// POST /_matrix/media/v3/upload - Upload content to the media repository
+        #[instrument(level = "debug", skip(body))]
+        pub async fn create_content_route(
+            headers: HeaderMap,
+            Query(params): Query<HashMap<String, String>>,
+            body: axum::body::Bytes,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let content_type = headers
+                .get(axum::http::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned);
+
+            let media_id = services().media_store.create(crate::service::media_store::Media {
+                uploader: user_id.clone(),
+                content_type,
+                filename: params.get("filename").cloned(),
+                data: body.to_vec(),
+            });
+            info!("📤 {} uploaded media {} ({} bytes)", user_id, media_id, body.len());
+
+            Ok(RumaResponse(Json(json!({
+                "content_uri": format!("mxc://matrixon.local/{}", media_id)
+            }))))
+        }
+
+        /// GET /_matrix/media/v3/download/{serverName}/{mediaId} - Download content
+        ///
+        /// Media of other servers is fetched from the origin, or from IPFS
+        /// when the origin published a CID for it. Only authenticated users
+        /// make the server fetch media it does not have cached.
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn get_content_route(
+            Path((server_name, media_id)): Path<(String, String)>,
+            headers: HeaderMap,
+        ) -> crate::Result<axum::response::Response> {
+            let config = &services().globals.config;
+            let media = if server_name == "matrixon.local" || server_name == config.server_name {
+                services()
+                    .media_store
+                    .get(&media_id)
+                    .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Media not found"))?
+            } else if let Some(media) = services().remote_media.cached(&server_name, &media_id) {
+                media
+            } else {
+                authenticated_device(&headers).await?;
+                services()
+                    .remote_media
+                    .fetch(
+                        &services().sending,
+                        &services().timeline,
+                        &services().ipfs,
+                        config.ipfs_gateway(),
+                        &server_name,
+                        &media_id,
+                        config.max_file_size.unwrap_or(50 * 1024 * 1024),
+                    )
+                    .await?
+            };
+
+            let content_type = media
+                .content_type
+                .unwrap_or_else(|| "application/octet-stream".to_owned());
+            Ok(([(axum::http::header::CONTENT_TYPE, content_type)], media.data).into_response())
+        }
+
+        /// POST /_matrix/client/r0/keys/upload - Publish device, one-time and fallback keys
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn upload_keys_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, device_id) = authenticated_device(&headers).await?;
+            info!("🔑 Key upload from {} / {}", user_id, device_id);
+            let keys = &services().keys;
+
+            if let Some(device_keys) = payload.get("device_keys") {
+                keys.add_device_keys(&user_id, &device_id, device_keys)?;
+            }
+            if let Some(one_time_keys) = payload.get("one_time_keys").and_then(Value::as_object) {
+                keys.add_one_time_keys(&user_id, &device_id, one_time_keys)?;
+            }
+            for field in ["fallback_keys", "org.matrix.msc2732.fallback_keys"] {
+                if let Some(fallback_keys) = payload.get(field).and_then(Value::as_object) {
+                    keys.add_fallback_keys(&user_id, &device_id, fallback_keys)?;
+                }
+            }
+
+            Ok(RumaResponse(Json(json!({
+                "one_time_key_counts": keys.one_time_key_counts(&user_id, &device_id)
+            }))))
+        }
+
+        /// POST /_matrix/client/r0/keys/query - Download device keys of users
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn get_keys_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            info!("🔍 Key query from {}", user_id);
+
+            let requested = payload.get("device_keys").and_then(Value::as_object).cloned().unwrap_or_default();
+            let mut response = services().keys.query_keys(Some(&user_id), &requested);
+            response["failures"] = json!({});
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// POST /_matrix/client/r0/keys/claim - Claim one-time keys for establishing sessions
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn claim_keys_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            info!("🎟️ Key claim from {}", user_id);
+
+            let requested = payload.get("one_time_keys").and_then(Value::as_object).cloned().unwrap_or_default();
+            Ok(RumaResponse(Json(json!({
+                "one_time_keys": services().keys.claim_keys(&requested),
+                "failures": {}
+            }))))
+        }
+
+        /// POST /_matrix/client/v3/keys/device_signing/upload - Publish cross-signing keys
+        ///
+        /// Replacing an existing master key with a different one needs
+        /// user-interactive authentication.
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn upload_signing_keys_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            info!("🔐 Cross-signing key upload from {}", user_id);
+            if services().keys.replaces_master_key(&user_id, payload.get("master_key")) {
+                require_password_auth(&user_id, &payload).await?;
+            }
+
+            services().keys.add_cross_signing_keys(
+                &user_id,
+                payload.get("master_key"),
+                payload.get("self_signing_key"),
+                payload.get("user_signing_key"),
+            )?;
+
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// POST /_matrix/client/v3/keys/signatures/upload - Publish cross-signing signatures
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn upload_signatures_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            info!("✍️ Signature upload from {}", user_id);
+
+            let mut failures = serde_json::Map::new();
+            for (target_user, signed_keys) in payload.as_object().into_iter().flatten() {
+                for (key_id, signed_object) in signed_keys.as_object().into_iter().flatten() {
+                    if let Err(e) = services().keys.add_signature(&user_id, target_user, key_id, signed_object) {
+                        warn!("⚠️ Rejected signature on {} / {}: {}", target_user, key_id, e);
+                        let (errcode, error) = match e {
+                            crate::Error::BadRequest(kind, message) => (kind.errcode().to_string(), message.to_owned()),
+                            other => ("M_UNKNOWN".to_owned(), other.to_string()),
+                        };
+                        failures
+                            .entry(target_user.clone())
+                            .or_insert_with(|| json!({}))[key_id] = json!({ "errcode": errcode, "error": error });
+                    }
+                }
+            }
+
+            Ok(RumaResponse(Json(json!({ "failures": failures }))))
+        }
+
+        /// `version` query parameter of the room key backup endpoints
+        fn backup_version(params: &HashMap<String, String>) -> crate::Result<String> {
+            params
+                .get("version")
+                .cloned()
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "Missing backup version"))
+        }
+
+        fn parse_backup_sessions(sessions: &Value) -> crate::Result<std::collections::BTreeMap<String, Value>> {
+            serde_json::from_value(sessions.clone())
+                .map_err(|_| crate::Error::BadRequest(ErrorKind::BadJson, "Invalid backup sessions"))
+        }
+
+        /// POST /_matrix/client/v3/room_keys/version - Create a key backup version
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn create_backup_version_route(
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let version = services().room_key_backup.create_version(
+                &user_id,
+                payload["algorithm"].clone(),
+                payload["auth_data"].clone(),
+            )?;
+            Ok(RumaResponse(Json(json!({ "version": version }))))
+        }
+
+        /// PUT /_matrix/client/v3/room_keys/version/{version} - Update a key backup version
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn update_backup_version_route(
+            Path(version): Path<String>,
+            headers: HeaderMap,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            if payload.get("version").is_some_and(|v| v.as_str() != Some(version.as_str())) {
+                return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "Version in body does not match path"));
+            }
+            services().room_key_backup.update_version(
+                &user_id,
+                &version,
+                &payload["algorithm"],
+                payload["auth_data"].clone(),
+            )?;
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// DELETE /_matrix/client/v3/room_keys/version/{version} - Delete a key backup version
+        #[instrument(level = "debug")]
+        pub async fn delete_backup_version_route(
+            Path(version): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            services().room_key_backup.delete_version(&user_id, &version)?;
+            Ok(RumaResponse(Json(json!({}))))
+        }
+
+        /// GET /_matrix/client/v3/room_keys/version - Get the latest key backup version
+        #[instrument(level = "debug")]
+        pub async fn get_latest_backup_info_route(headers: HeaderMap) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            Ok(RumaResponse(Json(services().room_key_backup.get_version_info(&user_id, None)?)))
+        }
+
+        /// GET /_matrix/client/v3/room_keys/version/{version} - Get a key backup version
+        #[instrument(level = "debug")]
+        pub async fn get_backup_info_route(
+            Path(version): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            Ok(RumaResponse(Json(services().room_key_backup.get_version_info(&user_id, Some(&version))?)))
+        }
+
+        /// PUT /_matrix/client/v3/room_keys/keys - Store keys for several rooms
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn add_backup_keys_route(
+            headers: HeaderMap,
+            Query(params): Query<HashMap<String, String>>,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let mut keys = std::collections::BTreeMap::new();
+            for (room_id, room) in payload["rooms"].as_object().into_iter().flatten() {
+                keys.insert(room_id.clone(), parse_backup_sessions(&room["sessions"])?);
+            }
+            let (count, etag) = services().room_key_backup.add_keys(&user_id, &backup_version(&params)?, keys)?;
+            Ok(RumaResponse(Json(json!({ "count": count, "etag": etag }))))
+        }
+
+        /// PUT /_matrix/client/v3/room_keys/keys/{roomId} - Store keys for a room
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn add_backup_keys_for_room_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+            Query(params): Query<HashMap<String, String>>,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let keys = std::collections::BTreeMap::from([(room_id, parse_backup_sessions(&payload["sessions"])?)]);
+            let (count, etag) = services().room_key_backup.add_keys(&user_id, &backup_version(&params)?, keys)?;
+            Ok(RumaResponse(Json(json!({ "count": count, "etag": etag }))))
+        }
+
+        /// PUT /_matrix/client/v3/room_keys/keys/{roomId}/{sessionId} - Store a single session key
+        #[instrument(level = "debug", skip(payload))]
+        pub async fn add_backup_keys_for_session_route(
+            Path((room_id, session_id)): Path<(String, String)>,
+            headers: HeaderMap,
+            Query(params): Query<HashMap<String, String>>,
+            Json(payload): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let keys = std::collections::BTreeMap::from([(
+                room_id,
+                std::collections::BTreeMap::from([(session_id, payload)]),
+            )]);
+            let (count, etag) = services().room_key_backup.add_keys(&user_id, &backup_version(&params)?, keys)?;
+            Ok(RumaResponse(Json(json!({ "count": count, "etag": etag }))))
+        }
+
+        /// GET /_matrix/client/v3/room_keys/keys - Get all backed up keys
         #[instrument(level = "debug")]
-        pub async fn logout_route(headers: HeaderMap) -> impl IntoResponse {
-            info!("🔒 User logout endpoint called");
-            RumaResponse(Json(json!({})))
+        pub async fn get_backup_keys_route(
+            headers: HeaderMap,
+            Query(params): Query<HashMap<String, String>>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let keys = services().room_key_backup.get_keys(&user_id, &backup_version(&params)?, None, None)?;
+            let rooms: serde_json::Map<String, Value> = keys
+                .into_iter()
+                .map(|(room_id, sessions)| (room_id, json!({ "sessions": sessions })))
+                .collect();
+            Ok(RumaResponse(Json(json!({ "rooms": rooms }))))
         }
 
-        /// POST /_matrix/client/r0/logout/all - Logout all devices
+        /// GET /_matrix/client/v3/room_keys/keys/{roomId} - Get backed up keys of a room
         #[instrument(level = "debug")]
-        pub async fn logout_all_route(headers: HeaderMap) -> impl IntoResponse {
-            info!("🔒 User logout all devices endpoint called");
-            RumaResponse(Json(json!({})))
+        pub async fn get_backup_keys_for_room_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+            Query(params): Query<HashMap<String, String>>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let mut keys = services().room_key_backup.get_keys(&user_id, &backup_version(&params)?, Some(&room_id), None)?;
+            let sessions = keys.remove(&room_id).unwrap_or_default();
+            Ok(RumaResponse(Json(json!({ "sessions": sessions }))))
         }
 
-        /// POST /_matrix/client/r0/createRoom - Create a new room
+        /// GET /_matrix/client/v3/room_keys/keys/{roomId}/{sessionId} - Get a backed up session key
         #[instrument(level = "debug")]
-        pub async fn create_room_route(headers: HeaderMap, Json(payload): Json<Value>) -> impl IntoResponse {
-            info!("🏠 Room creation endpoint called with payload: {:?}", payload);
-            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-            let room_id = format!("!matrixon_room_{}:matrixon.local", timestamp);
-            
-            let room_alias = payload.get("room_alias_name")
-                .and_then(|a| a.as_str())
-                .map(|a| format!("#{}:matrixon.local", a))
-                .unwrap_or_else(|| format!("#room_{}:matrixon.local", timestamp));
-            
-            RumaResponse(Json(json!({
-                "room_id": room_id,
-                "room_alias": room_alias
-            })))
+        pub async fn get_backup_keys_for_session_route(
+            Path((room_id, session_id)): Path<(String, String)>,
+            headers: HeaderMap,
+            Query(params): Query<HashMap<String, String>>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let mut keys = services()
+                .room_key_backup
+                .get_keys(&user_id, &backup_version(&params)?, Some(&room_id), Some(&session_id))?;
+            let key_data = keys
+                .remove(&room_id)
+                .and_then(|mut sessions| sessions.remove(&session_id))
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Backup key not found"))?;
+            Ok(RumaResponse(Json(key_data)))
         }
 
-        /// GET /_matrix/client/r0/joined_rooms - Get joined rooms
+        /// DELETE /_matrix/client/v3/room_keys/keys - Delete all backed up keys
         #[instrument(level = "debug")]
-        pub async fn joined_rooms_route(headers: HeaderMap) -> impl IntoResponse {
-            info!("🏠 Joined rooms endpoint called");
-            RumaResponse(Json(json!({
-                "joined_rooms": [
-                    "!matrixon_general:matrixon.local",
-                    "!matrixon_test:matrixon.local",
-                    "!matrixon_development:matrixon.local"
-                ]
-            })))
+        pub async fn delete_backup_keys_route(
+            headers: HeaderMap,
+            Query(params): Query<HashMap<String, String>>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let (count, etag) = services().room_key_backup.delete_keys(&user_id, &backup_version(&params)?, None, None)?;
+            Ok(RumaResponse(Json(json!({ "count": count, "etag": etag }))))
         }
 
-        /// GET /_matrix/client/r0/sync - Sync events
+        /// DELETE /_matrix/client/v3/room_keys/keys/{roomId} - Delete backed up keys of a room
         #[instrument(level = "debug")]
-        pub async fn sync_events_route(Query(params): Query<HashMap<String, String>>) -> impl IntoResponse {
-            info!("🔄 Sync events endpoint called with params: {:?}", params);
-            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-            
-            RumaResponse(Json(json!({
-                "next_batch": format!("matrixon_sync_batch_{}", timestamp),
-                "rooms": {
-                    "join": {},
-                    "invite": {},
-                    "leave": {},
-                    "knock": {}
-                },
-                "presence": {
-                    "events": []
-                },
-                "account_data": {
-                    "events": []
-                },
-                "to_device": {
-                    "events": []
-                },
-                "device_lists": {
-                    "changed": [],
-                    "left": []
-                },
-                "device_one_time_keys_count": {},
-                "device_unused_fallback_key_types": [],
-                "org.matrix.msc2732.device_unused_fallback_key_types": []
-            })))
+        pub async fn delete_backup_keys_for_room_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+            Query(params): Query<HashMap<String, String>>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let (count, etag) = services()
+                .room_key_backup
+                .delete_keys(&user_id, &backup_version(&params)?, Some(&room_id), None)?;
+            Ok(RumaResponse(Json(json!({ "count": count, "etag": etag }))))
         }
 
-        /// PUT /_matrix/client/r0/rooms/{roomId}/send/{eventType}/{txnId} - Send message
+        /// DELETE /_matrix/client/v3/room_keys/keys/{roomId}/{sessionId} - Delete a backed up session key
         #[instrument(level = "debug")]
-        pub async fn send_message_event_route(
-            Path((room_id, event_type, txn_id)): Path<(String, String, String)>,
+        pub async fn delete_backup_keys_for_session_route(
+            Path((room_id, session_id)): Path<(String, String)>,
             headers: HeaderMap,
-            Json(payload): Json<Value>
-        ) -> impl IntoResponse {
-            info!("💬 Message send endpoint called - Room: {}, Type: {}, TxnId: {}", room_id, event_type, txn_id);
-            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-            let event_id = format!("$matrixon_event_{}:matrixon.local", timestamp);
-            
-            RumaResponse(Json(json!({
-                "event_id": event_id
-            })))
+            Query(params): Query<HashMap<String, String>>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            let (count, etag) = services()
+                .room_key_backup
+                .delete_keys(&user_id, &backup_version(&params)?, Some(&room_id), Some(&session_id))?;
+            Ok(RumaResponse(Json(json!({ "count": count, "etag": etag }))))
         }
 
-        /// GET /_matrix/client/r0/publicRooms - Get public rooms
+        /// GET /_matrix/client/r0/rooms/{roomId}/state - Get all state events for room
         #[instrument(level = "debug")]
-        pub async fn get_public_rooms_route() -> impl IntoResponse {
-            info!("🌐 Public rooms endpoint called");
-            RumaResponse(Json(json!({
-                "chunk": [
-                    {
-                        "room_id": "!matrixon_general:matrixon.local",
-                        "name": "Matrixon General",
-                        "topic": "Welcome to Matrixon Matrix Server",
-                        "canonical_alias": "#general:matrixon.local",
-                        "num_joined_members": 1,
-                        "world_readable": true,
-                        "guest_can_join": true,
-                        "join_rule": "public",
-                        "room_type": null,
-                        "avatar_url": null
-                    },
-                    {
-                        "room_id": "!matrixon_development:matrixon.local",
-                        "name": "Matrixon Development",
-                        "topic": "Development discussion for Matrixon",
-                        "canonical_alias": "#dev:matrixon.local",
-                        "num_joined_members": 1,
-                        "world_readable": false,
-                        "guest_can_join": false,
-                        "join_rule": "invite",
-                        "room_type": null,
-                        "avatar_url": null
-                    }
-                ],
-                "next_batch": "matrixon_public_rooms_next_123",
-                "prev_batch": "matrixon_public_rooms_prev_123",
-                "total_room_count_estimate": 2
-            })))
+        pub async fn get_state_events_route(
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_device(&headers).await?;
+            info!("🎯 Get state events endpoint called for room: {}", room_id);
+            if !services().timeline.room_exists(&room_id) {
+                return Err(crate::Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+            }
+            Ok(RumaResponse(Json(json!(services().timeline.current_state(&room_id)))))
         }
 
-        /// GET /_matrix/client/r0/profile/{userId} - Get user profile
+        /// GET /_matrix/client/r0/rooms/{roomId}/state/{eventType}/{stateKey} - Get a state event's content
         #[instrument(level = "debug")]
-        pub async fn get_profile_route(Path(user_id): Path<String>) -> impl IntoResponse {
-            info!("👤 Get profile endpoint called for user: {}", user_id);
-            RumaResponse(Json(json!({
-                "displayname": format!("Matrixon User {}", user_id),
-                "avatar_url": format!("mxc://matrixon.local/avatar_{}", user_id.replace("@", "").replace(":", "_"))
-            })))
+        pub async fn get_state_events_for_key_route(
+            Path((room_id, event_type, state_key)): Path<(String, String, String)>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticated_device(&headers).await?;
+            let event = services()
+                .timeline
+                .state_event(&room_id, &event_type, &state_key)
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "State event not found"))?;
+            Ok(RumaResponse(Json(event["content"].clone())))
         }
 
-        /// PUT /_matrix/client/r0/profile/{userId}/displayname - Set display name
-        #[instrument(level = "debug")]
-        pub async fn set_displayname_route(
-            Path(user_id): Path<String>,
+        /// GET /_matrix/client/r0/rooms/{roomId}/state/{eventType} - Get a state event with an empty state key
+        pub async fn get_state_events_for_empty_key_route(
+            Path((room_id, event_type)): Path<(String, String)>,
             headers: HeaderMap,
-            Json(payload): Json<Value>
-        ) -> impl IntoResponse {
-            info!("✏️ Set displayname endpoint called for user: {}", user_id);
-            RumaResponse(Json(json!({})))
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            get_state_events_for_key_route(Path((room_id, event_type, String::new())), headers).await
         }
 
-        /// GET /_matrix/client/r0/profile/{userId}/displayname - Get display name
-        #[instrument(level = "debug")]
-        pub async fn get_displayname_route(Path(user_id): Path<String>) -> impl IntoResponse {
-            info!("📝 Get displayname endpoint called for user: {}", user_id);
-            RumaResponse(Json(json!({
-                "displayname": format!("Matrixon User {}", user_id)
-            })))
-        }
+        /// PUT /_matrix/client/r0/rooms/{roomId}/state/{eventType}/{stateKey} - Send a state event
+        #[instrument(level = "debug", skip(content))]
+        pub async fn send_state_event_for_key_route(
+            Path((room_id, event_type, state_key)): Path<(String, String, String)>,
+            headers: HeaderMap,
+            Json(content): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let (user_id, _) = authenticated_device(&headers).await?;
+            services().accounts.ensure_not_suspended(&user_id)?;
+            info!("🎯 {} sending state {} / {:?} in {}", user_id, event_type, state_key, room_id);
 
-        /// Placeholder macro for routes not yet implemented
-        macro_rules! placeholder_route {
-            ($name:ident) => {
-                #[instrument(level = "debug")]
-                pub async fn $name() -> impl IntoResponse {
-                    warn!("⚠️ Placeholder route called: {}", stringify!($name));
-                    RumaResponse(Json(json!({
-                        "status": "not_implemented",
-                        "route": stringify!($name),
-                        "message": "This endpoint is under development"
-                    })))
-                }
-            };
+            let room_creator = services()
+                .timeline
+                .state_event(&room_id, "m.room.create", "")
+                .and_then(|create| create["sender"].as_str().map(str::to_owned))
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Unknown room"))?;
+            services()
+                .globals
+                .config
+                .encryption_policy()
+                .check_state_event(&room_creator, &event_type, &content)?;
+            crate::service::membership::check_state_event(
+                &services().timeline,
+                &room_id,
+                &user_id,
+                &event_type,
+                &state_key,
+                &content,
+            )?;
+
+            let event_id = services()
+                .timeline
+                .append_event(&room_id, &user_id, &event_type, Some(&state_key), content);
+            Ok(RumaResponse(Json(json!({ "event_id": event_id }))))
         }
 
-        placeholder_route!(ping_appservice_route);
-        placeholder_route!(get_register_available_route);
-        placeholder_route!(change_password_route);
-        placeholder_route!(deactivate_route);
-        placeholder_route!(third_party_route);
-        placeholder_route!(request_3pid_management_token_via_email_route);
-        placeholder_route!(request_3pid_management_token_via_msisdn_route);
-        placeholder_route!(get_pushrules_all_route);
-        placeholder_route!(set_pushrule_route);
-        placeholder_route!(get_pushrule_route);
-        placeholder_route!(set_pushrule_enabled_route);
-        placeholder_route!(get_pushrule_enabled_route);
-        placeholder_route!(get_pushrule_actions_route);
-        placeholder_route!(set_pushrule_actions_route);
-        placeholder_route!(delete_pushrule_route);
-        placeholder_route!(get_room_event_route);
-        placeholder_route!(get_room_aliases_route);
-        placeholder_route!(get_filter_route);
-        placeholder_route!(create_filter_route);
-        placeholder_route!(create_openid_token_route);
-        placeholder_route!(set_global_account_data_route);
-        placeholder_route!(set_room_account_data_route);
-        placeholder_route!(get_global_account_data_route);
-        placeholder_route!(get_room_account_data_route);
-        placeholder_route!(set_avatar_url_route);
-        placeholder_route!(get_avatar_url_route);
-        placeholder_route!(set_presence_route);
-        placeholder_route!(get_presence_route);
-        placeholder_route!(upload_keys_route);
-        placeholder_route!(get_keys_route);
-        placeholder_route!(claim_keys_route);
-        placeholder_route!(create_backup_version_route);
-        placeholder_route!(update_backup_version_route);
-        placeholder_route!(delete_backup_version_route);
-        placeholder_route!(get_latest_backup_info_route);
-        placeholder_route!(get_backup_info_route);
-        placeholder_route!(add_backup_keys_route);
-        placeholder_route!(add_backup_keys_for_room_route);
-        placeholder_route!(add_backup_keys_for_session_route);
-        placeholder_route!(delete_backup_keys_for_room_route);
-        placeholder_route!(delete_backup_keys_for_session_route);
-        placeholder_route!(delete_backup_keys_route);
-        placeholder_route!(get_backup_keys_for_room_route);
-        placeholder_route!(get_backup_keys_for_session_route);
-        placeholder_route!(get_backup_keys_route);
-        placeholder_route!(set_read_marker_route);
-        placeholder_route!(create_receipt_route);
-        placeholder_route!(create_typing_event_route);
-        placeholder_route!(redact_event_route);
-        placeholder_route!(report_event_route);
-        placeholder_route!(create_alias_route);
-        placeholder_route!(delete_alias_route);
-        placeholder_route!(get_alias_route);
-        placeholder_route!(join_room_by_id_route);
-        placeholder_route!(join_room_by_id_or_alias_route);
-        placeholder_route!(knock_room_route);
-        placeholder_route!(joined_members_route);
-        placeholder_route!(leave_room_route);
-        placeholder_route!(forget_room_route);
-        placeholder_route!(kick_user_route);
-        placeholder_route!(ban_user_route);
-        placeholder_route!(unban_user_route);
-        placeholder_route!(invite_user_route);
-        placeholder_route!(set_room_visibility_route);
-        placeholder_route!(get_room_visibility_route);
-        placeholder_route!(get_public_rooms_filtered_route);
-        placeholder_route!(search_users_route);
-        placeholder_route!(get_member_events_route);
-        placeholder_route!(get_protocols_route);
-        /// GET /_matrix/client/r0/rooms/{roomId}/state - Get all state events for room
-        #[instrument(level = "debug")]
-        pub async fn get_state_events_route(Path(room_id): Path<String>) -> impl IntoResponse {
-            info!("🎯 Get state events endpoint called for room: {}", room_id);
-            RumaResponse(Json(json!([
-                {
-                    "type": "m.room.create",
-                    "state_key": "",
-                    "content": {
-                        "creator": "@admin:matrixon.local",
-                        "room_version": "9"
-                    },
-                    "sender": "@admin:matrixon.local",
-                    "origin_server_ts": 1640995200000i64,
-                    "event_id": "$create_event:matrixon.local"
-                }
-            ])))
+        /// PUT /_matrix/client/r0/rooms/{roomId}/state/{eventType} - Send a state event with an empty state key
+        pub async fn send_state_event_for_empty_key_route(
+            Path((room_id, event_type)): Path<(String, String)>,
+            headers: HeaderMap,
+            body: Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            send_state_event_for_key_route(Path((room_id, event_type, String::new())), headers, body).await
         }
 
         /// Health check endpoint for monitoring
@@ -793,20 +4762,13 @@ pub mod api {
             })))
         }
 
-        placeholder_route!(send_state_event_for_key_route);
-        placeholder_route!(get_state_events_for_key_route);
-        placeholder_route!(get_state_events_for_empty_key_route);
-        placeholder_route!(send_state_event_for_empty_key_route);
         placeholder_route!(sync_events_v5_route);
         placeholder_route!(get_context_route);
         placeholder_route!(get_message_events_route);
         placeholder_route!(search_events_route);
         placeholder_route!(turn_server_route);
-        placeholder_route!(send_event_to_device_route);
         placeholder_route!(get_media_config_route);
         placeholder_route!(get_media_config_auth_route);
-        placeholder_route!(create_content_route);
-        placeholder_route!(get_content_route);
         placeholder_route!(get_content_auth_route);
         placeholder_route!(get_content_as_filename_route);
         placeholder_route!(get_content_as_filename_auth_route);
@@ -820,8 +4782,6 @@ pub mod api {
         placeholder_route!(get_tags_route);
         placeholder_route!(update_tag_route);
         placeholder_route!(delete_tag_route);
-        placeholder_route!(upload_signing_keys_route);
-        placeholder_route!(upload_signatures_route);
         placeholder_route!(get_key_changes_route);
         placeholder_route!(get_pushers_route);
         placeholder_route!(set_pushers_route);
@@ -830,14 +4790,73 @@ pub mod api {
         placeholder_route!(get_relating_events_with_rel_type_and_event_type_route);
         placeholder_route!(get_relating_events_with_rel_type_route);
         placeholder_route!(get_relating_events_route);
-        placeholder_route!(get_hierarchy_route);
-        placeholder_route!(well_known_client);
-        placeholder_route!(get_metrics);
+
+        /// GET /_matrix/metrics - Prometheus metrics, served on listeners
+        /// with the `metrics` resource
+        pub async fn get_metrics() -> impl IntoResponse {
+            let mut metrics = services().inbound_federation.metrics().render();
+            metrics.push_str(&crate::service::cache::render_metrics(&services().caches()));
+            metrics.push_str(&services().join_coordinator.render());
+            metrics.push_str(&services().inbound_federation.partial_state().render());
+            metrics.push_str(&services().sending.dns().render());
+            metrics.push_str(&crate::service::allocator::render(
+                crate::service::allocator::stats(),
+                services().globals.config.max_memory_usage_mb,
+            ));
+            if let Some(retention) = &services().retention {
+                metrics.push_str(&retention.render());
+            }
+            if let Some(maintenance) = &services().maintenance {
+                metrics.push_str(&maintenance.render());
+            }
+            if let Some(room_webhooks) = &services().room_webhooks {
+                metrics.push_str(&room_webhooks.render());
+            }
+            if let Some(persistence) = &services().event_persistence {
+                metrics.push_str(&persistence.render());
+            }
+            if services().repositories.is_some() {
+                metrics.push_str(&matrixon_db::query_metrics::render());
+            }
+            ([(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")], metrics)
+        }
+
+        /// GET the configured `ready_path` - Health of every registered
+        /// subsystem. 503 while a critical subsystem has failed; degraded
+        /// subsystems still report ready. Reports are reused for a few
+        /// seconds so the probes cannot be hammered, and only admins see
+        /// the messages of the probes.
+        pub async fn get_readiness(headers: HeaderMap) -> impl IntoResponse {
+            const MAX_REPORT_AGE: std::time::Duration = std::time::Duration::from_secs(5);
+            let report = services().health.check_cached(MAX_REPORT_AGE).await;
+            let status = if report.is_ready() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+            let report = if authenticated_admin(&headers).await.is_ok() { report } else { report.redacted() };
+            (status, Json(report))
+        }
     }
 
     pub mod server_server {
         use crate::RumaResponse;
-        use axum::response::IntoResponse;
+        use axum::{
+            extract::{OriginalUri, Path},
+            http::{HeaderMap, Method, Uri},
+            response::IntoResponse,
+            Json,
+        };
+        use ruma::api::client::error::ErrorKind;
+        use serde_json::Value;
+        use std::{
+            collections::{BTreeMap, BTreeSet},
+            time::{Instant, SystemTime, UNIX_EPOCH},
+        };
+        use tracing::{instrument, Instrument};
+        use crate::services;
+        use crate::service::{
+            erasure, federation_history, federation_membership,
+            federation_metrics::Stage,
+            inbound_federation::{XMatrix, MAX_PDUS},
+            key_fetcher::required_signing_keys,
+        };
 
         // Placeholder for federation routes
         macro_rules! placeholder_route {
@@ -849,34 +4868,680 @@ pub mod api {
         }
 
         placeholder_route!(get_server_version_route);
-        placeholder_route!(get_server_keys_route);
-        placeholder_route!(get_server_keys_deprecated_route);
-        placeholder_route!(get_public_rooms_route);
-        placeholder_route!(get_public_rooms_filtered_route);
-        placeholder_route!(send_transaction_message_route);
+        /// # `GET /_matrix/key/v2/server`
+        ///
+        /// Publish this server's verify keys, signed with the current key.
+        pub async fn get_server_keys_route() -> crate::Result<RumaResponse<Json<Value>>> {
+            Ok(RumaResponse(Json(services().server_keys.server_keys_response()?)))
+        }
+
+        /// # `GET /_matrix/key/v2/server/{keyId}`
+        ///
+        /// Deprecated form with a key id, which is ignored.
+        pub async fn get_server_keys_deprecated_route() -> crate::Result<RumaResponse<Json<Value>>> {
+            get_server_keys_route().await
+        }
+
+        /// # `GET /_matrix/federation/v1/query/directory`
+        ///
+        /// The room an alias of this server points to and the servers in it.
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn get_room_alias_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticate(&method, &uri, &headers, None).await?;
+            let params: BTreeMap<String, String> =
+                url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()).into_owned().collect();
+            let room_alias = params.get("room_alias")
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "Missing room_alias"))?;
+            let (room_id, servers) =
+                services().room_aliases.query(&services().timeline, &services().globals.config.server_name, room_alias)?;
+            Ok(RumaResponse(Json(serde_json::json!({ "room_id": room_id, "servers": servers }))))
+        }
+
+        /// # `GET /_matrix/federation/v1/publicRooms`
+        ///
+        /// This server's public room directory.
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn get_public_rooms_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticate(&method, &uri, &headers, None).await?;
+            let params: BTreeMap<String, String> =
+                url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()).into_owned().collect();
+            let limit = params.get("limit").and_then(|limit| limit.parse::<usize>().ok());
+            let response = services().room_directory.public_rooms(
+                &services().timeline,
+                limit,
+                params.get("since").map(String::as_str),
+                None,
+            )?;
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// # `GET /_matrix/federation/v1/hierarchy/{roomId}`
+        ///
+        /// Summary of a local space and its direct children.
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn get_hierarchy_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let origin = authenticate(&method, &uri, &headers, None).await?;
+            let params: BTreeMap<String, String> =
+                url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()).into_owned().collect();
+            let suggested_only = params.get("suggested_only").is_some_and(|value| value == "true");
+            let response = crate::service::space_hierarchy::federation_hierarchy(&services().timeline, &room_id, &origin, suggested_only)?;
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// # `POST /_matrix/federation/v1/publicRooms`
+        ///
+        /// This server's public room directory, filtered by a search term.
+        #[instrument(level = "debug", skip(headers, body))]
+        pub async fn get_public_rooms_filtered_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            headers: HeaderMap,
+            Json(body): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticate(&method, &uri, &headers, Some(&body)).await?;
+            let response = services().room_directory.public_rooms(
+                &services().timeline,
+                body["limit"].as_u64().map(|limit| limit as usize),
+                body["since"].as_str(),
+                body["filter"]["generic_search_term"].as_str(),
+            )?;
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// # `PUT /_matrix/federation/v1/send/{txnId}`
+        ///
+        /// Receive a transaction of PDUs and EDUs pushed by another server.
+        #[instrument(level = "debug", skip(headers, body))]
+        pub async fn send_transaction_message_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path(txn_id): Path<String>,
+            headers: HeaderMap,
+            Json(mut body): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let start = Instant::now();
+            let origin = authenticate(&method, &uri, &headers, Some(&body)).await?;
+            if body["origin"].as_str().is_some_and(|claimed| claimed != origin) {
+                return Err(crate::Error::BadRequest(
+                    ErrorKind::forbidden(),
+                    "Transaction origin does not match the request signature",
+                ));
+            }
+            // Signatures cover the redacted form, so they survive this
+            erasure::enforce_erasure_in(&mut body, &["pdus"]);
+
+            let mut required: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+            for pdu in body["pdus"].as_array().into_iter().flatten().take(MAX_PDUS) {
+                for (server, key_ids) in required_signing_keys(pdu) {
+                    required.entry(server).or_default().extend(key_ids);
+                }
+            }
+            let key_fetch = Instant::now();
+            add_remote_keys(&required)
+                .instrument(tracing::debug_span!("federation_stage", stage = Stage::KeyFetch.as_str()))
+                .await;
+            let metrics = services().inbound_federation.metrics();
+            metrics.observe_stage(Stage::KeyFetch, key_fetch.elapsed());
+
+            let now_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+            let response = services().inbound_federation.handle_transaction(
+                &origin,
+                &txn_id,
+                &body,
+                &services().timeline,
+                &services().keys,
+                now_ms,
+            );
+            metrics.observe_transaction(&origin, start.elapsed());
+            Ok(RumaResponse(Json(response?)))
+        }
+
+        /// Check the X-Matrix signature of a request, fetching the origin's
+        /// key if needed, and return the origin server
+        async fn authenticate(method: &Method, uri: &Uri, headers: &HeaderMap, content: Option<&Value>) -> crate::Result<String> {
+            let uri = uri.path_and_query().map_or_else(|| uri.path(), |path| path.as_str());
+            let authorization = headers.get("authorization").and_then(|v| v.to_str().ok());
+            // The request is not authenticated yet: only fetch keys when the
+            // one it names is unknown or expired, and the key fetcher limits
+            // how often that happens
+            if let Some(x_matrix) = authorization.and_then(XMatrix::parse) {
+                if !services().inbound_federation.knows_key(&x_matrix.origin, &x_matrix.key) {
+                    let required = BTreeMap::from([(x_matrix.origin, BTreeSet::from([x_matrix.key]))]);
+                    add_remote_keys(&required).await;
+                }
+            }
+            services().inbound_federation.authenticate(
+                authorization,
+                method.as_str(),
+                uri,
+                &services().globals.config.server_name,
+                content,
+            )
+        }
+
+        /// Fetch missing or expired remote signing keys and hand them to
+        /// inbound federation
+        async fn add_remote_keys(required: &BTreeMap<String, BTreeSet<String>>) {
+            let fetched = services().key_fetcher.fetch_required_signing_keys(&services().sending, required).await;
+            for (server, keys) in fetched {
+                services().inbound_federation.add_expiring_server_keys(&server, keys.keys_with_validity());
+            }
+        }
+
         placeholder_route!(get_event_route);
-        placeholder_route!(get_backfill_route);
-        placeholder_route!(get_missing_events_route);
-        placeholder_route!(get_event_authorization_route);
-        placeholder_route!(get_room_state_route);
+
+        /// # `GET /_matrix/federation/v1/backfill/{roomId}`
+        ///
+        /// Events preceding the ones given in `v`, newest first, up to
+        /// `limit`.
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn get_backfill_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let origin = authenticate(&method, &uri, &headers, None).await?;
+            let mut event_ids = Vec::new();
+            let mut limit = None;
+            for (key, value) in url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()) {
+                match &*key {
+                    "v" => event_ids.push(value.into_owned()),
+                    "limit" => limit = value.parse::<usize>().ok(),
+                    _ => {}
+                }
+            }
+            let limit = limit.ok_or(crate::Error::BadRequest(ErrorKind::InvalidParam, "Missing limit"))?;
+            let mut pdus = federation_history::backfill(
+                &services().timeline,
+                &services().server_keys,
+                &services().globals.config.server_name,
+                &origin,
+                &room_id,
+                &event_ids,
+                limit,
+            )?;
+            pdus.iter_mut().for_each(erasure::enforce_erasure);
+            Ok(RumaResponse(Json(serde_json::json!({
+                "origin": services().globals.config.server_name,
+                "origin_server_ts": SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+                "pdus": pdus,
+            }))))
+        }
+
+        /// # `POST /_matrix/federation/v1/get_missing_events/{roomId}`
+        ///
+        /// Events between `earliest_events` and `latest_events` the
+        /// requesting server is missing, oldest first.
+        #[instrument(level = "debug", skip(headers, body))]
+        pub async fn get_missing_events_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+            Json(body): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let origin = authenticate(&method, &uri, &headers, Some(&body)).await?;
+            let event_ids = |field: &str| -> Vec<String> {
+                body[field].as_array().into_iter().flatten().filter_map(|id| id.as_str().map(str::to_owned)).collect()
+            };
+            let mut events = federation_history::get_missing_events(
+                &services().timeline,
+                &services().server_keys,
+                &services().globals.config.server_name,
+                &origin,
+                &room_id,
+                &event_ids("earliest_events"),
+                &event_ids("latest_events"),
+                body["limit"].as_u64().unwrap_or(10) as usize,
+                body["min_depth"].as_u64().unwrap_or(0),
+            )?;
+            events.iter_mut().for_each(erasure::enforce_erasure);
+            Ok(RumaResponse(Json(serde_json::json!({ "events": events }))))
+        }
+
+        /// # `GET /_matrix/federation/v1/event_auth/{roomId}/{eventId}`
+        ///
+        /// The auth chain of an event.
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn get_event_authorization_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path((room_id, event_id)): Path<(String, String)>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let origin = authenticate(&method, &uri, &headers, None).await?;
+            let mut auth_chain = federation_history::event_auth(
+                &services().timeline,
+                &services().inbound_federation,
+                &services().server_keys,
+                &services().globals.config.server_name,
+                &origin,
+                &room_id,
+                &event_id,
+            )?;
+            auth_chain.iter_mut().for_each(erasure::enforce_erasure);
+            Ok(RumaResponse(Json(serde_json::json!({ "auth_chain": auth_chain }))))
+        }
+
+        /// # `GET /_matrix/federation/v1/state/{roomId}`
+        ///
+        /// Room state before the event named by `event_id`, with its auth
+        /// chain.
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn get_room_state_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path(room_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let origin = authenticate(&method, &uri, &headers, None).await?;
+            services().inbound_federation.partial_state().ensure_full_state(&room_id)?;
+            let event_id = url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+                .find(|(key, _)| key == "event_id")
+                .map(|(_, value)| value.into_owned())
+                .ok_or(crate::Error::BadRequest(ErrorKind::MissingParam, "Missing event_id"))?;
+            let mut response = federation_history::state_at_event(
+                &services().timeline,
+                &services().inbound_federation,
+                &services().server_keys,
+                &services().globals.config.server_name,
+                &origin,
+                &room_id,
+                &event_id,
+            )?;
+            erasure::enforce_erasure_in(&mut response, &["pdus", "auth_chain"]);
+            Ok(RumaResponse(Json(response)))
+        }
         placeholder_route!(get_room_state_ids_route);
-        placeholder_route!(create_join_event_template_route);
-        placeholder_route!(create_join_event_v1_route);
-        placeholder_route!(create_join_event_v2_route);
-        placeholder_route!(create_leave_event_template_route);
-        placeholder_route!(create_leave_event_route);
-        placeholder_route!(create_knock_event_template_route);
-        placeholder_route!(create_knock_event_route);
-        placeholder_route!(create_invite_route);
-        placeholder_route!(get_devices_route);
-        placeholder_route!(get_content_route);
-        placeholder_route!(get_content_thumbnail_route);
+
+        /// # `GET /_matrix/federation/v1/make_join/{roomId}/{userId}`
+        ///
+        /// Template of a join event for a user of the requesting server.
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn create_join_event_template_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path((room_id, user_id)): Path<(String, String)>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let origin = authenticate(&method, &uri, &headers, None).await?;
+            if user_id.split_once(':').map(|(_, server)| server) != Some(origin.as_str()) {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "The user does not belong to the origin server"));
+            }
+            // Joins are checked against the member list
+            services().inbound_federation.partial_state().ensure_full_state(&room_id)?;
+            let supported_versions: Vec<String> = uri
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .filter_map(|param| param.strip_prefix("ver="))
+                .map(str::to_owned)
+                .collect();
+            let response = federation_membership::make_join(
+                &services().timeline,
+                &services().globals.config.server_name,
+                &room_id,
+                &user_id,
+                &supported_versions,
+            )?;
+            Ok(RumaResponse(Json(response)))
+        }
+
+        async fn send_join(
+            method: Method,
+            uri: Uri,
+            room_id: String,
+            event_id: String,
+            headers: HeaderMap,
+            pdu: Value,
+            omit_members: bool,
+        ) -> crate::Result<Value> {
+            let origin = authenticate(&method, &uri, &headers, Some(&pdu)).await?;
+            services().inbound_federation.partial_state().ensure_full_state(&room_id)?;
+            add_remote_keys(&required_signing_keys(&pdu)).await;
+            let mut response = federation_membership::send_join(
+                &services().timeline,
+                &services().inbound_federation,
+                &services().server_keys,
+                &services().globals.config.server_name,
+                &origin,
+                &room_id,
+                &event_id,
+                &pdu,
+                omit_members,
+            )?;
+            erasure::enforce_erasure_in(&mut response, &["state", "auth_chain"]);
+            Ok(response)
+        }
+
+        /// # `PUT /_matrix/federation/v1/send_join/{roomId}/{eventId}`
+        ///
+        /// Deprecated form of the v2 endpoint, wrapping the response in
+        /// `[200, response]`.
+        #[instrument(level = "debug", skip(headers, pdu))]
+        pub async fn create_join_event_v1_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path((room_id, event_id)): Path<(String, String)>,
+            headers: HeaderMap,
+            Json(pdu): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let mut response = send_join(method, uri, room_id, event_id, headers, pdu, false).await?;
+            if let Some(object) = response.as_object_mut() {
+                object.remove("event");
+                object.remove("members_omitted");
+            }
+            Ok(RumaResponse(Json(serde_json::json!([200, response]))))
+        }
+
+        /// # `PUT /_matrix/federation/v2/send_join/{roomId}/{eventId}`
+        ///
+        /// Accept a join event of a remote user and return the room state,
+        /// without the membership events if `omit_members=true` is asked for.
+        #[instrument(level = "debug", skip(headers, pdu))]
+        pub async fn create_join_event_v2_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path((room_id, event_id)): Path<(String, String)>,
+            headers: HeaderMap,
+            Json(pdu): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let omit_members = url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+                .any(|(key, value)| key == "omit_members" && value == "true");
+            Ok(RumaResponse(Json(send_join(method, uri, room_id, event_id, headers, pdu, omit_members).await?)))
+        }
+
+        /// # `GET /_matrix/federation/v1/make_leave/{roomId}/{userId}`
+        ///
+        /// Template of a leave event for a user of the requesting server.
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn create_leave_event_template_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path((room_id, user_id)): Path<(String, String)>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let origin = authenticate(&method, &uri, &headers, None).await?;
+            if user_id.split_once(':').map(|(_, server)| server) != Some(origin.as_str()) {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "The user does not belong to the origin server"));
+            }
+            // Leaves are checked against the member list
+            services().inbound_federation.partial_state().ensure_full_state(&room_id)?;
+            let response = federation_membership::make_leave(&services().timeline, &room_id, &user_id)?;
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// # `PUT /_matrix/federation/v1/send_leave/{roomId}/{eventId}`
+        /// # `PUT /_matrix/federation/v2/send_leave/{roomId}/{eventId}`
+        ///
+        /// Accept a leave event of a remote user. Version 1 wraps the
+        /// response in `[200, {}]`.
+        #[instrument(level = "debug", skip(headers, pdu))]
+        pub async fn create_leave_event_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path((room_id, event_id)): Path<(String, String)>,
+            headers: HeaderMap,
+            Json(pdu): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let origin = authenticate(&method, &uri, &headers, Some(&pdu)).await?;
+            add_remote_keys(&required_signing_keys(&pdu)).await;
+            federation_membership::send_leave(
+                &services().timeline,
+                &services().inbound_federation,
+                &origin,
+                &room_id,
+                &event_id,
+                &pdu,
+            )?;
+            if uri.path().starts_with("/_matrix/federation/v1/") {
+                return Ok(RumaResponse(Json(serde_json::json!([200, {}]))));
+            }
+            Ok(RumaResponse(Json(serde_json::json!({}))))
+        }
+
+        /// # `GET /_matrix/federation/v1/make_knock/{roomId}/{userId}`
+        ///
+        /// Template of a knock event for a user of the requesting server.
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn create_knock_event_template_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path((room_id, user_id)): Path<(String, String)>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let origin = authenticate(&method, &uri, &headers, None).await?;
+            if user_id.split_once(':').map(|(_, server)| server) != Some(origin.as_str()) {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "The user does not belong to the origin server"));
+            }
+            let supported_versions: Vec<String> = uri
+                .query()
+                .unwrap_or_default()
+                .split('&')
+                .filter_map(|param| param.strip_prefix("ver="))
+                .map(str::to_owned)
+                .collect();
+            let response = federation_membership::make_knock(&services().timeline, &room_id, &user_id, &supported_versions)?;
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// # `PUT /_matrix/federation/v1/send_knock/{roomId}/{eventId}`
+        ///
+        /// Accept a knock of a remote user and return the stripped room
+        /// state for their client.
+        #[instrument(level = "debug", skip(headers, pdu))]
+        pub async fn create_knock_event_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path((room_id, event_id)): Path<(String, String)>,
+            headers: HeaderMap,
+            Json(pdu): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let origin = authenticate(&method, &uri, &headers, Some(&pdu)).await?;
+            add_remote_keys(&required_signing_keys(&pdu)).await;
+            let response = federation_membership::send_knock(
+                &services().timeline,
+                &services().inbound_federation,
+                &origin,
+                &room_id,
+                &event_id,
+                &pdu,
+            )?;
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// # `PUT /_matrix/federation/v1/invite/{roomId}/{eventId}`
+        /// # `PUT /_matrix/federation/v2/invite/{roomId}/{eventId}`
+        ///
+        /// Invite a user of this server to a room. Version 1 sends the bare
+        /// event, with the room state in its `unsigned` section, and wraps
+        /// the response in `[200, response]`.
+        #[instrument(level = "debug", skip(headers, body))]
+        pub async fn create_invite_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path((room_id, event_id)): Path<(String, String)>,
+            headers: HeaderMap,
+            Json(body): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let origin = authenticate(&method, &uri, &headers, Some(&body)).await?;
+            let v1 = uri.path().starts_with("/_matrix/federation/v1/");
+            let (pdu, room_version, invite_room_state) = if v1 {
+                let invite_room_state = body["unsigned"]["invite_room_state"].as_array().cloned().unwrap_or_default();
+                (body, "1".to_owned(), invite_room_state)
+            } else {
+                let room_version = body["room_version"].as_str().unwrap_or("1").to_owned();
+                let invite_room_state = body["invite_room_state"].as_array().cloned().unwrap_or_default();
+                (body["event"].clone(), room_version, invite_room_state)
+            };
+            let invitee = pdu["state_key"].as_str().unwrap_or_default();
+            if services().accounts.is_deactivated(invitee) {
+                return Err(crate::Error::BadRequest(ErrorKind::forbidden(), "The invited user is deactivated"));
+            }
+            add_remote_keys(&required_signing_keys(&pdu)).await;
+            let event = federation_membership::invite(
+                &services().timeline,
+                &services().inbound_federation,
+                &services().membership,
+                &services().server_keys,
+                &services().globals.config.server_name,
+                &origin,
+                &room_id,
+                &event_id,
+                &room_version,
+                &pdu,
+                &invite_room_state,
+            )?;
+            if v1 {
+                return Ok(RumaResponse(Json(serde_json::json!([200, { "event": event }]))));
+            }
+            Ok(RumaResponse(Json(serde_json::json!({ "event": event }))))
+        }
+
+        /// Whether a user belongs to this server
+        fn is_local_user(user_id: &str) -> bool {
+            let server = user_id.split_once(':').map(|(_, server)| server);
+            server == Some("matrixon.local") || server == Some(services().globals.config.server_name.as_str())
+        }
+
+        /// Keep only the entries of a `{ "<user_id>": ... }` request about local users
+        fn local_users_only(requested: &Value) -> serde_json::Map<String, Value> {
+            requested
+                .as_object()
+                .into_iter()
+                .flatten()
+                .filter(|(user_id, _)| is_local_user(user_id))
+                .map(|(user_id, value)| (user_id.clone(), value.clone()))
+                .collect()
+        }
+
+        /// # `GET /_matrix/federation/v1/user/devices/{userId}`
+        ///
+        /// Devices of a local user with their keys, and the user's
+        /// cross-signing keys.
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn get_devices_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path(user_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            authenticate(&method, &uri, &headers, None).await?;
+            if !is_local_user(&user_id) {
+                return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "The user does not belong to this server"));
+            }
+            let keys = &services().keys;
+            let devices: Vec<Value> = keys
+                .get_device_keys(&user_id, &[])
+                .into_iter()
+                .map(|(device_id, device_keys)| serde_json::json!({ "device_id": device_id, "keys": device_keys }))
+                .collect();
+            let mut response = serde_json::json!({
+                "user_id": user_id,
+                "stream_id": keys.device_list_version(&user_id),
+                "devices": devices,
+            });
+            if let Some(master_key) = keys.get_master_key("", &user_id) {
+                response["master_key"] = master_key;
+            }
+            if let Some(self_signing_key) = keys.get_self_signing_key(&user_id) {
+                response["self_signing_key"] = self_signing_key;
+            }
+            Ok(RumaResponse(Json(response)))
+        }
+
+        /// # `GET /_matrix/federation/v1/media/download/{mediaId}`
+        ///
+        /// Media uploaded to this server, as `multipart/mixed`.
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn get_content_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path(media_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<axum::response::Response> {
+            authenticate(&method, &uri, &headers, None).await?;
+            local_media_response(&media_id)
+        }
+
+        /// # `GET /_matrix/federation/v1/media/thumbnail/{mediaId}`
+        ///
+        /// Thumbnail of media uploaded to this server. Images are not scaled,
+        /// so the original content is returned, which clients accept as a
+        /// thumbnail larger than requested.
+        #[instrument(level = "debug", skip(headers))]
+        pub async fn get_content_thumbnail_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            Path(media_id): Path<String>,
+            headers: HeaderMap,
+        ) -> crate::Result<axum::response::Response> {
+            authenticate(&method, &uri, &headers, None).await?;
+            let params: BTreeMap<String, String> =
+                url::form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes()).into_owned().collect();
+            if ["width", "height"].iter().any(|key| params.get(*key).and_then(|value| value.parse::<u32>().ok()).is_none()) {
+                return Err(crate::Error::BadRequest(ErrorKind::InvalidParam, "Missing or invalid thumbnail size"));
+            }
+            local_media_response(&media_id)
+        }
+
+        fn local_media_response(media_id: &str) -> crate::Result<axum::response::Response> {
+            let media = services()
+                .media_store
+                .get(media_id)
+                .ok_or(crate::Error::BadRequest(ErrorKind::NotFound, "Media not found"))?;
+            let (content_type, body) = media.to_multipart();
+            Ok(([(axum::http::header::CONTENT_TYPE, content_type)], body).into_response())
+        }
         placeholder_route!(get_room_information_route);
         placeholder_route!(get_profile_information_route);
-        placeholder_route!(get_keys_route);
-        placeholder_route!(claim_keys_route);
+
+        /// # `POST /_matrix/federation/v1/user/keys/query`
+        ///
+        /// Device and cross-signing keys of local users.
+        #[instrument(level = "debug", skip(headers, body))]
+        pub async fn get_keys_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            headers: HeaderMap,
+            Json(body): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let origin = authenticate(&method, &uri, &headers, Some(&body)).await?;
+            let requested = local_users_only(&body["device_keys"]);
+            tracing::debug!("🔍 Key query from {} for {} users", origin, requested.len());
+            Ok(RumaResponse(Json(services().keys.query_keys(None, &requested))))
+        }
+
+        /// # `POST /_matrix/federation/v1/user/keys/claim`
+        ///
+        /// Claim one-time keys of local users' devices.
+        #[instrument(level = "debug", skip(headers, body))]
+        pub async fn claim_keys_route(
+            method: Method,
+            OriginalUri(uri): OriginalUri,
+            headers: HeaderMap,
+            Json(body): Json<Value>,
+        ) -> crate::Result<RumaResponse<Json<Value>>> {
+            let origin = authenticate(&method, &uri, &headers, Some(&body)).await?;
+            let requested = local_users_only(&body["one_time_keys"]);
+            tracing::debug!("🎟️ Key claim from {} for {} users", origin, requested.len());
+            Ok(RumaResponse(Json(serde_json::json!({ "one_time_keys": services().keys.claim_keys(&requested) }))))
+        }
         placeholder_route!(get_openid_userinfo_route);
-        placeholder_route!(get_hierarchy_route);
         placeholder_route!(well_known_server);
 
         // Module namespaces for organized federation routes
@@ -966,14 +5631,182 @@ pub fn services() -> &'static Services {
     SERVICES.get().expect("Services not initialized")
 }
 
-/// Initialize global services with configuration
-pub fn init_services(config: Config) {
+/// Repositories of the configured database and its shards, connected
+/// lazily, so nothing is connected until they are first used
+pub fn connect_repositories(config: &Config) -> Result<Option<matrixon_db::Repositories>> {
+    let pool = match config.database_backend.as_deref() {
+        Some("postgresql") | Some("postgres") => match matrixon_db::DatabasePool::connect_lazy(&config.database_config()) {
+            Ok(pool) => pool,
+            Err(e) => {
+                tracing::warn!("⚠️ Invalid database URL, keeping data in memory: {}", e);
+                return Ok(None);
+            }
+        },
+        _ => return Ok(None),
+    };
+    let shards = matrixon_db::ShardRouter::connect_lazy(&config.database_config(), &pool)
+        .map_err(|e| Error::BadConfig(e.to_string()))?;
+    Ok(Some(matrixon_db::Repositories::with_shards(pool, shards)))
+}
+
+/// Initialize global services with configuration, keeping data in the
+/// database of `repositories` if there is one
+pub fn init_services(config: Config, repositories: Option<matrixon_db::Repositories>) -> Result<()> {
+    let auto_join_rooms = config.auto_join_rooms.clone().unwrap_or_default();
+    let profiles = service::profiles::Service::build(repositories.as_ref().map(|repositories| repositories.pool().clone()))
+        .with_cache_capacity_modifier(config.matrixon_cache_capacity_modifier.unwrap_or(1.0))
+        .with_fields_file(config.state_path("profile_fields.json"));
+    let room_directory = service::room_directory::Service::new().with_published_file(config.state_path("published_rooms.json"));
+    let room_aliases = service::room_aliases::Service::new().with_aliases_file(config.state_path("room_aliases.json"));
+    let webhooks = matrixon_core::webhooks::WebhookDispatcher::new(
+        config.server_name.clone(),
+        config.webhooks.clone().unwrap_or_default(),
+    );
+    let federation = config.federation().expect("Invalid [federation] section");
+    let sending = matrixon_federation::sending::Service::new(matrixon_federation::sending::SendingConfig {
+        server_name: config.server_name.clone(),
+        queue_dir: config.federation_queue_path(),
+        client: matrixon_federation::http::ClientSettings::from(&federation),
+        ..Default::default()
+    });
+    let audit_log_path = config.audit_log_path.clone().filter(|_| config.enable_audit_logging.unwrap_or(false));
+    let server_keys = std::sync::Arc::new(
+        service::server_keys::Service::load(&config.server_name, config.signing_key_path())
+            .expect("Failed to load the server signing key"),
+    );
+    let join_coordinator = service::join_coordinator::Service::new(
+        config.max_concurrent_federated_joins.unwrap_or(4),
+        std::time::Duration::from_secs(config.federated_join_wait_s.unwrap_or(60)),
+    );
+    let event_persistence = repositories.as_ref().map(|_| {
+        std::sync::Arc::new(service::event_persistence::Service::new(
+            config.event_persistence_batch_size.unwrap_or(256),
+            std::time::Duration::from_millis(config.event_persistence_max_delay_ms.unwrap_or(5)),
+            config.event_persistence_queue_size.unwrap_or(10_000),
+        ))
+    });
+    let mut timeline = service::timeline::Service::new()
+        .with_cache_capacity_modifier(config.matrixon_cache_capacity_modifier.unwrap_or(1.0))
+        .with_signer(server_keys.clone());
+    if let Some(persistence) = &event_persistence {
+        timeline = timeline.with_persistence(persistence.clone());
+    }
+    let key_fetcher = service::key_fetcher::Service::new(&config.server_name, config.trusted_servers());
+    let localization = service::localization::Service::new(&config.localization()).expect("Invalid localization configuration");
+    let email = config.email.clone().map(|email| {
+        std::sync::Arc::new(
+            matrixon_email::Mailer::new(email)
+                .expect("Invalid email configuration")
+                .with_catalog(localization.catalog()),
+        )
+    });
+    let pages = service::pages::Service::new(&config.pages(), &config.server_name, localization.catalog())
+        .expect("Invalid page templates");
+    let inbound_federation = service::inbound_federation::Service::new().with_transaction_log(
+        config.federation_transactions_path(),
+        config.federation_transaction_ttl_ms(),
+        std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64,
+    )
+    .with_fixture_recorder(config.federation_fixture_dir.as_ref().map(std::path::PathBuf::from))
+    .with_cache_capacity_modifier(config.matrixon_cache_capacity_modifier.unwrap_or(1.0))
+    .with_partial_state_file(config.state_path("partial_state.json"))
+    .with_repositories(repositories.as_ref());
+    let mut short = service::short::Service::new()
+        .with_cache_capacity_modifier(config.matrixon_cache_capacity_modifier.unwrap_or(1.0));
+    if let Some(repositories) = &repositories {
+        short = short.with_repository(repositories.short_ids.clone());
+    }
+    let event_reports = service::event_reports::Service::new(config.report_escalation.clone(), &config.server_name);
+    let legal_hold = service::legal_hold::Service::new().with_holds_file(config.state_path("legal_holds.json"));
+    let room_stats = config
+        .room_stats
+        .clone()
+        .map(|room_stats| service::room_stats::Service::new(room_stats).with_stats_file(config.state_path("room_stats.json")));
+    let room_webhooks = config.room_webhooks.clone().map(|webhooks| {
+        service::room_webhooks::Service::new(webhooks, &config.server_name).with_state_file(config.state_path("room_webhooks.json"))
+    });
+    let retention = config
+        .retention
+        .clone()
+        .map(|retention| service::retention::Service::new(retention).with_state_file(config.state_path("retention.json")));
+    let maintenance = config.cleanup_second_intervals.map(service::maintenance::Service::new);
+    let threepids = service::threepids::Service::new().with_state_file(config.state_path("threepids.json"));
+    let threepids = match &email {
+        Some(mailer) => threepids.with_mailer(mailer.clone(), &config.server_name),
+        None => threepids,
+    };
+    let keys = service::keys::Service::new().with_store_dir(config.state_path("e2ee_keys"));
+    let accounts = service::accounts::Service::new().with_state_file(config.state_path("accounts.json"));
+    let room_deletion = service::room_deletion::Service::new().with_state_file(config.state_path("blocked_rooms.json"));
+    let room_key_backup = match &repositories {
+        Some(repositories) => service::room_key_backup::Service::new().with_repository(repositories.room_key_backups.clone()),
+        None => service::room_key_backup::Service::new().with_state_file(config.state_path("room_key_backups.json")),
+    };
+    let health = matrixon_core::health::HealthRegistry::default();
+    if let Some(repositories) = &repositories {
+        health.register("database", true, std::sync::Arc::new(repositories.database_pool().clone()));
+        if repositories.shards().shards().len() > 1 {
+            health.register("database_shards", true, std::sync::Arc::new(repositories.shards().clone()));
+        }
+    }
+    if config.allow_federation {
+        health.register("federation", false, sending.clone());
+    }
+    let ipfs = service::ipfs::Service::new();
+    // The default public gateway is not worth probing, as at startup
+    if let Some(gateway) = &config.ipfs_gateway {
+        health.register("ipfs", false, ipfs.health_probe(gateway));
+    }
+    let appservices = service::appservices::Service::new(config.appservices.clone().unwrap_or_default(), &config.server_name)?;
     SERVICES.set(Services {
         globals: Globals {
             config,
             shutdown: AtomicBool::new(false),
         },
+        keys,
+        accounts,
+        timeline,
+        media_store: service::media_store::Service::new(),
+        delegated_auth: service::delegated_auth::Service::new(),
+        auto_join: service::auto_join::Service::new(auto_join_rooms),
+        profiles,
+        threepids,
+        pages,
+        localization,
+        room_summary: service::room_summary::Service::new(),
+        room_stats,
+        room_webhooks,
+        retention,
+        maintenance,
+        impersonation: service::impersonation::Service::new(audit_log_path),
+        appservices,
+        sessions: service::sessions::Service::new(),
+        legal_hold,
+        room_deletion,
+        event_reports,
+        webhooks,
+        membership: service::membership::Service::new(),
+        join_coordinator,
+        sending,
+        room_key_backup,
+        room_directory,
+        room_aliases,
+        space_hierarchy: service::space_hierarchy::Service::new(),
+        inbound_federation,
+        short,
+        server_keys,
+        key_fetcher,
+        nft_avatar: service::nft_avatar::Service::new(),
+        ipfs,
+        remote_media: service::remote_media::Service::new(),
+        email,
+        repositories,
+        event_persistence,
+        iot: std::sync::OnceLock::new(),
+        performance: std::sync::OnceLock::new(),
+        health,
     }).expect("Services already initialized");
+    Ok(())
 }
 
 /// Global shutdown signal for coordinated shutdown
@@ -53,18 +53,16 @@
 //
 // =============================================================================
 
-use std::{io, net::SocketAddr, sync::atomic, time::Duration};
+use std::{io, sync::atomic, time::Duration};
 
 use axum::{
     body::Body,
-    extract::{DefaultBodyLimit, MatchedPath, Path},
-    middleware::map_response,
+    extract::{DefaultBodyLimit, MatchedPath, Path, Query, RawQuery},
     response::{IntoResponse, Response, Json},
-    routing::{any, get, post, put},
+    routing::{any, delete, get, post, put},
     Router,
     http::{HeaderMap, StatusCode},
 };
-use tokio::net::TcpListener;
 use matrixon::api::{client_server, server_server};
 use figment::{
     providers::{Env, Format, Toml},
@@ -72,7 +70,7 @@ use figment::{
     Figment,
 };
 use axum::http::{
-    header::{self, HeaderName, CONTENT_SECURITY_POLICY},
+    header::{self, HeaderName},
     Method, Uri,
 };
 // OpenTelemetry disabled for now
@@ -80,7 +78,7 @@ use ruma::api::client::error::ErrorKind;
 use tokio::signal;
 use tower::ServiceBuilder;
 use tower_http::{
-    cors::{self, CorsLayer},
+    cors::{AllowOrigin, CorsLayer},
     trace::TraceLayer,
     // ServiceBuilderExt as _,
 };
@@ -88,8 +86,10 @@ use tracing::{debug, error, info, warn, instrument};
 use tracing_subscriber::{prelude::*, EnvFilter};
 // use matrixon::federation::{FederationManager, FederationConfig};
 use matrixon::*;
-use std::time::Instant;
-use uuid::Uuid;
+use matrixon::service::timeline::{Direction, SerializedEvent, Token};
+use matrixon::config::{ListenerConfig, ListenerResource};
+use matrixon::service::listener;
+use std::{collections::HashMap, time::Instant};
 
 mod clap;
 
@@ -100,7 +100,7 @@ use tikv_jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-static SUB_TABLES: [&str; 3] = ["well_known", "tls", "media"]; // Not doing `proxy` cause setting that with env vars would be a pain
+static SUB_TABLES: [&str; 4] = ["well_known", "tls", "media", "startup"]; // Not doing `proxy` cause setting that with env vars would be a pain
 
 // Yeah, I know it's terrible, but since it seems the container users dont want syntax like A[B][C]="...",
 // this is what we have to deal with. Also see: https://github.com/SergioBenitez/Figment/issues/12#issuecomment-801449465
@@ -150,6 +150,12 @@ async fn main() {
     let raw_config = Figment::new()
         .merge(Toml::file(&config_path).nested())
         .merge(Env::prefixed("MATRIXON_").global().map(|k| {
+            // `MATRIXON_IOT__MQTT_CONFIG__PORT` sets `iot.mqtt_config.port`: the
+            // keys of the crate sections have underscores of their own
+            if k.as_str().contains("__") {
+                return Uncased::from(k.as_str().replace("__", "."));
+            }
+
             let mut key: Uncased = k.into();
 
             'outer: for table in SUB_TABLES {
@@ -188,6 +194,25 @@ async fn main() {
         }
     };
     
+    // Keys no setting reads are most likely typos
+    if let Ok(file) = Figment::from(Toml::file(&config_path).nested()).extract::<serde_json::Value>() {
+        for key in config.unknown_keys(&file) {
+            tracing::warn!("⚠️ Unknown configuration key {}", key);
+        }
+    }
+    match config.check_sections() {
+        Ok(unknown_keys) => {
+            for key in unknown_keys {
+                tracing::warn!("⚠️ Unknown configuration key {}", key);
+            }
+        }
+        Err(e) => {
+            error!("Failed to load configuration: {}", e);
+            eprintln!("It looks like your config is invalid. The following error occurred: {e}");
+            std::process::exit(1);
+        }
+    }
+
     // Apply verbose mode if enabled
     if args.verbose {
         info!("🔍 Verbose mode enabled");
@@ -197,7 +222,7 @@ async fn main() {
     
     // Process commands based on CLI subcommand
     match args.command {
-        clap::Commands::Start { address, port, no_federation, daemon } => {
+        clap::Commands::Start { address, port, no_federation, daemon, wait_for_deps } => {
             // Override config with CLI arguments if provided
             if let Some(address_str) = address {
                 if let Ok(addr) = address_str.parse() {
@@ -219,6 +244,11 @@ async fn main() {
                 info!("🚫 Federation disabled via CLI flag");
             }
             
+            if wait_for_deps {
+                config.startup.get_or_insert_with(Default::default).wait_for_deps = true;
+                info!("⏳ Waiting for dependencies via CLI flag");
+            }
+            
             if daemon {
                 info!("🌙 Running in daemon mode");
                 // TODO: Implement daemon mode
@@ -252,11 +282,6 @@ async fn main() {
 
 /// Start the Matrix server
 async fn start_server(config: Config) {
-    info!("🚀 Starting Matrixon Matrix Server");
-    
-    // Initialize services
-    init_services(config.clone());
-
     let jaeger: Option<()> = if false { // Disabled for now due to version conflicts
         // OpenTelemetry configuration disabled temporarily
         None
@@ -299,8 +324,176 @@ async fn start_server(config: Config) {
     #[cfg(unix)]
     maximize_fd_limit().expect("should be able to increase the soft limit to the hard limit");
 
+    info!("🚀 Starting Matrixon Matrix Server");
+    let repositories = match matrixon::connect_repositories(&config) {
+        Ok(repositories) => repositories,
+        Err(e) => {
+            error!("❌ Could not set up the database: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    info!("Waiting for dependencies");
+    let startup = match matrixon::service::startup::plan(&config, repositories.as_ref()).run().await {
+        Ok(startup) => startup,
+        Err(e) => {
+            error!("❌ {}", e);
+            std::process::exit(1);
+        }
+    };
+    // An optional database that is down is left alone until restart, and
+    // data is kept in memory meanwhile
+    let repositories = repositories.filter(|_| startup.is_available("database"));
+
+    // Initialize services
+    if let Err(e) = init_services(config.clone(), repositories) {
+        error!("❌ Could not initialize the services: {}", e);
+        std::process::exit(1);
+    }
+
+    // Keep room summaries up to date in the background so syncs only apply
+    // the latest membership changes
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            services().room_summary.refresh_all();
+        }
+    });
+
+    if config.allow_federation {
+        services().sending.set_signer(services().server_keys.clone());
+        services().sending.set_down_hook(std::sync::Arc::new(|destination, error| {
+            services().webhooks.notify(matrixon_core::webhooks::WebhookEvent::FederationDestinationDown {
+                destination: destination.to_owned(),
+                error: error.to_owned(),
+            });
+        }));
+        if let Err(e) = services().sending.resume() {
+            error!("❌ Could not resume federation queues: {}", e);
+        }
+        tokio::spawn(matrixon::service::outbound_federation::run());
+        // Resume fetching the state of rooms joined with partial state
+        for (room_id, _) in services().inbound_federation.partial_state().rooms() {
+            tokio::spawn(matrixon::service::federation_membership::resync_partial_state(room_id));
+        }
+    }
+
+    if let Some(mailer) = &services().email {
+        tokio::spawn(mailer.run());
+    }
+
+    if let Some(path) = config.cache_snapshot_path() {
+        tokio::spawn(matrixon::service::cache_warmup::run(path));
+    }
+
+    if config.room_stats.is_some() {
+        tokio::spawn(matrixon::service::room_stats::run());
+    }
+
+    if config.room_webhooks.is_some() {
+        tokio::spawn(matrixon::service::room_webhooks::run());
+    }
+
+    if config.retention.is_some() {
+        tokio::spawn(matrixon::service::retention::run());
+    }
+
+    if config.cleanup_second_intervals.is_some() {
+        tokio::spawn(matrixon::service::maintenance::run());
+    }
+
+    match config.iot() {
+        Ok(Some(iot)) => match matrixon::service::iot::start(iot, config.presence_offline_timeout()).await {
+            Ok(manager) => {
+                services().health.register("iot", false, manager.health_probe());
+                let _ = services().iot.set(manager.clone());
+                tokio::spawn(matrixon::service::iot::run(manager));
+            }
+            Err(e) => error!("❌ {}", e),
+        },
+        Ok(None) => {}
+        Err(e) => error!("❌ {}", e),
+    }
+
+    // Alerts of email channels go out through the server's mailer
+    match config.monitor() {
+        Ok(Some(monitor)) => match matrixon_monitor::MonitorService::new_with_mailer(monitor, services().email.clone()).await {
+            Ok(mut monitor) => {
+                services().health.register("monitor", false, monitor.health_probe());
+                let performance = monitor.performance();
+                let _ = services().performance.set(performance.clone());
+                tokio::spawn(record_event_rate(performance));
+                tokio::spawn(async move {
+                    if let Err(e) = monitor.start().await {
+                        error!("❌ Monitor stopped: {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("❌ Could not set up the monitor: {}", e),
+        },
+        Ok(None) => {}
+        Err(e) => error!("❌ {}", e),
+    }
+
+    if let Some(export) = config.event_export.clone() {
+        let position_file = config.state_path("event_export.json");
+        tokio::spawn(async move {
+            if let Err(e) = matrixon::service::event_export::run(export, position_file).await {
+                error!("❌ Event export stopped: {}", e);
+            }
+        });
+    }
+
     info!("Loading database");
-    if let Err(error) = DummyDatabase::load_or_create(config.clone()).await {
+    let repositories = services().repositories.as_ref();
+    let migrated = match repositories {
+        Some(repositories) => {
+            repositories.database_pool().spawn_lag_monitor(Duration::from_secs(5));
+            repositories.database_pool().spawn_tuner(Duration::from_secs(10));
+            if repositories.shards().shards().len() > 1 {
+                for shard in repositories.shards().shards() {
+                    shard.spawn_tuner(Duration::from_secs(10));
+                }
+            }
+            repositories.migrate().await
+        }
+        None => Ok(()),
+    };
+    if let (true, Some(repositories)) = (config.enable_point_in_time_recovery == Some(true), repositories) {
+        match matrixon_db::pitr::archiver_status(repositories.pool()).await {
+            Ok(status) if status.archive_mode == "off" => {
+                warn!("⚠️ Point-in-time recovery is enabled but the database does not archive WAL (archive_mode = off)")
+            }
+            Ok(status) if status.failed_count > 0 => warn!(
+                "⚠️ WAL archiving failed {} times, last for {}",
+                status.failed_count,
+                status.last_failed_wal.as_deref().unwrap_or("-")
+            ),
+            Ok(_) => info!("✅ WAL is archived to {}", config.wal_archive().dir().display()),
+            Err(e) => warn!("⚠️ Could not check WAL archiving: {}", e),
+        }
+    }
+    if let (Ok(()), Some(repositories), Some(persistence)) =
+        (&migrated, repositories, &services().event_persistence)
+    {
+        if let Err(e) = service::event_persistence::load_timelines(&repositories.events, &services().timeline).await {
+            error!("❌ Could not load the stored timelines: {}", e);
+            std::process::exit(1);
+        }
+        persistence.spawn_writer(repositories.events.clone(), &services().short);
+    }
+    if let (Ok(()), Some(repositories)) = (&migrated, repositories) {
+        if let Err(e) = services().inbound_federation.load(repositories).await {
+            error!("❌ Could not load the stored outliers and auth chains: {}", e);
+            std::process::exit(1);
+        }
+        if let Err(e) = services().room_key_backup.load(&repositories.room_key_backups).await {
+            error!("❌ Could not load the stored room key backups: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Err(error) = migrated {
         error!("❌ Database initialization failed: {}", error);
         error!("🔍 Error details: {:?}", error);
         
@@ -323,6 +516,14 @@ async fn start_server(config: Config) {
     info!("Starting server");
     match run_server(&config).await {
         Ok(_) => {
+            if let Some(persistence) = &services().event_persistence {
+                if tokio::time::timeout(Duration::from_secs(30), persistence.flush()).await.is_err() {
+                    error!("❌ {} timeline events could not be written before shutdown", persistence.queued());
+                }
+            }
+            if let Some(path) = config.cache_snapshot_path() {
+                matrixon::service::cache_warmup::save_snapshot(&path);
+            }
             info!("✅ Server shutdown completed successfully");
         }
         Err(e) => {
@@ -450,20 +651,58 @@ async fn process_room_command(action: clap::RoomCommands, config: &Config) {
             info!("🆔 Room ID: {}", room_id);
         }
         
-        RoomCommands::Delete { room_id, force, block } => {
+        RoomCommands::Delete { room_id, force, block, reason, access_token, server_url } => {
             info!("🗑️ Deleting room: {}", room_id);
             
             if !force {
-                // TODO: Implement confirmation prompt
-                println!("Are you sure you want to delete room {}? [y/N]", room_id);
+                println!("Are you sure you want to delete room {}? Its events and media cannot be restored. [y/N]", room_id);
+                let mut answer = String::new();
+                if std::io::stdin().read_line(&mut answer).is_err() || !answer.trim().eq_ignore_ascii_case("y") {
+                    println!("Aborted");
+                    return;
+                }
             }
             
-            if block {
-                info!("🚫 Room will be blocked from recreation");
+            // The room lives in the running server, which purges it through
+            // the admin API
+            let server_url = server_url.unwrap_or_else(|| format!("http://{}:{}", config.address, config.port));
+            let mut url = match reqwest::Url::parse(&server_url) {
+                Ok(url) => url,
+                Err(e) => {
+                    error!("❌ Invalid server URL {}: {}", server_url, e);
+                    std::process::exit(1);
+                }
+            };
+            url.path_segments_mut()
+                .expect("HTTP URLs have a path")
+                .pop_if_empty()
+                .extend(["_matrixon", "admin", "v1", "rooms", room_id.as_str()]);
+            url.query_pairs_mut().append_pair("block", &block.to_string());
+            if let Some(reason) = &reason {
+                url.query_pairs_mut().append_pair("reason", reason);
             }
             
-            // TODO: Implement actual room deletion logic
-            info!("✅ Room {} deleted successfully", room_id);
+            let response = reqwest::Client::new().delete(url).bearer_auth(&access_token).send().await;
+            let report: serde_json::Value = match response {
+                Ok(response) if response.status().is_success() => response.json().await.unwrap_or_default(),
+                Ok(response) => {
+                    let status = response.status();
+                    error!("❌ Deleting {} failed with {}: {}", room_id, status, response.text().await.unwrap_or_default());
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    error!("❌ Could not reach the server at {}: {}", server_url, e);
+                    std::process::exit(1);
+                }
+            };
+            
+            println!("Room {} deleted", room_id);
+            println!("  Local users removed: {}", report["kicked_users"].as_array().map_or(0, Vec::len));
+            println!("  Events deleted: {}", report["deleted_events"]);
+            println!("  Media files deleted: {}", report["deleted_media"]);
+            if block {
+                println!("  Blocked from being joined again");
+            }
         }
         
         RoomCommands::List { detailed, public_only } => {
@@ -556,9 +795,37 @@ async fn process_database_command(action: clap::DatabaseCommands, config: &Confi
             if compress {
                 info!("🗜️ Compression enabled");
             }
-            
-            // TODO: Implement actual backup logic
-            info!("✅ Database backup created successfully");
+
+            // Custom-format dumps are compressed and restored with pg_restore
+            let format = if compress { "--format=custom" } else { "--format=plain" };
+            let dumped = matrixon_db::pg_tool("pg_dump", &config.database_url)
+                .arg(format)
+                .arg("--file")
+                .arg(&output)
+                .output()
+                .await;
+            match dumped {
+                Ok(dumped) if dumped.status.success() => info!("✅ Database backup created successfully"),
+                Ok(dumped) => {
+                    error!("❌ pg_dump failed: {}", String::from_utf8_lossy(&dumped.stderr).trim());
+                    std::process::exit(1);
+                }
+                Err(e) => {
+                    error!("❌ Could not run pg_dump: {}", e);
+                    std::process::exit(1);
+                }
+            }
+
+            let webhooks = matrixon_core::webhooks::WebhookDispatcher::new(
+                config.server_name.clone(),
+                config.webhooks.clone().unwrap_or_default(),
+            );
+            webhooks
+                .deliver(&matrixon_core::webhooks::WebhookEvent::BackupCompleted {
+                    location: output.display().to_string(),
+                    compressed: compress,
+                })
+                .await;
         }
         
         DatabaseCommands::Restore { input, force } => {
@@ -574,6 +841,83 @@ async fn process_database_command(action: clap::DatabaseCommands, config: &Confi
             info!("✅ Database restored successfully");
         }
         
+        // PostgreSQL runs `archive-wal` for every WAL segment and retries
+        // until it exits successfully
+        DatabaseCommands::ArchiveWal { path, name } => {
+            if let Err(e) = config.wal_archive().archive_segment(&path, &name) {
+                error!("❌ Archiving {} failed: {}", name, e);
+                std::process::exit(1);
+            }
+            debug!("📦 Archived WAL segment {}", name);
+        }
+        
+        DatabaseCommands::BaseBackup => {
+            match config.wal_archive().take_base_backup(&config.database_url).await {
+                Ok(backup) => println!("Base backup {} taken, starting at WAL segment {}", backup.id, backup.start_segment),
+                Err(e) => {
+                    error!("❌ Base backup failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        
+        DatabaseCommands::WalStatus => {
+            let archive = config.wal_archive();
+            println!("WAL archive: {}", archive.dir().display());
+            for backup in archive.base_backups().unwrap_or_default() {
+                println!("  base backup {}: {} to {}, from {}", backup.id, backup.started_at, backup.finished_at, backup.start_segment);
+            }
+            let segments = archive.segments().unwrap_or_default();
+            println!("  {} WAL files, latest {}", segments.len(), segments.last().map_or("-", String::as_str));
+            
+            let status = match matrixon_db::DatabasePool::connect_lazy(&config.database_config()) {
+                Ok(pool) => matrixon_db::pitr::archiver_status(pool.pool()).await,
+                Err(e) => Err(e),
+            };
+            match status {
+                Ok(status) => println!(
+                    "Database: archive_mode {}, {} archived (latest {}), {} failed (latest {})",
+                    status.archive_mode,
+                    status.archived_count,
+                    status.last_archived_wal.as_deref().unwrap_or("-"),
+                    status.failed_count,
+                    status.last_failed_wal.as_deref().unwrap_or("-"),
+                ),
+                Err(e) => println!("Database: archiving state unavailable: {}", e),
+            }
+        }
+        
+        DatabaseCommands::PruneWal { keep } => {
+            let keep = keep.or(config.wal_archive_keep_base_backups).unwrap_or(2);
+            match config.wal_archive().prune(keep) {
+                Ok(removed) => println!("Removed {} base backups and WAL files", removed),
+                Err(e) => {
+                    error!("❌ Pruning the WAL archive failed: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        
+        DatabaseCommands::RestoreToTime { target, data_dir } => {
+            let target = match chrono::DateTime::parse_from_rfc3339(&target) {
+                Ok(target) => target.with_timezone(&chrono::Utc),
+                Err(e) => {
+                    error!("❌ Invalid target time {}: {}", target, e);
+                    std::process::exit(1);
+                }
+            };
+            match config.wal_archive().prepare_restore(target, &data_dir).await {
+                Ok(backup) => {
+                    println!("Restored base backup {} into {}", backup.id, data_dir.display());
+                    println!("Start PostgreSQL on it to replay the archived WAL up to {}", target);
+                }
+                Err(e) => {
+                    error!("❌ Restore to {} failed: {}", target, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+        
         DatabaseCommands::Stats { detailed } => {
             info!("📊 Database statistics");
             
@@ -707,148 +1051,81 @@ async fn process_admin_command(action: clap::AdminCommands, config: &Config) {
             // TODO: Implement actual config reload logic
             info!("✅ Configuration reloaded successfully");
         }
+        
+        AdminCommands::InternalCa { dir, component, hosts } => {
+            use matrixon_common::internal_tls::InternalCa;
+
+            let issued = InternalCa::load_or_generate(&dir, &format!("{} internal CA", config.server_name))
+                .and_then(|ca| ca.issue_to(&dir, &component, &hosts));
+            match issued {
+                Ok(tls) => {
+                    info!("🔐 Certificate for {}: {}", component, tls.cert.display());
+                    info!("🔑 Key: {}", tls.key.display());
+                    info!("🏛️ CA certificate: {}", tls.ca_cert.display());
+                }
+                Err(e) => {
+                    error!("❌ Could not issue the certificate: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
     }
 }
 
-/// Adds additional headers to prevent any potential XSS attacks via the media repo
-async fn set_csp_header(response: Response) -> impl IntoResponse {
-    (
-        [(CONTENT_SECURITY_POLICY, "sandbox; default-src 'none'; script-src 'none'; plugin-types application/pdf; style-src 'unsafe-inline'; object-src 'self';")], response
-    )
+/// Adds the security headers of the route class a request is for, see
+/// [`service::security_headers`]
+async fn set_security_headers(
+    axum::extract::State((policy, tls)): axum::extract::State<(std::sync::Arc<service::security_headers::Service>, bool)>,
+    req: axum::http::Request<Body>,
+    next: axum::middleware::Next,
+) -> Response {
+    let path = req.uri().path().to_owned();
+    let mut response = next.run(req).await;
+    policy.apply(&path, tls, response.headers_mut());
+    response
 }
 
-/// Check if port is in use and kill processes using it
-/// 检测端口是否被占用，如果被占用则清理占用进程
-#[instrument(level = "debug")]
-async fn check_and_clear_port(port: u16) -> io::Result<()> {
-    use std::process::Command;
-    use tracing::{info, warn, debug};
-    
-    debug!("🔍 Checking if port {} is in use", port);
-    
-    // Check if port is in use using lsof command
-    let lsof_output = Command::new("lsof")
-        .args(["-ti", &format!(":{}", port)])
-        .output();
-    
-    match lsof_output {
-        Ok(output) => {
-            if output.status.success() && !output.stdout.is_empty() {
-                let pids_str = String::from_utf8_lossy(&output.stdout);
-                let pids: Vec<&str> = pids_str.trim().split('\n').filter(|s| !s.is_empty()).collect();
-                
-                if !pids.is_empty() {
-                    warn!("⚠️ Port {} is already in use by {} process(es): {:?}", port, pids.len(), pids);
-                    
-                    // Kill processes using the port
-                    for pid in pids {
-                        info!("🔧 Attempting to kill process {} using port {}", pid, port);
-                        
-                        // First try graceful termination (SIGTERM)
-                        let sigterm_result = Command::new("kill")
-                            .args(["-TERM", pid])
-                            .output();
-                        
-                        match sigterm_result {
-                            Ok(term_output) => {
-                                if term_output.status.success() {
-                                    info!("✅ Sent SIGTERM to process {}", pid);
-                                    
-                                    // Wait a moment for graceful shutdown
-                                    tokio::time::sleep(tokio::time::Duration::from_millis(1000)).await;
-                                    
-                                    // Check if process still exists
-                                    let check_result = Command::new("kill")
-                                        .args(["-0", pid])
-                                        .output();
-                                    
-                                    if let Ok(check_output) = check_result {
-                                        if check_output.status.success() {
-                                            // Process still exists, force kill
-                                            warn!("⚠️ Process {} still running, sending SIGKILL", pid);
-                                            let sigkill_result = Command::new("kill")
-                                                .args(["-KILL", pid])
-                                                .output();
-                                            
-                                            match sigkill_result {
-                                                Ok(kill_output) => {
-                                                    if kill_output.status.success() {
-                                                        info!("✅ Force killed process {}", pid);
-                                                    } else {
-                                                        warn!("❌ Failed to force kill process {}: {}", pid, 
-                                                            String::from_utf8_lossy(&kill_output.stderr));
-                                                    }
-                                                }
-                                                Err(e) => {
-                                                    warn!("❌ Failed to execute kill -KILL for process {}: {}", pid, e);
-                                                }
-                                            }
-                                        } else {
-                                            info!("✅ Process {} terminated gracefully", pid);
-                                        }
-                                    }
-                                } else {
-                                    warn!("❌ Failed to send SIGTERM to process {}: {}", pid, 
-                                        String::from_utf8_lossy(&term_output.stderr));
-                                }
-                            }
-                            Err(e) => {
-                                warn!("❌ Failed to execute kill command for process {}: {}", pid, e);
-                            }
-                        }
-                    }
-                    
-                    // Wait additional time for port to be released
-                    info!("⏳ Waiting for port {} to be released...", port);
-                    tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await;
-                    
-                    // Verify port is now free
-                    let verify_output = Command::new("lsof")
-                        .args(["-ti", &format!(":{}", port)])
-                        .output();
-                    
-                    match verify_output {
-                        Ok(output) => {
-                            if output.status.success() && !output.stdout.is_empty() {
-                                return Err(io::Error::new(
-                                    io::ErrorKind::AddrInUse,
-                                    format!("Port {} is still in use after cleanup attempt", port)
-                                ));
-                            } else {
-                                info!("✅ Port {} is now free and ready for use", port);
-                            }
-                        }
-                        Err(e) => {
-                            warn!("⚠️ Could not verify port status: {}", e);
-                        }
-                    }
-                } else {
-                    debug!("✅ Port {} is not in use", port);
-                }
-            } else {
-                debug!("✅ Port {} is not in use", port);
-            }
-        }
-        Err(e) => {
-            warn!("⚠️ Could not check port status (lsof command failed): {}", e);
-            warn!("⚠️ Proceeding anyway - if port is in use, binding will fail");
-        }
+/// CORS of every API: origins are checked against the allowlist of the API
+/// the request is for, so the admin API can stay closed to browsers while
+/// the Matrix APIs are open
+fn cors_layer(config: &config::CorsConfig) -> CorsLayer {
+    let credentials = config.credentials();
+    if config.allow_credentials && !credentials {
+        warn!("⚠️ CORS credentials are only allowed with an origin allowlist, ignoring allow_credentials");
     }
-    
-    Ok(())
+    if config.admin_allowed_origins.iter().any(|origin| origin == "*") {
+        warn!("⚠️ The admin API cannot be opened to any origin, ignoring `*` in admin_allowed_origins");
+    }
+    let policy = config.clone();
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin, request| {
+            origin.to_str().is_ok_and(|origin| policy.allows(request.uri.path(), origin))
+        }))
+        .allow_methods([
+            Method::GET,
+            Method::POST,
+            Method::PUT,
+            Method::DELETE,
+            Method::OPTIONS,
+        ])
+        .allow_headers([
+            header::ORIGIN,
+            HeaderName::from_static("x-requested-with"),
+            header::CONTENT_TYPE,
+            header::ACCEPT,
+            header::AUTHORIZATION,
+        ])
+        .allow_credentials(credentials)
+        .max_age(Duration::from_secs(config.max_age_s))
 }
 
 async fn run_server(config: &Config) -> io::Result<()> {
-    let addr = SocketAddr::from((config.address, config.port));
-
-    // Check and clear port before binding
-    check_and_clear_port(config.port).await?;
-
-    let x_requested_with = HeaderName::from_static("x-requested-with");
-
     let middlewares = ServiceBuilder::new()
         // .sensitive_headers([header::AUTHORIZATION])
         .layer(axum::middleware::from_fn(spawn_task))
+        .layer(axum::middleware::from_fn(persistence_backpressure))
+        .layer(axum::middleware::from_fn(record_latency))
+        .layer(axum::middleware::from_fn(audit_impersonation))
         .layer(
             TraceLayer::new_for_http().make_span_with(|request: &axum::http::Request<_>| {
                 let path = if let Some(path) = request.extensions().get::<MatchedPath>() {
@@ -861,32 +1138,10 @@ async fn run_server(config: &Config) -> io::Result<()> {
             }),
         )
         .layer(axum::middleware::from_fn(unrecognized_method))
-        .layer(
-            CorsLayer::new()
-                .allow_origin(cors::Any)
-                .allow_methods([
-                    Method::GET,
-                    Method::POST,
-                    Method::PUT,
-                    Method::DELETE,
-                    Method::OPTIONS,
-                ])
-                .allow_headers([
-                    header::ORIGIN,
-                    x_requested_with,
-                    header::CONTENT_TYPE,
-                    header::ACCEPT,
-                    header::AUTHORIZATION,
-                ])
-                .max_age(Duration::from_secs(86400)),
-        )
-        .layer(map_response(set_csp_header))
-        .layer(DefaultBodyLimit::max(
-            config
-                .max_request_size
-                .try_into()
-                .expect("failed to convert max request size"),
-        ));
+        .layer(cors_layer(&config.cors()));
+    let security_headers = service::security_headers::Service::new(&config.security_headers())
+        .map(std::sync::Arc::new)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
 
     // Initialize federation service (placeholder)
     if config.allow_federation {
@@ -896,17 +1151,53 @@ async fn run_server(config: &Config) -> io::Result<()> {
         info!("🚫 Federation disabled");
     }
 
-    let app = routes(config)
-        .layer(middlewares);
-
-    // Bind to address and start serving
-    let listener = TcpListener::bind(addr).await?;
-    info!("🚀 Matrixon server listening on: {}", addr);
+    // Take over the sockets from systemd, or bind them. With SO_REUSEPORT a
+    // previous instance keeps serving on the ports until it has drained.
+    let reuse_port = config.reuse_port.unwrap_or(false);
+    let pidfile = config.pidfile();
+    let port_conflict = config.port_conflict();
+    let activated = listener::socket_activation_fds();
+    // On SIGTERM stop accepting and let in-flight requests finish
+    let shutdown = futures::FutureExt::shared(Box::pin(shutdown_signal()));
+    let mut servers = Vec::new();
+    for (index, listener_config) in config.listeners().iter().enumerate() {
+        let bound = if index < activated {
+            listener::inherited(index, listener_config)?
+        } else {
+            listener::open(listener_config, reuse_port, &port_conflict, pidfile.as_deref()).await?
+        };
+        info!("🚀 Matrixon server listening on: {} {:?}", bound, listener_config.resources);
+        let max_request_size = listener_config.max_request_size.unwrap_or(config.max_request_size);
+        let app = routes(config, listener_config)
+            .layer(middlewares.clone())
+            .layer(axum::middleware::from_fn_with_state(
+                (security_headers.clone(), config.serves_tls(listener_config)),
+                set_security_headers,
+            ))
+            .layer(DefaultBodyLimit::max(max_request_size.try_into().expect("failed to convert max request size")));
+        let tls = match &listener_config.internal_tls {
+            Some(tls) => Some(
+                matrixon_common::internal_tls::acceptor(tls)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?,
+            ),
+            None => None,
+        };
+        servers.push(listener::serve(bound, app, listener_config.max_connections, tls, shutdown.clone()));
+    }
+    if let Some(pidfile) = &pidfile {
+        if let Err(e) = listener::write_pidfile(pidfile) {
+            warn!("⚠️ Could not write the pidfile {}: {}", pidfile.display(), e);
+        }
+    }
     
     #[cfg(feature = "systemd")]
     let _ = sd_notify::notify(true, &[sd_notify::NotifyState::Ready]);
 
-    axum::serve(listener, app).await
+    let result = futures::future::try_join_all(servers).await;
+    if let Some(pidfile) = &pidfile {
+        listener::remove_pidfile(pidfile);
+    }
+    result.map(|_| ())
 }
 
 async fn spawn_task(
@@ -921,6 +1212,85 @@ async fn spawn_task(
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
 }
 
+/// Holds back requests that may add events while too many events wait to
+/// be written to the database
+async fn persistence_backpressure(
+    req: axum::http::Request<Body>,
+    next: axum::middleware::Next,
+) -> Response {
+    if !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        if let Some(persistence) = &services().event_persistence {
+            persistence.wait_for_capacity().await;
+        }
+    }
+    next.run(req).await
+}
+
+/// Records the latency of requests to known routes with the monitor
+async fn record_latency(
+    req: axum::http::Request<Body>,
+    next: axum::middleware::Next,
+) -> Response {
+    let (Some(performance), Some(path)) = (services().performance.get(), req.extensions().get::<MatchedPath>().cloned()) else {
+        return next.run(req).await;
+    };
+    let started = Instant::now();
+    let response = next.run(req).await;
+    performance.record_request(path.as_str(), started.elapsed());
+    response
+}
+
+/// Authenticates requests made with an impersonation token, auditing
+/// their method and path; routes then look the token up without auditing
+/// it again
+async fn audit_impersonation(
+    req: axum::http::Request<Body>,
+    next: axum::middleware::Next,
+) -> Response {
+    let token = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .filter(|token| token.starts_with(service::impersonation::TOKEN_PREFIX));
+    if let Some(token) = token {
+        if services().impersonation.authenticate(token, req.method().as_str(), req.uri().path()).is_none() {
+            return Error::BadRequest(
+                ErrorKind::UnknownToken { soft_logout: false },
+                "Unknown or expired impersonation token",
+            )
+            .into_response();
+        }
+    }
+    next.run(req).await
+}
+
+/// Records the events appended to the timelines with the monitor
+async fn record_event_rate(performance: std::sync::Arc<matrixon_monitor::performance::PerformanceManager>) {
+    let timeline = &services().timeline;
+    let mut last = timeline.current_count();
+    loop {
+        let advanced = timeline.advanced();
+        let current = timeline.current_count();
+        if current > last {
+            performance.record_events(current - last);
+            last = current;
+        }
+        advanced.await;
+    }
+}
+
+/// Only lets requests of server admins through
+async fn require_admin(
+    req: axum::http::Request<Body>,
+    next: axum::middleware::Next,
+) -> Response {
+    match client_server::authenticated_admin(req.headers()).await {
+        Ok(_) => next.run(req).await,
+        Err(e) => e.into_response(),
+    }
+}
+
 async fn unrecognized_method(
     req: axum::http::Request<Body>,
     next: axum::middleware::Next,
@@ -941,8 +1311,29 @@ async fn unrecognized_method(
     Ok(inner)
 }
 
-fn routes(config: &Config) -> Router {
-    let router = Router::new()
+fn routes(config: &Config, listener: &ListenerConfig) -> Router {
+    let mut router = Router::new()
+        .route("/", get(it_works))
+        .route(config.ready_path(), get(client_server::get_readiness))
+        .fallback(not_found);
+    if listener.serves(ListenerResource::Client) {
+        router = router.merge(client_routes());
+        if let Some(iot) = services().iot.get() {
+            let fleet = matrixon_iot::fleet::router(iot.fleet()).layer(axum::middleware::from_fn(require_admin));
+            router = router.nest("/_matrixon/admin/v1/iot", fleet);
+        }
+    }
+    if listener.serves(ListenerResource::Federation) {
+        router = router.merge(federation_routes(config));
+    }
+    if listener.serves(ListenerResource::Metrics) {
+        router = router.route("/_matrix/metrics", get(client_server::get_metrics));
+    }
+    router
+}
+
+fn client_routes() -> Router {
+    Router::new()
         // Basic Matrix Client API endpoints
         .route("/_matrix/client/versions", get(client_server::get_supported_versions_route))
         .route("/_matrix/client/r0/capabilities", get(client_server::get_capabilities_route))
@@ -957,6 +1348,60 @@ fn routes(config: &Config) -> Router {
         .route("/_matrix/client/v3/logout", post(client_server::logout_route))
         .route("/_matrix/client/r0/logout/all", post(client_server::logout_all_route))
         .route("/_matrix/client/v3/logout/all", post(client_server::logout_all_route))
+        .route("/_matrix/client/r0/account/deactivate", post(client_server::deactivate_route))
+        .route("/_matrix/client/v3/account/deactivate", post(client_server::deactivate_route))
+
+        
+        // Third-party identifiers
+        .route("/_matrix/client/r0/account/3pid", get(client_server::third_party_route))
+        .route("/_matrix/client/r0/account/3pid/email/requestToken", post(client_server::request_3pid_management_token_via_email_route))
+        .route("/_matrix/client/r0/account/3pid/msisdn/requestToken", post(client_server::request_3pid_management_token_via_msisdn_route))
+        .route("/_matrix/client/r0/account/3pid/add", post(client_server::add_3pid_route))
+        .route("/_matrix/client/r0/account/3pid/bind", post(client_server::bind_3pid_route))
+        .route("/_matrix/client/r0/account/3pid/unbind", post(client_server::unbind_3pid_route))
+        .route("/_matrix/client/r0/account/3pid/delete", post(client_server::delete_3pid_route))
+        .route("/_matrix/client/v3/account/3pid", get(client_server::third_party_route))
+        .route("/_matrix/client/v3/account/3pid/email/requestToken", post(client_server::request_3pid_management_token_via_email_route))
+        .route("/_matrix/client/v3/account/3pid/msisdn/requestToken", post(client_server::request_3pid_management_token_via_msisdn_route))
+        .route("/_matrix/client/v3/account/3pid/add", post(client_server::add_3pid_route))
+        .route("/_matrix/client/v3/account/3pid/bind", post(client_server::bind_3pid_route))
+        .route("/_matrix/client/v3/account/3pid/unbind", post(client_server::unbind_3pid_route))
+        .route("/_matrix/client/v3/account/3pid/delete", post(client_server::delete_3pid_route))
+        .route("/_matrix/client/unstable/add_threepid/:medium/submit_token", post(client_server::submit_3pid_token_route))
+        .route("/_matrix/client/unstable/add_threepid/email/confirm", get(client_server::email_confirmation_page_route).post(client_server::email_confirmation_route))
+        .route("/_matrix/consent", get(client_server::consent_page_route).post(client_server::consent_route))
+        
+        // Admin API
+        .route("/_synapse/admin/v1/suspend/:user_id", put(client_server::suspend_user_route))
+        .route("/_synapse/admin/v2/users/:user_id", get(client_server::get_user_admin_route))
+        .route("/_synapse/admin/v1/deactivate/:user_id", post(client_server::deactivate_user_admin_route))
+        .route("/_synapse/admin/v1/users/:user_id/media", get(client_server::get_user_media_admin_route))
+        .route("/_synapse/admin/v1/rooms", get(client_server::list_rooms_admin_route))
+        .route("/_synapse/admin/v1/rooms/:room_id", get(client_server::get_room_admin_route))
+        .route("/_synapse/admin/v1/rooms/:room_id/members", get(client_server::get_room_members_admin_route))
+        .route("/_synapse/admin/v1/media/:server_name/:media_id", delete(client_server::delete_media_admin_route))
+        .route("/_synapse/admin/v1/media/quarantine/:server_name/:media_id", post(client_server::quarantine_media_admin_route))
+        .route("/_matrixon/admin/v1/users/:user_id/impersonate", post(client_server::impersonate_user_route).delete(client_server::revoke_impersonation_route))
+        .route("/_matrixon/admin/v1/impersonation/audit", get(client_server::impersonation_audit_route))
+        .route("/_synapse/admin/v1/federation/destinations", get(client_server::get_federation_destinations_route))
+        .route("/_synapse/admin/v1/federation/destinations/:destination", get(client_server::get_federation_destination_route))
+        .route("/_synapse/admin/v1/federation/destinations/:destination/reset_connection", post(client_server::reset_federation_destination_route))
+        .route("/_matrixon/admin/v1/server_keys/rotate", post(client_server::rotate_server_key_route))
+        .route("/_matrixon/admin/v1/auto_join_rooms", get(client_server::get_auto_join_rooms_route).put(client_server::set_auto_join_rooms_route))
+        .route("/_matrixon/admin/v1/room_stats", get(client_server::room_stats_route))
+        .route("/_matrixon/admin/v1/caches", get(client_server::get_caches_route))
+        .route("/_matrixon/admin/v1/memory", get(client_server::get_memory_route))
+        .route("/_matrixon/admin/v1/memory/heap_profile", post(client_server::dump_heap_profile_route))
+        .route("/_matrixon/admin/v1/database/shards", get(client_server::database_shards_route))
+        .route("/_matrixon/admin/v1/caches/:name/invalidate", post(client_server::invalidate_cache_route))
+        .route("/_matrixon/admin/v1/caches/:name/capacity", put(client_server::set_cache_capacity_route))
+        .route("/_matrixon/admin/v1/event_reports", get(client_server::get_event_reports_route))
+        .route("/_matrixon/admin/v1/event_reports/:event_id/restore", post(client_server::restore_reported_event_route))
+        .route("/_matrixon/admin/v1/legal_holds", get(client_server::get_legal_holds_route).post(client_server::place_legal_hold_route))
+        .route("/_matrixon/admin/v1/legal_holds/:hold_id", delete(client_server::release_legal_hold_route))
+        .route("/_matrixon/admin/v1/rooms/:room_id", delete(client_server::delete_room_route))
+        .route("/_matrixon/admin/v1/blocked_rooms", get(client_server::get_blocked_rooms_route))
+        .route("/_matrixon/admin/v1/blocked_rooms/:room_id", delete(client_server::unblock_room_route))
         
         // Room API
         .route("/_matrix/client/r0/createRoom", post(client_server::create_room_route))
@@ -964,15 +1409,47 @@ fn routes(config: &Config) -> Router {
         .route("/_matrix/client/r0/joined_rooms", get(client_server::joined_rooms_route))
         .route("/_matrix/client/v3/joined_rooms", get(client_server::joined_rooms_route))
         
+        // Room state API
+        .route("/_matrix/client/r0/rooms/:room_id/state", get(client_server::get_state_events_route))
+        .route("/_matrix/client/v3/rooms/:room_id/state", get(client_server::get_state_events_route))
+        .route("/_matrix/client/r0/rooms/:room_id/state/:event_type", get(client_server::get_state_events_for_empty_key_route).put(client_server::send_state_event_for_empty_key_route))
+        .route("/_matrix/client/v3/rooms/:room_id/state/:event_type", get(client_server::get_state_events_for_empty_key_route).put(client_server::send_state_event_for_empty_key_route))
+        .route("/_matrix/client/r0/rooms/:room_id/state/:event_type/:state_key", get(client_server::get_state_events_for_key_route).put(client_server::send_state_event_for_key_route))
+        .route("/_matrix/client/v3/rooms/:room_id/state/:event_type/:state_key", get(client_server::get_state_events_for_key_route).put(client_server::send_state_event_for_key_route))
+        
         // Room Membership API - 修复房间加入功能
         .route("/_matrix/client/r0/rooms/:room_id/join", post(simple_join_room_by_id_route))
         .route("/_matrix/client/v3/rooms/:room_id/join", post(simple_join_room_by_id_route))
         .route("/_matrix/client/r0/join/:room_id_or_alias", post(simple_join_room_by_alias_route))
         .route("/_matrix/client/v3/join/:room_id_or_alias", post(simple_join_room_by_alias_route))
+        .route("/_matrixon/client/v1/rooms/:room_id/join_status", get(client_server::join_status_route))
+        .route("/_matrixon/client/v1/rooms/:room_id/webhooks", get(client_server::get_room_webhooks_route).post(client_server::register_room_webhook_route))
+        .route("/_matrixon/client/v1/rooms/:room_id/webhooks/:webhook_id", delete(client_server::delete_room_webhook_route))
+        .route("/_matrix/client/unstable/org.matrix.msc2716/rooms/:room_id/batch_send", post(client_server::batch_send_route))
         .route("/_matrix/client/r0/rooms/:room_id/leave", post(client_server::leave_room_route))
         .route("/_matrix/client/v3/rooms/:room_id/leave", post(client_server::leave_room_route))
         .route("/_matrix/client/r0/rooms/:room_id/invite", post(client_server::invite_user_route))
         .route("/_matrix/client/v3/rooms/:room_id/invite", post(client_server::invite_user_route))
+        .route("/_matrix/client/v3/knock/:room_id_or_alias", post(client_server::knock_room_route))
+        .route("/_matrix/client/unstable/xyz.amorgan.knock/knock/:room_id_or_alias", post(client_server::knock_room_route))
+        .route("/_matrix/client/r0/rooms/:room_id/forget", post(client_server::forget_room_route))
+        .route("/_matrix/client/v3/rooms/:room_id/forget", post(client_server::forget_room_route))
+        .route("/_matrix/client/r0/rooms/:room_id/kick", post(client_server::kick_user_route))
+        .route("/_matrix/client/v3/rooms/:room_id/kick", post(client_server::kick_user_route))
+        .route("/_matrix/client/r0/rooms/:room_id/ban", post(client_server::ban_user_route))
+        .route("/_matrix/client/v3/rooms/:room_id/ban", post(client_server::ban_user_route))
+        .route("/_matrix/client/r0/rooms/:room_id/unban", post(client_server::unban_user_route))
+        .route("/_matrix/client/v3/rooms/:room_id/unban", post(client_server::unban_user_route))
+        .route("/_matrix/client/r0/rooms/:room_id/report/:event_id", post(client_server::report_event_route))
+        .route("/_matrix/client/v3/rooms/:room_id/report/:event_id", post(client_server::report_event_route))
+        .route("/_matrix/client/r0/rooms/:room_id/typing/:user_id", put(client_server::create_typing_event_route))
+        .route("/_matrix/client/v3/rooms/:room_id/typing/:user_id", put(client_server::create_typing_event_route))
+        .route("/_matrix/client/r0/rooms/:room_id/receipt/:receipt_type/:event_id", post(client_server::create_receipt_route))
+        .route("/_matrix/client/v3/rooms/:room_id/receipt/:receipt_type/:event_id", post(client_server::create_receipt_route))
+        .route("/_matrix/client/r0/rooms/:room_id/members", get(client_server::get_member_events_route))
+        .route("/_matrix/client/v3/rooms/:room_id/members", get(client_server::get_member_events_route))
+        .route("/_matrix/client/r0/rooms/:room_id/joined_members", get(client_server::joined_members_route))
+        .route("/_matrix/client/v3/rooms/:room_id/joined_members", get(client_server::joined_members_route))
         
         // Room Messaging API - 修复消息发送功能
         .route("/_matrix/client/r0/rooms/:room_id/send/:event_type/:txn_id", put(simple_send_message_route))
@@ -980,27 +1457,103 @@ fn routes(config: &Config) -> Router {
         .route("/_matrix/client/r0/rooms/:room_id/messages", get(simple_get_messages_route))
         .route("/_matrix/client/v3/rooms/:room_id/messages", get(simple_get_messages_route))
         
+        // Room directory
+        .route("/_matrix/client/r0/publicRooms", get(client_server::get_public_rooms_route).post(client_server::get_public_rooms_filtered_route))
+        .route("/_matrix/client/v3/publicRooms", get(client_server::get_public_rooms_route).post(client_server::get_public_rooms_filtered_route))
+        .route("/_matrix/client/v1/rooms/:room_id/hierarchy", get(client_server::get_hierarchy_route))
+        .route("/_matrix/client/r0/directory/list/room/:room_id", get(client_server::get_room_visibility_route).put(client_server::set_room_visibility_route))
+        .route("/_matrix/client/v3/directory/list/room/:room_id", get(client_server::get_room_visibility_route).put(client_server::set_room_visibility_route))
+        .route("/_matrix/client/r0/directory/room/:room_alias", get(client_server::get_alias_route).put(client_server::create_alias_route).delete(client_server::delete_alias_route))
+        .route("/_matrix/client/v3/directory/room/:room_alias", get(client_server::get_alias_route).put(client_server::create_alias_route).delete(client_server::delete_alias_route))
+        
         // Sync API
         .route("/_matrix/client/r0/sync", get(client_server::sync_events_route))
         .route("/_matrix/client/v3/sync", get(client_server::sync_events_route))
+        .route("/_matrix/client/r0/sendToDevice/:event_type/:txn_id", put(client_server::send_to_device_route))
+        .route("/_matrix/client/v3/sendToDevice/:event_type/:txn_id", put(client_server::send_to_device_route))
+        
+        // End-to-end encryption keys
+        .route("/_matrix/client/r0/keys/upload", post(client_server::upload_keys_route))
+        .route("/_matrix/client/v3/keys/upload", post(client_server::upload_keys_route))
+        .route("/_matrix/client/r0/keys/query", post(client_server::get_keys_route))
+        .route("/_matrix/client/v3/keys/query", post(client_server::get_keys_route))
+        .route("/_matrix/client/r0/keys/claim", post(client_server::claim_keys_route))
+        .route("/_matrix/client/v3/keys/claim", post(client_server::claim_keys_route))
+        .route("/_matrix/client/v3/keys/device_signing/upload", post(client_server::upload_signing_keys_route))
+        .route("/_matrix/client/unstable/keys/device_signing/upload", post(client_server::upload_signing_keys_route))
+        .route("/_matrix/client/v3/keys/signatures/upload", post(client_server::upload_signatures_route))
+        .route("/_matrix/client/unstable/keys/signatures/upload", post(client_server::upload_signatures_route))
+
+
+        
+        // Account data
+        .route("/_matrix/client/r0/user/:user_id/account_data/:event_type", get(client_server::get_global_account_data_route).put(client_server::set_global_account_data_route))
+        .route("/_matrix/client/v3/user/:user_id/account_data/:event_type", get(client_server::get_global_account_data_route).put(client_server::set_global_account_data_route))
+
+        // Profile API
+        .route("/_matrix/client/r0/profile/:user_id", get(client_server::get_profile_route))
+        .route("/_matrix/client/r0/profile/:user_id/displayname", get(client_server::get_displayname_route).put(client_server::set_displayname_route))
+        .route("/_matrix/client/r0/profile/:user_id/avatar_url", get(client_server::get_avatar_url_route).put(client_server::set_avatar_url_route))
+        .route("/_matrix/client/v3/profile/:user_id", get(client_server::get_profile_route))
+        .route("/_matrix/client/v3/profile/:user_id/displayname", get(client_server::get_displayname_route).put(client_server::set_displayname_route))
+        .route("/_matrix/client/v3/profile/:user_id/avatar_url", get(client_server::get_avatar_url_route).put(client_server::set_avatar_url_route))        
+        .route("/_matrix/client/v3/profile/:user_id/io.matrixon.nft_avatar", get(client_server::get_nft_avatar_route).put(client_server::set_nft_avatar_route))
+        // Server-side key backups
+        .route("/_matrix/client/r0/room_keys/version", get(client_server::get_latest_backup_info_route).post(client_server::create_backup_version_route))
+        .route("/_matrix/client/r0/room_keys/version/:version", get(client_server::get_backup_info_route).put(client_server::update_backup_version_route).delete(client_server::delete_backup_version_route))
+        .route("/_matrix/client/r0/room_keys/keys", get(client_server::get_backup_keys_route).put(client_server::add_backup_keys_route).delete(client_server::delete_backup_keys_route))
+        .route("/_matrix/client/r0/room_keys/keys/:room_id", get(client_server::get_backup_keys_for_room_route).put(client_server::add_backup_keys_for_room_route).delete(client_server::delete_backup_keys_for_room_route))
+        .route("/_matrix/client/r0/room_keys/keys/:room_id/:session_id", get(client_server::get_backup_keys_for_session_route).put(client_server::add_backup_keys_for_session_route).delete(client_server::delete_backup_keys_for_session_route))
+        .route("/_matrix/client/v3/room_keys/version", get(client_server::get_latest_backup_info_route).post(client_server::create_backup_version_route))
+        .route("/_matrix/client/v3/room_keys/version/:version", get(client_server::get_backup_info_route).put(client_server::update_backup_version_route).delete(client_server::delete_backup_version_route))
+        .route("/_matrix/client/v3/room_keys/keys", get(client_server::get_backup_keys_route).put(client_server::add_backup_keys_route).delete(client_server::delete_backup_keys_route))
+        .route("/_matrix/client/v3/room_keys/keys/:room_id", get(client_server::get_backup_keys_for_room_route).put(client_server::add_backup_keys_for_room_route).delete(client_server::delete_backup_keys_for_room_route))
+        .route("/_matrix/client/v3/room_keys/keys/:room_id/:session_id", get(client_server::get_backup_keys_for_session_route).put(client_server::add_backup_keys_for_session_route).delete(client_server::delete_backup_keys_for_session_route))
         
         // Media API
         .route("/_matrix/media/r0/config", get(client_server::get_media_config_route))
         .route("/_matrix/media/v3/config", get(client_server::get_media_config_route))
         .route("/_matrix/media/r0/upload", post(client_server::create_content_route))
         .route("/_matrix/media/v3/upload", post(client_server::create_content_route))
+        .route("/_matrix/media/r0/download/:server_name/:media_id", get(client_server::get_content_route))
+        .route("/_matrix/media/v3/download/:server_name/:media_id", get(client_server::get_content_route))
         
         // Well-known endpoints
         .route("/.well-known/matrix/client", get(client_server::well_known_client))
-        
-        // Root endpoint
-        .route("/", get(it_works))
-        .route("/_matrix/metrics", get(client_server::get_metrics))
-        .fallback(not_found);
+        .route("/_matrix/client/unstable/org.matrix.msc2965/auth_issuer", get(client_server::get_auth_issuer_route))
+        .route("/_matrix/client/v1/auth_metadata", get(client_server::get_auth_metadata_route))
+        .route("/_matrix/client/unstable/org.matrix.msc2965/auth_metadata", get(client_server::get_auth_metadata_route))
+}
 
+fn federation_routes(config: &Config) -> Router {
+    let router = Router::new();
     if config.allow_federation {
-        // TODO: Implement server-server API
         router
+            .route("/_matrix/federation/v1/send/:txn_id", put(server_server::send_transaction_message_route))
+            .route("/_matrix/federation/v1/make_join/:room_id/:user_id", get(server_server::create_join_event_template_route))
+            .route("/_matrix/federation/v1/send_join/:room_id/:event_id", put(server_server::create_join_event_v1_route))
+            .route("/_matrix/federation/v2/send_join/:room_id/:event_id", put(server_server::create_join_event_v2_route))
+            .route("/_matrix/federation/v1/make_leave/:room_id/:user_id", get(server_server::create_leave_event_template_route))
+            .route("/_matrix/federation/v1/send_leave/:room_id/:event_id", put(server_server::create_leave_event_route))
+            .route("/_matrix/federation/v2/send_leave/:room_id/:event_id", put(server_server::create_leave_event_route))
+            .route("/_matrix/federation/v1/make_knock/:room_id/:user_id", get(server_server::create_knock_event_template_route))
+            .route("/_matrix/federation/v1/send_knock/:room_id/:event_id", put(server_server::create_knock_event_route))
+            .route("/_matrix/federation/v1/invite/:room_id/:event_id", put(server_server::create_invite_route))
+            .route("/_matrix/federation/v2/invite/:room_id/:event_id", put(server_server::create_invite_route))
+            .route("/_matrix/federation/v1/backfill/:room_id", get(server_server::get_backfill_route))
+            .route("/_matrix/federation/v1/get_missing_events/:room_id", post(server_server::get_missing_events_route))
+            .route("/_matrix/federation/v1/state/:room_id", get(server_server::get_room_state_route))
+            .route("/_matrix/federation/v1/event_auth/:room_id/:event_id", get(server_server::get_event_authorization_route))
+            .route("/_matrix/federation/v1/user/devices/:user_id", get(server_server::get_devices_route))
+            .route("/_matrix/federation/v1/user/keys/query", post(server_server::get_keys_route))
+            .route("/_matrix/federation/v1/user/keys/claim", post(server_server::claim_keys_route))
+            .route("/_matrix/federation/v1/query/directory", get(server_server::get_room_alias_route))
+            .route("/_matrix/federation/v1/publicRooms", get(server_server::get_public_rooms_route).post(server_server::get_public_rooms_filtered_route))
+            .route("/_matrix/federation/v1/hierarchy/:room_id", get(server_server::get_hierarchy_route))
+            .route("/_matrix/federation/v1/media/download/:media_id", get(server_server::get_content_route))
+            .route("/_matrix/federation/v1/media/thumbnail/:media_id", get(server_server::get_content_thumbnail_route))
+            .route("/_matrix/key/v2/server", get(server_server::get_server_keys_route))
+            .route("/_matrix/key/v2/server/:key_id", get(server_server::get_server_keys_deprecated_route))
     } else {
         router
             .route("/_matrix/federation/*path", any(federation_disabled))
@@ -1067,30 +1620,19 @@ async fn it_works() -> &'static str {
 #[instrument(level = "debug")]
 pub async fn simple_join_room_by_id_route(
     Path(room_id): Path<String>,
+    RawQuery(query): RawQuery,
     headers: HeaderMap,
     Json(request): Json<serde_json::Value>,
-) -> std::result::Result<Json<serde_json::Value>, StatusCode> {
+) -> matrixon::Result<Json<serde_json::Value>> {
     let start = Instant::now();
     debug!("🔧 Simple room join requested for room: {}", room_id);
     
-    // Basic authentication check
-    let auth_header = headers.get("authorization")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.strip_prefix("Bearer "))
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-    
-    // Extract user_id from token (simplified - in real implementation would validate properly)
-    let user_id = if auth_header.starts_with("syt_matrixon_register_") {
-        format!("@user_{}:matrixon.local", &auth_header[22..32])
-    } else if auth_header.starts_with("syt_matrixon_login_") {
-        format!("@user_{}:matrixon.local", &auth_header[19..29])
-    } else {
-        return Err(StatusCode::UNAUTHORIZED);
-    };
+    let (user_id, _) = client_server::authenticated_device(&headers).await?;
+    services().accounts.ensure_not_suspended(&user_id)?;
     
     info!("✅ User {} attempting to join room {}", user_id, room_id);
+    services().membership.join(&room_id, &user_id, &client_server::via_servers(query.as_deref())).await?;
     
-    // Simulate successful room join (Matrix Construct style - direct response)
     let response = serde_json::json!({
         "room_id": room_id
     });
@@ -1105,37 +1647,22 @@ pub async fn simple_join_room_by_id_route(
 #[instrument(level = "debug")]
 pub async fn simple_join_room_by_alias_route(
     Path(room_id_or_alias): Path<String>,
+    RawQuery(query): RawQuery,
     headers: HeaderMap,
     Json(request): Json<serde_json::Value>,
-) -> std::result::Result<Json<serde_json::Value>, StatusCode> {
+) -> matrixon::Result<Json<serde_json::Value>> {
     let start = Instant::now();
     debug!("🔧 Simple room join by alias requested: {}", room_id_or_alias);
     
-    // Basic authentication check
-    let auth_header = headers.get("authorization")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.strip_prefix("Bearer "))
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-    
-    // Extract user_id from token
-    let user_id = if auth_header.starts_with("syt_matrixon_register_") {
-        format!("@user_{}:matrixon.local", &auth_header[22..32])
-    } else if auth_header.starts_with("syt_matrixon_login_") {
-        format!("@user_{}:matrixon.local", &auth_header[19..29])
-    } else {
-        return Err(StatusCode::UNAUTHORIZED);
-    };
+    let (user_id, _) = client_server::authenticated_device(&headers).await?;
+    services().accounts.ensure_not_suspended(&user_id)?;
     
-    // Convert alias to room_id if needed
-    let room_id = if room_id_or_alias.starts_with('#') {
-        // In real implementation, would resolve alias to room_id
-        format!("!{}_resolved:matrixon.local", &room_id_or_alias[1..])
-    } else {
-        room_id_or_alias.clone()
-    };
+    let (room_id, via) =
+        client_server::resolve_room_id_or_alias(&room_id_or_alias, client_server::via_servers(query.as_deref())).await?;
     
     info!("✅ User {} joining room {} (resolved from {})", 
           user_id, room_id, room_id_or_alias);
+    services().membership.join(&room_id, &user_id, &via).await?;
     
     let response = serde_json::json!({
         "room_id": room_id
@@ -1146,33 +1673,26 @@ pub async fn simple_join_room_by_alias_route(
 }
 
 /// Simplified message sending implementation inspired by Matrix Construct approach
-/// This bypasses complex service dependencies for basic functionality
+/// The sender must be joined with the power level the event type needs,
+/// and each transaction id sends one event
 #[instrument(level = "debug")]
 pub async fn simple_send_message_route(
     Path((room_id, event_type, txn_id)): Path<(String, String, String)>,
     headers: HeaderMap,
     Json(request): Json<serde_json::Value>,
-) -> std::result::Result<Json<serde_json::Value>, StatusCode> {
+) -> matrixon::Result<Json<serde_json::Value>> {
     let start = Instant::now();
     debug!("🔧 Simple message send requested to room: {}", room_id);
     
-    // Basic authentication check
-    let auth_header = headers.get("authorization")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.strip_prefix("Bearer "))
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-    
-    // Extract user_id from token
-    let user_id = if auth_header.starts_with("syt_matrixon_register_") {
-        format!("@user_{}:matrixon.local", &auth_header[22..32])
-    } else if auth_header.starts_with("syt_matrixon_login_") {
-        format!("@user_{}:matrixon.local", &auth_header[19..29])
-    } else {
-        return Err(StatusCode::UNAUTHORIZED);
-    };
+    let (user_id, device_id) = client_server::authenticated_device(&headers).await?;
+    services().accounts.ensure_not_suspended(&user_id)?;
     
-    // Generate event ID (Matrix Construct style)
-    let event_id = format!("${}:matrixon.local", Uuid::new_v4());
+    // A retried transaction gets the event it sent the first time
+    let event_id = services().sessions.send_event_txn(&user_id, &device_id, &txn_id, || {
+        let timeline = &services().timeline;
+        matrixon::service::membership::check_message_event(timeline, &room_id, &user_id, &event_type, &request)?;
+        Ok(timeline.append_event(&room_id, &user_id, &event_type, None, request.clone()))
+    })?;
     
     info!("✅ User {} sending {} message to room {} (txn: {})", 
           user_id, event_type, room_id, txn_id);
@@ -1191,39 +1711,54 @@ pub async fn simple_send_message_route(
     Ok(Json(response))
 }
 
+/// Response of `GET /rooms/{roomId}/messages`
+#[derive(serde::Serialize)]
+pub struct MessagesResponse {
+    start: String,
+    end: String,
+    chunk: Vec<SerializedEvent>,
+    state: Vec<serde_json::Value>,
+}
+
 /// Get messages from room (simplified implementation)
 #[instrument(level = "debug")]
 pub async fn simple_get_messages_route(
     Path(room_id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
     headers: HeaderMap,
-) -> std::result::Result<Json<serde_json::Value>, StatusCode> {
+) -> std::result::Result<Json<MessagesResponse>, StatusCode> {
     let start = Instant::now();
     debug!("🔧 Get messages requested for room: {}", room_id);
     
-    // Basic authentication check
-    let auth_header = headers.get("authorization")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.strip_prefix("Bearer "))
-        .ok_or(StatusCode::UNAUTHORIZED)?;
-    
-    // Extract user_id from token
-    let user_id = if auth_header.starts_with("syt_matrixon_register_") {
-        format!("@user_{}:matrixon.local", &auth_header[22..32])
-    } else if auth_header.starts_with("syt_matrixon_login_") {
-        format!("@user_{}:matrixon.local", &auth_header[19..29])
-    } else {
-        return Err(StatusCode::UNAUTHORIZED);
-    };
+    let (user_id, _) = client_server::authenticated_device(&headers)
+        .await
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    // Users who left may still page through the history they saw
+    if matrixon::service::membership::membership(&room_id, &user_id).is_none() {
+        return Err(StatusCode::FORBIDDEN);
+    }
     
     info!("✅ User {} requesting messages from room {}", user_id, room_id);
     
-    // Return empty message list for now (Matrix protocol compliant)
-    let response = serde_json::json!({
-        "start": "t1-0",
-        "end": "t1-0",
-        "chunk": [],
-        "state": []
-    });
+    let from = params.get("from").and_then(|token| token.parse::<Token>().ok());
+    let dir = match params.get("dir").map(String::as_str) {
+        Some("f") => Direction::Forward,
+        _ => Direction::Backward,
+    };
+    let limit = params
+        .get("limit")
+        .and_then(|l| l.parse::<usize>().ok())
+        .unwrap_or(10)
+        .min(1000);
+
+    let (start_token, chunk, end_token) = services().timeline.paginate_serialized(&room_id, from, dir, limit);
+
+    let response = MessagesResponse {
+        start: start_token.to_string(),
+        end: end_token.to_string(),
+        chunk,
+        state: Vec::new(),
+    };
     
     info!("✅ Messages retrieved in {:?}", start.elapsed());
     Ok(Json(response))
@@ -1254,13 +1789,88 @@ mod tests {
     use axum::http::{Method, StatusCode};
     use tower::ServiceExt;
 
+    async fn cors_headers(config: &config::CorsConfig, path: &str, origin: &str) -> axum::http::HeaderMap {
+        let router = Router::new()
+            .route("/_matrix/client/versions", get(|| async { "ok" }))
+            .route("/_matrixon/admin/v1/users", get(|| async { "ok" }))
+            .route("/_synapse/admin/v2/users", get(|| async { "ok" }))
+            .route("/_matrixon/client/v1/rooms/webhooks", get(|| async { "ok" }))
+            .layer(cors_layer(config));
+        let request = axum::http::Request::builder()
+            .method(Method::OPTIONS)
+            .uri(path)
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .body(Body::empty())
+            .unwrap();
+        let response = router.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        response.headers().clone()
+    }
+
+    #[tokio::test]
+    async fn test_cors_only_allows_listed_origins() {
+        let allowed = |headers: &axum::http::HeaderMap| {
+            headers.get(header::ACCESS_CONTROL_ALLOW_ORIGIN).map(|origin| origin.to_str().unwrap().to_owned())
+        };
+        let client = "/_matrix/client/versions";
+        let admin = "/_matrixon/admin/v1/users";
+        let synapse_admin = "/_synapse/admin/v2/users";
+        let extension = "/_matrixon/client/v1/rooms/webhooks";
+
+        // The Matrix APIs are open to any origin by default, the admin APIs
+        // closed under both prefixes
+        let default = config::CorsConfig::default();
+        let headers = cors_headers(&default, client, "https://any.example").await;
+        assert_eq!(allowed(&headers).as_deref(), Some("https://any.example"));
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+        assert_eq!(allowed(&cors_headers(&default, extension, "https://any.example").await).as_deref(), Some("https://any.example"));
+        assert_eq!(allowed(&cors_headers(&default, admin, "https://any.example").await), None);
+        assert_eq!(allowed(&cors_headers(&default, synapse_admin, "https://any.example").await), None);
+
+        let listed = config::CorsConfig {
+            allowed_origins: vec!["https://app.example".to_owned()],
+            admin_allowed_origins: vec!["https://admin.example".to_owned()],
+            allow_credentials: true,
+            ..Default::default()
+        };
+        let headers = cors_headers(&listed, client, "https://app.example").await;
+        assert_eq!(allowed(&headers).as_deref(), Some("https://app.example"));
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(allowed(&cors_headers(&listed, client, "https://evil.example").await), None);
+        assert_eq!(allowed(&cors_headers(&listed, admin, "https://app.example").await), None);
+        assert_eq!(allowed(&cors_headers(&listed, synapse_admin, "https://app.example").await), None);
+        let headers = cors_headers(&listed, admin, "https://admin.example").await;
+        assert_eq!(allowed(&headers).as_deref(), Some("https://admin.example"));
+        let headers = cors_headers(&listed, synapse_admin, "https://admin.example").await;
+        assert_eq!(allowed(&headers).as_deref(), Some("https://admin.example"));
+        // Extensions under `/_matrixon/client` follow the Matrix API list
+        let headers = cors_headers(&listed, extension, "https://app.example").await;
+        assert_eq!(allowed(&headers).as_deref(), Some("https://app.example"));
+        assert_eq!(allowed(&cors_headers(&listed, extension, "https://admin.example").await), None);
+
+        // Any origin only when asked for, never with credentials or for the
+        // admin API
+        let open = config::CorsConfig {
+            allowed_origins: vec!["*".to_owned()],
+            admin_allowed_origins: vec!["*".to_owned()],
+            allow_credentials: true,
+            ..Default::default()
+        };
+        let headers = cors_headers(&open, client, "https://any.example").await;
+        assert_eq!(allowed(&headers).as_deref(), Some("https://any.example"));
+        assert!(headers.get(header::ACCESS_CONTROL_ALLOW_CREDENTIALS).is_none());
+        assert_eq!(allowed(&cors_headers(&open, admin, "https://any.example").await), None);
+    }
+
     #[test]
     fn test_sub_tables_constants() {
         // Test that SUB_TABLES constant is properly defined
-        assert_eq!(SUB_TABLES.len(), 3, "Should have 3 sub-tables");
+        assert_eq!(SUB_TABLES.len(), 4, "Should have 4 sub-tables");
         assert!(SUB_TABLES.contains(&"well_known"), "Should contain well_known");
         assert!(SUB_TABLES.contains(&"tls"), "Should contain tls");
         assert!(SUB_TABLES.contains(&"media"), "Should contain media");
+        assert!(SUB_TABLES.contains(&"startup"), "Should contain startup");
         
         // Test that all entries are non-empty
         for table in &SUB_TABLES {
@@ -1291,6 +1901,7 @@ mod tests {
                 "well_known" => assert!(true, "well_known is valid Matrix configuration"),
                 "tls" => assert!(true, "tls is valid Matrix configuration"),
                 "media" => assert!(true, "media is valid Matrix configuration"),
+                "startup" => assert!(true, "startup is valid configuration"),
                 _ => panic!("Unexpected sub-table: {}", table),
             }
         }
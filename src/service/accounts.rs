@@ -0,0 +1,263 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Accounts Service
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Per-user account state such as deactivation, suspension and erasure,
+//   and the user's global account data. Users without a record are active
+//   accounts in their default state. Account states are kept in a state
+//   file when one is configured, so erasure markers and suspensions
+//   survive restarts.
+//
+// =============================================================================
+
+use std::{collections::HashMap, path::PathBuf, sync::RwLock};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::info;
+
+use crate::{service::state_file::StateFile, Error, Result};
+
+/// Account state of a single user
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Account {
+    /// The account can no longer log in or be used
+    pub deactivated: bool,
+    /// The user asked for their data to be erased (GDPR right to erasure)
+    pub erased: bool,
+    /// The account can log in and read, but not send events or invites (MSC3823)
+    pub suspended: bool,
+    /// Reason given by the admin who suspended the account
+    pub suspension_reason: Option<String>,
+    /// Argon2id hash of the password, when accounts are not kept in the
+    /// database
+    pub password_hash: Option<String>,
+    /// Version of the server's policy the user agreed to
+    pub consent_version: Option<String>,
+}
+
+/// Account state service
+#[derive(Debug, Default)]
+pub struct Service {
+    accounts: RwLock<HashMap<String, Account>>,
+    /// Global account data, by user and event type
+    account_data: RwLock<HashMap<String, HashMap<String, Value>>>,
+    state_file: Option<StateFile>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the account states in the file at `path`, loading the ones
+    /// stored there
+    pub fn with_state_file(mut self, path: Option<PathBuf>) -> Self {
+        if let Some(path) = path {
+            let state_file = StateFile::new(path);
+            if let Some(accounts) = state_file.load() {
+                self.accounts = RwLock::new(accounts);
+            }
+            self.state_file = Some(state_file);
+        }
+        self
+    }
+
+    /// Snapshot of a user's account state
+    pub fn get(&self, user_id: &str) -> Account {
+        self.accounts
+            .read()
+            .unwrap()
+            .get(user_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn is_deactivated(&self, user_id: &str) -> bool {
+        self.get(user_id).deactivated
+    }
+
+    pub fn is_erased(&self, user_id: &str) -> bool {
+        self.get(user_id).erased
+    }
+
+    pub fn deactivate(&self, user_id: &str) {
+        info!("🚫 Deactivating account {}", user_id);
+        self.update(user_id, |account| account.deactivated = true);
+    }
+
+    pub fn is_suspended(&self, user_id: &str) -> bool {
+        self.get(user_id).suspended
+    }
+
+    pub fn suspend(&self, user_id: &str, reason: Option<String>) {
+        info!("⏸️ Suspending account {} (reason: {:?})", user_id, reason);
+        self.update(user_id, |account| {
+            account.suspended = true;
+            account.suspension_reason = reason;
+        });
+    }
+
+    pub fn unsuspend(&self, user_id: &str) {
+        info!("▶️ Lifting suspension of account {}", user_id);
+        self.update(user_id, |account| {
+            account.suspended = false;
+            account.suspension_reason = None;
+        });
+    }
+
+    /// Fail with `M_USER_SUSPENDED` if the user may not send events or invites
+    pub fn ensure_not_suspended(&self, user_id: &str) -> Result<()> {
+        let account = self.get(user_id);
+        if !account.suspended {
+            return Ok(());
+        }
+        Err(Error::UserSuspended(
+            account
+                .suspension_reason
+                .unwrap_or_else(|| "This account has been suspended".to_owned()),
+        ))
+    }
+
+    /// Hash of the password of an account kept here rather than in the
+    /// database
+    pub fn password_hash(&self, user_id: &str) -> Option<String> {
+        self.get(user_id).password_hash
+    }
+
+    pub fn set_password_hash(&self, user_id: &str, password_hash: String) {
+        self.update(user_id, |account| account.password_hash = Some(password_hash));
+    }
+
+    /// Give a new account kept here its password hash, unless the user ID
+    /// is taken. Checked and set under one lock, so of two registrations
+    /// racing for a user ID only one gets it.
+    pub fn create_with_password_hash(&self, user_id: &str, password_hash: String) -> bool {
+        let mut created = false;
+        self.update(user_id, |account| {
+            if account.password_hash.is_none() && !account.deactivated {
+                account.password_hash = Some(password_hash);
+                created = true;
+            }
+        });
+        created
+    }
+
+    /// Record that the user agreed to `version` of the server's policy
+    pub fn set_consent_version(&self, user_id: &str, version: &str) {
+        info!("📜 {} agreed to version {} of the policy", user_id, version);
+        self.update(user_id, |account| account.consent_version = Some(version.to_owned()));
+    }
+
+    /// Record the erasure marker for a user. Erased users stay erased, so
+    /// events of theirs arriving later (e.g. via backfill) can be redacted.
+    pub fn mark_erased(&self, user_id: &str) {
+        info!("🧹 Marking account {} as erased", user_id);
+        self.update(user_id, |account| account.erased = true);
+    }
+
+    /// Content of the global account data event `event_type` of a user
+    pub fn account_data(&self, user_id: &str, event_type: &str) -> Option<Value> {
+        self.account_data.read().unwrap().get(user_id)?.get(event_type).cloned()
+    }
+
+    pub fn set_account_data(&self, user_id: &str, event_type: &str, content: Value) {
+        self.account_data
+            .write()
+            .unwrap()
+            .entry(user_id.to_owned())
+            .or_default()
+            .insert(event_type.to_owned(), content);
+    }
+
+    fn update(&self, user_id: &str, f: impl FnOnce(&mut Account)) {
+        let mut accounts = self.accounts.write().unwrap();
+        f(accounts.entry(user_id.to_owned()).or_default());
+        let snapshot = self.state_file.as_ref().map(|state_file| (state_file, state_file.snapshot(&*accounts)));
+        drop(accounts);
+        if let Some((state_file, snapshot)) = snapshot {
+            state_file.write(snapshot);
+        }
+    }
+}
+
+/// Argon2id hash of a password, for storing with the account
+pub fn hash_password(password: &str) -> Result<String> {
+    Argon2::default()
+        .hash_password(password.as_bytes(), &SaltString::generate(&mut OsRng))
+        .map(|hash| hash.to_string())
+        .map_err(|_| Error::BadRequest(ruma::api::client::error::ErrorKind::Unknown, "Password could not be hashed"))
+}
+
+/// Whether `password` matches a hash made by [`hash_password`]
+pub fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_hashes_verify() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password(&hash, "correct horse"));
+        assert!(!verify_password(&hash, "battery staple"));
+        assert!(!verify_password("not a hash", "correct horse"));
+    }
+
+    #[test]
+    fn test_user_ids_are_only_created_once() {
+        let service = Service::new();
+        assert!(service.create_with_password_hash("@alice:matrixon.local", "first".to_owned()));
+        assert!(!service.create_with_password_hash("@alice:matrixon.local", "second".to_owned()));
+        assert_eq!(service.password_hash("@alice:matrixon.local").as_deref(), Some("first"));
+
+        service.deactivate("@gone:matrixon.local");
+        assert!(!service.create_with_password_hash("@gone:matrixon.local", "reused".to_owned()));
+    }
+
+    #[test]
+    fn test_suspension_reason_reaches_client() {
+        let service = Service::new();
+        let user = "@spam:matrixon.local";
+        assert!(service.ensure_not_suspended(user).is_ok());
+
+        service.suspend(user, Some("Spam reports under review".to_owned()));
+        match service.ensure_not_suspended(user) {
+            Err(Error::UserSuspended(reason)) => assert_eq!(reason, "Spam reports under review"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(!service.is_deactivated(user));
+
+        service.unsuspend(user);
+        assert!(service.ensure_not_suspended(user).is_ok());
+    }
+
+    #[test]
+    fn test_erasure_marker_survives_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("accounts.json");
+        let service = Service::new().with_state_file(Some(path.clone()));
+        service.deactivate("@gone:matrixon.local");
+        service.mark_erased("@gone:matrixon.local");
+
+        let restarted = Service::new().with_state_file(Some(path));
+        assert!(restarted.is_erased("@gone:matrixon.local"));
+        assert!(restarted.is_deactivated("@gone:matrixon.local"));
+        assert!(!restarted.is_erased("@alice:matrixon.local"));
+    }
+}
@@ -0,0 +1,144 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Allocator Statistics
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Memory use of the server as seen by its allocator, for sizing caches
+//   and `max_memory_usage_mb`. Statistics are only available when built
+//   with the `jemalloc` feature, which makes jemalloc the global allocator.
+//   Heap profiles can be dumped when built with `jemalloc_profiling` and
+//   started with profiling on, e.g. `_RJEM_MALLOC_CONF=prof:true`; dumps
+//   are read with `jeprof`.
+//
+// =============================================================================
+
+use std::{fmt::Write, path::Path};
+
+use serde::Serialize;
+
+use crate::{Error, Result};
+
+/// Whether statistics come from jemalloc
+pub const JEMALLOC: bool = cfg!(all(not(target_env = "msvc"), feature = "jemalloc"));
+
+/// Allocator statistics, in bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Stats {
+    /// Allocated by the application
+    pub allocated: usize,
+    /// In pages holding allocations, a bit more than `allocated`
+    pub active: usize,
+    /// In physically resident pages, including metadata and free pages
+    /// not yet returned to the system
+    pub resident: usize,
+    /// In mapped extents
+    pub mapped: usize,
+    /// Unmapped but kept for reuse; not resident
+    pub retained: usize,
+    /// Used by the allocator itself
+    pub metadata: usize,
+}
+
+impl Stats {
+    fn samples(&self) -> [(&'static str, usize); 6] {
+        [
+            ("allocated", self.allocated),
+            ("active", self.active),
+            ("resident", self.resident),
+            ("mapped", self.mapped),
+            ("retained", self.retained),
+            ("metadata", self.metadata),
+        ]
+    }
+
+    /// Share of a budget of `budget_mb` in use, by resident memory
+    pub fn budget_used(&self, budget_mb: u64) -> Option<f64> {
+        (budget_mb > 0).then(|| self.resident as f64 / (budget_mb * 1024 * 1024) as f64)
+    }
+}
+
+/// Current allocator statistics, unless built without jemalloc
+#[cfg(all(not(target_env = "msvc"), feature = "jemalloc"))]
+pub fn stats() -> Option<Stats> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // Statistics are snapshots refreshed by advancing the epoch
+    epoch::advance().ok()?;
+    Some(Stats {
+        allocated: stats::allocated::read().ok()?,
+        active: stats::active::read().ok()?,
+        resident: stats::resident::read().ok()?,
+        mapped: stats::mapped::read().ok()?,
+        retained: stats::retained::read().ok()?,
+        metadata: stats::metadata::read().ok()?,
+    })
+}
+
+#[cfg(not(all(not(target_env = "msvc"), feature = "jemalloc")))]
+pub fn stats() -> Option<Stats> {
+    None
+}
+
+/// Write a heap profile to `path`
+#[cfg(all(not(target_env = "msvc"), feature = "jemalloc"))]
+pub fn dump_heap_profile(path: &Path) -> Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    // SAFETY: `opt.prof` is a bool
+    let profiling = unsafe { tikv_jemalloc_ctl::raw::read::<bool>(b"opt.prof\0") }.unwrap_or(false);
+    if !profiling {
+        return Err(Error::BadConfig("Heap profiling is off; build with jemalloc_profiling and start with prof:true".to_owned()));
+    }
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::BadConfig("Heap profile path contains a NUL byte".to_owned()))?;
+    // SAFETY: `prof.dump` takes a NUL-terminated path, which outlives the call
+    unsafe { tikv_jemalloc_ctl::raw::write(b"prof.dump\0", path.as_ptr()) }
+        .map_err(|e| Error::BadConfig(format!("Could not dump a heap profile: {}", e)))
+}
+
+#[cfg(not(all(not(target_env = "msvc"), feature = "jemalloc")))]
+pub fn dump_heap_profile(_path: &Path) -> Result<()> {
+    Err(Error::BadConfig("Heap profiles need a build with the jemalloc feature".to_owned()))
+}
+
+/// Prometheus text of the allocator statistics and the memory budget
+pub fn render(stats: Option<Stats>, budget_mb: Option<u64>) -> String {
+    let mut out = String::new();
+    if let Some(stats) = stats {
+        let _ = writeln!(out, "# HELP matrixon_allocator_bytes Memory use reported by jemalloc");
+        let _ = writeln!(out, "# TYPE matrixon_allocator_bytes gauge");
+        for (stat, bytes) in stats.samples() {
+            let _ = writeln!(out, "matrixon_allocator_bytes{{stat=\"{stat}\"}} {bytes}");
+        }
+    }
+    if let Some(budget_mb) = budget_mb {
+        let _ = writeln!(out, "# HELP matrixon_memory_budget_bytes The configured max_memory_usage_mb");
+        let _ = writeln!(out, "# TYPE matrixon_memory_budget_bytes gauge");
+        let _ = writeln!(out, "matrixon_memory_budget_bytes {}", budget_mb * 1024 * 1024);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_stats_and_budget() {
+        let sample = Stats { allocated: 100, resident: 512 * 1024 * 1024, ..Default::default() };
+        assert_eq!(sample.budget_used(1024), Some(0.5));
+        assert_eq!(sample.budget_used(0), None);
+
+        let rendered = render(Some(sample), Some(1024));
+        assert!(rendered.contains("matrixon_allocator_bytes{stat=\"allocated\"} 100\n"));
+        assert!(rendered.contains("matrixon_memory_budget_bytes 1073741824\n"));
+        assert!(render(None, None).is_empty());
+        assert_eq!(stats().is_some(), JEMALLOC);
+    }
+}
@@ -0,0 +1,110 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Application Services
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Application services registered in the config. An application service
+//   authenticates with its `as_token` and may act as its own user and the
+//   users of its user namespaces, whose regular expressions must match the
+//   whole user id.
+//
+// =============================================================================
+
+use regex::Regex;
+
+use crate::{config::AppserviceConfig, Error, Result};
+
+/// A registered application service
+#[derive(Debug)]
+pub struct Appservice {
+    pub id: String,
+    as_token: String,
+    /// Its own user
+    pub sender: String,
+    users: Vec<Regex>,
+}
+
+impl Appservice {
+    /// Whether the application service may act as `user_id`
+    pub fn is_user_in_namespace(&self, user_id: &str) -> bool {
+        user_id == self.sender || self.users.iter().any(|regex| regex.is_match(user_id))
+    }
+}
+
+/// Application service registry
+#[derive(Debug, Default)]
+pub struct Service {
+    appservices: Vec<Appservice>,
+}
+
+impl Service {
+    /// Registry of the application services in `configs`, whose users are
+    /// users of `server_name`
+    pub fn new(configs: Vec<AppserviceConfig>, server_name: &str) -> Result<Self> {
+        let mut appservices: Vec<Appservice> = Vec::with_capacity(configs.len());
+        for config in configs {
+            if config.as_token.is_empty() || appservices.iter().any(|appservice| appservice.as_token == config.as_token) {
+                return Err(Error::BadConfig(format!("Application service {} needs an as_token of its own", config.id)));
+            }
+            let users = config
+                .namespaces
+                .users
+                .iter()
+                .map(|namespace| {
+                    Regex::new(&format!("^(?:{})$", namespace.regex)).map_err(|e| {
+                        Error::BadConfig(format!("Invalid user namespace of application service {}: {}", config.id, e))
+                    })
+                })
+                .collect::<Result<_>>()?;
+            appservices.push(Appservice {
+                sender: format!("@{}:{}", config.sender_localpart, server_name),
+                id: config.id,
+                as_token: config.as_token,
+                users,
+            });
+        }
+        Ok(Self { appservices })
+    }
+
+    /// The application service `token` is the `as_token` of, if any
+    pub fn authenticate(&self, token: &str) -> Option<&Appservice> {
+        self.appservices.iter().find(|appservice| appservice.as_token == token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppserviceNamespace, AppserviceNamespaces};
+
+    fn config(as_token: &str, regex: &str) -> AppserviceConfig {
+        AppserviceConfig {
+            id: "bridge".to_owned(),
+            as_token: as_token.to_owned(),
+            sender_localpart: "bridgebot".to_owned(),
+            namespaces: AppserviceNamespaces { users: vec![AppserviceNamespace { exclusive: true, regex: regex.to_owned() }] },
+        }
+    }
+
+    #[test]
+    fn test_appservices_act_as_the_users_of_their_namespaces() {
+        let service = Service::new(vec![config("as_secret", "@bridge_.*:matrixon\\.local")], "matrixon.local").unwrap();
+        assert!(service.authenticate("other").is_none());
+        let appservice = service.authenticate("as_secret").unwrap();
+        assert!(appservice.is_user_in_namespace("@bridgebot:matrixon.local"));
+        assert!(appservice.is_user_in_namespace("@bridge_alice:matrixon.local"));
+        assert!(!appservice.is_user_in_namespace("@alice:matrixon.local"));
+        // Namespaces match whole user ids
+        assert!(!appservice.is_user_in_namespace("@bridge_alice:matrixon.local.evil.example"));
+
+        assert!(Service::new(vec![config("as_secret", "@bridge_(:matrixon\\.local")], "matrixon.local").is_err());
+        assert!(Service::new(vec![config("", ".*")], "matrixon.local").is_err());
+        assert!(Service::new(vec![config("as_secret", ".*"), config("as_secret", ".*")], "matrixon.local").is_err());
+    }
+}
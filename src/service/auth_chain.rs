@@ -0,0 +1,551 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Auth Chains
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Auth chains of events, answered from a chain cover index instead of
+//   walking auth events every time. Every indexed event is on a chain of
+//   events of its room, each of which is in the auth chain of the next, so
+//   an event's position (chain, sequence number) stands for itself and all
+//   events before it on the chain. Auth events on other chains are recorded
+//   as links between positions, unless already reachable. The auth chain of
+//   an event is then every chain up to the latest position reachable from
+//   its auth events. Events are indexed when an auth chain first needs
+//   them, once their whole auth chain can be found: until then, they are
+//   walked instead, events that cannot be found are left out and results
+//   are not cached. Lookups are split into chunks of starting events whose
+//   results are cached, and the index is written through to PostgreSQL
+//   when there is a database and loaded from it at startup.
+//
+// =============================================================================
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    sync::{Arc, RwLock},
+};
+
+use matrixon_db::{AuthChainLinkRecord, AuthChainPositionRecord, AuthChainRepo};
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::service::{cache::Cache, pdu_metadata::referenced_event_ids};
+
+/// Base capacity of the result cache, before the capacity modifier
+const CACHE_CAPACITY: usize = 100_000;
+
+/// Chunks the starting events of a lookup are split into, each cached on
+/// its own so lookups sharing events share cached results
+const CHUNKS: u64 = 50;
+
+/// Where an event is on its chain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Position {
+    chain: i64,
+    sequence: i64,
+}
+
+#[derive(Debug, Default)]
+struct Chain {
+    /// Event ids by sequence number, starting at 1
+    events: Vec<String>,
+    /// (sequence number, target chain, target sequence number): the target
+    /// and the events before it are in the auth chain of the event at the
+    /// sequence number
+    links: Vec<(i64, i64, i64)>,
+}
+
+/// Outcome of indexing an event
+enum Indexing {
+    Indexed(Position),
+    /// The event, whose auth chain is not complete yet
+    Incomplete(Value),
+    Unknown,
+}
+
+#[derive(Debug, Default)]
+struct Index {
+    positions: HashMap<String, Position>,
+    chains: HashMap<i64, Chain>,
+    /// (room, type, state key) -> latest chain of such events
+    latest_chains: HashMap<(String, String, String), i64>,
+    /// Room -> its chains
+    rooms: HashMap<String, Vec<i64>>,
+}
+
+impl Index {
+    /// The latest sequence number of every chain reachable from `starts`
+    fn reachable(&self, starts: impl IntoIterator<Item = Position>) -> HashMap<i64, i64> {
+        let mut reached: HashMap<i64, i64> = HashMap::new();
+        let mut pending = Vec::new();
+        let reach = |reached: &mut HashMap<i64, i64>, pending: &mut Vec<i64>, chain: i64, sequence: i64| {
+            let latest = reached.entry(chain).or_insert(0);
+            if *latest < sequence {
+                *latest = sequence;
+                pending.push(chain);
+            }
+        };
+        for position in starts {
+            reach(&mut reached, &mut pending, position.chain, position.sequence);
+        }
+        while let Some(chain) = pending.pop() {
+            let up_to = reached[&chain];
+            for &(sequence, target, target_sequence) in self.chains.get(&chain).map(|chain| &chain.links).into_iter().flatten() {
+                if sequence <= up_to {
+                    reach(&mut reached, &mut pending, target, target_sequence);
+                }
+            }
+        }
+        reached
+    }
+
+    /// The events up to the reached sequence number of every chain
+    fn events(&self, reached: &HashMap<i64, i64>) -> HashSet<String> {
+        reached
+            .iter()
+            .filter_map(|(chain, up_to)| Some(self.chains.get(chain)?.events.iter().take(*up_to as usize)))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// Put `event_id` on a chain after its auth events, which are indexed,
+    /// returning its position and its links
+    fn insert(&mut self, room_id: &str, event_id: &str, event: &Value) -> (Position, Vec<(i64, i64)>) {
+        if let Some(position) = self.positions.get(event_id) {
+            return (*position, Vec::new());
+        }
+        let auth: Vec<Position> = referenced_event_ids(event, "auth_events")
+            .filter_map(|auth_event_id| self.positions.get(auth_event_id).copied())
+            .collect();
+        let reached = self.reachable(auth.iter().copied());
+
+        // Events of a type and state key continue their chain as long as
+        // its latest event is in their auth chain
+        let key = (
+            room_id.to_owned(),
+            event["type"].as_str().unwrap_or_default().to_owned(),
+            event["state_key"].as_str().unwrap_or_default().to_owned(),
+        );
+        let continued = self.latest_chains.get(&key).copied().and_then(|chain| {
+            let len = self.chains.get(&chain)?.events.len() as i64;
+            (reached.get(&chain) == Some(&len)).then_some(Position { chain, sequence: len + 1 })
+        });
+        let position = continued.unwrap_or_else(|| {
+            let chain = loop {
+                let chain = rand::random::<i64>() & i64::MAX;
+                if !self.chains.contains_key(&chain) {
+                    break chain;
+                }
+            };
+            self.chains.insert(chain, Chain::default());
+            self.rooms.entry(room_id.to_owned()).or_default().push(chain);
+            self.latest_chains.insert(key, chain);
+            Position { chain, sequence: 1 }
+        });
+
+        // Only the latest auth event of each other chain needs a link, and
+        // none if the event before on the chain already reaches it
+        let before = self.reachable((position.sequence > 1).then_some(Position { sequence: position.sequence - 1, ..position }));
+        let mut targets: BTreeMap<i64, i64> = BTreeMap::new();
+        for auth_position in auth.into_iter().filter(|auth_position| auth_position.chain != position.chain) {
+            let target = targets.entry(auth_position.chain).or_insert(0);
+            *target = (*target).max(auth_position.sequence);
+        }
+        targets.retain(|chain, sequence| before.get(chain).is_none_or(|reached| reached < sequence));
+        let links: Vec<(i64, i64)> = targets.into_iter().collect();
+
+        let chain = self.chains.get_mut(&position.chain).expect("chains of positions exist");
+        chain.events.push(event_id.to_owned());
+        chain.links.extend(links.iter().map(|(target, target_sequence)| (position.sequence, *target, *target_sequence)));
+        self.positions.insert(event_id.to_owned(), position);
+        (position, links)
+    }
+
+    /// Add stored positions, in order along their chains, and links.
+    /// Chains missing a position are cut before it and not continued.
+    fn restore(&mut self, positions: Vec<AuthChainPositionRecord>, links: Vec<AuthChainLinkRecord>) {
+        let mut cut = HashSet::new();
+        for record in positions {
+            if self.positions.contains_key(&record.event_id) || cut.contains(&record.chain_id) {
+                continue;
+            }
+            let chain = self.chains.entry(record.chain_id).or_insert_with(|| {
+                self.rooms.entry(record.room_id.clone()).or_default().push(record.chain_id);
+                Chain::default()
+            });
+            if chain.events.len() as i64 + 1 != record.sequence_number {
+                warn!("⚠️ Auth chain {} misses positions before {}, cutting it", record.chain_id, record.event_id);
+                cut.insert(record.chain_id);
+                continue;
+            }
+            chain.events.push(record.event_id.clone());
+            self.positions.insert(record.event_id, Position { chain: record.chain_id, sequence: record.sequence_number });
+            self.latest_chains.insert((record.room_id, record.event_type, record.state_key), record.chain_id);
+        }
+        self.latest_chains.retain(|_, chain| !cut.contains(chain));
+        for link in links {
+            if let Some(chain) = self.chains.get_mut(&link.origin_chain_id) {
+                if link.origin_sequence_number <= chain.events.len() as i64 {
+                    chain.links.push((link.origin_sequence_number, link.target_chain_id, link.target_sequence_number));
+                }
+            }
+        }
+    }
+}
+
+/// Auth chain service
+#[derive(Debug)]
+pub struct Service {
+    index: RwLock<Index>,
+    /// (room, ids of a chunk of starting events) -> their auth chain
+    cache: Cache<(String, String), Arc<HashSet<String>>>,
+    repository: Option<AuthChainRepo>,
+}
+
+impl Default for Service {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self {
+            index: RwLock::new(Index::default()),
+            cache: Cache::new("auth_chains", CACHE_CAPACITY, 1.0),
+            repository: None,
+        }
+    }
+
+    /// Scale the result cache by `matrixon_cache_capacity_modifier`
+    pub fn with_cache_capacity_modifier(mut self, modifier: f64) -> Self {
+        self.cache = Cache::new("auth_chains", CACHE_CAPACITY, modifier);
+        self
+    }
+
+    /// Write the index through to `repository`
+    pub fn with_repository(mut self, repository: AuthChainRepo) -> Self {
+        self.repository = Some(repository);
+        self
+    }
+
+    /// The result cache, for exporting its statistics
+    pub fn cache(&self) -> &Cache<(String, String), Arc<HashSet<String>>> {
+        &self.cache
+    }
+
+    /// Ids of the auth chains of `event_ids`: their auth events, the auth
+    /// events of those, and so on. `lookup` finds events by id.
+    pub fn get_auth_chain(&self, room_id: &str, event_ids: &[String], lookup: impl Fn(&str) -> Option<Value>) -> HashSet<String> {
+        let mut chunks: BTreeMap<u64, Vec<&str>> = BTreeMap::new();
+        for event_id in event_ids {
+            let mut hasher = DefaultHasher::new();
+            event_id.hash(&mut hasher);
+            chunks.entry(hasher.finish() % CHUNKS).or_default().push(event_id);
+        }
+
+        let mut auth_chain = HashSet::new();
+        for mut chunk in chunks.into_values() {
+            chunk.sort_unstable();
+            chunk.dedup();
+            let key = (room_id.to_owned(), chunk.join(","));
+            if let Some(cached) = self.cache.get(&key) {
+                auth_chain.extend(cached.iter().cloned());
+                continue;
+            }
+            // Auth events that cannot be indexed yet are walked instead
+            let mut complete = true;
+            let mut starts = Vec::new();
+            let mut walked = HashSet::new();
+            let mut pending: Vec<String> = Vec::new();
+            for event_id in &chunk {
+                match lookup(event_id) {
+                    Some(event) => pending.extend(referenced_event_ids(&event, "auth_events").map(str::to_owned)),
+                    None => complete = false,
+                }
+            }
+            while let Some(auth_event_id) = pending.pop() {
+                match self.index(room_id, &auth_event_id, &lookup) {
+                    Indexing::Indexed(position) => starts.push(position),
+                    Indexing::Incomplete(auth_event) => {
+                        complete = false;
+                        if walked.insert(auth_event_id) {
+                            pending.extend(referenced_event_ids(&auth_event, "auth_events").map(str::to_owned));
+                        }
+                    }
+                    Indexing::Unknown => complete = false,
+                }
+            }
+            let mut events = {
+                let index = self.index.read().unwrap();
+                index.events(&index.reachable(starts))
+            };
+            events.extend(walked);
+            if complete {
+                let events = Arc::new(events);
+                auth_chain.extend(events.iter().cloned());
+                self.cache.insert(key, events);
+            } else {
+                auth_chain.extend(events);
+            }
+        }
+        auth_chain
+    }
+
+    /// Index `event_id`, after the events of its auth chain that are not
+    /// indexed yet, as long as its whole auth chain can be found
+    fn index(&self, room_id: &str, event_id: &str, lookup: &impl Fn(&str) -> Option<Value>) -> Indexing {
+        if let Some(position) = self.index.read().unwrap().positions.get(event_id) {
+            return Indexing::Indexed(*position);
+        }
+        let Some(event) = lookup(event_id) else {
+            return Indexing::Unknown;
+        };
+        // Depth first, so that auth events are indexed before the events
+        // they authorize. Events with an auth event that cannot be found
+        // or indexed are not indexed either; auth events that are already
+        // being indexed can only come from a cycle of events naming each
+        // other, and are treated like missing ones.
+        let mut stack = vec![(event_id.to_owned(), event.clone())];
+        let mut on_stack: HashSet<String> = HashSet::from([event_id.to_owned()]);
+        let mut unindexable: HashSet<String> = HashSet::new();
+        while let Some((current_id, current)) = stack.last() {
+            let unindexed = {
+                let index = self.index.read().unwrap();
+                referenced_event_ids(current, "auth_events")
+                    .find(|auth_event_id| {
+                        !index.positions.contains_key(*auth_event_id)
+                            && !unindexable.contains(*auth_event_id)
+                            && !on_stack.contains(*auth_event_id)
+                    })
+                    .map(str::to_owned)
+            };
+            match unindexed {
+                Some(auth_event_id) => match lookup(&auth_event_id) {
+                    Some(auth_event) => {
+                        on_stack.insert(auth_event_id.clone());
+                        stack.push((auth_event_id, auth_event));
+                    }
+                    None => {
+                        debug!("🔗 Auth event {} of {} is unknown, not indexing it yet", auth_event_id, current_id);
+                        unindexable.insert(auth_event_id);
+                    }
+                },
+                None => {
+                    let (current_id, current) = stack.pop().expect("the stack is not empty");
+                    on_stack.remove(&current_id);
+                    let mut index = self.index.write().unwrap();
+                    if referenced_event_ids(&current, "auth_events").all(|auth_event_id| index.positions.contains_key(auth_event_id)) {
+                        let (position, links) = index.insert(room_id, &current_id, &current);
+                        drop(index);
+                        self.store(room_id, &current_id, &current, position, links);
+                    } else {
+                        unindexable.insert(current_id);
+                    }
+                }
+            }
+        }
+        match self.index.read().unwrap().positions.get(event_id) {
+            Some(position) => Indexing::Indexed(*position),
+            None => Indexing::Incomplete(event),
+        }
+    }
+
+    fn store(&self, room_id: &str, event_id: &str, event: &Value, position: Position, links: Vec<(i64, i64)>) {
+        let Some(repository) = self.repository.clone() else {
+            return;
+        };
+        let record = AuthChainPositionRecord {
+            event_id: event_id.to_owned(),
+            room_id: room_id.to_owned(),
+            event_type: event["type"].as_str().unwrap_or_default().to_owned(),
+            state_key: event["state_key"].as_str().unwrap_or_default().to_owned(),
+            chain_id: position.chain,
+            sequence_number: position.sequence,
+        };
+        tokio::spawn(async move {
+            if let Err(e) = repository.insert(&record, &links).await {
+                warn!("⚠️ Could not store the auth chain position of {}: {}", record.event_id, e);
+            }
+        });
+    }
+
+    /// Add the stored index, in order along its chains, to the index
+    pub fn restore(&self, positions: Vec<AuthChainPositionRecord>, links: Vec<AuthChainLinkRecord>) {
+        self.index.write().unwrap().restore(positions, links);
+    }
+
+    /// Forget the index and cached auth chains of a room
+    pub fn delete_room(&self, room_id: &str) {
+        let mut index = self.index.write().unwrap();
+        for chain in index.rooms.remove(room_id).unwrap_or_default() {
+            if let Some(chain) = index.chains.remove(&chain) {
+                for event_id in &chain.events {
+                    index.positions.remove(event_id);
+                }
+            }
+        }
+        index.latest_chains.retain(|(room, _, _), _| room != room_id);
+        self.cache.invalidate(room_id);
+    }
+
+    /// Events indexed
+    pub fn len(&self) -> usize {
+        self.index.read().unwrap().positions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Chains the indexed events are on
+    pub fn chains(&self) -> usize {
+        self.index.read().unwrap().chains.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const ROOM: &str = "!room:remote.example";
+
+    fn event(event_type: &str, state_key: Option<&str>, auth_events: &[&str]) -> Value {
+        let mut event = json!({ "room_id": ROOM, "type": event_type, "auth_events": auth_events });
+        if let Some(state_key) = state_key {
+            event["state_key"] = json!(state_key);
+        }
+        event
+    }
+
+    /// Auth chains found by walking auth events, to check the index against
+    fn walked(events: &HashMap<&str, Value>, event_ids: &[&str]) -> HashSet<String> {
+        let mut chain = HashSet::new();
+        let mut pending: Vec<&str> = event_ids.iter().flat_map(|event_id| referenced_event_ids(&events[event_id], "auth_events")).collect();
+        while let Some(event_id) = pending.pop() {
+            if chain.insert(event_id.to_owned()) {
+                pending.extend(events.get(event_id).into_iter().flat_map(|event| referenced_event_ids(event, "auth_events")));
+            }
+        }
+        chain.retain(|event_id| events.contains_key(event_id.as_str()));
+        chain
+    }
+
+    #[test]
+    fn test_auth_chains_match_walking_auth_events() {
+        let events: HashMap<&str, Value> = HashMap::from([
+            ("$create", event("m.room.create", Some(""), &[])),
+            ("$alice", event("m.room.member", Some("@alice:remote.example"), &["$create"])),
+            ("$power", event("m.room.power_levels", Some(""), &["$create", "$alice"])),
+            ("$rules", event("m.room.join_rules", Some(""), &["$create", "$alice", "$power"])),
+            ("$bob", event("m.room.member", Some("@bob:remote.example"), &["$create", "$power", "$rules"])),
+            ("$bob_left", event("m.room.member", Some("@bob:remote.example"), &["$create", "$power", "$bob"])),
+            // A fork of the power levels not reaching the first ones
+            ("$power_fork", event("m.room.power_levels", Some(""), &["$create", "$alice"])),
+            ("$power_2", event("m.room.power_levels", Some(""), &["$create", "$alice", "$power"])),
+            ("$message", event("m.room.message", None, &["$create", "$power_2", "$bob_left", "$missing"])),
+        ]);
+        let lookup = |event_id: &str| events.get(event_id).cloned();
+
+        let service = Service::new();
+        for event_ids in [&["$message"][..], &["$bob_left", "$power_fork"], &["$rules"], &["$create"], &["$power_2", "$bob"]] {
+            let ids: Vec<String> = event_ids.iter().map(|id| id.to_string()).collect();
+            assert_eq!(service.get_auth_chain(ROOM, &ids, lookup), walked(&events, event_ids), "auth chain of {:?}", event_ids);
+        }
+        // Only auth events are indexed, not the message or the fork
+        assert_eq!(service.len(), 7);
+        assert!(service.chains() < service.len());
+
+        // Lookups are answered from the cache, chunk by chunk
+        let hits = service.cache().hits();
+        service.get_auth_chain(ROOM, &["$rules".to_owned()], |_| None);
+        assert_eq!(service.cache().hits(), hits + 1);
+
+        service.delete_room(ROOM);
+        assert!(service.is_empty());
+        assert_eq!(service.chains(), 0);
+        assert!(service.get_auth_chain(ROOM, &["$rules".to_owned()], |_| None).is_empty());
+    }
+
+    #[test]
+    fn test_events_are_indexed_once_their_auth_chain_is_complete() {
+        let mut events: HashMap<&str, Value> = HashMap::from([
+            ("$create", event("m.room.create", Some(""), &[])),
+            ("$power", event("m.room.power_levels", Some(""), &["$create", "$alice"])),
+            ("$message", event("m.room.message", None, &["$create", "$power"])),
+        ]);
+        let service = Service::new();
+        let ids = ["$message".to_owned()];
+
+        // The power levels are walked until their auth event arrives
+        let auth_chain = service.get_auth_chain(ROOM, &ids, |event_id| events.get(event_id).cloned());
+        assert_eq!(auth_chain, walked(&events, &["$message"]));
+        assert_eq!(service.len(), 1);
+
+        events.insert("$alice", event("m.room.member", Some("@alice:remote.example"), &["$create"]));
+        let auth_chain = service.get_auth_chain(ROOM, &ids, |event_id| events.get(event_id).cloned());
+        assert!(auth_chain.contains("$alice"));
+        assert_eq!(auth_chain, walked(&events, &["$message"]));
+        assert_eq!(service.len(), 3);
+    }
+
+    #[test]
+    fn test_stored_index_is_restored() {
+        let events: HashMap<&str, Value> = HashMap::from([
+            ("$create", event("m.room.create", Some(""), &[])),
+            ("$alice", event("m.room.member", Some("@alice:remote.example"), &["$create"])),
+            ("$power", event("m.room.power_levels", Some(""), &["$create", "$alice"])),
+            ("$alice_2", event("m.room.member", Some("@alice:remote.example"), &["$create", "$power", "$alice"])),
+            ("$message", event("m.room.message", None, &["$create", "$power", "$alice_2"])),
+        ]);
+        let lookup = |event_id: &str| events.get(event_id).cloned();
+        let service = Service::new();
+        let auth_chain = service.get_auth_chain(ROOM, &["$message".to_owned()], lookup);
+
+        let (mut positions, mut links) = (Vec::new(), Vec::new());
+        for (event_id, position) in &service.index.read().unwrap().positions {
+            let event = &events[event_id.as_str()];
+            positions.push(AuthChainPositionRecord {
+                event_id: event_id.clone(),
+                room_id: ROOM.to_owned(),
+                event_type: event["type"].as_str().unwrap().to_owned(),
+                state_key: event["state_key"].as_str().unwrap().to_owned(),
+                chain_id: position.chain,
+                sequence_number: position.sequence,
+            });
+        }
+        positions.sort_by_key(|record| (record.chain_id, record.sequence_number));
+        for (&chain_id, chain) in &service.index.read().unwrap().chains {
+            links.extend(chain.links.iter().map(|&(sequence, target, target_sequence)| AuthChainLinkRecord {
+                room_id: ROOM.to_owned(),
+                origin_chain_id: chain_id,
+                origin_sequence_number: sequence,
+                target_chain_id: target,
+                target_sequence_number: target_sequence,
+            }));
+        }
+
+        // Nothing needs to be looked up again
+        let restored = Service::new();
+        restored.restore(positions.clone(), links.clone());
+        assert_eq!(restored.len(), service.len());
+        let lookup_message = |event_id: &str| (event_id == "$message").then(|| events["$message"].clone());
+        assert_eq!(restored.get_auth_chain(ROOM, &["$message".to_owned()], lookup_message), auth_chain);
+
+        // Chains missing a position are cut before it
+        let cut = positions.iter().find(|record| record.sequence_number == 2).unwrap().clone();
+        positions.retain(|record| record != &cut);
+        let restored = Service::new();
+        restored.restore(positions, links);
+        assert_eq!(restored.len(), service.len() - 1);
+        assert!(!restored.index.read().unwrap().positions.contains_key(&cut.event_id));
+    }
+}
@@ -0,0 +1,150 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Auto-Join Rooms
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Welcome rooms that newly registered users are joined to. The list starts
+//   out as `auto_join_rooms` from the config and can be replaced at runtime
+//   through the admin API. Rooms may be given by alias, which is resolved
+//   through the alias directory, over federation for aliases of other
+//   servers; rooms this server is not in are joined over federation.
+//
+// =============================================================================
+
+use std::{future::Future, sync::RwLock};
+
+use ruma::api::client::error::ErrorKind;
+use tracing::{info, warn};
+
+use crate::{services, Error, Result};
+
+/// Auto-join rooms service
+#[derive(Debug, Default)]
+pub struct Service {
+    rooms: RwLock<Vec<String>>,
+}
+
+impl Service {
+    pub fn new(rooms: Vec<String>) -> Self {
+        Self {
+            rooms: RwLock::new(rooms),
+        }
+    }
+
+    /// Rooms new users are currently joined to
+    pub fn rooms(&self) -> Vec<String> {
+        self.rooms.read().unwrap().clone()
+    }
+
+    /// Replace the list of auto-join rooms
+    pub fn set_rooms(&self, rooms: Vec<String>) {
+        info!("🏠 Auto-join rooms set to {:?}", rooms);
+        *self.rooms.write().unwrap() = rooms;
+    }
+
+    /// Join a newly registered user to every auto-join room. A room that
+    /// cannot be joined is logged and skipped, so registration never fails
+    /// because of it. Returns the rooms that were joined.
+    pub async fn join_new_user(&self, user_id: &str) -> Vec<String> {
+        let services = services();
+        join_all(
+            &self.rooms(),
+            user_id,
+            move |alias| async move {
+                services
+                    .room_aliases
+                    .resolve(&services.timeline, &services.sending, &services.globals.config.server_name, &alias)
+                    .await
+            },
+            move |room_id, via| async move { services.membership.join(&room_id, user_id, &via).await },
+        )
+        .await
+    }
+}
+
+/// Join `user_id` to each room, resolving aliases to a room id and the
+/// servers to join it through first
+async fn join_all<R, J>(
+    rooms: &[String],
+    user_id: &str,
+    resolve: impl Fn(String) -> R,
+    join: impl Fn(String, Vec<String>) -> J,
+) -> Vec<String>
+where
+    R: Future<Output = Result<(String, Vec<String>)>>,
+    J: Future<Output = Result<String>>,
+{
+    let mut joined = Vec::new();
+    for room in rooms {
+        let result = if room.starts_with('!') {
+            join(room.clone(), Vec::new()).await
+        } else if room.starts_with('#') {
+            match resolve(room.clone()).await {
+                Ok((room_id, via)) => join(room_id, via).await,
+                Err(e) => Err(e),
+            }
+        } else {
+            Err(Error::BadRequest(ErrorKind::InvalidParam, "Not a room ID or alias"))
+        };
+
+        match result {
+            Ok(_) => joined.push(room.clone()),
+            Err(e) => warn!("⚠️ Could not auto-join {} to {}: {}", user_id, room, e),
+        }
+    }
+    joined
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_failed_rooms_are_skipped() {
+        let rooms = vec![
+            "!welcome:matrixon.local".to_owned(),
+            "!gone:matrixon.local".to_owned(),
+            "#unknown:matrixon.local".to_owned(),
+            "!help:matrixon.local".to_owned(),
+        ];
+        let joined = join_all(
+            &rooms,
+            "@new:matrixon.local",
+            |_| async { Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room alias")) },
+            |room, _| async move {
+                match room.as_str() {
+                    "!gone:matrixon.local" => Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room")),
+                    _ => Ok("$event".to_owned()),
+                }
+            },
+        )
+        .await;
+        assert_eq!(joined, vec!["!welcome:matrixon.local", "!help:matrixon.local"]);
+    }
+
+    #[tokio::test]
+    async fn test_aliases_are_joined_through_their_servers() {
+        let rooms = vec!["#lobby:remote.example".to_owned()];
+        let joined = join_all(
+            &rooms,
+            "@new:matrixon.local",
+            move |alias| async move {
+                assert_eq!(alias, "#lobby:remote.example");
+                Ok(("!lobby:remote.example".to_owned(), vec!["remote.example".to_owned()]))
+            },
+            |room_id, via| async move {
+                assert_eq!(room_id, "!lobby:remote.example");
+                assert_eq!(via, ["remote.example"]);
+                Ok("$event".to_owned())
+            },
+        )
+        .await;
+        assert_eq!(joined, rooms);
+    }
+}
@@ -0,0 +1,404 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Caches
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Bounded LRU caches for hot lookups, with hit and miss counters rendered
+//   on the metrics endpoint. Capacities are base sizes scaled by
+//   `matrixon_cache_capacity_modifier`, and can be changed at runtime
+//   through the cache admin API, which also drops stale entries. Memory
+//   use of a cache is estimated from a sample of its entries.
+//
+// =============================================================================
+
+use std::{
+    collections::HashSet,
+    fmt::Write,
+    hash::Hash,
+    mem,
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use lru::LruCache;
+use matrixon_db::Profile;
+
+/// Entries whose heap use is measured to estimate that of a whole cache
+const MEMORY_SAMPLE_SIZE: usize = 256;
+
+/// Bytes an LRU entry takes besides its key and value: the list pointers
+/// and the hash table slot
+const ENTRY_OVERHEAD: usize = 3 * mem::size_of::<usize>();
+
+/// A named LRU cache counting its hits and misses
+#[derive(Debug)]
+pub struct Cache<K: Hash + Eq, V> {
+    name: &'static str,
+    entries: Mutex<LruCache<K, V>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl<K: Hash + Eq, V: Clone> Cache<K, V> {
+    /// A cache holding `base_capacity` entries scaled by `modifier`
+    pub fn new(name: &'static str, base_capacity: usize, modifier: f64) -> Self {
+        let capacity = NonZeroUsize::new((base_capacity as f64 * modifier) as usize).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            name,
+            entries: Mutex::new(LruCache::new(capacity)),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let value = self.entries.lock().unwrap().get(key).cloned();
+        let counter = if value.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        self.entries.lock().unwrap().put(key, value);
+    }
+
+    pub fn remove(&self, key: &K) {
+        self.entries.lock().unwrap().pop(key);
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Drop every entry whose key mentions `key`, returning how many
+    pub fn invalidate(&self, key: &str) -> usize
+    where
+        K: CacheKey + Clone,
+    {
+        let mut entries = self.entries.lock().unwrap();
+        let stale: Vec<K> = entries.iter().map(|(k, _)| k).filter(|k| k.mentions(key)).cloned().collect();
+        for k in &stale {
+            entries.pop(k);
+        }
+        stale.len()
+    }
+
+    /// Hold at most `capacity` entries, evicting the least recently used
+    pub fn set_capacity(&self, capacity: usize) {
+        self.entries.lock().unwrap().resize(NonZeroUsize::new(capacity).unwrap_or(NonZeroUsize::MIN));
+    }
+
+    /// Keys of the cached entries, most recently used first
+    pub fn keys(&self) -> Vec<K>
+    where
+        K: Clone,
+    {
+        self.entries.lock().unwrap().iter().map(|(k, _)| k.clone()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.lock().unwrap().cap().get()
+    }
+
+    /// Estimated bytes held by the entries, extrapolated from the heap use
+    /// of the most recently used ones. They are measured after releasing
+    /// the lock, as values such as auth chains take a while to walk.
+    pub fn memory_usage(&self) -> usize
+    where
+        K: Clone + HeapSize,
+        V: Clone + HeapSize,
+    {
+        let (len, sample): (usize, Vec<(K, V)>) = {
+            let entries = self.entries.lock().unwrap();
+            (entries.len(), entries.iter().take(MEMORY_SAMPLE_SIZE).map(|(k, v)| (k.clone(), v.clone())).collect())
+        };
+        let heap = match sample.len() {
+            0 => 0,
+            sampled => sample.iter().map(|(k, v)| k.heap_size() + v.heap_size()).sum::<usize>() * len / sampled,
+        };
+        len * (mem::size_of::<K>() + mem::size_of::<V>() + ENTRY_OVERHEAD) + heap
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// Cache keys that can be invalidated by one of their parts, such as a
+/// room or user id
+pub trait CacheKey {
+    fn mentions(&self, key: &str) -> bool;
+}
+
+impl CacheKey for String {
+    fn mentions(&self, key: &str) -> bool {
+        self == key
+    }
+}
+
+impl CacheKey for u32 {
+    fn mentions(&self, key: &str) -> bool {
+        key.parse() == Ok(*self)
+    }
+}
+
+impl CacheKey for u64 {
+    fn mentions(&self, key: &str) -> bool {
+        key.parse() == Ok(*self)
+    }
+}
+
+impl CacheKey for (String, String) {
+    fn mentions(&self, key: &str) -> bool {
+        self.0 == key || self.1 == key
+    }
+}
+
+impl CacheKey for (String, u64) {
+    fn mentions(&self, key: &str) -> bool {
+        self.0 == key || self.1.mentions(key)
+    }
+}
+
+impl CacheKey for (String, String, String) {
+    fn mentions(&self, key: &str) -> bool {
+        self.0 == key || self.1 == key || self.2 == key
+    }
+}
+
+/// Bytes a value owns on the heap, beyond its own size
+pub trait HeapSize {
+    fn heap_size(&self) -> usize;
+}
+
+macro_rules! no_heap {
+    ($($t:ty),*) => {
+        $(impl HeapSize for $t {
+            fn heap_size(&self) -> usize {
+                0
+            }
+        })*
+    };
+}
+
+no_heap!(u32, u64, usize, &str);
+
+impl HeapSize for String {
+    fn heap_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+impl<T: HeapSize> HeapSize for Option<T> {
+    fn heap_size(&self) -> usize {
+        self.as_ref().map_or(0, HeapSize::heap_size)
+    }
+}
+
+impl<A: HeapSize, B: HeapSize> HeapSize for (A, B) {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size() + self.1.heap_size()
+    }
+}
+
+impl<A: HeapSize, B: HeapSize, C: HeapSize> HeapSize for (A, B, C) {
+    fn heap_size(&self) -> usize {
+        self.0.heap_size() + self.1.heap_size() + self.2.heap_size()
+    }
+}
+
+/// Counted in full, as cached values are rarely shared
+impl<T: HeapSize> HeapSize for Arc<T> {
+    fn heap_size(&self) -> usize {
+        mem::size_of::<T>() + T::heap_size(self)
+    }
+}
+
+impl<T: HeapSize> HeapSize for HashSet<T> {
+    fn heap_size(&self) -> usize {
+        self.capacity() * (mem::size_of::<T>() + 1) + self.iter().map(HeapSize::heap_size).sum::<usize>()
+    }
+}
+
+impl HeapSize for Profile {
+    fn heap_size(&self) -> usize {
+        self.user_id.heap_size() + self.displayname.heap_size() + self.avatar_url.heap_size()
+    }
+}
+
+/// Caches whose statistics are exported and that the admin API manages
+pub trait CacheStats {
+    fn name(&self) -> &'static str;
+    fn entries(&self) -> usize;
+    fn max_entries(&self) -> usize;
+    fn lookups(&self) -> (u64, u64);
+    /// Estimated bytes held by the entries
+    fn memory_bytes(&self) -> usize;
+    fn clear_entries(&self);
+    /// Drop the entries mentioning `key`, returning how many
+    fn invalidate_key(&self, key: &str) -> usize;
+    fn resize(&self, capacity: usize);
+
+    fn render_samples(&self, out: &mut Samples) {
+        let name = self.name();
+        let (hits, misses) = self.lookups();
+        let _ = writeln!(out.hits, "matrixon_cache_hits_total{{cache=\"{name}\"}} {hits}");
+        let _ = writeln!(out.misses, "matrixon_cache_misses_total{{cache=\"{name}\"}} {misses}");
+        let _ = writeln!(out.entries, "matrixon_cache_entries{{cache=\"{name}\"}} {}", self.entries());
+        let _ = writeln!(out.capacity, "matrixon_cache_capacity{{cache=\"{name}\"}} {}", self.max_entries());
+        let _ = writeln!(out.memory, "matrixon_cache_memory_bytes{{cache=\"{name}\"}} {}", self.memory_bytes());
+    }
+
+    /// Share of lookups that found an entry, if there were any
+    fn hit_rate(&self) -> Option<f64> {
+        let (hits, misses) = self.lookups();
+        (hits + misses > 0).then(|| hits as f64 / (hits + misses) as f64)
+    }
+}
+
+impl<K: Hash + Eq + Clone + CacheKey + HeapSize, V: Clone + HeapSize> CacheStats for Cache<K, V> {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn entries(&self) -> usize {
+        self.len()
+    }
+
+    fn max_entries(&self) -> usize {
+        self.capacity()
+    }
+
+    fn lookups(&self) -> (u64, u64) {
+        (self.hits(), self.misses())
+    }
+
+    fn memory_bytes(&self) -> usize {
+        self.memory_usage()
+    }
+
+    fn clear_entries(&self) {
+        self.clear();
+    }
+
+    fn invalidate_key(&self, key: &str) -> usize {
+        self.invalidate(key)
+    }
+
+    fn resize(&self, capacity: usize) {
+        self.set_capacity(capacity);
+    }
+}
+
+/// Samples of each metric family, collected before rendering them grouped
+#[derive(Debug, Default)]
+pub struct Samples {
+    hits: String,
+    misses: String,
+    entries: String,
+    capacity: String,
+    memory: String,
+}
+
+/// Statistics of `caches` in the Prometheus text exposition format
+pub fn render_metrics(caches: &[&dyn CacheStats]) -> String {
+    let mut samples = Samples::default();
+    for cache in caches {
+        cache.render_samples(&mut samples);
+    }
+    let mut out = String::new();
+    for (name, kind, help, samples) in [
+        ("matrixon_cache_hits_total", "counter", "Cache lookups that found an entry", &samples.hits),
+        ("matrixon_cache_misses_total", "counter", "Cache lookups that found no entry", &samples.misses),
+        ("matrixon_cache_entries", "gauge", "Entries in the cache", &samples.entries),
+        ("matrixon_cache_capacity", "gauge", "Most entries the cache holds", &samples.capacity),
+        ("matrixon_cache_memory_bytes", "gauge", "Estimated bytes held by the cache", &samples.memory),
+    ] {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        out.push_str(samples);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_eviction_and_stats() {
+        let cache: Cache<u32, &str> = Cache::new("test", 4, 0.5);
+        assert_eq!(cache.capacity(), 2);
+        cache.insert(1, "one");
+        cache.insert(2, "two");
+        assert_eq!(cache.get(&1), Some("one"));
+        cache.insert(3, "three");
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some("one"));
+        assert_eq!((cache.hits(), cache.misses()), (2, 1));
+
+        let rendered = render_metrics(&[&cache]);
+        assert!(rendered.contains("matrixon_cache_hits_total{cache=\"test\"} 2\n"));
+        assert!(rendered.contains("matrixon_cache_entries{cache=\"test\"} 2\n"));
+
+        // A modifier of zero still leaves room for one entry
+        assert_eq!(Cache::<u32, u32>::new("tiny", 4, 0.0).capacity(), 1);
+    }
+
+    #[test]
+    fn test_invalidation_and_resizing() {
+        let cache: Cache<(String, String), u32> = Cache::new("pdus", 8, 1.0);
+        for (room, event, position) in [("!a", "$1", 1), ("!a", "$2", 2), ("!b", "$3", 3)] {
+            cache.insert((room.to_owned(), event.to_owned()), position);
+        }
+        assert_eq!(cache.invalidate("!a"), 2);
+        assert_eq!(cache.invalidate("!a"), 0);
+        assert_eq!(cache.keys(), vec![("!b".to_owned(), "$3".to_owned())]);
+
+        let managed: &dyn CacheStats = &cache;
+        assert_eq!(managed.hit_rate(), None);
+        cache.get(&("!b".to_owned(), "$3".to_owned()));
+        cache.get(&("!b".to_owned(), "$4".to_owned()));
+        assert_eq!(managed.hit_rate(), Some(0.5));
+
+        cache.insert(("!c".to_owned(), "$5".to_owned()), 5);
+        managed.resize(1);
+        assert_eq!((managed.max_entries(), managed.entries()), (1, 1));
+        assert_eq!(cache.keys(), vec![("!c".to_owned(), "$5".to_owned())]);
+        managed.clear_entries();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_memory_usage_counts_heap_contents() {
+        let cache: Cache<String, (String, u64)> = Cache::new("sized", 8, 1.0);
+        assert_eq!(cache.memory_usage(), 0);
+        cache.insert("a".repeat(100), ("b".repeat(50), 1));
+        let one = cache.memory_usage();
+        assert!(one >= 150 + mem::size_of::<String>() * 2);
+        cache.insert("c".repeat(100), ("d".repeat(50), 2));
+        assert_eq!(cache.memory_usage(), 2 * one);
+        assert!(render_metrics(&[&cache]).contains(&format!("matrixon_cache_memory_bytes{{cache=\"sized\"}} {}\n", 2 * one)));
+    }
+}
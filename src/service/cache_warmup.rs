@@ -0,0 +1,175 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Cache Warm Start
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Keeps the in-memory caches warm across restarts. The keys of what is hot
+//   (recently active rooms, users with cached profiles, servers with cached
+//   signing keys) are written to a snapshot file periodically and on
+//   shutdown. At boot the snapshot is read back and those entries are loaded
+//   in the background, so the first requests after a deploy do not all miss
+//   the caches at once. Only keys are stored; the values are always loaded
+//   fresh.
+//
+// =============================================================================
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::{
+    service::{key_fetcher, profiles, timeline},
+    services, Error, Result,
+};
+
+/// Most keys of each kind kept in a snapshot
+pub const MAX_KEYS: usize = 10_000;
+
+/// Time between two snapshots
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Keys of the hot cache entries
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheSnapshot {
+    /// Most recently active rooms, latest first
+    pub rooms: Vec<String>,
+    /// Users with a cached profile, most recently used first
+    pub users: Vec<String>,
+    /// Servers with cached signing keys, most recently fetched first
+    pub servers: Vec<String>,
+}
+
+impl CacheSnapshot {
+    /// Snapshot of the current cache contents
+    pub fn collect(timeline: &timeline::Service, profiles: &profiles::Service, key_fetcher: &key_fetcher::Service) -> Self {
+        // Both come hottest first, so the coldest keys are cut off
+        let capped = |mut keys: Vec<String>| {
+            keys.truncate(MAX_KEYS);
+            keys
+        };
+        Self {
+            rooms: timeline.recent_room_ids(MAX_KEYS),
+            users: capped(profiles.cached_user_ids()),
+            servers: capped(key_fetcher.cached_servers()),
+        }
+    }
+
+    /// Write the snapshot, replacing the previous one atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let temporary = path.with_extension("tmp");
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(|e| Error::BadConfig(format!("{}: {}", dir.display(), e)))?;
+        }
+        fs::write(&temporary, serde_json::to_vec(self).expect("snapshot serializes"))
+            .and_then(|()| fs::rename(&temporary, path))
+            .map_err(|e| Error::BadConfig(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Read a snapshot; a missing file is an empty snapshot
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| Error::BadConfig(format!("Invalid cache snapshot: {}", e))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(Error::BadConfig(format!("{}: {}", path.display(), e))),
+        }
+    }
+}
+
+/// Load the entries of a snapshot into the caches. Failures only cost the
+/// warm-up of that entry.
+pub async fn warm(snapshot: &CacheSnapshot) {
+    let services = services();
+    for room_id in &snapshot.rooms {
+        services.room_summary.refresh(room_id);
+    }
+    for user_id in &snapshot.users {
+        if let Err(e) = services.profiles.get(user_id).await {
+            debug!("Could not warm the profile of {}: {}", user_id, e);
+        }
+    }
+    if services.globals.config.allow_federation {
+        for server in &snapshot.servers {
+            if let Err(e) = services.key_fetcher.fetch_server_keys(&services.sending, server).await {
+                debug!("Could not warm the keys of {}: {}", server, e);
+            }
+        }
+    }
+}
+
+/// Snapshot the caches now
+pub fn save_snapshot(path: &Path) {
+    let services = services();
+    let snapshot = CacheSnapshot::collect(&services.timeline, &services.profiles, &services.key_fetcher);
+    match snapshot.save(path) {
+        Ok(()) => debug!(
+            "💾 Saved cache snapshot: {} rooms, {} users, {} servers",
+            snapshot.rooms.len(),
+            snapshot.users.len(),
+            snapshot.servers.len()
+        ),
+        Err(e) => warn!("⚠️ Could not save the cache snapshot to {}: {}", path.display(), e),
+    }
+}
+
+/// Warm the caches from the snapshot at `path`, then keep snapshotting them
+pub async fn run(path: PathBuf) {
+    match CacheSnapshot::load(&path) {
+        Ok(snapshot) => {
+            info!(
+                "🔥 Warming caches: {} rooms, {} users, {} servers",
+                snapshot.rooms.len(),
+                snapshot.users.len(),
+                snapshot.servers.len()
+            );
+            warm(&snapshot).await;
+            info!("✅ Caches warmed");
+        }
+        Err(e) => warn!("⚠️ Ignoring the cache snapshot: {}", e),
+    }
+
+    let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+    // The first tick completes immediately
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        save_snapshot(&path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let timeline = timeline::Service::new();
+        for room_id in ["!old:matrixon.local", "!new:matrixon.local"] {
+            timeline.append_event(room_id, "@a:matrixon.local", "m.room.message", None, json!({ "body": "hi" }));
+        }
+        let key_fetcher = key_fetcher::Service::new("matrixon.local", Vec::new());
+        for (server, valid_until_ts) in [("old.example", 1_000), ("new.example", 2_000)] {
+            key_fetcher.add_server_keys(server, key_fetcher::ServerKeys { valid_until_ts, ..Default::default() });
+        }
+        let snapshot = CacheSnapshot::collect(&timeline, &profiles::Service::default(), &key_fetcher);
+        assert_eq!(snapshot.rooms, ["!new:matrixon.local", "!old:matrixon.local"]);
+        assert_eq!(snapshot.servers, ["new.example", "old.example"]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache").join("snapshot.json");
+        assert_eq!(CacheSnapshot::load(&path).unwrap(), CacheSnapshot::default());
+        snapshot.save(&path).unwrap();
+        assert_eq!(CacheSnapshot::load(&path).unwrap(), snapshot);
+    }
+}
@@ -0,0 +1,211 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Delegated Authentication
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   MSC2965/MSC3861 next-gen auth. Authentication is delegated to an OAuth 2.0
+//   provider such as the Matrix Authentication Service (MAS); access tokens it
+//   issues are validated through RFC 7662 token introspection and mapped to
+//   local users and devices. Introspected tokens are cached until they or
+//   the cache lifetime expire; expired entries are dropped as new ones are
+//   added.
+//
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use ruma::api::client::error::ErrorKind;
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::{config::DelegatedAuthConfig, Error, Result};
+
+/// Scope granting access to the client-server API
+const API_SCOPE: &str = "urn:matrix:org.matrix.msc2967.client:api:*";
+/// Prefix of the scope carrying the device id
+const DEVICE_SCOPE_PREFIX: &str = "urn:matrix:org.matrix.msc2967.client:device:";
+/// Longest user id the Matrix spec allows
+pub const MAX_USER_ID_LENGTH: usize = 255;
+
+#[derive(Debug, Clone)]
+struct CachedSession {
+    user_id: String,
+    device_id: String,
+    valid_until: Instant,
+}
+
+/// Delegated authentication service
+#[derive(Debug, Default)]
+pub struct Service {
+    client: reqwest::Client,
+    sessions: RwLock<HashMap<String, CachedSession>>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Resolve an access token issued by the auth provider to `(user_id, device_id)`
+    pub async fn authenticate(&self, config: &DelegatedAuthConfig, server_name: &str, token: &str) -> Result<(String, String)> {
+        if let Some(session) = self.sessions.read().unwrap().get(token) {
+            if session.valid_until > Instant::now() {
+                return Ok((session.user_id.clone(), session.device_id.clone()));
+            }
+        }
+
+        let response: Value = self
+            .client
+            .post(config.introspection_endpoint())
+            .basic_auth(&config.client_id, Some(&config.client_secret))
+            .form(&[("token", token), ("token_type_hint", "access_token")])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| {
+                warn!("❌ Token introspection failed: {}", e);
+                Error::BadServerResponse("Token introspection failed".to_owned())
+            })?
+            .json()
+            .await
+            .map_err(|_| Error::BadServerResponse("Invalid token introspection response".to_owned()))?;
+
+        let (user_id, device_id) = session_from_introspection(&response, server_name).ok_or(Error::BadRequest(
+            ErrorKind::UnknownToken { soft_logout: false },
+            "Unknown access token",
+        ))?;
+        debug!("🔑 Introspected token for {} ({})", user_id, device_id);
+
+        // Never cache a token past its own expiry
+        let mut ttl = Duration::from_secs(config.introspection_cache_ttl_s);
+        if let Some(exp) = response["exp"].as_u64() {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+            ttl = ttl.min(Duration::from_secs(exp.saturating_sub(now)));
+        }
+        let now = Instant::now();
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.retain(|_, session| session.valid_until > now);
+        sessions.insert(
+            token.to_owned(),
+            CachedSession {
+                user_id: user_id.clone(),
+                device_id: device_id.clone(),
+                valid_until: now + ttl,
+            },
+        );
+
+        Ok((user_id, device_id))
+    }
+
+    /// OAuth 2.0 authorization server metadata of the issuer, as served by
+    /// `GET /_matrix/client/v1/auth_metadata`
+    pub async fn server_metadata(&self, config: &DelegatedAuthConfig) -> Result<Value> {
+        let url = format!("{}/.well-known/openid-configuration", config.issuer.trim_end_matches('/'));
+        self.client
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|_| Error::BadServerResponse("Could not fetch auth issuer metadata".to_owned()))?
+            .json()
+            .await
+            .map_err(|_| Error::BadServerResponse("Invalid auth issuer metadata".to_owned()))
+    }
+
+    /// Forget cached sessions, e.g. after the provider revoked tokens
+    pub fn clear_cache(&self) {
+        self.sessions.write().unwrap().clear();
+    }
+}
+
+impl DelegatedAuthConfig {
+    /// Introspection endpoint, defaulting to the MAS location under the issuer
+    pub fn introspection_endpoint(&self) -> String {
+        self.introspection_endpoint
+            .clone()
+            .unwrap_or_else(|| format!("{}/oauth2/introspect", self.issuer.trim_end_matches('/')))
+    }
+}
+
+/// Map an RFC 7662 introspection response to a local user and device.
+///
+/// The token must be active and carry both the client API scope and a
+/// device scope; the local part comes from `username`, which must be a
+/// valid Matrix localpart.
+fn session_from_introspection(response: &Value, server_name: &str) -> Option<(String, String)> {
+    if response["active"].as_bool() != Some(true) {
+        return None;
+    }
+
+    let scopes: Vec<&str> = response["scope"].as_str()?.split(' ').collect();
+    if !scopes.contains(&API_SCOPE) {
+        return None;
+    }
+    let device_id = scopes.iter().find_map(|scope| scope.strip_prefix(DEVICE_SCOPE_PREFIX))?;
+    let username = response["username"].as_str().filter(|username| is_valid_localpart(username))?;
+    let user_id = format!("@{}:{}", username, server_name);
+    if user_id.len() > MAX_USER_ID_LENGTH {
+        return None;
+    }
+
+    Some((user_id, device_id.to_owned()))
+}
+
+/// Whether `localpart` only has the characters the Matrix spec allows in
+/// the local part of a user id
+pub fn is_valid_localpart(localpart: &str) -> bool {
+    !localpart.is_empty()
+        && localpart.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '.' | '_' | '=' | '-' | '/' | '+'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_active_token_maps_to_local_user() {
+        let response = json!({
+            "active": true,
+            "username": "alice",
+            "scope": "openid urn:matrix:org.matrix.msc2967.client:api:* urn:matrix:org.matrix.msc2967.client:device:ABCDEF"
+        });
+        assert_eq!(
+            session_from_introspection(&response, "matrixon.local"),
+            Some(("@alice:matrixon.local".to_owned(), "ABCDEF".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_inactive_or_unscoped_tokens_are_rejected() {
+        let scope = "urn:matrix:org.matrix.msc2967.client:api:* urn:matrix:org.matrix.msc2967.client:device:ABCDEF";
+        assert!(session_from_introspection(&json!({ "active": false, "username": "alice", "scope": scope }), "matrixon.local").is_none());
+        assert!(session_from_introspection(
+            &json!({ "active": true, "username": "alice", "scope": "urn:matrix:org.matrix.msc2967.client:api:*" }),
+            "matrixon.local"
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_invalid_usernames_are_rejected() {
+        let scope = "urn:matrix:org.matrix.msc2967.client:api:* urn:matrix:org.matrix.msc2967.client:device:ABCDEF";
+        let too_long = "a".repeat(300);
+        for username in ["", "a:evil", "Alice", "al ice", "alice\n", too_long.as_str()] {
+            let response = json!({ "active": true, "username": username, "scope": scope });
+            assert!(session_from_introspection(&response, "matrixon.local").is_none(), "{:?}", username);
+        }
+        let response = json!({ "active": true, "username": "alice.b_c=d-e/f+g", "scope": scope });
+        assert!(session_from_introspection(&response, "matrixon.local").is_some());
+    }
+}
@@ -0,0 +1,101 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Encryption Policy
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Server policy requiring end-to-end encryption for new rooms, either
+//   globally or for selected room creators, and preventing it from being
+//   turned off again in rooms it applies to.
+//
+// =============================================================================
+
+use ruma::api::client::error::ErrorKind;
+use serde_json::{json, Value};
+
+use crate::{config::EncryptionPolicyConfig, Error, Result};
+
+/// Algorithm injected into rooms that must be encrypted
+pub const DEFAULT_ALGORITHM: &str = "m.megolm.v1.aes-sha2";
+
+impl EncryptionPolicyConfig {
+    /// Whether rooms created by `creator` must be encrypted
+    pub fn requires_encryption(&self, creator: &str) -> bool {
+        if self.exempt_creators.iter().any(|pattern| matches_user(pattern, creator)) {
+            return false;
+        }
+        self.require_for_new_rooms
+            || self.required_for_creators.iter().any(|pattern| matches_user(pattern, creator))
+    }
+
+    /// Reject state changes that would weaken encryption in a room the
+    /// policy applies to.
+    pub fn check_state_event(&self, room_creator: &str, event_type: &str, content: &Value) -> Result<()> {
+        if event_type != "m.room.encryption" || !self.requires_encryption(room_creator) {
+            return Ok(());
+        }
+        if content.get("algorithm").and_then(Value::as_str) == Some(DEFAULT_ALGORITHM) {
+            Ok(())
+        } else {
+            Err(Error::BadRequest(
+                ErrorKind::forbidden(),
+                "Server policy requires encryption in this room",
+            ))
+        }
+    }
+}
+
+/// Content of the `m.room.encryption` event injected at room creation
+pub fn encryption_event_content() -> Value {
+    json!({ "algorithm": DEFAULT_ALGORITHM })
+}
+
+/// Match a user id against an exact id or a pattern with a single `*`,
+/// e.g. `*:example.org` or `@staff_*`.
+fn matches_user(pattern: &str, user_id: &str) -> bool {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => {
+            user_id.len() >= prefix.len() + suffix.len()
+                && user_id.starts_with(prefix)
+                && user_id.ends_with(suffix)
+        }
+        None => pattern == user_id,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_creator_groups() {
+        let policy = EncryptionPolicyConfig {
+            require_for_new_rooms: false,
+            required_for_creators: vec!["*:corp.example".to_owned(), "@staff_*".to_owned()],
+            exempt_creators: vec!["@bot:corp.example".to_owned()],
+        };
+        assert!(policy.requires_encryption("@alice:corp.example"));
+        assert!(policy.requires_encryption("@staff_bob:matrixon.local"));
+        assert!(!policy.requires_encryption("@bot:corp.example"));
+        assert!(!policy.requires_encryption("@carol:matrixon.local"));
+    }
+
+    #[test]
+    fn test_disabling_encryption_is_blocked() {
+        let policy = EncryptionPolicyConfig {
+            require_for_new_rooms: true,
+            ..Default::default()
+        };
+        let creator = "@alice:matrixon.local";
+        assert!(policy.check_state_event(creator, "m.room.encryption", &json!({})).is_err());
+        assert!(policy
+            .check_state_event(creator, "m.room.encryption", &encryption_event_content())
+            .is_ok());
+        assert!(policy.check_state_event(creator, "m.room.topic", &json!({})).is_ok());
+    }
+}
@@ -0,0 +1,121 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Erasure Service
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   GDPR right to erasure. Erasing a user redacts their events, deletes
+//   their media and keys, and records an erasure marker that keeps events
+//   served over federation or received later (backfill) compliant: the
+//   federation routes pass the events they serve and receive through
+//   `enforce_erasure`, and the marker is kept with the account state. Data
+//   under a legal hold is kept: nothing of a held user, and none of their
+//   events in held rooms.
+//
+// =============================================================================
+
+use serde_json::Value;
+use tracing::info;
+
+use crate::services;
+use crate::service::timeline::redact_event;
+
+/// Summary of an erasure run
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ErasureReport {
+    pub redacted_events: usize,
+    pub deleted_media: usize,
+}
+
+/// Erase all data of a (deactivated) user
+pub fn erase_user(user_id: &str) -> ErasureReport {
+    let services = services();
+    services.accounts.mark_erased(user_id);
+    if services.legal_hold.is_user_held(user_id) {
+        info!("⚖️ Keeping the data of {} under legal hold", user_id);
+        return ErasureReport::default();
+    }
+
+    // Media posted to held rooms stays with them
+    let held_media = services.legal_hold.held_room_media(&services.timeline, &services.globals.config.server_name);
+    let report = ErasureReport {
+        redacted_events: services
+            .timeline
+            .redact_events_from(user_id, |room_id| !services.legal_hold.is_room_held(room_id)),
+        deleted_media: services.media_store.delete_user_media(user_id, |media_id| held_media.contains(media_id)),
+    };
+    services.keys.remove_user(user_id);
+    info!(
+        "🧹 Erased {}: {} events redacted, {} media files deleted",
+        user_id, report.redacted_events, report.deleted_media
+    );
+    report
+}
+
+/// Apply erasure markers to an event crossing the federation boundary.
+///
+/// Used both for stored events served to other servers and for events
+/// received from them (e.g. via backfill) before they are stored: events
+/// sent by erased users are redacted and membership events about erased
+/// users lose their profile data.
+pub fn enforce_erasure(event: &mut Value) {
+    apply_erasure(event, |user_id| services().accounts.is_erased(user_id));
+}
+
+/// Apply erasure markers to the events in the arrays `fields` of a
+/// federation request or response
+pub fn enforce_erasure_in(body: &mut Value, fields: &[&str]) {
+    for field in fields {
+        for event in body.get_mut(*field).and_then(Value::as_array_mut).into_iter().flatten() {
+            enforce_erasure(event);
+        }
+    }
+}
+
+fn apply_erasure(event: &mut Value, is_erased: impl Fn(&str) -> bool) {
+    if event["sender"].as_str().is_some_and(&is_erased) {
+        redact_event(event);
+    }
+
+    let is_member_event = event["type"] == "m.room.member";
+    if is_member_event && event["state_key"].as_str().is_some_and(&is_erased) {
+        if let Some(content) = event.get_mut("content").and_then(Value::as_object_mut) {
+            content.remove("displayname");
+            content.remove("avatar_url");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_membership_of_erased_user_loses_profile() {
+        let mut event = json!({
+            "type": "m.room.member",
+            "sender": "@admin:matrixon.local",
+            "state_key": "@gone:matrixon.local",
+            "content": { "membership": "invite", "displayname": "Gone", "reason": "hi" }
+        });
+        apply_erasure(&mut event, |user| user == "@gone:matrixon.local");
+        assert_eq!(event["content"], json!({ "membership": "invite", "reason": "hi" }));
+    }
+
+    #[test]
+    fn test_events_from_erased_user_are_redacted() {
+        let mut event = json!({
+            "type": "m.room.message",
+            "sender": "@gone:matrixon.local",
+            "content": { "body": "secret" }
+        });
+        apply_erasure(&mut event, |user| user == "@gone:matrixon.local");
+        assert_eq!(event["content"], json!({}));
+    }
+}
@@ -0,0 +1,227 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Event Export
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Publishes the server's event stream to Kafka (`kafka` feature) or NATS
+//   JetStream (`nats` feature) for data warehouse and analytics pipelines.
+//   Every PDU goes to `{topic_prefix}.pdus`; membership changes are also
+//   published to `{topic_prefix}.membership`. Delivery is at-least-once: the
+//   export position only advances once the broker acknowledged an event, so
+//   consumers should deduplicate on `event_id`. The position is kept in
+//   `event_export.json` below `database_path`, so a restart resumes where
+//   the export stopped instead of starting over.
+//
+// =============================================================================
+
+use std::{path::PathBuf, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::{config::EventExportConfig, service::state_file::StateFile, services, Error, Result};
+
+/// Version of the exported payload format, bumped on incompatible changes
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Connection to the message broker
+pub enum Sink {
+    #[cfg(feature = "kafka")]
+    Kafka(rdkafka::producer::FutureProducer),
+    #[cfg(feature = "nats")]
+    Nats(async_nats::jetstream::Context),
+}
+
+impl Sink {
+    pub async fn connect(config: &EventExportConfig) -> Result<Self> {
+        match config.backend.as_str() {
+            #[cfg(feature = "kafka")]
+            "kafka" => {
+                let producer = rdkafka::ClientConfig::new()
+                    .set("bootstrap.servers", &config.servers)
+                    .set("acks", "all")
+                    .set("enable.idempotence", "true")
+                    .set("message.timeout.ms", "30000")
+                    .create()
+                    .map_err(|e| Error::BadConfig(format!("Could not create Kafka producer: {}", e)))?;
+                Ok(Sink::Kafka(producer))
+            }
+            #[cfg(feature = "nats")]
+            "nats" => {
+                let client = async_nats::connect(&config.servers)
+                    .await
+                    .map_err(|e| Error::BadConfig(format!("Could not connect to NATS: {}", e)))?;
+                let jetstream = async_nats::jetstream::new(client);
+                jetstream
+                    .get_or_create_stream(async_nats::jetstream::stream::Config {
+                        name: "MATRIXON_EVENTS".to_owned(),
+                        subjects: vec![format!("{}.>", config.topic_prefix)],
+                        ..Default::default()
+                    })
+                    .await
+                    .map_err(|e| Error::BadConfig(format!("Could not create NATS stream: {}", e)))?;
+                Ok(Sink::Nats(jetstream))
+            }
+            backend => Err(Error::BadConfig(format!(
+                "Event export backend `{}` is unknown or was not enabled at build time",
+                backend
+            ))),
+        }
+    }
+
+    /// Publish one payload and wait for the broker to acknowledge it
+    #[cfg_attr(not(any(feature = "kafka", feature = "nats")), allow(unused_variables))]
+    async fn publish(&self, topic: &str, key: &str, payload: &[u8]) -> Result<()> {
+        match *self {
+            #[cfg(feature = "kafka")]
+            Sink::Kafka(ref producer) => {
+                use rdkafka::message::{Header, OwnedHeaders};
+
+                let version = SCHEMA_VERSION.to_string();
+                let record = rdkafka::producer::FutureRecord::to(topic).key(key).payload(payload).headers(
+                    OwnedHeaders::new().insert(Header {
+                        key: "schema_version",
+                        value: Some(&version),
+                    }),
+                );
+                producer
+                    .send(record, Duration::from_secs(0))
+                    .await
+                    .map(|_| ())
+                    .map_err(|(e, _)| Error::BadServerResponse(format!("Kafka: {}", e)))
+            }
+            #[cfg(feature = "nats")]
+            Sink::Nats(ref jetstream) => {
+                let mut headers = async_nats::HeaderMap::new();
+                headers.insert("schema_version", SCHEMA_VERSION.to_string().as_str());
+                headers.insert("room_id", key);
+                jetstream
+                    .publish_with_headers(topic.to_owned(), headers, payload.to_vec().into())
+                    .await
+                    .map_err(|e| Error::BadServerResponse(format!("NATS: {}", e)))?
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| Error::BadServerResponse(format!("NATS: {}", e)))
+            }
+        }
+    }
+}
+
+/// Payloads for an event at `position` in the stream, with the topic
+/// suffix each is published under
+pub fn payloads(position: u64, event: &Value) -> Vec<(&'static str, Value)> {
+    let mut payloads = vec![(
+        "pdus",
+        json!({
+            "schema_version": SCHEMA_VERSION,
+            "kind": "pdu",
+            "stream_position": position,
+            "room_id": event["room_id"],
+            "event": event
+        }),
+    )];
+
+    if event["type"] == "m.room.member" {
+        payloads.push((
+            "membership",
+            json!({
+                "schema_version": SCHEMA_VERSION,
+                "kind": "membership",
+                "stream_position": position,
+                "room_id": event["room_id"],
+                "event_id": event["event_id"],
+                "user_id": event["state_key"],
+                "membership": event["content"]["membership"],
+                "sender": event["sender"],
+                "origin_server_ts": event["origin_server_ts"]
+            }),
+        ));
+    }
+    payloads
+}
+
+/// Stream position of the last event the broker acknowledged
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct ExportPosition {
+    position: u64,
+}
+
+/// Export the event stream forever, resuming after the position saved in
+/// `position_file`, or starting with events appended after this call the
+/// first time. Events that cannot be published are retried until the broker
+/// accepts them, so nothing is skipped.
+pub async fn run(config: EventExportConfig, position_file: Option<PathBuf>) -> Result<()> {
+    let sink = Sink::connect(&config).await?;
+    let timeline = &services().timeline;
+    let position_file = position_file.map(StateFile::new);
+    let mut position = match position_file.as_ref().and_then(StateFile::load::<ExportPosition>) {
+        // A stream that restarted below the saved position is followed from its head
+        Some(saved) => saved.position.min(timeline.current_count()),
+        None => timeline.current_count(),
+    };
+    info!("📤 Exporting events to {} from position {}", config.backend, position);
+
+    loop {
+        let batch = timeline.stream_since(position, config.batch_size);
+        if batch.is_empty() {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            continue;
+        }
+
+        for (count, event) in batch {
+            let key = event["room_id"].as_str().unwrap_or_default().to_owned();
+            for (suffix, payload) in payloads(count, &event) {
+                let topic = format!("{}.{}", config.topic_prefix, suffix);
+                let payload = serde_json::to_vec(&payload).expect("JSON values always serialize");
+                publish_until_acknowledged(&sink, &topic, &key, &payload).await;
+            }
+            position = count;
+        }
+        if let Some(file) = &position_file {
+            file.save(&ExportPosition { position });
+        }
+    }
+}
+
+async fn publish_until_acknowledged(sink: &Sink, topic: &str, key: &str, payload: &[u8]) {
+    let mut backoff = Duration::from_secs(1);
+    while let Err(e) = sink.publish(topic, key, payload).await {
+        warn!("⚠️ Could not export event to {}, retrying in {:?}: {}", topic, backoff, e);
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(Duration::from_secs(60));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_membership_changes_have_their_own_payload() {
+        let message = json!({ "type": "m.room.message", "room_id": "!r:matrixon.local", "event_id": "$1" });
+        let payloads = payloads(7, &message);
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].0, "pdus");
+        assert_eq!(payloads[0].1["schema_version"], SCHEMA_VERSION);
+        assert_eq!(payloads[0].1["stream_position"], 7);
+
+        let member = json!({
+            "type": "m.room.member",
+            "room_id": "!r:matrixon.local",
+            "event_id": "$2",
+            "state_key": "@alice:matrixon.local",
+            "content": { "membership": "join" }
+        });
+        let payloads = super::payloads(8, &member);
+        assert_eq!(payloads.iter().map(|(suffix, _)| *suffix).collect::<Vec<_>>(), vec!["pdus", "membership"]);
+        assert_eq!(payloads[1].1["user_id"], "@alice:matrixon.local");
+        assert_eq!(payloads[1].1["membership"], "join");
+    }
+}
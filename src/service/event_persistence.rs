@@ -0,0 +1,384 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Event Persistence
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Write-behind persistence of timeline events. Appending an event only
+//   queues it; a writer task collects queued events into batches, closed
+//   when they reach the batch size or the oldest event has waited the
+//   maximum delay, and stores each batch in one transaction. Sending a
+//   message therefore never waits on the database, and at high throughput
+//   one round trip stores many events. Rewritten events, e.g. redacted
+//   ones, are queued again and replace what was stored.
+//
+//   A batch that cannot be written is retried until it is, backing off up
+//   to a maximum delay. Meanwhile the queue fills, and once it holds the
+//   configured number of events requests that may add events wait for it
+//   to drain. At startup the stored timelines are loaded back. Every
+//   stored event and state key gets its short id before it is written.
+//
+// =============================================================================
+
+use std::{
+    fmt::Write,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use futures::TryStreamExt;
+use matrixon_db::{EventRecord, EventRepo};
+use serde_json::Value;
+use tokio::{
+    sync::{mpsc, oneshot, Notify},
+    task::JoinHandle,
+    time::Instant,
+};
+use tracing::{debug, error, info, warn};
+
+use crate::service::{short, timeline};
+
+/// Failed attempts at writing a batch after which failures are logged as
+/// errors; the batch is still retried
+const WRITE_ATTEMPTS: u32 = 3;
+/// Wait before the first retry, doubled for each further one
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+/// Longest wait between retries
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Events read at a time when loading the timelines
+const LOAD_BATCH_SIZE: i64 = 10_000;
+
+#[derive(Debug)]
+enum Message {
+    Event(EventRecord),
+    /// Write the events queued so far, then answer
+    Flush(oneshot::Sender<()>),
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    /// Events queued and not yet written
+    queued: AtomicU64,
+    batches: AtomicU64,
+    events: AtomicU64,
+    /// Attempts at writing a batch that failed
+    failed_writes: AtomicU64,
+    /// Notified whenever a batch is written
+    drained: Notify,
+}
+
+/// Event persistence service
+#[derive(Debug)]
+pub struct Service {
+    /// Unbounded so appending to a timeline never blocks; requests that
+    /// may append wait in [`Service::wait_for_capacity`] instead
+    sender: mpsc::UnboundedSender<Message>,
+    /// Taken by the writer task when it is spawned
+    receiver: Mutex<Option<mpsc::UnboundedReceiver<Message>>>,
+    batch_size: usize,
+    max_delay: Duration,
+    /// Queued events above which requests wait for the queue to drain
+    capacity: u64,
+    stats: Arc<Stats>,
+}
+
+impl Service {
+    pub fn new(batch_size: usize, max_delay: Duration, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let batch_size = batch_size.max(1);
+        Self {
+            sender,
+            receiver: Mutex::new(Some(receiver)),
+            batch_size,
+            max_delay,
+            capacity: capacity.max(batch_size) as u64,
+            stats: Arc::default(),
+        }
+    }
+
+    /// Queue an event of a room timeline at stream position `count` and
+    /// `ordinal`, which orders imported events sharing the position
+    pub fn enqueue(&self, room_id: &str, count: u64, ordinal: u64, event: &Value) {
+        let Some(event_id) = event["event_id"].as_str() else {
+            warn!("⚠️ Not persisting an event without an event id in {}", room_id);
+            return;
+        };
+        let record = EventRecord {
+            event_id: event_id.to_owned(),
+            room_id: room_id.to_owned(),
+            stream_ordering: count as i64,
+            ordinal: ordinal as i64,
+            sender: event["sender"].as_str().unwrap_or_default().to_owned(),
+            event_type: event["type"].as_str().unwrap_or_default().to_owned(),
+            state_key: event["state_key"].as_str().map(str::to_owned),
+            json: event.clone(),
+        };
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        if self.sender.send(Message::Event(record)).is_err() {
+            self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+            error!("❌ Event persistence writer stopped, {} is not persisted", event_id);
+        }
+    }
+
+    /// Events queued and not yet written
+    pub fn queued(&self) -> u64 {
+        self.stats.queued.load(Ordering::Relaxed)
+    }
+
+    /// Wait while the queue is full, before handling a request that may
+    /// append events. Returns at once if no writer was spawned.
+    pub async fn wait_for_capacity(&self) {
+        if self.receiver.lock().unwrap().is_some() {
+            return;
+        }
+        loop {
+            let drained = self.stats.drained.notified();
+            if self.queued() < self.capacity {
+                return;
+            }
+            drained.await;
+        }
+    }
+
+    /// Wait until the events queued so far are written. Returns at once if
+    /// no writer was spawned.
+    pub async fn flush(&self) {
+        if self.receiver.lock().unwrap().is_some() {
+            return;
+        }
+        let (done, written) = oneshot::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = written.await;
+        }
+    }
+
+    /// Spawn the writer task storing batches in `events`, after handing
+    /// out the short ids of their events and state keys. Returns `None`
+    /// if it was already spawned.
+    pub fn spawn_writer(&self, events: EventRepo, short: &'static short::Service) -> Option<JoinHandle<()>> {
+        self.spawn_writer_with(move |batch| {
+            let events = events.clone();
+            async move {
+                for event in &batch {
+                    short.get_or_create_shorteventid(&event.event_id).await.map_err(|e| e.to_string())?;
+                    if let Some(state_key) = &event.state_key {
+                        short
+                            .get_or_create_shortstatekey(&event.event_type, state_key)
+                            .await
+                            .map_err(|e| e.to_string())?;
+                    }
+                }
+                events.insert_batch(&batch).await.map_err(|e| e.to_string())
+            }
+        })
+    }
+
+    /// Spawn the writer task storing batches with `write`
+    pub fn spawn_writer_with<F, Fut>(&self, write: F) -> Option<JoinHandle<()>>
+    where
+        F: FnMut(Vec<EventRecord>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        let receiver = self.receiver.lock().unwrap().take()?;
+        let writer = Writer {
+            batch_size: self.batch_size,
+            max_delay: self.max_delay,
+            stats: self.stats.clone(),
+        };
+        Some(tokio::spawn(writer.run(receiver, write)))
+    }
+
+    /// The writer's counters in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, kind, help, value) in [
+            ("queued_events", "gauge", "Events waiting to be written", &self.stats.queued),
+            ("batches_total", "counter", "Batches of events written", &self.stats.batches),
+            ("events_total", "counter", "Events written", &self.stats.events),
+            ("failed_writes_total", "counter", "Attempts at writing a batch that failed", &self.stats.failed_writes),
+        ] {
+            let _ = writeln!(out, "# HELP matrixon_event_persistence_{name} {help}");
+            let _ = writeln!(out, "# TYPE matrixon_event_persistence_{name} {kind}");
+            let _ = writeln!(out, "matrixon_event_persistence_{name} {}", value.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+struct Writer {
+    batch_size: usize,
+    max_delay: Duration,
+    stats: Arc<Stats>,
+}
+
+impl Writer {
+    async fn run<F, Fut>(self, mut receiver: mpsc::UnboundedReceiver<Message>, mut write: F)
+    where
+        F: FnMut(Vec<EventRecord>) -> Fut,
+        Fut: Future<Output = Result<(), String>>,
+    {
+        while let Some(first) = receiver.recv().await {
+            let mut batch = Vec::new();
+            let mut flushes = Vec::new();
+            let mut message = Some(first);
+            let deadline = Instant::now() + self.max_delay;
+            // A flush closes the batch at once
+            while let Some(next) = message.take() {
+                match next {
+                    Message::Event(event) => batch.push(event),
+                    Message::Flush(done) => {
+                        flushes.push(done);
+                        break;
+                    }
+                }
+                if batch.len() >= self.batch_size {
+                    break;
+                }
+                message = tokio::time::timeout_at(deadline, receiver.recv()).await.ok().flatten();
+            }
+
+            if !batch.is_empty() {
+                self.write_batch(batch, &mut write).await;
+            }
+            for done in flushes {
+                let _ = done.send(());
+            }
+        }
+    }
+
+    async fn write_batch<F, Fut>(&self, batch: Vec<EventRecord>, write: &mut F)
+    where
+        F: FnMut(Vec<EventRecord>) -> Fut,
+        Fut: Future<Output = Result<(), String>>,
+    {
+        let len = batch.len() as u64;
+        let mut delay = RETRY_DELAY;
+        let mut attempts = 0;
+        loop {
+            match write(batch.clone()).await {
+                Ok(()) => {
+                    debug!("💾 Persisted a batch of {} events", len);
+                    self.stats.batches.fetch_add(1, Ordering::Relaxed);
+                    self.stats.events.fetch_add(len, Ordering::Relaxed);
+                    self.stats.queued.fetch_sub(len, Ordering::Relaxed);
+                    self.stats.drained.notify_waiters();
+                    return;
+                }
+                Err(e) => {
+                    attempts += 1;
+                    self.stats.failed_writes.fetch_add(1, Ordering::Relaxed);
+                    if attempts < WRITE_ATTEMPTS {
+                        warn!("⚠️ Persisting {} events failed, retrying: {}", len, e);
+                    } else {
+                        error!("❌ Persisting {} events failed {} times, retrying in {:?}: {}", len, attempts, delay, e);
+                    }
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(MAX_RETRY_DELAY);
+                }
+            }
+        }
+    }
+}
+
+/// Load the stored timelines into `timeline`, returning how many events
+/// were loaded
+pub async fn load_timelines(events: &EventRepo, timeline: &timeline::Service) -> crate::Result<usize> {
+    let mut stored = std::pin::pin!(events.stream(LOAD_BATCH_SIZE));
+    let mut batch = Vec::new();
+    let mut loaded = 0;
+    loop {
+        let event = stored.try_next().await.map_err(|e| crate::Error::BadDatabase(e.to_string()))?;
+        let done = event.is_none();
+        if let Some(event) = event {
+            batch.push((event.room_id, event.stream_ordering.max(0) as u64, event.ordinal.max(0) as u64, event.json));
+        }
+        if done || batch.len() == LOAD_BATCH_SIZE as usize {
+            loaded += batch.len();
+            timeline.restore(batch.drain(..));
+        }
+        if done {
+            break;
+        }
+    }
+    info!("📚 Loaded {} stored timeline events", loaded);
+    Ok(loaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_events_are_written_in_batches() {
+        let service = Service::new(2, Duration::from_secs(60), 100);
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let written = batches.clone();
+        let mut failures = 1;
+        service.spawn_writer_with(move |batch: Vec<EventRecord>| {
+            let result = if failures > 0 {
+                failures -= 1;
+                Err("connection reset".to_owned())
+            } else {
+                written.lock().unwrap().push(batch.iter().map(|event| event.stream_ordering).collect::<Vec<_>>());
+                Ok(())
+            };
+            async move { result }
+        });
+        assert!(service.spawn_writer_with(|_| async { Ok(()) }).is_none());
+
+        for count in 1..=5 {
+            let event = json!({ "event_id": format!("${}", count), "sender": "@a:matrixon.local", "type": "m.room.message" });
+            service.enqueue("!room:matrixon.local", count, 0, &event);
+        }
+        // The last event would wait for the delay; a flush writes it at once
+        service.flush().await;
+
+        // The failed first write was retried
+        assert_eq!(*batches.lock().unwrap(), vec![vec![1, 2], vec![3, 4], vec![5]]);
+        let rendered = service.render();
+        assert!(rendered.contains("matrixon_event_persistence_batches_total 3\n"));
+        assert!(rendered.contains("matrixon_event_persistence_events_total 5\n"));
+        assert!(rendered.contains("matrixon_event_persistence_queued_events 0\n"));
+        assert!(rendered.contains("matrixon_event_persistence_failed_writes_total 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_requests_wait_while_the_queue_is_full() {
+        let service = Service::new(1, Duration::ZERO, 2);
+        let down = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let written = Arc::new(AtomicU64::new(0));
+        let (database, events) = (down.clone(), written.clone());
+        service.spawn_writer_with(move |batch: Vec<EventRecord>| {
+            let result = if database.load(Ordering::SeqCst) {
+                Err("connection refused".to_owned())
+            } else {
+                events.fetch_add(batch.len() as u64, Ordering::SeqCst);
+                Ok(())
+            };
+            async move { result }
+        });
+
+        for count in 1..=2 {
+            let event = json!({ "event_id": format!("${}", count), "sender": "@a:matrixon.local", "type": "m.room.message" });
+            service.enqueue("!room:matrixon.local", count, 0, &event);
+        }
+        assert!(tokio::time::timeout(Duration::from_millis(50), service.wait_for_capacity()).await.is_err());
+
+        // Nothing is given up on while the database is down
+        down.store(false, Ordering::SeqCst);
+        tokio::time::timeout(Duration::from_secs(5), service.wait_for_capacity()).await.unwrap();
+        service.flush().await;
+        assert_eq!(written.load(Ordering::SeqCst), 2);
+        assert_eq!(service.queued(), 0);
+    }
+}
@@ -0,0 +1,372 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Event Reports
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Reports of events by room members, escalated to the room's moderators
+//   once an event collects enough reports within a time window. Moderators
+//   (power level 50 or more, on this server) are told in the moderation
+//   room when the server config names one, otherwise by a direct message
+//   from the server notices user, and the event can be hidden until a
+//   server admin reviews it, though never on the word of a single member.
+//   The server config sets the defaults; rooms override the threshold,
+//   window and hiding with an `im.matrixon.report_escalation` state event.
+//
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ruma::api::client::error::ErrorKind;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::{
+    config::ReportEscalationConfig,
+    service::{
+        membership,
+        timeline::{self, redact_event},
+    },
+    Error, Result,
+};
+
+/// Room state event overriding the escalation settings of the server, with
+/// the keys `threshold`, `window_minutes`, `hide_event` and `enabled`
+pub const ESCALATION_EVENT_TYPE: &str = "im.matrixon.report_escalation";
+
+/// Lowest power level of the moderators reports are escalated to
+pub const MODERATOR_LEVEL: i64 = 50;
+
+/// Distinct recent reporters needed to hide an event, whatever the
+/// threshold of its room
+pub const MIN_HIDING_REPORTERS: usize = 2;
+
+/// One member's report of an event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Report {
+    pub reporter: String,
+    pub reason: Option<String>,
+    pub score: Option<i64>,
+    pub ts: u64,
+}
+
+/// An event with its reports
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportedEvent {
+    pub room_id: String,
+    pub event_id: String,
+    pub reports: Vec<Report>,
+    /// When the moderators were notified
+    pub escalated_at: Option<u64>,
+    /// Whether the event is hidden pending review
+    pub hidden: bool,
+    #[serde(skip)]
+    original: Option<Value>,
+    /// Restored by a server admin, and not hidden again
+    #[serde(skip)]
+    restored: bool,
+}
+
+/// Event reports service
+#[derive(Debug)]
+pub struct Service {
+    config: Option<ReportEscalationConfig>,
+    server_name: String,
+    notices_user: String,
+    events: RwLock<HashMap<String, ReportedEvent>>,
+    /// Direct rooms of the notices user by moderator
+    direct_rooms: RwLock<HashMap<String, String>>,
+}
+
+impl Service {
+    /// Escalation is off unless `config` is set or a room enables it
+    pub fn new(config: Option<ReportEscalationConfig>, server_name: &str) -> Self {
+        let localpart = config.as_ref().map_or("matrixon", |config| config.notices_user.as_str());
+        Self {
+            notices_user: format!("@{}:{}", localpart, server_name),
+            server_name: server_name.to_owned(),
+            config,
+            events: RwLock::new(HashMap::new()),
+            direct_rooms: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Record a report of an event, a repeated report by the same member
+    /// replacing their earlier one, and escalate the event if it now has
+    /// enough recent reports. Returns whether it was escalated.
+    pub fn report(
+        &self,
+        timeline: &timeline::Service,
+        room_id: &str,
+        event_id: &str,
+        reporter: &str,
+        reason: Option<String>,
+        score: Option<i64>,
+    ) -> Result<bool> {
+        if timeline.get_event(room_id, event_id).is_none() {
+            return Err(Error::BadRequest(ErrorKind::NotFound, "Event not found"));
+        }
+        let now = now_millis();
+        let mut events = self.events.write().unwrap();
+        let reported = events.entry(event_id.to_owned()).or_insert_with(|| ReportedEvent {
+            room_id: room_id.to_owned(),
+            event_id: event_id.to_owned(),
+            reports: Vec::new(),
+            escalated_at: None,
+            hidden: false,
+            original: None,
+            restored: false,
+        });
+        reported.reports.retain(|report| report.reporter != reporter);
+        reported.reports.push(Report { reporter: reporter.to_owned(), reason, score, ts: now });
+
+        let Some(settings) = self.settings(timeline, room_id) else {
+            return Ok(false);
+        };
+        let since = now.saturating_sub(settings.window_minutes * 60 * 1000);
+        let recent = reported.reports.iter().filter(|report| report.ts >= since).count();
+        if recent < settings.threshold {
+            return Ok(false);
+        }
+
+        let escalated = reported.escalated_at.is_none();
+        if escalated {
+            info!("🚩 {} in {} was reported {} times, escalating to moderators", event_id, room_id, recent);
+            reported.escalated_at = Some(now);
+            self.notify(timeline, &settings, reported, recent);
+        }
+        if settings.hide_event && !reported.hidden && !reported.restored && recent >= MIN_HIDING_REPORTERS {
+            if let Some(mut event) = timeline.get_event(room_id, event_id) {
+                redact_event(&mut event);
+                reported.original = timeline.replace_event(room_id, event_id, event);
+                reported.hidden = true;
+            }
+        }
+        Ok(escalated)
+    }
+
+    /// Reported events, most recently escalated first, then by event id
+    pub fn reported_events(&self) -> Vec<ReportedEvent> {
+        let mut events: Vec<ReportedEvent> = self.events.read().unwrap().values().cloned().collect();
+        events.sort_by(|a, b| b.escalated_at.cmp(&a.escalated_at).then_with(|| a.event_id.cmp(&b.event_id)));
+        events
+    }
+
+    /// Show a hidden event again after review
+    pub fn restore(&self, timeline: &timeline::Service, event_id: &str) -> Result<()> {
+        let mut events = self.events.write().unwrap();
+        let reported = events
+            .get_mut(event_id)
+            .filter(|reported| reported.hidden)
+            .ok_or(Error::BadRequest(ErrorKind::NotFound, "No hidden event with this id"))?;
+        if let Some(original) = reported.original.take() {
+            timeline.replace_event(&reported.room_id, event_id, original);
+        }
+        reported.hidden = false;
+        reported.restored = true;
+        Ok(())
+    }
+
+    /// Settings in effect for a room, `None` when escalation is off there.
+    /// The moderation room only comes from the server config, so a room
+    /// cannot have its reports sent elsewhere.
+    fn settings(&self, timeline: &timeline::Service, room_id: &str) -> Option<ReportEscalationConfig> {
+        let overrides = timeline
+            .state_event(room_id, ESCALATION_EVENT_TYPE, "")
+            .map(|event| event["content"].clone());
+        let mut settings = match (&self.config, &overrides) {
+            (None, None) => return None,
+            (Some(config), _) => config.clone(),
+            (None, Some(_)) => ReportEscalationConfig::default(),
+        };
+        let Some(overrides) = overrides else {
+            return Some(settings);
+        };
+        if overrides["enabled"] == false {
+            return None;
+        }
+        if let Some(threshold) = overrides["threshold"].as_u64() {
+            settings.threshold = threshold.max(1) as usize;
+        }
+        if let Some(window_minutes) = overrides["window_minutes"].as_u64() {
+            settings.window_minutes = window_minutes;
+        }
+        if let Some(hide_event) = overrides["hide_event"].as_bool() {
+            settings.hide_event = hide_event;
+        }
+        Some(settings)
+    }
+
+    fn notify(&self, timeline: &timeline::Service, settings: &ReportEscalationConfig, reported: &ReportedEvent, recent: usize) {
+        let reasons: Vec<&str> = reported.reports.iter().filter_map(|report| report.reason.as_deref()).collect();
+        let mut body = format!(
+            "Event {} in {} was reported {} times in the last {} minutes.",
+            reported.event_id, reported.room_id, recent, settings.window_minutes
+        );
+        if !reasons.is_empty() {
+            body.push_str(&format!(" Reasons: {}", reasons.join("; ")));
+        }
+        let content = json!({
+            "msgtype": "m.notice",
+            "body": body,
+            "im.matrixon.report": {
+                "room_id": reported.room_id,
+                "event_id": reported.event_id,
+                "reports": recent
+            }
+        });
+
+        if let Some(moderation_room) = &settings.moderation_room {
+            timeline.append_event(moderation_room, &self.notices_user, "m.room.message", None, content);
+            return;
+        }
+        let moderators = moderators(timeline, &reported.room_id, &self.server_name, &self.notices_user);
+        if moderators.is_empty() {
+            warn!("⚠️ {} has no moderators on this server to escalate reports to", reported.room_id);
+        }
+        for moderator in moderators {
+            let room_id = self.direct_room(timeline, &moderator);
+            timeline.append_event(&room_id, &self.notices_user, "m.room.message", None, content.clone());
+        }
+    }
+
+    /// Direct room of the notices user with `user_id`, created and the user
+    /// invited on first use
+    fn direct_room(&self, timeline: &timeline::Service, user_id: &str) -> String {
+        if let Some(room_id) = self.direct_rooms.read().unwrap().get(user_id) {
+            return room_id.clone();
+        }
+        let room_id = format!("!{}:{}", uuid::Uuid::new_v4().simple(), self.server_name);
+        let sender = self.notices_user.as_str();
+        timeline.append_event(&room_id, sender, "m.room.create", Some(""), json!({ "creator": sender, "room_version": "10" }));
+        timeline.append_event(&room_id, sender, "m.room.member", Some(sender), json!({ "membership": "join" }));
+        timeline.append_event(&room_id, sender, "m.room.power_levels", Some(""), json!({ "users": { sender: 100 } }));
+        timeline.append_event(&room_id, sender, "m.room.join_rules", Some(""), json!({ "join_rule": "invite" }));
+        timeline.append_event(&room_id, sender, "m.room.name", Some(""), json!({ "name": "Moderation" }));
+        timeline.append_event(
+            &room_id,
+            sender,
+            "m.room.member",
+            Some(user_id),
+            json!({ "membership": "invite", "is_direct": true }),
+        );
+        self.direct_rooms.write().unwrap().insert(user_id.to_owned(), room_id.clone());
+        room_id
+    }
+}
+
+/// Joined members of this server with at least `MODERATOR_LEVEL` in a room
+fn moderators(timeline: &timeline::Service, room_id: &str, server_name: &str, notices_user: &str) -> Vec<String> {
+    timeline
+        .current_state(room_id)
+        .into_iter()
+        .filter(|event| event["type"] == "m.room.member" && event["content"]["membership"] == "join")
+        .filter_map(|event| event["state_key"].as_str().map(str::to_owned))
+        .filter(|user_id| {
+            let local = user_id.split_once(':').is_some_and(|(_, domain)| domain == server_name);
+            local && user_id != notices_user && membership::power_level_of(timeline, room_id, user_id) >= MODERATOR_LEVEL
+        })
+        .collect()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOM: &str = "!room:matrixon.local";
+    const OWNER: &str = "@owner:matrixon.local";
+
+    fn room(timeline: &timeline::Service) -> String {
+        timeline.append_event(ROOM, OWNER, "m.room.create", Some(""), json!({ "creator": OWNER }));
+        timeline.append_event(ROOM, OWNER, "m.room.member", Some(OWNER), json!({ "membership": "join" }));
+        timeline.append_event(ROOM, OWNER, "m.room.power_levels", Some(""), json!({ "users": { OWNER: 100 } }));
+        for member in ["@a:matrixon.local", "@b:matrixon.local"] {
+            timeline.append_event(ROOM, member, "m.room.member", Some(member), json!({ "membership": "join" }));
+        }
+        timeline.append_event(ROOM, "@a:matrixon.local", "m.room.message", None, json!({ "body": "spam" }))
+    }
+
+    #[test]
+    fn test_reports_escalate_once_past_threshold() {
+        let timeline = timeline::Service::new();
+        let event_id = room(&timeline);
+        let config = ReportEscalationConfig { threshold: 2, hide_event: true, ..Default::default() };
+        let service = Service::new(Some(config), "matrixon.local");
+
+        assert!(!service.report(&timeline, ROOM, &event_id, "@b:matrixon.local", Some("spam".to_owned()), None).unwrap());
+        // The same reporter counts once
+        assert!(!service.report(&timeline, ROOM, &event_id, "@b:matrixon.local", None, Some(-100)).unwrap());
+        assert!(service.report(&timeline, ROOM, &event_id, OWNER, None, None).unwrap());
+        assert!(!service.report(&timeline, ROOM, &event_id, "@c:matrixon.local", None, None).unwrap());
+
+        let direct_room = service.direct_rooms.read().unwrap()[OWNER].clone();
+        let notice = timeline.events_since(&direct_room, 0, 1).0.remove(0);
+        assert_eq!(notice["sender"], "@matrixon:matrixon.local");
+        assert!(direct_room.ends_with(":matrixon.local"));
+        assert_eq!(notice["content"]["im.matrixon.report"]["event_id"], event_id.as_str());
+        assert_eq!(service.direct_rooms.read().unwrap().len(), 1);
+
+        assert_eq!(timeline.get_event(ROOM, &event_id).unwrap()["content"], json!({}));
+        assert!(service.reported_events()[0].hidden);
+        service.restore(&timeline, &event_id).unwrap();
+        assert_eq!(timeline.get_event(ROOM, &event_id).unwrap()["content"]["body"], "spam");
+        assert!(service.restore(&timeline, &event_id).is_err());
+        service.report(&timeline, ROOM, &event_id, "@d:matrixon.local", None, None).unwrap();
+        assert_eq!(timeline.get_event(ROOM, &event_id).unwrap()["content"]["body"], "spam");
+    }
+
+    #[test]
+    fn test_rooms_override_server_settings() {
+        let timeline = timeline::Service::new();
+        let event_id = room(&timeline);
+        let service = Service::new(None, "matrixon.local");
+        assert!(!service.report(&timeline, ROOM, &event_id, "@b:matrixon.local", None, None).unwrap());
+        assert!(service.report(&timeline, ROOM, "$unknown", "@b:matrixon.local", None, None).is_err());
+
+        timeline.append_event(
+            ROOM,
+            OWNER,
+            ESCALATION_EVENT_TYPE,
+            Some(""),
+            json!({ "threshold": 1, "hide_event": true, "moderation_room": "!elsewhere:matrixon.local" }),
+        );
+        let other = timeline.append_event(ROOM, "@a:matrixon.local", "m.room.message", None, json!({ "body": "more spam" }));
+        assert!(service.report(&timeline, ROOM, &other, OWNER, None, None).unwrap());
+        // Reports stay with the moderators of the room
+        assert!(timeline.events_since("!elsewhere:matrixon.local", 0, 10).0.is_empty());
+        assert!(service.direct_rooms.read().unwrap().contains_key(OWNER));
+        // A single reporter does not hide the event, a second one does
+        assert_eq!(timeline.get_event(ROOM, &other).unwrap()["content"]["body"], "more spam");
+        assert!(!service.report(&timeline, ROOM, &other, "@b:matrixon.local", None, None).unwrap());
+        assert_eq!(timeline.get_event(ROOM, &other).unwrap()["content"], json!({}));
+    }
+
+    #[test]
+    fn test_configured_moderation_room_is_notified() {
+        let timeline = timeline::Service::new();
+        let event_id = room(&timeline);
+        let config = ReportEscalationConfig {
+            threshold: 1,
+            moderation_room: Some("!mods:matrixon.local".to_owned()),
+            ..Default::default()
+        };
+        let service = Service::new(Some(config), "matrixon.local");
+        assert!(service.report(&timeline, ROOM, &event_id, "@b:matrixon.local", None, None).unwrap());
+        assert_eq!(timeline.events_since("!mods:matrixon.local", 0, 10).0.len(), 1);
+        assert!(service.direct_rooms.read().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,350 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Federated Room History
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Room history served to other servers. Only servers with a member in the
+//   room may read it, and events the room's history visibility hides from
+//   the requesting server at the time they were sent are handed out in
+//   their redacted form, the way Synapse does, so the room graph stays
+//   complete. Gaps a server noticed in the room graph are filled by
+//   walking `prev_events` back from the events it has; locally created
+//   events, which carry none, follow the one appended before them.
+//
+// =============================================================================
+
+use std::collections::{BinaryHeap, HashSet};
+
+use ruma::api::client::error::ErrorKind;
+use serde_json::{json, Value};
+
+use crate::{
+    service::{federation_membership, inbound_federation, server_keys, timeline},
+    Error, Result,
+};
+
+/// Most events returned by one backfill request
+pub const MAX_BACKFILL: usize = 100;
+
+/// Most events returned by one get_missing_events request
+pub const MAX_MISSING_EVENTS: usize = 20;
+
+fn server_name(user_id: &str) -> Option<&str> {
+    user_id.split_once(':').map(|(_, server)| server)
+}
+
+/// Whether any user of `server` has one of `memberships` in `state`
+fn server_has_member(state: &[Value], server: &str, memberships: &[&str]) -> bool {
+    state.iter().any(|event| {
+        event["type"] == "m.room.member"
+            && event["state_key"].as_str().and_then(server_name) == Some(server)
+            && event["content"]["membership"].as_str().is_some_and(|membership| memberships.contains(&membership))
+    })
+}
+
+/// Check that `server` may read the history of a room: it must currently
+/// have a joined member there
+pub fn check_server_in_room(timeline: &timeline::Service, room_id: &str, server: &str) -> Result<()> {
+    if !timeline.room_exists(room_id) {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+    }
+    if !server_has_member(&timeline.current_state(room_id), server, &["join"]) {
+        return Err(Error::BadRequest(ErrorKind::forbidden(), "The server is not in this room"));
+    }
+    Ok(())
+}
+
+/// Whether `server` may see the event at `position` according to the
+/// history visibility in effect when it was sent
+pub fn server_can_see(timeline: &timeline::Service, room_id: &str, position: usize, server: &str) -> bool {
+    let state = timeline.state_before(room_id, position);
+    let visibility = state
+        .iter()
+        .find(|event| event["type"] == "m.room.history_visibility" && event["state_key"] == "")
+        .and_then(|event| event["content"]["history_visibility"].as_str())
+        .unwrap_or("shared");
+    match visibility {
+        "invited" => server_has_member(&state, server, &["join", "invite"]),
+        "joined" => server_has_member(&state, server, &["join"]),
+        _ => true,
+    }
+}
+
+/// Answer `GET /backfill`: up to `limit` events ending with the latest of
+/// `event_ids`, newest first, as federation PDUs
+pub fn backfill(
+    timeline: &timeline::Service,
+    server_keys: &server_keys::Service,
+    own_server: &str,
+    origin: &str,
+    room_id: &str,
+    event_ids: &[String],
+    limit: usize,
+) -> Result<Vec<Value>> {
+    check_server_in_room(timeline, room_id, origin)?;
+    let Some(end) = event_ids.iter().filter_map(|event_id| timeline.position(room_id, event_id)).max() else {
+        return Ok(Vec::new());
+    };
+
+    let room_version = federation_membership::room_version(timeline, room_id);
+    let (events, _) = timeline.paginate(room_id, Some(end + 1), timeline::Direction::Backward, limit.min(MAX_BACKFILL));
+    let pdus = events
+        .iter()
+        .enumerate()
+        .map(|(offset, event)| {
+            let position = end - offset;
+            let mut pdu = federation_membership::federation_pdu(server_keys, own_server, &room_version, event);
+            if !server_can_see(timeline, room_id, position, origin) {
+                timeline::redact_event(&mut pdu);
+            }
+            pdu
+        })
+        .collect();
+    Ok(pdus)
+}
+
+/// Answer `GET /state`: the room state in effect before `event_id` and its
+/// auth chain, as federation PDUs
+pub fn state_at_event(
+    timeline: &timeline::Service,
+    inbound: &inbound_federation::Service,
+    server_keys: &server_keys::Service,
+    own_server: &str,
+    origin: &str,
+    room_id: &str,
+    event_id: &str,
+) -> Result<Value> {
+    check_server_in_room(timeline, room_id, origin)?;
+    let position = timeline
+        .position(room_id, event_id)
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Unknown event"))?;
+    if !server_can_see(timeline, room_id, position, origin) {
+        return Err(Error::BadRequest(ErrorKind::forbidden(), "The server may not see this event"));
+    }
+    let room_version = federation_membership::room_version(timeline, room_id);
+    let state = timeline.state_before(room_id, position);
+    let (pdus, auth_chain) =
+        federation_membership::state_pdus_and_auth_chain(timeline, inbound, server_keys, own_server, room_id, &room_version, &state);
+    Ok(json!({ "pdus": pdus, "auth_chain": auth_chain }))
+}
+
+/// Answer `GET /event_auth`: the auth chain of `event_id`, as federation
+/// PDUs
+pub fn event_auth(
+    timeline: &timeline::Service,
+    inbound: &inbound_federation::Service,
+    server_keys: &server_keys::Service,
+    own_server: &str,
+    origin: &str,
+    room_id: &str,
+    event_id: &str,
+) -> Result<Vec<Value>> {
+    check_server_in_room(timeline, room_id, origin)?;
+    let position = timeline
+        .position(room_id, event_id)
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Unknown event"))?;
+    if !server_can_see(timeline, room_id, position, origin) {
+        return Err(Error::BadRequest(ErrorKind::forbidden(), "The server may not see this event"));
+    }
+    let room_version = federation_membership::room_version(timeline, room_id);
+    let auth_chain = inbound
+        .auth_chain(timeline, room_id, &[event_id.to_owned()])
+        .iter()
+        .map(|event| federation_membership::federation_pdu(server_keys, own_server, &room_version, event))
+        .collect();
+    Ok(auth_chain)
+}
+
+/// Depth of an event: its `depth` field, or one more than its position for
+/// local events
+fn depth(event: &Value, position: usize) -> u64 {
+    event["depth"].as_u64().unwrap_or(position as u64 + 1)
+}
+
+/// Positions of the events preceding the one at `position` in the room graph
+fn prev_positions(timeline: &timeline::Service, room_id: &str, event: &Value, position: usize) -> Vec<usize> {
+    match event["prev_events"].as_array() {
+        // Room versions 1 and 2 reference `[event_id, hashes]` pairs
+        Some(prev_events) => prev_events
+            .iter()
+            .filter_map(|prev| prev.as_str().or_else(|| prev[0].as_str()))
+            .filter_map(|event_id| timeline.position(room_id, event_id))
+            .collect(),
+        None => position.checked_sub(1).into_iter().collect(),
+    }
+}
+
+/// Answer `POST /get_missing_events`: up to `limit` events preceding
+/// `latest_events` that are not reachable from `earliest_events`, no deeper
+/// than `min_depth`, oldest first, as federation PDUs
+#[allow(clippy::too_many_arguments)]
+pub fn get_missing_events(
+    timeline: &timeline::Service,
+    server_keys: &server_keys::Service,
+    own_server: &str,
+    origin: &str,
+    room_id: &str,
+    earliest_events: &[String],
+    latest_events: &[String],
+    limit: usize,
+    min_depth: u64,
+) -> Result<Vec<Value>> {
+    check_server_in_room(timeline, room_id, origin)?;
+    let limit = limit.min(MAX_MISSING_EVENTS);
+
+    let mut seen: HashSet<usize> = earliest_events
+        .iter()
+        .chain(latest_events)
+        .filter_map(|event_id| timeline.position(room_id, event_id))
+        .collect();
+    // Walk the newest unseen predecessor first, like Synapse does
+    let mut queue: BinaryHeap<usize> = latest_events
+        .iter()
+        .filter_map(|event_id| timeline.position(room_id, event_id))
+        .flat_map(|position| {
+            let event = timeline.event_at(room_id, position).unwrap_or_default();
+            prev_positions(timeline, room_id, &event, position)
+        })
+        .collect();
+
+    let mut found = Vec::new();
+    while found.len() < limit {
+        let Some(position) = queue.pop() else {
+            break;
+        };
+        if !seen.insert(position) {
+            continue;
+        }
+        let Some(event) = timeline.event_at(room_id, position) else {
+            continue;
+        };
+        if depth(&event, position) < min_depth {
+            continue;
+        }
+        queue.extend(prev_positions(timeline, room_id, &event, position));
+        found.push((position, event));
+    }
+    found.sort_by_key(|(position, _)| *position);
+
+    let room_version = federation_membership::room_version(timeline, room_id);
+    let pdus = found
+        .into_iter()
+        .map(|(position, event)| {
+            let mut pdu = federation_membership::federation_pdu(server_keys, own_server, &room_version, &event);
+            if !server_can_see(timeline, room_id, position, origin) {
+                timeline::redact_event(&mut pdu);
+            }
+            pdu
+        })
+        .collect();
+    Ok(pdus)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const OWN: &str = "matrixon.local";
+    const ROOM: &str = "!room:matrixon.local";
+    const OWNER: &str = "@owner:matrixon.local";
+    const BOB: &str = "@bob:remote.example";
+
+    #[test]
+    fn test_backfill_hides_history_before_join() {
+        let timeline = timeline::Service::new();
+        let keys = server_keys::Service::load(OWN, None).unwrap();
+        timeline.append_event(ROOM, OWNER, "m.room.create", Some(""), json!({ "creator": OWNER, "room_version": "10" }));
+        timeline.append_event(ROOM, OWNER, "m.room.member", Some(OWNER), json!({ "membership": "join" }));
+        timeline.append_event(ROOM, OWNER, "m.room.history_visibility", Some(""), json!({ "history_visibility": "joined" }));
+        let secret = timeline.append_event(ROOM, OWNER, "m.room.message", None, json!({ "body": "secret" }));
+        assert!(backfill(&timeline, &keys, OWN, "remote.example", ROOM, &[secret.clone()], 10).is_err());
+
+        timeline.append_event(ROOM, BOB, "m.room.member", Some(BOB), json!({ "membership": "join" }));
+        let hello = timeline.append_event(ROOM, OWNER, "m.room.message", None, json!({ "body": "hello" }));
+
+        let pdus = backfill(&timeline, &keys, OWN, "remote.example", ROOM, &[secret.clone(), hello.clone()], 3).unwrap();
+        assert_eq!(pdus.len(), 3);
+        assert_eq!(pdus[0]["content"]["body"], "hello");
+        assert_eq!(pdus[1]["content"]["membership"], "join");
+        assert_eq!(pdus[2]["type"], "m.room.message");
+        assert!(pdus[2]["content"].get("body").is_none());
+        assert!(pdus[2]["signatures"][OWN].is_object());
+
+        let pdus = backfill(&timeline, &keys, OWN, "remote.example", ROOM, &[secret], 500).unwrap();
+        assert_eq!(pdus.len(), 4);
+        assert!(backfill(&timeline, &keys, OWN, "remote.example", ROOM, &["$unknown".to_owned()], 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_state_at_event() {
+        let timeline = timeline::Service::new();
+        let inbound = inbound_federation::Service::new();
+        let keys = server_keys::Service::load(OWN, None).unwrap();
+        timeline.append_event(ROOM, OWNER, "m.room.create", Some(""), json!({ "creator": OWNER, "room_version": "10" }));
+        timeline.append_event(ROOM, OWNER, "m.room.member", Some(OWNER), json!({ "membership": "join" }));
+        timeline.append_event(ROOM, OWNER, "m.room.name", Some(""), json!({ "name": "Before" }));
+        let join = timeline.append_event(ROOM, BOB, "m.room.member", Some(BOB), json!({ "membership": "join" }));
+        let after = timeline.append_event(ROOM, OWNER, "m.room.name", Some(""), json!({ "name": "After" }));
+
+        let state = state_at_event(&timeline, &inbound, &keys, OWN, "remote.example", ROOM, &join).unwrap();
+        let pdus = state["pdus"].as_array().unwrap();
+        assert_eq!(pdus.len(), 3);
+        assert!(pdus.iter().any(|pdu| pdu["content"]["name"] == "Before"));
+        assert!(pdus.iter().all(|pdu| pdu["state_key"] != BOB));
+        assert_eq!(state["auth_chain"].as_array().unwrap().len(), 2);
+
+        let auth_chain = event_auth(&timeline, &inbound, &keys, OWN, "remote.example", ROOM, &after).unwrap();
+        assert_eq!(auth_chain.len(), 2);
+        assert_eq!(auth_chain[0]["type"], "m.room.create");
+        assert!(auth_chain.iter().all(|pdu| pdu["type"] != "m.room.name"));
+
+        assert!(state_at_event(&timeline, &inbound, &keys, OWN, "other.example", ROOM, &join).is_err());
+        assert!(state_at_event(&timeline, &inbound, &keys, OWN, "remote.example", ROOM, "$unknown").is_err());
+    }
+
+    #[test]
+    fn test_get_missing_events_stops_at_earliest() {
+        let timeline = timeline::Service::new();
+        let keys = server_keys::Service::load(OWN, None).unwrap();
+        timeline.append_event(ROOM, OWNER, "m.room.create", Some(""), json!({ "creator": OWNER, "room_version": "10" }));
+        timeline.append_event(ROOM, OWNER, "m.room.member", Some(OWNER), json!({ "membership": "join" }));
+        timeline.append_event(ROOM, BOB, "m.room.member", Some(BOB), json!({ "membership": "join" }));
+        let earliest = timeline.append_event(ROOM, OWNER, "m.room.message", None, json!({ "body": "seen" }));
+        let first = timeline.append_event(ROOM, OWNER, "m.room.message", None, json!({ "body": "one" }));
+        timeline.append_event(ROOM, OWNER, "m.room.message", None, json!({ "body": "two" }));
+        let latest = timeline.append_event(ROOM, OWNER, "m.room.message", None, json!({ "body": "latest" }));
+
+        let missing = |limit, min_depth| {
+            get_missing_events(
+                &timeline,
+                &keys,
+                OWN,
+                "remote.example",
+                ROOM,
+                &[earliest.clone()],
+                &[latest.clone()],
+                limit,
+                min_depth,
+            )
+            .unwrap()
+        };
+        let pdus = missing(10, 0);
+        assert_eq!(pdus.len(), 2);
+        assert_eq!(pdus[0]["content"]["body"], "one");
+        assert_eq!(pdus[1]["content"]["body"], "two");
+
+        let pdus = missing(1, 0);
+        assert_eq!(pdus.len(), 1);
+        assert_eq!(pdus[0]["content"]["body"], "two");
+        assert_eq!(missing(10, 6).len(), 1);
+
+        assert!(get_missing_events(&timeline, &keys, OWN, "other.example", ROOM, &[], &[first], 10, 0).is_err());
+    }
+}
@@ -0,0 +1,1185 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Federated Membership
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Membership changes of users on other servers in rooms hosted here, using
+//   the two-step make_/send_ handshake: the remote server asks for an event
+//   template, signs the completed event and sends it back. Joins honour the
+//   room's join rules, including restricted rooms, where this server vouches
+//   for the join by signing it on behalf of one of its users. Accepted joins
+//   are answered with the room state and its auth chain. Leaves work the
+//   same way for users who are joined, invited or knocking. Invites of
+//   local users are single-step: the inviting server sends the event and
+//   gets it back with this server's signature added, once the inviter's
+//   power level and the invitee's membership allow it in rooms hosted
+//   here. Local users join rooms this server is not in through the same
+//   handshake, trying the resident servers in turn until one can authorise
+//   the join, and knock on them the same way. Large rooms are joined with
+//   partial state: the membership events are left out of the
+//   send_join response and fetched in the background afterwards.
+//
+// =============================================================================
+
+use std::{
+    collections::BTreeSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ruma::{api::client::error::ErrorKind, signatures::Verified, CanonicalJsonValue, RoomVersionId};
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use crate::{
+    service::{inbound_federation, key_fetcher, membership, room_versions, server_keys, timeline},
+    services, Error, Result,
+};
+
+/// Wait before fetching the full state of a partial state room again after
+/// every server failed, doubled for each further attempt up to
+/// `MAX_RESYNC_DELAY`
+const RESYNC_DELAY: Duration = Duration::from_secs(5);
+const MAX_RESYNC_DELAY: Duration = Duration::from_secs(600);
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+fn server_name(user_id: &str) -> Option<&str> {
+    user_id.split_once(':').map(|(_, server)| server)
+}
+
+fn membership_in(timeline: &timeline::Service, room_id: &str, user_id: &str) -> Option<String> {
+    timeline
+        .state_event(room_id, "m.room.member", user_id)
+        .and_then(|event| event["content"]["membership"].as_str().map(str::to_owned))
+}
+
+/// Room version of a local room, from its create event
+pub fn room_version(timeline: &timeline::Service, room_id: &str) -> String {
+    timeline
+        .state_event(room_id, "m.room.create", "")
+        .and_then(|create| create["content"]["room_version"].as_str().map(str::to_owned))
+        .unwrap_or_else(|| "1".to_owned())
+}
+
+/// References to events in `prev_events` / `auth_events`; rooms before
+/// version 3 pair each id with its hashes
+pub fn event_references(room_version: &str, event_ids: Vec<String>) -> Value {
+    match room_version {
+        "1" | "2" => event_ids.into_iter().map(|event_id| json!([event_id, {}])).collect(),
+        _ => json!(event_ids),
+    }
+}
+
+/// An event as other servers expect it: events of this server that were
+/// not signed when created are hashed and signed, others are passed on as
+/// they were signed
+pub fn federation_pdu(server_keys: &server_keys::Service, own_server: &str, room_version: &str, event: &Value) -> Value {
+    if event["sender"].as_str().and_then(server_name) == Some(own_server) && event.get("signatures").is_none() {
+        if let Ok(pdu) = server_keys.sign_pdu(room_version, event) {
+            return pdu;
+        }
+    }
+    let mut pdu = event.clone();
+    if let Some(object) = pdu.as_object_mut() {
+        object.remove("unsigned");
+        if !matches!(room_version, "1" | "2") {
+            object.remove("event_id");
+        }
+    }
+    pdu
+}
+
+/// Current state of a room and its auth chain, as federation PDUs
+pub fn state_and_auth_chain(
+    timeline: &timeline::Service,
+    inbound: &inbound_federation::Service,
+    server_keys: &server_keys::Service,
+    own_server: &str,
+    room_id: &str,
+) -> (Vec<Value>, Vec<Value>) {
+    let room_version = room_version(timeline, room_id);
+    let state = timeline.current_state(room_id);
+    state_pdus_and_auth_chain(timeline, inbound, server_keys, own_server, room_id, &room_version, &state)
+}
+
+/// `state` of a room and its auth chain, as federation PDUs
+pub fn state_pdus_and_auth_chain(
+    timeline: &timeline::Service,
+    inbound: &inbound_federation::Service,
+    server_keys: &server_keys::Service,
+    own_server: &str,
+    room_id: &str,
+    room_version: &str,
+    state: &[Value],
+) -> (Vec<Value>, Vec<Value>) {
+    let state_ids: Vec<String> = state.iter().filter_map(|event| event["event_id"].as_str().map(str::to_owned)).collect();
+    let auth_chain = inbound
+        .auth_chain(timeline, room_id, &state_ids)
+        .iter()
+        .map(|event| federation_pdu(server_keys, own_server, room_version, event))
+        .collect();
+    let state = state
+        .iter()
+        .map(|event| federation_pdu(server_keys, own_server, room_version, event))
+        .collect();
+    (state, auth_chain)
+}
+
+/// Check that `user_id` may join, returning the local user authorising the
+/// join if it relies on a restricted join rule
+fn check_join(timeline: &timeline::Service, own_server: &str, room_id: &str, user_id: &str) -> Result<Option<String>> {
+    let join_rule = timeline
+        .state_event(room_id, "m.room.join_rules", "")
+        .and_then(|event| event["content"]["join_rule"].as_str().map(str::to_owned))
+        .unwrap_or_else(|| "invite".to_owned());
+
+    match membership_in(timeline, room_id, user_id).as_deref() {
+        Some("ban") => Err(Error::BadRequest(ErrorKind::forbidden(), "The user is banned from this room")),
+        Some("join") | Some("invite") => Ok(None),
+        _ if join_rule == "public" => Ok(None),
+        _ => {
+            let Some(allowed) = membership::allowed_rooms(timeline, room_id) else {
+                return Err(Error::BadRequest(ErrorKind::forbidden(), "The user is not invited to this room"));
+            };
+            // Without being in an allowed room this server cannot tell
+            // whether the user is; another resident server may
+            if !allowed.iter().any(|allowed_room| timeline.room_exists(allowed_room)) {
+                return Err(Error::BadRequest(
+                    ErrorKind::UnableToAuthorizeJoin,
+                    "This server is not in any room that gives access to this room",
+                ));
+            }
+            if !membership::in_allowed_room(timeline, &allowed, user_id) {
+                return Err(Error::BadRequest(ErrorKind::forbidden(), "The user is not allowed to join this room"));
+            }
+            match membership::restricted_join_authoriser(timeline, room_id, user_id, own_server) {
+                Some(authoriser) => Ok(Some(authoriser)),
+                None => Err(Error::BadRequest(
+                    ErrorKind::UnableToAuthorizeJoin,
+                    "No user of this server can authorise the join",
+                )),
+            }
+        }
+    }
+}
+
+/// Build the template of a member event of `user_id` with `membership`,
+/// answering a make_join / make_leave / make_knock request
+pub fn make_membership_template(
+    timeline: &timeline::Service,
+    room_id: &str,
+    user_id: &str,
+    membership: &str,
+    mut content: Value,
+) -> Value {
+    let room_version = room_version(timeline, room_id);
+    let (latest, _) = timeline.paginate(room_id, None, timeline::Direction::Backward, 1);
+    let depth = timeline.end_position(room_id);
+    let prev_events = latest.iter().filter_map(|event| event["event_id"].as_str().map(str::to_owned)).collect();
+
+    let mut auth_keys = vec![
+        ("m.room.create", ""),
+        ("m.room.power_levels", ""),
+        ("m.room.join_rules", ""),
+        ("m.room.member", user_id),
+    ];
+    if let Some(authoriser) = content["join_authorised_via_users_server"].as_str() {
+        auth_keys.push(("m.room.member", authoriser));
+    }
+    let auth_events = auth_keys
+        .into_iter()
+        .filter_map(|(event_type, state_key)| timeline.state_event(room_id, event_type, state_key))
+        .filter_map(|event| event["event_id"].as_str().map(str::to_owned))
+        .collect();
+
+    content["membership"] = json!(membership);
+    json!({
+        "room_id": room_id,
+        "sender": user_id,
+        "origin": server_name(user_id),
+        "origin_server_ts": now_millis(),
+        "type": "m.room.member",
+        "state_key": user_id,
+        "content": content,
+        "depth": depth,
+        "prev_events": event_references(&room_version, prev_events),
+        "auth_events": event_references(&room_version, auth_events),
+    })
+}
+
+/// The version of a local room, if the remote server supports it according
+/// to the `ver` parameters of its request
+fn check_remote_supports_version(timeline: &timeline::Service, room_id: &str, supported_versions: &[String]) -> Result<String> {
+    // Servers that do not say which versions they support only know version 1
+    let room_version = room_version(timeline, room_id);
+    let supported = supported_versions.contains(&room_version) || (supported_versions.is_empty() && room_version == "1");
+    if !supported {
+        let room_version = RoomVersionId::try_from(room_version.as_str()).unwrap_or(RoomVersionId::V1);
+        return Err(Error::BadRequest(
+            ErrorKind::IncompatibleRoomVersion { room_version },
+            "Your server does not support the version of this room",
+        ));
+    }
+    Ok(room_version)
+}
+
+/// Answer `GET /make_join`: the join event template for `user_id`, if the
+/// user may join and the remote server supports the room version
+pub fn make_join(
+    timeline: &timeline::Service,
+    own_server: &str,
+    room_id: &str,
+    user_id: &str,
+    supported_versions: &[String],
+) -> Result<Value> {
+    if !timeline.room_exists(room_id) {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+    }
+    room_versions::check_supported(&room_version(timeline, room_id))?;
+    let room_version = check_remote_supports_version(timeline, room_id, supported_versions)?;
+
+    let mut content = json!({});
+    if let Some(authoriser) = check_join(timeline, own_server, room_id, user_id)? {
+        content["join_authorised_via_users_server"] = json!(authoriser);
+    }
+    let event = make_membership_template(timeline, room_id, user_id, "join", content);
+    Ok(json!({ "room_version": room_version, "event": event }))
+}
+
+/// Check a member event sent back through send_join / send_leave /
+/// send_knock: it must be the given membership of a user of `origin` in
+/// the room, and `event_id` must be its id. Returns the room version.
+pub fn check_membership_event(
+    timeline: &timeline::Service,
+    origin: &str,
+    room_id: &str,
+    event_id: &str,
+    pdu: &Value,
+    membership: &str,
+) -> Result<RoomVersionId> {
+    if !timeline.room_exists(room_id) {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+    }
+    room_versions::check_supported(&room_version(timeline, room_id))?;
+    let sender = pdu["sender"].as_str().unwrap_or_default();
+    if pdu["room_id"] != room_id
+        || pdu["type"] != "m.room.member"
+        || pdu["state_key"] != sender
+        || pdu["content"]["membership"] != membership
+    {
+        return Err(Error::BadRequest(ErrorKind::InvalidParam, "Not a membership event of the expected kind"));
+    }
+    if server_name(sender) != Some(origin) {
+        return Err(Error::BadRequest(ErrorKind::forbidden(), "The sender does not belong to the origin server"));
+    }
+    let (computed_id, room_version) =
+        inbound_federation::pdu_event_id(pdu, timeline).map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid event"))?;
+    if computed_id != event_id {
+        return Err(Error::BadRequest(ErrorKind::InvalidParam, "The event id does not match the event"));
+    }
+    Ok(room_version)
+}
+
+/// Servers with a joined member in `state`
+fn servers_in_room(state: &[Value]) -> BTreeSet<String> {
+    state
+        .iter()
+        .filter(|event| event["type"] == "m.room.member" && event["content"]["membership"] == "join")
+        .filter_map(|event| event["state_key"].as_str().and_then(server_name).map(str::to_owned))
+        .collect()
+}
+
+/// Answer `PUT /send_join`: check and store the join event, returning the
+/// room state before the join, its auth chain and, for restricted joins,
+/// the event with this server's signature added. With `omit_members` the
+/// membership events of other users are left out of the state and the
+/// servers in the room are listed instead.
+#[allow(clippy::too_many_arguments)]
+pub fn send_join(
+    timeline: &timeline::Service,
+    inbound: &inbound_federation::Service,
+    server_keys: &server_keys::Service,
+    own_server: &str,
+    origin: &str,
+    room_id: &str,
+    event_id: &str,
+    pdu: &Value,
+    omit_members: bool,
+) -> Result<Value> {
+    let room_version = check_membership_event(timeline, origin, room_id, event_id, pdu, "join")?;
+    let sender = pdu["sender"].as_str().unwrap_or_default();
+    let expected_authoriser = check_join(timeline, own_server, room_id, sender)?;
+
+    let mut event = pdu.clone();
+    let authoriser = pdu["content"]["join_authorised_via_users_server"].as_str();
+    if let Some(authoriser) = authoriser {
+        if server_name(authoriser) != Some(own_server) || !membership::can_authorise_joins(timeline, room_id, authoriser) {
+            return Err(Error::BadRequest(ErrorKind::forbidden(), "The join is not authorised by a user of this server"));
+        }
+        event = server_keys.sign_pdu(room_version.as_str(), pdu)?;
+        inbound.add_server_keys(own_server, [(server_keys.key_id(), server_keys.public_key())].into());
+    } else if expected_authoriser.is_some() {
+        return Err(Error::BadRequest(ErrorKind::forbidden(), "Restricted joins must name an authorising user"));
+    }
+
+    let mut state = timeline.current_state(room_id);
+    let servers = servers_in_room(&state);
+    if omit_members {
+        state.retain(|event| event["type"] != "m.room.member" || event["state_key"] == sender);
+    }
+    let (state, auth_chain) = state_pdus_and_auth_chain(timeline, inbound, server_keys, own_server, room_id, room_version.as_str(), &state);
+    inbound
+        .handle_pdu(&event, event_id, &room_version, timeline)
+        .map_err(|_| Error::BadRequest(ErrorKind::forbidden(), "The join event was rejected"))?;
+    info!("🚪 {} joined {} over federation", sender, room_id);
+
+    let mut response = json!({
+        "origin": own_server,
+        "state": state,
+        "auth_chain": auth_chain,
+        "members_omitted": omit_members,
+    });
+    if omit_members {
+        response["servers_in_room"] = json!(servers);
+    }
+    if authoriser.is_some() {
+        response["event"] = event;
+    }
+    Ok(response)
+}
+
+/// Check that `user_id` is in a state a leave event can end: joined,
+/// invited or knocking
+fn check_leave(timeline: &timeline::Service, room_id: &str, user_id: &str) -> Result<()> {
+    if !timeline.room_exists(room_id) {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+    }
+    match membership_in(timeline, room_id, user_id).as_deref() {
+        Some("join") | Some("invite") | Some("knock") => Ok(()),
+        _ => Err(Error::BadRequest(ErrorKind::forbidden(), "The user is not in this room")),
+    }
+}
+
+/// Answer `GET /make_leave`: the leave event template for `user_id`
+pub fn make_leave(timeline: &timeline::Service, room_id: &str, user_id: &str) -> Result<Value> {
+    check_leave(timeline, room_id, user_id)?;
+    let event = make_membership_template(timeline, room_id, user_id, "leave", json!({}));
+    Ok(json!({ "room_version": room_version(timeline, room_id), "event": event }))
+}
+
+/// Answer `PUT /send_leave`: check and store the leave event
+pub fn send_leave(
+    timeline: &timeline::Service,
+    inbound: &inbound_federation::Service,
+    origin: &str,
+    room_id: &str,
+    event_id: &str,
+    pdu: &Value,
+) -> Result<()> {
+    let room_version = check_membership_event(timeline, origin, room_id, event_id, pdu, "leave")?;
+    let sender = pdu["sender"].as_str().unwrap_or_default();
+    if membership_in(timeline, room_id, sender).as_deref() == Some("leave") {
+        return Ok(());
+    }
+    check_leave(timeline, room_id, sender)?;
+    inbound
+        .handle_pdu(pdu, event_id, &room_version, timeline)
+        .map_err(|_| Error::BadRequest(ErrorKind::forbidden(), "The leave event was rejected"))?;
+    info!("🚪 {} left {} over federation", sender, room_id);
+    Ok(())
+}
+
+/// Answer `GET /make_knock`: the knock event template for `user_id`, if the
+/// room accepts knocks and the remote server supports its version
+pub fn make_knock(timeline: &timeline::Service, room_id: &str, user_id: &str, supported_versions: &[String]) -> Result<Value> {
+    if !timeline.room_exists(room_id) {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+    }
+    let room_version = check_remote_supports_version(timeline, room_id, supported_versions)?;
+    membership::check_knock(timeline, room_id, user_id)?;
+    let event = make_membership_template(timeline, room_id, user_id, "knock", json!({}));
+    Ok(json!({ "room_version": room_version, "event": event }))
+}
+
+/// Answer `PUT /send_knock`: check and store the knock event, returning the
+/// stripped room state for the knocking user's client
+pub fn send_knock(
+    timeline: &timeline::Service,
+    inbound: &inbound_federation::Service,
+    origin: &str,
+    room_id: &str,
+    event_id: &str,
+    pdu: &Value,
+) -> Result<Value> {
+    let room_version = check_membership_event(timeline, origin, room_id, event_id, pdu, "knock")?;
+    let sender = pdu["sender"].as_str().unwrap_or_default();
+    if timeline.get_event(room_id, event_id).is_none() {
+        membership::check_knock(timeline, room_id, sender)?;
+        inbound
+            .handle_pdu(pdu, event_id, &room_version, timeline)
+            .map_err(|_| Error::BadRequest(ErrorKind::forbidden(), "The knock event was rejected"))?;
+        info!("🚪 {} knocked on {} over federation", sender, room_id);
+    }
+    Ok(json!({ "knock_room_state": membership::stripped_state_in(timeline, room_id, sender) }))
+}
+
+/// A state event reduced to the keys shown to users who are not in the room
+fn strip_state_event(event: &Value) -> Value {
+    json!({
+        "type": event["type"],
+        "state_key": event["state_key"],
+        "sender": event["sender"],
+        "content": event["content"],
+    })
+}
+
+/// Answer `PUT /invite`: check the invite of a local user, sign it and
+/// return it. Invites to rooms hosted here go into the room; for rooms on
+/// other servers the stripped `invite_room_state` is kept for the invitee's
+/// next /sync.
+#[allow(clippy::too_many_arguments)]
+pub fn invite(
+    timeline: &timeline::Service,
+    inbound: &inbound_federation::Service,
+    memberships: &membership::Service,
+    server_keys: &server_keys::Service,
+    own_server: &str,
+    origin: &str,
+    room_id: &str,
+    event_id: &str,
+    room_version: &str,
+    pdu: &Value,
+    invite_room_state: &[Value],
+) -> Result<Value> {
+    if !room_versions::is_supported(room_version) {
+        return Err(Error::BadRequest(
+            ErrorKind::IncompatibleRoomVersion {
+                room_version: RoomVersionId::try_from(room_version).unwrap_or(RoomVersionId::V1),
+            },
+            "This server does not support the room version",
+        ));
+    }
+    let room_version_id = RoomVersionId::try_from(room_version).expect("supported room versions are valid");
+
+    let sender = pdu["sender"].as_str().unwrap_or_default();
+    let invitee = pdu["state_key"].as_str().unwrap_or_default();
+    if pdu["room_id"] != room_id || pdu["type"] != "m.room.member" || pdu["content"]["membership"] != "invite" {
+        return Err(Error::BadRequest(ErrorKind::InvalidParam, "Not an invite event"));
+    }
+    if server_name(sender) != Some(origin) {
+        return Err(Error::BadRequest(ErrorKind::forbidden(), "The sender does not belong to the origin server"));
+    }
+    if server_name(invitee) != Some(own_server) {
+        return Err(Error::BadRequest(ErrorKind::InvalidParam, "The invited user does not belong to this server"));
+    }
+    let computed_id = inbound_federation::reference_event_id(pdu, &room_version_id)
+        .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid event"))?;
+    if computed_id != event_id {
+        return Err(Error::BadRequest(ErrorKind::InvalidParam, "The event id does not match the event"));
+    }
+    let Ok(CanonicalJsonValue::Object(object)) = CanonicalJsonValue::try_from(pdu.clone()) else {
+        return Err(Error::BadRequest(ErrorKind::InvalidParam, "Invalid event"));
+    };
+    if !matches!(inbound.verify_pdu(&object, &room_version_id), Ok(Verified::All)) {
+        return Err(Error::BadRequest(ErrorKind::forbidden(), "The invite is not signed by the inviting server"));
+    }
+
+    if timeline.room_exists(room_id) {
+        membership::check_invite(timeline, room_id, sender, invitee)?;
+    }
+    let event = server_keys.sign_pdu(room_version, pdu)?;
+    if timeline.room_exists(room_id) {
+        inbound.add_server_keys(own_server, [(server_keys.key_id(), server_keys.public_key())].into());
+        inbound
+            .handle_pdu(&event, event_id, &room_version_id, timeline)
+            .map_err(|_| Error::BadRequest(ErrorKind::forbidden(), "The invite event was rejected"))?;
+    } else {
+        let mut invite_state: Vec<Value> = invite_room_state
+            .iter()
+            .filter(|state| !(state["type"] == "m.room.member" && state["state_key"] == invitee))
+            .map(strip_state_event)
+            .collect();
+        invite_state.push(strip_state_event(&event));
+        memberships.add_remote_invite(room_id, invitee, membership::RemoteInvite { count: timeline.next_count(), invite_state });
+    }
+    info!("✉️ {} invited {} to {} over federation", sender, invitee, room_id);
+    Ok(event)
+}
+
+fn encode(segment: &str) -> String {
+    url::form_urlencoded::byte_serialize(segment.as_bytes()).collect()
+}
+
+/// Servers to ask for a join, in order: the ones given by the client, then
+/// the server that created the room
+pub fn join_candidates(room_id: &str, via: &[String], own_server: &str) -> Vec<String> {
+    let mut candidates: Vec<String> = Vec::new();
+    for server in via.iter().map(String::as_str).chain(room_id.split_once(':').map(|(_, server)| server)) {
+        if server != own_server && !candidates.iter().any(|candidate| candidate == server) {
+            candidates.push(server.to_owned());
+        }
+    }
+    candidates
+}
+
+/// Complete a `membership` event template from make_join or make_knock
+/// for `user_id`. Returns the signed PDU and its event id.
+pub fn complete_membership_template(
+    server_keys: &server_keys::Service,
+    own_server: &str,
+    user_id: &str,
+    room_version: &str,
+    membership: &str,
+    template: &Value,
+) -> Result<(String, Value)> {
+    if template["type"] != "m.room.member"
+        || template["sender"] != user_id
+        || template["state_key"] != user_id
+        || template["content"]["membership"] != membership
+    {
+        return Err(Error::BadServerResponse(format!("Invalid {} event template", membership)));
+    }
+    let mut event = template.clone();
+    event["origin"] = json!(own_server);
+    event["origin_server_ts"] = json!(now_millis());
+    let room_version_id = RoomVersionId::try_from(room_version).expect("supported room versions are valid");
+    if matches!(room_version_id, RoomVersionId::V1 | RoomVersionId::V2) {
+        event["event_id"] = json!(format!("${}:{}", uuid::Uuid::new_v4().simple(), own_server));
+    }
+    let pdu = server_keys.sign_pdu(room_version, &event)?;
+    let event_id = inbound_federation::reference_event_id(&pdu, &room_version_id).map_err(Error::BadServerResponse)?;
+    Ok((event_id, pdu))
+}
+
+/// Join a room this server is not in on behalf of `user_id`, asking the
+/// servers of [`join_candidates`] for a join event in turn. A server that
+/// cannot authorise a restricted join is skipped for the next one; the
+/// completed event goes to the server whose user authorised it. The room
+/// state of the send_join response becomes this server's copy of the room;
+/// if the membership events were left out, they are fetched in the
+/// background by [`resync_partial_state`].
+pub async fn join_remote(room_id: &str, user_id: &str, via: &[String]) -> Result<String> {
+    let services = services();
+    let own_server = services.globals.config.server_name.as_str();
+    let versions: Vec<String> = room_versions::SUPPORTED_ROOM_VERSIONS.iter().map(|version| format!("ver={}", version)).collect();
+    let make_join_path = format!("/_matrix/federation/v1/make_join/{}/{}?{}", encode(room_id), encode(user_id), versions.join("&"));
+
+    let mut unable_to_authorise = false;
+    for server in join_candidates(room_id, via, own_server) {
+        let response = match services.sending.send_federation_request(&server, reqwest::Method::GET, &make_join_path, None).await {
+            Ok(response) => response,
+            Err(e) => {
+                unable_to_authorise |= e.to_string().contains("M_UNABLE_TO_AUTHORISE_JOIN");
+                debug!("{} could not make a join event for {} in {}: {}", server, user_id, room_id, e);
+                continue;
+            }
+        };
+        let room_version = response["room_version"].as_str().unwrap_or("1");
+        room_versions::check_supported(room_version)?;
+        let (event_id, pdu) =
+            complete_membership_template(&services.server_keys, own_server, user_id, room_version, "join", &response["event"])?;
+
+        // A restricted join is checked and signed by the authorising server
+        let join_server = pdu["content"]["join_authorised_via_users_server"]
+            .as_str()
+            .and_then(server_name)
+            .unwrap_or(&server)
+            .to_owned();
+        let send_join_path =
+            format!("/_matrix/federation/v2/send_join/{}/{}?omit_members=true", encode(room_id), encode(&event_id));
+        let response = match services
+            .sending
+            .send_federation_request(&join_server, reqwest::Method::PUT, &send_join_path, Some(pdu.clone()))
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => {
+                debug!("{} did not accept the join of {} to {}: {}", join_server, user_id, room_id, e);
+                continue;
+            }
+        };
+        let joined = if response["event"].is_object() { response["event"].clone() } else { pdu };
+        let partial_state = services.inbound_federation.partial_state();
+        if response["members_omitted"] == true {
+            // Until the full state is known, events of the omitted members
+            // must not be rejected
+            let mut servers = vec![join_server.clone()];
+            for server in response["servers_in_room"].as_array().into_iter().flatten().filter_map(Value::as_str) {
+                if server != own_server && !servers.iter().any(|known| known == server) {
+                    servers.push(server.to_owned());
+                }
+            }
+            partial_state.mark(room_id, &event_id, servers);
+        }
+        if let Err(e) = apply_send_join_response(room_id, room_version, &response, &event_id, &joined).await {
+            partial_state.abandon(room_id);
+            return Err(e);
+        }
+        if partial_state.is_partial(room_id) {
+            info!("🚪 {} joined {} through {} with partial state", user_id, room_id, join_server);
+            tokio::spawn(resync_partial_state(room_id.to_owned()));
+        } else {
+            info!("🚪 {} joined {} through {}", user_id, room_id, join_server);
+        }
+        return Ok(event_id);
+    }
+
+    Err(if unable_to_authorise {
+        Error::BadRequest(ErrorKind::UnableToAuthorizeJoin, "No server in the room could authorise the join")
+    } else {
+        Error::BadRequest(ErrorKind::NotFound, "No server in the room could be reached")
+    })
+}
+
+/// Knock on a room this server is not in on behalf of `user_id`, asking
+/// the servers of [`join_candidates`] for a knock event in turn. Returns
+/// the knock event id and the stripped room state to show the user,
+/// including the knock itself.
+pub async fn knock_remote(room_id: &str, user_id: &str, reason: Option<&str>, via: &[String]) -> Result<(String, Vec<Value>)> {
+    let services = services();
+    let own_server = services.globals.config.server_name.as_str();
+    let versions: Vec<String> = room_versions::SUPPORTED_ROOM_VERSIONS.iter().map(|version| format!("ver={}", version)).collect();
+    let make_knock_path = format!("/_matrix/federation/v1/make_knock/{}/{}?{}", encode(room_id), encode(user_id), versions.join("&"));
+
+    for server in join_candidates(room_id, via, own_server) {
+        let response = match services.sending.send_federation_request(&server, reqwest::Method::GET, &make_knock_path, None).await {
+            Ok(response) => response,
+            Err(e) => {
+                debug!("{} could not make a knock event for {} in {}: {}", server, user_id, room_id, e);
+                continue;
+            }
+        };
+        let room_version = response["room_version"].as_str().unwrap_or("1");
+        room_versions::check_supported(room_version)?;
+        let mut template = response["event"].clone();
+        if let Some(reason) = reason {
+            template["content"]["reason"] = json!(reason);
+        }
+        let (event_id, pdu) = complete_membership_template(&services.server_keys, own_server, user_id, room_version, "knock", &template)?;
+
+        let send_knock_path = format!("/_matrix/federation/v1/send_knock/{}/{}", encode(room_id), encode(&event_id));
+        let response =
+            match services.sending.send_federation_request(&server, reqwest::Method::PUT, &send_knock_path, Some(pdu.clone())).await {
+                Ok(response) => response,
+                Err(e) => {
+                    debug!("{} did not accept the knock of {} on {}: {}", server, user_id, room_id, e);
+                    continue;
+                }
+            };
+        let mut knock_state: Vec<Value> = response["knock_room_state"].as_array().cloned().unwrap_or_default();
+        knock_state.push(json!({
+            "type": "m.room.member",
+            "state_key": user_id,
+            "sender": user_id,
+            "content": pdu["content"],
+        }));
+        info!("🚪 {} knocked on {} through {}", user_id, room_id, server);
+        return Ok((event_id, knock_state));
+    }
+
+    Err(Error::BadRequest(ErrorKind::NotFound, "No server in the room could be reached"))
+}
+
+/// Store the room state of a send_join response, then the join itself
+async fn apply_send_join_response(room_id: &str, room_version: &str, response: &Value, event_id: &str, join: &Value) -> Result<()> {
+    let services = services();
+    let room_version_id = RoomVersionId::try_from(room_version).expect("supported room versions are valid");
+    apply_state(room_id, &room_version_id, &response["auth_chain"], &response["state"], Some(join)).await;
+    services
+        .inbound_federation
+        .handle_pdu(join, event_id, &room_version_id, &services.timeline)
+        .map_err(|e| Error::BadServerResponse(format!("The join event was rejected: {}", e)))
+}
+
+/// Store the events of `auth_chain` and `state` received from another
+/// server, oldest first, after fetching the keys they and `also_signed`
+/// are signed with. Auth events that are not part of `state` are kept as
+/// outliers. Events that cannot be checked are skipped.
+async fn apply_state(room_id: &str, room_version_id: &RoomVersionId, auth_chain: &Value, state: &Value, also_signed: Option<&Value>) {
+    let services = services();
+    let own_server = services.globals.config.server_name.as_str();
+    let mut events: Vec<&Value> = auth_chain
+        .as_array()
+        .into_iter()
+        .chain(state.as_array())
+        .flatten()
+        .filter(|event| event["room_id"] == room_id)
+        .collect();
+    events.sort_by_key(|event| event["depth"].as_u64().unwrap_or(0));
+
+    let mut required = also_signed.map(key_fetcher::required_signing_keys).unwrap_or_default();
+    for event in &events {
+        for (server, key_ids) in key_fetcher::required_signing_keys(event) {
+            required.entry(server).or_default().extend(key_ids);
+        }
+    }
+    required.remove(own_server);
+    for (server, keys) in services.key_fetcher.fetch_required_signing_keys(&services.sending, &required).await {
+        services.inbound_federation.add_expiring_server_keys(&server, keys.keys_with_validity());
+    }
+    services
+        .inbound_federation
+        .add_server_keys(own_server, [(services.server_keys.key_id(), services.server_keys.public_key())].into());
+
+    let state_event_ids: BTreeSet<String> = state
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|event| inbound_federation::reference_event_id(event, room_version_id).ok())
+        .collect();
+    for event in events {
+        let Ok(event_id) = inbound_federation::reference_event_id(event, room_version_id) else {
+            continue;
+        };
+        let stored = if state_event_ids.contains(&event_id) {
+            services.inbound_federation.handle_state_pdu(event, &event_id, room_version_id, &services.timeline)
+        } else {
+            services.inbound_federation.add_outlier(room_id, event, &event_id, room_version_id, &services.timeline)
+        };
+        if let Err(e) = stored {
+            warn!("⚠️ Skipping event {} of the state of {}: {}", event_id, room_id, e);
+        }
+    }
+}
+
+/// The state events of a `/state` response this server does not have an
+/// entry for yet. State received since the partial join is newer than the
+/// state at the join, so it is kept.
+pub fn missing_state<'a>(timeline: &timeline::Service, room_id: &str, state: &'a Value) -> Vec<&'a Value> {
+    state
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|event| {
+            let (Some(event_type), Some(state_key)) = (event["type"].as_str(), event["state_key"].as_str()) else {
+                return false;
+            };
+            timeline.state_event(room_id, event_type, state_key).is_none()
+        })
+        .collect()
+}
+
+/// Check that a `/state` response is the state of `room_id`: state events
+/// of the room only, including the create event this server has for it
+pub fn check_full_state(
+    timeline: &timeline::Service,
+    room_id: &str,
+    room_version_id: &RoomVersionId,
+    response: &Value,
+) -> std::result::Result<(), String> {
+    let pdus = response["pdus"].as_array().ok_or("The response has no state")?;
+    if let Some(event) = pdus
+        .iter()
+        .find(|event| event["room_id"] != room_id || !event["type"].is_string() || !event["state_key"].is_string())
+    {
+        return Err(format!("{} is not a state event of the room", event["event_id"]));
+    }
+    let create_event_id = timeline
+        .state_event(room_id, "m.room.create", "")
+        .and_then(|create| create["event_id"].as_str().map(str::to_owned))
+        .ok_or("The room has no create event")?;
+    let same_create = pdus
+        .iter()
+        .filter(|event| event["type"] == "m.room.create" && event["state_key"] == "")
+        .any(|event| inbound_federation::reference_event_id(event, room_version_id).is_ok_and(|event_id| event_id == create_event_id));
+    if !same_create {
+        return Err("The state does not include the create event of the room".to_owned());
+    }
+    Ok(())
+}
+
+/// Fetch the full state of a room joined with partial state from the
+/// servers in it, at the join event, until one of them answers with valid
+/// state. Events accepted while the state was partial are then checked
+/// against it. Waits longer after each round in which every server failed.
+pub async fn resync_partial_state(room_id: String) {
+    let services = services();
+    let partial_state = services.inbound_federation.partial_state();
+    let own_server = services.globals.config.server_name.as_str();
+    let mut delay = RESYNC_DELAY;
+    while let Some(room) = partial_state.get(&room_id) {
+        let path = format!("/_matrix/federation/v1/state/{}?event_id={}", encode(&room_id), encode(&room.event_id));
+        for server in room.servers.iter().filter(|server| *server != own_server) {
+            let response = match services.sending.send_federation_request(server, reqwest::Method::GET, &path, None).await {
+                Ok(response) => response,
+                Err(e) => {
+                    debug!("{} could not send the state of {}: {}", server, room_id, e);
+                    continue;
+                }
+            };
+            let room_version_id = RoomVersionId::try_from(room_version(&services.timeline, &room_id))
+                .unwrap_or(RoomVersionId::V10);
+            if let Err(e) = check_full_state(&services.timeline, &room_id, &room_version_id, &response) {
+                warn!("⚠️ {} sent invalid state of {}: {}", server, room_id, e);
+                continue;
+            }
+            let missing = json!(missing_state(&services.timeline, &room_id, &response["pdus"]));
+            apply_state(&room_id, &room_version_id, &response["auth_chain"], &missing, None).await;
+            let lenient = partial_state.complete(&room_id).map(|room| room.lenient).unwrap_or_default();
+            let rejected = services.inbound_federation.reauthorize(&services.timeline, &room_id, &lenient);
+            info!(
+                "✅ Fetched the full state of {} from {}, {} of {} events accepted meanwhile were rejected",
+                room_id,
+                server,
+                rejected,
+                lenient.len()
+            );
+            return;
+        }
+        let attempts = partial_state.failed_attempt(&room_id);
+        warn!("⚠️ No server sent the full state of {} ({} attempts), retrying in {:?}", room_id, attempts, delay);
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RESYNC_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWN: &str = "matrixon.local";
+    const ROOM: &str = "!room:matrixon.local";
+    const SPACE: &str = "!space:matrixon.local";
+    const OWNER: &str = "@owner:matrixon.local";
+    const BOB: &str = "@bob:remote.example";
+
+    fn restricted_room() -> timeline::Service {
+        let timeline = timeline::Service::new();
+        timeline.append_event(ROOM, OWNER, "m.room.create", Some(""), json!({ "creator": OWNER, "room_version": "10" }));
+        timeline.append_event(ROOM, OWNER, "m.room.member", Some(OWNER), json!({ "membership": "join" }));
+        timeline.append_event(ROOM, OWNER, "m.room.power_levels", Some(""), json!({ "users": { OWNER: 100 } }));
+        timeline.append_event(
+            ROOM,
+            OWNER,
+            "m.room.join_rules",
+            Some(""),
+            json!({ "join_rule": "restricted", "allow": [{ "type": "m.room_membership", "room_id": SPACE }] }),
+        );
+        timeline.append_event(SPACE, OWNER, "m.room.create", Some(""), json!({ "creator": OWNER }));
+        timeline
+    }
+
+    /// Complete and sign a template the way the remote server would
+    fn sign_template(remote: &server_keys::Service, template: &Value) -> (String, Value) {
+        let pdu = remote.sign_pdu("10", template).unwrap();
+        let object = match ruma::CanonicalJsonValue::try_from(pdu.clone()).unwrap() {
+            ruma::CanonicalJsonValue::Object(object) => object,
+            _ => unreachable!(),
+        };
+        let hash = ruma::signatures::reference_hash(&object, &RoomVersionId::V10).unwrap();
+        (format!("${}", hash), pdu)
+    }
+
+    #[test]
+    fn test_make_join_checks_version_and_join_rules() {
+        let timeline = restricted_room();
+        let versions = vec!["10".to_owned()];
+        assert!(make_join(&timeline, OWN, ROOM, BOB, &["9".to_owned()]).is_err());
+        assert!(make_join(&timeline, OWN, ROOM, BOB, &versions).is_err());
+
+        timeline.append_event(SPACE, BOB, "m.room.member", Some(BOB), json!({ "membership": "join" }));
+        let response = make_join(&timeline, OWN, ROOM, BOB, &versions).unwrap();
+        assert_eq!(response["room_version"], "10");
+        assert_eq!(response["event"]["content"]["membership"], "join");
+        assert_eq!(response["event"]["content"]["join_authorised_via_users_server"], OWNER);
+        assert_eq!(response["event"]["auth_events"].as_array().unwrap().len(), 4);
+
+        // Rooms of versions this server does not support cannot be joined
+        let unknown = timeline::Service::new();
+        unknown.append_event("!unknown:matrixon.local", OWNER, "m.room.create", Some(""), json!({ "room_version": "org.example.custom" }));
+        assert!(matches!(
+            make_join(&unknown, OWN, "!unknown:matrixon.local", BOB, &["org.example.custom".to_owned()]),
+            Err(Error::BadRequest(ErrorKind::UnsupportedRoomVersion, _))
+        ));
+    }
+
+    #[test]
+    fn test_send_join_signs_restricted_join() {
+        let timeline = restricted_room();
+        timeline.append_event(SPACE, BOB, "m.room.member", Some(BOB), json!({ "membership": "join" }));
+        let own_keys = server_keys::Service::load(OWN, None).unwrap();
+        let remote = server_keys::Service::load("remote.example", None).unwrap();
+        let inbound = inbound_federation::Service::new();
+        inbound.add_server_keys("remote.example", [(remote.key_id(), remote.public_key())].into());
+
+        let template = make_join(&timeline, OWN, ROOM, BOB, &["10".to_owned()]).unwrap()["event"].clone();
+        let (event_id, pdu) = sign_template(&remote, &template);
+
+        assert!(send_join(&timeline, &inbound, &own_keys, OWN, "other.example", ROOM, &event_id, &pdu, false).is_err());
+        assert!(send_join(&timeline, &inbound, &own_keys, OWN, "remote.example", ROOM, "$wrong", &pdu, false).is_err());
+
+        let response = send_join(&timeline, &inbound, &own_keys, OWN, "remote.example", ROOM, &event_id, &pdu, false).unwrap();
+        assert!(response["event"]["signatures"][OWN].is_object());
+        assert!(response["state"].as_array().unwrap().iter().any(|event| event["type"] == "m.room.join_rules"));
+        assert_eq!(membership_in(&timeline, ROOM, BOB).as_deref(), Some("join"));
+    }
+
+    #[test]
+    fn test_partial_state_join() {
+        const CAROL: &str = "@carol:third.example";
+        const DAVE: &str = "@dave:remote.example";
+        let timeline = restricted_room();
+        timeline.append_event(SPACE, BOB, "m.room.member", Some(BOB), json!({ "membership": "join" }));
+        timeline.append_event(ROOM, CAROL, "m.room.member", Some(CAROL), json!({ "membership": "join" }));
+        let own_keys = server_keys::Service::load(OWN, None).unwrap();
+        let remote = server_keys::Service::load("remote.example", None).unwrap();
+        let inbound = inbound_federation::Service::new();
+        inbound.add_server_keys("remote.example", [(remote.key_id(), remote.public_key())].into());
+
+        let template = make_join(&timeline, OWN, ROOM, BOB, &["10".to_owned()]).unwrap()["event"].clone();
+        let (event_id, pdu) = sign_template(&remote, &template);
+        let response = send_join(&timeline, &inbound, &own_keys, OWN, "remote.example", ROOM, &event_id, &pdu, true).unwrap();
+        assert_eq!(response["members_omitted"], true);
+        assert_eq!(response["servers_in_room"], json!(["matrixon.local", "third.example"]));
+        let state = response["state"].as_array().unwrap();
+        assert!(state.iter().all(|event| event["type"] != "m.room.member"));
+        assert!(state.iter().any(|event| event["type"] == "m.room.join_rules"));
+
+        // Only state this server has no entry for is taken from a resync
+        let full_state = json!([
+            { "type": "m.room.member", "state_key": CAROL, "content": { "membership": "invite" } },
+            { "type": "m.room.member", "state_key": DAVE, "content": { "membership": "join" } },
+        ]);
+        let missing = missing_state(&timeline, ROOM, &full_state);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0]["state_key"], DAVE);
+
+        // Senders whose membership is unknown are trusted until the resync
+        let message = json!({
+            "room_id": ROOM,
+            "sender": DAVE,
+            "type": "m.room.message",
+            "content": { "body": "hi" },
+            "origin_server_ts": 1,
+            "depth": 10,
+            "prev_events": [],
+            "auth_events": [],
+        });
+        let (message_id, message) = sign_template(&remote, &message);
+        assert!(inbound.handle_pdu(&message, &message_id, &RoomVersionId::V10, &timeline).is_err());
+        inbound.partial_state().mark(ROOM, &event_id, vec!["remote.example".to_owned()]);
+        inbound.handle_pdu(&message, &message_id, &RoomVersionId::V10, &timeline).unwrap();
+
+        // ...but only senders of the servers in the room
+        let elsewhere = server_keys::Service::load("elsewhere.example", None).unwrap();
+        inbound.add_server_keys("elsewhere.example", [(elsewhere.key_id(), elsewhere.public_key())].into());
+        let mut spam = message.clone();
+        spam["sender"] = json!("@eve:elsewhere.example");
+        let (spam_id, spam) = sign_template(&elsewhere, &spam);
+        assert!(inbound.handle_pdu(&spam, &spam_id, &RoomVersionId::V10, &timeline).is_err());
+
+        // The full state says the sender had left: the message is redacted
+        let lenient = inbound.partial_state().complete(ROOM).unwrap().lenient;
+        assert_eq!(lenient, [message_id.clone()]);
+        timeline.append_event(ROOM, DAVE, "m.room.member", Some(DAVE), json!({ "membership": "leave" }));
+        assert_eq!(inbound.reauthorize(&timeline, ROOM, &lenient), 1);
+        assert!(timeline.get_event(ROOM, &message_id).unwrap()["content"].as_object().unwrap().is_empty());
+        assert!(inbound.pdu_metadata().is_event_soft_failed(&message_id));
+    }
+
+    #[test]
+    fn test_resynced_state_must_be_the_state_of_the_room() {
+        let timeline = timeline::Service::new();
+        let remote = server_keys::Service::load("remote.example", None).unwrap();
+        let create = json!({
+            "room_id": ROOM,
+            "sender": BOB,
+            "type": "m.room.create",
+            "state_key": "",
+            "content": { "room_version": "10" },
+            "origin_server_ts": 1,
+            "depth": 1,
+            "prev_events": [],
+            "auth_events": [],
+        });
+        let (create_id, create) = sign_template(&remote, &create);
+        let mut stored = create.clone();
+        stored["event_id"] = json!(create_id);
+        timeline.append_pdu(ROOM, stored);
+        let member = json!({ "room_id": ROOM, "type": "m.room.member", "state_key": BOB, "content": { "membership": "join" } });
+
+        let valid = json!({ "pdus": [create.clone(), member.clone()] });
+        assert!(check_full_state(&timeline, ROOM, &RoomVersionId::V10, &valid).is_ok());
+        assert!(check_full_state(&timeline, ROOM, &RoomVersionId::V10, &json!({})).is_err());
+        // The state of another room, or without the room's create event
+        let mut other_create = create.clone();
+        other_create["content"]["creator"] = json!(BOB);
+        assert!(check_full_state(&timeline, ROOM, &RoomVersionId::V10, &json!({ "pdus": [other_create, member.clone()] })).is_err());
+        let mut other_room = member.clone();
+        other_room["room_id"] = json!(SPACE);
+        assert!(check_full_state(&timeline, ROOM, &RoomVersionId::V10, &json!({ "pdus": [create.clone(), other_room] })).is_err());
+        let mut message = member;
+        message.as_object_mut().unwrap().remove("state_key");
+        assert!(check_full_state(&timeline, ROOM, &RoomVersionId::V10, &json!({ "pdus": [create, message] })).is_err());
+    }
+
+    #[test]
+    fn test_completed_join_template_needs_the_authorising_signature() {
+        let timeline = restricted_room();
+        timeline.append_event(SPACE, BOB, "m.room.member", Some(BOB), json!({ "membership": "join" }));
+        let own_keys = server_keys::Service::load(OWN, None).unwrap();
+        let remote = server_keys::Service::load("remote.example", None).unwrap();
+        let inbound = inbound_federation::Service::new();
+        inbound.add_server_keys("remote.example", [(remote.key_id(), remote.public_key())].into());
+
+        let template = make_join(&timeline, OWN, ROOM, BOB, &["10".to_owned()]).unwrap()["event"].clone();
+        assert!(complete_membership_template(&remote, "remote.example", "@eve:remote.example", "10", "join", &template).is_err());
+        let (event_id, pdu) = complete_membership_template(&remote, "remote.example", BOB, "10", "join", &template).unwrap();
+        assert!(inbound.handle_pdu(&pdu, &event_id, &RoomVersionId::V10, &timeline).is_err());
+
+        send_join(&timeline, &inbound, &own_keys, OWN, "remote.example", ROOM, &event_id, &pdu, false).unwrap();
+        assert_eq!(membership_in(&timeline, ROOM, BOB).as_deref(), Some("join"));
+    }
+
+    #[test]
+    fn test_join_candidates() {
+        let via = ["a.example".to_owned(), OWN.to_owned(), "a.example".to_owned()];
+        assert_eq!(join_candidates("!room:b.example", &via, OWN), ["a.example", "b.example"]);
+        assert!(join_candidates(ROOM, &[], OWN).is_empty());
+    }
+
+    #[test]
+    fn test_leave_handshake() {
+        let timeline = restricted_room();
+        let remote = server_keys::Service::load("remote.example", None).unwrap();
+        let inbound = inbound_federation::Service::new();
+        inbound.add_server_keys("remote.example", [(remote.key_id(), remote.public_key())].into());
+        assert!(make_leave(&timeline, ROOM, BOB).is_err());
+
+        timeline.append_event(ROOM, OWNER, "m.room.member", Some(BOB), json!({ "membership": "invite" }));
+        let template = make_leave(&timeline, ROOM, BOB).unwrap()["event"].clone();
+        assert_eq!(template["content"]["membership"], "leave");
+        let (event_id, pdu) = sign_template(&remote, &template);
+
+        assert!(send_leave(&timeline, &inbound, "other.example", ROOM, &event_id, &pdu).is_err());
+        send_leave(&timeline, &inbound, "remote.example", ROOM, &event_id, &pdu).unwrap();
+        assert_eq!(membership_in(&timeline, ROOM, BOB).as_deref(), Some("leave"));
+        // Sending it again is harmless
+        send_leave(&timeline, &inbound, "remote.example", ROOM, &event_id, &pdu).unwrap();
+    }
+
+    #[test]
+    fn test_knock_handshake() {
+        let timeline = restricted_room();
+        let remote = server_keys::Service::load("remote.example", None).unwrap();
+        let inbound = inbound_federation::Service::new();
+        inbound.add_server_keys("remote.example", [(remote.key_id(), remote.public_key())].into());
+        let versions = ["10".to_owned()];
+        assert!(make_knock(&timeline, ROOM, BOB, &versions).is_err());
+
+        timeline.append_event(ROOM, OWNER, "m.room.join_rules", Some(""), json!({ "join_rule": "knock" }));
+        timeline.append_event(ROOM, OWNER, "m.room.name", Some(""), json!({ "name": "Knock knock" }));
+        assert!(make_knock(&timeline, ROOM, BOB, &["6".to_owned()]).is_err());
+        let template = make_knock(&timeline, ROOM, BOB, &versions).unwrap()["event"].clone();
+        assert_eq!(template["content"]["membership"], "knock");
+        assert!(complete_membership_template(&remote, "remote.example", BOB, "10", "join", &template).is_err());
+        let (event_id, pdu) = complete_membership_template(&remote, "remote.example", BOB, "10", "knock", &template).unwrap();
+
+        assert!(send_knock(&timeline, &inbound, "other.example", ROOM, &event_id, &pdu).is_err());
+        let response = send_knock(&timeline, &inbound, "remote.example", ROOM, &event_id, &pdu).unwrap();
+        assert_eq!(membership_in(&timeline, ROOM, BOB).as_deref(), Some("knock"));
+        let state = response["knock_room_state"].as_array().unwrap();
+        assert!(state.iter().any(|event| event["content"]["name"] == "Knock knock"));
+        assert!(state.iter().all(|event| event.get("event_id").is_none()));
+        // Sending it again is harmless
+        send_knock(&timeline, &inbound, "remote.example", ROOM, &event_id, &pdu).unwrap();
+
+        timeline.append_event(ROOM, OWNER, "m.room.member", Some(BOB), json!({ "membership": "ban" }));
+        assert!(make_knock(&timeline, ROOM, BOB, &versions).is_err());
+    }
+
+    #[test]
+    fn test_invite_to_local_room_needs_power() {
+        const ALICE: &str = "@alice:matrixon.local";
+        let timeline = restricted_room();
+        timeline.append_event(ROOM, OWNER, "m.room.power_levels", Some(""), json!({ "users": { OWNER: 100 }, "invite": 50 }));
+        timeline.append_event(ROOM, BOB, "m.room.member", Some(BOB), json!({ "membership": "join" }));
+        let memberships = membership::Service::new();
+        let own_keys = server_keys::Service::load(OWN, None).unwrap();
+        let remote = server_keys::Service::load("remote.example", None).unwrap();
+        let inbound = inbound_federation::Service::new();
+        inbound.add_server_keys("remote.example", [(remote.key_id(), remote.public_key())].into());
+
+        let template = json!({
+            "room_id": ROOM,
+            "sender": BOB,
+            "type": "m.room.member",
+            "state_key": ALICE,
+            "content": { "membership": "invite" },
+            "origin_server_ts": 1,
+            "depth": 6,
+            "prev_events": [],
+            "auth_events": [],
+        });
+        let (event_id, pdu) = sign_template(&remote, &template);
+        let invite = || invite(&timeline, &inbound, &memberships, &own_keys, OWN, "remote.example", ROOM, &event_id, "10", &pdu, &[]);
+
+        assert!(invite().is_err());
+        timeline.append_event(ROOM, OWNER, "m.room.power_levels", Some(""), json!({ "users": { OWNER: 100, BOB: 50 }, "invite": 50 }));
+        timeline.append_event(ROOM, OWNER, "m.room.member", Some(ALICE), json!({ "membership": "ban" }));
+        assert!(invite().is_err());
+        assert_eq!(membership_in(&timeline, ROOM, ALICE).as_deref(), Some("ban"));
+    }
+
+    #[test]
+    fn test_invite_to_remote_room_is_kept_for_sync() {
+        const REMOTE_ROOM: &str = "!room:remote.example";
+        const ALICE: &str = "@alice:matrixon.local";
+        let timeline = timeline::Service::new();
+        let memberships = membership::Service::new();
+        let own_keys = server_keys::Service::load(OWN, None).unwrap();
+        let remote = server_keys::Service::load("remote.example", None).unwrap();
+        let inbound = inbound_federation::Service::new();
+        inbound.add_server_keys("remote.example", [(remote.key_id(), remote.public_key())].into());
+
+        let template = json!({
+            "room_id": REMOTE_ROOM,
+            "sender": BOB,
+            "type": "m.room.member",
+            "state_key": ALICE,
+            "content": { "membership": "invite" },
+            "origin_server_ts": 1,
+            "depth": 5,
+            "prev_events": [],
+            "auth_events": [],
+        });
+        let (event_id, pdu) = sign_template(&remote, &template);
+        let room_state = vec![json!({
+            "type": "m.room.name",
+            "state_key": "",
+            "sender": BOB,
+            "content": { "name": "Remote" },
+            "event_id": "$leaked",
+        })];
+        let invite = |origin: &str, version: &str| {
+            invite(&timeline, &inbound, &memberships, &own_keys, OWN, origin, REMOTE_ROOM, &event_id, version, &pdu, &room_state)
+        };
+
+        assert!(invite("other.example", "10").is_err());
+        assert!(invite("remote.example", "custom").is_err());
+        let signed = invite("remote.example", "10").unwrap();
+        assert!(signed["signatures"][OWN].is_object());
+        assert!(signed["signatures"]["remote.example"].is_object());
+
+        let invites = memberships.remote_invites(ALICE);
+        assert_eq!(invites.len(), 1);
+        let (room_id, invite) = &invites[0];
+        assert_eq!(room_id, REMOTE_ROOM);
+        assert_eq!(invite.invite_state.len(), 2);
+        assert!(invite.invite_state[0].get("event_id").is_none());
+        assert_eq!(invite.invite_state[1]["content"]["membership"], "invite");
+        assert!(memberships.remote_invites(BOB).is_empty());
+
+        assert!(memberships.remove_remote_invite(REMOTE_ROOM, ALICE));
+        assert!(!memberships.remove_remote_invite(REMOTE_ROOM, ALICE));
+        assert!(memberships.remote_invites(ALICE).is_empty());
+    }
+}
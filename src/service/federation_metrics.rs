@@ -0,0 +1,217 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Federation Metrics
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Counters and latency histograms of the inbound federation pipeline:
+//   how long each stage of handling a PDU takes, how many PDUs were
+//   accepted, soft-failed or rejected and why, and how long transactions
+//   from each origin take end to end. Rendered in the Prometheus text format
+//   on the metrics listener.
+//
+// =============================================================================
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Origins with their own transaction histogram; later ones share one so a
+/// flood of servers cannot grow the metrics without bound
+const MAX_ORIGINS: usize = 1000;
+const OTHER_ORIGINS: &str = "other";
+
+/// Stages of handling a PDU
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// Fetching signing keys of the servers that signed the PDUs
+    KeyFetch,
+    /// Checking signatures and content hash
+    SignatureCheck,
+    /// Resolving the room state the PDU is authorized against
+    StateResolution,
+    /// Authorizing the PDU against that state
+    Auth,
+    /// Appending the PDU to the room timeline
+    Persistence,
+}
+
+impl Stage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stage::KeyFetch => "key_fetch",
+            Stage::SignatureCheck => "signature_check",
+            Stage::StateResolution => "state_res",
+            Stage::Auth => "auth",
+            Stage::Persistence => "persistence",
+        }
+    }
+}
+
+/// Cumulative latency histogram
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[bucket] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, label: &str, value: &str) {
+        let value = escape(value);
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{{label}=\"{value}\",le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{label}=\"{value}\",le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{label}=\"{value}\"}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{label}=\"{value}\"}} {}", self.count);
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Federation pipeline metrics
+#[derive(Debug, Default)]
+pub struct Service {
+    stages: RwLock<BTreeMap<Stage, Histogram>>,
+    accepted: AtomicU64,
+    soft_failed: RwLock<BTreeMap<&'static str, u64>>,
+    rejected: RwLock<BTreeMap<&'static str, u64>>,
+    transactions: RwLock<BTreeMap<String, Histogram>>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn observe_stage(&self, stage: Stage, duration: Duration) {
+        self.stages.write().unwrap().entry(stage).or_default().observe(duration);
+    }
+
+    pub fn accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a PDU that passed authorization against its auth events but
+    /// not against the current room state
+    pub fn soft_failed(&self, reason: &'static str) {
+        *self.soft_failed.write().unwrap().entry(reason).or_default() += 1;
+    }
+
+    pub fn rejected(&self, reason: &'static str) {
+        *self.rejected.write().unwrap().entry(reason).or_default() += 1;
+    }
+
+    /// Record how long a transaction from `origin` took end to end
+    pub fn observe_transaction(&self, origin: &str, duration: Duration) {
+        let mut transactions = self.transactions.write().unwrap();
+        let origin = if transactions.contains_key(origin) || transactions.len() < MAX_ORIGINS {
+            origin
+        } else {
+            OTHER_ORIGINS
+        };
+        transactions.entry(origin.to_owned()).or_default().observe(duration);
+    }
+
+    /// The metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP matrixon_federation_pdu_stage_seconds Time spent in each stage of handling inbound PDUs\n");
+        out.push_str("# TYPE matrixon_federation_pdu_stage_seconds histogram\n");
+        for (stage, histogram) in self.stages.read().unwrap().iter() {
+            histogram.render(&mut out, "matrixon_federation_pdu_stage_seconds", "stage", stage.as_str());
+        }
+
+        out.push_str("# HELP matrixon_federation_pdus_accepted_total Inbound PDUs appended to their room\n");
+        out.push_str("# TYPE matrixon_federation_pdus_accepted_total counter\n");
+        let _ = writeln!(out, "matrixon_federation_pdus_accepted_total {}", self.accepted.load(Ordering::Relaxed));
+
+        for (name, help, counts) in [
+            ("soft_failed", "Inbound PDUs soft-failed against the current room state", &self.soft_failed),
+            ("rejected", "Inbound PDUs rejected", &self.rejected),
+        ] {
+            let _ = writeln!(out, "# HELP matrixon_federation_pdus_{name}_total {help}, by reason");
+            let _ = writeln!(out, "# TYPE matrixon_federation_pdus_{name}_total counter");
+            for (reason, count) in counts.read().unwrap().iter() {
+                let _ = writeln!(out, "matrixon_federation_pdus_{name}_total{{reason=\"{reason}\"}} {count}");
+            }
+        }
+
+        out.push_str("# HELP matrixon_federation_transaction_seconds Time to process inbound transactions, by origin\n");
+        out.push_str("# TYPE matrixon_federation_transaction_seconds histogram\n");
+        for (origin, histogram) in self.transactions.read().unwrap().iter() {
+            histogram.render(&mut out, "matrixon_federation_transaction_seconds", "origin", origin);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_counters_and_histograms() {
+        let metrics = Service::new();
+        metrics.observe_stage(Stage::Auth, Duration::from_millis(3));
+        metrics.observe_stage(Stage::Auth, Duration::from_millis(200));
+        metrics.accepted();
+        metrics.rejected("auth");
+        metrics.rejected("auth");
+        metrics.rejected("signatures");
+        metrics.observe_transaction("remote.example", Duration::from_secs(60));
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("matrixon_federation_pdu_stage_seconds_bucket{stage=\"auth\",le=\"0.005\"} 1\n"));
+        assert!(rendered.contains("matrixon_federation_pdu_stage_seconds_bucket{stage=\"auth\",le=\"0.25\"} 2\n"));
+        assert!(rendered.contains("matrixon_federation_pdu_stage_seconds_count{stage=\"auth\"} 2\n"));
+        assert!(rendered.contains("matrixon_federation_pdus_accepted_total 1\n"));
+        assert!(rendered.contains("matrixon_federation_pdus_rejected_total{reason=\"auth\"} 2\n"));
+        assert!(rendered.contains("matrixon_federation_pdus_rejected_total{reason=\"signatures\"} 1\n"));
+        assert!(rendered.contains("matrixon_federation_transaction_seconds_bucket{origin=\"remote.example\",le=\"30\"} 0\n"));
+        assert!(rendered.contains("matrixon_federation_transaction_seconds_bucket{origin=\"remote.example\",le=\"+Inf\"} 1\n"));
+    }
+
+    #[test]
+    fn test_origins_are_capped() {
+        let metrics = Service::new();
+        for i in 0..=MAX_ORIGINS {
+            metrics.observe_transaction(&format!("server{}.example", i), Duration::from_millis(1));
+        }
+        metrics.observe_transaction("server0.example", Duration::from_millis(1));
+
+        let transactions = metrics.transactions.read().unwrap();
+        assert_eq!(transactions.len(), MAX_ORIGINS + 1);
+        assert_eq!(transactions[OTHER_ORIGINS].count, 1);
+        assert_eq!(transactions["server0.example"].count, 2);
+    }
+}
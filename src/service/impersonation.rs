@@ -0,0 +1,219 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Admin Impersonation
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Short-lived access tokens letting a server admin act as another user, so
+//   support staff can reproduce user-specific sync or push issues without
+//   resetting passwords. Only available when enabled in the config, for
+//   existing local users; every issued token and every request made with
+//   it, with its method and path, is recorded in the audit trail, and in the
+//   audit log file when audit logging is configured.
+//
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::Write,
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rand::{distributions::Alphanumeric, Rng};
+use serde::Serialize;
+use tracing::{info, warn};
+
+/// Prefix of impersonation access tokens
+pub const TOKEN_PREFIX: &str = "syt_matrixon_impersonate_";
+
+/// Number of audit entries kept in memory
+const MAX_AUDIT_ENTRIES: usize = 10_000;
+
+#[derive(Debug, Clone)]
+struct Session {
+    admin: String,
+    user_id: String,
+    device_id: String,
+    expires_at: u64,
+}
+
+/// One impersonation event: a token being issued or used
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditEntry {
+    pub ts: u64,
+    pub action: &'static str,
+    pub admin: String,
+    pub user_id: String,
+    pub device_id: String,
+    pub reason: Option<String>,
+    /// Method and path of the request a token was used for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+}
+
+/// Admin impersonation service
+#[derive(Debug, Default)]
+pub struct Service {
+    sessions: RwLock<HashMap<String, Session>>,
+    audit: RwLock<Vec<AuditEntry>>,
+    audit_log_path: Option<String>,
+}
+
+impl Service {
+    /// `audit_log_path` is the file audit entries are appended to, if any
+    pub fn new(audit_log_path: Option<String>) -> Self {
+        Self {
+            audit_log_path,
+            ..Default::default()
+        }
+    }
+
+    /// Issue a token for `admin` to act as `user_id` for `lifetime_s`
+    /// seconds, forgetting the tokens that expired. Returns the token and
+    /// its expiry in milliseconds.
+    pub fn issue(&self, admin: &str, user_id: &str, reason: &str, lifetime_s: u64) -> (String, u64) {
+        let secret: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+        let token = format!("{}{}", TOKEN_PREFIX, secret);
+        let session = Session {
+            admin: admin.to_owned(),
+            user_id: user_id.to_owned(),
+            device_id: format!("IMPERSONATION_{}", &secret[..8]),
+            expires_at: now_millis() + lifetime_s * 1000,
+        };
+
+        warn!("🕵️ {} is impersonating {} until {}: {}", admin, user_id, session.expires_at, reason);
+        self.record(&session, "issued", Some(reason.to_owned()), None);
+        let expires_at = session.expires_at;
+        let now = now_millis();
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.retain(|_, session| session.expires_at > now);
+        sessions.insert(token.clone(), session);
+        (token, expires_at)
+    }
+
+    /// Authenticate a request made with an impersonation token, auditing
+    /// its `method` and `path`. Returns the user and device the token acts
+    /// as; expired tokens are forgotten.
+    pub fn authenticate(&self, token: &str, method: &str, path: &str) -> Option<(String, String)> {
+        let session = self.session(token)?;
+        self.record(&session, "used", None, Some((method, path)));
+        Some((session.user_id, session.device_id))
+    }
+
+    /// User and device an impersonation token acts as, without auditing
+    /// it, for requests [`Self::authenticate`] already audited
+    pub fn lookup(&self, token: &str) -> Option<(String, String)> {
+        self.session(token).map(|session| (session.user_id, session.device_id))
+    }
+
+    fn session(&self, token: &str) -> Option<Session> {
+        let session = self.sessions.read().unwrap().get(token).cloned()?;
+        if session.expires_at <= now_millis() {
+            self.sessions.write().unwrap().remove(token);
+            info!("🕵️ Impersonation of {} by {} expired", session.user_id, session.admin);
+            return None;
+        }
+        Some(session)
+    }
+
+    /// Revoke every impersonation token acting as `user_id`. Returns how
+    /// many were revoked.
+    pub fn revoke(&self, user_id: &str) -> usize {
+        let mut sessions = self.sessions.write().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, session| session.user_id != user_id);
+        before - sessions.len()
+    }
+
+    /// Audit trail, most recent last, optionally for one impersonated user
+    pub fn audit_trail(&self, user_id: Option<&str>) -> Vec<AuditEntry> {
+        self.audit
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|entry| user_id.is_none_or(|user_id| entry.user_id == user_id))
+            .cloned()
+            .collect()
+    }
+
+    fn record(&self, session: &Session, action: &'static str, reason: Option<String>, request: Option<(&str, &str)>) {
+        let entry = AuditEntry {
+            ts: now_millis(),
+            action,
+            admin: session.admin.clone(),
+            user_id: session.user_id.clone(),
+            device_id: session.device_id.clone(),
+            reason,
+            method: request.map(|(method, _)| method.to_owned()),
+            path: request.map(|(_, path)| path.to_owned()),
+        };
+
+        if let Some(path) = &self.audit_log_path {
+            let written = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", serde_json::to_string(&entry).unwrap_or_default()));
+            if let Err(e) = written {
+                warn!("⚠️ Could not write impersonation audit entry to {}: {}", path, e);
+            }
+        }
+
+        let mut audit = self.audit.write().unwrap();
+        if audit.len() >= MAX_AUDIT_ENTRIES {
+            audit.remove(0);
+        }
+        audit.push(entry);
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_act_as_user_and_are_audited() {
+        let service = Service::new(None);
+        let (token, _) = service.issue("@admin:matrixon.local", "@alice:matrixon.local", "sync issue #12", 60);
+        let (user_id, device_id) = service.authenticate(&token, "GET", "/_matrix/client/v3/sync").unwrap();
+        assert_eq!(user_id, "@alice:matrixon.local");
+        assert!(device_id.starts_with("IMPERSONATION_"));
+        assert_eq!(service.lookup(&token), Some((user_id, device_id)));
+
+        let trail = service.audit_trail(Some("@alice:matrixon.local"));
+        assert_eq!(trail.iter().map(|e| e.action).collect::<Vec<_>>(), vec!["issued", "used"]);
+        assert_eq!(trail[0].reason.as_deref(), Some("sync issue #12"));
+        assert_eq!(trail[1].method.as_deref(), Some("GET"));
+        assert_eq!(trail[1].path.as_deref(), Some("/_matrix/client/v3/sync"));
+        assert!(service.audit_trail(Some("@bob:matrixon.local")).is_empty());
+
+        assert_eq!(service.revoke("@alice:matrixon.local"), 1);
+        assert!(service.lookup(&token).is_none());
+    }
+
+    #[test]
+    fn test_expired_tokens_are_rejected() {
+        let service = Service::new(None);
+        let (token, _) = service.issue("@admin:matrixon.local", "@alice:matrixon.local", "push", 0);
+        assert!(service.authenticate(&token, "GET", "/_matrix/client/v3/sync").is_none());
+        assert!(service.sessions.read().unwrap().is_empty());
+
+        // Expired tokens nobody uses again are forgotten on the next issue
+        service.issue("@admin:matrixon.local", "@alice:matrixon.local", "push", 0);
+        service.issue("@admin:matrixon.local", "@bob:matrixon.local", "push", 60);
+        assert_eq!(service.sessions.read().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,1281 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Inbound Federation
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Processing of transactions other servers push to /federation/v1/send.
+//   Requests are authenticated by their X-Matrix signature, transactions are
+//   deduplicated by origin and transaction id, PDUs are checked and appended
+//   to the room timeline and EDUs update typing, receipt, presence and
+//   device list state. Responses of processed transactions are kept for a
+//   while, in a log file when configured so replays after a restart are
+//   answered from it too instead of being applied again. A PDU the current
+//   state does not allow, but the state named by its auth events does, is
+//   soft-failed: kept as an outlier, out of the timeline, rather than
+//   rejected. Transactions can also be recorded as sanitized fixtures for
+//   replay in tests. Each stage of handling a PDU runs in its own span and
+//   is timed in the federation metrics.
+//
+// =============================================================================
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fs::{self, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use ruma::{api::client::error::ErrorKind, serde::Base64, CanonicalJsonObject, CanonicalJsonValue, RoomVersionId};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, debug_span, info, info_span, warn};
+
+use crate::{
+    service::{
+        federation_fixtures::{Fixture, Recorder},
+        federation_metrics::{self, Stage},
+        auth_chain, keys, membership, outlier, partial_state, pdu_metadata, timeline,
+    },
+    Error, Result,
+};
+
+/// Most PDUs and EDUs a single transaction may carry
+pub const MAX_PDUS: usize = 50;
+pub const MAX_EDUS: usize = 100;
+
+/// How many transaction responses are remembered for deduplication
+const REMEMBERED_TRANSACTIONS: usize = 10_000;
+
+/// How long transaction responses are remembered by default
+pub const DEFAULT_TRANSACTION_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// How long a typing notification lasts without a refresh
+pub const TYPING_TIMEOUT_MS: u64 = 30_000;
+
+/// Parsed `Authorization: X-Matrix ...` header of a federation request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XMatrix {
+    pub origin: String,
+    pub destination: Option<String>,
+    pub key: String,
+    pub sig: String,
+}
+
+impl XMatrix {
+    pub fn parse(header: &str) -> Option<Self> {
+        let params = header.strip_prefix("X-Matrix ")?;
+        let mut fields: HashMap<String, String> = HashMap::new();
+        for param in params.split(',') {
+            let (name, value) = param.trim().split_once('=')?;
+            let value = value.trim();
+            let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+            fields.insert(name.trim().to_ascii_lowercase(), value.to_owned());
+        }
+
+        Some(XMatrix {
+            origin: fields.remove("origin")?,
+            destination: fields.remove("destination"),
+            key: fields.remove("key")?,
+            sig: fields.remove("sig")?,
+        })
+    }
+}
+
+/// Check the X-Matrix signature of a request to `destination` against the
+/// origin's verify keys (key id -> unpadded base64 public key)
+pub fn verify_request(
+    x_matrix: &XMatrix,
+    method: &str,
+    uri: &str,
+    destination: &str,
+    content: Option<&Value>,
+    verify_keys: &BTreeMap<String, String>,
+) -> bool {
+    if x_matrix.destination.as_deref().is_some_and(|d| d != destination) {
+        return false;
+    }
+    let Some(public_key) = verify_keys.get(&x_matrix.key).and_then(|key| Base64::parse(key).ok()) else {
+        return false;
+    };
+
+    let mut request = json!({
+        "method": method,
+        "uri": uri,
+        "origin": x_matrix.origin,
+        "destination": destination,
+        "signatures": { &x_matrix.origin: { &x_matrix.key: x_matrix.sig } },
+    });
+    if let Some(content) = content {
+        request["content"] = content.clone();
+    }
+    let Ok(CanonicalJsonValue::Object(request)) = CanonicalJsonValue::try_from(request) else {
+        return false;
+    };
+
+    let public_key_map = BTreeMap::from([(
+        x_matrix.origin.clone(),
+        BTreeMap::from([(x_matrix.key.clone(), public_key)]),
+    )]);
+    ruma::signatures::verify_json(&public_key_map, &request).is_ok()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Server part of a user id
+fn server_name(user_id: &str) -> Option<&str> {
+    user_id.split_once(':').map(|(_, server)| server)
+}
+
+/// Why a PDU was rejected: a metrics label and a message for the origin
+type Rejection = (&'static str, String);
+
+/// Receipts in a room by (receipt type, user)
+type RoomReceipts = BTreeMap<(String, String), Value>;
+
+/// A processed transaction, as written to the transaction log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ProcessedTransaction {
+    origin: String,
+    txn_id: String,
+    processed_at: u64,
+    response: Value,
+}
+
+/// Responses of processed transactions by (origin, txn id)
+#[derive(Debug)]
+struct Transactions {
+    responses: HashMap<(String, String), ProcessedTransaction>,
+    /// Keys of `responses`, oldest first
+    order: VecDeque<(String, String)>,
+    ttl_ms: u64,
+    /// JSON lines file every processed transaction is appended to
+    log_path: Option<PathBuf>,
+    /// Lines in the log file, compacted once mostly expired
+    logged: usize,
+}
+
+impl Default for Transactions {
+    fn default() -> Self {
+        Self {
+            responses: HashMap::new(),
+            order: VecDeque::new(),
+            ttl_ms: DEFAULT_TRANSACTION_TTL_MS,
+            log_path: None,
+            logged: 0,
+        }
+    }
+}
+
+impl Transactions {
+    fn get(&self, key: &(String, String), now_ms: u64) -> Option<&Value> {
+        self.responses
+            .get(key)
+            .filter(|processed| processed.processed_at + self.ttl_ms > now_ms)
+            .map(|processed| &processed.response)
+    }
+
+    fn insert(&mut self, processed: ProcessedTransaction, now_ms: u64) {
+        let key = (processed.origin.clone(), processed.txn_id.clone());
+        if let Some(path) = &self.log_path {
+            let written = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", serde_json::to_string(&processed).unwrap_or_default()));
+            match written {
+                Ok(()) => self.logged += 1,
+                Err(e) => warn!("⚠️ Could not log federation transaction to {}: {}", path.display(), e),
+            }
+        }
+        if self.responses.insert(key.clone(), processed).is_none() {
+            self.order.push_back(key);
+        }
+        self.expire(now_ms);
+        if self.logged > 2 * self.order.len().max(REMEMBERED_TRANSACTIONS / 10) {
+            self.compact();
+        }
+    }
+
+    /// Forget transactions past their lifetime or beyond the most remembered
+    fn expire(&mut self, now_ms: u64) {
+        while let Some(oldest) = self.order.front() {
+            let expired = self
+                .responses
+                .get(oldest)
+                .is_none_or(|processed| processed.processed_at + self.ttl_ms <= now_ms);
+            if !expired && self.order.len() <= REMEMBERED_TRANSACTIONS {
+                break;
+            }
+            if let Some(oldest) = self.order.pop_front() {
+                self.responses.remove(&oldest);
+            }
+        }
+    }
+
+    /// Rewrite the log with only the remembered transactions
+    fn compact(&mut self) {
+        let Some(path) = &self.log_path else {
+            return;
+        };
+        let mut lines = String::new();
+        for processed in self.order.iter().filter_map(|key| self.responses.get(key)) {
+            lines.push_str(&serde_json::to_string(processed).unwrap_or_default());
+            lines.push('\n');
+        }
+        let temporary = path.with_extension("tmp");
+        match fs::write(&temporary, lines).and_then(|()| fs::rename(&temporary, path)) {
+            Ok(()) => self.logged = self.order.len(),
+            Err(e) => warn!("⚠️ Could not compact the federation transaction log {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// Verify keys by key id: the public key and the time in ms until which
+/// it may be used
+pub type ExpiringKeys = BTreeMap<String, (String, u64)>;
+
+/// Federation state received from other servers
+#[derive(Debug, Default)]
+pub struct Service {
+    /// Verify keys of remote servers by server
+    server_keys: RwLock<HashMap<String, ExpiringKeys>>,
+    transactions: RwLock<Transactions>,
+    /// room -> user -> typing expiry in ms since the epoch
+    typing: RwLock<HashMap<String, HashMap<String, u64>>>,
+    receipts: RwLock<HashMap<String, RoomReceipts>>,
+    /// Latest presence of remote users
+    presence: RwLock<HashMap<String, Value>>,
+    /// Remote users whose device list changed, with the change position
+    device_list_changes: RwLock<HashMap<String, u64>>,
+    device_list_count: AtomicU64,
+    metrics: federation_metrics::Service,
+    partial_state: partial_state::Service,
+    outliers: outlier::Service,
+    pdu_metadata: pdu_metadata::Service,
+    auth_chain: auth_chain::Service,
+    fixtures: Option<Recorder>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember transaction responses for `ttl_ms`, persisted to the log at
+    /// `path` if any. Transactions logged before a restart are loaded again.
+    pub fn with_transaction_log(self, path: Option<PathBuf>, ttl_ms: u64, now_ms: u64) -> Self {
+        {
+            let mut transactions = self.transactions.write().unwrap();
+            transactions.ttl_ms = ttl_ms;
+            if let Some(path) = &path {
+                match fs::read_to_string(path) {
+                    Ok(log) => {
+                        let mut entries: Vec<ProcessedTransaction> =
+                            log.lines().filter_map(|line| serde_json::from_str(line).ok()).collect();
+                        entries.sort_by_key(|processed| processed.processed_at);
+                        for processed in entries {
+                            let key = (processed.origin.clone(), processed.txn_id.clone());
+                            if transactions.responses.insert(key.clone(), processed).is_none() {
+                                transactions.order.push_back(key);
+                            }
+                        }
+                        transactions.expire(now_ms);
+                        info!("📥 Loaded {} processed federation transactions", transactions.order.len());
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => warn!("⚠️ Could not read the federation transaction log {}: {}", path.display(), e),
+                }
+                if let Some(dir) = path.parent() {
+                    let _ = fs::create_dir_all(dir);
+                }
+            }
+            transactions.log_path = path;
+            transactions.compact();
+        }
+        self
+    }
+
+    /// Write outliers, references, soft failures and the auth chain index
+    /// through to the repositories, if there are any
+    pub fn with_repositories(mut self, repositories: Option<&matrixon_db::Repositories>) -> Self {
+        if let Some(repositories) = repositories {
+            self.outliers = outlier::Service::new().with_repository(repositories.outliers.clone());
+            self.pdu_metadata = pdu_metadata::Service::new().with_repository(repositories.pdu_metadata.clone());
+            self.auth_chain = std::mem::take(&mut self.auth_chain).with_repository(repositories.auth_chains.clone());
+        }
+        self
+    }
+
+    /// Keep the rooms joined with partial state in the file at `path`
+    pub fn with_partial_state_file(mut self, path: Option<PathBuf>) -> Self {
+        self.partial_state = partial_state::Service::new().with_state_file(path);
+        self
+    }
+
+    /// Scale the auth chain cache by `matrixon_cache_capacity_modifier`
+    pub fn with_cache_capacity_modifier(mut self, modifier: f64) -> Self {
+        self.auth_chain = std::mem::take(&mut self.auth_chain).with_cache_capacity_modifier(modifier);
+        self
+    }
+
+    pub fn metrics(&self) -> &federation_metrics::Service {
+        &self.metrics
+    }
+
+    /// Rooms joined with partial state, whose events are authorized
+    /// leniently until the full state is known
+    pub fn partial_state(&self) -> &partial_state::Service {
+        &self.partial_state
+    }
+
+    /// Events kept outside the timelines: auth events that are not part of
+    /// the current state, and soft-failed events
+    pub fn outliers(&self) -> &outlier::Service {
+        &self.outliers
+    }
+
+    pub fn pdu_metadata(&self) -> &pdu_metadata::Service {
+        &self.pdu_metadata
+    }
+
+    pub fn auth_chains(&self) -> &auth_chain::Service {
+        &self.auth_chain
+    }
+
+    /// Events of the auth chains of `event_ids`, looked up in the timeline
+    /// and among the outliers, lowest depth first
+    pub fn auth_chain(&self, timeline: &timeline::Service, room_id: &str, event_ids: &[String]) -> Vec<Value> {
+        let lookup = |event_id: &str| timeline.get_event(room_id, event_id).or_else(|| self.outliers.get_outlier_pdu(event_id));
+        let mut events: Vec<Value> = self
+            .auth_chain
+            .get_auth_chain(room_id, event_ids, lookup)
+            .iter()
+            .filter_map(|event_id| lookup(event_id))
+            .collect();
+        events.sort_by_key(|event| event["depth"].as_u64().unwrap_or(0));
+        events
+    }
+
+    /// Drop the outliers received before `before_ms` that no event refers
+    /// to, with their soft failures, returning how many
+    pub fn prune_outliers(&self, before_ms: u64) -> usize {
+        let pruned = self
+            .outliers
+            .prune(before_ms, |room_id, event_id| self.pdu_metadata.is_event_referenced(room_id, event_id));
+        let pruned: Vec<String> = pruned.into_iter().map(|(_, event_id)| event_id).collect();
+        self.pdu_metadata.forget_soft_failed(&pruned);
+        self.pdu_metadata.retain_references(|event_id| self.outliers.is_outlier(event_id));
+        pruned.len()
+    }
+
+    /// Load the stored outliers, references, soft failures and auth chain
+    /// index, returning how many outliers there were
+    pub async fn load(&self, repositories: &matrixon_db::Repositories) -> crate::Result<usize> {
+        let database_error = |e: matrixon_core::MatrixonError| crate::Error::BadDatabase(e.to_string());
+        let outliers = repositories.outliers.load().await.map_err(database_error)?;
+        let references = repositories.pdu_metadata.load_references().await.map_err(database_error)?;
+        let soft_failed = repositories.pdu_metadata.load_soft_failed().await.map_err(database_error)?;
+        let (positions, links) = repositories.auth_chains.load().await.map_err(database_error)?;
+        let loaded = outliers.len();
+        self.outliers.restore(outliers);
+        self.pdu_metadata.restore(references, soft_failed);
+        self.auth_chain.restore(positions, links);
+        info!("📥 Loaded {} stored outliers", loaded);
+        Ok(loaded)
+    }
+
+    /// Forget the outliers, references and soft failures of a deleted room
+    pub fn delete_room(&self, room_id: &str) {
+        self.outliers.delete_room(room_id);
+        self.pdu_metadata.delete_room(room_id);
+        self.auth_chain.delete_room(room_id);
+    }
+
+    /// Run one stage of handling a PDU in its own span and time it
+    fn stage<T>(&self, stage: Stage, f: impl FnOnce() -> T) -> T {
+        let _span = debug_span!("federation_stage", stage = stage.as_str()).entered();
+        let start = Instant::now();
+        let result = f();
+        self.metrics.observe_stage(stage, start.elapsed());
+        result
+    }
+
+    /// Record every processed transaction as a fixture in `dir`, if any
+    pub fn with_fixture_recorder(mut self, dir: Option<PathBuf>) -> Self {
+        self.fixtures = dir.map(Recorder::new);
+        self
+    }
+
+    /// Remember verify keys of a remote server that do not expire
+    pub fn add_server_keys(&self, server: &str, verify_keys: BTreeMap<String, String>) {
+        let verify_keys = verify_keys.into_iter().map(|(key_id, key)| (key_id, (key, u64::MAX))).collect();
+        self.add_expiring_server_keys(server, verify_keys);
+    }
+
+    /// Remember the verify keys of a remote server, each with the time
+    /// until which it may be used
+    pub fn add_expiring_server_keys(&self, server: &str, verify_keys: ExpiringKeys) {
+        self.server_keys.write().unwrap().entry(server.to_owned()).or_default().extend(verify_keys);
+    }
+
+    /// Known verify keys of a remote server, expired ones included
+    pub fn server_keys(&self, server: &str) -> Option<BTreeMap<String, String>> {
+        self.server_keys_valid_at(server, 0)
+    }
+
+    /// Verify keys of a remote server that may be used for signatures made
+    /// at `ts`
+    pub fn server_keys_valid_at(&self, server: &str, ts: u64) -> Option<BTreeMap<String, String>> {
+        let server_keys = self.server_keys.read().unwrap();
+        let keys = server_keys.get(server)?.iter().filter(|(_, (_, valid_until))| *valid_until >= ts);
+        Some(keys.map(|(key_id, (key, _))| (key_id.clone(), key.clone())).collect())
+    }
+
+    /// Whether `key_id` of `server` is known and may be used now, so a
+    /// request signed with it can be checked without fetching keys
+    pub fn knows_key(&self, server: &str, key_id: &str) -> bool {
+        self.server_keys_valid_at(server, now_millis()).is_some_and(|keys| keys.contains_key(key_id))
+    }
+
+    /// Authenticate a federation request, returning the origin server
+    pub fn authenticate(
+        &self,
+        authorization: Option<&str>,
+        method: &str,
+        uri: &str,
+        destination: &str,
+        content: Option<&Value>,
+    ) -> Result<String> {
+        let x_matrix = authorization
+            .and_then(XMatrix::parse)
+            .ok_or(Error::BadRequest(ErrorKind::Unauthorized, "Missing or invalid X-Matrix authorization"))?;
+        let verify_keys = self
+            .server_keys_valid_at(&x_matrix.origin, now_millis())
+            .ok_or(Error::BadRequest(ErrorKind::Unauthorized, "Signing keys of the origin are unknown"))?;
+
+        if !verify_request(&x_matrix, method, uri, destination, content, &verify_keys) {
+            warn!("❌ Rejected federation request from {}: bad signature", x_matrix.origin);
+            return Err(Error::BadRequest(ErrorKind::Unauthorized, "Invalid request signature"));
+        }
+        Ok(x_matrix.origin)
+    }
+
+    /// Process a `/send` transaction and build its response. A transaction
+    /// that was already processed gets the same response again.
+    pub fn handle_transaction(
+        &self,
+        origin: &str,
+        txn_id: &str,
+        body: &Value,
+        timeline: &timeline::Service,
+        keys: &keys::Service,
+        now_ms: u64,
+    ) -> Result<Value> {
+        let txn_key = (origin.to_owned(), txn_id.to_owned());
+        if let Some(response) = self.transactions.read().unwrap().get(&txn_key, now_ms) {
+            debug!("🔁 Transaction {} from {} was already processed", txn_id, origin);
+            return Ok(response.clone());
+        }
+
+        let pdus = body["pdus"].as_array().map(Vec::as_slice).unwrap_or_default();
+        let edus = body["edus"].as_array().map(Vec::as_slice).unwrap_or_default();
+        if pdus.len() > MAX_PDUS || edus.len() > MAX_EDUS {
+            return Err(Error::BadRequest(ErrorKind::TooLarge, "Too many PDUs or EDUs in transaction"));
+        }
+
+        let fixture = self.fixtures.as_ref().map(|_| {
+            Fixture::capture(origin, txn_id, now_ms, pdus, timeline, |server| self.server_keys(server))
+        });
+
+        let mut results = serde_json::Map::new();
+        for pdu in pdus {
+            let (event_id, result) = match pdu_event_id(pdu, timeline) {
+                Ok((event_id, room_version)) => {
+                    let result = self.handle_pdu(pdu, &event_id, &room_version, timeline);
+                    (event_id, result)
+                }
+                Err(error) => {
+                    self.metrics.rejected("event_id");
+                    (pdu["event_id"].as_str().unwrap_or_default().to_owned(), Err(error))
+                }
+            };
+            if let Err(error) = &result {
+                debug!("❌ PDU {} from {} rejected: {}", event_id, origin, error);
+            }
+            results.insert(event_id, result.map_or_else(|error| json!({ "error": error }), |()| json!({})));
+        }
+        for edu in edus {
+            self.handle_edu(origin, edu, keys, now_ms);
+        }
+
+        info!("📥 Transaction {} from {}: {} PDUs, {} EDUs", txn_id, origin, pdus.len(), edus.len());
+        let response = json!({ "pdus": results });
+        if let (Some(recorder), Some(mut fixture)) = (&self.fixtures, fixture) {
+            fixture.response = response.clone();
+            recorder.record(&fixture);
+        }
+
+        let (origin, txn_id) = txn_key;
+        let processed = ProcessedTransaction { origin, txn_id, processed_at: now_ms, response: response.clone() };
+        self.transactions.write().unwrap().insert(processed, now_ms);
+        Ok(response)
+    }
+
+    /// Check a PDU with the given id and append it to its room, or say why
+    /// it was rejected
+    pub fn handle_pdu(
+        &self,
+        pdu: &Value,
+        event_id: &str,
+        room_version: &RoomVersionId,
+        timeline: &timeline::Service,
+    ) -> std::result::Result<(), String> {
+        self.handle(pdu, event_id, room_version, timeline, false)
+    }
+
+    /// Like [`Service::handle_pdu`], for an event of the state of a room
+    /// another server sent when it was joined. That state is the outcome of
+    /// state resolution on the other server, so the event only needs to be
+    /// allowed by its auth events.
+    pub fn handle_state_pdu(
+        &self,
+        pdu: &Value,
+        event_id: &str,
+        room_version: &RoomVersionId,
+        timeline: &timeline::Service,
+    ) -> std::result::Result<(), String> {
+        self.handle(pdu, event_id, room_version, timeline, true)
+    }
+
+    fn handle(
+        &self,
+        pdu: &Value,
+        event_id: &str,
+        room_version: &RoomVersionId,
+        timeline: &timeline::Service,
+        resolved_state: bool,
+    ) -> std::result::Result<(), String> {
+        let room_id = pdu["room_id"].as_str().unwrap_or_default();
+        let _span = info_span!("federation_pdu", event_id, room_id).entered();
+        match self.check_and_append(pdu, event_id, room_version, timeline, resolved_state) {
+            Ok(appended) => {
+                if appended {
+                    self.metrics.accepted();
+                }
+                Ok(())
+            }
+            Err((reason, message)) => {
+                self.metrics.rejected(reason);
+                Err(message)
+            }
+        }
+    }
+
+    /// Check the signatures of an auth event of `room_id` that is not part
+    /// of its current state and keep it as an outlier, or say why it was
+    /// rejected
+    pub fn add_outlier(
+        &self,
+        room_id: &str,
+        pdu: &Value,
+        event_id: &str,
+        room_version: &RoomVersionId,
+        timeline: &timeline::Service,
+    ) -> std::result::Result<(), String> {
+        if pdu["room_id"].as_str() != Some(room_id) {
+            return Err(format!("Event is not of {}", room_id));
+        }
+        if timeline.get_event(room_id, event_id).is_some() || self.outliers.is_outlier(event_id) {
+            return Ok(());
+        }
+        let mut event = self.verified_event(pdu, event_id, room_version).map_err(|(_, message)| message)?;
+        event["event_id"] = json!(event_id);
+        self.outliers.add_pdu_outlier(room_id, event_id, event, now_millis());
+        Ok(())
+    }
+
+    /// The stages of [`Service::handle_pdu`], returning whether the PDU
+    /// was appended to the timeline. Events of `resolved_state` allowed by
+    /// their auth events are appended rather than soft-failed.
+    fn check_and_append(
+        &self,
+        pdu: &Value,
+        event_id: &str,
+        room_version: &RoomVersionId,
+        timeline: &timeline::Service,
+        resolved_state: bool,
+    ) -> std::result::Result<bool, Rejection> {
+        let invalid = |message: String| ("invalid", message);
+        let room_id = pdu["room_id"].as_str().ok_or_else(|| invalid("PDU has no room_id".to_owned()))?;
+        if timeline.get_event(room_id, event_id).is_some() || self.pdu_metadata.is_event_soft_failed(event_id) {
+            return Ok(false);
+        }
+        let mut event = self.verified_event(pdu, event_id, room_version)?;
+        let state = self.stage(Stage::StateResolution, || {
+            AuthState::resolve(timeline, room_id, &event, &self.partial_state)
+        });
+        event["event_id"] = json!(event_id);
+
+        if let Err(error) = self.stage(Stage::Auth, || authorize(&state, &event)) {
+            if !self.stage(Stage::Auth, || self.authorized_by_auth_events(timeline, room_id, &event)) {
+                return Err(("auth", error));
+            }
+            if !resolved_state {
+                // The state the sender based the event on allows it: keep
+                // it, but out of the timeline
+                let reason = state.soft_fail_reason();
+                debug!("🔕 Soft-failing {}: {}", event_id, error);
+                self.pdu_metadata.mark_event_soft_failed(room_id, event_id, reason);
+                self.outliers.add_pdu_outlier(room_id, event_id, event, now_millis());
+                self.metrics.soft_failed(reason);
+                return Ok(false);
+            }
+        }
+
+        if state.unknown_sender_trusted {
+            self.partial_state.accepted_leniently(room_id, event_id);
+        }
+        self.pdu_metadata.mark_as_referenced(room_id, &event, |event_id| self.outliers.is_outlier(event_id));
+        self.outliers.remove(event_id);
+        self.stage(Stage::Persistence, || timeline.append_pdu(room_id, event));
+        Ok(true)
+    }
+
+    /// Check the events of a room accepted while its state was partial
+    /// against its full state. Events whose sender turns out not to be
+    /// joined are soft-failed; clients may have seen them already, so they
+    /// stay in the timeline, redacted. Returns how many were.
+    pub fn reauthorize(&self, timeline: &timeline::Service, room_id: &str, event_ids: &[String]) -> usize {
+        let mut rejected = 0;
+        for event_id in event_ids {
+            let Some(mut event) = timeline.get_event(room_id, event_id) else {
+                continue;
+            };
+            let state = AuthState::resolve(timeline, room_id, &event, &self.partial_state);
+            let Err(error) = authorize(&state, &event) else {
+                continue;
+            };
+            if self.authorized_by_auth_events(timeline, room_id, &event) {
+                continue;
+            }
+            warn!("⚠️ {} was accepted before the full state of {} was known: {}", event_id, room_id, error);
+            let reason = state.soft_fail_reason();
+            self.pdu_metadata.mark_event_soft_failed(room_id, event_id, reason);
+            self.metrics.soft_failed(reason);
+            timeline::redact_event(&mut event);
+            timeline.replace_event(room_id, event_id, event);
+            rejected += 1;
+        }
+        rejected
+    }
+
+    /// Check the sender and signatures of a PDU, returning it as stored:
+    /// redacted if its content hash does not match
+    fn verified_event(&self, pdu: &Value, event_id: &str, room_version: &RoomVersionId) -> std::result::Result<Value, Rejection> {
+        let invalid = |message: String| ("invalid", message);
+        let sender = pdu["sender"].as_str().ok_or_else(|| invalid("PDU has no sender".to_owned()))?;
+        let sender_server = server_name(sender).ok_or_else(|| invalid("Invalid sender".to_owned()))?;
+        let Ok(CanonicalJsonValue::Object(mut object)) = CanonicalJsonValue::try_from(pdu.clone()) else {
+            return Err(invalid("PDU is not valid canonical JSON".to_owned()));
+        };
+
+        if self.server_keys(sender_server).is_none() {
+            return Err(("unknown_keys", format!("Signing keys of {} are unknown", sender_server)));
+        }
+        let verified = self
+            .stage(Stage::SignatureCheck, || self.verify_pdu(&object, room_version))
+            .map_err(|e| ("signatures", e))?;
+        match verified {
+            ruma::signatures::Verified::All => {}
+            ruma::signatures::Verified::Signatures => {
+                // Content hash mismatch: keep the event, but only its redacted form
+                warn!("⚠️ Content hash of {} does not match, storing it redacted", event_id);
+                object = ruma::canonical_json::redact(object, room_version, None).map_err(|e| invalid(e.to_string()))?;
+            }
+        }
+        serde_json::to_value(&object).map_err(|e| invalid(e.to_string()))
+    }
+
+    /// Whether the state `event` names as its auth events allows it. Auth
+    /// events are looked up in the timeline and among the outliers; they
+    /// must include the create event of the room, and the sender's
+    /// membership is unknown when none of them is.
+    fn authorized_by_auth_events(&self, timeline: &timeline::Service, room_id: &str, event: &Value) -> bool {
+        let sender = event["sender"].as_str().unwrap_or_default();
+        let auth_events: Vec<Value> = pdu_metadata::referenced_event_ids(event, "auth_events")
+            .filter_map(|auth_event_id| {
+                timeline.get_event(room_id, auth_event_id).or_else(|| self.outliers.get_outlier_pdu(auth_event_id))
+            })
+            .collect();
+        if !auth_events.iter().any(|auth_event| auth_event["type"] == "m.room.create") {
+            return false;
+        }
+        let sender_membership = auth_events
+            .iter()
+            .find(|auth_event| auth_event["type"] == "m.room.member" && auth_event["state_key"] == sender)
+            .and_then(|auth_event| auth_event["content"]["membership"].as_str().map(str::to_owned));
+        let state = AuthState {
+            sender_membership,
+            restricted_join: false,
+            authoriser_may_authorise: false,
+            unknown_sender_trusted: false,
+            state_events: auth_events,
+        };
+        authorize(&state, event).is_ok()
+    }
+
+    /// Verify the signatures of a PDU with the keys of the servers that
+    /// signed it that were valid when it was sent
+    pub fn verify_pdu(
+        &self,
+        object: &CanonicalJsonObject,
+        room_version: &RoomVersionId,
+    ) -> std::result::Result<ruma::signatures::Verified, String> {
+        let origin_server_ts = match object.get("origin_server_ts") {
+            Some(CanonicalJsonValue::Integer(ts)) => u64::try_from(i64::from(*ts)).unwrap_or_default(),
+            _ => 0,
+        };
+        // Other servers may have signed too, e.g. the one authorising a
+        // restricted join
+        let public_key_map = object
+            .get("signatures")
+            .and_then(|signatures| match signatures {
+                CanonicalJsonValue::Object(signatures) => Some(signatures),
+                _ => None,
+            })
+            .into_iter()
+            .flatten()
+            .filter_map(|(server, _)| {
+                let public_keys = self
+                    .server_keys_valid_at(server, origin_server_ts)?
+                    .into_iter()
+                    .filter_map(|(key_id, key)| Some((key_id, Base64::parse(key).ok()?)))
+                    .collect();
+                Some((server.clone(), public_keys))
+            })
+            .collect();
+        ruma::signatures::verify_event(&public_key_map, object, room_version)
+            .map_err(|e| format!("Signature verification failed: {}", e))
+    }
+
+    /// Apply an EDU. EDUs about users of other servers than the origin are
+    /// ignored.
+    pub fn handle_edu(&self, origin: &str, edu: &Value, keys: &keys::Service, now_ms: u64) {
+        let content = &edu["content"];
+        let from_origin = |user_id: &str| server_name(user_id) == Some(origin);
+
+        match edu["edu_type"].as_str().unwrap_or_default() {
+            "m.typing" => {
+                let (Some(room_id), Some(user_id)) = (content["room_id"].as_str(), content["user_id"].as_str()) else {
+                    return;
+                };
+                if from_origin(user_id) {
+                    self.set_typing(room_id, user_id, content["typing"].as_bool() == Some(true), now_ms + TYPING_TIMEOUT_MS);
+                }
+            }
+            "m.receipt" => {
+                for (room_id, by_type) in content.as_object().into_iter().flatten() {
+                    for (receipt_type, by_user) in by_type.as_object().into_iter().flatten() {
+                        for (user_id, receipt) in by_user.as_object().into_iter().flatten() {
+                            if from_origin(user_id) {
+                                self.set_receipt(room_id, receipt_type, user_id, receipt.clone());
+                            }
+                        }
+                    }
+                }
+            }
+            "m.presence" => {
+                let mut presence = self.presence.write().unwrap();
+                for update in content["push"].as_array().into_iter().flatten() {
+                    if let Some(user_id) = update["user_id"].as_str().filter(|user_id| from_origin(user_id)) {
+                        let mut update = update.clone();
+                        update["last_updated_ts"] = json!(now_ms);
+                        presence.insert(user_id.to_owned(), update);
+                    }
+                }
+            }
+            "m.device_list_update" => {
+                let (Some(user_id), Some(device_id)) = (content["user_id"].as_str(), content["device_id"].as_str()) else {
+                    return;
+                };
+                if !from_origin(user_id) {
+                    return;
+                }
+                if content["deleted"].as_bool() == Some(true) {
+                    keys.remove_device(user_id, device_id);
+                } else if content["keys"].is_object() {
+                    if let Err(e) = keys.add_device_keys(user_id, device_id, &content["keys"]) {
+                        warn!("❌ Device keys of {} ({}) from {} rejected: {}", user_id, device_id, origin, e);
+                    }
+                }
+                let count = self.device_list_count.fetch_add(1, Ordering::SeqCst) + 1;
+                self.device_list_changes.write().unwrap().insert(user_id.to_owned(), count);
+            }
+            other => debug!("🤷 Ignoring EDU {} from {}", other, origin),
+        }
+    }
+
+    /// Record that a user started typing in a room, until `until_ms`, or
+    /// stopped
+    pub fn set_typing(&self, room_id: &str, user_id: &str, typing: bool, until_ms: u64) {
+        let mut rooms = self.typing.write().unwrap();
+        let room = rooms.entry(room_id.to_owned()).or_default();
+        if typing {
+            room.insert(user_id.to_owned(), until_ms);
+        } else {
+            room.remove(user_id);
+        }
+    }
+
+    /// Record the latest receipt of a type a user sent in a room, as
+    /// `{ "event_ids": [...], "data": { "ts": ... } }`
+    pub fn set_receipt(&self, room_id: &str, receipt_type: &str, user_id: &str, receipt: Value) {
+        self.receipts
+            .write()
+            .unwrap()
+            .entry(room_id.to_owned())
+            .or_default()
+            .insert((receipt_type.to_owned(), user_id.to_owned()), receipt);
+    }
+
+    /// Users currently typing in a room
+    pub fn typing_users(&self, room_id: &str, now_ms: u64) -> Vec<String> {
+        let typing = self.typing.read().unwrap();
+        let mut users: Vec<String> = typing
+            .get(room_id)
+            .into_iter()
+            .flatten()
+            .filter(|(_, expiry)| **expiry > now_ms)
+            .map(|(user_id, _)| user_id.clone())
+            .collect();
+        users.sort();
+        users
+    }
+
+    /// Receipts of users in a room as `(receipt type, user, receipt)`
+    pub fn receipts(&self, room_id: &str) -> Vec<(String, String, Value)> {
+        let receipts = self.receipts.read().unwrap();
+        receipts
+            .get(room_id)
+            .into_iter()
+            .flatten()
+            .map(|((receipt_type, user_id), receipt)| (receipt_type.clone(), user_id.clone(), receipt.clone()))
+            .collect()
+    }
+
+    /// Latest presence a remote server reported for one of its users
+    pub fn presence(&self, user_id: &str) -> Option<Value> {
+        self.presence.read().unwrap().get(user_id).cloned()
+    }
+
+    /// Remote users whose device lists changed after position `since`,
+    /// with the current position
+    pub fn device_list_changes_since(&self, since: u64) -> (Vec<String>, u64) {
+        let changes = self.device_list_changes.read().unwrap();
+        let mut users: Vec<String> = changes
+            .iter()
+            .filter(|(_, count)| **count > since)
+            .map(|(user_id, _)| user_id.clone())
+            .collect();
+        users.sort();
+        (users, self.device_list_count.load(Ordering::SeqCst))
+    }
+}
+
+/// Event id and room version of a PDU for a room known to this server. The
+/// event id is given explicitly up to room version 2 and derived from the
+/// reference hash after that.
+pub fn pdu_event_id(pdu: &Value, timeline: &timeline::Service) -> std::result::Result<(String, RoomVersionId), String> {
+    let room_id = pdu["room_id"].as_str().ok_or("PDU has no room_id")?;
+    if !timeline.room_exists(room_id) {
+        return Err("Room is unknown to this server".to_owned());
+    }
+    let room_version = room_version(timeline, room_id)?;
+    Ok((reference_event_id(pdu, &room_version)?, room_version))
+}
+
+/// Version of a room according to its create event
+pub fn room_version(timeline: &timeline::Service, room_id: &str) -> std::result::Result<RoomVersionId, String> {
+    let room_version = timeline
+        .state_event(room_id, "m.room.create", "")
+        .and_then(|create| create["content"]["room_version"].as_str().map(str::to_owned))
+        .unwrap_or_else(|| "1".to_owned());
+    RoomVersionId::try_from(room_version.as_str()).map_err(|e| e.to_string())
+}
+
+/// Event id of a PDU: the one it carries in room versions 1 and 2, its
+/// reference hash from version 3 on
+pub fn reference_event_id(pdu: &Value, room_version: &RoomVersionId) -> std::result::Result<String, String> {
+    let event_id = match room_version {
+        RoomVersionId::V1 | RoomVersionId::V2 => pdu["event_id"].as_str().ok_or("PDU has no event_id")?.to_owned(),
+        _ => {
+            let Ok(CanonicalJsonValue::Object(object)) = CanonicalJsonValue::try_from(pdu.clone()) else {
+                return Err("PDU is not valid canonical JSON".to_owned());
+            };
+            let hash = ruma::signatures::reference_hash(&object, room_version).map_err(|e| e.to_string())?;
+            format!("${}", hash)
+        }
+    };
+    Ok(event_id)
+}
+
+/// Membership of a user according to the current room state
+fn membership_in(timeline: &timeline::Service, room_id: &str, user_id: &str) -> Option<String> {
+    timeline
+        .state_event(room_id, "m.room.member", user_id)
+        .and_then(|event| event["content"]["membership"].as_str().map(str::to_owned))
+}
+
+/// The part of the current room state an event is authorized against
+#[derive(Debug)]
+struct AuthState {
+    sender_membership: Option<String>,
+    /// Whether the event is a join relying on a restricted join rule
+    restricted_join: bool,
+    /// Whether the user the event names as authorising its join may do so
+    authoriser_may_authorise: bool,
+    /// Whether the sender's membership is unknown in a room joined with
+    /// partial state, and the sender belongs to one of the servers in it,
+    /// so they may well be joined
+    unknown_sender_trusted: bool,
+    /// The create and power levels events, and the membership of the user
+    /// a membership event is about, that power levels are checked against
+    state_events: Vec<Value>,
+}
+
+impl AuthState {
+    fn resolve(timeline: &timeline::Service, room_id: &str, event: &Value, partial_state: &partial_state::Service) -> Self {
+        let sender = event["sender"].as_str().unwrap_or_default();
+        let sender_membership = membership_in(timeline, room_id, sender);
+        let unknown_sender_trusted = sender_membership.is_none()
+            && server_name(sender).is_some_and(|server| partial_state.trusts(room_id, server));
+        let restricted_join = event["type"] == "m.room.member"
+            && event["state_key"] == sender
+            && event["content"]["membership"] == "join"
+            && !matches!(sender_membership.as_deref(), Some("join") | Some("invite"))
+            && membership::allowed_rooms(timeline, room_id).is_some();
+        let authoriser_may_authorise = restricted_join
+            && event["content"]["join_authorised_via_users_server"]
+                .as_str()
+                .is_some_and(|authoriser| membership::can_authorise_joins(timeline, room_id, authoriser));
+        let mut state_events: Vec<Value> = ["m.room.create", "m.room.power_levels"]
+            .into_iter()
+            .filter_map(|event_type| timeline.state_event(room_id, event_type, ""))
+            .collect();
+        if let Some(target) = event["state_key"].as_str().filter(|_| event["type"] == "m.room.member") {
+            state_events.extend(timeline.state_event(room_id, "m.room.member", target));
+        }
+        Self { sender_membership, restricted_join, authoriser_may_authorise, unknown_sender_trusted, state_events }
+    }
+
+    fn state_event(&self, event_type: &str, state_key: &str) -> Option<Value> {
+        self.state_events
+            .iter()
+            .find(|event| event["type"] == event_type && event["state_key"] == state_key)
+            .cloned()
+    }
+
+    /// Why an event this state does not allow is soft-failed
+    fn soft_fail_reason(&self) -> &'static str {
+        match self.sender_membership.as_deref() {
+            Some("ban") => "banned",
+            Some("join") if self.restricted_join => "restricted_join",
+            _ => "not_joined",
+        }
+    }
+}
+
+/// Authorization against the current state: a create event must be the
+/// first event of a room of its sender's server. Banned senders are
+/// rejected, and apart from their own membership changes senders must be
+/// joined to the room. Joins relying on a restricted join rule must name a
+/// user who may authorise them and carry the signature of their server.
+/// Past that the event must pass the power levels of the room the way
+/// events of local users do, see [`membership::check_power_levels`]. In
+/// rooms with partial state, senders whose membership is not known yet are
+/// trusted if they belong to one of the servers in the room.
+fn authorize(state: &AuthState, event: &Value) -> std::result::Result<(), String> {
+    let sender = event["sender"].as_str().unwrap_or_default();
+    if event["type"] == "m.room.create" {
+        if event["room_id"].as_str().and_then(server_name) != server_name(sender) {
+            return Err("The room was not created by a user of its server".to_owned());
+        }
+        return membership::check_power_levels(&|event_type, state_key| state.state_event(event_type, state_key), event)
+            .map_err(str::to_owned);
+    }
+    if state.sender_membership.as_deref() == Some("ban") {
+        return Err("Sender is banned from the room".to_owned());
+    }
+
+    let own_membership_change = event["type"] == "m.room.member" && event["state_key"] == sender;
+    if !own_membership_change && state.sender_membership.as_deref() != Some("join") && !state.unknown_sender_trusted {
+        return Err("Sender is not joined to the room".to_owned());
+    }
+
+    if state.restricted_join {
+        let authoriser = event["content"]["join_authorised_via_users_server"]
+            .as_str()
+            .ok_or("Restricted join without an authorising user")?;
+        if !state.authoriser_may_authorise {
+            return Err("The authorising user may not authorise joins".to_owned());
+        }
+        let authoriser_server = server_name(authoriser).ok_or("Invalid authorising user")?;
+        if !event["signatures"][authoriser_server].is_object() {
+            return Err("The join is not signed by the server of the authorising user".to_owned());
+        }
+    }
+    membership::check_power_levels(&|event_type, state_key| state.state_event(event_type, state_key), event).map_err(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ruma::signatures::{Ed25519KeyPair, KeyPair};
+
+    fn key_pair() -> Ed25519KeyPair {
+        let document = Ed25519KeyPair::generate().unwrap();
+        Ed25519KeyPair::from_der(&document, "1".to_owned()).unwrap()
+    }
+
+    fn verify_keys(key_pair: &Ed25519KeyPair) -> BTreeMap<String, String> {
+        let public_key: Base64 = Base64::new(key_pair.public_key().to_vec());
+        BTreeMap::from([("ed25519:1".to_owned(), public_key.encode())])
+    }
+
+    #[test]
+    fn test_request_signature() {
+        let key_pair = key_pair();
+        let content = json!({ "pdus": [], "edus": [] });
+        let request = json!({
+            "method": "PUT",
+            "uri": "/_matrix/federation/v1/send/1",
+            "origin": "remote.example",
+            "destination": "matrixon.local",
+            "content": content,
+        });
+        let CanonicalJsonValue::Object(mut object) = CanonicalJsonValue::try_from(request).unwrap() else {
+            unreachable!()
+        };
+        ruma::signatures::sign_json("remote.example", &key_pair, &mut object).unwrap();
+        let signatures = serde_json::to_value(&object["signatures"]).unwrap();
+        let sig = signatures["remote.example"]["ed25519:1"].as_str().unwrap();
+
+        let header = format!(
+            r#"X-Matrix origin="remote.example",destination="matrixon.local",key="ed25519:1",sig="{}""#,
+            sig
+        );
+        let x_matrix = XMatrix::parse(&header).unwrap();
+        assert_eq!(x_matrix.origin, "remote.example");
+
+        let keys = verify_keys(&key_pair);
+        let uri = "/_matrix/federation/v1/send/1";
+        assert!(verify_request(&x_matrix, "PUT", uri, "matrixon.local", Some(&content), &keys));
+        assert!(!verify_request(&x_matrix, "PUT", uri, "matrixon.local", Some(&json!({})), &keys));
+        assert!(!verify_request(&x_matrix, "PUT", uri, "other.example", Some(&content), &keys));
+
+        let service = Service::new();
+        let expired = keys.into_iter().map(|(key_id, key)| (key_id, (key, 1))).collect();
+        service.add_expiring_server_keys("remote.example", expired);
+        assert!(!service.knows_key("remote.example", "ed25519:1"));
+        assert!(service.authenticate(Some(&header), "PUT", uri, "matrixon.local", Some(&content)).is_err());
+        service.add_server_keys("remote.example", verify_keys(&key_pair));
+        assert!(service.knows_key("remote.example", "ed25519:1"));
+        assert!(service.authenticate(Some(&header), "PUT", uri, "matrixon.local", Some(&content)).is_ok());
+    }
+
+    #[test]
+    fn test_transaction_processing() {
+        let service = Service::new();
+        let timeline = timeline::Service::new();
+        let keys = keys::Service::new();
+        let key_pair = key_pair();
+        service.add_server_keys("remote.example", verify_keys(&key_pair));
+
+        let room_id = "!room:matrixon.local";
+        timeline.append_event(room_id, "@alice:matrixon.local", "m.room.create", Some(""), json!({ "room_version": "10" }));
+        timeline.append_event(
+            room_id,
+            "@bob:remote.example",
+            "m.room.member",
+            Some("@bob:remote.example"),
+            json!({ "membership": "join" }),
+        );
+
+        let signed = |content: Value, sender: &str| {
+            let pdu = json!({
+                "room_id": room_id,
+                "sender": sender,
+                "type": "m.room.message",
+                "content": content,
+                "origin_server_ts": 1,
+                "depth": 3,
+                "prev_events": [],
+                "auth_events": [],
+            });
+            let CanonicalJsonValue::Object(mut object) = CanonicalJsonValue::try_from(pdu).unwrap() else {
+                unreachable!()
+            };
+            ruma::signatures::hash_and_sign_event("remote.example", &key_pair, &mut object, &RoomVersionId::V10).unwrap();
+            serde_json::to_value(object).unwrap()
+        };
+        let message = signed(json!({ "body": "hi" }), "@bob:remote.example");
+        let mut forged = message.clone();
+        forged["sender"] = json!("@carol:remote.example");
+
+        let body = json!({
+            "pdus": [message, forged],
+            "edus": [
+                { "edu_type": "m.typing", "content": { "room_id": room_id, "user_id": "@bob:remote.example", "typing": true } },
+                { "edu_type": "m.typing", "content": { "room_id": room_id, "user_id": "@eve:elsewhere.example", "typing": true } }
+            ]
+        });
+        let response = service.handle_transaction("remote.example", "txn1", &body, &timeline, &keys, 1_000).unwrap();
+        let results = response["pdus"].as_object().unwrap();
+        assert_eq!(results.values().filter(|result| result.get("error").is_none()).count(), 1);
+        assert_eq!(results.values().filter(|result| result.get("error").is_some()).count(), 1);
+        assert_eq!(service.typing_users(room_id, 2_000), vec!["@bob:remote.example"]);
+        assert!(service.typing_users(room_id, 1_000 + TYPING_TIMEOUT_MS).is_empty());
+        let metrics = service.metrics().render();
+        assert!(metrics.contains("matrixon_federation_pdus_accepted_total 1\n"));
+        assert!(metrics.contains("matrixon_federation_pdus_rejected_total{reason=\"signatures\"} 1\n"));
+
+        // A retried transaction is answered without being applied again
+        let count = timeline.current_count();
+        let retried = service.handle_transaction("remote.example", "txn1", &body, &timeline, &keys, 3_000).unwrap();
+        assert_eq!(retried, response);
+        assert_eq!(timeline.current_count(), count);
+    }
+
+    #[test]
+    fn test_events_allowed_by_their_auth_events_are_soft_failed() {
+        let service = Service::new();
+        let timeline = timeline::Service::new();
+        let key_pair = key_pair();
+        service.add_server_keys("remote.example", verify_keys(&key_pair));
+
+        let room_id = "!room:matrixon.local";
+        let create = timeline.append_event(room_id, "@alice:matrixon.local", "m.room.create", Some(""), json!({ "room_version": "10" }));
+        let join = timeline.append_event(room_id, "@bob:remote.example", "m.room.member", Some("@bob:remote.example"), json!({ "membership": "join" }));
+        timeline.append_event(room_id, "@alice:matrixon.local", "m.room.member", Some("@bob:remote.example"), json!({ "membership": "leave" }));
+
+        let signed = |sender: &str, auth_events: Vec<&str>| {
+            let pdu = json!({
+                "room_id": room_id,
+                "sender": sender,
+                "type": "m.room.message",
+                "content": { "body": "sent before the kick arrived" },
+                "origin_server_ts": 1,
+                "depth": 3,
+                "prev_events": [join],
+                "auth_events": auth_events,
+            });
+            let CanonicalJsonValue::Object(mut object) = CanonicalJsonValue::try_from(pdu).unwrap() else {
+                unreachable!()
+            };
+            ruma::signatures::hash_and_sign_event("remote.example", &key_pair, &mut object, &RoomVersionId::V10).unwrap();
+            serde_json::to_value(object).unwrap()
+        };
+
+        let count = timeline.current_count();
+        service.handle_pdu(&signed("@bob:remote.example", vec![&create, &join]), "$late", &RoomVersionId::V10, &timeline).unwrap();
+        assert_eq!(timeline.current_count(), count);
+        assert!(service.pdu_metadata().is_event_soft_failed("$late"));
+        assert_eq!(service.outliers().get_outlier_pdu("$late").unwrap()["event_id"], "$late");
+        // Handling it again does not count it twice
+        service.handle_pdu(&signed("@bob:remote.example", vec![&create, &join]), "$late", &RoomVersionId::V10, &timeline).unwrap();
+
+        let error = service.handle_pdu(&signed("@carol:remote.example", vec![&create, &join]), "$forged", &RoomVersionId::V10, &timeline);
+        assert_eq!(error.unwrap_err(), "Sender is not joined to the room");
+        let metrics = service.metrics().render();
+        assert!(metrics.contains("matrixon_federation_pdus_soft_failed_total{reason=\"not_joined\"} 1\n"));
+        assert!(metrics.contains("matrixon_federation_pdus_rejected_total{reason=\"auth\"} 1\n"));
+
+        // Auth events of the state of a room must be of that room
+        let other_room = service.add_outlier("!other:matrixon.local", &signed("@bob:remote.example", vec![&create]), "$auth", &RoomVersionId::V10, &timeline);
+        assert_eq!(other_room.unwrap_err(), "Event is not of !other:matrixon.local");
+        assert!(!service.outliers().is_outlier("$auth"));
+
+        // Unreferenced outliers are dropped once old enough
+        assert_eq!(service.prune_outliers(0), 0);
+        assert_eq!(service.prune_outliers(u64::MAX), 1);
+        assert!(!service.pdu_metadata().is_event_soft_failed("$late"));
+
+        // Joined remote users cannot raise their own power level or kick
+        // the owner of the room, neither by the current state nor by the
+        // auth events they name
+        const OWNER: &str = "@alice:matrixon.local";
+        const DAN: &str = "@dan:remote.example";
+        let owner_join = timeline.append_event(room_id, OWNER, "m.room.member", Some(OWNER), json!({ "membership": "join" }));
+        let power_levels = timeline.append_event(room_id, OWNER, "m.room.power_levels", Some(""), json!({ "users": { OWNER: 100 } }));
+        let dan_join = timeline.append_event(room_id, DAN, "m.room.member", Some(DAN), json!({ "membership": "join" }));
+        let signed_state = |event_type: &str, state_key: &str, content: Value, auth_events: Vec<&str>| {
+            let pdu = json!({
+                "room_id": room_id,
+                "sender": DAN,
+                "type": event_type,
+                "state_key": state_key,
+                "content": content,
+                "origin_server_ts": 2,
+                "depth": 7,
+                "prev_events": [dan_join],
+                "auth_events": auth_events,
+            });
+            let CanonicalJsonValue::Object(mut object) = CanonicalJsonValue::try_from(pdu).unwrap() else {
+                unreachable!()
+            };
+            ruma::signatures::hash_and_sign_event("remote.example", &key_pair, &mut object, &RoomVersionId::V10).unwrap();
+            serde_json::to_value(object).unwrap()
+        };
+
+        let escalation = signed_state(
+            "m.room.power_levels",
+            "",
+            json!({ "users": { OWNER: 100, DAN: 100 } }),
+            vec![&create, &power_levels, &dan_join],
+        );
+        let error = service.handle_pdu(&escalation, "$escalation", &RoomVersionId::V10, &timeline);
+        assert_eq!(error.unwrap_err(), "The sender's power level is too low to send this event");
+        let kick = signed_state("m.room.member", OWNER, json!({ "membership": "leave" }), vec![&create, &power_levels, &dan_join, &owner_join]);
+        let error = service.handle_pdu(&kick, "$kick", &RoomVersionId::V10, &timeline);
+        assert_eq!(error.unwrap_err(), "You do not have permission to kick this user");
+        assert!(!service.pdu_metadata().is_event_soft_failed("$kick"));
+        assert_eq!(membership_in(&timeline, room_id, OWNER).as_deref(), Some("join"));
+        assert_eq!(timeline.state_event(room_id, "m.room.power_levels", "").unwrap()["content"]["users"][DAN], Value::Null);
+    }
+
+    #[test]
+    fn test_transaction_log_survives_restart_until_expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactions.jsonl");
+        let timeline = timeline::Service::new();
+        let keys = keys::Service::new();
+        let body = json!({ "pdus": [], "edus": [] });
+
+        let service = Service::new().with_transaction_log(Some(path.clone()), 10_000, 0);
+        let response = service.handle_transaction("remote.example", "txn1", &body, &timeline, &keys, 1_000).unwrap();
+        service.handle_transaction("remote.example", "txn2", &body, &timeline, &keys, 5_000).unwrap();
+
+        let restarted = Service::new().with_transaction_log(Some(path.clone()), 10_000, 6_000);
+        let transactions = restarted.transactions.read().unwrap();
+        assert_eq!(transactions.get(&("remote.example".to_owned(), "txn1".to_owned()), 6_000), Some(&response));
+        assert!(transactions.get(&("remote.example".to_owned(), "txn1".to_owned()), 11_000).is_none());
+        drop(transactions);
+
+        // Expired entries are dropped from the log when loading
+        Service::new().with_transaction_log(Some(path.clone()), 10_000, 12_000);
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+    }
+}
@@ -0,0 +1,97 @@
+// =============================================================================
+// Matrixon Matrix NextServer - IoT Gateway
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Runs the IoT gateway of the `matrixon-iot` crate when `[global.iot]` is
+//   configured. Commands to battery-powered devices are held back while
+//   every member of the device's room is offline, going by when local
+//   members last made a request; remote members' presence is unknown, so a
+//   room with remote members never counts as offline. Held-back commands
+//   are sent once their batching window passes or a member of the room
+//   becomes active again.
+//
+// =============================================================================
+
+use std::{
+    collections::BTreeSet,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use matrixon_iot::{DeliveryConfig, IoTConfig, IoTManager, PresenceSource};
+use tracing::{info, warn};
+
+use crate::{service::membership, services, Error, Result};
+
+/// How often held-back commands are checked for delivery
+const FLUSH_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Presence of the rooms devices are bound to
+struct RoomPresence {
+    offline_after: Duration,
+}
+
+#[async_trait]
+impl PresenceSource for RoomPresence {
+    async fn all_members_offline(&self, room_id: &str) -> bool {
+        let local_suffix = format!(":{}", services().globals.config.server_name);
+        services()
+            .timeline
+            .current_state(room_id)
+            .iter()
+            .filter(|event| event["type"] == "m.room.member" && event["content"]["membership"] == "join")
+            .filter_map(|event| event["state_key"].as_str())
+            .all(|member| {
+                member.ends_with(&local_suffix) && !services().sessions.active_within(member, self.offline_after)
+            })
+    }
+}
+
+/// Start the IoT gateway
+pub async fn start(config: IoTConfig, offline_after: Duration) -> Result<Arc<IoTManager>> {
+    let mut manager = IoTManager::with_config(config)
+        .await
+        .map_err(|e| Error::BadConfig(format!("Could not start the IoT gateway: {}", e)))?
+        .with_presence_hints(Arc::new(RoomPresence { offline_after }), DeliveryConfig::default());
+    manager
+        .start_processing()
+        .await
+        .map_err(|e| Error::BadConfig(format!("Could not start IoT processing: {}", e)))?;
+    info!("📡 IoT gateway started");
+    Ok(Arc::new(manager))
+}
+
+/// Deliver held-back commands: those whose batching window passed, and
+/// those to devices in rooms a member was active in since the last check
+pub async fn run(manager: Arc<IoTManager>) {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    let mut checked = Instant::now();
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        let rooms: BTreeSet<String> = services()
+            .sessions
+            .active_since(checked)
+            .iter()
+            .flat_map(|user_id| membership::joined_rooms(user_id))
+            .collect();
+        checked = now;
+
+        if let Err(e) = manager.flush_queued_commands(None).await {
+            warn!("⚠️ Could not deliver held-back IoT commands: {}", e);
+        }
+        for room_id in &rooms {
+            if let Err(e) = manager.flush_queued_commands(Some(room_id)).await {
+                warn!("⚠️ Could not deliver held-back IoT commands of {}: {}", room_id, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,203 @@
+// =============================================================================
+// Matrixon Matrix NextServer - IPFS Retrieval
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Retrieval of content addressed by `ipfs://` URIs or bare CIDs through
+//   the configured IPFS HTTP gateway. Plain `http(s)://` URIs are fetched
+//   directly, so callers can treat both kinds of links alike; as they come
+//   from users or other servers, they may only point to public addresses
+//   and redirects are not followed. Downloads are capped in size. Content
+//   fetched by CID is checked against it, which works for CIDv1 of raw
+//   content hashed with SHA-256 (`bafkrei...`), the only kind accepted.
+//
+// =============================================================================
+
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use matrixon_core::health::{ComponentHealth, HealthProbe};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::{service::outbound_http, Error, Result};
+
+/// Longest time a download may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Health of the IPFS gateway: failed while it does not serve content
+pub struct GatewayProbe {
+    client: reqwest::Client,
+    gateway: String,
+}
+
+#[async_trait]
+impl HealthProbe for GatewayProbe {
+    async fn check(&self) -> ComponentHealth {
+        match check_gateway(&self.client, &self.gateway).await {
+            Ok(()) => ComponentHealth::healthy(),
+            Err(e) => ComponentHealth::failed(format!("The IPFS gateway is unreachable: {}", e)),
+        }
+    }
+}
+
+async fn check_gateway(client: &reqwest::Client, gateway: &str) -> std::result::Result<(), String> {
+    client
+        .get(format!("{}/ipfs/bafkqaaa", gateway.trim_end_matches('/')))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Whether `cid` looks like a CID: non-empty and alphanumeric only
+pub fn is_cid(cid: &str) -> bool {
+    !cid.is_empty() && cid.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// SHA-256 digest a CID names, for CIDv1 of raw content in base32
+pub fn sha256_digest(cid: &str) -> Option<[u8; 32]> {
+    let bytes = decode_base32(cid.strip_prefix('b')?)?;
+    // CID version 1, raw codec, sha2-256 multihash of 32 bytes
+    let digest = bytes.strip_prefix(&[0x01, 0x55, 0x12, 0x20])?;
+    digest.try_into().ok()
+}
+
+/// Decode unpadded lowercase RFC 4648 base32
+fn decode_base32(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in encoded.bytes() {
+        let value = match c {
+            b'a'..=b'z' => c - b'a',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+/// HTTP URL of a content URI; `ipfs://` URIs go through the gateway
+pub fn gateway_url(uri: &str, gateway: &str) -> Option<String> {
+    if let Some(path) = uri.strip_prefix("ipfs://") {
+        let path = path.strip_prefix("ipfs/").unwrap_or(path);
+        if path.is_empty() {
+            return None;
+        }
+        return Some(format!("{}/ipfs/{}", gateway.trim_end_matches('/'), path));
+    }
+    (uri.starts_with("https://") || uri.starts_with("http://")).then(|| uri.to_owned())
+}
+
+/// IPFS gateway client
+#[derive(Debug)]
+pub struct Service {
+    /// Client for the configured gateway
+    client: reqwest::Client,
+}
+
+impl Default for Service {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self { client: reqwest::Client::builder().timeout(FETCH_TIMEOUT).build().expect("the IPFS client builds") }
+    }
+
+    /// Fetch the content of a CID, checking that it matches the CID
+    pub async fn fetch_cid(&self, gateway: &str, cid: &str, max_size: u64) -> Result<(Option<String>, Vec<u8>)> {
+        let digest = sha256_digest(cid).ok_or_else(|| Error::BadServerResponse("Unsupported CID".to_owned()))?;
+        let (content_type, data) = self.fetch(gateway, &format!("ipfs://{}", cid), max_size).await?;
+        if Sha256::digest(&data).as_slice() != digest {
+            warn!("❌ Content of {} from the IPFS gateway does not match the CID", cid);
+            return Err(Error::BadServerResponse("The content does not match its CID".to_owned()));
+        }
+        Ok((content_type, data))
+    }
+
+    /// Check that `gateway` serves content, by fetching the empty inline
+    /// CID gateways answer without looking anything up
+    pub async fn check_gateway(&self, gateway: &str) -> std::result::Result<(), String> {
+        check_gateway(&self.client, gateway).await
+    }
+
+    /// Probe for the server's readiness endpoint, see [`GatewayProbe`]
+    pub fn health_probe(&self, gateway: &str) -> Arc<dyn HealthProbe> {
+        Arc::new(GatewayProbe { client: self.client.clone(), gateway: gateway.to_owned() })
+    }
+
+    /// Fetch an `ipfs://` or `http(s)://` URI, returning its content type
+    /// and at most `max_size` bytes of content
+    pub async fn fetch(&self, gateway: &str, uri: &str, max_size: u64) -> Result<(Option<String>, Vec<u8>)> {
+        let url = gateway_url(uri, gateway).ok_or_else(|| Error::BadServerResponse("Unsupported content URI".to_owned()))?;
+        let client = if uri.starts_with("ipfs://") {
+            self.client.clone()
+        } else {
+            outbound_http::client_for(&outbound_http::check_url(&url)?, FETCH_TIMEOUT).await?
+        };
+        debug!("🌐 Fetching {}", url);
+        let response = client
+            .get(&url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| {
+                warn!("❌ Fetching {} failed: {}", url, e);
+                Error::BadServerResponse("The content could not be fetched".to_owned())
+            })?;
+        let content_type = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.split(';').next().unwrap_or(value).trim().to_owned());
+        let body = outbound_http::read_capped(response, max_size).await?;
+        Ok((content_type, body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gateway_urls() {
+        assert_eq!(
+            gateway_url("ipfs://bafy/1.json", "https://gateway.example/").as_deref(),
+            Some("https://gateway.example/ipfs/bafy/1.json")
+        );
+        assert_eq!(
+            gateway_url("ipfs://ipfs/bafy", "https://gateway.example").as_deref(),
+            Some("https://gateway.example/ipfs/bafy")
+        );
+        assert!(gateway_url("file:///etc/passwd", "https://gateway.example").is_none());
+        assert!(is_cid("bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi"));
+        assert!(!is_cid("bafy/../../etc"));
+    }
+
+    #[test]
+    fn test_raw_cids_name_their_digest() {
+        // CIDv1 of the raw bytes "hello world"
+        let digest = sha256_digest("bafkreifzjut3te2nhyekklss27nh3k72ysco7y32koao5eei66wof36n5e").unwrap();
+        assert_eq!(digest.as_slice(), Sha256::digest(b"hello world").as_slice());
+        // dag-pb CIDs name a DAG node, not the content
+        assert!(sha256_digest("bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi").is_none());
+        assert!(sha256_digest("QmT78zSuBmuS4z925WZfrqQ1qHaJ56DQaTfyMUF7F8ff5o").is_none());
+    }
+}
@@ -0,0 +1,441 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Federated Join Coordinator
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Joins of rooms this server is not in yet. Fetching the state of a large
+//   room over federation can take minutes, so each room is joined over
+//   federation once: joins to a room already being joined wait for that join
+//   and then join locally. Only a limited number of rooms are joined at
+//   once; the rest wait in a queue. A join runs in the background, so a
+//   client that gives up waiting can retry the request, or poll the join
+//   status, until it is done. A failed join only fails the request of the
+//   user it was made for; users who waited on it try their own join.
+//
+// =============================================================================
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use ruma::api::client::error::ErrorKind;
+use serde_json::{json, Value};
+use tokio::sync::{watch, Semaphore};
+use tracing::{info, warn};
+
+use crate::{Error, Result};
+
+/// Upper bounds in seconds of the join duration histogram buckets
+const DURATION_BUCKETS: [f64; 7] = [1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0];
+
+/// Progress of the federated join of a room
+#[derive(Debug, Clone)]
+pub enum JoinState {
+    /// Waiting for one of the concurrent joins to finish
+    Queued,
+    /// Fetching the room from a server in it
+    Joining,
+    /// Joined for `user_id` with the membership event `event_id`
+    Joined { user_id: String, event_id: String },
+    /// The join for `user_id` failed
+    Failed { user_id: String, error: Arc<Error> },
+}
+
+/// How a join waited on by a user ended
+#[derive(Debug, PartialEq)]
+pub enum JoinOutcome {
+    /// The user's own join completed, with its membership event id
+    Joined(String),
+    /// Another user's join brought the room to this server; the user still
+    /// has to join it locally
+    RoomJoined,
+}
+
+#[derive(Debug)]
+struct PendingJoin {
+    state: watch::Sender<JoinState>,
+    /// Users whose requests waited on the join, who may see its status
+    users: HashSet<String>,
+    queued_at: Instant,
+    queued_at_ms: u64,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    joined: AtomicU64,
+    failed: AtomicU64,
+    /// Joins finished within each bucket of `DURATION_BUCKETS`, and beyond
+    buckets: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    duration_ms: AtomicU64,
+}
+
+/// Join coordinator service
+#[derive(Debug)]
+pub struct Service {
+    pending: Arc<Mutex<HashMap<String, PendingJoin>>>,
+    permits: Arc<Semaphore>,
+    /// How long a join request waits for the join before asking the client
+    /// to retry
+    wait: Duration,
+    stats: Arc<Stats>,
+}
+
+impl Service {
+    pub fn new(max_concurrent: usize, wait: Duration) -> Self {
+        Self {
+            pending: Arc::default(),
+            permits: Arc::new(Semaphore::new(max_concurrent.max(1))),
+            wait,
+            stats: Arc::default(),
+        }
+    }
+
+    /// Join `room_id` for `user_id` over federation with `join`, or wait
+    /// for the join of the room already under way. Returns
+    /// `M_UNKNOWN` if the join does not finish in time; it goes on in the
+    /// background and a retry waits for it again.
+    pub async fn join<F, Fut>(&self, room_id: &str, user_id: &str, join: F) -> Result<JoinOutcome>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<String>> + Send + 'static,
+    {
+        let deadline = tokio::time::Instant::now() + self.wait;
+        let mut join = Some(join);
+        loop {
+            let mut receiver = {
+                let mut pending = self.pending.lock().unwrap();
+                match pending.get_mut(room_id) {
+                    Some(room) => {
+                        room.users.insert(user_id.to_owned());
+                        room.state.subscribe()
+                    }
+                    None => {
+                        // A join for this user that failed is not retried
+                        // within the same request
+                        let Some(join) = join.take() else {
+                            return Err(Error::BadServerResponse("The join of the room was abandoned".to_owned()));
+                        };
+                        let (state, receiver) = watch::channel(JoinState::Queued);
+                        pending.insert(
+                            room_id.to_owned(),
+                            PendingJoin {
+                                state,
+                                users: HashSet::from([user_id.to_owned()]),
+                                queued_at: Instant::now(),
+                                queued_at_ms: now_ms(),
+                            },
+                        );
+                        self.spawn_join(room_id, user_id, join());
+                        receiver
+                    }
+                }
+            };
+
+            let finished = tokio::time::timeout_at(
+                deadline,
+                receiver.wait_for(|state| matches!(state, JoinState::Joined { .. } | JoinState::Failed { .. })),
+            )
+            .await;
+            let state = match finished {
+                Ok(Ok(state)) => state.clone(),
+                // The join task ended without a result
+                Ok(Err(_)) => return Err(Error::BadServerResponse("The join of the room was abandoned".to_owned())),
+                Err(_) => {
+                    return Err(Error::BadRequest(
+                        ErrorKind::Unknown,
+                        "The room is still being joined over federation, retry the request",
+                    ))
+                }
+            };
+            return match state {
+                JoinState::Joined { user_id: joined, event_id } if joined == user_id => Ok(JoinOutcome::Joined(event_id)),
+                JoinState::Joined { .. } => Ok(JoinOutcome::RoomJoined),
+                JoinState::Failed { user_id: failed, error } if failed == user_id => Err(copy_error(&error)),
+                // Why another user could not join says nothing about this
+                // user, who tries their own join
+                JoinState::Failed { .. } => continue,
+                JoinState::Queued | JoinState::Joining => unreachable!("waited for a finished join"),
+            };
+        }
+    }
+
+    /// Progress of the federated join of a room that `user_id` is waiting
+    /// on, if one is under way
+    pub fn status(&self, room_id: &str, user_id: &str) -> Option<Value> {
+        let pending = self.pending.lock().unwrap();
+        let room = pending.get(room_id).filter(|room| room.users.contains(user_id))?;
+        let mut status = json!({ "queued_at": room.queued_at_ms });
+        let state = room.state.borrow().clone();
+        match state {
+            JoinState::Queued => {
+                let position = pending
+                    .values()
+                    .filter(|other| matches!(*other.state.borrow(), JoinState::Queued) && other.queued_at <= room.queued_at)
+                    .count();
+                status["state"] = json!("queued");
+                status["position"] = json!(position);
+            }
+            JoinState::Joining => status["state"] = json!("joining"),
+            JoinState::Joined { .. } => status["state"] = json!("joined"),
+            JoinState::Failed { error, .. } => {
+                status["state"] = json!("failed");
+                status["error"] = json!(error.to_string());
+            }
+        }
+        Some(status)
+    }
+
+    /// Join statistics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let (mut queued, mut joining) = (0, 0);
+        for room in self.pending.lock().unwrap().values() {
+            match *room.state.borrow() {
+                JoinState::Queued => queued += 1,
+                JoinState::Joining => joining += 1,
+                JoinState::Joined { .. } | JoinState::Failed { .. } => {}
+            }
+        }
+        let stats = &self.stats;
+        let mut out = String::new();
+        for (name, help, value) in [
+            ("queued", "Federated room joins waiting for their turn", queued),
+            ("in_progress", "Federated room joins under way", joining),
+        ] {
+            let _ = writeln!(out, "# HELP matrixon_federated_joins_{name} {help}");
+            let _ = writeln!(out, "# TYPE matrixon_federated_joins_{name} gauge");
+            let _ = writeln!(out, "matrixon_federated_joins_{name} {value}");
+        }
+        let _ = writeln!(out, "# HELP matrixon_federated_joins_total Federated room joins finished");
+        let _ = writeln!(out, "# TYPE matrixon_federated_joins_total counter");
+        let _ = writeln!(out, "matrixon_federated_joins_total{{result=\"joined\"}} {}", stats.joined.load(Ordering::Relaxed));
+        let _ = writeln!(out, "matrixon_federated_joins_total{{result=\"failed\"}} {}", stats.failed.load(Ordering::Relaxed));
+
+        let name = "matrixon_federated_join_duration_seconds";
+        let _ = writeln!(out, "# HELP {name} Time from queueing a federated room join to its end");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (index, bucket) in stats.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            match DURATION_BUCKETS.get(index) {
+                Some(bound) => {
+                    let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+                }
+                None => {
+                    let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {cumulative}");
+                }
+            }
+        }
+        let _ = writeln!(out, "{name}_sum {}", stats.duration_ms.load(Ordering::Relaxed) as f64 / 1000.0);
+        let _ = writeln!(out, "{name}_count {cumulative}");
+        out
+    }
+
+    fn spawn_join<Fut>(&self, room_id: &str, user_id: &str, join: Fut)
+    where
+        Fut: Future<Output = Result<String>> + Send + 'static,
+    {
+        let room_id = room_id.to_owned();
+        let user_id = user_id.to_owned();
+        let pending = self.pending.clone();
+        let permits = self.permits.clone();
+        let stats = self.stats.clone();
+        tokio::spawn(async move {
+            // Ends the join even if the task panics or is cancelled, so the
+            // room is not left pending forever
+            let mut guard = PendingGuard { pending, room_id, state: None };
+            let _permit = permits.acquire_owned().await;
+            let started = set_state(&guard.pending, &guard.room_id, JoinState::Joining);
+            let room_id = guard.room_id.clone();
+            let result = join.await;
+
+            let duration = started.elapsed();
+            let bucket = DURATION_BUCKETS.iter().position(|bound| duration.as_secs_f64() <= *bound).unwrap_or(DURATION_BUCKETS.len());
+            stats.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+            stats.duration_ms.fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+            let state = match result {
+                Ok(event_id) => {
+                    info!("🚪 Joined {} over federation in {:?}", room_id, duration);
+                    stats.joined.fetch_add(1, Ordering::Relaxed);
+                    JoinState::Joined { user_id, event_id }
+                }
+                Err(e) => {
+                    warn!("⚠️ Joining {} over federation failed after {:?}: {}", room_id, duration, e);
+                    stats.failed.fetch_add(1, Ordering::Relaxed);
+                    JoinState::Failed { user_id, error: Arc::new(e) }
+                }
+            };
+            guard.state = Some(state);
+        });
+    }
+}
+
+/// Removes a join from the pending joins when its task ends
+struct PendingGuard {
+    pending: Arc<Mutex<HashMap<String, PendingJoin>>>,
+    room_id: String,
+    /// How the join ended; without one, waiting requests see it abandoned
+    state: Option<JoinState>,
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        // Waiting requests still hold a receiver and see the result; later
+        // ones find the room joined or start over
+        let Ok(mut pending) = self.pending.lock() else { return };
+        if let (Some(room), Some(state)) = (pending.remove(&self.room_id), self.state.take()) {
+            room.state.send_replace(state);
+        }
+    }
+}
+
+/// Set the state of a pending join, returning when it was queued
+fn set_state(pending: &Mutex<HashMap<String, PendingJoin>>, room_id: &str, state: JoinState) -> Instant {
+    let pending = pending.lock().unwrap();
+    let room = pending.get(room_id).expect("pending until the join task ends");
+    room.state.send_replace(state);
+    room.queued_at
+}
+
+/// A copy of an error, for every request waiting on the same join
+fn copy_error(error: &Error) -> Error {
+    match error {
+        Error::BadConfig(message) => Error::BadConfig(message.clone()),
+        Error::BadRequest(kind, message) => Error::BadRequest(kind.clone(), message),
+        Error::BadDatabase(message) => Error::BadDatabase(message.clone()),
+        Error::BadServerResponse(message) => Error::BadServerResponse(message.clone()),
+        Error::UserSuspended(reason) => Error::UserSuspended(reason.clone()),
+        Error::Uiaa(body) => Error::Uiaa(body.clone()),
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn test_joins_are_deduplicated_and_queued() {
+        let service = Arc::new(Service::new(1, Duration::from_millis(200)));
+        let (release_first, first) = oneshot::channel::<()>();
+        let (release_second, second) = oneshot::channel::<()>();
+
+        // The first join takes longer than a request waits
+        let timed_out = service
+            .join("!big:remote", "@alice:matrixon.local", || async move {
+                first.await.unwrap();
+                Ok("$alice".to_owned())
+            })
+            .await;
+        assert!(matches!(timed_out, Err(Error::BadRequest(ErrorKind::Unknown, _))));
+        assert_eq!(service.status("!big:remote", "@alice:matrixon.local").unwrap()["state"], "joining");
+        // Other users do not learn about the join
+        assert!(service.status("!big:remote", "@mallory:matrixon.local").is_none());
+
+        // Only one room is joined at a time
+        let queued = service
+            .join("!other:remote", "@bob:matrixon.local", || async move {
+                second.await.unwrap();
+                Err(Error::BadRequest(ErrorKind::NotFound, "No server in the room could be reached"))
+            })
+            .await;
+        assert!(queued.is_err());
+        let status = service.status("!other:remote", "@bob:matrixon.local").unwrap();
+        assert_eq!((status["state"].as_str(), status["position"].as_u64()), (Some("queued"), Some(1)));
+        assert!(service.render().contains("matrixon_federated_joins_queued 1\n"));
+
+        // Retries and joins of other users wait for the join under way
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            release_first.send(()).unwrap();
+        });
+        let not_called = || async { unreachable!("the room is already being joined") };
+        let (retry, other_user) = tokio::join!(
+            service.join("!big:remote", "@alice:matrixon.local", not_called),
+            service.join("!big:remote", "@carol:matrixon.local", not_called),
+        );
+        assert_eq!(retry.unwrap(), JoinOutcome::Joined("$alice".to_owned()));
+        assert_eq!(other_user.unwrap(), JoinOutcome::RoomJoined);
+        assert!(service.status("!big:remote", "@alice:matrixon.local").is_none());
+
+        // The queued join ran once the first finished, and its error reaches
+        // the retry
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            release_second.send(()).unwrap();
+        });
+        let failed = service.join("!other:remote", "@bob:matrixon.local", not_called).await;
+        assert!(matches!(failed, Err(Error::BadRequest(ErrorKind::NotFound, _))));
+
+        let rendered = service.render();
+        assert!(rendered.contains("matrixon_federated_joins_total{result=\"joined\"} 1\n"));
+        assert!(rendered.contains("matrixon_federated_joins_total{result=\"failed\"} 1\n"));
+        assert!(rendered.contains("matrixon_federated_join_duration_seconds_count 2\n"));
+    }
+
+    #[tokio::test]
+    async fn test_failures_only_reach_the_joining_user() {
+        let service = Arc::new(Service::new(1, Duration::from_secs(5)));
+        let (release, released) = oneshot::channel::<()>();
+        let banned = {
+            let service = service.clone();
+            tokio::spawn(async move {
+                service
+                    .join("!room:remote", "@banned:matrixon.local", || async move {
+                        released.await.unwrap();
+                        Err(Error::BadRequest(ErrorKind::forbidden(), "You are banned from the room"))
+                    })
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // Another user waits on the join, then joins on their own
+        let other = {
+            let service = service.clone();
+            tokio::spawn(async move {
+                service
+                    .join("!room:remote", "@alice:matrixon.local", || async { Ok("$alice".to_owned()) })
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(service.status("!room:remote", "@alice:matrixon.local").is_some());
+        release.send(()).unwrap();
+
+        assert!(matches!(banned.await.unwrap(), Err(Error::BadRequest(ErrorKind::Forbidden { .. }, _))));
+        assert_eq!(other.await.unwrap().unwrap(), JoinOutcome::Joined("$alice".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn test_panicking_join_is_not_left_pending() {
+        let service = Service::new(1, Duration::from_secs(5));
+        let abandoned = service
+            .join("!room:remote", "@alice:matrixon.local", || async { panic!("join task panicked") })
+            .await;
+        assert!(matches!(abandoned, Err(Error::BadServerResponse(_))));
+        assert!(service.status("!room:remote", "@alice:matrixon.local").is_none());
+
+        // A later join starts over
+        let joined = service
+            .join("!room:remote", "@alice:matrixon.local", || async { Ok("$alice".to_owned()) })
+            .await;
+        assert_eq!(joined.unwrap(), JoinOutcome::Joined("$alice".to_owned()));
+    }
+}
@@ -0,0 +1,139 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Streaming JSON Responses
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Response bodies serialized incrementally instead of being built as one
+//   JSON blob. The serializer runs on a blocking thread and writes into
+//   fixed size chunks that are handed to the HTTP body as they fill up, so
+//   at most a few chunks are held in memory. Large maps such as the joined
+//   rooms of /sync are produced lazily, one entry at a time, while they are
+//   serialized. Serialization stops early once the client went away.
+//
+// =============================================================================
+
+use std::{io, mem, sync::Mutex};
+
+use axum::{
+    body::{Body, Bytes},
+    http::header,
+    response::{IntoResponse, Response},
+};
+use serde::{Serialize, Serializer};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+/// Size of the chunks handed to the response body
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Chunks buffered ahead of the client
+const CHANNEL_CAPACITY: usize = 4;
+
+/// A map whose entries are produced while it is serialized. It can only be
+/// serialized once; later attempts yield an empty map.
+pub struct LazyMap<I>(Mutex<Option<I>>);
+
+impl<I> LazyMap<I> {
+    pub fn new(entries: I) -> Self {
+        Self(Mutex::new(Some(entries)))
+    }
+}
+
+impl<I, K, V> Serialize for LazyMap<I>
+where
+    I: Iterator<Item = (K, V)>,
+    K: Serialize,
+    V: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let entries = self.0.lock().unwrap().take();
+        serializer.collect_map(entries.into_iter().flatten())
+    }
+}
+
+/// Writer sending full chunks to a response body
+struct ChunkWriter {
+    buffer: Vec<u8>,
+    sender: mpsc::Sender<io::Result<Bytes>>,
+}
+
+impl ChunkWriter {
+    fn send_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        self.sender
+            .blocking_send(Ok(chunk.into()))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "The client went away"))
+    }
+}
+
+impl io::Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffer()
+    }
+}
+
+/// Body streaming the JSON serialization of `value`
+pub fn body<T: Serialize + Send + 'static>(value: T) -> Body {
+    let (sender, mut receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    tokio::task::spawn_blocking(move || {
+        let mut writer = ChunkWriter { buffer: Vec::with_capacity(CHUNK_SIZE), sender };
+        let result = serde_json::to_writer(&mut writer, &value)
+            .map_err(io::Error::from)
+            .and_then(|()| io::Write::flush(&mut writer));
+        if let Err(e) = result {
+            debug!("Streaming a JSON response stopped: {}", e);
+            // Fail the body so the client does not take a truncated response for a complete one
+            let _ = writer.sender.blocking_send(Err(e));
+        }
+    });
+    Body::from_stream(futures::stream::poll_fn(move |cx| receiver.poll_recv(cx)))
+}
+
+/// `200 OK` response streaming the JSON serialization of `value`
+pub fn response<T: Serialize + Send + 'static>(value: T) -> Response {
+    ([(header::CONTENT_TYPE, "application/json")], body(value)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[tokio::test]
+    async fn test_streams_lazy_maps() {
+        let rooms = (0..2000).map(|i| (format!("!room{}:x", i), json!({ "body": "x".repeat(100) })));
+        let response = response(json!({ "before": 1 }));
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+
+        #[derive(Serialize)]
+        struct Sync<J> {
+            next_batch: &'static str,
+            join: J,
+        }
+        let body = body(Sync { next_batch: "s1", join: LazyMap::new(rooms) });
+        let bytes = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        assert!(bytes.len() > 2 * CHUNK_SIZE);
+
+        let value: Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(value["next_batch"], "s1");
+        assert_eq!(value["join"].as_object().unwrap().len(), 2000);
+        assert_eq!(value["join"]["!room7:x"]["body"].as_str().unwrap().len(), 100);
+    }
+}
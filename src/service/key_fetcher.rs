@@ -0,0 +1,437 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Remote Server Key Fetching
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Retrieval of the signing keys of other servers, needed to check their
+//   request and event signatures. Keys are fetched from the server itself
+//   at /_matrix/key/v2/server and, if it cannot be reached, from the
+//   configured trusted notary servers through /_matrix/key/v2/query. Every
+//   response must be self-signed, notary responses are also signed by the
+//   notary. Keys are cached until their `valid_until_ts` and fetched again
+//   once that has passed. Fetches are triggered by unauthenticated
+//   requests, so they are rate limited: the keys of a server are fetched
+//   at most once a minute, not again for a while after that failed, and
+//   only so many fetches start per minute overall.
+//
+// =============================================================================
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Mutex, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use matrixon_federation::sending;
+use ruma::{serde::Base64, CanonicalJsonValue};
+use serde_json::{json, Value};
+use tracing::{debug, info, instrument, warn};
+
+use crate::{Error, Result};
+
+/// Least time between two fetches of the keys of the same server
+const REFETCH_INTERVAL_MS: u64 = 60 * 1000;
+/// How long the keys of a server are not fetched again after that failed
+const FAILURE_BACKOFF_MS: u64 = 5 * 60 * 1000;
+/// Most key fetches started per minute, over all servers
+const MAX_FETCHES_PER_MINUTE: usize = 120;
+
+/// Verify keys of a remote server
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerKeys {
+    /// Current keys by key id, as unpadded base64
+    pub verify_keys: BTreeMap<String, String>,
+    /// Rotated keys by key id, with the time they expired
+    pub old_verify_keys: BTreeMap<String, (String, u64)>,
+    /// Until when the current keys may be used without fetching them again
+    pub valid_until_ts: u64,
+}
+
+impl ServerKeys {
+    /// Whether `key_id` is one of the keys
+    pub fn contains(&self, key_id: &str) -> bool {
+        self.verify_keys.contains_key(key_id) || self.old_verify_keys.contains_key(key_id)
+    }
+
+    /// All keys, current and old, by key id
+    pub fn all_keys(&self) -> BTreeMap<String, String> {
+        let old = self.old_verify_keys.iter().map(|(key_id, (key, _))| (key_id.clone(), key.clone()));
+        old.chain(self.verify_keys.clone()).collect()
+    }
+
+    /// All keys by key id, with the time until which they may be used:
+    /// `valid_until_ts` for current keys, `expired_ts` for old ones
+    pub fn keys_with_validity(&self) -> BTreeMap<String, (String, u64)> {
+        let current = self.verify_keys.iter().map(|(key_id, key)| (key_id.clone(), (key.clone(), self.valid_until_ts)));
+        self.old_verify_keys.clone().into_iter().chain(current).collect()
+    }
+
+    /// Add keys from a newer response, keeping keys we already knew
+    fn merge(&mut self, newer: ServerKeys) {
+        for (key_id, key) in &self.verify_keys {
+            if !newer.verify_keys.contains_key(key_id) {
+                self.old_verify_keys.entry(key_id.clone()).or_insert((key.clone(), now_millis()));
+            }
+        }
+        self.verify_keys = newer.verify_keys;
+        self.old_verify_keys.extend(newer.old_verify_keys);
+        self.valid_until_ts = self.valid_until_ts.max(newer.valid_until_ts);
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+/// Whether `object` carries a valid signature of `entity` made with one of
+/// `keys`. Signatures of other entities and unknown keys are ignored.
+fn signed_by(object: &Value, entity: &str, keys: &BTreeMap<String, String>) -> bool {
+    let Some(signatures) = object["signatures"][entity].as_object() else {
+        return false;
+    };
+    let known: serde_json::Map<String, Value> =
+        signatures.iter().filter(|(key_id, _)| keys.contains_key(*key_id)).map(|(k, v)| (k.clone(), v.clone())).collect();
+    if known.is_empty() {
+        return false;
+    }
+
+    let mut object = object.clone();
+    object["signatures"] = json!({ entity: known });
+    let Ok(CanonicalJsonValue::Object(canonical)) = CanonicalJsonValue::try_from(object) else {
+        return false;
+    };
+    let public_keys = keys
+        .iter()
+        .filter_map(|(key_id, key)| Some((key_id.clone(), Base64::parse(key).ok()?)))
+        .collect();
+    let public_key_map = BTreeMap::from([(entity.to_owned(), public_keys)]);
+    ruma::signatures::verify_json(&public_key_map, &canonical).is_ok()
+}
+
+/// Check a `/_matrix/key/v2/server` response of `server` and extract its
+/// keys. With a notary, the response must also be signed by the notary
+/// with one of `notary_keys`.
+pub fn verify_server_keys(
+    server: &str,
+    response: &Value,
+    notary: Option<(&str, &BTreeMap<String, String>)>,
+) -> std::result::Result<ServerKeys, String> {
+    if response["server_name"].as_str() != Some(server) {
+        return Err(format!("Key response is not about {}", server));
+    }
+    let verify_keys: BTreeMap<String, String> = response["verify_keys"]
+        .as_object()
+        .ok_or("Key response has no verify_keys")?
+        .iter()
+        .filter_map(|(key_id, key)| Some((key_id.clone(), key["key"].as_str()?.to_owned())))
+        .collect();
+    let old_verify_keys = response["old_verify_keys"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(key_id, key)| Some((key_id.clone(), (key["key"].as_str()?.to_owned(), key["expired_ts"].as_u64()?))))
+        .collect();
+    let valid_until_ts = response["valid_until_ts"].as_u64().ok_or("Key response has no valid_until_ts")?;
+
+    if !signed_by(response, server, &verify_keys) {
+        return Err(format!("Key response of {} is not self-signed", server));
+    }
+    if let Some((notary, notary_keys)) = notary {
+        if !signed_by(response, notary, notary_keys) {
+            return Err(format!("Key response of {} is not signed by notary {}", server, notary));
+        }
+    }
+    Ok(ServerKeys { verify_keys, old_verify_keys, valid_until_ts })
+}
+
+/// Servers and key ids that signed an event or request body
+pub fn required_signing_keys(object: &Value) -> BTreeMap<String, BTreeSet<String>> {
+    object["signatures"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .filter_map(|(server, signatures)| Some((server.clone(), signatures.as_object()?.keys().cloned().collect())))
+        .collect()
+}
+
+/// Last fetch of the keys of a server
+#[derive(Debug, Clone, Copy)]
+struct Attempt {
+    at_ms: u64,
+    failed: bool,
+}
+
+/// Which key fetches may start
+#[derive(Debug, Default)]
+struct FetchLimiter {
+    attempts: HashMap<String, Attempt>,
+    window_start_ms: u64,
+    window_fetches: usize,
+}
+
+impl FetchLimiter {
+    /// Record a fetch of the keys of `server` if one may start now
+    fn try_start(&mut self, server: &str, now_ms: u64) -> bool {
+        if let Some(attempt) = self.attempts.get(server) {
+            let wait = if attempt.failed { FAILURE_BACKOFF_MS } else { REFETCH_INTERVAL_MS };
+            if now_ms < attempt.at_ms + wait {
+                return false;
+            }
+        }
+        if now_ms >= self.window_start_ms + 60 * 1000 {
+            self.window_start_ms = now_ms;
+            self.window_fetches = 0;
+            self.attempts.retain(|_, attempt| now_ms < attempt.at_ms + FAILURE_BACKOFF_MS);
+        }
+        if self.window_fetches >= MAX_FETCHES_PER_MINUTE {
+            return false;
+        }
+        self.window_fetches += 1;
+        self.attempts.insert(server.to_owned(), Attempt { at_ms: now_ms, failed: false });
+        true
+    }
+
+    fn failed(&mut self, server: &str) {
+        if let Some(attempt) = self.attempts.get_mut(server) {
+            attempt.failed = true;
+        }
+    }
+}
+
+/// Remote server key service
+#[derive(Debug)]
+pub struct Service {
+    server_name: String,
+    trusted_servers: Vec<String>,
+    cache: RwLock<HashMap<String, ServerKeys>>,
+    limiter: Mutex<FetchLimiter>,
+}
+
+impl Service {
+    pub fn new(server_name: &str, trusted_servers: Vec<String>) -> Self {
+        Self {
+            server_name: server_name.to_owned(),
+            trusted_servers,
+            cache: RwLock::new(HashMap::new()),
+            limiter: Mutex::new(FetchLimiter::default()),
+        }
+    }
+
+    /// Cached keys of a server, also if they are due for revalidation
+    pub fn cached(&self, server: &str) -> Option<ServerKeys> {
+        self.cache.read().unwrap().get(server).cloned()
+    }
+
+    /// Servers with cached keys, those valid the longest, which were
+    /// fetched most recently, first
+    pub fn cached_servers(&self) -> Vec<String> {
+        let cache = self.cache.read().unwrap();
+        let mut servers: Vec<(u64, &String)> = cache.iter().map(|(server, keys)| (keys.valid_until_ts, server)).collect();
+        servers.sort_unstable_by(|a, b| b.cmp(a));
+        servers.into_iter().map(|(_, server)| server.clone()).collect()
+    }
+
+    /// Add verified keys of a server to the cache
+    pub fn add_server_keys(&self, server: &str, keys: ServerKeys) {
+        let mut cache = self.cache.write().unwrap();
+        match cache.get_mut(server) {
+            Some(cached) => cached.merge(keys),
+            None => {
+                cache.insert(server.to_owned(), keys);
+            }
+        }
+    }
+
+    /// Whether the keys of `server` must be fetched: they are unknown,
+    /// lack one of `key_ids`, or their validity ended before `now_ms`
+    pub fn needs_fetch(&self, server: &str, key_ids: &BTreeSet<String>, now_ms: u64) -> bool {
+        match self.cache.read().unwrap().get(server) {
+            Some(cached) => cached.valid_until_ts < now_ms || !key_ids.iter().all(|key_id| cached.contains(key_id)),
+            None => true,
+        }
+    }
+
+    /// Fetch the keys of `server` from the server itself
+    #[instrument(level = "debug", skip(self, sending))]
+    pub async fn fetch_server_keys(&self, sending: &sending::Service, server: &str) -> Result<ServerKeys> {
+        let response = sending
+            .send_federation_request(server, reqwest::Method::GET, "/_matrix/key/v2/server", None)
+            .await
+            .map_err(|e| Error::BadServerResponse(format!("Could not fetch keys of {}: {}", server, e)))?;
+        let keys = verify_server_keys(server, &response, None).map_err(Error::BadServerResponse)?;
+        debug!("🔑 Fetched {} keys of {}", keys.verify_keys.len(), server);
+        self.add_server_keys(server, keys.clone());
+        Ok(keys)
+    }
+
+    /// Ask `notary` for the keys of several servers at once. Only servers
+    /// whose keys check out are returned.
+    #[instrument(level = "debug", skip(self, sending))]
+    pub async fn get_remote_server_keys_batch(
+        &self,
+        sending: &sending::Service,
+        notary: &str,
+        servers: &BTreeMap<String, BTreeSet<String>>,
+        now_ms: u64,
+    ) -> Result<BTreeMap<String, ServerKeys>> {
+        let notary_keys = match self.cached(notary) {
+            Some(keys) if keys.valid_until_ts >= now_ms => keys,
+            _ => self.fetch_server_keys(sending, notary).await?,
+        };
+
+        let criteria: serde_json::Map<String, Value> = servers
+            .iter()
+            .map(|(server, key_ids)| {
+                let key_ids: serde_json::Map<String, Value> =
+                    key_ids.iter().map(|key_id| (key_id.clone(), json!({ "minimum_valid_until_ts": now_ms }))).collect();
+                (server.clone(), Value::Object(key_ids))
+            })
+            .collect();
+        let response = sending
+            .send_federation_request(notary, reqwest::Method::POST, "/_matrix/key/v2/query", Some(json!({ "server_keys": criteria })))
+            .await
+            .map_err(|e| Error::BadServerResponse(format!("Notary {} failed: {}", notary, e)))?;
+
+        let mut fetched = BTreeMap::new();
+        for server_keys in response["server_keys"].as_array().into_iter().flatten() {
+            let Some(server) = server_keys["server_name"].as_str().filter(|server| servers.contains_key(*server)) else {
+                continue;
+            };
+            match verify_server_keys(server, server_keys, Some((notary, &notary_keys.all_keys()))) {
+                Ok(keys) => {
+                    self.add_server_keys(server, keys.clone());
+                    fetched.insert(server.to_owned(), keys);
+                }
+                Err(e) => warn!("❌ Notary {} returned bad keys: {}", notary, e),
+            }
+        }
+        debug!("🔑 Notary {} returned keys of {} servers", notary, fetched.len());
+        Ok(fetched)
+    }
+
+    /// Make sure the keys in `required` are known, fetching what is
+    /// missing or expired: from each server directly first, then from the
+    /// trusted notaries. Servers whose keys were fetched too recently are
+    /// skipped. Returns the keys of all servers that are known afterwards,
+    /// which can be stale if they could not be revalidated.
+    pub async fn fetch_required_signing_keys(
+        &self,
+        sending: &sending::Service,
+        required: &BTreeMap<String, BTreeSet<String>>,
+    ) -> BTreeMap<String, ServerKeys> {
+        let now_ms = now_millis();
+        let mut missing: BTreeMap<String, BTreeSet<String>> = required
+            .iter()
+            .filter(|(server, key_ids)| **server != self.server_name && self.needs_fetch(server, key_ids, now_ms))
+            .map(|(server, key_ids)| (server.clone(), key_ids.clone()))
+            .collect();
+        {
+            let mut limiter = self.limiter.lock().unwrap();
+            missing.retain(|server, _| limiter.try_start(server, now_ms));
+        }
+
+        let direct = missing.keys().map(|server| async move { (server.clone(), self.fetch_server_keys(sending, server).await) });
+        for (server, result) in futures::future::join_all(direct).await {
+            match result {
+                Ok(_) if !self.needs_fetch(&server, &missing[&server], now_ms) => {
+                    missing.remove(&server);
+                }
+                Ok(_) => debug!("Keys of {} lack some of {:?}", server, missing[&server]),
+                Err(e) => debug!("{}", e),
+            }
+        }
+
+        for notary in &self.trusted_servers {
+            if missing.is_empty() {
+                break;
+            }
+            match self.get_remote_server_keys_batch(sending, notary, &missing, now_ms).await {
+                Ok(fetched) => missing.retain(|server, key_ids| !fetched.contains_key(server) || self.needs_fetch(server, key_ids, now_ms)),
+                Err(e) => warn!("⚠️ {}", e),
+            }
+        }
+        if !missing.is_empty() {
+            info!("⚠️ Could not fetch signing keys of {:?}", missing.keys().collect::<Vec<_>>());
+            let mut limiter = self.limiter.lock().unwrap();
+            missing.keys().for_each(|server| limiter.failed(server));
+        }
+
+        required.keys().filter_map(|server| Some((server.clone(), self.cached(server)?))).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::server_keys;
+
+    #[test]
+    fn test_verify_direct_and_notary_responses() {
+        let remote = server_keys::Service::load("remote.example", None).unwrap();
+        let notary = server_keys::Service::load("notary.example", None).unwrap();
+        let notary_keys = BTreeMap::from([(notary.key_id(), notary.public_key())]);
+
+        let response = remote.server_keys_response().unwrap();
+        let keys = verify_server_keys("remote.example", &response, None).unwrap();
+        assert!(keys.contains(&remote.key_id()));
+        assert!(verify_server_keys("other.example", &response, None).is_err());
+        assert!(verify_server_keys("remote.example", &response, Some(("notary.example", &notary_keys))).is_err());
+
+        let mut relayed = response.clone();
+        notary.sign_json(&mut relayed).unwrap();
+        assert!(verify_server_keys("remote.example", &relayed, Some(("notary.example", &notary_keys))).is_ok());
+
+        let mut forged = response;
+        forged["verify_keys"] = json!({ notary.key_id(): { "key": notary.public_key() } });
+        assert!(verify_server_keys("remote.example", &forged, None).is_err());
+    }
+
+    #[test]
+    fn test_expired_and_rotated_keys_are_refetched() {
+        let service = Service::new("matrixon.local", Vec::new());
+        let remote = server_keys::Service::load("remote.example", None).unwrap();
+        let first = remote.key_id();
+        let now = now_millis();
+
+        let keys = verify_server_keys("remote.example", &remote.server_keys_response().unwrap(), None).unwrap();
+        service.add_server_keys("remote.example", keys);
+        let wanted = BTreeSet::from([first.clone()]);
+        assert!(!service.needs_fetch("remote.example", &wanted, now));
+        assert!(service.needs_fetch("remote.example", &wanted, now + 8 * 24 * 60 * 60 * 1000));
+
+        let second = remote.rotate().unwrap();
+        assert!(service.needs_fetch("remote.example", &BTreeSet::from([second.clone()]), now));
+        let keys = verify_server_keys("remote.example", &remote.server_keys_response().unwrap(), None).unwrap();
+        service.add_server_keys("remote.example", keys);
+        let cached = service.cached("remote.example").unwrap();
+        assert!(cached.verify_keys.contains_key(&second));
+        assert!(cached.old_verify_keys.contains_key(&first));
+        assert_eq!(cached.all_keys().len(), 2);
+
+        let event = json!({ "signatures": { "remote.example": { second.clone(): "sig" } } });
+        assert_eq!(required_signing_keys(&event)["remote.example"], BTreeSet::from([second]));
+    }
+
+    #[test]
+    fn test_key_fetches_are_rate_limited() {
+        let mut limiter = FetchLimiter::default();
+        let now = now_millis();
+        assert!(limiter.try_start("remote.example", now));
+        assert!(!limiter.try_start("remote.example", now + 1000));
+        assert!(limiter.try_start("remote.example", now + REFETCH_INTERVAL_MS));
+
+        limiter.failed("remote.example");
+        assert!(!limiter.try_start("remote.example", now + 2 * REFETCH_INTERVAL_MS));
+        assert!(limiter.try_start("remote.example", now + REFETCH_INTERVAL_MS + FAILURE_BACKOFF_MS));
+
+        let later = now + 10 * FAILURE_BACKOFF_MS;
+        let started = (0..2 * MAX_FETCHES_PER_MINUTE).filter(|i| limiter.try_start(&format!("s{}.example", i), later)).count();
+        assert_eq!(started, MAX_FETCHES_PER_MINUTE);
+    }
+}
@@ -0,0 +1,881 @@
+// =============================================================================
+// Matrixon Matrix NextServer - E2EE Keys Service
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Storage for end-to-end encryption keys: device identity keys, one-time
+//   keys, fallback keys and cross-signing keys, backing the /keys/upload,
+//   /keys/query, /keys/claim, /keys/device_signing/upload and
+//   /keys/signatures/upload client endpoints and their federation
+//   counterparts. Each user's device list has a version, bumped whenever
+//   one of their devices' keys change, which remote servers track. Keys are
+//   kept in memory and, when a directory is configured, in one file per
+//   user, rewritten whenever one of the user's keys change.
+//
+// =============================================================================
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+    sync::{Mutex, RwLock},
+};
+
+use ruma::{api::client::error::ErrorKind, serde::Base64, CanonicalJsonValue};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use crate::{service::state_file, Error, Result};
+
+/// Keys published by a single device
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct DeviceKeyState {
+    /// Signed `device_keys` object as uploaded by the client
+    device_keys: Option<Value>,
+    /// One-time keys keyed by `<algorithm>:<key_id>`
+    one_time_keys: BTreeMap<String, Value>,
+    /// Fallback keys keyed by `<algorithm>:<key_id>`, with their "used" flag
+    fallback_keys: BTreeMap<String, (Value, bool)>,
+}
+
+/// Cross-signing keys published by a single user
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct CrossSigningKeys {
+    master: Option<Value>,
+    self_signing: Option<Value>,
+    user_signing: Option<Value>,
+    /// Signatures other users made on this user's master key with their
+    /// user-signing key, keyed by signer. Only visible to the signer.
+    master_signatures_by: BTreeMap<String, serde_json::Map<String, Value>>,
+}
+
+/// Cross-signing key role, matching the `usage` field of the key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyUsage {
+    Master,
+    SelfSigning,
+    UserSigning,
+}
+
+impl KeyUsage {
+    fn as_str(self) -> &'static str {
+        match self {
+            KeyUsage::Master => "master",
+            KeyUsage::SelfSigning => "self_signing",
+            KeyUsage::UserSigning => "user_signing",
+        }
+    }
+}
+
+/// Every key of one user, as kept in their file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoredUserKeys {
+    user_id: String,
+    devices: BTreeMap<String, DeviceKeyState>,
+    cross_signing: Option<CrossSigningKeys>,
+    device_list_version: u64,
+}
+
+/// Directory of the per-user key files
+#[derive(Debug)]
+struct Store {
+    dir: PathBuf,
+    /// Held while a user's keys are read and written, so files are written
+    /// in the order the keys changed
+    writing: Mutex<()>,
+}
+
+/// E2EE key storage service
+#[derive(Debug, Default)]
+pub struct Service {
+    devices: RwLock<HashMap<(String, String), DeviceKeyState>>,
+    cross_signing: RwLock<HashMap<String, CrossSigningKeys>>,
+    device_list_versions: RwLock<HashMap<String, u64>>,
+    store: Option<Store>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the keys in files below `dir`, loading those stored there
+    pub fn with_store_dir(mut self, dir: Option<PathBuf>) -> Self {
+        let Some(dir) = dir else {
+            return self;
+        };
+        let mut users = 0;
+        for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let stored = match fs::read(&path).map_err(|e| e.to_string()).and_then(|data| {
+                serde_json::from_slice::<StoredUserKeys>(&data).map_err(|e| e.to_string())
+            }) {
+                Ok(stored) => stored,
+                Err(e) => {
+                    warn!("⚠️ Ignoring invalid key file {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let devices = self.devices.get_mut().unwrap();
+            for (device_id, state) in stored.devices {
+                devices.insert((stored.user_id.clone(), device_id), state);
+            }
+            if let Some(cross_signing) = stored.cross_signing {
+                self.cross_signing.get_mut().unwrap().insert(stored.user_id.clone(), cross_signing);
+            }
+            self.device_list_versions.get_mut().unwrap().insert(stored.user_id, stored.device_list_version);
+            users += 1;
+        }
+        if users > 0 {
+            info!("🔑 Loaded the E2EE keys of {} users", users);
+        }
+        self.store = Some(Store { dir, writing: Mutex::new(()) });
+        self
+    }
+
+    /// Write the keys of `user_id` to their file, if keys are stored
+    fn persist(&self, user_id: &str) {
+        let Some(store) = &self.store else {
+            return;
+        };
+        let _writing = store.writing.lock().unwrap();
+        let stored = StoredUserKeys {
+            user_id: user_id.to_owned(),
+            devices: self
+                .devices
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|((user, _), _)| user == user_id)
+                .map(|((_, device), state)| (device.clone(), state.clone()))
+                .collect(),
+            cross_signing: self.cross_signing.read().unwrap().get(user_id).cloned(),
+            device_list_version: self.device_list_version(user_id),
+        };
+        let path = store.dir.join(format!("{}.json", state_file::escape_file_name(user_id)));
+        let data = serde_json::to_vec(&stored).expect("keys serialize");
+        if let Err(e) = state_file::write_atomic(&path, &data) {
+            warn!("⚠️ Could not persist the E2EE keys of {}: {}", user_id, e);
+        }
+    }
+
+    /// Store device keys for `(user_id, device_id)`.
+    ///
+    /// The `user_id` and `device_id` inside the payload must match the
+    /// uploading device, otherwise the upload is rejected.
+    pub fn add_device_keys(&self, user_id: &str, device_id: &str, device_keys: &Value) -> Result<()> {
+        let claimed_user = device_keys.get("user_id").and_then(Value::as_str);
+        let claimed_device = device_keys.get("device_id").and_then(Value::as_str);
+        if claimed_user != Some(user_id) || claimed_device != Some(device_id) {
+            return Err(Error::BadRequest(
+                ErrorKind::InvalidParam,
+                "device_keys do not match the uploading device",
+            ));
+        }
+
+        let mut devices = self.devices.write().unwrap();
+        devices
+            .entry((user_id.to_owned(), device_id.to_owned()))
+            .or_default()
+            .device_keys = Some(device_keys.clone());
+        drop(devices);
+        self.bump_device_list(user_id);
+        self.persist(user_id);
+        debug!("🔑 Stored device keys for {} / {}", user_id, device_id);
+        Ok(())
+    }
+
+    fn bump_device_list(&self, user_id: &str) {
+        *self.device_list_versions.write().unwrap().entry(user_id.to_owned()).or_default() += 1;
+    }
+
+    /// Version of a user's device list, `stream_id` over federation
+    pub fn device_list_version(&self, user_id: &str) -> u64 {
+        self.device_list_versions.read().unwrap().get(user_id).copied().unwrap_or(0)
+    }
+
+    /// Add one-time keys. Existing key ids are left untouched.
+    pub fn add_one_time_keys(&self, user_id: &str, device_id: &str, keys: &serde_json::Map<String, Value>) -> Result<()> {
+        validate_key_ids(keys)?;
+        let mut devices = self.devices.write().unwrap();
+        let state = devices
+            .entry((user_id.to_owned(), device_id.to_owned()))
+            .or_default();
+        for (key_id, key) in keys {
+            state
+                .one_time_keys
+                .entry(key_id.clone())
+                .or_insert_with(|| key.clone());
+        }
+        drop(devices);
+        self.persist(user_id);
+        Ok(())
+    }
+
+    /// Replace the fallback keys for each algorithm present in `keys`.
+    pub fn add_fallback_keys(&self, user_id: &str, device_id: &str, keys: &serde_json::Map<String, Value>) -> Result<()> {
+        validate_key_ids(keys)?;
+        let mut devices = self.devices.write().unwrap();
+        let state = devices
+            .entry((user_id.to_owned(), device_id.to_owned()))
+            .or_default();
+        for (key_id, key) in keys {
+            let algorithm = algorithm_of(key_id);
+            state
+                .fallback_keys
+                .retain(|existing, _| algorithm_of(existing) != algorithm);
+            state
+                .fallback_keys
+                .insert(key_id.clone(), (key.clone(), false));
+        }
+        drop(devices);
+        self.persist(user_id);
+        Ok(())
+    }
+
+    /// Number of unclaimed one-time keys per algorithm
+    pub fn one_time_key_counts(&self, user_id: &str, device_id: &str) -> BTreeMap<String, u64> {
+        let devices = self.devices.read().unwrap();
+        let mut counts = BTreeMap::new();
+        if let Some(state) = devices.get(&(user_id.to_owned(), device_id.to_owned())) {
+            for key_id in state.one_time_keys.keys() {
+                *counts.entry(algorithm_of(key_id).to_owned()).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    /// Algorithms for which the device has an unused fallback key
+    pub fn unused_fallback_key_types(&self, user_id: &str, device_id: &str) -> Vec<String> {
+        let devices = self.devices.read().unwrap();
+        let mut algorithms: Vec<String> = devices
+            .get(&(user_id.to_owned(), device_id.to_owned()))
+            .map(|state| {
+                state
+                    .fallback_keys
+                    .iter()
+                    .filter(|(_, (_, used))| !used)
+                    .map(|(key_id, _)| algorithm_of(key_id).to_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        algorithms.dedup();
+        algorithms
+    }
+
+    /// Device keys for a user. An empty `device_ids` slice means all devices.
+    pub fn get_device_keys(&self, user_id: &str, device_ids: &[String]) -> BTreeMap<String, Value> {
+        let devices = self.devices.read().unwrap();
+        devices
+            .iter()
+            .filter(|((user, device), _)| {
+                user == user_id && (device_ids.is_empty() || device_ids.contains(device))
+            })
+            .filter_map(|((_, device), state)| {
+                state
+                    .device_keys
+                    .clone()
+                    .map(|keys| (device.clone(), keys))
+            })
+            .collect()
+    }
+
+    /// Claim a single key of `algorithm` for a device.
+    ///
+    /// One-time keys are removed once claimed. When none are left, the
+    /// device's fallback key is returned instead and marked as used.
+    pub fn claim_key(&self, user_id: &str, device_id: &str, algorithm: &str) -> Option<(String, Value)> {
+        let claimed = self.take_key(user_id, device_id, algorithm);
+        if claimed.is_some() {
+            self.persist(user_id);
+        }
+        claimed
+    }
+
+    fn take_key(&self, user_id: &str, device_id: &str, algorithm: &str) -> Option<(String, Value)> {
+        let mut devices = self.devices.write().unwrap();
+        let state = devices.get_mut(&(user_id.to_owned(), device_id.to_owned()))?;
+
+        let one_time_key_id = state
+            .one_time_keys
+            .keys()
+            .find(|key_id| algorithm_of(key_id) == algorithm)
+            .cloned();
+        if let Some(key_id) = one_time_key_id {
+            let key = state.one_time_keys.remove(&key_id)?;
+            return Some((key_id, key));
+        }
+
+        state
+            .fallback_keys
+            .iter_mut()
+            .find(|(key_id, _)| algorithm_of(key_id) == algorithm)
+            .map(|(key_id, (key, used))| {
+                *used = true;
+                (key_id.clone(), key.clone())
+            })
+    }
+
+    /// Store cross-signing keys for a user.
+    ///
+    /// Self-signing and user-signing keys must be signed by the user's master
+    /// key (the one uploaded in the same request, or the stored one). A new
+    /// master key drops self-signing and user-signing keys that are not
+    /// re-uploaded alongside it, since they are no longer signed by it.
+    pub fn add_cross_signing_keys(
+        &self,
+        user_id: &str,
+        master_key: Option<&Value>,
+        self_signing_key: Option<&Value>,
+        user_signing_key: Option<&Value>,
+    ) -> Result<()> {
+        let mut cross_signing = self.cross_signing.write().unwrap();
+
+        let master_key = match master_key {
+            Some(key) => {
+                parse_cross_signing_key(key, user_id, KeyUsage::Master)?;
+                key.clone()
+            }
+            None => cross_signing
+                .get(user_id)
+                .and_then(|keys| keys.master.clone())
+                .ok_or(Error::BadRequest(
+                    ErrorKind::MissingParam,
+                    "Tried to upload cross-signing keys without a master key",
+                ))?,
+        };
+        let (master_key_id, master_public_key) =
+            parse_cross_signing_key(&master_key, user_id, KeyUsage::Master)?;
+
+        for (key, usage) in [
+            (self_signing_key, KeyUsage::SelfSigning),
+            (user_signing_key, KeyUsage::UserSigning),
+        ] {
+            if let Some(key) = key {
+                parse_cross_signing_key(key, user_id, usage)?;
+                if !verify_key_signature(key, user_id, &master_key_id, &master_public_key) {
+                    return Err(Error::BadRequest(
+                        ErrorKind::InvalidParam,
+                        "Cross-signing key is not signed by the master key",
+                    ));
+                }
+            }
+        }
+
+        let entry = cross_signing.entry(user_id.to_owned()).or_default();
+        if entry.master.as_ref() != Some(&master_key) {
+            entry.self_signing = None;
+            entry.user_signing = None;
+            entry.master_signatures_by.clear();
+        }
+        entry.master = Some(master_key);
+        if let Some(key) = self_signing_key {
+            entry.self_signing = Some(key.clone());
+        }
+        if let Some(key) = user_signing_key {
+            entry.user_signing = Some(key.clone());
+        }
+        drop(cross_signing);
+        self.persist(user_id);
+        debug!("🔐 Stored cross-signing keys for {}", user_id);
+        Ok(())
+    }
+
+    /// Whether uploading `master_key` would replace a different master key
+    /// `user_id` already has, which needs user-interactive authentication.
+    /// Re-uploading the stored key, with or without signatures, does not.
+    pub fn replaces_master_key(&self, user_id: &str, master_key: Option<&Value>) -> bool {
+        let Some(master_key) = master_key else {
+            return false;
+        };
+        self.cross_signing
+            .read()
+            .unwrap()
+            .get(user_id)
+            .and_then(|keys| keys.master.as_ref())
+            .is_some_and(|stored| !same_signed_content(stored, master_key))
+    }
+
+    /// Master key of `user_id` as seen by `requester`.
+    ///
+    /// Signatures made by other users' user-signing keys are only included
+    /// when the requester is the signer.
+    pub fn get_master_key(&self, requester: &str, user_id: &str) -> Option<Value> {
+        let cross_signing = self.cross_signing.read().unwrap();
+        let keys = cross_signing.get(user_id)?;
+        let mut master = keys.master.clone()?;
+        if let Some(signatures) = keys.master_signatures_by.get(requester) {
+            merge_signatures(&mut master, requester, signatures);
+        }
+        Some(master)
+    }
+
+    pub fn get_self_signing_key(&self, user_id: &str) -> Option<Value> {
+        let cross_signing = self.cross_signing.read().unwrap();
+        cross_signing.get(user_id)?.self_signing.clone()
+    }
+
+    pub fn get_user_signing_key(&self, user_id: &str) -> Option<Value> {
+        let cross_signing = self.cross_signing.read().unwrap();
+        cross_signing.get(user_id)?.user_signing.clone()
+    }
+
+    /// Attach `signer`'s signature from `signed_object` to the key `key_id`
+    /// of `target_user`.
+    ///
+    /// Supported cases:
+    /// - a device of the signer, signed by the signer's self-signing key
+    /// - the signer's own master key, signed by one of the signer's devices
+    /// - another user's master key, signed by the signer's user-signing key
+    pub fn add_signature(&self, signer: &str, target_user: &str, key_id: &str, signed_object: &Value) -> Result<()> {
+        let invalid_signature = || Error::BadRequest(ErrorKind::InvalidParam, "Invalid signature");
+        let signatures = signed_object
+            .get("signatures")
+            .and_then(|signatures| signatures.get(signer))
+            .and_then(Value::as_object)
+            .cloned()
+            .ok_or(Error::BadRequest(ErrorKind::MissingParam, "No signatures from the uploading user"))?;
+
+        let target_master = self.get_master_key(signer, target_user);
+        let is_master_key = target_master
+            .as_ref()
+            .and_then(|master| parse_cross_signing_key(master, target_user, KeyUsage::Master).ok())
+            .is_some_and(|(_, public_key)| public_key == key_id);
+
+        if signer == target_user && !is_master_key {
+            // Device key signed with the self-signing key
+            let self_signing = self.get_self_signing_key(signer).ok_or(invalid_signature())?;
+            let (ssk_id, ssk_public) = parse_cross_signing_key(&self_signing, signer, KeyUsage::SelfSigning)?;
+            let mut devices = self.devices.write().unwrap();
+            let stored = devices
+                .get_mut(&(signer.to_owned(), key_id.to_owned()))
+                .and_then(|state| state.device_keys.as_mut())
+                .ok_or(Error::BadRequest(ErrorKind::NotFound, "Unknown device"))?;
+            if !same_signed_content(stored, signed_object) || !verify_key_signature(signed_object, signer, &ssk_id, &ssk_public) {
+                return Err(invalid_signature());
+            }
+            let signature = json!({ ssk_id.clone(): signatures[&ssk_id] });
+            merge_signatures(stored, signer, signature.as_object().unwrap());
+            drop(devices);
+            self.persist(signer);
+            return Ok(());
+        }
+
+        let master = target_master.ok_or(Error::BadRequest(ErrorKind::NotFound, "Unknown key"))?;
+        if !is_master_key || !same_signed_content(&master, signed_object) {
+            return Err(invalid_signature());
+        }
+
+        let mut cross_signing = self.cross_signing.write().unwrap();
+        if signer == target_user {
+            // Own master key signed by one of the signer's devices
+            let devices = self.devices.read().unwrap();
+            let verified: serde_json::Map<String, Value> = signatures
+                .iter()
+                .filter(|(signing_key_id, _)| {
+                    let Some(device_id) = signing_key_id.strip_prefix("ed25519:") else {
+                        return false;
+                    };
+                    devices
+                        .get(&(signer.to_owned(), device_id.to_owned()))
+                        .and_then(|state| state.device_keys.as_ref())
+                        .and_then(|keys| keys.get("keys")?.get(signing_key_id.as_str())?.as_str().map(str::to_owned))
+                        .is_some_and(|public_key| verify_key_signature(signed_object, signer, signing_key_id, &public_key))
+                })
+                .map(|(id, signature)| (id.clone(), signature.clone()))
+                .collect();
+            if verified.is_empty() {
+                return Err(invalid_signature());
+            }
+            let keys = cross_signing.get_mut(signer).ok_or(invalid_signature())?;
+            merge_signatures(keys.master.as_mut().ok_or(invalid_signature())?, signer, &verified);
+        } else {
+            // Another user's master key signed with the user-signing key
+            let user_signing = cross_signing
+                .get(signer)
+                .and_then(|keys| keys.user_signing.clone())
+                .ok_or(invalid_signature())?;
+            let (usk_id, usk_public) = parse_cross_signing_key(&user_signing, signer, KeyUsage::UserSigning)?;
+            if !verify_key_signature(signed_object, signer, &usk_id, &usk_public) {
+                return Err(invalid_signature());
+            }
+            let keys = cross_signing.get_mut(target_user).ok_or(invalid_signature())?;
+            keys.master_signatures_by
+                .entry(signer.to_owned())
+                .or_default()
+                .insert(usk_id.clone(), signatures[&usk_id].clone());
+        }
+        drop(cross_signing);
+        self.persist(target_user);
+        Ok(())
+    }
+
+    /// Remove every key belonging to a device, e.g. when it is deleted
+    pub fn remove_device(&self, user_id: &str, device_id: &str) {
+        self.devices
+            .write()
+            .unwrap()
+            .remove(&(user_id.to_owned(), device_id.to_owned()));
+        self.bump_device_list(user_id);
+        self.persist(user_id);
+    }
+
+    /// Remove every key belonging to a user, including cross-signing keys
+    pub fn remove_user(&self, user_id: &str) {
+        self.devices
+            .write()
+            .unwrap()
+            .retain(|(user, _), _| user != user_id);
+        self.cross_signing.write().unwrap().remove(user_id);
+        self.bump_device_list(user_id);
+        self.persist(user_id);
+    }
+
+    /// Answer a key query for the users and devices in `requested`, as
+    /// `{ "<user_id>": [device ids, empty for all] }`. The user-signing key
+    /// is only returned to its owner; `requester` is `None` for queries
+    /// from other servers, which get no user-signing keys at all.
+    pub fn query_keys(&self, requester: Option<&str>, requested: &serde_json::Map<String, Value>) -> Value {
+        let mut device_keys = serde_json::Map::new();
+        let mut master_keys = serde_json::Map::new();
+        let mut self_signing_keys = serde_json::Map::new();
+        let mut user_signing_keys = serde_json::Map::new();
+        for (target_user, devices) in requested {
+            let device_ids: Vec<String> = devices
+                .as_array()
+                .map(|ids| ids.iter().filter_map(|id| id.as_str().map(str::to_owned)).collect())
+                .unwrap_or_default();
+            device_keys.insert(target_user.clone(), json!(self.get_device_keys(target_user, &device_ids)));
+            if let Some(master_key) = self.get_master_key(requester.unwrap_or_default(), target_user) {
+                master_keys.insert(target_user.clone(), master_key);
+            }
+            if let Some(self_signing_key) = self.get_self_signing_key(target_user) {
+                self_signing_keys.insert(target_user.clone(), self_signing_key);
+            }
+            if requester == Some(target_user.as_str()) {
+                if let Some(user_signing_key) = self.get_user_signing_key(target_user) {
+                    user_signing_keys.insert(target_user.clone(), user_signing_key);
+                }
+            }
+        }
+
+        let mut response = json!({
+            "device_keys": device_keys,
+            "master_keys": master_keys,
+            "self_signing_keys": self_signing_keys,
+        });
+        if requester.is_some() {
+            response["user_signing_keys"] = json!(user_signing_keys);
+        }
+        response
+    }
+
+    /// Claim one key per device in `requested`, as
+    /// `{ "<user_id>": { "<device_id>": "<algorithm>" } }`
+    pub fn claim_keys(&self, requested: &serde_json::Map<String, Value>) -> serde_json::Map<String, Value> {
+        let mut one_time_keys = serde_json::Map::new();
+        for (target_user, devices) in requested {
+            let mut claimed = serde_json::Map::new();
+            for (device_id, algorithm) in devices.as_object().into_iter().flatten() {
+                let Some(algorithm) = algorithm.as_str() else {
+                    continue;
+                };
+                if let Some((key_id, key)) = self.claim_key(target_user, device_id, algorithm) {
+                    claimed.insert(device_id.clone(), json!({ key_id: key }));
+                }
+            }
+            one_time_keys.insert(target_user.clone(), Value::Object(claimed));
+        }
+        one_time_keys
+    }
+}
+
+/// Validate a cross-signing key and return its `(key_id, public_key)`
+fn parse_cross_signing_key(key: &Value, user_id: &str, usage: KeyUsage) -> Result<(String, String)> {
+    if key.get("user_id").and_then(Value::as_str) != Some(user_id) {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Cross-signing key user_id does not match",
+        ));
+    }
+    let has_usage = key
+        .get("usage")
+        .and_then(Value::as_array)
+        .is_some_and(|usages| usages.iter().any(|u| u.as_str() == Some(usage.as_str())));
+    if !has_usage {
+        return Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Cross-signing key has the wrong usage",
+        ));
+    }
+
+    let mut keys = key
+        .get("keys")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flatten();
+    match (keys.next(), keys.next()) {
+        (Some((key_id, Value::String(public_key))), None) if key_id.starts_with("ed25519:") => {
+            Ok((key_id.clone(), public_key.clone()))
+        }
+        _ => Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Cross-signing key must contain exactly one ed25519 key",
+        )),
+    }
+}
+
+/// Check that `object` carries a valid signature by `signer` with `key_id`
+fn verify_key_signature(object: &Value, signer: &str, key_id: &str, public_key: &str) -> bool {
+    let Some(signature) = object
+        .get("signatures")
+        .and_then(|signatures| signatures.get(signer))
+        .and_then(|signatures| signatures.get(key_id))
+        .cloned()
+    else {
+        return false;
+    };
+    let Ok(public_key) = Base64::parse(public_key) else {
+        return false;
+    };
+
+    // Only verify the one signature we care about; `verify_json` checks all of them
+    let mut object = object.clone();
+    object["signatures"] = json!({ signer: { key_id: signature } });
+    let Ok(CanonicalJsonValue::Object(object)) = CanonicalJsonValue::try_from(object) else {
+        return false;
+    };
+
+    let public_key_map = BTreeMap::from([(
+        signer.to_owned(),
+        BTreeMap::from([(key_id.to_owned(), public_key)]),
+    )]);
+    match ruma::signatures::verify_json(&public_key_map, &object) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("❌ Signature by {} ({}) failed to verify: {}", signer, key_id, e);
+            false
+        }
+    }
+}
+
+/// Whether two signed objects are identical apart from their signatures
+fn same_signed_content(a: &Value, b: &Value) -> bool {
+    let strip = |value: &Value| {
+        let mut value = value.clone();
+        if let Some(object) = value.as_object_mut() {
+            object.remove("signatures");
+            object.remove("unsigned");
+        }
+        value
+    };
+    strip(a) == strip(b)
+}
+
+/// Merge `signatures` made by `signer` into `object.signatures`
+fn merge_signatures(object: &mut Value, signer: &str, signatures: &serde_json::Map<String, Value>) {
+    if !object.get("signatures").is_some_and(Value::is_object) {
+        object["signatures"] = json!({});
+    }
+    let entry = &mut object["signatures"][signer];
+    if !entry.is_object() {
+        *entry = json!({});
+    }
+    if let Some(entry) = entry.as_object_mut() {
+        entry.extend(signatures.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+}
+
+/// `signed_curve25519:AAAAHg` -> `signed_curve25519`
+fn algorithm_of(key_id: &str) -> &str {
+    key_id.split_once(':').map_or(key_id, |(algorithm, _)| algorithm)
+}
+
+fn validate_key_ids(keys: &serde_json::Map<String, Value>) -> Result<()> {
+    if keys.keys().all(|key_id| key_id.split_once(':').is_some_and(|(a, id)| !a.is_empty() && !id.is_empty())) {
+        Ok(())
+    } else {
+        Err(Error::BadRequest(
+            ErrorKind::InvalidParam,
+            "Key ids must be of the form <algorithm>:<key_id>",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const USER: &str = "@alice:matrixon.local";
+    const DEVICE: &str = "ALICEDEVICE";
+
+    fn keys(value: Value) -> serde_json::Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_device_keys_must_match_uploader() {
+        let service = Service::new();
+        let device_keys = json!({ "user_id": USER, "device_id": "OTHER" });
+        assert!(service.add_device_keys(USER, DEVICE, &device_keys).is_err());
+
+        let device_keys = json!({ "user_id": USER, "device_id": DEVICE });
+        service.add_device_keys(USER, DEVICE, &device_keys).unwrap();
+        assert_eq!(service.get_device_keys(USER, &[]).len(), 1);
+    }
+
+    #[test]
+    fn test_remote_queries_get_no_user_signing_keys() {
+        let service = Service::new();
+        service.add_device_keys(USER, DEVICE, &json!({ "user_id": USER, "device_id": DEVICE })).unwrap();
+        assert_eq!(service.device_list_version(USER), 1);
+
+        let requested = keys(json!({ USER: [] }));
+        let local = service.query_keys(Some(USER), &requested);
+        assert!(local["device_keys"][USER][DEVICE].is_object());
+        assert!(local["user_signing_keys"].is_object());
+        let remote = service.query_keys(None, &requested);
+        assert_eq!(remote["device_keys"], local["device_keys"]);
+        assert!(remote.get("user_signing_keys").is_none());
+
+        service.remove_device(USER, DEVICE);
+        assert_eq!(service.device_list_version(USER), 2);
+    }
+
+    #[test]
+    fn test_claim_consumes_one_time_keys_then_falls_back() {
+        let service = Service::new();
+        service
+            .add_one_time_keys(USER, DEVICE, &keys(json!({ "signed_curve25519:AAAA": { "key": "otk" } })))
+            .unwrap();
+        service
+            .add_fallback_keys(USER, DEVICE, &keys(json!({ "signed_curve25519:BBBB": { "key": "fallback" } })))
+            .unwrap();
+        assert_eq!(service.one_time_key_counts(USER, DEVICE)["signed_curve25519"], 1);
+
+        let (key_id, _) = service.claim_key(USER, DEVICE, "signed_curve25519").unwrap();
+        assert_eq!(key_id, "signed_curve25519:AAAA");
+        assert!(service.one_time_key_counts(USER, DEVICE).is_empty());
+        assert_eq!(service.unused_fallback_key_types(USER, DEVICE), vec!["signed_curve25519"]);
+
+        let (key_id, _) = service.claim_key(USER, DEVICE, "signed_curve25519").unwrap();
+        assert_eq!(key_id, "signed_curve25519:BBBB");
+        assert!(service.unused_fallback_key_types(USER, DEVICE).is_empty());
+    }
+
+    fn signing_key(user_id: &str, usage: &str, key_pair: &ruma::signatures::Ed25519KeyPair) -> Value {
+        let public_key = Base64::<ruma::serde::base64::Standard, _>::new(key_pair.public_key().to_vec()).encode();
+        json!({
+            "user_id": user_id,
+            "usage": [usage],
+            "keys": { format!("ed25519:{public_key}"): public_key }
+        })
+    }
+
+    fn key_pair() -> ruma::signatures::Ed25519KeyPair {
+        let document = ruma::signatures::Ed25519KeyPair::generate().unwrap();
+        ruma::signatures::Ed25519KeyPair::from_der(&document, "unused".to_owned()).unwrap()
+    }
+
+    fn sign(value: &Value, signer: &str, key_pair: &ruma::signatures::Ed25519KeyPair, key_id: &str) -> Value {
+        let CanonicalJsonValue::Object(mut object) = CanonicalJsonValue::try_from(value.clone()).unwrap() else {
+            unreachable!()
+        };
+        ruma::signatures::sign_json(signer, key_pair, &mut object).unwrap();
+        let mut signed: Value = CanonicalJsonValue::Object(object).into();
+        // sign_json names the key after the key pair version; rename it
+        let signature = signed["signatures"][signer]["ed25519:unused"].take();
+        signed["signatures"][signer].as_object_mut().unwrap().remove("ed25519:unused");
+        signed["signatures"][signer][key_id] = signature;
+        signed
+    }
+
+    #[test]
+    fn test_self_signing_key_requires_master_signature() {
+        let service = Service::new();
+        let (master_pair, ssk_pair) = (key_pair(), key_pair());
+        let master = signing_key(USER, "master", &master_pair);
+        let master_id = master["keys"].as_object().unwrap().keys().next().unwrap().clone();
+        let ssk = signing_key(USER, "self_signing", &ssk_pair);
+
+        assert!(service.add_cross_signing_keys(USER, Some(&master), Some(&ssk), None).is_err());
+
+        let signed_ssk = sign(&ssk, USER, &master_pair, &master_id);
+        service
+            .add_cross_signing_keys(USER, Some(&master), Some(&signed_ssk), None)
+            .unwrap();
+        assert_eq!(service.get_self_signing_key(USER), Some(signed_ssk));
+        assert!(service.get_master_key(USER, USER).is_some());
+    }
+
+    #[test]
+    fn test_replacing_the_master_key_is_detected() {
+        let service = Service::new();
+        let master = signing_key(USER, "master", &key_pair());
+        assert!(!service.replaces_master_key(USER, Some(&master)));
+        service.add_cross_signing_keys(USER, Some(&master), None, None).unwrap();
+
+        let mut resigned = master.clone();
+        resigned["signatures"] = json!({ USER: { "ed25519:ALICEDEVICE": "signature" } });
+        assert!(!service.replaces_master_key(USER, Some(&resigned)));
+        assert!(!service.replaces_master_key(USER, None));
+        assert!(service.replaces_master_key(USER, Some(&signing_key(USER, "master", &key_pair()))));
+    }
+
+    #[test]
+    fn test_device_signature_upload() {
+        let service = Service::new();
+        let (master_pair, ssk_pair) = (key_pair(), key_pair());
+        let master = signing_key(USER, "master", &master_pair);
+        let master_id = master["keys"].as_object().unwrap().keys().next().unwrap().clone();
+        let ssk = sign(&signing_key(USER, "self_signing", &ssk_pair), USER, &master_pair, &master_id);
+        let ssk_id = ssk["keys"].as_object().unwrap().keys().next().unwrap().clone();
+        service.add_cross_signing_keys(USER, Some(&master), Some(&ssk), None).unwrap();
+
+        let device_keys = json!({ "user_id": USER, "device_id": DEVICE, "keys": {} });
+        service.add_device_keys(USER, DEVICE, &device_keys).unwrap();
+
+        let forged = sign(&device_keys, USER, &key_pair(), &ssk_id);
+        assert!(service.add_signature(USER, USER, DEVICE, &forged).is_err());
+
+        let signed = sign(&device_keys, USER, &ssk_pair, &ssk_id);
+        service.add_signature(USER, USER, DEVICE, &signed).unwrap();
+        let stored = &service.get_device_keys(USER, &[])[DEVICE];
+        assert!(stored["signatures"][USER][&ssk_id].is_string());
+    }
+
+    #[test]
+    fn test_invalid_key_id_rejected() {
+        let service = Service::new();
+        assert!(service
+            .add_one_time_keys(USER, DEVICE, &keys(json!({ "nocolon": {} })))
+            .is_err());
+    }
+
+    #[test]
+    fn test_keys_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = Service::new().with_store_dir(Some(dir.path().to_owned()));
+        service.add_device_keys(USER, DEVICE, &json!({ "user_id": USER, "device_id": DEVICE })).unwrap();
+        service
+            .add_one_time_keys(USER, DEVICE, &keys(json!({ "signed_curve25519:A": {}, "signed_curve25519:B": {} })))
+            .unwrap();
+        assert!(service.claim_key(USER, DEVICE, "signed_curve25519").is_some());
+
+        let restarted = Service::new().with_store_dir(Some(dir.path().to_owned()));
+        assert_eq!(restarted.get_device_keys(USER, &[]).len(), 1);
+        assert_eq!(restarted.one_time_key_counts(USER, DEVICE).get("signed_curve25519"), Some(&1));
+        assert_eq!(restarted.device_list_version(USER), 1);
+    }
+}
@@ -0,0 +1,282 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Legal Holds
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Legal holds preserving the data of rooms or users for litigation or an
+//   investigation. While a hold is active, jobs deleting data (account
+//   erasure, retention purges, media cleanup) leave the held data alone;
+//   media is held when its uploader is, or when an event of a held room
+//   refers to it. Holds are placed and released by server admins, lapse at
+//   their expiry, if any, and are kept in a state file across restarts.
+//
+// =============================================================================
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::PathBuf,
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::service::{room_deletion, state_file::StateFile, timeline};
+
+/// What a hold preserves
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HoldTarget {
+    /// Every event of the room
+    RoomId(String),
+    /// Every event and media file of the user
+    UserId(String),
+}
+
+/// A legal hold, serialized with its target as `room_id` or `user_id`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hold {
+    pub hold_id: String,
+    #[serde(flatten)]
+    pub target: HoldTarget,
+    /// Case or matter the hold belongs to
+    pub case_id: String,
+    pub reason: Option<String>,
+    /// Admin who placed the hold
+    pub created_by: String,
+    pub created_at: u64,
+    /// Milliseconds since the epoch the hold lapses at, kept until released
+    /// when unset
+    pub expires_at: Option<u64>,
+}
+
+impl Hold {
+    fn is_active(&self, now: u64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+/// Legal hold service
+#[derive(Debug, Default)]
+pub struct Service {
+    holds: RwLock<HashMap<String, Hold>>,
+    holds_file: Option<StateFile>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the holds in the file at `path`
+    pub fn with_holds_file(mut self, path: Option<PathBuf>) -> Self {
+        if let Some(path) = path {
+            let holds_file = StateFile::new(path);
+            if let Some(holds) = holds_file.load() {
+                self.holds = RwLock::new(holds);
+            }
+            self.holds_file = Some(holds_file);
+        }
+        self
+    }
+
+    /// Change the holds and save them
+    fn update<T>(&self, f: impl FnOnce(&mut HashMap<String, Hold>) -> T) -> T {
+        let mut holds = self.holds.write().unwrap();
+        let result = f(&mut holds);
+        let snapshot = self.holds_file.as_ref().map(|holds_file| holds_file.snapshot(&*holds));
+        drop(holds);
+        if let (Some(holds_file), Some(snapshot)) = (&self.holds_file, snapshot) {
+            holds_file.write(snapshot);
+        }
+        result
+    }
+
+    /// Place a hold on behalf of `admin`
+    pub fn place(
+        &self,
+        admin: &str,
+        target: HoldTarget,
+        case_id: &str,
+        reason: Option<String>,
+        expires_at: Option<u64>,
+    ) -> Hold {
+        let hold = Hold {
+            hold_id: Uuid::new_v4().simple().to_string(),
+            target,
+            case_id: case_id.to_owned(),
+            reason,
+            created_by: admin.to_owned(),
+            created_at: now_millis(),
+            expires_at,
+        };
+        info!("⚖️ {} placed legal hold {} on {:?} for case {}", admin, hold.hold_id, hold.target, case_id);
+        self.update(|holds| holds.insert(hold.hold_id.clone(), hold.clone()));
+        hold
+    }
+
+    /// Release a hold, returning it if it existed
+    pub fn release(&self, hold_id: &str) -> Option<Hold> {
+        self.update(|holds| holds.remove(hold_id))
+    }
+
+    /// Active holds, oldest first, optionally of one case
+    pub fn holds(&self, case_id: Option<&str>) -> Vec<Hold> {
+        let now = now_millis();
+        let mut holds: Vec<Hold> = self
+            .holds
+            .read()
+            .unwrap()
+            .values()
+            .filter(|hold| hold.is_active(now) && case_id.is_none_or(|case_id| hold.case_id == case_id))
+            .cloned()
+            .collect();
+        holds.sort_by(|a, b| (a.created_at, &a.hold_id).cmp(&(b.created_at, &b.hold_id)));
+        holds
+    }
+
+    pub fn is_room_held(&self, room_id: &str) -> bool {
+        self.is_held(|target| matches!(target, HoldTarget::RoomId(held) if held == room_id))
+    }
+
+    pub fn is_user_held(&self, user_id: &str) -> bool {
+        self.is_held(|target| matches!(target, HoldTarget::UserId(held) if held == user_id))
+    }
+
+    /// Users under an active hold
+    pub fn held_users(&self) -> Vec<String> {
+        self.holds(None)
+            .into_iter()
+            .filter_map(|hold| match hold.target {
+                HoldTarget::UserId(user_id) => Some(user_id),
+                HoldTarget::RoomId(_) => None,
+            })
+            .collect()
+    }
+
+    /// Whether an event of `sender` in `room_id` must be preserved
+    pub fn is_event_held(&self, room_id: &str, sender: &str) -> bool {
+        self.is_room_held(room_id) || self.is_user_held(sender)
+    }
+
+    /// Ids of the local media (`mxc://{server_name}/…`) the events of held
+    /// rooms refer to
+    pub fn held_room_media(&self, timeline: &timeline::Service, server_name: &str) -> BTreeSet<String> {
+        let now = now_millis();
+        let rooms: BTreeSet<String> = self
+            .holds
+            .read()
+            .unwrap()
+            .values()
+            .filter(|hold| hold.is_active(now))
+            .filter_map(|hold| match &hold.target {
+                HoldTarget::RoomId(room_id) => Some(room_id.clone()),
+                HoldTarget::UserId(_) => None,
+            })
+            .collect();
+        let mut media = BTreeSet::new();
+        if rooms.is_empty() {
+            return media;
+        }
+        let prefix = format!("mxc://{}/", server_name);
+        timeline.for_each_event(|event| {
+            if event["room_id"].as_str().is_some_and(|room_id| rooms.contains(room_id)) {
+                room_deletion::collect_media(&event["content"], &prefix, &mut media);
+            }
+        });
+        media
+    }
+
+    /// Whether the local media `media_id` uploaded by `uploader` must be
+    /// preserved
+    pub fn is_media_held(&self, timeline: &timeline::Service, server_name: &str, media_id: &str, uploader: &str) -> bool {
+        self.is_user_held(uploader) || self.held_room_media(timeline, server_name).contains(media_id)
+    }
+
+    fn is_held(&self, matches: impl Fn(&HoldTarget) -> bool) -> bool {
+        let now = now_millis();
+        self.holds
+            .read()
+            .unwrap()
+            .values()
+            .any(|hold| hold.is_active(now) && matches(&hold.target))
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_holds_cover_their_target_until_released_or_expired() {
+        let service = Service::new();
+        let room = service.place(
+            "@admin:matrixon.local",
+            HoldTarget::RoomId("!a:matrixon.local".to_owned()),
+            "CASE-1",
+            Some("litigation".to_owned()),
+            None,
+        );
+        service.place("@admin:matrixon.local", HoldTarget::UserId("@bob:matrixon.local".to_owned()), "CASE-2", None, None);
+        service.place("@admin:matrixon.local", HoldTarget::UserId("@carol:matrixon.local".to_owned()), "CASE-2", None, Some(1));
+
+        assert!(service.is_event_held("!a:matrixon.local", "@alice:matrixon.local"));
+        assert!(service.is_event_held("!b:matrixon.local", "@bob:matrixon.local"));
+        assert!(!service.is_event_held("!b:matrixon.local", "@alice:matrixon.local"));
+        // Expired
+        assert!(!service.is_user_held("@carol:matrixon.local"));
+        assert_eq!(service.holds(Some("CASE-2")).len(), 1);
+
+        let json = serde_json::to_value(&room).unwrap();
+        assert_eq!(json["room_id"], "!a:matrixon.local");
+        assert_eq!(json["case_id"], "CASE-1");
+
+        assert_eq!(service.release(&room.hold_id), Some(room));
+        assert!(!service.is_room_held("!a:matrixon.local"));
+        assert_eq!(service.holds(None).len(), 1);
+    }
+
+    #[test]
+    fn test_holds_are_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("legal_holds.json");
+        let service = Service::new().with_holds_file(Some(path.clone()));
+        let room = service.place("@admin:matrixon.local", HoldTarget::RoomId("!a:matrixon.local".to_owned()), "CASE-1", None, None);
+        let user = service.place("@admin:matrixon.local", HoldTarget::UserId("@bob:matrixon.local".to_owned()), "CASE-2", None, None);
+        service.release(&room.hold_id);
+
+        let service = Service::new().with_holds_file(Some(path));
+        assert_eq!(service.holds(None), vec![user]);
+        assert!(service.is_user_held("@bob:matrixon.local"));
+        assert!(!service.is_room_held("!a:matrixon.local"));
+    }
+
+    #[test]
+    fn test_media_of_held_rooms_and_users_is_held() {
+        let timeline = timeline::Service::new();
+        let service = Service::new();
+        for (room_id, media_id) in [("!held:matrixon.local", "kept"), ("!other:matrixon.local", "free")] {
+            let content = serde_json::json!({ "msgtype": "m.image", "url": format!("mxc://matrixon.local/{}", media_id) });
+            timeline.append_event(room_id, "@alice:matrixon.local", "m.room.message", None, content);
+        }
+        service.place("@admin:matrixon.local", HoldTarget::RoomId("!held:matrixon.local".to_owned()), "CASE-1", None, None);
+        service.place("@admin:matrixon.local", HoldTarget::UserId("@bob:matrixon.local".to_owned()), "CASE-2", None, None);
+
+        assert_eq!(service.held_room_media(&timeline, "matrixon.local"), BTreeSet::from(["kept".to_owned()]));
+        assert!(service.is_media_held(&timeline, "matrixon.local", "kept", "@alice:matrixon.local"));
+        assert!(!service.is_media_held(&timeline, "matrixon.local", "free", "@alice:matrixon.local"));
+        assert!(service.is_media_held(&timeline, "matrixon.local", "free", "@bob:matrixon.local"));
+    }
+}
@@ -0,0 +1,483 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Listening Socket
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   The listening sockets and the loop serving connections on them. Each
+//   configured listener is a TCP address or a unix domain socket, serves a
+//   subset of the APIs and limits its own connections; TCP listeners used
+//   only by other components can require mutual TLS with certificates of
+//   the internal CA. Zero-downtime deploys
+//   are supported: under systemd socket activation the sockets are inherited
+//   from the service manager, which keeps them open across restarts, the
+//   n-th passed socket being used for the n-th listener. Otherwise TCP
+//   sockets
+//   can be bound with SO_REUSEPORT, so a new process listens on the port
+//   while the previous one is still draining its connections after
+//   SIGTERM. An address taken by another process is handled as configured:
+//   fail with a diagnostic, wait for it to be released, or stop the previous
+//   Matrixon instance named in the pidfile and wait for it. Nothing else is
+//   ever signalled.
+//
+// =============================================================================
+
+use std::{
+    env, fmt,
+    future::Future,
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{
+        conn::auto,
+        graceful::{GracefulShutdown, Watcher},
+    },
+    service::TowerToHyperService,
+};
+use matrixon_common::internal_tls::{self, TlsAcceptor};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpSocket},
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::config::{ListenerConfig, OnPortConflict, PortConflictConfig};
+
+/// First file descriptor passed by systemd socket activation
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Backlog of sockets bound by Matrixon
+const BACKLOG: u32 = 1024;
+
+/// Time between two attempts to bind a taken address
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A bound listening socket
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "http://{}", addr),
+                Err(_) => f.write_str("tcp"),
+            },
+            #[cfg(unix)]
+            Self::Unix(_, path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Number of sockets passed to this process by systemd socket activation
+pub fn socket_activation_fds() -> usize {
+    let for_us = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok()) == Some(std::process::id());
+    if !for_us {
+        return 0;
+    }
+    env::var("LISTEN_FDS").ok().and_then(|fds| fds.parse().ok()).unwrap_or(0)
+}
+
+/// The `index`-th socket passed by systemd, of the kind `config` asks for
+#[cfg(unix)]
+pub fn inherited(index: usize, config: &ListenerConfig) -> io::Result<Listener> {
+    use std::os::unix::io::{FromRawFd, RawFd};
+
+    let fd = RawFd::try_from(index).ok().and_then(|index| SD_LISTEN_FDS_START.checked_add(index));
+    let fd = fd.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Too many inherited sockets"))?;
+    // SAFETY: systemd passes the listening sockets as the file descriptors
+    // starting at SD_LISTEN_FDS_START, LISTEN_PID says they are ours, and
+    // each one is taken by the listener at its position only.
+    match &config.unix_socket {
+        Some(path) => {
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(Listener::Unix(tokio::net::UnixListener::from_std(listener)?, PathBuf::from(path)))
+        }
+        None => {
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn inherited(_index: usize, _config: &ListenerConfig) -> io::Result<Listener> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Socket activation is only supported on Unix"))
+}
+
+/// Bind the socket of a configured listener
+pub async fn open(
+    config: &ListenerConfig,
+    reuse_port: bool,
+    port_conflict: &PortConflictConfig,
+    pidfile: Option<&Path>,
+) -> io::Result<Listener> {
+    match (config.bind, &config.unix_socket) {
+        (Some(addr), None) => Ok(Listener::Tcp(bind_resolving_conflicts(addr, reuse_port, port_conflict, pidfile).await?)),
+        (None, Some(path)) => bind_unix(Path::new(path), config.unix_socket_mode),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "A listener needs exactly one of `bind` and `unix_socket`")),
+    }
+}
+
+/// Bind a unix domain socket, replacing a stale socket file left behind by
+/// an instance that did not shut down cleanly
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<Listener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is already in use", path.display())));
+        }
+        debug!("Removing the stale socket {}", path.display());
+        fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(Listener::Unix(listener, path.to_owned()))
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &Path, _mode: Option<u32>) -> io::Result<Listener> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Unix sockets are only supported on Unix"))
+}
+
+/// Serve `app` on `listener` until `shutdown` completes, then wait for the
+/// open connections to finish. At most `max_connections` are served at once;
+/// further ones wait in the backlog. With `tls`, TCP connections must
+/// complete a TLS handshake with it first.
+pub async fn serve(
+    listener: Listener,
+    app: Router,
+    max_connections: Option<usize>,
+    tls: Option<TlsAcceptor>,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    let limit = max_connections.map(|max| Arc::new(Semaphore::new(max)));
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    loop {
+        let permit = match &limit {
+            Some(limit) => tokio::select! {
+                permit = limit.clone().acquire_owned() => Some(permit.expect("the semaphore is never closed")),
+                () = &mut shutdown => break,
+            },
+            None => None,
+        };
+        let accepted = tokio::select! {
+            accepted = accept(&listener) => accepted,
+            () = &mut shutdown => break,
+        };
+        match accepted {
+            Ok(Connection::Tcp(stream)) => match &tls {
+                Some(acceptor) => {
+                    let (acceptor, app, watcher) = (acceptor.clone(), app.clone(), graceful.watcher());
+                    tokio::spawn(async move {
+                        match tokio::time::timeout(internal_tls::HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                            Ok(Ok(stream)) => spawn_connection(stream, &app, watcher, permit),
+                            Ok(Err(e)) => debug!("Rejected a TLS connection: {}", e),
+                            Err(_) => debug!("A TLS handshake timed out"),
+                        }
+                    });
+                }
+                None => spawn_connection(stream, &app, graceful.watcher(), permit),
+            },
+            #[cfg(unix)]
+            Ok(Connection::Unix(stream)) => spawn_connection(stream, &app, graceful.watcher(), permit),
+            Err(e) => {
+                // Out of file descriptors and the like, give it some time
+                error!("❌ Accepting a connection on {} failed: {}", listener, e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+    debug!("Draining {} connections on {}", graceful.count(), listener);
+    graceful.shutdown().await;
+    #[cfg(unix)]
+    if let Listener::Unix(_, path) = &listener {
+        let _ = fs::remove_file(path);
+    }
+    Ok(())
+}
+
+enum Connection {
+    Tcp(tokio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+async fn accept(listener: &Listener) -> io::Result<Connection> {
+    match listener {
+        Listener::Tcp(listener) => listener.accept().await.map(|(stream, _)| Connection::Tcp(stream)),
+        #[cfg(unix)]
+        Listener::Unix(listener, _) => listener.accept().await.map(|(stream, _)| Connection::Unix(stream)),
+    }
+}
+
+fn spawn_connection<S>(stream: S, app: &Router, watcher: Watcher, permit: Option<OwnedSemaphorePermit>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = TowerToHyperService::new(app.clone());
+    let connection = auto::Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(TokioIo::new(stream), service)
+        .into_owned();
+    let connection = watcher.watch(connection);
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Connection closed: {}", e);
+        }
+        drop(permit);
+    });
+}
+
+/// Bind `addr`, with SO_REUSEPORT when `reuse_port` is set
+pub fn bind(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket.set_reuseaddr(true)?;
+    #[cfg(unix)]
+    socket.set_reuseport(reuse_port)?;
+    #[cfg(not(unix))]
+    if reuse_port {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "SO_REUSEPORT is only supported on Unix"));
+    }
+    socket.bind(addr)?;
+    socket.listen(BACKLOG)
+}
+
+/// Bind `addr`, handling an address in use as `port_conflict` says
+pub async fn bind_resolving_conflicts(
+    addr: SocketAddr,
+    reuse_port: bool,
+    port_conflict: &PortConflictConfig,
+    pidfile: Option<&Path>,
+) -> io::Result<TcpListener> {
+    match bind(addr, reuse_port) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {}
+        result => return result,
+    }
+    let wait = Duration::from_secs(port_conflict.wait_s);
+    match port_conflict.on_conflict {
+        OnPortConflict::Fail => Err(in_use(addr, "")),
+        OnPortConflict::Wait => {
+            info!("⏳ {} is in use, waiting up to {}s for it to be released", addr, wait.as_secs());
+            retry_bind(addr, reuse_port, wait).await
+        }
+        OnPortConflict::Takeover => {
+            let pid = previous_instance(addr, pidfile)?;
+            info!("🔁 Stopping the previous instance (pid {}) to take over {}", pid, addr);
+            terminate(pid)?;
+            retry_bind(addr, reuse_port, wait).await
+        }
+    }
+}
+
+/// Error explaining how to resolve a conflict on `addr`
+fn in_use(addr: SocketAddr, detail: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!(
+            "{} is already in use{}. Stop the process listening on it, change `port`, enable `reuse_port` for rolling \
+             restarts, or set `port_conflict.on_conflict` to \"wait\" or \"takeover\"",
+            addr, detail
+        ),
+    )
+}
+
+async fn retry_bind(addr: SocketAddr, reuse_port: bool, wait: Duration) -> io::Result<TcpListener> {
+    let deadline = Instant::now() + wait;
+    loop {
+        tokio::time::sleep(RETRY_INTERVAL).await;
+        match bind(addr, reuse_port) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+                if Instant::now() >= deadline {
+                    return Err(in_use(addr, &format!(" after waiting {}s", wait.as_secs())));
+                }
+            }
+            result => return result,
+        }
+    }
+}
+
+fn read_pid(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Pid of the previous Matrixon instance named in the pidfile
+fn previous_instance(addr: SocketAddr, pidfile: Option<&Path>) -> io::Result<u32> {
+    let pidfile = pidfile.ok_or_else(|| in_use(addr, " and no pidfile is configured"))?;
+    let pid = read_pid(pidfile).ok_or_else(|| in_use(addr, &format!(" and {} names no process", pidfile.display())))?;
+    if pid == std::process::id() || !is_matrixon(pid) {
+        return Err(in_use(addr, &format!(" and pid {} from {} is not a previous Matrixon instance", pid, pidfile.display())));
+    }
+    Ok(pid)
+}
+
+/// Whether `pid` runs the executable this process was started from, also
+/// when a deploy has replaced the file since. Only Linux can tell; elsewhere
+/// no process is taken over.
+#[cfg(target_os = "linux")]
+fn is_matrixon(pid: u32) -> bool {
+    let exe = |pid: &str| {
+        let path = fs::read_link(format!("/proc/{}/exe", pid)).ok()?;
+        let path = path.to_str()?;
+        Some(path.strip_suffix(" (deleted)").unwrap_or(path).to_owned())
+    };
+    matches!((exe(&pid.to_string()), exe("self")), (Some(theirs), Some(ours)) if theirs == ours)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_matrixon(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn terminate(pid: u32) -> io::Result<()> {
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::Pid;
+
+    let pid = i32::try_from(pid).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid pid"))?;
+    kill(Pid::from_raw(pid), Signal::SIGTERM).map_err(io::Error::from)
+}
+
+#[cfg(not(unix))]
+fn terminate(_pid: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Taking over a previous instance is only supported on Unix"))
+}
+
+/// Record the pid of this process
+pub fn write_pidfile(path: &Path) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, format!("{}\n", std::process::id()))
+}
+
+/// Remove the pidfile unless another instance has replaced it meanwhile
+pub fn remove_pidfile(path: &Path) {
+    if read_pid(path) == Some(std::process::id()) {
+        if let Err(e) = fs::remove_file(path) {
+            warn!("⚠️ Could not remove the pidfile {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reuse_port_allows_a_second_listener() {
+        let first = bind("127.0.0.1:0".parse().unwrap(), true).unwrap();
+        let addr = first.local_addr().unwrap();
+        let second = bind(addr, true).unwrap();
+        assert_eq!(second.local_addr().unwrap(), addr);
+
+        let exclusive = bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        assert!(bind(exclusive.local_addr().unwrap(), false).is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_serves_on_unix_sockets() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("matrixon.sock");
+        // A socket file nobody listens on is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        let config = ListenerConfig {
+            unix_socket: Some(path.to_str().unwrap().to_owned()),
+            unix_socket_mode: Some(0o660),
+            max_connections: Some(1),
+            ..Default::default()
+        };
+        let listener = open(&config, false, &PortConflictConfig::default(), None).await.unwrap();
+        assert_eq!(listener.to_string(), format!("unix:{}", path.display()));
+
+        let app = Router::new().route("/", axum::routing::get(|| async { "it works" }));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, app, config.max_connections, None, async {
+            let _ = stopped.await;
+        }));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("it works"));
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_listener_needs_one_socket() {
+        let config = ListenerConfig {
+            bind: Some("127.0.0.1:0".parse().unwrap()),
+            unix_socket: Some("/tmp/matrixon.sock".to_owned()),
+            ..Default::default()
+        };
+        let e = open(&config, false, &PortConflictConfig::default(), None).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_port_conflicts() {
+        let holder = bind("127.0.0.1:0".parse().unwrap(), false).unwrap();
+        let addr = holder.local_addr().unwrap();
+        let fail = PortConflictConfig::default();
+        let e = bind_resolving_conflicts(addr, false, &fail, None).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        assert!(e.to_string().contains("port_conflict.on_conflict"));
+
+        // Only a previous instance named in the pidfile is taken over
+        let dir = tempfile::tempdir().unwrap();
+        let pidfile = dir.path().join("matrixon.pid");
+        let takeover = PortConflictConfig { on_conflict: OnPortConflict::Takeover, wait_s: 0, pidfile: None };
+        assert!(bind_resolving_conflicts(addr, false, &takeover, Some(&pidfile)).await.is_err());
+        write_pidfile(&pidfile).unwrap();
+        assert!(bind_resolving_conflicts(addr, false, &takeover, Some(&pidfile)).await.is_err());
+        #[cfg(target_os = "linux")]
+        {
+            assert!(is_matrixon(std::process::id()));
+            // Init runs another executable
+            assert!(!is_matrixon(1));
+        }
+        remove_pidfile(&pidfile);
+        assert!(!pidfile.exists());
+
+        let wait = PortConflictConfig { on_conflict: OnPortConflict::Wait, wait_s: 5, pidfile: None };
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            drop(holder);
+        });
+        let listener = bind_resolving_conflicts(addr, false, &wait, None).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+    }
+}
@@ -0,0 +1,104 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Localization
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Translations of the text the server writes for people rather than
+//   clients: emails, the server-rendered pages and the errors shown on
+//   them. The built-in messages are English; operators add or override
+//   translations as Fluent files below the configured locale directory.
+//   The locale of a user is the one stored in their account data, then the
+//   browser's `Accept-Language`, then the configured default. Errors of the
+//   client API stay untranslated, clients localize them by `errcode`.
+//
+// =============================================================================
+
+use std::sync::Arc;
+
+use axum::http::{header, HeaderMap};
+use matrixon_common::i18n::{self, Catalog};
+
+use crate::{
+    config::LocalizationConfig,
+    service::{accounts, pages},
+    Error, Result,
+};
+
+/// Global account data event holding the user's preferred locale, as
+/// `{ "locale": "de-DE" }`
+pub const LOCALE_EVENT_TYPE: &str = "im.matrixon.locale";
+
+/// Localization service
+#[derive(Debug)]
+pub struct Service {
+    catalog: Arc<Catalog>,
+}
+
+impl Service {
+    /// The built-in messages with the translations of the configured locale
+    /// directory
+    pub fn new(config: &LocalizationConfig) -> Result<Self> {
+        let mut catalog = Catalog::new("en").with_default_locale(&config.default_locale);
+        for messages in [matrixon_email::template::MESSAGES, pages::MESSAGES] {
+            catalog.add_resource("en", messages).expect("built-in messages are valid");
+        }
+        if let Some(dir) = &config.locale_dir {
+            catalog
+                .load_dir(dir)
+                .map_err(|e| Error::BadConfig(format!("Invalid translations: {}", e)))?;
+        }
+        Ok(Self { catalog: Arc::new(catalog) })
+    }
+
+    pub fn catalog(&self) -> Arc<Catalog> {
+        Arc::clone(&self.catalog)
+    }
+
+    /// Locale to write to the user of a request in, `user_id` being the
+    /// authenticated user if any
+    pub fn locale(&self, accounts: &accounts::Service, user_id: Option<&str>, headers: &HeaderMap) -> String {
+        let preferred = user_id
+            .and_then(|user_id| accounts.account_data(user_id, LOCALE_EVENT_TYPE))
+            .and_then(|content| content["locale"].as_str().map(str::to_owned));
+        let accepted = headers
+            .get(header::ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .map(i18n::parse_accept_language)
+            .unwrap_or_default();
+        self.catalog
+            .negotiate(preferred.iter().chain(accepted.iter()).map(String::as_str))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use serde_json::json;
+
+    #[test]
+    fn test_locale_selection() {
+        let dir = tempfile::tempdir().unwrap();
+        for locale in ["de", "fr"] {
+            std::fs::create_dir(dir.path().join(locale)).unwrap();
+            std::fs::write(dir.path().join(locale).join("pages.ftl"), "page-confirm = ...").unwrap();
+        }
+        let service = Service::new(&LocalizationConfig { locale_dir: Some(dir.path().to_owned()), ..Default::default() }).unwrap();
+        let accounts = accounts::Service::new();
+        let mut headers = HeaderMap::new();
+        assert_eq!(service.locale(&accounts, None, &headers), "en");
+
+        headers.insert(header::ACCEPT_LANGUAGE, HeaderValue::from_static("ja, fr-CH;q=0.9, de;q=0.8"));
+        assert_eq!(service.locale(&accounts, Some("@alice:matrixon.local"), &headers), "fr");
+
+        accounts.set_account_data("@alice:matrixon.local", LOCALE_EVENT_TYPE, json!({ "locale": "de_DE" }));
+        assert_eq!(service.locale(&accounts, Some("@alice:matrixon.local"), &headers), "de");
+        assert_eq!(service.locale(&accounts, Some("@bob:matrixon.local"), &headers), "fr");
+    }
+}
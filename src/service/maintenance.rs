@@ -0,0 +1,378 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Scheduled Maintenance
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Housekeeping run every `cleanup_second_intervals`. Tables with many
+//   dead rows are vacuumed and analyzed on the primary and every shard,
+//   B-tree indexes are checked for bloat, and local media that no event
+//   refers to and that is not its uploader's avatar is deleted once it is
+//   old enough to not just be waiting to be sent. Encrypted events hide
+//   what they refer to, so media of uploaders who sent any is kept. Rooms
+//   are scanned one at a time, and events appended during the scan are
+//   checked again right before deleting. Outliers no event refers
+//   to are dropped once they are old, soft-failed ones included. Outcomes
+//   are exported as metrics.
+//
+// =============================================================================
+
+use std::{
+    collections::BTreeSet,
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use matrixon_db::maintenance;
+use serde_json::Value;
+use sqlx::postgres::PgPool;
+use tracing::{debug, info, warn};
+
+use crate::{
+    service::{media_store, room_deletion, timeline},
+    services,
+};
+
+/// Uploads younger than this are never orphaned: they may not be sent yet
+const ORPHANED_MEDIA_GRACE: Duration = Duration::from_secs(24 * 3600);
+/// Outliers no event refers to are kept this long, in case a late event
+/// still names them
+const OUTLIER_RETENTION: Duration = Duration::from_secs(7 * 24 * 3600);
+/// Dead rows a table needs before it is vacuumed
+const VACUUM_MIN_DEAD_TUPLES: i64 = 1000;
+/// Share of dead rows a table needs before it is vacuumed
+const VACUUM_MIN_DEAD_RATIO: f64 = 0.1;
+/// Smallest index checked for bloat
+const BLOAT_MIN_INDEX_BYTES: i64 = 10 << 20;
+/// Share of bloat above which an index is reported
+const BLOAT_MIN_RATIO: f64 = 0.5;
+
+/// Maintenance scheduler
+#[derive(Debug)]
+pub struct Service {
+    interval: Duration,
+    runs: AtomicU64,
+    failures: AtomicU64,
+    vacuumed_tables: AtomicU64,
+    /// Indexes found bloated by the last run
+    bloated_indexes: AtomicU64,
+    index_bloat_bytes: AtomicU64,
+    deleted_media: AtomicU64,
+    pruned_outliers: AtomicU64,
+    last_run_ms: AtomicU64,
+}
+
+impl Service {
+    pub fn new(interval_s: u64) -> Self {
+        Self {
+            interval: Duration::from_secs(interval_s.max(1)),
+            runs: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            vacuumed_tables: AtomicU64::new(0),
+            bloated_indexes: AtomicU64::new(0),
+            index_bloat_bytes: AtomicU64::new(0),
+            deleted_media: AtomicU64::new(0),
+            pruned_outliers: AtomicU64::new(0),
+            last_run_ms: AtomicU64::new(0),
+        }
+    }
+
+    /// Vacuum the tables of `pools` with many dead rows and check their
+    /// indexes for bloat
+    pub async fn maintain_databases(&self, pools: &[&PgPool]) {
+        let (mut bloated, mut bloat_bytes) = (0, 0);
+        for (index, pool) in pools.iter().enumerate() {
+            match maintenance::tables_needing_vacuum(pool, VACUUM_MIN_DEAD_TUPLES, VACUUM_MIN_DEAD_RATIO).await {
+                Ok(tables) => {
+                    for table in tables {
+                        match maintenance::vacuum_analyze(pool, &table.schema, &table.table).await {
+                            Ok(()) => {
+                                debug!("🧹 Vacuumed {} on database {}: {} dead rows", table.table, index, table.dead_tuples);
+                                self.vacuumed_tables.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(e) => self.failed(&format!("Could not vacuum {} on database {}", table.table, index), e),
+                        }
+                    }
+                }
+                Err(e) => self.failed(&format!("Could not find tables to vacuum on database {}", index), e),
+            }
+
+            match maintenance::bloated_indexes(pool, BLOAT_MIN_INDEX_BYTES, BLOAT_MIN_RATIO).await {
+                Ok(indexes) => {
+                    for index_bloat in &indexes {
+                        warn!(
+                            "⚠️ Index {} of {} on database {} is {:.0}% bloated ({} MiB), consider REINDEX CONCURRENTLY",
+                            index_bloat.index,
+                            index_bloat.table,
+                            index,
+                            index_bloat.bloat_ratio() * 100.0,
+                            index_bloat.bloat_bytes() >> 20
+                        );
+                    }
+                    bloated += indexes.len() as u64;
+                    bloat_bytes += indexes.iter().map(|index| index.bloat_bytes() as u64).sum::<u64>();
+                }
+                Err(e) => self.failed(&format!("Could not check the indexes of database {}", index), e),
+            }
+        }
+        self.bloated_indexes.store(bloated, Ordering::Relaxed);
+        self.index_bloat_bytes.store(bloat_bytes, Ordering::Relaxed);
+    }
+
+    /// Drop the outliers older than `OUTLIER_RETENTION` that no event
+    /// refers to, in memory and in the database
+    async fn prune_outliers(&self, now: u64) {
+        let services = services();
+        let before = now.saturating_sub(OUTLIER_RETENTION.as_millis() as u64);
+        let mut pruned = services.inbound_federation.prune_outliers(before) as u64;
+        if let Some(repositories) = &services.repositories {
+            let before = chrono::DateTime::from_timestamp_millis(before as i64).unwrap_or_default();
+            match repositories.outliers.prune(before).await {
+                Ok(stored) => pruned = pruned.max(stored),
+                Err(e) => self.failed("Could not prune the stored outliers", e),
+            }
+        }
+        if pruned > 0 {
+            debug!("🧹 Dropped {} unreferenced outliers", pruned);
+        }
+        self.pruned_outliers.fetch_add(pruned, Ordering::Relaxed);
+    }
+
+    fn failed(&self, what: &str, error: impl std::fmt::Display) {
+        warn!("⚠️ {}: {}", what, error);
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Maintenance in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let metrics = [
+            ("runs_total", "counter", "Maintenance runs", &self.runs),
+            ("failures_total", "counter", "Maintenance tasks that failed", &self.failures),
+            ("vacuumed_tables_total", "counter", "Tables vacuumed and analyzed", &self.vacuumed_tables),
+            ("bloated_indexes", "gauge", "Indexes found bloated by the last run", &self.bloated_indexes),
+            ("index_bloat_bytes", "gauge", "Bytes of bloat in the indexes found bloated by the last run", &self.index_bloat_bytes),
+            ("orphaned_media_deleted_total", "counter", "Unreferenced local media deleted", &self.deleted_media),
+            ("outliers_pruned_total", "counter", "Unreferenced outlier events dropped", &self.pruned_outliers),
+            ("last_run_duration_ms", "gauge", "Duration of the last run", &self.last_run_ms),
+        ];
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(out, "# HELP matrixon_maintenance_{} {}", name, help);
+            let _ = writeln!(out, "# TYPE matrixon_maintenance_{} {}", name, kind);
+            let _ = writeln!(out, "matrixon_maintenance_{} {}", name, value.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+/// References to local media found in the timeline
+struct References {
+    prefix: String,
+    /// Uploaders of the candidates for deletion
+    uploaders: BTreeSet<String>,
+    media: BTreeSet<String>,
+    /// Uploaders who sent encrypted events, whose references cannot be seen
+    encrypting: BTreeSet<String>,
+}
+
+impl References {
+    fn new(server_name: &str, candidates: &[(String, String)]) -> Self {
+        Self {
+            prefix: format!("mxc://{}/", server_name),
+            uploaders: candidates.iter().map(|(_, uploader)| uploader.clone()).collect(),
+            media: BTreeSet::new(),
+            encrypting: BTreeSet::new(),
+        }
+    }
+
+    fn collect(&mut self, event: &Value) {
+        room_deletion::collect_media(&event["content"], &self.prefix, &mut self.media);
+        if event["type"] == "m.room.encrypted" {
+            if let Some(sender) = event["sender"].as_str().filter(|sender| self.uploaders.contains(*sender)) {
+                self.encrypting.insert(sender.to_owned());
+            }
+        }
+    }
+
+    fn keep(&self, (media_id, uploader): &(String, String)) -> bool {
+        self.media.contains(media_id) || self.encrypting.contains(uploader)
+    }
+}
+
+/// Ids and uploaders of the local media uploaded before `before`, in
+/// milliseconds since the epoch, that no event refers to, with the stream
+/// count the scan started at. Rooms are scanned one at a time.
+pub async fn orphaned_media(
+    media_store: &media_store::Service,
+    timeline: &timeline::Service,
+    server_name: &str,
+    before: u64,
+) -> (Vec<(String, String)>, u64) {
+    let position = timeline.current_count();
+    let candidates = media_store.uploaded_before(before);
+    if candidates.is_empty() {
+        return (candidates, position);
+    }
+    let mut references = References::new(server_name, &candidates);
+    for room_id in timeline.room_ids() {
+        timeline.for_each_event_in(&room_id, |event| references.collect(event));
+        tokio::task::yield_now().await;
+    }
+    (candidates.into_iter().filter(|candidate| !references.keep(candidate)).collect(), position)
+}
+
+/// Delete `orphaned` media unless an event appended after stream count
+/// `position` refers to it, returning how many files were deleted. No event
+/// is appended while this checks and deletes.
+pub fn delete_unless_referenced_since(
+    media_store: &media_store::Service,
+    timeline: &timeline::Service,
+    server_name: &str,
+    position: u64,
+    orphaned: Vec<(String, String)>,
+) -> usize {
+    let mut references = References::new(server_name, &orphaned);
+    timeline.with_events_since(position, |events| {
+        events.for_each(|event| references.collect(event));
+        orphaned
+            .iter()
+            .filter(|candidate| !references.keep(candidate))
+            .filter(|(media_id, _)| media_store.delete(media_id).is_some())
+            .count()
+    })
+}
+
+/// Delete orphaned media that is not its uploader's avatar either,
+/// returning how many files were deleted
+async fn delete_orphaned_media(now: u64) -> usize {
+    let services = services();
+    let server_name = &services.globals.config.server_name;
+    let before = now.saturating_sub(ORPHANED_MEDIA_GRACE.as_millis() as u64);
+    let (orphaned, position) = orphaned_media(&services.media_store, &services.timeline, server_name, before).await;
+    let mut deletable = Vec::new();
+    for (media_id, uploader) in orphaned {
+        // Orphaned media is in no room, so only a hold on its uploader
+        // applies
+        if services.legal_hold.is_user_held(&uploader) {
+            continue;
+        }
+        let uri = format!("mxc://{}/{}", server_name, media_id);
+        let is_avatar = match services.profiles.get(&uploader).await {
+            Ok(profile) => profile.avatar_url.as_deref() == Some(uri.as_str()),
+            // Keep what cannot be checked
+            Err(_) => true,
+        };
+        if !is_avatar {
+            deletable.push((media_id, uploader));
+        }
+    }
+    delete_unless_referenced_since(&services.media_store, &services.timeline, server_name, position, deletable)
+}
+
+/// Run maintenance every `cleanup_second_intervals`
+pub async fn run() {
+    let Some(maintenance) = &services().maintenance else {
+        return;
+    };
+    info!("🧹 Running database and media maintenance every {}s", maintenance.interval.as_secs());
+
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + maintenance.interval, maintenance.interval);
+    loop {
+        interval.tick().await;
+        let start = Instant::now();
+        if let Some(repositories) = &services().repositories {
+            let mut pools = vec![repositories.pool()];
+            if repositories.shards().shards().len() > 1 {
+                pools.extend(repositories.shards().shards().iter().map(|shard| shard.pool()));
+            }
+            maintenance.maintain_databases(&pools).await;
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        maintenance.prune_outliers(now).await;
+        let deleted = delete_orphaned_media(now).await;
+        if deleted > 0 {
+            info!("🧹 Deleted {} orphaned media files", deleted);
+        }
+        maintenance.deleted_media.fetch_add(deleted as u64, Ordering::Relaxed);
+        maintenance.runs.fetch_add(1, Ordering::Relaxed);
+        maintenance.last_run_ms.store(start.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_only_unreferenced_media_is_orphaned() {
+        let media_store = media_store::Service::new();
+        let upload = |uploader: &str| {
+            media_store.create(media_store::Media {
+                uploader: uploader.to_owned(),
+                content_type: None,
+                filename: None,
+                data: vec![0],
+            })
+        };
+        let sent = upload("@alice:matrixon.local");
+        let avatar = upload("@bob:matrixon.local");
+        let orphan = upload("@bob:matrixon.local");
+        let encrypted = upload("@carol:matrixon.local");
+
+        let timeline = timeline::Service::new();
+        timeline.append_event("!room:matrixon.local", "@alice:matrixon.local", "m.room.message", None, json!({
+            "msgtype": "m.image",
+            "url": format!("mxc://matrixon.local/{}", sent),
+        }));
+        timeline.append_event("!room:matrixon.local", "@bob:matrixon.local", "m.room.member", Some("@bob:matrixon.local"), json!({
+            "membership": "join",
+            "avatar_url": format!("mxc://matrixon.local/{}", avatar),
+        }));
+        timeline.append_event("!secret:matrixon.local", "@carol:matrixon.local", "m.room.encrypted", None, json!({
+            "algorithm": "m.megolm.v1.aes-sha2",
+            "ciphertext": "AwgAEn",
+        }));
+
+        assert!(orphaned_media(&media_store, &timeline, "matrixon.local", 0).await.0.is_empty());
+        let (orphaned, position) = orphaned_media(&media_store, &timeline, "matrixon.local", u64::MAX).await;
+        assert_eq!(orphaned, [(orphan, "@bob:matrixon.local".to_owned())]);
+        assert_eq!(position, timeline.current_count());
+        assert!(media_store.get(&encrypted).is_some());
+
+        let service = Service::new(60);
+        assert!(service.render().contains("matrixon_maintenance_orphaned_media_deleted_total 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_media_sent_during_the_scan_is_kept() {
+        let media_store = media_store::Service::new();
+        let upload = || {
+            media_store.create(media_store::Media {
+                uploader: "@alice:matrixon.local".to_owned(),
+                content_type: None,
+                filename: None,
+                data: vec![0],
+            })
+        };
+        let (late, orphan) = (upload(), upload());
+        let timeline = timeline::Service::new();
+        let (orphaned, position) = orphaned_media(&media_store, &timeline, "matrixon.local", u64::MAX).await;
+        assert_eq!(orphaned.len(), 2);
+
+        timeline.append_event("!room:matrixon.local", "@alice:matrixon.local", "m.room.message", None, json!({
+            "msgtype": "m.file",
+            "url": format!("mxc://matrixon.local/{}", late),
+        }));
+        assert_eq!(delete_unless_referenced_since(&media_store, &timeline, "matrixon.local", position, orphaned), 1);
+        assert!(media_store.get(&late).is_some());
+        assert!(media_store.get(&orphan).is_none());
+    }
+}
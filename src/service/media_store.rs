@@ -0,0 +1,165 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Media Store Service
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Storage for locally uploaded media, addressed by `mxc://` media id and
+//   tracked per uploader with the time it was uploaded. Also renders media
+//   in the `multipart/mixed` form the federation media endpoints respond
+//   with.
+//
+// =============================================================================
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use uuid::Uuid;
+
+/// A stored media file
+#[derive(Debug, Clone)]
+pub struct Media {
+    pub uploader: String,
+    pub content_type: Option<String>,
+    pub filename: Option<String>,
+    pub data: Vec<u8>,
+}
+
+impl Media {
+    /// Federation media response: a JSON metadata part followed by the
+    /// content. Returns the content type, with its boundary, and the body.
+    pub fn to_multipart(&self) -> (String, Vec<u8>) {
+        let boundary = Uuid::new_v4().simple().to_string();
+        let mut body = format!("--{}\r\nContent-Type: application/json\r\n\r\n{{}}\r\n--{}\r\n", boundary, boundary).into_bytes();
+        let content_type = self.content_type.as_deref().map(header_value).filter(|value| !value.is_empty());
+        body.extend_from_slice(format!("Content-Type: {}\r\n", content_type.as_deref().unwrap_or("application/octet-stream")).as_bytes());
+        if let Some(filename) = &self.filename {
+            let filename: String = header_value(filename).chars().filter(|c| !matches!(c, '"' | '\\')).collect();
+            body.extend_from_slice(format!("Content-Disposition: inline; filename=\"{}\"\r\n", filename).as_bytes());
+        }
+        body.extend_from_slice(b"\r\n");
+        body.extend_from_slice(&self.data);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        (format!("multipart/mixed; boundary={}", boundary), body)
+    }
+}
+
+/// `value` without the control characters, such as CR and LF, that would
+/// end a header line and start another
+fn header_value(value: &str) -> String {
+    value.chars().filter(|c| !c.is_control()).collect()
+}
+
+/// Local media storage service
+#[derive(Debug, Default)]
+pub struct Service {
+    media: RwLock<HashMap<String, Media>>,
+    /// Upload time of every file, milliseconds since the epoch
+    uploaded_at: RwLock<HashMap<String, u64>>,
+    /// Files an admin quarantined, which are no longer served
+    quarantined: RwLock<HashSet<String>>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store an upload and return its media id
+    pub fn create(&self, media: Media) -> String {
+        let media_id = Uuid::new_v4().simple().to_string();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        self.media.write().unwrap().insert(media_id.clone(), media);
+        self.uploaded_at.write().unwrap().insert(media_id.clone(), now);
+        media_id
+    }
+
+    pub fn get(&self, media_id: &str) -> Option<Media> {
+        if self.quarantined.read().unwrap().contains(media_id) {
+            return None;
+        }
+        self.media.read().unwrap().get(media_id).cloned()
+    }
+
+    pub fn delete(&self, media_id: &str) -> Option<Media> {
+        self.uploaded_at.write().unwrap().remove(media_id);
+        self.quarantined.write().unwrap().remove(media_id);
+        self.media.write().unwrap().remove(media_id)
+    }
+
+    /// Stop serving a file. Returns false if there is no such file.
+    pub fn quarantine(&self, media_id: &str) -> bool {
+        if !self.media.read().unwrap().contains_key(media_id) {
+            return false;
+        }
+        self.quarantined.write().unwrap().insert(media_id.to_owned());
+        true
+    }
+
+    /// Ids, files and upload times of the files uploaded by `user_id`,
+    /// newest first
+    pub fn uploaded_by(&self, user_id: &str) -> Vec<(String, Media, u64)> {
+        let uploaded_at = self.uploaded_at.read().unwrap();
+        let mut files: Vec<_> = self
+            .media
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, media)| media.uploader == user_id)
+            .map(|(media_id, media)| (media_id.clone(), media.clone(), uploaded_at.get(media_id).copied().unwrap_or_default()))
+            .collect();
+        files.sort_unstable_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(&b.0)));
+        files
+    }
+
+    /// Ids and uploaders of the files uploaded before `before`, in
+    /// milliseconds since the epoch
+    pub fn uploaded_before(&self, before: u64) -> Vec<(String, String)> {
+        let uploaded_at = self.uploaded_at.read().unwrap();
+        self.media
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(media_id, _)| uploaded_at.get(*media_id).is_some_and(|at| *at < before))
+            .map(|(media_id, media)| (media_id.clone(), media.uploader.clone()))
+            .collect()
+    }
+
+    /// Delete every file uploaded by `user_id` but those `keep` returns true
+    /// for. Returns the number deleted.
+    pub fn delete_user_media(&self, user_id: &str, keep: impl Fn(&str) -> bool) -> usize {
+        let mut media = self.media.write().unwrap();
+        let before = media.len();
+        media.retain(|media_id, file| file.uploader != user_id || keep(media_id));
+        self.uploaded_at.write().unwrap().retain(|media_id, _| media.contains_key(media_id));
+        self.quarantined.write().unwrap().retain(|media_id| media.contains_key(media_id));
+        before - media.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_multipart_headers_cannot_be_injected() {
+        let media = Media {
+            uploader: "@alice:matrixon.local".to_owned(),
+            content_type: Some("text/plain\r\nX-Injected: 1".to_owned()),
+            filename: Some("a\"\r\n\r\nbody.txt".to_owned()),
+            data: b"content".to_vec(),
+        };
+        let (_, body) = media.to_multipart();
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.contains("Content-Type: text/plainX-Injected: 1\r\n"));
+        assert!(body.contains("Content-Disposition: inline; filename=\"abody.txt\"\r\n"));
+    }
+}
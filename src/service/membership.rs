@@ -0,0 +1,935 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Room Membership
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Membership of local users in rooms hosted on this server, derived from
+//   the `m.room.member` state in the room timeline. Membership changes are
+//   authorized against the room's power levels the way the Matrix auth
+//   rules do before their member event is appended, and so are the other
+//   state events members send, including changes of the power levels
+//   themselves. Invites of local users to rooms on other servers are kept
+//   aside with the stripped room state the inviting server sent along,
+//   until the user joins or rejects them; only the newest ones are kept per
+//   user. Knocks of local users on rooms on other servers are sent over
+//   federation and kept aside the same way. Rooms shut down and blocked by a server admin cannot be joined
+//   again.
+//
+// =============================================================================
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+use ruma::api::client::error::ErrorKind;
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::{
+    service::{federation_membership, join_coordinator::JoinOutcome, timeline},
+    services, Error, Result,
+};
+
+/// Most pending invites to rooms on other servers kept per user; older
+/// ones are dropped
+const MAX_REMOTE_INVITES_PER_USER: usize = 100;
+
+/// Invite of a local user to a room hosted on another server
+#[derive(Debug, Clone)]
+pub struct RemoteInvite {
+    /// Stream count at which the invite arrived
+    pub count: u64,
+    /// Stripped state of the room, including the invite itself
+    pub invite_state: Vec<Value>,
+}
+
+/// Knock of a local user on a room hosted on another server
+#[derive(Debug, Clone)]
+pub struct RemoteKnock {
+    /// Stream count at which the knock was accepted
+    pub count: u64,
+    /// Stripped state of the room, including the knock itself
+    pub knock_state: Vec<Value>,
+}
+
+/// Rooms users have forgotten after leaving them, and pending invites to
+/// and knocks on rooms on other servers
+#[derive(Debug, Default)]
+pub struct Service {
+    forgotten: RwLock<HashSet<(String, String)>>,
+    remote_invites: RwLock<HashMap<(String, String), RemoteInvite>>,
+    remote_knocks: RwLock<HashMap<(String, String), RemoteKnock>>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forget a room the user is no longer in, hiding it from their history
+    pub fn forget(&self, room_id: &str, user_id: &str) -> Result<()> {
+        if matches!(membership(room_id, user_id).as_deref(), Some("join") | Some("invite") | Some("knock")) {
+            return Err(Error::BadRequest(ErrorKind::Unknown, "You must leave the room before forgetting it"));
+        }
+        self.forgotten.write().unwrap().insert((user_id.to_owned(), room_id.to_owned()));
+        Ok(())
+    }
+
+    pub fn is_forgotten(&self, room_id: &str, user_id: &str) -> bool {
+        self.forgotten.read().unwrap().contains(&(user_id.to_owned(), room_id.to_owned()))
+    }
+
+    /// Join a room, honouring its join rules; a forgotten room is
+    /// remembered again. Rooms this server is not in yet are joined over
+    /// federation through the servers of `via`, once per room by the join
+    /// coordinator. Returns the membership event id.
+    pub async fn join(&self, room_id: &str, user_id: &str, via: &[String]) -> Result<String> {
+        services().room_deletion.ensure_not_blocked(room_id)?;
+        let event_id = if services().timeline.room_exists(room_id) {
+            join_room(room_id, user_id)?
+        } else {
+            let (room, user, via) = (room_id.to_owned(), user_id.to_owned(), via.to_vec());
+            let remote_join = || async move { federation_membership::join_remote(&room, &user, &via).await };
+            match services().join_coordinator.join(room_id, user_id, remote_join).await? {
+                JoinOutcome::Joined(event_id) => event_id,
+                JoinOutcome::RoomJoined => join_room(room_id, user_id)?,
+            }
+        };
+        self.forgotten.write().unwrap().remove(&(user_id.to_owned(), room_id.to_owned()));
+        self.remove_remote_invite(room_id, user_id);
+        self.remote_knocks.write().unwrap().remove(&(user_id.to_owned(), room_id.to_owned()));
+        Ok(event_id)
+    }
+
+    /// Knock on a room. Rooms this server is not in are knocked on over
+    /// federation through the servers of `via`. Returns the knock event id.
+    pub async fn knock(&self, room_id: &str, user_id: &str, reason: Option<&str>, via: &[String]) -> Result<String> {
+        services().room_deletion.ensure_not_blocked(room_id)?;
+        if services().timeline.room_exists(room_id) {
+            return knock(room_id, user_id, reason);
+        }
+        let (event_id, knock_state) = federation_membership::knock_remote(room_id, user_id, reason, via).await?;
+        let knock = RemoteKnock { count: services().timeline.next_count(), knock_state };
+        self.remote_knocks.write().unwrap().insert((user_id.to_owned(), room_id.to_owned()), knock);
+        Ok(event_id)
+    }
+
+    /// Leave a room, reject an invite or withdraw a knock. An invite to or
+    /// knock on a room on another server is dropped.
+    pub fn leave(&self, room_id: &str, user_id: &str, reason: Option<&str>) -> Result<Option<String>> {
+        if !services().timeline.room_exists(room_id) {
+            let knocked = self.remote_knocks.write().unwrap().remove(&(user_id.to_owned(), room_id.to_owned())).is_some();
+            if self.remove_remote_invite(room_id, user_id) | knocked {
+                info!("🚫 {} left remote room {}", user_id, room_id);
+                return Ok(None);
+            }
+        }
+        leave(room_id, user_id, reason).map(Some)
+    }
+
+    /// Remember an invite of a local user to a room on another server
+    pub fn add_remote_invite(&self, room_id: &str, user_id: &str, invite: RemoteInvite) {
+        info!("✉️ {} was invited to remote room {}", user_id, room_id);
+        let mut remote_invites = self.remote_invites.write().unwrap();
+        remote_invites.insert((user_id.to_owned(), room_id.to_owned()), invite);
+
+        let mut counts: Vec<u64> =
+            remote_invites.iter().filter(|((invitee, _), _)| invitee == user_id).map(|(_, invite)| invite.count).collect();
+        if counts.len() > MAX_REMOTE_INVITES_PER_USER {
+            counts.sort_unstable();
+            let oldest_kept = counts[counts.len() - MAX_REMOTE_INVITES_PER_USER];
+            remote_invites.retain(|(invitee, _), invite| invitee != user_id || invite.count >= oldest_kept);
+        }
+    }
+
+    /// Drop a pending invite to a room on another server, returning
+    /// whether there was one
+    pub fn remove_remote_invite(&self, room_id: &str, user_id: &str) -> bool {
+        self.remote_invites.write().unwrap().remove(&(user_id.to_owned(), room_id.to_owned())).is_some()
+    }
+
+    /// Drop all pending invites of a user to and knocks on rooms on other
+    /// servers
+    pub fn clear_remote_invites(&self, user_id: &str) {
+        self.remote_invites.write().unwrap().retain(|(invitee, _), _| invitee != user_id);
+        self.remote_knocks.write().unwrap().retain(|(knocker, _), _| knocker != user_id);
+    }
+
+    /// Pending invites of a user to rooms on other servers, by room id
+    pub fn remote_invites(&self, user_id: &str) -> Vec<(String, RemoteInvite)> {
+        self.remote_invites
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|((invitee, _), _)| invitee == user_id)
+            .map(|((_, room_id), invite)| (room_id.clone(), invite.clone()))
+            .collect()
+    }
+
+    /// Pending knocks of a user on rooms on other servers, by room id
+    pub fn remote_knocks(&self, user_id: &str) -> Vec<(String, RemoteKnock)> {
+        self.remote_knocks
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|((knocker, _), _)| knocker == user_id)
+            .map(|((_, room_id), knock)| (room_id.clone(), knock.clone()))
+            .collect()
+    }
+}
+
+/// Current membership (`join`, `invite`, `leave`, `ban`, ...) of a user in a room
+pub fn membership(room_id: &str, user_id: &str) -> Option<String> {
+    membership_in(&services().timeline, room_id, user_id)
+}
+
+/// Rooms a user is currently joined to
+pub fn joined_rooms(user_id: &str) -> Vec<String> {
+    rooms_with_membership(user_id, "join")
+}
+
+/// Rooms in which a user currently has the given membership
+pub fn rooms_with_membership(user_id: &str, membership: &str) -> Vec<String> {
+    let timeline = &services().timeline;
+    timeline
+        .room_ids()
+        .into_iter()
+        .filter(|room_id| membership_in(timeline, room_id, user_id).as_deref() == Some(membership))
+        .collect()
+}
+
+/// Stripped state shown to users invited to or knocking on a room, so
+/// clients can display it before the user joins
+pub fn stripped_state(room_id: &str, user_id: &str) -> Vec<Value> {
+    stripped_state_in(&services().timeline, room_id, user_id)
+}
+
+pub fn stripped_state_in(timeline: &timeline::Service, room_id: &str, user_id: &str) -> Vec<Value> {
+    let keys = [
+        ("m.room.create", ""),
+        ("m.room.join_rules", ""),
+        ("m.room.name", ""),
+        ("m.room.canonical_alias", ""),
+        ("m.room.avatar", ""),
+        ("m.room.topic", ""),
+        ("m.room.encryption", ""),
+        ("m.room.member", user_id),
+    ];
+    keys.iter()
+        .filter_map(|(event_type, state_key)| timeline.state_event(room_id, event_type, state_key))
+        .map(|event| {
+            json!({
+                "type": event["type"],
+                "state_key": event["state_key"],
+                "sender": event["sender"],
+                "content": event["content"]
+            })
+        })
+        .collect()
+}
+
+/// Ask to join a room with a `knock` or `knock_restricted` join rule
+pub fn knock(room_id: &str, user_id: &str, reason: Option<&str>) -> Result<String> {
+    knock_in(&services().timeline, room_id, user_id, reason)
+}
+
+/// Join a local room, honouring its join rules. Returns the membership event id.
+pub fn join_room(room_id: &str, user_id: &str) -> Result<String> {
+    join_room_in(&services().timeline, room_id, user_id)
+}
+
+/// Invite `target` on behalf of `sender`
+pub fn invite(room_id: &str, sender: &str, target: &str, reason: Option<&str>) -> Result<String> {
+    change_membership_in(&services().timeline, room_id, sender, target, Change::Invite, reason)
+}
+
+/// Leave a room, or reject an invite or withdraw a knock
+pub fn leave(room_id: &str, user_id: &str, reason: Option<&str>) -> Result<String> {
+    leave_in(&services().timeline, room_id, user_id, reason)
+}
+
+pub fn leave_in(timeline: &timeline::Service, room_id: &str, user_id: &str, reason: Option<&str>) -> Result<String> {
+    change_membership_in(timeline, room_id, user_id, user_id, Change::Leave, reason)
+}
+
+/// Kick `target` out of a room
+pub fn kick(room_id: &str, sender: &str, target: &str, reason: Option<&str>) -> Result<String> {
+    change_membership_in(&services().timeline, room_id, sender, target, Change::Kick, reason)
+}
+
+/// Ban `target` from a room
+pub fn ban(room_id: &str, sender: &str, target: &str, reason: Option<&str>) -> Result<String> {
+    change_membership_in(&services().timeline, room_id, sender, target, Change::Ban, reason)
+}
+
+/// Lift the ban of `target`, leaving them with `leave` membership
+pub fn unban(room_id: &str, sender: &str, target: &str, reason: Option<&str>) -> Result<String> {
+    change_membership_in(&services().timeline, room_id, sender, target, Change::Unban, reason)
+}
+
+/// Check that `user_id` may knock on a room: its join rule must be `knock`
+/// or `knock_restricted` in a room version having it, and the user must not
+/// be banned, joined or invited already
+pub fn check_knock(timeline: &timeline::Service, room_id: &str, user_id: &str) -> Result<()> {
+    let join_rule = timeline
+        .state_event(room_id, "m.room.join_rules", "")
+        .and_then(|event| event["content"]["join_rule"].as_str().map(str::to_owned))
+        .unwrap_or_default();
+    let room_version = timeline
+        .state_event(room_id, "m.room.create", "")
+        .and_then(|create| create["content"]["room_version"].as_str().map(str::to_owned))
+        .unwrap_or_else(|| "1".to_owned());
+    if !matches!(join_rule.as_str(), "knock" | "knock_restricted") || !supports_join_rule(&room_version, &join_rule) {
+        return Err(Error::BadRequest(ErrorKind::forbidden(), "This room does not accept knocks"));
+    }
+
+    match membership_in(timeline, room_id, user_id).as_deref() {
+        Some("ban") => Err(Error::BadRequest(ErrorKind::forbidden(), "The user is banned from this room")),
+        Some("join") => Err(Error::BadRequest(ErrorKind::forbidden(), "The user is already in this room")),
+        Some("invite") => Err(Error::BadRequest(ErrorKind::forbidden(), "The user is already invited to this room")),
+        _ => Ok(()),
+    }
+}
+
+/// Check that `sender` may invite `target` to a room: the sender must be
+/// joined with the `invite` level, and the target neither joined nor banned
+pub fn check_invite(timeline: &timeline::Service, room_id: &str, sender: &str, target: &str) -> Result<()> {
+    if membership_in(timeline, room_id, sender).as_deref() != Some("join") {
+        return Err(Error::BadRequest(ErrorKind::forbidden(), "The inviting user is not in this room"));
+    }
+    let power_levels = timeline
+        .state_event(room_id, "m.room.power_levels", "")
+        .map(|event| event["content"].clone())
+        .unwrap_or(Value::Null);
+    let sender_level = power_level(timeline, room_id, &power_levels, sender);
+    let target_level = power_level(timeline, room_id, &power_levels, target);
+    let current = membership_in(timeline, room_id, target);
+    check_membership_levels(&power_levels, sender_level, target_level, current.as_deref(), "invite")
+        .map_err(|message| Error::BadRequest(ErrorKind::forbidden(), message))
+}
+
+fn knock_in(timeline: &timeline::Service, room_id: &str, user_id: &str, reason: Option<&str>) -> Result<String> {
+    if !timeline.room_exists(room_id) {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+    }
+    check_knock(timeline, room_id, user_id)?;
+
+    let mut content = json!({ "membership": "knock" });
+    if let Some(reason) = reason {
+        content["reason"] = json!(reason);
+    }
+    let event_id = timeline.append_event(room_id, user_id, "m.room.member", Some(user_id), content);
+    info!("🚪 {} knocked on {}", user_id, room_id);
+    Ok(event_id)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Invite,
+    Leave,
+    Kick,
+    Ban,
+    Unban,
+}
+
+/// Power level of `user_id` in a room, following the spec defaults when
+/// the room has no `m.room.power_levels` event
+fn power_level(timeline: &timeline::Service, room_id: &str, power_levels: &Value, user_id: &str) -> i64 {
+    let creator = power_levels.is_null().then(|| {
+        timeline
+            .state_event(room_id, "m.room.create", "")
+            .and_then(|event| event["content"]["creator"].as_str().or(event["sender"].as_str()).map(str::to_owned))
+    });
+    level_in(power_levels, creator.flatten().as_deref(), user_id)
+}
+
+/// Power level of `user_id` in a room
+pub fn power_level_of(timeline: &timeline::Service, room_id: &str, user_id: &str) -> i64 {
+    let power_levels = timeline
+        .state_event(room_id, "m.room.power_levels", "")
+        .map(|event| event["content"].clone())
+        .unwrap_or(Value::Null);
+    power_level(timeline, room_id, &power_levels, user_id)
+}
+
+/// Power level required for `action` (`invite`, `kick` or `ban`)
+fn required_level(power_levels: &Value, action: &str) -> i64 {
+    let default = if action == "invite" { 0 } else { 50 };
+    power_levels[action].as_i64().unwrap_or(default)
+}
+
+fn change_membership_in(
+    timeline: &timeline::Service,
+    room_id: &str,
+    sender: &str,
+    target: &str,
+    change: Change,
+    reason: Option<&str>,
+) -> Result<String> {
+    if !timeline.room_exists(room_id) {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+    }
+    let forbidden = |message| Err(Error::BadRequest(ErrorKind::forbidden(), message));
+
+    let current = membership_in(timeline, room_id, target);
+    let sender_membership = membership_in(timeline, room_id, sender);
+    if change != Change::Leave && sender_membership.as_deref() != Some("join") {
+        return forbidden("You are not in this room");
+    }
+
+    let power_levels = timeline
+        .state_event(room_id, "m.room.power_levels", "")
+        .map(|event| event["content"].clone())
+        .unwrap_or(Value::Null);
+    let sender_level = power_level(timeline, room_id, &power_levels, sender);
+    let target_level = power_level(timeline, room_id, &power_levels, target);
+
+    let membership = match change {
+        Change::Invite => "invite",
+        Change::Leave => {
+            if !matches!(current.as_deref(), Some("join") | Some("invite") | Some("knock")) {
+                return forbidden("You are not in this room");
+            }
+            "leave"
+        }
+        Change::Kick => {
+            if !matches!(current.as_deref(), Some("join") | Some("invite") | Some("knock")) {
+                return forbidden("The user is not in the room");
+            }
+            "leave"
+        }
+        Change::Ban => "ban",
+        Change::Unban => {
+            if current.as_deref() != Some("ban") {
+                return forbidden("The user is not banned");
+            }
+            "leave"
+        }
+    };
+    if change != Change::Leave {
+        check_membership_levels(&power_levels, sender_level, target_level, current.as_deref(), membership)
+            .map_err(|message| Error::BadRequest(ErrorKind::forbidden(), message))?;
+    }
+
+    let mut content = json!({ "membership": membership });
+    if let Some(reason) = reason {
+        content["reason"] = json!(reason);
+    }
+    let event_id = timeline.append_event(room_id, sender, "m.room.member", Some(target), content);
+    info!("🚪 {} changed membership of {} in {} to {} ({:?})", sender, target, room_id, membership, change);
+    Ok(event_id)
+}
+
+/// Check that `sender` may send a message event of `event_type` to a
+/// room: they must be joined, with the power level `events` or
+/// `events_default` requires
+pub fn check_message_event(timeline: &timeline::Service, room_id: &str, sender: &str, event_type: &str, content: &Value) -> Result<()> {
+    if !timeline.room_exists(room_id) {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+    }
+    if membership_in(timeline, room_id, sender).as_deref() != Some("join") {
+        return Err(Error::BadRequest(ErrorKind::forbidden(), "You are not in this room"));
+    }
+
+    let event = json!({ "type": event_type, "sender": sender, "content": content });
+    check_power_levels(&|event_type, state_key| timeline.state_event(room_id, event_type, state_key), &event)
+        .map_err(|message| Error::BadRequest(ErrorKind::forbidden(), message))
+}
+
+/// Check a state event a member sends to a room through the client API.
+/// Membership changes have endpoints of their own, and the create event
+/// can only be the first one. Past that the sender must be joined and pass
+/// [`check_power_levels`].
+pub fn check_state_event(
+    timeline: &timeline::Service,
+    room_id: &str,
+    sender: &str,
+    event_type: &str,
+    state_key: &str,
+    content: &Value,
+) -> Result<()> {
+    if !timeline.room_exists(room_id) {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+    }
+    let forbidden = |message| Err(Error::BadRequest(ErrorKind::forbidden(), message));
+    match event_type {
+        "m.room.member" => return forbidden("Membership is changed through the membership endpoints"),
+        "m.room.create" => return forbidden("The room already has a create event"),
+        _ => {}
+    }
+    if membership_in(timeline, room_id, sender).as_deref() != Some("join") {
+        return forbidden("You are not in this room");
+    }
+
+    let event = json!({ "type": event_type, "state_key": state_key, "sender": sender, "content": content });
+    check_power_levels(&|event_type, state_key| timeline.state_event(room_id, event_type, state_key), &event)
+        .map_err(|message| Error::BadRequest(ErrorKind::forbidden(), message))
+}
+
+/// Check an event against the power levels of the room state `state` looks
+/// up by type and state key, the way the Matrix auth rules do: a create
+/// event must be the first one of the room, membership changes of other
+/// users need the `invite`, `kick` or `ban` level and a level above the
+/// target's, and other events the level `events` lists for their type, or
+/// `state_default` / `events_default`. State keys naming a user are only
+/// theirs to set, and nobody may raise a power level above their own.
+pub fn check_power_levels(state: &dyn Fn(&str, &str) -> Option<Value>, event: &Value) -> std::result::Result<(), &'static str> {
+    let sender = event["sender"].as_str().unwrap_or_default();
+    let event_type = event["type"].as_str().unwrap_or_default();
+    let create = state("m.room.create", "");
+    if event_type == "m.room.create" {
+        return match create {
+            Some(_) => Err("The room already has a create event"),
+            None => Ok(()),
+        };
+    }
+    let power_levels = state("m.room.power_levels", "").map(|event| event["content"].clone()).unwrap_or(Value::Null);
+    let creator = create.and_then(|event| event["content"]["creator"].as_str().or(event["sender"].as_str()).map(str::to_owned));
+    let sender_level = level_in(&power_levels, creator.as_deref(), sender);
+
+    let state_key = event["state_key"].as_str();
+    if event_type == "m.room.member" {
+        let target = state_key.unwrap_or_default();
+        if target == sender {
+            return Ok(());
+        }
+        let current = state("m.room.member", target).and_then(|event| event["content"]["membership"].as_str().map(str::to_owned));
+        let target_level = level_in(&power_levels, creator.as_deref(), target);
+        let membership = event["content"]["membership"].as_str().unwrap_or_default();
+        return check_membership_levels(&power_levels, sender_level, target_level, current.as_deref(), membership);
+    }
+
+    let required = match state_key {
+        Some(_) => power_levels["events"][event_type].as_i64().unwrap_or_else(|| power_levels["state_default"].as_i64().unwrap_or(50)),
+        None => power_levels["events"][event_type].as_i64().unwrap_or_else(|| power_levels["events_default"].as_i64().unwrap_or(0)),
+    };
+    if sender_level < required {
+        return Err("The sender's power level is too low to send this event");
+    }
+    if state_key.is_some_and(|state_key| state_key.starts_with('@') && state_key != sender) {
+        return Err("The state key belongs to another user");
+    }
+
+    if event_type == "m.room.power_levels" && !power_levels.is_null() {
+        check_power_levels_change(&power_levels, &event["content"], sender, sender_level)?;
+    }
+    Ok(())
+}
+
+/// Check that a sender at `sender_level` may change the membership of
+/// another user at `target_level` from `current` to `membership`: invites
+/// need the `invite` level and a target neither joined nor banned, kicks
+/// the `kick` level, unbans and bans the `ban` level, and kicks and bans a
+/// level above the target's
+fn check_membership_levels(
+    power_levels: &Value,
+    sender_level: i64,
+    target_level: i64,
+    current: Option<&str>,
+    membership: &str,
+) -> std::result::Result<(), &'static str> {
+    match (membership, current) {
+        ("invite", Some("join")) => Err("The user is already in the room"),
+        ("invite", Some("ban")) => Err("The user is banned from the room"),
+        ("invite", _) if sender_level < required_level(power_levels, "invite") => Err("You do not have permission to invite users"),
+        ("invite", _) => Ok(()),
+        ("leave", Some("ban")) if sender_level < required_level(power_levels, "ban") => Err("You do not have permission to unban users"),
+        ("leave", Some("ban")) => Ok(()),
+        ("leave", _) if sender_level < required_level(power_levels, "kick") || sender_level <= target_level => {
+            Err("You do not have permission to kick this user")
+        }
+        ("leave", _) => Ok(()),
+        ("ban", _) if sender_level < required_level(power_levels, "ban") || sender_level <= target_level => {
+            Err("You do not have permission to ban this user")
+        }
+        ("ban", _) => Ok(()),
+        _ => Err("The membership of other users can only be changed to invite, leave or ban"),
+    }
+}
+
+/// Power level of `user_id` under `power_levels`, or under the spec
+/// defaults when the room has none: 100 for its creator, 0 for others
+fn level_in(power_levels: &Value, creator: Option<&str>, user_id: &str) -> i64 {
+    if power_levels.is_null() {
+        return if creator == Some(user_id) { 100 } else { 0 };
+    }
+    power_levels["users"][user_id]
+        .as_i64()
+        .unwrap_or_else(|| power_levels["users_default"].as_i64().unwrap_or(0))
+}
+
+/// Check a change of the power levels from `old` to `new`: levels that are
+/// added, changed or removed may be no higher than the sender's own, and
+/// apart from their own, users at or above the sender's level keep theirs
+fn check_power_levels_change(old: &Value, new: &Value, sender: &str, sender_level: i64) -> std::result::Result<(), &'static str> {
+    let above_sender = |level: &Value| level.as_i64().is_some_and(|level| level > sender_level);
+    for key in ["users_default", "events_default", "state_default", "ban", "redact", "kick", "invite"] {
+        if old[key] != new[key] && (above_sender(&old[key]) || above_sender(&new[key])) {
+            return Err("Power levels above the sender's own cannot be changed");
+        }
+    }
+
+    let keys = |levels: &Value| levels.as_object().map(|levels| levels.keys().cloned().collect::<Vec<_>>()).unwrap_or_default();
+    let mut event_types = keys(&old["events"]);
+    event_types.extend(keys(&new["events"]));
+    for event_type in &event_types {
+        let (before, after) = (&old["events"][event_type], &new["events"][event_type]);
+        if before != after && (above_sender(before) || above_sender(after)) {
+            return Err("Power levels above the sender's own cannot be changed");
+        }
+    }
+
+    let mut users = keys(&old["users"]);
+    users.extend(keys(&new["users"]));
+    for user_id in &users {
+        let (before, after) = (&old["users"][user_id], &new["users"][user_id]);
+        if before == after {
+            continue;
+        }
+        if user_id != sender && before.as_i64().is_some_and(|level| level >= sender_level) {
+            return Err("The power level of users at or above the sender's level cannot be changed");
+        }
+        if above_sender(after) {
+            return Err("Nobody can be given a power level above the sender's own");
+        }
+    }
+    Ok(())
+}
+
+/// Current membership of `user_id` in a room of `timeline`
+pub fn membership_in(timeline: &timeline::Service, room_id: &str, user_id: &str) -> Option<String> {
+    timeline
+        .state_event(room_id, "m.room.member", user_id)
+        .and_then(|event| event["content"]["membership"].as_str().map(str::to_owned))
+}
+
+/// Whether `user_id` is joined to a room and may invite others, which
+/// makes them eligible to authorise restricted joins
+pub fn can_authorise_joins(timeline: &timeline::Service, room_id: &str, user_id: &str) -> bool {
+    if membership_in(timeline, room_id, user_id).as_deref() != Some("join") {
+        return false;
+    }
+    let power_levels = timeline
+        .state_event(room_id, "m.room.power_levels", "")
+        .map(|event| event["content"].clone())
+        .unwrap_or(Value::Null);
+    power_level(timeline, room_id, &power_levels, user_id) >= required_level(&power_levels, "invite")
+}
+
+/// Whether `user_id` is joined to a room and may change its power levels,
+/// which makes them one of its admins
+pub fn is_room_admin(timeline: &timeline::Service, room_id: &str, user_id: &str) -> bool {
+    if membership_in(timeline, room_id, user_id).as_deref() != Some("join") {
+        return false;
+    }
+    let power_levels = timeline
+        .state_event(room_id, "m.room.power_levels", "")
+        .map(|event| event["content"].clone())
+        .unwrap_or(Value::Null);
+    let required = power_levels["events"]["m.room.power_levels"]
+        .as_i64()
+        .unwrap_or_else(|| power_levels["state_default"].as_i64().unwrap_or(50));
+    power_level(timeline, room_id, &power_levels, user_id) >= required
+}
+
+/// Whether a room version has a join rule: `knock` from version 7 on,
+/// `restricted` from version 8 on, `knock_restricted` from version 10 on,
+/// the others in all versions
+pub fn supports_join_rule(room_version: &str, join_rule: &str) -> bool {
+    let version: u32 = room_version.parse().unwrap_or(0);
+    match join_rule {
+        "knock" => version >= 7,
+        "restricted" => version >= 8,
+        "knock_restricted" => version >= 10,
+        _ => true,
+    }
+}
+
+/// For a room with a `restricted` or `knock_restricted` join rule, the
+/// rooms whose members may join it without an invite. `None` if the join
+/// rule is not restricted in the room's version.
+pub fn allowed_rooms(timeline: &timeline::Service, room_id: &str) -> Option<Vec<String>> {
+    let join_rules = timeline.state_event(room_id, "m.room.join_rules", "")?;
+    let join_rule = join_rules["content"]["join_rule"].as_str()?;
+    let room_version = timeline
+        .state_event(room_id, "m.room.create", "")
+        .and_then(|create| create["content"]["room_version"].as_str().map(str::to_owned))
+        .unwrap_or_else(|| "1".to_owned());
+    if !matches!(join_rule, "restricted" | "knock_restricted") || !supports_join_rule(&room_version, join_rule) {
+        return None;
+    }
+    let allowed = join_rules["content"]["allow"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|condition| condition["type"] == "m.room_membership")
+        .filter_map(|condition| condition["room_id"].as_str().map(str::to_owned))
+        .collect();
+    Some(allowed)
+}
+
+/// Whether `user_id` is joined to one of the rooms of `allowed`
+pub fn in_allowed_room(timeline: &timeline::Service, allowed: &[String], user_id: &str) -> bool {
+    allowed.iter().any(|room_id| membership_in(timeline, room_id, user_id).as_deref() == Some("join"))
+}
+
+/// For a room with a restricted join rule, a user of `server` who can
+/// authorise `user_id` joining because `user_id` is in one of the rooms the
+/// join rule allows. The most powerful such user is chosen. `None` if the
+/// join rule does not let the user in this way.
+pub fn restricted_join_authoriser(timeline: &timeline::Service, room_id: &str, user_id: &str, server: &str) -> Option<String> {
+    let allowed = allowed_rooms(timeline, room_id)?;
+    if !in_allowed_room(timeline, &allowed, user_id) {
+        return None;
+    }
+
+    let power_levels = timeline
+        .state_event(room_id, "m.room.power_levels", "")
+        .map(|event| event["content"].clone())
+        .unwrap_or(Value::Null);
+    timeline
+        .current_state(room_id)
+        .iter()
+        .filter(|event| event["type"] == "m.room.member")
+        .filter_map(|event| event["state_key"].as_str())
+        .filter(|member| member.split_once(':').map(|(_, member_server)| member_server) == Some(server))
+        .filter(|member| can_authorise_joins(timeline, room_id, member))
+        .max_by_key(|member| power_level(timeline, room_id, &power_levels, member))
+        .map(str::to_owned)
+}
+
+fn join_room_in(timeline: &timeline::Service, room_id: &str, user_id: &str) -> Result<String> {
+    if !timeline.room_exists(room_id) {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+    }
+
+    let join_rule = timeline
+        .state_event(room_id, "m.room.join_rules", "")
+        .and_then(|event| event["content"]["join_rule"].as_str().map(str::to_owned))
+        .unwrap_or_else(|| "invite".to_owned());
+
+    let mut content = json!({ "membership": "join" });
+    match membership_in(timeline, room_id, user_id).as_deref() {
+        Some("ban") => return Err(Error::BadRequest(ErrorKind::forbidden(), "You are banned from this room")),
+        Some("join") | Some("invite") => {}
+        _ if join_rule == "public" => {}
+        _ => {
+            let Some(allowed) = allowed_rooms(timeline, room_id) else {
+                return Err(Error::BadRequest(ErrorKind::forbidden(), "You are not invited to this room"));
+            };
+            if !in_allowed_room(timeline, &allowed, user_id) {
+                return Err(Error::BadRequest(ErrorKind::forbidden(), "You are not in a room that gives access to this room"));
+            }
+            // The room is hosted here, so the authorising user is one of ours
+            let own_server = user_id.split_once(':').map(|(_, server)| server).unwrap_or_default();
+            let authoriser = restricted_join_authoriser(timeline, room_id, user_id, own_server).ok_or(Error::BadRequest(
+                ErrorKind::UnableToAuthorizeJoin,
+                "No user of this server can authorise joining this room",
+            ))?;
+            content["join_authorised_via_users_server"] = json!(authoriser);
+        }
+    }
+
+    Ok(timeline.append_event(room_id, user_id, "m.room.member", Some(user_id), content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOM: &str = "!room:matrixon.local";
+
+    fn room_with_join_rule(join_rule: &str) -> timeline::Service {
+        let timeline = timeline::Service::new();
+        timeline.append_event(ROOM, "@owner:matrixon.local", "m.room.create", Some(""), json!({ "room_version": "10" }));
+        timeline.append_event(ROOM, "@owner:matrixon.local", "m.room.join_rules", Some(""), json!({ "join_rule": join_rule }));
+        timeline
+    }
+
+    #[test]
+    fn test_join_rules_are_honoured() {
+        let public = room_with_join_rule("public");
+        join_room_in(&public, ROOM, "@alice:matrixon.local").unwrap();
+        assert_eq!(membership_in(&public, ROOM, "@alice:matrixon.local").as_deref(), Some("join"));
+
+        let private = room_with_join_rule("invite");
+        assert!(join_room_in(&private, ROOM, "@alice:matrixon.local").is_err());
+        private.append_event(ROOM, "@owner:matrixon.local", "m.room.member", Some("@alice:matrixon.local"), json!({ "membership": "invite" }));
+        assert!(join_room_in(&private, ROOM, "@alice:matrixon.local").is_ok());
+    }
+
+    #[test]
+    fn test_kicks_and_bans_need_power() {
+        const OWNER: &str = "@owner:matrixon.local";
+        const ALICE: &str = "@alice:matrixon.local";
+        const BOB: &str = "@bob:matrixon.local";
+        let timeline = room_with_join_rule("public");
+        timeline.append_event(ROOM, OWNER, "m.room.member", Some(OWNER), json!({ "membership": "join" }));
+        timeline.append_event(ROOM, OWNER, "m.room.power_levels", Some(""), json!({ "users": { OWNER: 100 } }));
+        join_room_in(&timeline, ROOM, ALICE).unwrap();
+        join_room_in(&timeline, ROOM, BOB).unwrap();
+
+        assert!(change_membership_in(&timeline, ROOM, ALICE, BOB, Change::Kick, None).is_err());
+        assert!(change_membership_in(&timeline, ROOM, ALICE, OWNER, Change::Ban, None).is_err());
+
+        change_membership_in(&timeline, ROOM, OWNER, BOB, Change::Ban, Some("spam")).unwrap();
+        assert_eq!(membership_in(&timeline, ROOM, BOB).as_deref(), Some("ban"));
+        assert!(join_room_in(&timeline, ROOM, BOB).is_err());
+        assert!(change_membership_in(&timeline, ROOM, OWNER, BOB, Change::Invite, None).is_err());
+
+        change_membership_in(&timeline, ROOM, OWNER, BOB, Change::Unban, None).unwrap();
+        assert_eq!(membership_in(&timeline, ROOM, BOB).as_deref(), Some("leave"));
+
+        change_membership_in(&timeline, ROOM, OWNER, ALICE, Change::Kick, None).unwrap();
+        assert!(change_membership_in(&timeline, ROOM, ALICE, ALICE, Change::Leave, None).is_err());
+    }
+
+    #[test]
+    fn test_state_events_need_membership_and_power() {
+        const OWNER: &str = "@owner:matrixon.local";
+        const ALICE: &str = "@alice:matrixon.local";
+        const MOD: &str = "@mod:matrixon.local";
+        let timeline = room_with_join_rule("public");
+        timeline.append_event(ROOM, OWNER, "m.room.member", Some(OWNER), json!({ "membership": "join" }));
+        timeline.append_event(ROOM, OWNER, "m.room.power_levels", Some(""), json!({ "users": { OWNER: 100, MOD: 50 } }));
+        let topic = json!({ "topic": "hijacked" });
+
+        // Neither a non-member nor a member at level 0 may set state
+        assert!(check_state_event(&timeline, ROOM, ALICE, "m.room.topic", "", &topic).is_err());
+        join_room_in(&timeline, ROOM, ALICE).unwrap();
+        assert!(check_state_event(&timeline, ROOM, ALICE, "m.room.topic", "", &topic).is_err());
+        let own_levels = json!({ "users": { OWNER: 100, MOD: 50, ALICE: 100 } });
+        assert!(check_state_event(&timeline, ROOM, ALICE, "m.room.power_levels", "", &own_levels).is_err());
+        assert!(check_state_event(&timeline, ROOM, ALICE, "m.room.member", ALICE, &json!({ "membership": "ban" })).is_err());
+        assert!(check_state_event(&timeline, ROOM, OWNER, "m.room.create", "", &json!({})).is_err());
+        check_state_event(&timeline, ROOM, OWNER, "m.room.topic", "", &topic).unwrap();
+
+        // Moderators cannot raise anyone above themselves or touch the owner
+        timeline.append_event(ROOM, MOD, "m.room.member", Some(MOD), json!({ "membership": "join" }));
+        timeline.append_event(
+            ROOM,
+            OWNER,
+            "m.room.power_levels",
+            Some(""),
+            json!({ "users": { OWNER: 100, MOD: 50 }, "events": { "m.room.power_levels": 50 } }),
+        );
+        let levels = |users: Value| json!({ "users": users, "events": { "m.room.power_levels": 50 } });
+        check_state_event(&timeline, ROOM, MOD, "m.room.power_levels", "", &levels(json!({ OWNER: 100, MOD: 50, ALICE: 50 })))
+            .unwrap();
+        assert!(check_state_event(&timeline, ROOM, MOD, "m.room.power_levels", "", &levels(json!({ OWNER: 100, MOD: 51 }))).is_err());
+        assert!(check_state_event(&timeline, ROOM, MOD, "m.room.power_levels", "", &levels(json!({ OWNER: 0, MOD: 50 }))).is_err());
+        assert!(check_state_event(&timeline, ROOM, MOD, "m.room.topic", OWNER, &topic).is_err());
+    }
+
+    #[test]
+    fn test_message_events_need_membership_and_power() {
+        const OWNER: &str = "@owner:matrixon.local";
+        const ALICE: &str = "@alice:matrixon.local";
+        let timeline = room_with_join_rule("public");
+        timeline.append_event(ROOM, OWNER, "m.room.member", Some(OWNER), json!({ "membership": "join" }));
+        timeline.append_event(
+            ROOM,
+            OWNER,
+            "m.room.power_levels",
+            Some(""),
+            json!({ "users": { OWNER: 100 }, "events": { "m.room.encrypted": 0, "m.reaction": 50 } }),
+        );
+        let message = json!({ "msgtype": "m.text", "body": "hi" });
+
+        assert!(check_message_event(&timeline, "!unknown:matrixon.local", OWNER, "m.room.message", &message).is_err());
+        assert!(check_message_event(&timeline, ROOM, ALICE, "m.room.message", &message).is_err());
+        join_room_in(&timeline, ROOM, ALICE).unwrap();
+        check_message_event(&timeline, ROOM, ALICE, "m.room.message", &message).unwrap();
+        assert!(check_message_event(&timeline, ROOM, ALICE, "m.reaction", &json!({})).is_err());
+        check_message_event(&timeline, ROOM, OWNER, "m.reaction", &json!({})).unwrap();
+
+        timeline.append_event(ROOM, OWNER, "m.room.power_levels", Some(""), json!({ "users": { OWNER: 100 }, "events_default": 50 }));
+        assert!(check_message_event(&timeline, ROOM, ALICE, "m.room.message", &message).is_err());
+    }
+
+    #[test]
+    fn test_knocks_are_approved_by_invite() {
+        const OWNER: &str = "@owner:matrixon.local";
+        const ALICE: &str = "@alice:matrixon.local";
+        assert!(knock_in(&room_with_join_rule("invite"), ROOM, ALICE, None).is_err());
+
+        let timeline = room_with_join_rule("knock");
+        timeline.append_event(ROOM, OWNER, "m.room.member", Some(OWNER), json!({ "membership": "join" }));
+        knock_in(&timeline, ROOM, ALICE, Some("let me in")).unwrap();
+        assert_eq!(membership_in(&timeline, ROOM, ALICE).as_deref(), Some("knock"));
+        assert!(join_room_in(&timeline, ROOM, ALICE).is_err());
+
+        change_membership_in(&timeline, ROOM, OWNER, ALICE, Change::Invite, None).unwrap();
+        assert!(knock_in(&timeline, ROOM, ALICE, None).is_err());
+        join_room_in(&timeline, ROOM, ALICE).unwrap();
+    }
+
+    #[test]
+    fn test_restricted_joins_need_an_allowed_room() {
+        const OWNER: &str = "@owner:matrixon.local";
+        const ALICE: &str = "@alice:matrixon.local";
+        const SPACE: &str = "!space:matrixon.local";
+        let restricted_room = |room_version: &str| {
+            let timeline = timeline::Service::new();
+            timeline.append_event(ROOM, OWNER, "m.room.create", Some(""), json!({ "room_version": room_version }));
+            timeline.append_event(ROOM, OWNER, "m.room.member", Some(OWNER), json!({ "membership": "join" }));
+            timeline.append_event(ROOM, OWNER, "m.room.power_levels", Some(""), json!({ "users": { OWNER: 100 }, "invite": 50 }));
+            timeline.append_event(
+                ROOM,
+                OWNER,
+                "m.room.join_rules",
+                Some(""),
+                json!({ "join_rule": "restricted", "allow": [{ "type": "m.room_membership", "room_id": SPACE }] }),
+            );
+            timeline.append_event(SPACE, OWNER, "m.room.create", Some(""), json!({}));
+            timeline.append_event(SPACE, ALICE, "m.room.member", Some(ALICE), json!({ "membership": "join" }));
+            timeline
+        };
+
+        // Before version 8 the join rule does not exist and acts like `invite`
+        assert!(join_room_in(&restricted_room("7"), ROOM, ALICE).is_err());
+
+        let timeline = restricted_room("10");
+        assert!(join_room_in(&timeline, ROOM, "@bob:matrixon.local").is_err());
+        join_room_in(&timeline, ROOM, ALICE).unwrap();
+        let join = timeline.state_event(ROOM, "m.room.member", ALICE).unwrap();
+        assert_eq!(join["content"]["join_authorised_via_users_server"], OWNER);
+
+        // Nobody left who may invite
+        let timeline = restricted_room("10");
+        timeline.append_event(ROOM, OWNER, "m.room.power_levels", Some(""), json!({ "users": { OWNER: 100 }, "invite": 101 }));
+        assert!(matches!(
+            join_room_in(&timeline, ROOM, ALICE),
+            Err(Error::BadRequest(ErrorKind::UnableToAuthorizeJoin, _))
+        ));
+    }
+
+    #[test]
+    fn test_unknown_room_is_rejected() {
+        assert!(join_room_in(&timeline::Service::new(), ROOM, "@alice:matrixon.local").is_err());
+    }
+
+    #[test]
+    fn test_only_the_newest_remote_invites_are_kept() {
+        let service = Service::new();
+        let invite = |count| RemoteInvite { count, invite_state: Vec::new() };
+        for count in 0..MAX_REMOTE_INVITES_PER_USER as u64 + 5 {
+            service.add_remote_invite(&format!("!r{}:remote.example", count), "@alice:matrixon.local", invite(count));
+        }
+        service.add_remote_invite("!r:remote.example", "@bob:matrixon.local", invite(0));
+
+        let invites = service.remote_invites("@alice:matrixon.local");
+        assert_eq!(invites.len(), MAX_REMOTE_INVITES_PER_USER);
+        assert!(invites.iter().all(|(_, invite)| invite.count >= 5));
+        service.clear_remote_invites("@alice:matrixon.local");
+        assert!(service.remote_invites("@alice:matrixon.local").is_empty());
+        assert_eq!(service.remote_invites("@bob:matrixon.local").len(), 1);
+    }
+}
@@ -0,0 +1,257 @@
+// =============================================================================
+// Matrixon Matrix NextServer - NFT Avatars
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Verified NFT avatars. A user proves control of a wallet by signing a
+//   message naming their user id and the token (`personal_sign`), and the
+//   chain confirms the wallet holds the ERC-721 or ERC-1155 token. The token
+//   image is then fetched through its metadata, with `ipfs://` URIs resolved
+//   through the IPFS gateway, so it can be copied to the media repository;
+//   other metadata and image URLs may only point to public addresses.
+//   Chains are read through the `matrixon-web3` client and NFT contract
+//   binding of each configured JSON-RPC endpoint; signers are recovered
+//   locally.
+//
+// =============================================================================
+
+use std::{collections::HashMap, sync::RwLock};
+
+use base64::{engine::general_purpose, Engine as _};
+use matrixon_web3::{
+    client::Web3Client,
+    contracts::{ContractError, NftContract, NftStandard},
+    prelude::{Address, U256},
+};
+use ruma::api::client::error::ErrorKind;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::{config::NftAvatarConfig, service::ipfs, Error, Result};
+
+/// Custom profile field marking the avatar as a verified NFT
+pub const PROFILE_FIELD: &str = "io.matrixon.nft_avatar";
+
+/// Token standards of NFTs accepted as avatars
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenStandard {
+    #[default]
+    Erc721,
+    Erc1155,
+}
+
+impl From<TokenStandard> for NftStandard {
+    fn from(standard: TokenStandard) -> Self {
+        match standard {
+            TokenStandard::Erc721 => NftStandard::Erc721,
+            TokenStandard::Erc1155 => NftStandard::Erc1155,
+        }
+    }
+}
+
+/// Body of a request to use an NFT as avatar
+#[derive(Debug, Clone, Deserialize)]
+pub struct NftAvatarRequest {
+    pub chain_id: u64,
+    /// Contract address, `0x`-prefixed
+    pub contract: String,
+    /// Token id, decimal or `0x`-prefixed hex
+    pub token_id: String,
+    #[serde(default)]
+    pub standard: TokenStandard,
+    /// Wallet holding the token
+    pub address: String,
+    /// `personal_sign` signature of [`ownership_message`] by `address`
+    pub signature: String,
+}
+
+/// An NFT whose ownership was verified, with its image
+#[derive(Debug, Clone)]
+pub struct VerifiedNft {
+    pub image_uri: String,
+    pub content_type: String,
+    pub image: Vec<u8>,
+    pub name: Option<String>,
+}
+
+/// The message users sign to prove they control the wallet holding the NFT
+pub fn ownership_message(user_id: &str, request: &NftAvatarRequest) -> String {
+    format!(
+        "Use this NFT as my Matrix avatar\nUser: {}\nChain: {}\nContract: {}\nToken: {}",
+        user_id,
+        request.chain_id,
+        request.contract.to_ascii_lowercase(),
+        request.token_id
+    )
+}
+
+/// NFT avatar verification service
+#[derive(Debug, Default)]
+pub struct Service {
+    /// Client of each JSON-RPC endpoint in use
+    clients: RwLock<HashMap<String, Web3Client>>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check that `user_id` controls a wallet holding the requested token
+    /// and fetch the token image
+    pub async fn verify(
+        &self,
+        config: &NftAvatarConfig,
+        ipfs: &ipfs::Service,
+        ipfs_gateway: &str,
+        user_id: &str,
+        request: &NftAvatarRequest,
+    ) -> Result<VerifiedNft> {
+        let rpc_url = config
+            .rpc_url(request.chain_id)
+            .ok_or(Error::BadRequest(ErrorKind::InvalidParam, "This chain is not supported"))?;
+        let (Ok(contract), Ok(address), Some(token_id)) = (
+            request.contract.parse::<Address>(),
+            request.address.parse::<Address>(),
+            parse_token_id(&request.token_id),
+        ) else {
+            return Err(Error::BadRequest(ErrorKind::InvalidParam, "Invalid contract, address or token id"));
+        };
+        let signature = from_hex(&request.signature).ok_or(Error::BadRequest(ErrorKind::InvalidParam, "Invalid signature"))?;
+
+        let client = self.client(rpc_url)?;
+        let signer = client
+            .recover_personal_signer(&ownership_message(user_id, request), &signature)
+            .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid signature"))?;
+        if signer != address {
+            return Err(Error::BadRequest(ErrorKind::forbidden(), "The signature is not from the given address"));
+        }
+        let nft = NftContract::new(client.web3(), contract, request.standard.into()).map_err(contract_call_failed)?;
+        if !nft.holds(address, token_id).await.map_err(contract_call_failed)? {
+            return Err(Error::BadRequest(ErrorKind::forbidden(), "The address does not own this token"));
+        }
+
+        let metadata_uri = nft.metadata_uri(token_id).await.map_err(contract_call_failed)?;
+        let (_, metadata) = self.fetch(ipfs, ipfs_gateway, &metadata_uri, config.max_image_size()).await?;
+        let metadata: Value = serde_json::from_slice(&metadata)
+            .map_err(|_| Error::BadServerResponse("Invalid token metadata".to_owned()))?;
+        let image_uri = metadata["image"]
+            .as_str()
+            .or_else(|| metadata["image_url"].as_str())
+            .ok_or_else(|| Error::BadServerResponse("The token has no image".to_owned()))?
+            .to_owned();
+        let (content_type, image) = self.fetch(ipfs, ipfs_gateway, &image_uri, config.max_image_size()).await?;
+        let content_type = content_type
+            .filter(|content_type| content_type.starts_with("image/"))
+            .ok_or_else(|| Error::BadServerResponse("The token image is not an image".to_owned()))?;
+
+        info!("🖼️ {} verified NFT {:?}/{} on chain {}", user_id, contract, request.token_id, request.chain_id);
+        Ok(VerifiedNft {
+            image_uri,
+            content_type,
+            image,
+            name: metadata["name"].as_str().map(str::to_owned),
+        })
+    }
+
+    /// Client of the JSON-RPC endpoint at `rpc_url`
+    fn client(&self, rpc_url: &str) -> Result<Web3Client> {
+        if let Some(client) = self.clients.read().unwrap().get(rpc_url) {
+            return Ok(client.clone());
+        }
+        let client = Web3Client::connect(rpc_url).map_err(|e| {
+            warn!("❌ Could not create a JSON-RPC client of {}: {}", rpc_url, e);
+            Error::BadServerResponse("The chain could not be reached".to_owned())
+        })?;
+        self.clients.write().unwrap().insert(rpc_url.to_owned(), client.clone());
+        Ok(client)
+    }
+
+    /// Fetch an `http(s)://`, `ipfs://` or `data:` URI, returning its
+    /// content type and content
+    async fn fetch(&self, ipfs: &ipfs::Service, ipfs_gateway: &str, uri: &str, max_size: u64) -> Result<(Option<String>, Vec<u8>)> {
+        if let Some(data) = uri.strip_prefix("data:") {
+            return decode_data_uri(data).ok_or_else(|| Error::BadServerResponse("Invalid data URI".to_owned()));
+        }
+        ipfs.fetch(ipfs_gateway, uri, max_size).await
+    }
+}
+
+impl NftAvatarConfig {
+    pub fn rpc_url(&self, chain_id: u64) -> Option<&str> {
+        self.rpc_urls.get(&chain_id.to_string()).map(String::as_str)
+    }
+
+    pub fn max_image_size(&self) -> u64 {
+        self.max_image_size.unwrap_or(10 * 1024 * 1024)
+    }
+}
+
+/// Content type and content of a `data:` URI, without the scheme
+fn decode_data_uri(data: &str) -> Option<(Option<String>, Vec<u8>)> {
+    let (header, content) = data.split_once(',')?;
+    let mut parts = header.split(';');
+    let content_type = parts.next().filter(|content_type| !content_type.is_empty()).map(str::to_owned);
+    let content = if parts.any(|part| part == "base64") {
+        general_purpose::STANDARD.decode(content).ok()?
+    } else {
+        content.as_bytes().to_vec()
+    };
+    Some((content_type, content))
+}
+
+fn contract_call_failed(error: ContractError) -> Error {
+    warn!("❌ NFT contract call failed: {}", error);
+    Error::BadServerResponse("The contract call failed".to_owned())
+}
+
+/// Token id given in decimal or as `0x`-prefixed hex
+fn parse_token_id(token_id: &str) -> Option<U256> {
+    match token_id.strip_prefix("0x") {
+        Some(hex) if !hex.is_empty() => hex.parse().ok(),
+        Some(_) => None,
+        None if !token_id.is_empty() => U256::from_dec_str(token_id).ok(),
+        None => None,
+    }
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_ids() {
+        assert_eq!(parse_token_id("1000"), Some(U256::from(1000)));
+        assert_eq!(parse_token_id("0x3e8"), Some(U256::from(1000)));
+        assert!(parse_token_id("12a").is_none());
+        assert!(parse_token_id("0x").is_none());
+        assert!(parse_token_id("").is_none());
+        assert!(parse_token_id(&"9".repeat(80)).is_none());
+    }
+
+    #[test]
+    fn test_data_uris() {
+        let (content_type, content) = decode_data_uri("application/json;base64,eyJhIjoxfQ==").unwrap();
+        assert_eq!(content_type.as_deref(), Some("application/json"));
+        assert_eq!(content, br#"{"a":1}"#);
+    }
+}
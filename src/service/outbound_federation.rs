@@ -0,0 +1,210 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Outbound Federation
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Hands events created on this server to the federation sending queue,
+//   addressed to every other server with members in the room, or in rooms
+//   joined with partial state, every server the resident server listed as
+//   in it, since most members are not known yet. This covers
+//   messages as well as membership and profile updates, which are ordinary
+//   `m.room.member` events. Events are hashed and signed with the server
+//   key before they are queued.
+//   Typing notifications and read receipts of local users go out the same
+//   way as `m.typing` and `m.receipt` EDUs, which the sending queue
+//   coalesces while a destination is busy or backing off.
+//
+// =============================================================================
+
+use std::{
+    collections::BTreeSet,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+
+use tokio::sync::Notify;
+
+use crate::services;
+
+/// Stream count up to which local events were handed to the federation
+/// queue
+static FEDERATED: AtomicU64 = AtomicU64::new(0);
+/// Notified whenever `FEDERATED` advances
+static FEDERATED_ADVANCED: Notify = Notify::const_new();
+
+/// Servers with joined members in a room according to its `state`, besides
+/// `own_server`. Members who just left still receive their own leave event.
+pub fn destinations(state: &[Value], event: &Value, own_server: &str) -> BTreeSet<String> {
+    let joined = state
+        .iter()
+        .filter(|state_event| state_event["type"] == "m.room.member" && state_event["content"]["membership"] == "join")
+        .filter_map(|state_event| state_event["state_key"].as_str());
+    let affected = (event["type"] == "m.room.member").then(|| event["state_key"].as_str()).flatten();
+
+    joined
+        .chain(affected)
+        .filter_map(server_name)
+        .filter(|server| *server != own_server)
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Servers to send an event of `room_id` to: those with members in its
+/// state, and while its state is partial, the servers in the room
+/// according to the send_join response
+pub fn room_destinations(room_id: &str, event: &Value, own_server: &str) -> BTreeSet<String> {
+    let services = services();
+    let mut destinations = destinations(&services.timeline.current_state(room_id), event, own_server);
+    if let Some(room) = services.inbound_federation.partial_state().get(room_id) {
+        destinations.extend(room.servers.into_iter().filter(|server| server != own_server));
+    }
+    destinations
+}
+
+/// `m.typing` EDU of a local user
+pub fn typing_edu(room_id: &str, user_id: &str, typing: bool) -> Value {
+    json!({ "edu_type": "m.typing", "content": { "room_id": room_id, "user_id": user_id, "typing": typing } })
+}
+
+/// `m.receipt` EDU carrying one receipt of a local user
+pub fn receipt_edu(room_id: &str, receipt_type: &str, user_id: &str, receipt: &Value) -> Value {
+    json!({ "edu_type": "m.receipt", "content": { room_id: { receipt_type: { user_id: receipt } } } })
+}
+
+/// Queue an EDU about a room for every other server with members in it
+pub fn send_room_edu(room_id: &str, edu: Value) {
+    let services = services();
+    if !services.globals.config.allow_federation {
+        return;
+    }
+    let destinations = room_destinations(room_id, &Value::Null, &services.globals.config.server_name);
+    debug!("🌐 Sending {} for {} to {} servers", edu["edu_type"], room_id, destinations.len());
+    for destination in destinations {
+        services.sending.send_edu(&destination, edu.clone());
+    }
+}
+
+/// Server part of a user id
+fn server_name(user_id: &str) -> Option<&str> {
+    user_id.split_once(':').map(|(_, server)| server)
+}
+
+/// Forward local events to the federation queue forever, starting with
+/// events appended after this call
+pub async fn run() {
+    let timeline = &services().timeline;
+    let own_server = services().globals.config.server_name.clone();
+    let mut position = timeline.current_count();
+    info!("🌐 Federating events of {} from position {}", own_server, position);
+
+    loop {
+        // Events are appended under the timeline lock, so when nothing came
+        // after `position` every count up to `current` is handled
+        let current = timeline.current_count();
+        let batch = timeline.stream_since(position, 500);
+        if batch.is_empty() {
+            FEDERATED.fetch_max(current, Ordering::SeqCst);
+            FEDERATED_ADVANCED.notify_waiters();
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            continue;
+        }
+
+        for (count, event) in batch {
+            position = count;
+            federate(&event, &own_server);
+        }
+        FEDERATED.fetch_max(position, Ordering::SeqCst);
+        FEDERATED_ADVANCED.notify_waiters();
+    }
+}
+
+/// Wait up to `timeout` for [`run`] to hand the local events up to stream
+/// count `count` to the federation queue, returning whether it did
+pub async fn wait_until_federated(count: u64, timeout: Duration) -> bool {
+    let deadline = tokio::time::Instant::now() + timeout;
+    loop {
+        let advanced = FEDERATED_ADVANCED.notified();
+        if FEDERATED.load(Ordering::SeqCst) >= count {
+            return true;
+        }
+        if tokio::time::timeout_at(deadline, advanced).await.is_err() {
+            return FEDERATED.load(Ordering::SeqCst) >= count;
+        }
+    }
+}
+
+/// Sign a local event and queue it for every other server with members in
+/// its room; events of other servers are left alone
+pub fn federate(event: &Value, own_server: &str) {
+    let timeline = &services().timeline;
+    let is_local = event["sender"].as_str().and_then(server_name) == Some(own_server);
+    if !is_local {
+        return;
+    }
+    let room_id = event["room_id"].as_str().unwrap_or_default();
+    let destinations = room_destinations(room_id, event, own_server);
+    if destinations.is_empty() {
+        return;
+    }
+    let room_version = timeline
+        .state_event(room_id, "m.room.create", "")
+        .and_then(|create| create["content"]["room_version"].as_str().map(str::to_owned))
+        .unwrap_or_else(|| "1".to_owned());
+    let pdu = match services().server_keys.sign_pdu(&room_version, event) {
+        Ok(pdu) => pdu,
+        Err(e) => {
+            warn!("❌ Could not sign {} for federation: {}", event["event_id"], e);
+            return;
+        }
+    };
+    debug!("🌐 Federating {} to {} servers", event["event_id"], destinations.len());
+    services().sending.send_pdu(destinations.iter().map(String::as_str), pdu);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn member(user_id: &str, membership: &str) -> Value {
+        json!({ "type": "m.room.member", "state_key": user_id, "content": { "membership": membership } })
+    }
+
+    #[test]
+    fn test_destinations_are_remote_member_servers() {
+        let state = vec![
+            member("@alice:matrixon.local", "join"),
+            member("@bob:remote.example", "join"),
+            member("@carol:remote.example", "join"),
+            member("@dave:left.example", "leave"),
+            member("@erin:other.example:8448", "join"),
+        ];
+        let message = json!({ "type": "m.room.message", "sender": "@alice:matrixon.local" });
+        let servers: Vec<String> = destinations(&state, &message, "matrixon.local").into_iter().collect();
+        assert_eq!(servers, vec!["other.example:8448", "remote.example"]);
+
+        let kick = member("@dave:left.example", "leave");
+        assert!(destinations(&state, &kick, "matrixon.local").contains("left.example"));
+    }
+
+    #[test]
+    fn test_ephemeral_edus() {
+        let typing = typing_edu("!room:matrixon.local", "@alice:matrixon.local", true);
+        assert_eq!(typing["content"]["user_id"], "@alice:matrixon.local");
+        assert_eq!(typing["content"]["typing"], true);
+
+        let receipt = json!({ "event_ids": ["$event"], "data": { "ts": 1 } });
+        let edu = receipt_edu("!room:matrixon.local", "m.read", "@alice:matrixon.local", &receipt);
+        assert_eq!(edu["edu_type"], "m.receipt");
+        assert_eq!(edu["content"]["!room:matrixon.local"]["m.read"]["@alice:matrixon.local"], receipt);
+    }
+}
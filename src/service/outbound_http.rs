@@ -0,0 +1,148 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Outbound HTTP to Untrusted URLs
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Requests to URLs chosen by users or other servers, such as token
+//   metadata, webhooks and media origins. Such requests only go to public
+//   addresses: host names are resolved up front, by the resolver of the
+//   federation client so `[federation.dns]` applies to them too; loopback,
+//   private, shared (100.64.0.0/10), link-local and unique local addresses
+//   are dropped, and the client is pinned to the remaining ones, so a name
+//   cannot pass the check and then resolve elsewhere when connecting.
+//   Redirects are not followed, and bodies are read up to a size limit only.
+//
+// =============================================================================
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use matrixon_federation::dns::DnsResolver;
+use ruma::api::client::error::ErrorKind;
+
+use crate::{Error, Result};
+
+/// Whether `ip` is not a public address
+pub fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                // Shared address space (100.64.0.0/10) and 0.0.0.0/8
+                || (a == 100 && (b & 0xc0) == 64)
+                || a == 0
+        }
+        IpAddr::V6(ip) => {
+            let segment = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local (fc00::/7) and link-local (fe80::/10) addresses
+                || (segment & 0xfe00) == 0xfc00
+                || (segment & 0xffc0) == 0xfe80
+                || ip.to_ipv4_mapped().is_some_and(|ip| is_internal(IpAddr::V4(ip)))
+        }
+    }
+}
+
+/// Check that `url` is an HTTP(S) URL whose host is not an internal
+/// address. Host names are checked when they are resolved.
+pub fn check_url(url: &str) -> Result<url::Url> {
+    let invalid = |message| Error::BadRequest(ErrorKind::InvalidParam, message);
+    let url = url::Url::parse(url).map_err(|_| invalid("Not a valid URL"))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid("Only HTTP and HTTPS URLs are allowed"));
+    }
+    let internal = match url.host() {
+        None => return Err(invalid("The URL has no host")),
+        Some(url::Host::Domain(domain)) => domain == "localhost" || domain.ends_with(".localhost"),
+        Some(url::Host::Ipv4(ip)) => is_internal(IpAddr::V4(ip)),
+        Some(url::Host::Ipv6(ip)) => is_internal(IpAddr::V6(ip)),
+    };
+    if internal {
+        return Err(invalid("URLs of internal addresses are not allowed"));
+    }
+    Ok(url)
+}
+
+/// Resolver of the federation client; the system's before the services
+/// are initialized
+fn resolver() -> DnsResolver {
+    crate::SERVICES.get().map(|services| services.sending.dns().clone()).unwrap_or_default()
+}
+
+/// Client for one request to `url`: its host name is resolved here and
+/// pinned to its public addresses, and redirects are not followed
+pub async fn client_for(url: &url::Url, timeout: Duration) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder().redirect(reqwest::redirect::Policy::none()).timeout(timeout);
+    if let Some(url::Host::Domain(domain)) = url.host() {
+        let port = url.port_or_known_default().unwrap_or(443);
+        let addrs: Vec<SocketAddr> = resolver()
+            .lookup_host(domain, port)
+            .await
+            .map_err(|_| Error::BadServerResponse(format!("{} could not be resolved", domain)))?
+            .into_iter()
+            .filter(|addr| !is_internal(addr.ip()))
+            .collect();
+        if addrs.is_empty() {
+            return Err(Error::BadRequest(ErrorKind::InvalidParam, "URLs of internal addresses are not allowed"));
+        }
+        builder = builder.resolve_to_addrs(domain, &addrs);
+    }
+    builder.build().map_err(|e| Error::BadServerResponse(e.to_string()))
+}
+
+/// Read the body of `response`, failing once it exceeds `max_size` bytes
+pub async fn read_capped(mut response: reqwest::Response, max_size: u64) -> Result<Vec<u8>> {
+    let too_large = || Error::BadRequest(ErrorKind::TooLarge, "The content is too large");
+    if response.content_length().is_some_and(|length| length > max_size) {
+        return Err(too_large());
+    }
+    let mut body = Vec::new();
+    while let Some(chunk) = response
+        .chunk()
+        .await
+        .map_err(|_| Error::BadServerResponse("The content could not be fetched".to_owned()))?
+    {
+        if (body.len() + chunk.len()) as u64 > max_size {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_internal_addresses_are_refused() {
+        for ip in ["127.0.0.1", "10.1.2.3", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.1.2.3", "::1", "fd00::1", "fe80::1", "::ffff:10.0.0.1"] {
+            assert!(is_internal(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["1.1.1.1", "100.128.0.1", "2606:4700::1111"] {
+            assert!(!is_internal(ip.parse().unwrap()), "{}", ip);
+        }
+
+        assert!(check_url("https://example.org/image.png").is_ok());
+        assert!(check_url("http://[fd12::1]/").is_err());
+        assert!(check_url("http://169.254.169.254/latest/meta-data").is_err());
+        assert!(check_url("http://metadata.localhost/").is_err());
+        assert!(check_url("file:///etc/passwd").is_err());
+    }
+}
@@ -0,0 +1,199 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Outlier Events
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Events kept outside room timelines. Auth events received with the state
+//   of a room that are not part of its current state are stored here, so
+//   later events naming them as auth events can be authorized against
+//   them, and so are soft-failed events, which are kept but never shown to
+//   clients. An outlier that is appended to its room's timeline after all
+//   stops being one. Outliers are written through to PostgreSQL when there
+//   is a database, one write at a time so that a removal never overtakes
+//   the insert before it, and loaded back at startup. Old ones no event
+//   refers to are pruned.
+//
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+use matrixon_db::{OutlierRecord, OutlierRepo};
+use serde_json::Value;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+#[derive(Debug, Clone)]
+struct Outlier {
+    room_id: String,
+    event: Value,
+    /// Milliseconds since the epoch
+    received_at: u64,
+}
+
+/// A write to the outlier repository
+#[derive(Debug)]
+enum Write {
+    Insert { room_id: String, event_id: String, event: Value },
+    Delete { room_id: String, event_id: String },
+}
+
+/// Outlier service
+#[derive(Debug, Default)]
+pub struct Service {
+    /// Event id -> outlier
+    events: RwLock<HashMap<String, Outlier>>,
+    /// Writes for the task writing them to the repository, in order
+    writes: Option<mpsc::UnboundedSender<Write>>,
+    pruned: AtomicU64,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write outliers through to `repository`, from a task spawned now
+    pub fn with_repository(mut self, repository: OutlierRepo) -> Self {
+        let (writes, mut queued) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(write) = queued.recv().await {
+                match write {
+                    Write::Insert { room_id, event_id, event } => {
+                        if let Err(e) = repository.insert(&room_id, &event_id, &event).await {
+                            warn!("⚠️ Could not store the outlier {}: {}", event_id, e);
+                        }
+                    }
+                    Write::Delete { room_id, event_id } => {
+                        if let Err(e) = repository.delete(&room_id, &event_id).await {
+                            warn!("⚠️ Could not delete the outlier {}: {}", event_id, e);
+                        }
+                    }
+                }
+            }
+        });
+        self.writes = Some(writes);
+        self
+    }
+
+    /// Put back outliers loaded from the repository, without storing them
+    /// again
+    pub fn restore(&self, outliers: impl IntoIterator<Item = OutlierRecord>) {
+        let mut events = self.events.write().unwrap();
+        for outlier in outliers {
+            let received_at = outlier.received_at.timestamp_millis().max(0) as u64;
+            events.insert(outlier.event_id, Outlier { room_id: outlier.room_id, event: outlier.json, received_at });
+        }
+    }
+
+    fn write(&self, write: Write) {
+        if let Some(writes) = &self.writes {
+            let _ = writes.send(write);
+        }
+    }
+
+    /// Keep `event` as an outlier of `room_id`; keeping it again has no
+    /// effect
+    pub fn add_pdu_outlier(&self, room_id: &str, event_id: &str, event: Value, now_ms: u64) {
+        let mut events = self.events.write().unwrap();
+        if events.contains_key(event_id) {
+            return;
+        }
+        self.write(Write::Insert { room_id: room_id.to_owned(), event_id: event_id.to_owned(), event: event.clone() });
+        events.insert(event_id.to_owned(), Outlier { room_id: room_id.to_owned(), event, received_at: now_ms });
+    }
+
+    pub fn get_outlier_pdu(&self, event_id: &str) -> Option<Value> {
+        self.events.read().unwrap().get(event_id).map(|outlier| outlier.event.clone())
+    }
+
+    pub fn is_outlier(&self, event_id: &str) -> bool {
+        self.events.read().unwrap().contains_key(event_id)
+    }
+
+    /// Stop keeping `event_id` as an outlier, e.g. once it is appended to
+    /// the timeline
+    pub fn remove(&self, event_id: &str) -> Option<Value> {
+        let mut events = self.events.write().unwrap();
+        let outlier = events.remove(event_id)?;
+        // Queued under the lock, after the insert of the outlier
+        self.write(Write::Delete { room_id: outlier.room_id, event_id: event_id.to_owned() });
+        Some(outlier.event)
+    }
+
+    /// Drop the outliers of a room, returning how many there were
+    pub fn delete_room(&self, room_id: &str) -> usize {
+        let mut events = self.events.write().unwrap();
+        let before = events.len();
+        events.retain(|_, outlier| outlier.room_id != room_id);
+        before - events.len()
+    }
+
+    /// Drop the outliers received before `before_ms`, in milliseconds since
+    /// the epoch, that `referenced` does not report as referenced by their
+    /// room. Returns the rooms and ids of the dropped outliers.
+    pub fn prune(&self, before_ms: u64, referenced: impl Fn(&str, &str) -> bool) -> Vec<(String, String)> {
+        let mut events = self.events.write().unwrap();
+        let pruned: Vec<(String, String)> = events
+            .iter()
+            .filter(|(event_id, outlier)| outlier.received_at < before_ms && !referenced(&outlier.room_id, event_id))
+            .map(|(event_id, outlier)| (outlier.room_id.clone(), event_id.clone()))
+            .collect();
+        for (_, event_id) in &pruned {
+            events.remove(event_id);
+        }
+        self.pruned.fetch_add(pruned.len() as u64, Ordering::Relaxed);
+        pruned
+    }
+
+    /// Outliers kept in memory
+    pub fn len(&self) -> usize {
+        self.events.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Outliers pruned so far
+    pub fn pruned(&self) -> u64 {
+        self.pruned.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_outliers_are_kept_until_unreferenced_and_old() {
+        const ROOM: &str = "!room:remote.example";
+        let service = Service::new();
+        service.add_pdu_outlier(ROOM, "$old", json!({ "type": "m.room.member" }), 1_000);
+        service.add_pdu_outlier(ROOM, "$old", json!({ "type": "ignored" }), 1_000);
+        service.add_pdu_outlier(ROOM, "$auth", json!({ "type": "m.room.power_levels" }), 1_000);
+        service.add_pdu_outlier(ROOM, "$new", json!({ "type": "m.room.message" }), 5_000);
+        assert_eq!(service.get_outlier_pdu("$old").unwrap()["type"], "m.room.member");
+
+        let pruned = service.prune(2_000, |room_id, event_id| room_id == ROOM && event_id == "$auth");
+        assert_eq!(pruned, [(ROOM.to_owned(), "$old".to_owned())]);
+        assert!(service.is_outlier("$auth") && service.is_outlier("$new"));
+        assert_eq!(service.pruned(), 1);
+
+        assert!(service.remove("$new").is_some());
+        assert_eq!(service.delete_room(ROOM), 1);
+        assert!(service.is_empty());
+    }
+}
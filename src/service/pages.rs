@@ -0,0 +1,455 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Server-Rendered Pages
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   The HTML pages the server serves to browsers rather than to Matrix
+//   clients: email address confirmation and consent to the server's
+//   policy. Every page is a body template
+//   wrapped in a shared layout carrying the configured theme; both can be
+//   replaced from a template directory. Forms posted back by these pages
+//   are protected against cross-site request forgery with a double-submit
+//   cookie: the page sets a random nonce as a cookie and embeds a token
+//   signed over that nonce, and a post is only accepted when the two match.
+//   The text of the built-in templates comes from the localized messages in
+//   `MESSAGES`, available to templates as variables named by message id.
+//   Consent links carry the user id signed with the configured form secret,
+//   so they can be handed out in notices and emails.
+//
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{Html, IntoResponse, Response},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use hmac::{Hmac, Mac};
+use matrixon_common::{i18n::Catalog, template};
+use rand::RngCore;
+use ruma::api::client::error::ErrorKind;
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tracing::warn;
+
+use crate::{
+    config::{PageTheme, PagesConfig},
+    Error, Result,
+};
+
+/// Cookie carrying the CSRF nonce of a form
+pub const CSRF_COOKIE: &str = "matrixon_csrf";
+
+/// How long a rendered form can be posted
+pub const CSRF_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// English messages of the built-in templates, in the Fluent syntax. Errors
+/// are shown with their own message in English; translations of
+/// `error-<errcode>` replace it in other locales.
+pub const MESSAGES: &str = "\
+page-continue = Continue
+page-confirm = Confirm
+page-consent-title = Terms and conditions
+page-consent-accept = I have read and agree to the { $policy_name }
+page-consent-given-title = Terms accepted
+page-consent-given-text = You have agreed to the { $policy_name }. You can close this page and return to your application.
+page-threepid-confirm-title = Confirm your email address
+page-threepid-confirm-intro = Confirm that this email address belongs to you to finish adding it to your account on { $server_name }.
+page-threepid-confirmed-title = Email address confirmed
+page-threepid-confirmed-text = Your email address is confirmed. You can close this page and return to your application.
+page-error-title = Something went wrong
+page-error-internal = The request could not be completed, please try again later.
+error-m-forbidden = You are not allowed to do this.
+error-m-not-found = This page does not exist.
+error-m-threepid-auth-failed = This confirmation link is invalid or was already used.
+error-m-limit-exceeded = Too many attempts, please try again later.
+";
+
+/// Pages the server renders
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Page {
+    /// Accept the server's policy
+    Consent,
+    /// The user has accepted the current policy
+    ConsentGiven,
+    /// Confirm an email address from the link in a validation email
+    ThreepidConfirm,
+    /// The email address was confirmed
+    ThreepidConfirmed,
+    /// Something went wrong, with `message` explaining what
+    Error,
+}
+
+const PAGES: [Page; 5] = [Page::Consent, Page::ConsentGiven, Page::ThreepidConfirm, Page::ThreepidConfirmed, Page::Error];
+
+impl Page {
+    /// File name of the template, without the `.html` extension
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Page::Consent => "consent",
+            Page::ConsentGiven => "consent_given",
+            Page::ThreepidConfirm => "threepid_confirm",
+            Page::ThreepidConfirmed => "threepid_confirmed",
+            Page::Error => "error",
+        }
+    }
+
+    /// Message id of the page title
+    fn title_id(self) -> String {
+        format!("page-{}-title", self.as_str().replace('_', "-"))
+    }
+
+    fn builtin(self) -> &'static str {
+        match self {
+            Page::Consent => {
+                "{{{ policy_html }}}\n\
+                 <form method=\"post\" action=\"{{ action }}\">\n\
+                 <input type=\"hidden\" name=\"csrf_token\" value=\"{{ csrf_token }}\">\n\
+                 <input type=\"hidden\" name=\"u\" value=\"{{ user_id }}\">\n\
+                 <input type=\"hidden\" name=\"h\" value=\"{{ user_hash }}\">\n\
+                 <input type=\"hidden\" name=\"v\" value=\"{{ policy_version }}\">\n\
+                 <label><input type=\"checkbox\" name=\"accept\" required> {{ page-consent-accept }}</label>\n\
+                 <button type=\"submit\">{{ page-continue }}</button>\n\
+                 </form>\n"
+            }
+            Page::ThreepidConfirm => {
+                "<p>{{ page-threepid-confirm-intro }}</p>\n\
+                 <form method=\"post\" action=\"{{ action }}\">\n\
+                 <input type=\"hidden\" name=\"csrf_token\" value=\"{{ csrf_token }}\">\n\
+                 <input type=\"hidden\" name=\"sid\" value=\"{{ sid }}\">\n\
+                 <input type=\"hidden\" name=\"client_secret\" value=\"{{ client_secret }}\">\n\
+                 <input type=\"hidden\" name=\"token\" value=\"{{ token }}\">\n\
+                 <button type=\"submit\">{{ page-confirm }}</button>\n\
+                 </form>\n"
+            }
+            Page::ConsentGiven => "{{{ policy_html }}}\n<p>{{ page-consent-given-text }}</p>\n",
+            Page::ThreepidConfirmed => "<p>{{ page-threepid-confirmed-text }}</p>\n",
+            Page::Error => "<p>{{ message }}</p>\n",
+        }
+    }
+}
+
+const LAYOUT: &str = "<!DOCTYPE html>\n\
+<html lang=\"{{ locale }}\">\n\
+<head>\n\
+<meta charset=\"utf-8\">\n\
+<meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+<title>{{ title }} - {{ brand }}</title>\n\
+<style>{{{ style }}}</style>\n\
+</head>\n\
+<body>\n\
+<main>\n\
+<h1>{{ title }}</h1>\n\
+{{{ body }}}\
+</main>\n\
+<footer>{{ brand }}</footer>\n\
+</body>\n\
+</html>\n";
+
+const BASE_STYLE: &str = "body{margin:0;font-family:system-ui,sans-serif;line-height:1.5;background:var(--bg);color:var(--fg)}\
+main{max-width:32rem;margin:3rem auto;padding:0 1rem}\
+footer{text-align:center;font-size:.85rem;opacity:.6;margin:2rem 0}\
+button{display:block;width:100%;margin:.5rem 0;padding:.6rem;border:0;border-radius:.4rem;\
+background:var(--accent);color:#fff;font-size:1rem;cursor:pointer}\
+a{color:var(--accent)}";
+
+const LIGHT: &str = ":root{--bg:#ffffff;--fg:#17191c;--accent:#0b7d5c}";
+
+const DARK: &str = ":root{--bg:#15191e;--fg:#e6e8eb;--accent:#0dbd8b}";
+
+fn style(theme: PageTheme) -> String {
+    match theme {
+        PageTheme::Light => format!("{}{}", LIGHT, BASE_STYLE),
+        PageTheme::Dark => format!("{}{}", DARK, BASE_STYLE),
+        PageTheme::Auto => format!("{}@media (prefers-color-scheme: dark){{{}}}{}", LIGHT, DARK, BASE_STYLE),
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Value of the cookie `name` in a `Cookie` header
+fn cookie<'a>(cookies: &'a str, name: &str) -> Option<&'a str> {
+    cookies
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Server-rendered pages service
+#[derive(Debug)]
+pub struct Service {
+    templates: HashMap<Page, String>,
+    layout: String,
+    style: String,
+    brand: String,
+    catalog: Arc<Catalog>,
+    csrf_key: [u8; 32],
+}
+
+impl Service {
+    /// The built-in templates, with the files of the configured template
+    /// directory replacing them, taking their text from `catalog`
+    pub fn new(config: &PagesConfig, server_name: &str, catalog: Arc<Catalog>) -> Result<Self> {
+        let mut templates: HashMap<Page, String> = PAGES.into_iter().map(|page| (page, page.builtin().to_owned())).collect();
+        let mut layout = LAYOUT.to_owned();
+        if let Some(dir) = &config.template_dir {
+            let files = templates.iter_mut().map(|(page, template)| (page.as_str(), template));
+            for (name, template) in files.chain([("layout", &mut layout)]) {
+                let path = dir.join(format!("{}.html", name));
+                match fs::read_to_string(&path) {
+                    Ok(content) => *template = content,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(Error::BadConfig(format!("{}: {}", path.display(), e))),
+                }
+            }
+        }
+        // Surface syntax errors at startup rather than on the first visit
+        for (name, template) in templates.iter().map(|(page, template)| (page.as_str(), template)).chain([("layout", &layout)]) {
+            template::render(template, &json!({}), true)
+                .map_err(|e| Error::BadConfig(format!("Invalid {} page template: {}", name, e)))?;
+        }
+
+        let mut csrf_key = [0; 32];
+        rand::thread_rng().fill_bytes(&mut csrf_key);
+        Ok(Self {
+            templates,
+            layout,
+            style: style(config.theme),
+            brand: config.brand.clone().unwrap_or_else(|| server_name.to_owned()),
+            catalog,
+            csrf_key,
+        })
+    }
+
+    /// Render `page` in `locale` with the fields of `vars` inside the layout
+    pub fn render(&self, page: Page, locale: &str, vars: &Value) -> Result<String> {
+        let mut scope = self.catalog.messages(locale, vars);
+        scope.extend(vars.as_object().cloned().unwrap_or_default());
+        let scope = Value::Object(scope);
+        let body = template::render(&self.templates[&page], &scope, true)
+            .map_err(|e| Error::BadConfig(format!("Invalid {} page template: {}", page.as_str(), e)))?;
+        let mut layout_vars = scope;
+        if layout_vars.get("title").is_none() {
+            layout_vars["title"] = json!(self.catalog.format(locale, &page.title_id(), vars));
+        }
+        layout_vars["locale"] = json!(locale);
+        layout_vars["brand"] = json!(self.brand);
+        layout_vars["style"] = json!(self.style);
+        layout_vars["body"] = json!(body);
+        template::render(&self.layout, &layout_vars, true)
+            .map_err(|e| Error::BadConfig(format!("Invalid layout page template: {}", e)))
+    }
+
+    /// HTTP response with `page`. A CSRF `nonce` from [`Service::csrf`] is
+    /// set as the cookie the posted form is checked against.
+    pub fn response(&self, status: StatusCode, page: Page, locale: &str, vars: &Value, nonce: Option<&str>) -> Response {
+        let html = match self.render(page, locale, vars) {
+            Ok(html) => html,
+            Err(e) => {
+                warn!("⚠️ Could not render the {} page: {}", page.as_str(), e);
+                return (StatusCode::INTERNAL_SERVER_ERROR, "The page could not be rendered").into_response();
+            }
+        };
+        let mut response = (status, Html(html)).into_response();
+        let headers = response.headers_mut();
+        headers.insert(
+            header::CONTENT_SECURITY_POLICY,
+            HeaderValue::from_static("default-src 'none'; style-src 'unsafe-inline'; img-src https: data:; form-action 'self'; frame-ancestors 'none'"),
+        );
+        headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        if let Some(nonce) = nonce {
+            let cookie = format!(
+                "{}={}; Path=/; HttpOnly; Secure; SameSite=Strict; Max-Age={}",
+                CSRF_COOKIE,
+                nonce,
+                CSRF_TTL.as_secs()
+            );
+            if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+                headers.insert(header::SET_COOKIE, cookie);
+            }
+        }
+        response
+    }
+
+    /// The error page explaining `error` in `locale`. Client errors show
+    /// their message, or the translation of their error code; internal ones
+    /// are not exposed.
+    pub fn error_response(&self, error: &Error, locale: &str) -> Response {
+        let message = match error {
+            Error::BadRequest(kind, message) => {
+                let id = format!("error-{}", kind.errcode().to_string().to_lowercase().replace('_', "-"));
+                match self.catalog.translates(locale, &id) {
+                    true => self.catalog.format(locale, &id, &json!({})),
+                    false => Some(message.to_string()),
+                }
+            }
+            _ => self.catalog.format(locale, "page-error-internal", &json!({})),
+        };
+        self.response(error.status_code(), Page::Error, locale, &json!({ "message": message }), None)
+    }
+
+    fn sign(&self, nonce: &str, expiry: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.csrf_key).expect("HMAC takes keys of any size");
+        mac.update(nonce.as_bytes());
+        mac.update(b".");
+        mac.update(expiry.to_string().as_bytes());
+        mac
+    }
+
+    /// A fresh CSRF nonce, to be set as cookie, and the token embedded in
+    /// the form as `csrf_token`
+    pub fn csrf(&self) -> (String, String) {
+        let mut nonce = [0; 16];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let nonce = URL_SAFE_NO_PAD.encode(nonce);
+        let expiry = now_secs() + CSRF_TTL.as_secs();
+        let signature = URL_SAFE_NO_PAD.encode(self.sign(&nonce, expiry).finalize().into_bytes());
+        (nonce, format!("{}.{}", expiry, signature))
+    }
+
+    /// Signature of `user_id` in consent links, made with the configured
+    /// form secret
+    pub fn consent_hash(form_secret: &str, user_id: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(form_secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(user_id.as_bytes());
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    }
+
+    /// Check the signature `hash` of `user_id` from a consent link
+    pub fn verify_consent_hash(form_secret: &str, user_id: &str, hash: &str) -> Result<()> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(form_secret.as_bytes()).expect("HMAC takes keys of any size");
+        mac.update(user_id.as_bytes());
+        let hash = URL_SAFE_NO_PAD.decode(hash).unwrap_or_default();
+        mac.verify_slice(&hash)
+            .map_err(|_| Error::BadRequest(ErrorKind::forbidden(), "This consent link is invalid"))
+    }
+
+    /// Check the `csrf_token` of a posted form against the cookie of the
+    /// request
+    pub fn verify_csrf(&self, headers: &HeaderMap, token: &str) -> Result<()> {
+        let invalid = || Error::BadRequest(ErrorKind::forbidden(), "This form has expired, please reload the page and try again");
+        let nonce = headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find_map(|cookies| cookie(cookies, CSRF_COOKIE))
+            .ok_or_else(invalid)?;
+        let (expiry, signature) = token.split_once('.').ok_or_else(invalid)?;
+        let expiry: u64 = expiry.parse().map_err(|_| invalid())?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+        if expiry < now_secs() || self.sign(nonce, expiry).verify_slice(&signature).is_err() {
+            return Err(invalid());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cookie_headers(nonce: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_str(&format!("theme=dark; {}={}", CSRF_COOKIE, nonce)).unwrap());
+        headers
+    }
+
+    fn catalog() -> Arc<Catalog> {
+        let mut catalog = Catalog::new("en");
+        catalog.add_resource("en", MESSAGES).unwrap();
+        catalog
+            .add_resource("fr", "page-error-title = Une erreur est survenue\nerror-m-forbidden = Action interdite.")
+            .unwrap();
+        Arc::new(catalog)
+    }
+
+    #[test]
+    fn test_render_in_layout() {
+        let pages = Service::new(&PagesConfig { theme: PageTheme::Auto, ..Default::default() }, "matrixon.local", catalog()).unwrap();
+        let html = pages
+            .render(Page::Error, "en", &json!({ "message": "<script>alert(1)</script>" }))
+            .unwrap();
+        assert!(html.contains("<title>Something went wrong - matrixon.local</title>"));
+        assert!(html.contains("<p>&lt;script&gt;alert(1)&lt;/script&gt;</p>"));
+        assert!(html.contains("prefers-color-scheme: dark"));
+    }
+
+    #[test]
+    fn test_template_dir_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("layout.html"), "[{{ brand }}] {{{ body }}}").unwrap();
+        fs::write(dir.path().join("threepid_confirmed.html"), "Done, {{ name }}").unwrap();
+        let config = PagesConfig {
+            template_dir: Some(dir.path().to_owned()),
+            brand: Some("Example".to_owned()),
+            ..Default::default()
+        };
+        let pages = Service::new(&config, "matrixon.local", catalog()).unwrap();
+        assert_eq!(pages.render(Page::ThreepidConfirmed, "en", &json!({ "name": "Alice" })).unwrap(), "[Example] Done, Alice");
+
+        fs::write(dir.path().join("consent.html"), "{{#if accept}}unclosed").unwrap();
+        assert!(Service::new(&config, "matrixon.local", catalog()).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_localized_error_page() {
+        async fn page(error: Error, locale: &str) -> String {
+            let pages = Service::new(&PagesConfig::default(), "matrixon.local", catalog()).unwrap();
+            let response = pages.error_response(&error, locale);
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        }
+        let forbidden = || Error::BadRequest(ErrorKind::forbidden(), "This form has expired");
+
+        let html = page(forbidden(), "fr").await;
+        assert!(html.contains("<html lang=\"fr\">"));
+        assert!(html.contains("<h1>Une erreur est survenue</h1>"));
+        assert!(html.contains("<p>Action interdite.</p>"));
+        assert!(page(forbidden(), "en").await.contains("<p>This form has expired</p>"));
+        // Untranslated error codes keep their message
+        let not_found = Error::BadRequest(ErrorKind::NotFound, "Unknown session");
+        assert!(page(not_found, "fr").await.contains("<p>Unknown session</p>"));
+        let internal = page(Error::BadDatabase("disk on fire".to_owned()), "fr").await;
+        assert!(internal.contains("please try again later") && !internal.contains("disk on fire"));
+    }
+
+    #[test]
+    fn test_csrf_double_submit() {
+        let pages = Service::new(&PagesConfig::default(), "matrixon.local", catalog()).unwrap();
+        let (nonce, token) = pages.csrf();
+        pages.verify_csrf(&cookie_headers(&nonce), &token).unwrap();
+
+        let (other_nonce, other_token) = pages.csrf();
+        assert!(pages.verify_csrf(&cookie_headers(&other_nonce), &token).is_err());
+        assert!(pages.verify_csrf(&HeaderMap::new(), &other_token).is_err());
+        assert!(pages.verify_csrf(&cookie_headers(&nonce), "").is_err());
+
+        let expired = format!("1.{}", URL_SAFE_NO_PAD.encode(pages.sign(&nonce, 1).finalize().into_bytes()));
+        assert!(pages.verify_csrf(&cookie_headers(&nonce), &expired).is_err());
+    }
+
+    #[test]
+    fn test_consent_links_are_signed() {
+        let hash = Service::consent_hash("secret", "@alice:matrixon.local");
+        Service::verify_consent_hash("secret", "@alice:matrixon.local", &hash).unwrap();
+        assert!(Service::verify_consent_hash("secret", "@bob:matrixon.local", &hash).is_err());
+        assert!(Service::verify_consent_hash("other", "@alice:matrixon.local", &hash).is_err());
+        assert!(Service::verify_consent_hash("secret", "@alice:matrixon.local", "not base64!").is_err());
+    }
+}
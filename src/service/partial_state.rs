@@ -0,0 +1,254 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Partial State Rooms
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Rooms joined with partial state (MSC3706, "faster joins"). The resident
+//   server may leave the membership events out of its send_join response,
+//   so the join completes without fetching the member list of a large room.
+//   Until the full state at the join has been fetched in the background the
+//   room is served with the members known so far, events of senders whose
+//   membership is unknown are trusted if the sender belongs to one of the
+//   servers in the room, and operations that need the complete member list
+//   wait or are refused. Events accepted that way are checked again once
+//   the full state is known. Rooms are kept in a state file when one is
+//   configured, so the resync resumes after a restart.
+//
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use ruma::api::client::error::ErrorKind;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::{service::state_file::StateFile, Error, Result};
+
+/// How long requests needing the member list of a room wait for its full
+/// state before the client is asked to retry
+pub const FULL_STATE_WAIT: Duration = Duration::from_secs(30);
+
+/// A room whose full state is still being fetched
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartialRoom {
+    /// The join event the state was partial at
+    pub event_id: String,
+    /// Servers in the room according to the send_join response, asked for
+    /// the full state in turn
+    pub servers: Vec<String>,
+    pub joined_at: u64,
+    /// Failed attempts at fetching the full state
+    pub attempts: u32,
+    /// Events accepted although the membership of their sender was unknown
+    #[serde(default)]
+    pub lenient: Vec<String>,
+}
+
+/// Partial state service
+#[derive(Debug, Default)]
+pub struct Service {
+    rooms: RwLock<HashMap<String, PartialRoom>>,
+    /// Woken whenever a room gets its full state
+    resynced: Notify,
+    resyncs: AtomicU64,
+    state_file: Option<StateFile>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the rooms in the file at `path`, loading the ones stored there
+    pub fn with_state_file(mut self, path: Option<PathBuf>) -> Self {
+        if let Some(path) = path {
+            let state_file = StateFile::new(path);
+            if let Some(rooms) = state_file.load() {
+                self.rooms = RwLock::new(rooms);
+            }
+            self.state_file = Some(state_file);
+        }
+        self
+    }
+
+    /// Remember that `room_id` was joined with partial state by `event_id`
+    pub fn mark(&self, room_id: &str, event_id: &str, servers: Vec<String>) {
+        let joined_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let room = PartialRoom { event_id: event_id.to_owned(), servers, joined_at, attempts: 0, lenient: Vec::new() };
+        self.update(|rooms| {
+            rooms.insert(room_id.to_owned(), room);
+        });
+    }
+
+    pub fn is_partial(&self, room_id: &str) -> bool {
+        self.rooms.read().unwrap().contains_key(room_id)
+    }
+
+    pub fn get(&self, room_id: &str) -> Option<PartialRoom> {
+        self.rooms.read().unwrap().get(room_id).cloned()
+    }
+
+    /// Rooms still waiting for their full state
+    pub fn rooms(&self) -> Vec<(String, PartialRoom)> {
+        self.rooms.read().unwrap().iter().map(|(room_id, room)| (room_id.clone(), room.clone())).collect()
+    }
+
+    /// Whether `room_id` has partial state and `server` is one of the
+    /// servers in it, whose users' events are accepted without a known
+    /// membership
+    pub fn trusts(&self, room_id: &str, server: &str) -> bool {
+        self.rooms
+            .read()
+            .unwrap()
+            .get(room_id)
+            .is_some_and(|room| room.servers.iter().any(|known| known == server))
+    }
+
+    /// Remember that `event_id` was accepted without knowing whether its
+    /// sender is in `room_id`
+    pub fn accepted_leniently(&self, room_id: &str, event_id: &str) {
+        self.update(|rooms| {
+            if let Some(room) = rooms.get_mut(room_id) {
+                room.lenient.push(event_id.to_owned());
+            }
+        });
+    }
+
+    /// Count a failed attempt at fetching the full state of `room_id`,
+    /// returning the number of attempts so far
+    pub fn failed_attempt(&self, room_id: &str) -> u32 {
+        self.update(|rooms| {
+            rooms.get_mut(room_id).map_or(0, |room| {
+                room.attempts += 1;
+                room.attempts
+            })
+        })
+    }
+
+    /// The full state of `room_id` has been fetched. Returns the room, whose
+    /// leniently accepted events are to be checked again.
+    pub fn complete(&self, room_id: &str) -> Option<PartialRoom> {
+        let room = self.update(|rooms| rooms.remove(room_id))?;
+        self.resyncs.fetch_add(1, Ordering::Relaxed);
+        self.resynced.notify_waiters();
+        Some(room)
+    }
+
+    /// Stop tracking `room_id` without its full state, after the join failed
+    pub fn abandon(&self, room_id: &str) {
+        self.update(|rooms| {
+            rooms.remove(room_id);
+        });
+        self.resynced.notify_waiters();
+    }
+
+    /// Refuse an operation needing the full state of `room_id` while it is
+    /// still being fetched
+    pub fn ensure_full_state(&self, room_id: &str) -> Result<()> {
+        if self.is_partial(room_id) {
+            return Err(Error::BadRequest(
+                ErrorKind::Unknown,
+                "The state of this room is still being fetched, retry the request",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for the full state of `room_id`
+    pub async fn wait_for_full_state(&self, room_id: &str, timeout: Duration) -> Result<()> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            let resynced = self.resynced.notified();
+            if !self.is_partial(room_id) {
+                return Ok(());
+            }
+            if tokio::time::timeout_at(deadline, resynced).await.is_err() {
+                return self.ensure_full_state(room_id);
+            }
+        }
+    }
+
+    /// Partial state rooms in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP matrixon_partial_state_rooms Rooms whose full state is still being fetched");
+        let _ = writeln!(out, "# TYPE matrixon_partial_state_rooms gauge");
+        let _ = writeln!(out, "matrixon_partial_state_rooms {}", self.rooms.read().unwrap().len());
+        let _ = writeln!(out, "# HELP matrixon_partial_state_resyncs_total Rooms that got their full state after a partial join");
+        let _ = writeln!(out, "# TYPE matrixon_partial_state_resyncs_total counter");
+        let _ = writeln!(out, "matrixon_partial_state_resyncs_total {}", self.resyncs.load(Ordering::Relaxed));
+        out
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut HashMap<String, PartialRoom>) -> T) -> T {
+        let mut rooms = self.rooms.write().unwrap();
+        let result = f(&mut rooms);
+        let snapshot = self.state_file.as_ref().map(|state_file| (state_file, state_file.snapshot(&*rooms)));
+        drop(rooms);
+        if let Some((state_file, snapshot)) = snapshot {
+            state_file.write(snapshot);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_waiting_for_full_state() {
+        const ROOM: &str = "!big:remote.example";
+        let service = Arc::new(Service::new());
+        assert!(service.ensure_full_state(ROOM).is_ok());
+
+        service.mark(ROOM, "$join", vec!["remote.example".to_owned()]);
+        assert!(matches!(service.ensure_full_state(ROOM), Err(Error::BadRequest(ErrorKind::Unknown, _))));
+        assert!(service.wait_for_full_state(ROOM, Duration::from_millis(10)).await.is_err());
+        assert_eq!((service.failed_attempt(ROOM), service.failed_attempt(ROOM)), (1, 2));
+        assert_eq!(service.get(ROOM).unwrap().servers, ["remote.example"]);
+        assert!(service.trusts(ROOM, "remote.example"));
+        assert!(!service.trusts(ROOM, "elsewhere.example"));
+
+        let resync = service.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            resync.complete(ROOM);
+        });
+        service.wait_for_full_state(ROOM, Duration::from_secs(5)).await.unwrap();
+        assert!(service.rooms().is_empty());
+        assert!(!service.trusts(ROOM, "remote.example"));
+        assert!(service.render().contains("matrixon_partial_state_resyncs_total 1\n"));
+    }
+
+    #[test]
+    fn test_partial_rooms_survive_a_restart() {
+        const ROOM: &str = "!big:remote.example";
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("partial_state.json");
+        let service = Service::new().with_state_file(Some(path.clone()));
+        service.mark(ROOM, "$join", vec!["remote.example".to_owned()]);
+        service.accepted_leniently(ROOM, "$message");
+
+        let restarted = Service::new().with_state_file(Some(path));
+        assert!(restarted.trusts(ROOM, "remote.example"));
+        assert_eq!(restarted.complete(ROOM).unwrap().lenient, ["$message"]);
+        assert!(restarted.complete(ROOM).is_none());
+    }
+}
@@ -0,0 +1,201 @@
+// =============================================================================
+// Matrixon Matrix NextServer - PDU Metadata
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   What is known about PDUs beyond their content. Every event appended to
+//   a room marks the outliers it names as prev and auth events as
+//   referenced, so outliers other events still depend on are kept by
+//   cleanup; references to anything else are not needed and not kept, and
+//   they are forgotten once their event is no longer an outlier. Events
+//   that passed authorization against their auth events but not against
+//   the current room state are marked as soft-failed with the reason: they
+//   are stored, but never shown to clients. Marks are written through to
+//   PostgreSQL when there is a database, and loaded back at startup.
+//
+// =============================================================================
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+use matrixon_db::{PduMetadataRepo, SoftFailureRecord};
+use serde_json::Value;
+use tracing::warn;
+
+/// PDU metadata service
+#[derive(Debug, Default)]
+pub struct Service {
+    /// (room, event) pairs some event of the room references
+    referenced: RwLock<HashSet<(String, String)>>,
+    /// Soft-failed event -> (room, reason)
+    soft_failed: RwLock<HashMap<String, (String, &'static str)>>,
+    repository: Option<PduMetadataRepo>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write marks through to `repository`
+    pub fn with_repository(mut self, repository: PduMetadataRepo) -> Self {
+        self.repository = Some(repository);
+        self
+    }
+
+    /// Mark the prev and auth events of `event` that `is_outlier` as
+    /// referenced
+    pub fn mark_as_referenced(&self, room_id: &str, event: &Value, is_outlier: impl Fn(&str) -> bool) {
+        let event_ids: Vec<String> = ["prev_events", "auth_events"]
+            .into_iter()
+            .flat_map(|field| referenced_event_ids(event, field))
+            .filter(|event_id| is_outlier(event_id))
+            .map(str::to_owned)
+            .collect();
+        if event_ids.is_empty() {
+            return;
+        }
+        {
+            let mut referenced = self.referenced.write().unwrap();
+            for event_id in &event_ids {
+                referenced.insert((room_id.to_owned(), event_id.clone()));
+            }
+        }
+        if let Some(repository) = self.repository.clone() {
+            let room_id = room_id.to_owned();
+            tokio::spawn(async move {
+                if let Err(e) = repository.mark_referenced(&room_id, &event_ids).await {
+                    warn!("⚠️ Could not store the references of an event of {}: {}", room_id, e);
+                }
+            });
+        }
+    }
+
+    /// Forget the references to events that are not `is_outlier` any
+    /// longer, returning how many were forgotten
+    pub fn retain_references(&self, is_outlier: impl Fn(&str) -> bool) -> usize {
+        let mut referenced = self.referenced.write().unwrap();
+        let before = referenced.len();
+        referenced.retain(|(_, event_id)| is_outlier(event_id));
+        before - referenced.len()
+    }
+
+    /// Put back references and soft failures loaded from the repository,
+    /// without storing them again
+    pub fn restore(&self, references: impl IntoIterator<Item = (String, String)>, soft_failed: impl IntoIterator<Item = SoftFailureRecord>) {
+        self.referenced.write().unwrap().extend(references);
+        let mut soft_failed_events = self.soft_failed.write().unwrap();
+        for soft_failure in soft_failed {
+            let reason = known_reason(&soft_failure.reason);
+            soft_failed_events.insert(soft_failure.event_id, (soft_failure.room_id, reason));
+        }
+    }
+
+    /// Whether an event of `room_id` names `event_id` as a prev or auth
+    /// event
+    pub fn is_event_referenced(&self, room_id: &str, event_id: &str) -> bool {
+        self.referenced.read().unwrap().contains(&(room_id.to_owned(), event_id.to_owned()))
+    }
+
+    /// Mark `event_id` as authorized by its auth events but not by the
+    /// current state of `room_id`
+    pub fn mark_event_soft_failed(&self, room_id: &str, event_id: &str, reason: &'static str) {
+        self.soft_failed.write().unwrap().insert(event_id.to_owned(), (room_id.to_owned(), reason));
+        if let Some(repository) = self.repository.clone() {
+            let (room_id, event_id) = (room_id.to_owned(), event_id.to_owned());
+            tokio::spawn(async move {
+                if let Err(e) = repository.mark_soft_failed(&room_id, &event_id, reason).await {
+                    warn!("⚠️ Could not store the soft failure of {}: {}", event_id, e);
+                }
+            });
+        }
+    }
+
+    pub fn is_event_soft_failed(&self, event_id: &str) -> bool {
+        self.soft_failed.read().unwrap().contains_key(event_id)
+    }
+
+    /// Why `event_id` was soft-failed, if it was
+    pub fn soft_fail_reason(&self, event_id: &str) -> Option<&'static str> {
+        self.soft_failed.read().unwrap().get(event_id).map(|(_, reason)| *reason)
+    }
+
+    /// Forget the soft failures of events that are no longer stored
+    pub fn forget_soft_failed(&self, event_ids: &[String]) {
+        let mut soft_failed = self.soft_failed.write().unwrap();
+        for event_id in event_ids {
+            soft_failed.remove(event_id);
+        }
+    }
+
+    /// Forget the references and soft failures of a room
+    pub fn delete_room(&self, room_id: &str) {
+        self.referenced.write().unwrap().retain(|(room, _)| room != room_id);
+        self.soft_failed.write().unwrap().retain(|_, (room, _)| room != room_id);
+    }
+
+    /// Events soft-failed and still stored
+    pub fn soft_failed_count(&self) -> usize {
+        self.soft_failed.read().unwrap().len()
+    }
+}
+
+/// The reason of a stored soft failure, among the reasons events are
+/// soft-failed for
+fn known_reason(reason: &str) -> &'static str {
+    ["banned", "restricted_join", "not_joined"].into_iter().find(|known| *known == reason).unwrap_or("unknown")
+}
+
+/// Ids of the events `event` names in `field`, `prev_events` or
+/// `auth_events`, which are plain ids from room version 3 on and
+/// `[id, hashes]` pairs before
+pub fn referenced_event_ids<'a>(event: &'a Value, field: &str) -> impl Iterator<Item = &'a str> {
+    event[field]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|reference| reference.as_str().or_else(|| reference[0].as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_references_and_soft_failures() {
+        const ROOM: &str = "!room:remote.example";
+        let service = Service::new();
+        let outlier = |event_id: &str| event_id != "$timeline";
+        service.mark_as_referenced(ROOM, &json!({ "prev_events": ["$prev"], "auth_events": ["$create", "$member", "$timeline"] }), outlier);
+        service.mark_as_referenced(ROOM, &json!({ "prev_events": [["$v1", { "sha256": "abc" }]] }), outlier);
+        assert!(["$prev", "$create", "$member", "$v1"].iter().all(|event_id| service.is_event_referenced(ROOM, event_id)));
+        assert!(!service.is_event_referenced(ROOM, "$timeline"));
+        assert!(!service.is_event_referenced("!other:remote.example", "$prev"));
+        assert_eq!(service.retain_references(|event_id| event_id != "$v1"), 1);
+        assert!(!service.is_event_referenced(ROOM, "$v1"));
+
+        service.mark_event_soft_failed(ROOM, "$kicked", "not_joined");
+        assert!(service.is_event_soft_failed("$kicked"));
+        assert_eq!(service.soft_fail_reason("$kicked"), Some("not_joined"));
+
+        service.delete_room(ROOM);
+        assert!(!service.is_event_referenced(ROOM, "$prev"));
+        assert_eq!(service.soft_failed_count(), 0);
+
+        service.restore(
+            [(ROOM.to_owned(), "$prev".to_owned())],
+            [SoftFailureRecord { event_id: "$kicked".to_owned(), room_id: ROOM.to_owned(), reason: "banned".to_owned() }],
+        );
+        assert!(service.is_event_referenced(ROOM, "$prev"));
+        assert_eq!(service.soft_fail_reason("$kicked"), Some("banned"));
+    }
+}
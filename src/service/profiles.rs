@@ -0,0 +1,205 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Profile Service
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Displaynames and avatar URLs of users. Profiles are persisted through
+//   matrixon-db when a PostgreSQL database is configured and kept in an LRU
+//   cache in front of it; changes are propagated as `m.room.member` updates to every room
+//   the user has joined. Custom profile fields, such as the verified NFT
+//   avatar marker, are kept in a state file when one is configured.
+//
+// =============================================================================
+
+use std::{collections::HashMap, path::PathBuf, sync::RwLock};
+
+use matrixon_db::{migrations, queries, Profile};
+use serde_json::{json, Map, Value};
+use sqlx::postgres::PgPool;
+use tokio::sync::OnceCell;
+use tracing::info;
+
+use crate::{
+    service::{
+        cache::{Cache, CacheStats},
+        membership, nft_avatar,
+        state_file::StateFile,
+    },
+    services, Error, Result,
+};
+
+/// Base number of cached profiles
+const PROFILE_CACHE_CAPACITY: usize = 10_000;
+
+/// Profile service
+#[derive(Debug)]
+pub struct Service {
+    cache: Cache<String, Profile>,
+    /// Every profile, when there is no database to store them in
+    memory: RwLock<HashMap<String, Profile>>,
+    fields: RwLock<HashMap<String, Map<String, Value>>>,
+    fields_file: Option<StateFile>,
+    pool: Option<PgPool>,
+    schema: OnceCell<()>,
+}
+
+impl Default for Service {
+    fn default() -> Self {
+        Self::build(None)
+    }
+}
+
+impl Service {
+    /// Profiles are stored in PostgreSQL when a database pool is given,
+    /// and only in memory otherwise
+    pub fn build(pool: Option<PgPool>) -> Self {
+        Self {
+            cache: Cache::new("profiles", PROFILE_CACHE_CAPACITY, 1.0),
+            memory: RwLock::default(),
+            fields: RwLock::default(),
+            fields_file: None,
+            pool,
+            schema: OnceCell::new(),
+        }
+    }
+
+    /// Scale the profile cache by `matrixon_cache_capacity_modifier`
+    pub fn with_cache_capacity_modifier(mut self, modifier: f64) -> Self {
+        self.cache = Cache::new("profiles", PROFILE_CACHE_CAPACITY, modifier);
+        self
+    }
+
+    /// Keep the custom profile fields in the file at `path`, loading the
+    /// ones stored there
+    pub fn with_fields_file(mut self, path: Option<PathBuf>) -> Self {
+        if let Some(path) = path {
+            let fields_file = StateFile::new(path);
+            if let Some(fields) = fields_file.load() {
+                self.fields = RwLock::new(fields);
+            }
+            self.fields_file = Some(fields_file);
+        }
+        self
+    }
+
+    /// The profile cache, for exporting its statistics and the admin API
+    pub fn cache(&self) -> &dyn CacheStats {
+        &self.cache
+    }
+
+    /// Profile of a user; users without a stored profile have an empty one
+    pub async fn get(&self, user_id: &str) -> Result<Profile> {
+        if let Some(profile) = self.cache.get(&user_id.to_owned()) {
+            return Ok(profile);
+        }
+
+        let profile = match self.db().await? {
+            Some(pool) => queries::get_profile(pool, user_id)
+                .await
+                .map_err(|e| Error::BadDatabase(e.to_string()))?,
+            None => self.memory.read().unwrap().get(user_id).cloned(),
+        }
+        .unwrap_or_else(|| Profile {
+            user_id: user_id.to_owned(),
+            ..Default::default()
+        });
+
+        self.cache.insert(user_id.to_owned(), profile.clone());
+        Ok(profile)
+    }
+
+    pub async fn set_displayname(&self, user_id: &str, displayname: Option<String>) -> Result<()> {
+        let mut profile = self.get(user_id).await?;
+        profile.displayname = displayname;
+        self.store(profile).await
+    }
+
+    /// Change the avatar; a new avatar is no longer a verified NFT
+    pub async fn set_avatar_url(&self, user_id: &str, avatar_url: Option<String>) -> Result<()> {
+        let mut profile = self.get(user_id).await?;
+        profile.avatar_url = avatar_url;
+        self.store(profile).await?;
+        self.set_field(user_id, nft_avatar::PROFILE_FIELD, None);
+        Ok(())
+    }
+
+    /// Users whose profile is cached, most recently used first
+    pub fn cached_user_ids(&self) -> Vec<String> {
+        self.cache.keys()
+    }
+
+    /// Custom profile fields of a user
+    pub fn fields(&self, user_id: &str) -> Map<String, Value> {
+        self.fields.read().unwrap().get(user_id).cloned().unwrap_or_default()
+    }
+
+    /// Set or, with `None`, remove a custom profile field
+    pub fn set_field(&self, user_id: &str, key: &str, value: Option<Value>) {
+        let mut fields = self.fields.write().unwrap();
+        match value {
+            Some(value) => {
+                fields.entry(user_id.to_owned()).or_default().insert(key.to_owned(), value);
+            }
+            None => {
+                if let Some(user_fields) = fields.get_mut(user_id) {
+                    user_fields.remove(key);
+                }
+            }
+        }
+        let snapshot = self.fields_file.as_ref().map(|fields_file| (fields_file, fields_file.snapshot(&*fields)));
+        drop(fields);
+        if let Some((fields_file, snapshot)) = snapshot {
+            fields_file.write(snapshot);
+        }
+    }
+
+    async fn store(&self, profile: Profile) -> Result<()> {
+        match self.db().await? {
+            Some(pool) => queries::upsert_profile(pool, &profile)
+                .await
+                .map_err(|e| Error::BadDatabase(e.to_string()))?,
+            None => {
+                self.memory.write().unwrap().insert(profile.user_id.clone(), profile.clone());
+            }
+        }
+        self.cache.insert(profile.user_id.clone(), profile.clone());
+        propagate(&profile);
+        Ok(())
+    }
+
+    /// Database pool, with the schema created on first use
+    async fn db(&self) -> Result<Option<&PgPool>> {
+        let Some(pool) = &self.pool else {
+            return Ok(None);
+        };
+        self.schema
+            .get_or_try_init(|| migrations::run_migrations(pool))
+            .await
+            .map_err(|e| Error::BadDatabase(e.to_string()))?;
+        Ok(Some(pool))
+    }
+}
+
+/// Send an updated `m.room.member` event into every room the user has joined
+fn propagate(profile: &Profile) {
+    let rooms = membership::joined_rooms(&profile.user_id);
+    for room_id in &rooms {
+        let mut content = json!({ "membership": "join" });
+        if let Some(displayname) = &profile.displayname {
+            content["displayname"] = json!(displayname);
+        }
+        if let Some(avatar_url) = &profile.avatar_url {
+            content["avatar_url"] = json!(avatar_url);
+        }
+        services()
+            .timeline
+            .append_event(room_id, &profile.user_id, "m.room.member", Some(&profile.user_id), content);
+    }
+    info!("👤 Propagated profile of {} to {} rooms", profile.user_id, rooms.len());
+}
@@ -0,0 +1,278 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Remote Media
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Media of other servers, downloaded from the origin through the
+//   authenticated federation media endpoint and cached. When the origin
+//   cannot deliver it, the content is looked up on IPFS instead: the origin
+//   may publish a CID for it either in an event of one of its users
+//   referencing the media, through an `io.matrixon.ipfs_cid` field next to
+//   its `url`, or at `/.well-known/matrix/ipfs/{mediaId}` as
+//   `{"cid": ...}`. Content from IPFS must match the CID. Locations the
+//   origin redirects to may only be public addresses, and only small media
+//   is kept in the bounded cache.
+//
+// =============================================================================
+
+use std::time::Duration;
+
+use matrixon_federation::sending;
+use ruma::api::client::error::ErrorKind;
+use serde_json::Value;
+use tracing::{debug, info, instrument, warn};
+
+use crate::{
+    service::{cache::Cache, ipfs, media_store::Media, outbound_http, timeline},
+    Error, Result,
+};
+
+/// Number of remote media kept in the cache
+const CACHE_CAPACITY: usize = 256;
+/// Largest media kept in the cache
+const MAX_CACHED_SIZE: usize = 1024 * 1024;
+/// Longest time fetching from a media location or well-known may take
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+/// Largest well-known IPFS document read
+const MAX_WELL_KNOWN_SIZE: u64 = 4096;
+
+/// Event content field carrying the IPFS CID of the media in `url`
+pub const IPFS_CID_FIELD: &str = "io.matrixon.ipfs_cid";
+
+/// Second part of a federation media response
+#[derive(Debug, PartialEq, Eq)]
+enum MediaPart {
+    Inline { content_type: Option<String>, data: Vec<u8> },
+    Location(String),
+}
+
+/// Remote media service
+#[derive(Debug)]
+pub struct Service {
+    cache: Cache<(String, String), Media>,
+}
+
+impl Default for Service {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self { cache: Cache::new("remote_media", CACHE_CAPACITY, 1.0) }
+    }
+
+    /// Media `media_id` of `server` if it is cached
+    pub fn cached(&self, server: &str, media_id: &str) -> Option<Media> {
+        self.cache.get(&(server.to_owned(), media_id.to_owned()))
+    }
+
+    /// Media `media_id` of `server`, from the cache, the origin or IPFS
+    #[allow(clippy::too_many_arguments)]
+    #[instrument(level = "debug", skip(self, sending, timeline, ipfs))]
+    pub async fn fetch(
+        &self,
+        sending: &sending::Service,
+        timeline: &timeline::Service,
+        ipfs: &ipfs::Service,
+        ipfs_gateway: &str,
+        server: &str,
+        media_id: &str,
+        max_size: u64,
+    ) -> Result<Media> {
+        if let Some(media) = self.cached(server, media_id) {
+            return Ok(media);
+        }
+
+        let media = match self.fetch_from_origin(sending, server, media_id, max_size).await {
+            Ok(media) => media,
+            Err(e) => {
+                warn!("⚠️ Could not fetch mxc://{}/{} from its origin: {}", server, media_id, e);
+                let cid = match cid_from_events(timeline, server, media_id) {
+                    Some(cid) => Some(cid),
+                    None => self.cid_from_well_known(server, media_id).await,
+                }
+                .ok_or(Error::BadRequest(ErrorKind::NotFound, "Media not found"))?;
+                let (content_type, data) = ipfs.fetch_cid(ipfs_gateway, &cid, max_size).await?;
+                info!("📦 Fetched mxc://{}/{} from IPFS ({})", server, media_id, cid);
+                Media { uploader: String::new(), content_type, filename: None, data }
+            }
+        };
+        if media.data.len() <= MAX_CACHED_SIZE {
+            self.cache.insert((server.to_owned(), media_id.to_owned()), media.clone());
+        }
+        Ok(media)
+    }
+
+    async fn fetch_from_origin(&self, sending: &sending::Service, server: &str, media_id: &str, max_size: u64) -> Result<Media> {
+        let path = format!("/_matrix/federation/v1/media/download/{}", media_id);
+        let (content_type, body) = sending
+            .send_federation_request_raw(server, &path)
+            .await
+            .map_err(|e| Error::BadServerResponse(e.to_string()))?;
+        let part = content_type
+            .as_deref()
+            .and_then(|content_type| parse_multipart(content_type, &body))
+            .ok_or_else(|| Error::BadServerResponse("Invalid media response".to_owned()))?;
+        let (content_type, data) = match part {
+            MediaPart::Inline { content_type, data } => (content_type, data),
+            MediaPart::Location(location) => {
+                debug!("Following media redirect of {} to {}", server, location);
+                let url = outbound_http::check_url(&location)?;
+                let response = outbound_http::client_for(&url, FETCH_TIMEOUT)
+                    .await?
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| Error::BadServerResponse(e.to_string()))?;
+                let content_type = response
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_owned);
+                (content_type, outbound_http::read_capped(response, max_size).await?)
+            }
+        };
+        if data.len() as u64 > max_size {
+            return Err(Error::BadRequest(ErrorKind::TooLarge, "The media is too large"));
+        }
+        Ok(Media { uploader: String::new(), content_type, filename: None, data })
+    }
+
+    /// CID the origin publishes for a media id at its well-known location
+    async fn cid_from_well_known(&self, server: &str, media_id: &str) -> Option<String> {
+        let url = outbound_http::check_url(&format!("https://{}/.well-known/matrix/ipfs/{}", server, media_id)).ok()?;
+        let response = outbound_http::client_for(&url, FETCH_TIMEOUT)
+            .await
+            .ok()?
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .ok()?;
+        let response: Value = serde_json::from_slice(&outbound_http::read_capped(response, MAX_WELL_KNOWN_SIZE).await.ok()?).ok()?;
+        response["cid"].as_str().filter(|cid| ipfs::is_cid(cid)).map(str::to_owned)
+    }
+}
+
+/// CID published in an event referencing `mxc://{server}/{media_id}`.
+/// Only events of users of `server` count: others cannot speak for the
+/// origin's media.
+fn cid_from_events(timeline: &timeline::Service, server: &str, media_id: &str) -> Option<String> {
+    let mxc = format!("mxc://{}/{}", server, media_id);
+    let event = timeline.find_event(|event| {
+        let content = &event["content"];
+        let sender_server = event["sender"].as_str().and_then(|sender| sender.split_once(':')).map(|(_, server)| server);
+        sender_server == Some(server)
+            && (content["url"] == mxc || content["file"]["url"] == mxc)
+            && content[IPFS_CID_FIELD].is_string()
+    })?;
+    event["content"][IPFS_CID_FIELD].as_str().filter(|cid| ipfs::is_cid(cid)).map(str::to_owned)
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| position + from)
+}
+
+/// Parse a `multipart/mixed` federation media response: a JSON part,
+/// then the content or a `Location` header pointing at it
+fn parse_multipart(content_type: &str, body: &[u8]) -> Option<MediaPart> {
+    let (mime, parameters) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/mixed") {
+        return None;
+    }
+    let boundary = parameters
+        .split(';')
+        .find_map(|parameter| parameter.trim().strip_prefix("boundary="))?
+        .trim_matches('"');
+    let delimiter = format!("--{}", boundary).into_bytes();
+
+    let first = find(body, &delimiter, 0)?;
+    let second = find(body, &delimiter, first + delimiter.len())?;
+    let start = second + delimiter.len() + 2;
+    let end = find(body, &delimiter, start)?;
+    let part = body.get(start..end.checked_sub(2)?)?;
+
+    let headers_end = find(part, b"\r\n\r\n", 0).unwrap_or(0);
+    let headers = String::from_utf8_lossy(&part[..headers_end]);
+    let header = |name: &str| {
+        headers.split("\r\n").find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim().to_owned())
+        })
+    };
+    if let Some(location) = header("Location") {
+        return Some(MediaPart::Location(location));
+    }
+    let data = part.get(headers_end + 4..).unwrap_or_default().to_vec();
+    Some(MediaPart::Inline { content_type: header("Content-Type"), data })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_multipart() {
+        let content_type = "multipart/mixed; boundary=\"abc\"";
+        let body = b"--abc\r\nContent-Type: application/json\r\n\r\n{}\r\n--abc\r\nContent-Type: image/png\r\n\r\n\x89PNG\r\n--abc--\r\n";
+        assert_eq!(
+            parse_multipart(content_type, body),
+            Some(MediaPart::Inline { content_type: Some("image/png".to_owned()), data: b"\x89PNG".to_vec() })
+        );
+
+        let body = b"--abc\r\nContent-Type: application/json\r\n\r\n{}\r\n--abc\r\nLocation: https://cdn.example/x\r\n\r\n\r\n--abc--\r\n";
+        assert_eq!(parse_multipart(content_type, body), Some(MediaPart::Location("https://cdn.example/x".to_owned())));
+        assert!(parse_multipart("application/json", body).is_none());
+    }
+
+    #[test]
+    fn test_multipart_round_trip() {
+        let media = Media {
+            uploader: "@a:matrixon.local".to_owned(),
+            content_type: Some("text/plain".to_owned()),
+            filename: Some("a.txt".to_owned()),
+            data: b"--not a boundary\r\n".to_vec(),
+        };
+        let (content_type, body) = media.to_multipart();
+        assert_eq!(
+            parse_multipart(&content_type, &body),
+            Some(MediaPart::Inline { content_type: Some("text/plain".to_owned()), data: media.data })
+        );
+    }
+
+    #[test]
+    fn test_cid_from_events() {
+        let timeline = timeline::Service::new();
+        timeline.append_event(
+            "!room:remote.example",
+            "@bob:remote.example",
+            "m.room.message",
+            None,
+            json!({ "msgtype": "m.image", "url": "mxc://remote.example/abc", IPFS_CID_FIELD: "bafyabc" }),
+        );
+        timeline.append_event(
+            "!room:remote.example",
+            "@mallory:evil.example",
+            "m.room.message",
+            None,
+            json!({ "msgtype": "m.image", "url": "mxc://remote.example/def", IPFS_CID_FIELD: "bafyevil" }),
+        );
+        assert_eq!(cid_from_events(&timeline, "remote.example", "abc").as_deref(), Some("bafyabc"));
+        assert!(cid_from_events(&timeline, "remote.example", "other").is_none());
+        assert!(cid_from_events(&timeline, "remote.example", "def").is_none());
+    }
+}
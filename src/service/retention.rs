@@ -0,0 +1,317 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Message Retention
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Retention of room history (MSC1763). A room's m.room.retention state
+//   event sets how long its events are kept, clamped to the lifetimes the
+//   server allows; rooms without one fall back to the server default. A
+//   background job periodically deletes events older than their room's
+//   lifetime, together with the state events superseded before then, but
+//   keeps the room's state at that point and its latest event so the room
+//   stays usable. Events that were redacted longer ago than the redaction
+//   retention period lose their original content for good. Rooms and
+//   senders under a legal hold are left alone. The database is purged by
+//   the same cut-off, so events stored but not in memory go too, and the
+//   state groups of the deleted events are freed.
+//
+// =============================================================================
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::RetentionConfig,
+    service::{legal_hold, state_file::StateFile, timeline},
+    services,
+};
+
+/// Retention policy of a room, from its m.room.retention event
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RoomPolicy {
+    /// Milliseconds events are at least kept for
+    pub min_lifetime: Option<u64>,
+    /// Milliseconds events are kept for at most
+    pub max_lifetime: Option<u64>,
+}
+
+impl RoomPolicy {
+    pub fn from_content(content: &Value) -> Self {
+        Self { min_lifetime: content["min_lifetime"].as_u64(), max_lifetime: content["max_lifetime"].as_u64() }
+    }
+}
+
+/// Where a room's history was cut
+#[derive(Debug, Default)]
+pub struct Cutoff {
+    /// Events sent before this many milliseconds since the epoch expired
+    pub before_ts: u64,
+    /// Ids of the expired events kept for the room's state or a legal hold
+    pub kept: Vec<String>,
+}
+
+/// What a purge run deleted and stripped
+#[derive(Debug, Default)]
+pub struct PurgeReport {
+    /// Ids of the deleted events by room
+    pub purged: BTreeMap<String, Vec<String>>,
+    /// Cut-off of every room whose events expire, for purging the database
+    pub cutoffs: BTreeMap<String, Cutoff>,
+    /// Events whose redacted content was stripped, by room
+    pub censored: BTreeMap<String, Vec<Value>>,
+}
+
+/// Retention service
+#[derive(Debug)]
+pub struct Service {
+    config: RetentionConfig,
+    /// Redactions sent before this many milliseconds since the epoch have
+    /// had their targets stripped
+    censored_until: AtomicU64,
+    runs: AtomicU64,
+    purged_events: AtomicU64,
+    censored_events: AtomicU64,
+    state_file: Option<StateFile>,
+}
+
+/// Progress kept in the state file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Progress {
+    censored_until: u64,
+}
+
+impl Service {
+    pub fn new(config: RetentionConfig) -> Self {
+        Self {
+            config,
+            censored_until: AtomicU64::new(0),
+            runs: AtomicU64::new(0),
+            purged_events: AtomicU64::new(0),
+            censored_events: AtomicU64::new(0),
+            state_file: None,
+        }
+    }
+
+    /// Keep how far redacted content was stripped in the file at `path`,
+    /// so restarts do not strip it again from the start
+    pub fn with_state_file(mut self, path: Option<PathBuf>) -> Self {
+        if let Some(path) = path {
+            let state_file = StateFile::new(path);
+            let progress: Progress = state_file.load().unwrap_or_default();
+            self.censored_until = AtomicU64::new(progress.censored_until);
+            self.state_file = Some(state_file);
+        }
+        self
+    }
+
+    /// Milliseconds events of a room with `policy` are kept for, if they
+    /// expire at all
+    pub fn max_lifetime(&self, policy: Option<RoomPolicy>) -> Option<u64> {
+        let policy = policy.filter(|policy| policy.max_lifetime.is_some()).unwrap_or(RoomPolicy {
+            min_lifetime: self.config.default_min_lifetime_ms,
+            max_lifetime: self.config.default_max_lifetime_ms,
+        });
+        let lifetime = policy.max_lifetime?.max(policy.min_lifetime.unwrap_or(0));
+        let lifetime = lifetime.max(self.config.allowed_lifetime_min_ms.unwrap_or(0));
+        Some(self.config.allowed_lifetime_max_ms.map_or(lifetime, |max| lifetime.min(max)))
+    }
+
+    /// Purge expired events and strip redacted content in every room of
+    /// `timeline`, as of `now` in milliseconds since the epoch
+    pub fn purge(&self, timeline: &timeline::Service, legal_hold: &legal_hold::Service, now: u64) -> PurgeReport {
+        let mut report = PurgeReport::default();
+        let censor_from = self.censored_until.load(Ordering::SeqCst);
+        let censor_before = self.config.redaction_retention_s.map(|retention| now.saturating_sub(retention * 1000));
+
+        for room_id in timeline.room_ids() {
+            if legal_hold.is_room_held(&room_id) {
+                continue;
+            }
+            let held = |event: &Value| event["sender"].as_str().is_some_and(|sender| legal_hold.is_user_held(sender));
+
+            let policy = timeline
+                .state_event(&room_id, "m.room.retention", "")
+                .map(|event| RoomPolicy::from_content(&event["content"]));
+            if let Some(lifetime) = self.max_lifetime(policy) {
+                let before_ts = now.saturating_sub(lifetime);
+                let (purged, kept) = timeline.purge_before(&room_id, before_ts, held);
+                if !purged.is_empty() {
+                    debug!("🗑️ Purged {} events of {} older than {} ms", purged.len(), room_id, lifetime);
+                    report.purged.insert(room_id.clone(), purged);
+                }
+                report.cutoffs.insert(room_id.clone(), Cutoff { before_ts, kept });
+            }
+            if let Some(censor_before) = censor_before.filter(|before| *before > censor_from) {
+                let censored = timeline.censor_redacted(&room_id, censor_from, censor_before, held);
+                if !censored.is_empty() {
+                    report.censored.insert(room_id.clone(), censored);
+                }
+            }
+        }
+
+        if let Some(censor_before) = censor_before.filter(|before| *before > censor_from) {
+            self.censored_until.fetch_max(censor_before, Ordering::SeqCst);
+            if let Some(state_file) = &self.state_file {
+                state_file.save(&Progress { censored_until: censor_before });
+            }
+        }
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.purged_events.fetch_add(report.purged.values().map(|ids| ids.len() as u64).sum(), Ordering::Relaxed);
+        self.censored_events.fetch_add(report.censored.values().map(|events| events.len() as u64).sum(), Ordering::Relaxed);
+        report
+    }
+
+    /// Retention in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP matrixon_retention_runs_total Retention purge runs");
+        let _ = writeln!(out, "# TYPE matrixon_retention_runs_total counter");
+        let _ = writeln!(out, "matrixon_retention_runs_total {}", self.runs.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP matrixon_retention_purged_events_total Events deleted after their room's lifetime");
+        let _ = writeln!(out, "# TYPE matrixon_retention_purged_events_total counter");
+        let _ = writeln!(out, "matrixon_retention_purged_events_total {}", self.purged_events.load(Ordering::Relaxed));
+        let _ = writeln!(out, "# HELP matrixon_retention_censored_events_total Redacted events whose content was stripped");
+        let _ = writeln!(out, "# TYPE matrixon_retention_censored_events_total counter");
+        let _ = writeln!(out, "matrixon_retention_censored_events_total {}", self.censored_events.load(Ordering::Relaxed));
+        out
+    }
+}
+
+/// Purge expired history every `purge_interval_s`, in memory and in the
+/// database if there is one
+pub async fn run() {
+    let Some(retention) = &services().retention else {
+        return;
+    };
+    info!(
+        "🗑️ Purging expired history every {}s, default lifetime {:?} ms",
+        retention.config.purge_interval_s, retention.config.default_max_lifetime_ms
+    );
+
+    let mut interval = tokio::time::interval(Duration::from_secs(retention.config.purge_interval_s.max(1)));
+    loop {
+        interval.tick().await;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        let report = retention.purge(&services().timeline, &services().legal_hold, now);
+        let (purged, censored) = (report.purged.values().map(Vec::len).sum::<usize>(), report.censored.values().map(Vec::len).sum::<usize>());
+        if purged + censored > 0 {
+            info!("🗑️ Retention purged {} events and stripped {} redacted events", purged, censored);
+        }
+
+        let Some(repositories) = &services().repositories else {
+            continue;
+        };
+        let held_users = services().legal_hold.held_users();
+        for (room_id, cutoff) in &report.cutoffs {
+            let before_ts = i64::try_from(cutoff.before_ts).unwrap_or(i64::MAX);
+            if let Err(e) = repositories.events.purge_before(room_id, before_ts, &cutoff.kept, &held_users).await {
+                warn!("⚠️ Could not purge expired events of {}: {}", room_id, e);
+            }
+        }
+        for (room_id, events) in &report.censored {
+            for event in events {
+                let event_id = event["event_id"].as_str().unwrap_or_default();
+                if let Err(e) = repositories.events.update_json(room_id, event_id, event).await {
+                    warn!("⚠️ Could not strip redacted event {}: {}", event_id, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::legal_hold::HoldTarget;
+    use serde_json::json;
+
+    const DAY: u64 = 24 * 3600 * 1000;
+
+    #[test]
+    fn test_purge_keeps_state_latest_event_and_held_events() {
+        const ROOM: &str = "!room:matrixon.local";
+        let timeline = timeline::Service::new();
+        let legal_hold = legal_hold::Service::new();
+        let event = |id: &str, sender: &str, event_type: &str, state_key: Option<&str>, ts: u64, content: Value| {
+            let mut event = json!({ "event_id": id, "room_id": ROOM, "sender": sender, "type": event_type, "origin_server_ts": ts, "content": content });
+            if let Some(state_key) = state_key {
+                event["state_key"] = json!(state_key);
+            }
+            timeline.append_pdu(ROOM, event);
+        };
+        event("$create", "@a:matrixon.local", "m.room.create", Some(""), 0, json!({}));
+        event("$topic1", "@a:matrixon.local", "m.room.topic", Some(""), DAY, json!({ "topic": "old" }));
+        event("$topic2", "@a:matrixon.local", "m.room.topic", Some(""), 2 * DAY, json!({ "topic": "new" }));
+        event("$old", "@a:matrixon.local", "m.room.message", None, 3 * DAY, json!({ "body": "old" }));
+        event("$held", "@held:matrixon.local", "m.room.message", None, 3 * DAY, json!({ "body": "held" }));
+        event("$secret", "@a:matrixon.local", "m.room.message", None, 9 * DAY, json!({ "body": "secret" }));
+        event("$redaction", "@a:matrixon.local", "m.room.redaction", None, 9 * DAY, json!({ "redacts": "$secret" }));
+        event("$retention", "@a:matrixon.local", "m.room.retention", Some(""), 9 * DAY, json!({ "max_lifetime": 5 * DAY }));
+        legal_hold.place("@admin:matrixon.local", HoldTarget::UserId("@held:matrixon.local".to_owned()), "case", None, None);
+
+        let service = Service::new(RetentionConfig { redaction_retention_s: Some(DAY / 1000), ..Default::default() });
+        let report = service.purge(&timeline, &legal_hold, 10 * DAY + 1);
+        assert_eq!(report.purged[ROOM], ["$topic1", "$old"]);
+        assert_eq!(report.cutoffs[ROOM].before_ts, 5 * DAY + 1);
+        assert_eq!(report.cutoffs[ROOM].kept, ["$create", "$topic2", "$held"]);
+        assert_eq!(report.censored[ROOM].len(), 1);
+        assert_eq!(timeline.get_event(ROOM, "$secret").unwrap()["content"], json!({}));
+        assert_eq!(timeline.state_event(ROOM, "m.room.topic", "").unwrap()["event_id"], "$topic2");
+        assert!(timeline.get_event(ROOM, "$held").is_some() && timeline.get_event(ROOM, "$create").is_some());
+
+        // Nothing is left to purge or strip, and held rooms are skipped
+        assert!(service.purge(&timeline, &legal_hold, 10 * DAY + 2).purged.is_empty());
+        legal_hold.place("@admin:matrixon.local", HoldTarget::RoomId(ROOM.to_owned()), "case", None, None);
+        assert!(service.purge(&timeline, &legal_hold, 100 * DAY).purged.is_empty());
+        assert!(service.render().contains("matrixon_retention_purged_events_total 2\n"));
+    }
+
+    #[test]
+    fn test_stripping_resumes_after_a_restart() {
+        const ROOM: &str = "!room:matrixon.local";
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("retention.json");
+        let config = RetentionConfig { redaction_retention_s: Some(DAY / 1000), ..Default::default() };
+        let (timeline, legal_hold) = (timeline::Service::new(), legal_hold::Service::new());
+        let service = Service::new(config.clone()).with_state_file(Some(path.clone()));
+        service.purge(&timeline, &legal_hold, 3 * DAY);
+
+        // A redaction sent before the stripped period is not looked at again
+        let redaction = json!({ "event_id": "$redaction", "type": "m.room.redaction", "sender": "@a:matrixon.local", "origin_server_ts": DAY, "redacts": "$secret" });
+        timeline.append_pdu(ROOM, json!({ "event_id": "$secret", "type": "m.room.message", "sender": "@a:matrixon.local", "origin_server_ts": DAY, "content": { "body": "secret" } }));
+        timeline.append_pdu(ROOM, redaction);
+        let restarted = Service::new(config.clone()).with_state_file(Some(path));
+        assert!(restarted.purge(&timeline, &legal_hold, 3 * DAY + 1).censored.is_empty());
+        assert_eq!(Service::new(config).purge(&timeline, &legal_hold, 3 * DAY + 1).censored[ROOM].len(), 1);
+    }
+
+    #[test]
+    fn test_lifetimes_are_clamped_to_the_allowed_range() {
+        let service = Service::new(RetentionConfig {
+            default_max_lifetime_ms: Some(30 * DAY),
+            allowed_lifetime_min_ms: Some(DAY),
+            allowed_lifetime_max_ms: Some(90 * DAY),
+            ..Default::default()
+        });
+        let policy = |max_lifetime| Some(RoomPolicy { min_lifetime: None, max_lifetime: Some(max_lifetime) });
+        assert_eq!(service.max_lifetime(None), Some(30 * DAY));
+        assert_eq!(service.max_lifetime(policy(1)), Some(DAY));
+        assert_eq!(service.max_lifetime(policy(365 * DAY)), Some(90 * DAY));
+        assert_eq!(Service::new(RetentionConfig::default()).max_lifetime(None), None);
+    }
+}
@@ -0,0 +1,253 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Room Alias Directory
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   The room aliases of this server and the rooms they point to. An alias
+//   belongs to the server in its name, so only aliases of this server are
+//   kept here; those of other servers are resolved by asking that server
+//   over federation. Aliases are created by members of a room and deleted
+//   by whoever created them or by an admin of the room. A room's
+//   `m.room.canonical_alias` event only advertises aliases, it never makes
+//   an alias point to the room. The directory is kept in a state file when
+//   one is configured.
+//
+// =============================================================================
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+    sync::RwLock,
+};
+
+use matrixon_federation::sending;
+use ruma::api::client::error::ErrorKind;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::{
+    service::{membership, state_file::StateFile, timeline},
+    Error, Result,
+};
+
+/// Room an alias points to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AliasEntry {
+    room_id: String,
+    /// User who created the alias, who may delete it again
+    creator: String,
+}
+
+/// Room alias directory service
+#[derive(Debug, Default)]
+pub struct Service {
+    aliases: RwLock<BTreeMap<String, AliasEntry>>,
+    aliases_file: Option<StateFile>,
+}
+
+/// Split `#localpart:server` into its localpart and server name
+fn parse_alias(alias: &str) -> Option<(&str, &str)> {
+    let (localpart, server) = alias.strip_prefix('#')?.split_once(':')?;
+    (!localpart.is_empty() && !server.is_empty()).then_some((localpart, server))
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the aliases in the file at `path`, loading the ones stored there
+    pub fn with_aliases_file(mut self, path: Option<PathBuf>) -> Self {
+        if let Some(path) = path {
+            let aliases_file = StateFile::new(path);
+            if let Some(aliases) = aliases_file.load() {
+                self.aliases = RwLock::new(aliases);
+            }
+            self.aliases_file = Some(aliases_file);
+        }
+        self
+    }
+
+    /// Change the aliases and persist them
+    fn update<T>(&self, f: impl FnOnce(&mut BTreeMap<String, AliasEntry>) -> Result<T>) -> Result<T> {
+        let mut aliases = self.aliases.write().unwrap();
+        let result = f(&mut aliases)?;
+        let snapshot = self.aliases_file.as_ref().map(|aliases_file| (aliases_file, aliases_file.snapshot(&*aliases)));
+        drop(aliases);
+        if let Some((aliases_file, snapshot)) = snapshot {
+            aliases_file.write(snapshot);
+        }
+        Ok(result)
+    }
+
+    /// Point the alias `alias` of this server at a room `user_id` is joined
+    /// to. Aliases are never moved to another room; delete them first.
+    pub fn create(&self, timeline: &timeline::Service, own_server: &str, alias: &str, room_id: &str, user_id: &str) -> Result<()> {
+        let Some((_, server)) = parse_alias(alias) else {
+            return Err(Error::BadRequest(ErrorKind::InvalidParam, "Invalid room alias"));
+        };
+        if server != own_server {
+            return Err(Error::BadRequest(ErrorKind::InvalidParam, "Room aliases of other servers cannot be created here"));
+        }
+        if !timeline.room_exists(room_id) {
+            return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+        }
+        if membership::membership_in(timeline, room_id, user_id).as_deref() != Some("join") {
+            return Err(Error::BadRequest(ErrorKind::forbidden(), "You are not in this room"));
+        }
+        self.update(|aliases| {
+            if aliases.contains_key(alias) {
+                return Err(Error::BadRequest(ErrorKind::RoomInUse, "Room alias already taken"));
+            }
+            aliases.insert(alias.to_owned(), AliasEntry { room_id: room_id.to_owned(), creator: user_id.to_owned() });
+            Ok(())
+        })?;
+        info!("🏷️ {} pointed {} at {}", user_id, alias, room_id);
+        Ok(())
+    }
+
+    /// Delete an alias on behalf of its creator or an admin of its room
+    pub fn delete(&self, timeline: &timeline::Service, alias: &str, user_id: &str) -> Result<()> {
+        self.update(|aliases| {
+            let entry = aliases.get(alias).ok_or(Error::BadRequest(ErrorKind::NotFound, "Unknown room alias"))?;
+            if entry.creator != user_id && !membership::is_room_admin(timeline, &entry.room_id, user_id) {
+                return Err(Error::BadRequest(ErrorKind::forbidden(), "You are not allowed to delete this room alias"));
+            }
+            aliases.remove(alias);
+            Ok(())
+        })?;
+        info!("🏷️ {} deleted {}", user_id, alias);
+        Ok(())
+    }
+
+    /// Room a local alias points to
+    pub fn local(&self, alias: &str) -> Option<String> {
+        self.aliases.read().unwrap().get(alias).map(|entry| entry.room_id.clone())
+    }
+
+    /// Drop the aliases of a deleted room, returning how many there were
+    pub fn delete_room(&self, room_id: &str) -> usize {
+        self.update(|aliases| {
+            let before = aliases.len();
+            aliases.retain(|_, entry| entry.room_id != room_id);
+            Ok(before - aliases.len())
+        })
+        .unwrap_or_default()
+    }
+
+    /// Room a local alias points to and the servers joined to it, as
+    /// answered to `/query/directory` and `GET /directory/room`
+    pub fn query(&self, timeline: &timeline::Service, own_server: &str, alias: &str) -> Result<(String, Vec<String>)> {
+        let room_id = self.local(alias).ok_or(Error::BadRequest(ErrorKind::NotFound, "Unknown room alias"))?;
+        Ok((room_id.clone(), resident_servers(timeline, &room_id, own_server)))
+    }
+
+    /// Room an alias of any server points to, with servers to join it
+    /// through. Aliases of other servers are looked up over federation.
+    pub async fn resolve(
+        &self,
+        timeline: &timeline::Service,
+        sending: &sending::Service,
+        own_server: &str,
+        alias: &str,
+    ) -> Result<(String, Vec<String>)> {
+        let Some((_, server)) = parse_alias(alias) else {
+            return Err(Error::BadRequest(ErrorKind::InvalidParam, "Invalid room alias"));
+        };
+        if server == own_server {
+            return self.query(timeline, own_server, alias);
+        }
+
+        debug!("🌐 Resolving {} through {}", alias, server);
+        let query = url::form_urlencoded::Serializer::new(String::new()).append_pair("room_alias", alias).finish();
+        let response = sending
+            .send_federation_request(server, reqwest::Method::GET, &format!("/_matrix/federation/v1/query/directory?{}", query), None)
+            .await
+            .map_err(|_| Error::BadRequest(ErrorKind::NotFound, "Unknown room alias"))?;
+        let room_id = response["room_id"]
+            .as_str()
+            .filter(|room_id| room_id.starts_with('!'))
+            .ok_or_else(|| Error::BadServerResponse(format!("{} returned an invalid room alias", server)))?;
+        let mut servers: Vec<String> =
+            response["servers"].as_array().into_iter().flatten().filter_map(|server| server.as_str().map(str::to_owned)).collect();
+        if !servers.iter().any(|known| known == server) {
+            servers.insert(0, server.to_owned());
+        }
+        Ok((room_id.to_owned(), servers))
+    }
+}
+
+/// Servers with users joined to a room, this one first
+fn resident_servers(timeline: &timeline::Service, room_id: &str, own_server: &str) -> Vec<String> {
+    let others: BTreeSet<String> = timeline
+        .current_state(room_id)
+        .iter()
+        .filter(|event| event["type"] == "m.room.member" && event["content"]["membership"] == "join")
+        .filter_map(|event| event["state_key"].as_str()?.split_once(':').map(|(_, server)| server.to_owned()))
+        .filter(|server| server != own_server)
+        .collect();
+    std::iter::once(own_server.to_owned()).chain(others).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const OWN: &str = "matrixon.local";
+    const ROOM: &str = "!lobby:matrixon.local";
+    const OWNER: &str = "@owner:matrixon.local";
+    const ALICE: &str = "@alice:matrixon.local";
+
+    fn room() -> timeline::Service {
+        let timeline = timeline::Service::new();
+        timeline.append_event(ROOM, OWNER, "m.room.create", Some(""), json!({ "creator": OWNER, "room_version": "10" }));
+        timeline.append_event(ROOM, OWNER, "m.room.member", Some(OWNER), json!({ "membership": "join" }));
+        timeline.append_event(ROOM, OWNER, "m.room.power_levels", Some(""), json!({ "users": { OWNER: 100 } }));
+        timeline.append_event(ROOM, "@bob:remote.example", "m.room.member", Some("@bob:remote.example"), json!({ "membership": "join" }));
+        timeline
+    }
+
+    #[test]
+    fn test_aliases_point_at_rooms_of_their_members() {
+        let timeline = room();
+        let aliases = Service::new();
+        assert!(aliases.create(&timeline, OWN, "#lobby:remote.example", ROOM, OWNER).is_err());
+        assert!(aliases.create(&timeline, OWN, "#lobby:matrixon.local", ROOM, ALICE).is_err());
+        aliases.create(&timeline, OWN, "#lobby:matrixon.local", ROOM, OWNER).unwrap();
+
+        // Taken aliases cannot be claimed again, nor by claiming them in a
+        // canonical alias event
+        timeline.append_event("!other:matrixon.local", ALICE, "m.room.create", Some(""), json!({ "creator": ALICE }));
+        timeline.append_event("!other:matrixon.local", ALICE, "m.room.member", Some(ALICE), json!({ "membership": "join" }));
+        timeline.append_event("!other:matrixon.local", ALICE, "m.room.canonical_alias", Some(""), json!({ "alias": "#lobby:matrixon.local" }));
+        assert!(aliases.create(&timeline, OWN, "#lobby:matrixon.local", "!other:matrixon.local", ALICE).is_err());
+        let (room_id, servers) = aliases.query(&timeline, OWN, "#lobby:matrixon.local").unwrap();
+        assert_eq!(room_id, ROOM);
+        assert_eq!(servers, ["matrixon.local", "remote.example"]);
+
+        assert!(aliases.delete(&timeline, "#lobby:matrixon.local", ALICE).is_err());
+        aliases.delete(&timeline, "#lobby:matrixon.local", OWNER).unwrap();
+        assert_eq!(aliases.local("#lobby:matrixon.local"), None);
+    }
+
+    #[test]
+    fn test_aliases_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("room_aliases.json");
+        let timeline = room();
+        let aliases = Service::new().with_aliases_file(Some(path.clone()));
+        aliases.create(&timeline, OWN, "#lobby:matrixon.local", ROOM, OWNER).unwrap();
+
+        let restarted = Service::new().with_aliases_file(Some(path));
+        assert_eq!(restarted.local("#lobby:matrixon.local").as_deref(), Some(ROOM));
+        assert_eq!(restarted.delete_room(ROOM), 1);
+        assert_eq!(restarted.local("#lobby:matrixon.local"), None);
+    }
+}
@@ -0,0 +1,328 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Room Deletion
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Shutting down and purging rooms on behalf of server admins. Local
+//   members leave the room, their leave events are sent to the other
+//   servers in it, and the room's events and state are deleted from memory
+//   and the database along with the local media only its events referred
+//   to. The room is dropped from the directory, its aliases and room
+//   summaries, and may be blocked so it cannot be joined again; blocked
+//   rooms are kept in a state file when one is configured. Rooms under a
+//   legal hold are refused.
+//
+// =============================================================================
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    path::PathBuf,
+    sync::RwLock,
+    time::Duration,
+};
+
+use ruma::api::client::error::ErrorKind;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::{
+    service::{membership, outbound_federation, state_file::StateFile, timeline},
+    services, Error, Result,
+};
+
+/// Reason local members are given for leaving a deleted room
+const DEFAULT_REASON: &str = "This room has been shut down by a server admin";
+/// How long the leave events of a deleted room may take to reach the
+/// federation queue before they are queued directly
+const FEDERATION_WAIT: Duration = Duration::from_secs(10);
+
+/// What deleting a room did
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct DeletionReport {
+    pub room_id: String,
+    /// Local users who were made to leave
+    pub kicked_users: Vec<String>,
+    pub deleted_events: usize,
+    pub deleted_media: usize,
+    pub blocked: bool,
+}
+
+/// Room deletion service, holding the rooms blocked from being joined
+#[derive(Debug, Default)]
+pub struct Service {
+    blocked: RwLock<HashSet<String>>,
+    state_file: Option<StateFile>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the blocked rooms in the file at `path`, loading the ones
+    /// stored there
+    pub fn with_state_file(mut self, path: Option<PathBuf>) -> Self {
+        if let Some(path) = path {
+            let state_file = StateFile::new(path);
+            if let Some(blocked) = state_file.load() {
+                self.blocked = RwLock::new(blocked);
+            }
+            self.state_file = Some(state_file);
+        }
+        self
+    }
+
+    pub fn block(&self, room_id: &str) {
+        self.update(|blocked| blocked.insert(room_id.to_owned()));
+    }
+
+    /// Allow a blocked room to be joined again, returning whether it was
+    /// blocked
+    pub fn unblock(&self, room_id: &str) -> bool {
+        self.update(|blocked| blocked.remove(room_id))
+    }
+
+    fn update<T>(&self, f: impl FnOnce(&mut HashSet<String>) -> T) -> T {
+        let mut blocked = self.blocked.write().unwrap();
+        let result = f(&mut blocked);
+        let snapshot = self.state_file.as_ref().map(|state_file| (state_file, state_file.snapshot(&*blocked)));
+        drop(blocked);
+        if let Some((state_file, snapshot)) = snapshot {
+            state_file.write(snapshot);
+        }
+        result
+    }
+
+    pub fn is_blocked(&self, room_id: &str) -> bool {
+        self.blocked.read().unwrap().contains(room_id)
+    }
+
+    /// Blocked rooms, sorted
+    pub fn blocked(&self) -> Vec<String> {
+        let mut blocked: Vec<String> = self.blocked.read().unwrap().iter().cloned().collect();
+        blocked.sort();
+        blocked
+    }
+
+    /// Refuse joining a blocked room
+    pub fn ensure_not_blocked(&self, room_id: &str) -> Result<()> {
+        if self.is_blocked(room_id) {
+            return Err(Error::BadRequest(ErrorKind::forbidden(), "This room has been blocked by a server admin"));
+        }
+        Ok(())
+    }
+
+    /// Shut down and purge a room on behalf of `admin`. A room this server
+    /// does not know can still be blocked.
+    pub async fn delete(&self, room_id: &str, admin: &str, block: bool, reason: Option<&str>) -> Result<DeletionReport> {
+        let services = services();
+        let server_name = &services.globals.config.server_name;
+        if !services.timeline.room_exists(room_id) && !block {
+            return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+        }
+        if services.legal_hold.is_room_held(room_id) {
+            return Err(Error::BadRequest(ErrorKind::forbidden(), "The room is under a legal hold"));
+        }
+        // Block first so nobody joins while the room is being purged
+        if block {
+            self.block(room_id);
+        }
+
+        let leaves = evict_local_members(&services.timeline, room_id, server_name, reason.unwrap_or(DEFAULT_REASON));
+        // The leaves go out with the other local events, as long as the room
+        // is there to address them; queue them directly only if that stalls
+        if services.globals.config.allow_federation
+            && !leaves.is_empty()
+            && !outbound_federation::wait_until_federated(services.timeline.current_count(), FEDERATION_WAIT).await
+        {
+            warn!("⚠️ Federation is behind, sending the leave events of {} directly", room_id);
+            for (_, event_id) in &leaves {
+                if let Some(event) = services.timeline.get_event(room_id, event_id) {
+                    outbound_federation::federate(&event, server_name);
+                }
+            }
+        }
+
+        let events = services.timeline.delete_room(room_id);
+        let mut media = local_media(&events, server_name);
+        let prefix = format!("mxc://{}/", server_name);
+        for other_room in services.timeline.room_ids() {
+            if media.is_empty() {
+                break;
+            }
+            let mut referenced = BTreeSet::new();
+            services.timeline.for_each_event_in(&other_room, |event| collect_media(&event["content"], &prefix, &mut referenced));
+            media.retain(|media_id| !referenced.contains(media_id));
+            tokio::task::yield_now().await;
+        }
+        let mut deleted_media = 0;
+        for media_id in media {
+            let held = services
+                .media_store
+                .get(&media_id)
+                .is_some_and(|media| services.legal_hold.is_user_held(&media.uploader));
+            if !held && services.media_store.delete(&media_id).is_some() {
+                deleted_media += 1;
+            }
+        }
+        services.room_directory.unpublish(room_id);
+        services.room_aliases.delete_room(room_id);
+        services.room_summary.remove(room_id);
+        services.inbound_federation.delete_room(room_id);
+        if let Some(room_webhooks) = &services.room_webhooks {
+            room_webhooks.delete_room(room_id);
+        }
+
+        if let Some(repositories) = &services.repositories {
+            if let Some(persistence) = &services.event_persistence {
+                persistence.flush().await;
+            }
+            if let Err(e) = repositories.events.delete_room(room_id).await {
+                warn!("⚠️ Could not delete the stored events of {}: {}", room_id, e);
+            }
+            if let Err(e) = repositories.outliers.delete_room(room_id).await {
+                warn!("⚠️ Could not delete the stored outliers of {}: {}", room_id, e);
+            }
+            if let Err(e) = repositories.pdu_metadata.delete_room(room_id).await {
+                warn!("⚠️ Could not delete the stored event metadata of {}: {}", room_id, e);
+            }
+            if let Err(e) = repositories.auth_chains.delete_room(room_id).await {
+                warn!("⚠️ Could not delete the auth chain index of {}: {}", room_id, e);
+            }
+            if let Err(e) = repositories.rooms.delete(room_id).await {
+                warn!("⚠️ Could not delete the stored room {}: {}", room_id, e);
+            }
+        }
+
+        let report = DeletionReport {
+            room_id: room_id.to_owned(),
+            kicked_users: leaves.into_iter().map(|(user_id, _)| user_id).collect(),
+            deleted_events: events.len(),
+            deleted_media,
+            blocked: block,
+        };
+        info!(
+            "🗑️ {} deleted {}: {} local users removed, {} events and {} media files deleted{}",
+            admin,
+            room_id,
+            report.kicked_users.len(),
+            report.deleted_events,
+            report.deleted_media,
+            if block { ", room blocked" } else { "" }
+        );
+        Ok(report)
+    }
+}
+
+/// Make every local user joined to, invited to or knocking on a room leave
+/// it. Returns the users with their leave event ids.
+fn evict_local_members(timeline: &timeline::Service, room_id: &str, server_name: &str, reason: &str) -> Vec<(String, String)> {
+    let local_suffix = format!(":{}", server_name);
+    let members: Vec<String> = timeline
+        .current_state(room_id)
+        .iter()
+        .filter(|event| event["type"] == "m.room.member")
+        .filter(|event| matches!(event["content"]["membership"].as_str(), Some("join" | "invite" | "knock")))
+        .filter_map(|event| event["state_key"].as_str())
+        .filter(|user_id| user_id.ends_with(&local_suffix))
+        .map(str::to_owned)
+        .collect();
+
+    members
+        .into_iter()
+        .filter_map(|user_id| match membership::leave_in(timeline, room_id, &user_id, Some(reason)) {
+            Ok(event_id) => Some((user_id, event_id)),
+            Err(e) => {
+                warn!("⚠️ Could not remove {} from {}: {}", user_id, room_id, e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Ids of the media of `server_name` that `events` refer to
+fn local_media(events: &[Value], server_name: &str) -> BTreeSet<String> {
+    let prefix = format!("mxc://{}/", server_name);
+    let mut media = BTreeSet::new();
+    for event in events {
+        collect_media(&event["content"], &prefix, &mut media);
+    }
+    media
+}
+
+/// Add the ids of the media `value` refers to under `prefix`
+/// (`mxc://{server_name}/`) to `media`
+pub(crate) fn collect_media(value: &Value, prefix: &str, media: &mut BTreeSet<String>) {
+    match value {
+        Value::String(uri) => {
+            if let Some(media_id) = uri.strip_prefix(prefix).filter(|media_id| !media_id.is_empty()) {
+                media.insert(media_id.to_owned());
+            }
+        }
+        Value::Array(values) => values.iter().for_each(|value| collect_media(value, prefix, media)),
+        Value::Object(values) => values.values().for_each(|value| collect_media(value, prefix, media)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const ROOM: &str = "!doomed:matrixon.local";
+
+    #[test]
+    fn test_local_members_leave_and_media_is_found() {
+        let timeline = timeline::Service::new();
+        timeline.append_event(ROOM, "@owner:matrixon.local", "m.room.create", Some(""), json!({ "room_version": "10" }));
+        timeline.append_event(ROOM, "@owner:matrixon.local", "m.room.join_rules", Some(""), json!({ "join_rule": "public" }));
+        for user_id in ["@owner:matrixon.local", "@alice:matrixon.local", "@bob:remote.example"] {
+            timeline.append_event(ROOM, user_id, "m.room.member", Some(user_id), json!({ "membership": "join" }));
+        }
+        timeline.append_event(ROOM, "@alice:matrixon.local", "m.room.message", None, json!({
+            "msgtype": "m.image",
+            "url": "mxc://matrixon.local/abc",
+            "info": { "thumbnail_url": "mxc://matrixon.local/thumb" },
+        }));
+        timeline.append_event(ROOM, "@bob:remote.example", "m.room.message", None, json!({ "url": "mxc://remote.example/xyz" }));
+
+        let leaves = evict_local_members(&timeline, ROOM, "matrixon.local", DEFAULT_REASON);
+        let users: Vec<&str> = leaves.iter().map(|(user_id, _)| user_id.as_str()).collect();
+        assert_eq!(users, ["@owner:matrixon.local", "@alice:matrixon.local"]);
+        let leave = timeline.state_event(ROOM, "m.room.member", "@alice:matrixon.local").unwrap();
+        assert_eq!(leave["content"], json!({ "membership": "leave", "reason": DEFAULT_REASON }));
+
+        let events = timeline.delete_room(ROOM);
+        assert!(!timeline.room_exists(ROOM));
+        assert_eq!(local_media(&events, "matrixon.local").into_iter().collect::<Vec<_>>(), ["abc", "thumb"]);
+    }
+
+    #[test]
+    fn test_blocked_rooms_cannot_be_joined() {
+        let service = Service::new();
+        assert!(service.ensure_not_blocked(ROOM).is_ok());
+        service.block(ROOM);
+        assert!(matches!(service.ensure_not_blocked(ROOM), Err(Error::BadRequest(ErrorKind::Forbidden { .. }, _))));
+        assert_eq!(service.blocked(), [ROOM]);
+        assert!(service.unblock(ROOM) && !service.is_blocked(ROOM));
+    }
+
+    #[test]
+    fn test_blocked_rooms_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocked_rooms.json");
+        Service::new().with_state_file(Some(path.clone())).block(ROOM);
+        let restarted = Service::new().with_state_file(Some(path.clone()));
+        assert!(restarted.is_blocked(ROOM));
+        restarted.unblock(ROOM);
+        assert!(Service::new().with_state_file(Some(path)).blocked().is_empty());
+    }
+}
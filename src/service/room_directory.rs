@@ -0,0 +1,313 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Public Room Directory
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   The rooms published in this server's public room directory, served to
+//   local clients and, over federation, to other servers. Entries are built
+//   from the current room state and ordered by joined members, largest
+//   first. Pagination tokens are offsets into that order: `n<offset>` for
+//   the next page, `p<offset>` for the previous one. Directories of other
+//   servers are browsed through their federation endpoint. Which rooms are
+//   published is kept in a state file when one is configured.
+//
+// =============================================================================
+
+use std::{collections::BTreeSet, path::PathBuf, sync::RwLock};
+
+use matrixon_federation::sending;
+use ruma::api::client::error::ErrorKind;
+use serde_json::{json, Map, Value};
+use tracing::{debug, info};
+
+use crate::{
+    service::{membership, state_file::StateFile, timeline},
+    Error, Result,
+};
+
+/// Page size when the request sets no limit
+pub const DEFAULT_LIMIT: usize = 100;
+
+/// Power level needed to change whether a room is published
+const PUBLISH_LEVEL: i64 = 50;
+
+/// Directory entry of a room, `PublicRoomsChunk` in the spec
+pub fn public_room(timeline: &timeline::Service, room_id: &str) -> Value {
+    let content = |event_type: &str| timeline.state_event(room_id, event_type, "").map(|event| event["content"].clone());
+    let num_joined_members = timeline
+        .current_state(room_id)
+        .iter()
+        .filter(|event| event["type"] == "m.room.member" && event["content"]["membership"] == "join")
+        .count();
+
+    let mut room = Map::new();
+    room.insert("room_id".to_owned(), json!(room_id));
+    room.insert("num_joined_members".to_owned(), json!(num_joined_members));
+    let history_visibility = content("m.room.history_visibility");
+    room.insert(
+        "world_readable".to_owned(),
+        json!(history_visibility.is_some_and(|content| content["history_visibility"] == "world_readable")),
+    );
+    let guest_access = content("m.room.guest_access");
+    room.insert("guest_can_join".to_owned(), json!(guest_access.is_some_and(|content| content["guest_access"] == "can_join")));
+    let optional = [
+        ("name", content("m.room.name").map(|content| content["name"].clone())),
+        ("topic", content("m.room.topic").map(|content| content["topic"].clone())),
+        ("canonical_alias", content("m.room.canonical_alias").map(|content| content["alias"].clone())),
+        ("avatar_url", content("m.room.avatar").map(|content| content["url"].clone())),
+        ("join_rule", content("m.room.join_rules").map(|content| content["join_rule"].clone())),
+        ("room_type", content("m.room.create").map(|content| content["type"].clone())),
+    ];
+    for (key, value) in optional {
+        if let Some(value) = value.filter(Value::is_string) {
+            room.insert(key.to_owned(), value);
+        }
+    }
+    Value::Object(room)
+}
+
+/// Whether a directory entry matches a `generic_search_term`
+fn matches(room: &Value, term: &str) -> bool {
+    let term = term.to_lowercase();
+    ["name", "topic", "canonical_alias", "room_id"]
+        .iter()
+        .filter_map(|key| room[*key].as_str())
+        .any(|value| value.to_lowercase().contains(&term))
+}
+
+fn parse_token(token: &str) -> Option<usize> {
+    token.strip_prefix('n').or_else(|| token.strip_prefix('p'))?.parse().ok()
+}
+
+/// Public room directory service
+#[derive(Debug, Default)]
+pub struct Service {
+    published: RwLock<BTreeSet<String>>,
+    published_file: Option<StateFile>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the published rooms in the file at `path`, loading the ones
+    /// stored there
+    pub fn with_published_file(mut self, path: Option<PathBuf>) -> Self {
+        if let Some(path) = path {
+            let published_file = StateFile::new(path);
+            if let Some(published) = published_file.load() {
+                self.published = RwLock::new(published);
+            }
+            self.published_file = Some(published_file);
+        }
+        self
+    }
+
+    pub fn publish(&self, room_id: &str) {
+        self.update(|published| published.insert(room_id.to_owned()));
+    }
+
+    pub fn unpublish(&self, room_id: &str) {
+        self.update(|published| published.remove(room_id));
+    }
+
+    /// Change the published rooms and persist them if `f` changed them
+    fn update(&self, f: impl FnOnce(&mut BTreeSet<String>) -> bool) {
+        let mut published = self.published.write().unwrap();
+        if !f(&mut published) {
+            return;
+        }
+        let snapshot = self.published_file.as_ref().map(|published_file| (published_file, published_file.snapshot(&*published)));
+        drop(published);
+        if let Some((published_file, snapshot)) = snapshot {
+            published_file.write(snapshot);
+        }
+    }
+
+    pub fn is_published(&self, room_id: &str) -> bool {
+        self.published.read().unwrap().contains(room_id)
+    }
+
+    /// Publish a room in or remove it from the directory on behalf of a
+    /// room moderator
+    pub fn set_visibility(&self, timeline: &timeline::Service, room_id: &str, user_id: &str, public: bool) -> Result<()> {
+        if !timeline.room_exists(room_id) {
+            return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room"));
+        }
+        if membership::power_level_of(timeline, room_id, user_id) < PUBLISH_LEVEL {
+            return Err(Error::BadRequest(ErrorKind::forbidden(), "You are not allowed to change the room's visibility"));
+        }
+        if public {
+            self.publish(room_id);
+        } else {
+            self.unpublish(room_id);
+        }
+        info!("📖 {} {} {} in the room directory", user_id, if public { "published" } else { "unpublished" }, room_id);
+        Ok(())
+    }
+
+    /// A page of the directory, `PublicRoomsResponse` in the spec
+    pub fn public_rooms(
+        &self,
+        timeline: &timeline::Service,
+        limit: Option<usize>,
+        since: Option<&str>,
+        search_term: Option<&str>,
+    ) -> Result<Value> {
+        let offset = match since {
+            Some(since) => parse_token(since).ok_or(Error::BadRequest(ErrorKind::InvalidParam, "Invalid since token"))?,
+            None => 0,
+        };
+        let limit = limit.unwrap_or(DEFAULT_LIMIT).max(1);
+
+        let published: Vec<String> = self.published.read().unwrap().iter().cloned().collect();
+        let mut rooms: Vec<Value> = published
+            .iter()
+            .filter(|room_id| timeline.room_exists(room_id))
+            .map(|room_id| public_room(timeline, room_id))
+            .filter(|room| search_term.is_none_or(|term| matches(room, term)))
+            .collect();
+        rooms.sort_by(|a, b| {
+            b["num_joined_members"]
+                .as_u64()
+                .cmp(&a["num_joined_members"].as_u64())
+                .then_with(|| a["room_id"].as_str().cmp(&b["room_id"].as_str()))
+        });
+
+        let total = rooms.len();
+        let chunk: Vec<Value> = rooms.into_iter().skip(offset).take(limit).collect();
+        let mut response = json!({ "chunk": chunk, "total_room_count_estimate": total });
+        let next = offset.saturating_add(limit);
+        if next < total {
+            response["next_batch"] = json!(format!("n{}", next));
+        }
+        if offset > 0 {
+            response["prev_batch"] = json!(format!("p{}", offset.saturating_sub(limit)));
+        }
+        Ok(response)
+    }
+}
+
+/// Browse the directory of another server. `filter` is the body of the
+/// filtered variant and sent with `POST`; without it the plain `GET`
+/// endpoint is used.
+pub async fn remote_public_rooms(
+    sending: &sending::Service,
+    server: &str,
+    limit: Option<usize>,
+    since: Option<&str>,
+    filter: Option<Value>,
+) -> Result<Value> {
+    let (method, path, body) = match filter {
+        Some(filter) => {
+            let mut body = json!({ "filter": filter });
+            if let Some(limit) = limit {
+                body["limit"] = json!(limit);
+            }
+            if let Some(since) = since {
+                body["since"] = json!(since);
+            }
+            (reqwest::Method::POST, "/_matrix/federation/v1/publicRooms".to_owned(), Some(body))
+        }
+        None => {
+            let mut query = url::form_urlencoded::Serializer::new(String::new());
+            if let Some(limit) = limit {
+                query.append_pair("limit", &limit.to_string());
+            }
+            if let Some(since) = since {
+                query.append_pair("since", since);
+            }
+            (reqwest::Method::GET, format!("/_matrix/federation/v1/publicRooms?{}", query.finish()), None)
+        }
+    };
+    debug!("🌐 Browsing the room directory of {}", server);
+    let response = sending
+        .send_federation_request(server, method, &path, body)
+        .await
+        .map_err(|e| Error::BadServerResponse(e.to_string()))?;
+    if !response["chunk"].is_array() {
+        return Err(Error::BadServerResponse(format!("{} returned an invalid room directory", server)));
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: &str = "@owner:matrixon.local";
+
+    fn create_room(timeline: &timeline::Service, room_id: &str, name: &str, members: usize) {
+        timeline.append_event(room_id, OWNER, "m.room.create", Some(""), json!({ "creator": OWNER, "room_version": "10" }));
+        timeline.append_event(room_id, OWNER, "m.room.join_rules", Some(""), json!({ "join_rule": "public" }));
+        timeline.append_event(room_id, OWNER, "m.room.name", Some(""), json!({ "name": name }));
+        for i in 0..members {
+            let user_id = format!("@user{}:matrixon.local", i);
+            timeline.append_event(room_id, &user_id, "m.room.member", Some(&user_id), json!({ "membership": "join" }));
+        }
+    }
+
+    #[test]
+    fn test_public_rooms_pagination_and_search() {
+        let timeline = timeline::Service::new();
+        let directory = Service::new();
+        create_room(&timeline, "!small:matrixon.local", "Small talk", 1);
+        create_room(&timeline, "!big:matrixon.local", "Big room", 3);
+        create_room(&timeline, "!hidden:matrixon.local", "Hidden", 5);
+        directory.publish("!small:matrixon.local");
+        directory.publish("!big:matrixon.local");
+
+        let page = directory.public_rooms(&timeline, Some(1), None, None).unwrap();
+        assert_eq!(page["chunk"][0]["room_id"], "!big:matrixon.local");
+        assert_eq!(page["chunk"][0]["num_joined_members"], 3);
+        assert_eq!(page["chunk"][0]["join_rule"], "public");
+        assert_eq!(page["total_room_count_estimate"], 2);
+        assert!(page.get("prev_batch").is_none());
+
+        let since = page["next_batch"].as_str().unwrap();
+        let page = directory.public_rooms(&timeline, Some(1), Some(since), None).unwrap();
+        assert_eq!(page["chunk"][0]["room_id"], "!small:matrixon.local");
+        assert!(page.get("next_batch").is_none());
+        assert_eq!(page["prev_batch"], "p0");
+
+        let page = directory.public_rooms(&timeline, None, None, Some("TALK")).unwrap();
+        assert_eq!(page["chunk"].as_array().unwrap().len(), 1);
+        assert!(directory.public_rooms(&timeline, None, Some("bogus"), None).is_err());
+
+        // Huge limits do not overflow the next page's offset
+        let page = directory.public_rooms(&timeline, Some(usize::MAX), Some("n1"), None).unwrap();
+        assert_eq!(page["chunk"].as_array().unwrap().len(), 1);
+        assert!(page.get("next_batch").is_none());
+    }
+
+    #[test]
+    fn test_published_rooms_are_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("published_rooms.json");
+        let directory = Service::new().with_published_file(Some(path.clone()));
+        directory.publish("!kept:matrixon.local");
+        directory.publish("!removed:matrixon.local");
+        directory.unpublish("!removed:matrixon.local");
+
+        let directory = Service::new().with_published_file(Some(path));
+        assert!(directory.is_published("!kept:matrixon.local"));
+        assert!(!directory.is_published("!removed:matrixon.local"));
+    }
+
+    #[test]
+    fn test_only_moderators_publish() {
+        let timeline = timeline::Service::new();
+        let directory = Service::new();
+        create_room(&timeline, "!room:matrixon.local", "Room", 1);
+        assert!(directory.set_visibility(&timeline, "!room:matrixon.local", "@user0:matrixon.local", true).is_err());
+        directory.set_visibility(&timeline, "!room:matrixon.local", OWNER, true).unwrap();
+        assert!(directory.is_published("!room:matrixon.local"));
+    }
+}
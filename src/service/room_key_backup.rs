@@ -0,0 +1,388 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Room Key Backup Service
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Server-side backup of encrypted megolm session keys. Each user has
+//   numbered backup versions; keys can only be written to the latest one.
+//   Backups are written through to PostgreSQL when there is a database,
+//   one user's versions at a time and in the order they changed, and loaded
+//   back at startup; otherwise they are kept in a state file.
+//
+// =============================================================================
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    sync::RwLock,
+};
+
+use matrixon_db::{RoomKeyBackupRecord, RoomKeyBackupRepo};
+use ruma::api::client::error::ErrorKind;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+use crate::{service::state_file::StateFile, Error, Result};
+
+/// Sessions of a backup, keyed by room id then session id
+pub type BackupKeys = BTreeMap<String, BTreeMap<String, Value>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Backup {
+    algorithm: Value,
+    auth_data: Value,
+    /// Bumped on every change to the stored keys
+    etag: u64,
+    keys: BackupKeys,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct UserBackups {
+    /// Last version number handed out; versions are never reused
+    last_version: u64,
+    versions: BTreeMap<u64, Backup>,
+}
+
+/// Room key backup service
+#[derive(Debug, Default)]
+pub struct Service {
+    users: RwLock<HashMap<String, UserBackups>>,
+    state_file: Option<StateFile>,
+    /// Backups of users for the task writing them to the repository, in
+    /// the order they changed
+    writes: Option<mpsc::UnboundedSender<(String, Value)>>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep the backups in the file at `path`, loading the ones stored there
+    pub fn with_state_file(mut self, path: Option<PathBuf>) -> Self {
+        if let Some(path) = path {
+            let state_file = StateFile::new(path);
+            if let Some(users) = state_file.load() {
+                self.users = RwLock::new(users);
+            }
+            self.state_file = Some(state_file);
+        }
+        self
+    }
+
+    /// Write backups through to `repository`, from a task spawned now
+    pub fn with_repository(mut self, repository: RoomKeyBackupRepo) -> Self {
+        let (writes, mut queued) = mpsc::unbounded_channel::<(String, Value)>();
+        tokio::spawn(async move {
+            while let Some((user_id, backups)) = queued.recv().await {
+                if let Err(e) = repository.save(&user_id, &backups).await {
+                    warn!("⚠️ Could not store the key backups of {}: {}", user_id, e);
+                }
+            }
+        });
+        self.writes = Some(writes);
+        self
+    }
+
+    /// Load the backups stored in the repository, returning for how many
+    /// users
+    pub async fn load(&self, repository: &RoomKeyBackupRepo) -> Result<usize> {
+        let records = repository.load().await.map_err(|e| Error::BadDatabase(e.to_string()))?;
+        let loaded = records.len();
+        self.restore(records);
+        info!("📥 Loaded the key backups of {} users", loaded);
+        Ok(loaded)
+    }
+
+    /// Put back backups loaded from the repository, without storing them
+    /// again
+    pub fn restore(&self, records: impl IntoIterator<Item = RoomKeyBackupRecord>) {
+        let mut users = self.users.write().unwrap();
+        for record in records {
+            match serde_json::from_value(record.backups) {
+                Ok(backups) => {
+                    users.insert(record.user_id, backups);
+                }
+                Err(e) => warn!("⚠️ Ignoring invalid key backups of {}: {}", record.user_id, e),
+            }
+        }
+    }
+
+    /// Change the backups of `user_id`, storing them if `f` succeeds
+    fn update<T>(&self, user_id: &str, f: impl FnOnce(&mut UserBackups) -> Result<T>) -> Result<T> {
+        let mut users = self.users.write().unwrap();
+        let result = f(users.entry(user_id.to_owned()).or_default())?;
+        if let Some(writes) = &self.writes {
+            // Queued under the lock, after the changes before this one
+            let _ = writes.send((user_id.to_owned(), serde_json::to_value(&users[user_id]).expect("backups serialize")));
+        }
+        let snapshot = self.state_file.as_ref().map(|state_file| (state_file, state_file.snapshot(&*users)));
+        drop(users);
+        if let Some((state_file, snapshot)) = snapshot {
+            state_file.write(snapshot);
+        }
+        Ok(result)
+    }
+
+    /// Create a new backup version, which becomes the current one
+    pub fn create_version(&self, user_id: &str, algorithm: Value, auth_data: Value) -> Result<String> {
+        self.update(user_id, |backups| {
+            backups.last_version += 1;
+            backups.versions.insert(
+                backups.last_version,
+                Backup {
+                    algorithm,
+                    auth_data,
+                    etag: 0,
+                    keys: BackupKeys::new(),
+                },
+            );
+            debug!("🗝️ Created key backup version {} for {}", backups.last_version, user_id);
+            Ok(backups.last_version.to_string())
+        })
+    }
+
+    /// Update `auth_data` of an existing version. The algorithm cannot change.
+    pub fn update_version(&self, user_id: &str, version: &str, algorithm: &Value, auth_data: Value) -> Result<()> {
+        self.update(user_id, |backups| {
+            let backup = get_backup_mut(backups, version)?;
+            if &backup.algorithm != algorithm {
+                return Err(Error::BadRequest(
+                    ErrorKind::InvalidParam,
+                    "Algorithm of a backup version cannot be changed",
+                ));
+            }
+            backup.auth_data = auth_data;
+            Ok(())
+        })
+    }
+
+    pub fn delete_version(&self, user_id: &str, version: &str) -> Result<()> {
+        let number = parse_version(version)?;
+        self.update(user_id, |backups| backups.versions.remove(&number).map(|_| ()).ok_or_else(unknown_version))
+    }
+
+    /// Backup info as returned by `GET /room_keys/version[/{version}]`.
+    /// `None` selects the latest version.
+    pub fn get_version_info(&self, user_id: &str, version: Option<&str>) -> Result<Value> {
+        let users = self.users.read().unwrap();
+        let backups = users.get(user_id).ok_or_else(unknown_version)?;
+        let (number, backup) = match version {
+            Some(version) => {
+                let number = parse_version(version)?;
+                (number, backups.versions.get(&number).ok_or_else(unknown_version)?)
+            }
+            None => backups.versions.iter().next_back().map(|(n, b)| (*n, b)).ok_or_else(unknown_version)?,
+        };
+
+        Ok(json!({
+            "algorithm": backup.algorithm,
+            "auth_data": backup.auth_data,
+            "count": count_keys(&backup.keys),
+            "etag": backup.etag.to_string(),
+            "version": number.to_string()
+        }))
+    }
+
+    /// Store session keys in the current backup version.
+    ///
+    /// An existing session is only replaced by a "better" one: verified over
+    /// unverified, then a lower `first_message_index`, then a lower
+    /// `forwarded_count`. Returns `(count, etag)` of the backup.
+    pub fn add_keys(&self, user_id: &str, version: &str, keys: BackupKeys) -> Result<(u64, String)> {
+        let number = parse_version(version)?;
+        self.update(user_id, |backups| {
+            let current = backups.versions.keys().next_back().copied();
+            if current != Some(number) {
+                return Err(Error::BadRequest(
+                    ErrorKind::WrongRoomKeysVersion {
+                        current_version: current.map(|v| v.to_string()),
+                    },
+                    "Keys can only be added to the current backup version",
+                ));
+            }
+
+            let backup = get_backup_mut(backups, version)?;
+            let mut changed = false;
+            for (room_id, sessions) in keys {
+                let stored_sessions = backup.keys.entry(room_id).or_default();
+                for (session_id, key_data) in sessions {
+                    let replace = stored_sessions
+                        .get(&session_id)
+                        .is_none_or(|existing| is_better_key(&key_data, existing));
+                    if replace {
+                        stored_sessions.insert(session_id, key_data);
+                        changed = true;
+                    }
+                }
+            }
+            if changed {
+                backup.etag += 1;
+            }
+            Ok((count_keys(&backup.keys), backup.etag.to_string()))
+        })
+    }
+
+    /// Keys of a backup version, optionally narrowed to a room and session
+    pub fn get_keys(&self, user_id: &str, version: &str, room_id: Option<&str>, session_id: Option<&str>) -> Result<BackupKeys> {
+        let users = self.users.read().unwrap();
+        let number = parse_version(version)?;
+        let backup = users
+            .get(user_id)
+            .and_then(|backups| backups.versions.get(&number))
+            .ok_or_else(unknown_version)?;
+
+        Ok(backup
+            .keys
+            .iter()
+            .filter(|(room, _)| room_id.is_none_or(|r| r == room.as_str()))
+            .map(|(room, sessions)| {
+                let sessions = sessions
+                    .iter()
+                    .filter(|(session, _)| session_id.is_none_or(|s| s == session.as_str()))
+                    .map(|(session, data)| (session.clone(), data.clone()))
+                    .collect();
+                (room.clone(), sessions)
+            })
+            .collect())
+    }
+
+    /// Delete keys of a backup version, optionally narrowed to a room and
+    /// session. Returns `(count, etag)` of the backup.
+    pub fn delete_keys(&self, user_id: &str, version: &str, room_id: Option<&str>, session_id: Option<&str>) -> Result<(u64, String)> {
+        self.update(user_id, |backups| {
+            let backup = get_backup_mut(backups, version)?;
+            let before = count_keys(&backup.keys);
+
+            match (room_id, session_id) {
+                (None, _) => backup.keys.clear(),
+                (Some(room_id), None) => {
+                    backup.keys.remove(room_id);
+                }
+                (Some(room_id), Some(session_id)) => {
+                    if let Some(sessions) = backup.keys.get_mut(room_id) {
+                        sessions.remove(session_id);
+                        if sessions.is_empty() {
+                            backup.keys.remove(room_id);
+                        }
+                    }
+                }
+            }
+
+            let count = count_keys(&backup.keys);
+            if count != before {
+                backup.etag += 1;
+            }
+            Ok((count, backup.etag.to_string()))
+        })
+    }
+}
+
+fn get_backup_mut<'a>(backups: &'a mut UserBackups, version: &str) -> Result<&'a mut Backup> {
+    let number = parse_version(version)?;
+    backups.versions.get_mut(&number).ok_or_else(unknown_version)
+}
+
+fn parse_version(version: &str) -> Result<u64> {
+    version.parse().map_err(|_| unknown_version())
+}
+
+fn unknown_version() -> Error {
+    Error::BadRequest(ErrorKind::NotFound, "Unknown backup version")
+}
+
+fn count_keys(keys: &BackupKeys) -> u64 {
+    keys.values().map(|sessions| sessions.len() as u64).sum()
+}
+
+/// Whether `new` should replace `existing` in the backup
+fn is_better_key(new: &Value, existing: &Value) -> bool {
+    let verified = |v: &Value| v["is_verified"].as_bool().unwrap_or(false);
+    let first_index = |v: &Value| v["first_message_index"].as_u64().unwrap_or(u64::MAX);
+    let forwarded = |v: &Value| v["forwarded_count"].as_u64().unwrap_or(u64::MAX);
+
+    if verified(new) != verified(existing) {
+        return verified(new);
+    }
+    if first_index(new) != first_index(existing) {
+        return first_index(new) < first_index(existing);
+    }
+    forwarded(new) < forwarded(existing)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: &str = "@alice:matrixon.local";
+
+    fn keys(room: &str, session: &str, data: Value) -> BackupKeys {
+        BTreeMap::from([(room.to_owned(), BTreeMap::from([(session.to_owned(), data)]))])
+    }
+
+    #[test]
+    fn test_keys_only_added_to_current_version() {
+        let service = Service::new();
+        let old = service.create_version(USER, json!("m.megolm_backup.v1.curve25519-aes-sha2"), json!({})).unwrap();
+        let new = service.create_version(USER, json!("m.megolm_backup.v1.curve25519-aes-sha2"), json!({})).unwrap();
+
+        let data = json!({ "first_message_index": 0, "forwarded_count": 0, "is_verified": true });
+        assert!(service.add_keys(USER, &old, keys("!r", "s", data.clone())).is_err());
+        assert_eq!(service.add_keys(USER, &new, keys("!r", "s", data)).unwrap().0, 1);
+        assert_eq!(service.get_version_info(USER, None).unwrap()["version"], new);
+    }
+
+    #[test]
+    fn test_better_key_wins() {
+        let service = Service::new();
+        let version = service.create_version(USER, json!("alg"), json!({})).unwrap();
+        let worse = json!({ "first_message_index": 5, "forwarded_count": 0, "is_verified": false, "session_data": "worse" });
+        let better = json!({ "first_message_index": 1, "forwarded_count": 0, "is_verified": false, "session_data": "better" });
+
+        service.add_keys(USER, &version, keys("!r", "s", better)).unwrap();
+        let (_, etag) = service.add_keys(USER, &version, keys("!r", "s", worse)).unwrap();
+        assert_eq!(etag, "1");
+
+        let stored = service.get_keys(USER, &version, Some("!r"), Some("s")).unwrap();
+        assert_eq!(stored["!r"]["s"]["session_data"], "better");
+
+        assert_eq!(service.delete_keys(USER, &version, Some("!r"), None).unwrap().0, 0);
+    }
+
+    #[test]
+    fn test_backups_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("room_key_backups.json");
+        let service = Service::new().with_state_file(Some(path.clone()));
+        let deleted = service.create_version(USER, json!("alg"), json!({})).unwrap();
+        service.delete_version(USER, &deleted).unwrap();
+        let version = service.create_version(USER, json!("alg"), json!({ "public_key": "abc" })).unwrap();
+        let data = json!({ "first_message_index": 0, "forwarded_count": 0, "is_verified": true, "session_data": "secret" });
+        service.add_keys(USER, &version, keys("!r", "s", data)).unwrap();
+
+        let restarted = Service::new().with_state_file(Some(path));
+        let info = restarted.get_version_info(USER, None).unwrap();
+        assert_eq!(info["version"], version);
+        assert_eq!(info["auth_data"]["public_key"], "abc");
+        assert_eq!(info["etag"], "1");
+        assert_eq!(restarted.get_keys(USER, &version, None, None).unwrap()["!r"]["s"]["session_data"], "secret");
+        // Versions are not reused after a restart either
+        assert_eq!(restarted.create_version(USER, json!("alg"), json!({})).unwrap(), "3");
+
+        let record = RoomKeyBackupRecord {
+            user_id: USER.to_owned(),
+            backups: serde_json::to_value(&restarted.users.read().unwrap()[USER]).unwrap(),
+        };
+        let loaded = Service::new();
+        loaded.restore([record]);
+        assert_eq!(loaded.get_version_info(USER, None).unwrap()["version"], "3");
+    }
+}
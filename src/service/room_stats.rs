@@ -0,0 +1,294 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Room Statistics
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Opt-in daily statistics of rooms for community health dashboards:
+//   messages, reactions and active users per room and UTC day, aggregated
+//   from the event stream. Only counts are kept. Senders are remembered as
+//   keyed hashes for counting distinct users, and days with fewer active
+//   users than configured are left out of the admin API so small rooms
+//   cannot be singled out. The statistics, the position in the stream and
+//   the hash key are kept in a state file readable only by the server, so
+//   days are neither lost nor counted twice across restarts.
+//
+// =============================================================================
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+    sync::RwLock,
+    time::Duration,
+};
+
+use chrono::{DateTime, NaiveDate, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+use tracing::{debug, info};
+
+use crate::{config::RoomStatsConfig, service::state_file::StateFile, services};
+
+/// Events read from the stream at a time
+const BATCH_SIZE: usize = 500;
+
+/// Statistics of a room on one UTC day
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DailyStats {
+    pub room_id: String,
+    /// `YYYY-MM-DD`
+    pub day: NaiveDate,
+    /// Messages, stickers and encrypted events
+    pub messages: u64,
+    /// Reactions of unencrypted rooms; those of encrypted rooms are counted
+    /// as messages since their type is not visible to the server
+    pub reactions: u64,
+    /// Distinct senders of messages and reactions
+    pub active_users: usize,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Day {
+    messages: u64,
+    reactions: u64,
+    senders: HashSet<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    /// Keys the sender hashes
+    key: Vec<u8>,
+    /// Stream count of the last aggregated event
+    position: u64,
+    /// Days by room
+    rooms: BTreeMap<String, BTreeMap<NaiveDate, Day>>,
+}
+
+/// Room statistics service
+#[derive(Debug)]
+pub struct Service {
+    config: RoomStatsConfig,
+    state: RwLock<State>,
+    stats_file: Option<StateFile>,
+}
+
+impl Service {
+    pub fn new(config: RoomStatsConfig) -> Self {
+        let mut key = vec![0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        Self {
+            config,
+            state: RwLock::new(State { key, position: 0, rooms: BTreeMap::new() }),
+            stats_file: None,
+        }
+    }
+
+    /// Keep the statistics in the file at `path`
+    pub fn with_stats_file(mut self, path: Option<PathBuf>) -> Self {
+        if let Some(path) = path {
+            let stats_file = StateFile::new(path);
+            if let Some(state) = stats_file.load() {
+                self.state = RwLock::new(state);
+            }
+            self.stats_file = Some(stats_file);
+        }
+        self
+    }
+
+    fn save(&self) {
+        if let Some(stats_file) = &self.stats_file {
+            let snapshot = stats_file.snapshot(&*self.state.read().unwrap());
+            stats_file.write(snapshot);
+        }
+    }
+
+    fn sender_hash(key: &[u8], sender: &str) -> u64 {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+        mac.update(sender.as_bytes());
+        let hash = mac.finalize().into_bytes();
+        u64::from_le_bytes(hash[..8].try_into().expect("SHA-256 is longer than 8 bytes"))
+    }
+
+    /// Stream count of the last aggregated event
+    pub fn position(&self) -> u64 {
+        self.state.read().unwrap().position
+    }
+
+    /// Record that the events up to stream count `position` are aggregated,
+    /// saving the statistics
+    pub fn advance(&self, position: u64) {
+        self.state.write().unwrap().position = position;
+        self.save();
+    }
+
+    /// Count an event in the statistics of its room and day
+    pub fn record(&self, event: &Value) {
+        let is_reaction = match event["type"].as_str() {
+            Some("m.reaction") => true,
+            Some("m.room.message" | "m.room.encrypted" | "m.sticker") => false,
+            _ => return,
+        };
+        if event.get("state_key").is_some() {
+            return;
+        }
+        let (Some(room_id), Some(sender), Some(day)) = (
+            event["room_id"].as_str(),
+            event["sender"].as_str(),
+            event["origin_server_ts"].as_i64().and_then(DateTime::from_timestamp_millis),
+        ) else {
+            return;
+        };
+
+        let mut state = self.state.write().unwrap();
+        let sender = Self::sender_hash(&state.key, sender);
+        let stats = state.rooms.entry(room_id.to_owned()).or_default().entry(day.date_naive()).or_default();
+        if is_reaction {
+            stats.reactions += 1;
+        } else {
+            stats.messages += 1;
+        }
+        stats.senders.insert(sender);
+    }
+
+    /// Statistics of `room_id`, or of every room, for the days from `from`
+    /// to `to` inclusive, by room and then day
+    pub fn query(&self, room_id: Option<&str>, from: Option<NaiveDate>, to: Option<NaiveDate>) -> Vec<DailyStats> {
+        self.state
+            .read()
+            .unwrap()
+            .rooms
+            .iter()
+            .filter(|(room, _)| room_id.is_none_or(|room_id| *room == room_id))
+            .flat_map(|(room, days)| days.iter().map(move |(day, stats)| (room, day, stats)))
+            .filter(|(_, day, stats)| {
+                from.is_none_or(|from| **day >= from)
+                    && to.is_none_or(|to| **day <= to)
+                    && stats.senders.len() >= self.config.min_active_users
+            })
+            .map(|(room, day, stats)| DailyStats {
+                room_id: room.clone(),
+                day: *day,
+                messages: stats.messages,
+                reactions: stats.reactions,
+                active_users: stats.senders.len(),
+            })
+            .collect()
+    }
+
+    /// Forget days past the retention period, returning how many room days
+    /// were removed
+    pub fn prune(&self, today: NaiveDate) -> usize {
+        let oldest = today - chrono::Duration::days(i64::from(self.config.retention_days));
+        let mut pruned = 0;
+        {
+            let mut state = self.state.write().unwrap();
+            state.rooms.retain(|_, days| {
+                let before = days.len();
+                days.retain(|day, _| *day > oldest);
+                pruned += before - days.len();
+                !days.is_empty()
+            });
+        }
+        if pruned > 0 {
+            self.save();
+        }
+        pruned
+    }
+}
+
+/// Aggregate the event stream forever, starting with the events already
+/// stored
+pub async fn run() {
+    let Some(stats) = &services().room_stats else {
+        return;
+    };
+    let timeline = &services().timeline;
+    info!("📊 Aggregating room statistics, kept for {} days", stats.config.retention_days);
+
+    loop {
+        let batch = timeline.stream_since(stats.position(), BATCH_SIZE);
+        if batch.is_empty() {
+            let pruned = stats.prune(Utc::now().date_naive());
+            if pruned > 0 {
+                debug!("📊 Pruned {} expired room days", pruned);
+            }
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            continue;
+        }
+
+        let mut position = stats.position();
+        for (count, event) in batch {
+            stats.record(&event);
+            position = count;
+        }
+        stats.advance(position);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn event(event_type: &str, room_id: &str, sender: &str, ts: i64) -> Value {
+        json!({ "type": event_type, "room_id": room_id, "sender": sender, "origin_server_ts": ts, "content": { "body": "secret" } })
+    }
+
+    #[test]
+    fn test_daily_aggregation() {
+        let stats = Service::new(RoomStatsConfig { min_active_users: 2, ..Default::default() });
+        // 2024-12-11 and 2024-12-12, UTC
+        let (first, second) = (1_733_875_200_000, 1_733_961_600_000);
+        stats.record(&event("m.room.message", "!a:matrixon.local", "@alice:matrixon.local", first));
+        stats.record(&event("m.room.message", "!a:matrixon.local", "@alice:matrixon.local", first + 1));
+        stats.record(&event("m.reaction", "!a:matrixon.local", "@bob:matrixon.local", first + 2));
+        stats.record(&event("m.room.encrypted", "!a:matrixon.local", "@alice:matrixon.local", second));
+        stats.record(&event("m.room.message", "!a:matrixon.local", "@bob:matrixon.local", second));
+        stats.record(&event("m.room.message", "!b:matrixon.local", "@alice:matrixon.local", first));
+        let mut name = event("m.room.name", "!a:matrixon.local", "@carol:matrixon.local", first);
+        name["state_key"] = json!("");
+        stats.record(&name);
+
+        let all = stats.query(None, None, None);
+        // The single active user of !b is not reported
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].day.to_string(), "2024-12-11");
+        assert_eq!((all[0].messages, all[0].reactions, all[0].active_users), (2, 1, 2));
+        assert_eq!((all[1].messages, all[1].reactions, all[1].active_users), (2, 0, 2));
+        assert!(!serde_json::to_string(&all).unwrap().contains("secret"));
+
+        let day = all[1].day;
+        assert_eq!(stats.query(Some("!a:matrixon.local"), Some(day), None), vec![all[1].clone()]);
+        assert!(stats.query(Some("!b:matrixon.local"), None, None).is_empty());
+
+        assert_eq!(stats.prune(all[0].day + chrono::Duration::days(i64::from(RoomStatsConfig::default().retention_days))), 2);
+        assert_eq!(stats.query(None, None, None).len(), 1);
+    }
+
+    #[test]
+    fn test_statistics_are_persisted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("room_stats.json");
+        let config = RoomStatsConfig { min_active_users: 1, ..Default::default() };
+        let ts = 1_733_875_200_000;
+
+        let stats = Service::new(config.clone()).with_stats_file(Some(path.clone()));
+        stats.record(&event("m.room.message", "!a:matrixon.local", "@alice:matrixon.local", ts));
+        stats.advance(7);
+
+        // The same sender is still counted once after a restart
+        let stats = Service::new(config).with_stats_file(Some(path));
+        assert_eq!(stats.position(), 7);
+        stats.record(&event("m.room.message", "!a:matrixon.local", "@alice:matrixon.local", ts + 1));
+        let all = stats.query(None, None, None);
+        assert_eq!((all[0].messages, all[0].active_users), (2, 1));
+    }
+}
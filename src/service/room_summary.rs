@@ -0,0 +1,311 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Room Summaries
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Room summaries (heroes and member counts) for /sync, and batching of
+//   membership state so very large rooms do not send their full member
+//   list on every sync. Clients complete the member list through /members.
+//
+//   Summaries are maintained incrementally: each room keeps a membership
+//   index that only applies the member events appended since it was last
+//   brought up to date, either by a sync or by the background refresh.
+//
+// =============================================================================
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+use serde_json::{json, Value};
+use tracing::debug;
+
+use crate::{service::timeline, services};
+
+/// Number of heroes included in a summary, as recommended by the spec
+const MAX_HEROES: usize = 5;
+
+/// Summary of a room's membership as seen by one user
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomSummary {
+    pub heroes: Vec<String>,
+    pub joined_member_count: u64,
+    pub invited_member_count: u64,
+}
+
+impl RoomSummary {
+    /// The `summary` object of a joined room in /sync
+    pub fn to_sync_json(&self) -> Value {
+        json!({
+            "m.heroes": self.heroes,
+            "m.joined_member_count": self.joined_member_count,
+            "m.invited_member_count": self.invited_member_count
+        })
+    }
+}
+
+/// Membership index of one room
+#[derive(Debug, Default)]
+struct RoomMembers {
+    /// Stream count up to which member events have been applied
+    processed: u64,
+    /// Members in the order they first appeared in the room
+    order: Vec<String>,
+    membership: HashMap<String, String>,
+    joined: u64,
+    invited: u64,
+}
+
+impl RoomMembers {
+    fn apply(&mut self, user_id: &str, membership: &str) {
+        let previous = self.membership.insert(user_id.to_owned(), membership.to_owned());
+        match previous.as_deref() {
+            None => self.order.push(user_id.to_owned()),
+            Some("join") => self.joined -= 1,
+            Some("invite") => self.invited -= 1,
+            Some(_) => {}
+        }
+        match membership {
+            "join" => self.joined += 1,
+            "invite" => self.invited += 1,
+            _ => {}
+        }
+    }
+
+    /// Summary for `user_id`. Heroes are the longest-standing joined or
+    /// invited members other than the user, falling back to members who
+    /// left when nobody else is in the room.
+    fn summary(&self, user_id: &str, with_heroes: bool) -> RoomSummary {
+        let mut summary = RoomSummary {
+            heroes: Vec::new(),
+            joined_member_count: self.joined,
+            invited_member_count: self.invited,
+        };
+        if !with_heroes {
+            return summary;
+        }
+
+        let others = || self.order.iter().filter(|member| *member != user_id);
+        let with_membership = |memberships: &[&str]| {
+            others()
+                .filter(|member| memberships.contains(&self.membership[*member].as_str()))
+                .take(MAX_HEROES)
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        summary.heroes = with_membership(&["join", "invite"]);
+        if summary.heroes.is_empty() {
+            summary.heroes = with_membership(&["leave", "ban"]);
+        }
+        summary
+    }
+}
+
+/// Room summary service
+#[derive(Debug, Default)]
+pub struct Service {
+    rooms: RwLock<HashMap<String, RoomMembers>>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Summary of a room for `user_id`. Heroes are only computed for rooms
+    /// without a name or canonical alias, where clients need them.
+    pub fn summary(&self, room_id: &str, user_id: &str) -> RoomSummary {
+        let timeline = &services().timeline;
+        self.catch_up(timeline, room_id);
+        let named = timeline.state_event(room_id, "m.room.name", "").is_some_and(|e| e["content"]["name"].is_string())
+            || timeline
+                .state_event(room_id, "m.room.canonical_alias", "")
+                .is_some_and(|e| e["content"]["alias"].is_string());
+
+        self.rooms
+            .read()
+            .unwrap()
+            .get(room_id)
+            .map(|members| members.summary(user_id, !named))
+            .unwrap_or_default()
+    }
+
+    /// Bring a room's index up to date
+    pub fn refresh(&self, room_id: &str) {
+        self.catch_up(&services().timeline, room_id);
+    }
+
+    /// Drop the index of a deleted room
+    pub fn remove(&self, room_id: &str) {
+        self.rooms.write().unwrap().remove(room_id);
+    }
+
+    /// Bring every room's index up to date; run periodically in the background
+    pub fn refresh_all(&self) {
+        let timeline = &services().timeline;
+        for room_id in timeline.room_ids() {
+            self.catch_up(timeline, &room_id);
+        }
+    }
+
+    /// Apply the member events appended to a room since its last update
+    fn catch_up(&self, timeline: &timeline::Service, room_id: &str) {
+        let processed = self.rooms.read().unwrap().get(room_id).map_or(0, |members| members.processed);
+        let (events, last_count) = timeline.state_events_since(room_id, "m.room.member", processed);
+        if last_count == processed {
+            return;
+        }
+
+        let mut rooms = self.rooms.write().unwrap();
+        let members = rooms.entry(room_id.to_owned()).or_default();
+        // Another caller may have caught up in the meantime
+        if members.processed != processed {
+            return;
+        }
+        for (user_id, membership) in memberships(&events) {
+            members.apply(user_id, membership);
+        }
+        members.processed = last_count;
+        debug!("👥 Applied {} member events to summary of {}", events.len(), room_id);
+    }
+}
+
+/// Reduce the membership part of `state` for a room with more members than
+/// `threshold`: only the user's own membership and those of `relevant`
+/// users (e.g. timeline senders) are kept. Returns the remaining state and
+/// the number of membership events left out.
+pub fn batch_member_state(state: Vec<Value>, user_id: &str, relevant: &HashSet<&str>, threshold: usize) -> (Vec<Value>, usize) {
+    let member_count = memberships(&state).count();
+    if member_count <= threshold {
+        return (state, 0);
+    }
+
+    let before = state.len();
+    let kept: Vec<Value> = state
+        .into_iter()
+        .filter(|event| {
+            if event["type"] != "m.room.member" {
+                return true;
+            }
+            let member = event["state_key"].as_str().unwrap_or_default();
+            member == user_id || relevant.contains(member)
+        })
+        .collect();
+    let omitted = before - kept.len();
+    (kept, omitted)
+}
+
+/// Reduce the membership part of `state` for a client lazy-loading
+/// members: only the memberships of the user and of `senders` are kept.
+/// Senders whose membership is not in `state` are added with `lookup`
+/// unless `is_known` says the client has it already.
+pub fn lazy_member_state(
+    state: Vec<Value>,
+    user_id: &str,
+    senders: &[&str],
+    is_known: impl Fn(&str) -> bool,
+    lookup: impl Fn(&str) -> Option<Value>,
+) -> Vec<Value> {
+    let mut state: Vec<Value> = state
+        .into_iter()
+        .filter(|event| {
+            if event["type"] != "m.room.member" {
+                return true;
+            }
+            let member = event["state_key"].as_str().unwrap_or_default();
+            member == user_id || senders.contains(&member)
+        })
+        .collect();
+    let included: HashSet<String> = memberships(&state).map(|(member, _)| member.to_owned()).collect();
+    for sender in senders {
+        if !included.contains(*sender) && !is_known(sender) {
+            state.extend(lookup(sender));
+        }
+    }
+    state
+}
+
+/// `(user_id, membership)` of every member event in `state`
+fn memberships(state: &[Value]) -> impl Iterator<Item = (&str, &str)> {
+    state.iter().filter(|event| event["type"] == "m.room.member").filter_map(|event| {
+        Some((event["state_key"].as_str()?, event["content"]["membership"].as_str()?))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member(user_id: &str, membership: &str) -> Value {
+        json!({ "type": "m.room.member", "state_key": user_id, "content": { "membership": membership } })
+    }
+
+    #[test]
+    fn test_summary_counts_and_heroes() {
+        let timeline = timeline::Service::new();
+        let room = "!room:matrixon.local";
+        for (user, membership) in [("@me", "join"), ("@a", "join"), ("@b", "invite"), ("@c", "join")] {
+            timeline.append_event(room, user, "m.room.member", Some(user), json!({ "membership": membership }));
+        }
+
+        let service = Service::new();
+        service.catch_up(&timeline, room);
+        let summary = service.rooms.read().unwrap()[room].summary("@me", true);
+        assert_eq!(summary.joined_member_count, 3);
+        assert_eq!(summary.invited_member_count, 1);
+        assert_eq!(summary.heroes, vec!["@a", "@b", "@c"]);
+
+        // Only new membership changes are applied
+        timeline.append_event(room, "@a", "m.room.member", Some("@a"), json!({ "membership": "leave" }));
+        timeline.append_event(room, "@b", "m.room.member", Some("@b"), json!({ "membership": "join" }));
+        service.catch_up(&timeline, room);
+        let summary = service.rooms.read().unwrap()[room].summary("@me", true);
+        assert_eq!((summary.joined_member_count, summary.invited_member_count), (3, 0));
+        assert_eq!(summary.heroes, vec!["@b", "@c"]);
+    }
+
+    #[test]
+    fn test_former_members_are_heroes_of_empty_rooms() {
+        let mut members = RoomMembers::default();
+        members.apply("@me", "join");
+        members.apply("@c", "join");
+        members.apply("@c", "leave");
+        assert_eq!(members.summary("@me", true).heroes, vec!["@c"]);
+        assert!(members.summary("@me", false).heroes.is_empty());
+    }
+
+    #[test]
+    fn test_large_rooms_omit_irrelevant_members() {
+        let mut state = vec![json!({ "type": "m.room.name", "state_key": "", "content": { "name": "Big" } })];
+        state.extend((0..10).map(|i| member(&format!("@u{}:matrixon.local", i), "join")));
+        state.push(member("@me:matrixon.local", "join"));
+
+        let relevant = HashSet::from(["@u3:matrixon.local"]);
+        let (kept, omitted) = batch_member_state(state.clone(), "@me:matrixon.local", &relevant, 5);
+        assert_eq!(omitted, 9);
+        assert_eq!(kept.len(), 3);
+
+        let (kept, omitted) = batch_member_state(state, "@me:matrixon.local", &relevant, 100);
+        assert_eq!((kept.len(), omitted), (12, 0));
+    }
+
+    #[test]
+    fn test_lazy_loading_sends_unknown_senders_only() {
+        let mut state = vec![json!({ "type": "m.room.name", "state_key": "", "content": { "name": "Big" } })];
+        state.extend(["@me", "@a", "@b"].map(|user| member(user, "join")));
+
+        let senders = ["@a", "@c", "@d"];
+        let kept = lazy_member_state(state, "@me", &senders, |user| user == "@d", |user| Some(member(user, "join")));
+        let members: Vec<&str> = kept.iter().filter_map(|event| event["state_key"].as_str()).collect();
+        // The room name, the user, a sender from the state and an unknown
+        // sender looked up; the known sender is left out
+        assert_eq!(members, ["", "@me", "@a", "@c"]);
+    }
+}
@@ -0,0 +1,67 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Room Versions
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   The room versions this server supports. Room creation, joins over
+//   federation and the capabilities advertised to clients all read this one
+//   registry, so a client is never offered a version a join then rejects.
+//
+// =============================================================================
+
+use ruma::{api::client::error::ErrorKind, RoomVersionId};
+use serde_json::{json, Map, Value};
+
+use crate::{Error, Result};
+
+/// Room versions this server can participate in, all stable
+pub const SUPPORTED_ROOM_VERSIONS: &[&str] = &["1", "2", "3", "4", "5", "6", "7", "8", "9", "10"];
+
+/// Version of rooms created without asking for one
+pub const DEFAULT_ROOM_VERSION: &str = "10";
+
+pub fn is_supported(room_version: &str) -> bool {
+    SUPPORTED_ROOM_VERSIONS.contains(&room_version)
+}
+
+/// The version, if this server supports it, or `M_UNSUPPORTED_ROOM_VERSION`
+pub fn check_supported(room_version: &str) -> Result<RoomVersionId> {
+    if !is_supported(room_version) {
+        return Err(Error::BadRequest(
+            ErrorKind::UnsupportedRoomVersion,
+            "This server does not support the version of this room",
+        ));
+    }
+    Ok(RoomVersionId::try_from(room_version).expect("supported room versions are valid"))
+}
+
+/// The `m.room_versions` capability
+pub fn capability() -> Value {
+    let available: Map<String, Value> =
+        SUPPORTED_ROOM_VERSIONS.iter().map(|version| (version.to_string(), json!("stable"))).collect();
+    json!({ "default": DEFAULT_ROOM_VERSION, "available": available })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capability_matches_checks() {
+        let capability = capability();
+        assert!(is_supported(capability["default"].as_str().unwrap()));
+        for version in capability["available"].as_object().unwrap().keys() {
+            assert!(check_supported(version).is_ok());
+        }
+        assert!(matches!(
+            check_supported("org.example.custom"),
+            Err(Error::BadRequest(ErrorKind::UnsupportedRoomVersion, _))
+        ));
+    }
+}
@@ -0,0 +1,513 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Room Webhooks
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Outgoing webhooks registered by room admins. Events appended to a room
+//   after a webhook was registered are matched against its filters (event
+//   types, senders and keywords of the message body) and POSTed to its URL,
+//   signed like the server's lifecycle webhooks with the webhook's secret
+//   and retried with backoff. Deliveries of a room share a rate cap; events
+//   over it are dropped and counted. Webhooks to loopback, private and
+//   link-local addresses are refused unless allowed; host names are resolved
+//   again at every delivery and pinned to public addresses, and redirects
+//   are not followed. Webhooks whose admin left the room or lost the power
+//   to manage it are removed instead of delivered. Webhooks are kept in a
+//   state file when one is configured.
+//
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    net::IpAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock, RwLockWriteGuard,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use matrixon_common::rate_limit::RateLimiter;
+use matrixon_core::webhooks::{post_signed, WebhookConfig};
+use rand::{distributions::Alphanumeric, Rng};
+use ruma::api::client::error::ErrorKind;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    config::RoomWebhooksConfig,
+    service::{membership, outbound_http, state_file::StateFile},
+    services, Error, Result,
+};
+
+/// Events read from the stream at a time
+const BATCH_SIZE: usize = 500;
+
+/// Name of room event payloads, sent in the event header
+const EVENT_NAME: &str = "room_event";
+
+/// Timeout of each delivery attempt
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Which events of its room a webhook is sent; every filter left empty
+/// matches all events
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct WebhookFilter {
+    /// Event types, or prefixes of them ending in `*` like `m.room.*`
+    #[serde(default)]
+    pub types: Vec<String>,
+    #[serde(default)]
+    pub senders: Vec<String>,
+    /// Words the `body` of the event's content contains, ignoring case
+    #[serde(default)]
+    pub keywords: Vec<String>,
+}
+
+impl WebhookFilter {
+    pub fn matches(&self, event: &Value) -> bool {
+        let event_type = event["type"].as_str().unwrap_or_default();
+        let sender = event["sender"].as_str().unwrap_or_default();
+        let types = self.types.is_empty()
+            || self.types.iter().any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => event_type.starts_with(prefix),
+                None => pattern == event_type,
+            });
+        let senders = self.senders.is_empty() || self.senders.iter().any(|user_id| user_id == sender);
+        let keywords = self.keywords.is_empty()
+            || event["content"]["body"].as_str().is_some_and(|body| {
+                let body = body.to_lowercase();
+                self.keywords.iter().any(|keyword| body.contains(&keyword.to_lowercase()))
+            });
+        types && senders && keywords
+    }
+}
+
+/// A webhook of a room, serialized without its secret
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RoomWebhook {
+    pub webhook_id: String,
+    pub room_id: String,
+    pub url: String,
+    #[serde(skip)]
+    pub secret: String,
+    #[serde(flatten)]
+    pub filter: WebhookFilter,
+    /// Admin who registered the webhook
+    pub created_by: String,
+    pub created_at: u64,
+}
+
+/// A webhook as kept in the state file, with its secret
+#[derive(Deserialize, Serialize)]
+struct StoredWebhook {
+    #[serde(flatten)]
+    webhook: RoomWebhook,
+    secret: String,
+}
+
+/// Room webhook service
+#[derive(Debug)]
+pub struct Service {
+    config: RoomWebhooksConfig,
+    server_name: String,
+    /// Room -> its webhooks, oldest first
+    webhooks: RwLock<HashMap<String, Vec<RoomWebhook>>>,
+    /// Deliveries per room
+    limiter: RateLimiter<String>,
+    client: reqwest::Client,
+    delivered: AtomicU64,
+    failed: AtomicU64,
+    rate_limited: AtomicU64,
+    state_file: Option<StateFile>,
+}
+
+impl Service {
+    pub fn new(config: RoomWebhooksConfig, server_name: &str) -> Self {
+        Self {
+            limiter: RateLimiter::new("room_webhooks", config.rate_limit),
+            config,
+            server_name: server_name.to_owned(),
+            webhooks: RwLock::new(HashMap::new()),
+            client: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .timeout(TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            delivered: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            state_file: None,
+        }
+    }
+
+    /// Keep webhooks in the file at `path`, loading the ones stored there
+    pub fn with_state_file(mut self, path: Option<PathBuf>) -> Self {
+        if let Some(path) = path {
+            let state_file = StateFile::new(path);
+            if let Some(stored) = state_file.load::<HashMap<String, Vec<StoredWebhook>>>() {
+                let webhooks = stored
+                    .into_iter()
+                    .map(|(room_id, stored)| {
+                        let webhooks = stored.into_iter().map(|StoredWebhook { webhook, secret }| RoomWebhook { secret, ..webhook });
+                        (room_id, webhooks.collect())
+                    })
+                    .collect();
+                self.webhooks = RwLock::new(webhooks);
+            }
+            self.state_file = Some(state_file);
+        }
+        self
+    }
+
+    /// Write the webhooks, read under `webhooks`, to the state file
+    fn persist(&self, webhooks: RwLockWriteGuard<'_, HashMap<String, Vec<RoomWebhook>>>) {
+        let snapshot = self.state_file.as_ref().map(|state_file| {
+            let stored: HashMap<&String, Vec<StoredWebhook>> = webhooks
+                .iter()
+                .map(|(room_id, webhooks)| {
+                    let stored = webhooks.iter().map(|webhook| StoredWebhook { webhook: webhook.clone(), secret: webhook.secret.clone() });
+                    (room_id, stored.collect())
+                })
+                .collect();
+            (state_file, state_file.snapshot(&stored))
+        });
+        drop(webhooks);
+        if let Some((state_file, snapshot)) = snapshot {
+            state_file.write(snapshot);
+        }
+    }
+
+    /// Register a webhook of `room_id` on behalf of `admin`, signed with
+    /// `secret` or a generated one, which is only returned here
+    pub fn register(
+        &self,
+        room_id: &str,
+        admin: &str,
+        url: &str,
+        secret: Option<String>,
+        filter: WebhookFilter,
+    ) -> Result<RoomWebhook> {
+        self.check_url(url)?;
+        let mut webhooks = self.webhooks.write().unwrap();
+        let room_webhooks = webhooks.entry(room_id.to_owned()).or_default();
+        if room_webhooks.len() >= self.config.max_per_room {
+            return Err(Error::BadRequest(ErrorKind::LimitExceeded { retry_after: None }, "This room has too many webhooks"));
+        }
+        let webhook = RoomWebhook {
+            webhook_id: Uuid::new_v4().simple().to_string(),
+            room_id: room_id.to_owned(),
+            url: url.to_owned(),
+            secret: secret
+                .filter(|secret| !secret.is_empty())
+                .unwrap_or_else(|| rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect()),
+            filter,
+            created_by: admin.to_owned(),
+            created_at: now_millis(),
+        };
+        info!("🪝 {} registered webhook {} of {} to {}", admin, webhook.webhook_id, room_id, url);
+        room_webhooks.push(webhook.clone());
+        self.persist(webhooks);
+        Ok(webhook)
+    }
+
+    /// Webhooks must be HTTP(S) URLs, and not to local addresses unless
+    /// allowed
+    fn check_url(&self, url: &str) -> Result<()> {
+        let invalid = |message| Err(Error::BadRequest(ErrorKind::InvalidParam, message));
+        let Ok(url) = url::Url::parse(url) else {
+            return invalid("The webhook URL is not a valid URL");
+        };
+        if !matches!(url.scheme(), "http" | "https") {
+            return invalid("Webhook URLs must be HTTP or HTTPS URLs");
+        }
+        let local = match url.host() {
+            None => return invalid("The webhook URL has no host"),
+            Some(url::Host::Domain(domain)) => domain == "localhost" || domain.ends_with(".localhost"),
+            Some(url::Host::Ipv4(ip)) => outbound_http::is_internal(IpAddr::V4(ip)),
+            Some(url::Host::Ipv6(ip)) => outbound_http::is_internal(IpAddr::V6(ip)),
+        };
+        if local && !self.config.allow_private_addresses {
+            return invalid("Webhooks to local addresses are not allowed");
+        }
+        Ok(())
+    }
+
+    /// Webhooks of a room, oldest first
+    pub fn webhooks(&self, room_id: &str) -> Vec<RoomWebhook> {
+        self.webhooks.read().unwrap().get(room_id).cloned().unwrap_or_default()
+    }
+
+    /// Remove a webhook of a room, returning it if it existed
+    pub fn remove(&self, room_id: &str, webhook_id: &str) -> Option<RoomWebhook> {
+        let mut webhooks = self.webhooks.write().unwrap();
+        let room_webhooks = webhooks.get_mut(room_id)?;
+        let position = room_webhooks.iter().position(|webhook| webhook.webhook_id == webhook_id)?;
+        let webhook = room_webhooks.remove(position);
+        if room_webhooks.is_empty() {
+            webhooks.remove(room_id);
+        }
+        self.persist(webhooks);
+        Some(webhook)
+    }
+
+    /// Remove the webhooks of a room, returning how many there were
+    pub fn delete_room(&self, room_id: &str) -> usize {
+        let mut webhooks = self.webhooks.write().unwrap();
+        let removed = webhooks.remove(room_id).map_or(0, |webhooks| webhooks.len());
+        if removed > 0 {
+            self.persist(webhooks);
+        }
+        removed
+    }
+
+    /// Webhooks `event` is sent to: those of its room whose filters match,
+    /// as long as the room is under its rate cap. Matching webhooks whose
+    /// admin is no longer `is_admin` of the room are removed instead.
+    pub fn deliveries(&self, event: &Value, is_admin: impl Fn(&str, &str) -> bool) -> Vec<RoomWebhook> {
+        let Some(room_id) = event["room_id"].as_str() else {
+            return Vec::new();
+        };
+        let matching: Vec<RoomWebhook> = self
+            .webhooks
+            .read()
+            .unwrap()
+            .get(room_id)
+            .into_iter()
+            .flatten()
+            .filter(|webhook| webhook.filter.matches(event))
+            .cloned()
+            .collect();
+        let (matching, revoked): (Vec<_>, Vec<_>) =
+            matching.into_iter().partition(|webhook| is_admin(room_id, &webhook.created_by));
+        for webhook in revoked {
+            if self.remove(room_id, &webhook.webhook_id).is_some() {
+                info!(
+                    "🪝 Removed webhook {} of {}: {} is no longer an admin of the room",
+                    webhook.webhook_id, room_id, webhook.created_by
+                );
+            }
+        }
+        matching
+            .into_iter()
+            .filter(|webhook| {
+                let allowed = self.limiter.check(&webhook.room_id).is_ok();
+                if !allowed {
+                    self.rate_limited.fetch_add(1, Ordering::Relaxed);
+                    debug!("🪝 Dropped {} for webhook {}: {} is over its rate cap", event["event_id"], webhook.webhook_id, room_id);
+                }
+                allowed
+            })
+            .collect()
+    }
+
+    /// Send `event` to the webhooks it matches in the background
+    pub fn dispatch(&'static self, event: &Value) {
+        let is_admin = |room_id: &str, user_id: &str| membership::is_room_admin(&services().timeline, room_id, user_id);
+        for webhook in self.deliveries(event, is_admin) {
+            let event = event.clone();
+            tokio::spawn(async move { self.deliver(&webhook, &event).await });
+        }
+    }
+
+    /// Client for a delivery to `url`, pinned to the public addresses its
+    /// host resolves to now unless local addresses are allowed
+    async fn client_for(&self, url: &str) -> Result<reqwest::Client> {
+        if self.config.allow_private_addresses {
+            return Ok(self.client.clone());
+        }
+        outbound_http::client_for(&outbound_http::check_url(url)?, TIMEOUT).await
+    }
+
+    async fn deliver(&self, webhook: &RoomWebhook, event: &Value) {
+        let client = match self.client_for(&webhook.url).await {
+            Ok(client) => client,
+            Err(e) => {
+                warn!("❌ Not sending webhook {} to {}: {}", webhook.webhook_id, webhook.url, e);
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+        };
+        let id = Uuid::new_v4().to_string();
+        let ts = now_millis();
+        let body = json!({
+            "id": id,
+            "ts": ts,
+            "server_name": self.server_name,
+            "webhook_id": webhook.webhook_id,
+            "room_id": webhook.room_id,
+            "event": event
+        })
+        .to_string();
+        let endpoint = WebhookConfig {
+            url: webhook.url.clone(),
+            secret: webhook.secret.clone(),
+            events: Vec::new(),
+            max_retries: self.config.max_retries,
+        };
+        let counter = if post_signed(&client, &endpoint, EVENT_NAME, &id, ts, &body).await {
+            &self.delivered
+        } else {
+            &self.failed
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Deliveries dropped for exceeding their room's rate cap so far
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.load(Ordering::Relaxed)
+    }
+
+    /// Webhooks registered across rooms
+    pub fn len(&self) -> usize {
+        self.webhooks.read().unwrap().values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Delivery counters in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP matrixon_room_webhooks Webhooks registered by rooms");
+        let _ = writeln!(out, "# TYPE matrixon_room_webhooks gauge");
+        let _ = writeln!(out, "matrixon_room_webhooks {}", self.len());
+        let _ = writeln!(out, "# HELP matrixon_room_webhook_deliveries_total Room events sent to webhooks, by outcome");
+        let _ = writeln!(out, "# TYPE matrixon_room_webhook_deliveries_total counter");
+        for (outcome, counter) in [("delivered", &self.delivered), ("failed", &self.failed), ("rate_limited", &self.rate_limited)] {
+            let _ = writeln!(out, "matrixon_room_webhook_deliveries_total{{outcome=\"{}\"}} {}", outcome, counter.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+/// Send the events appended from now on to the webhooks of their rooms,
+/// forever
+pub async fn run() {
+    let Some(webhooks) = &services().room_webhooks else {
+        return;
+    };
+    let timeline = &services().timeline;
+    let mut position = timeline.current_count();
+    info!("🪝 Sending room events to room webhooks from position {}", position);
+
+    loop {
+        let advanced = timeline.advanced();
+        let batch = timeline.stream_since(position, BATCH_SIZE);
+        if batch.is_empty() {
+            advanced.await;
+            continue;
+        }
+
+        for (count, event) in batch {
+            webhooks.dispatch(&event);
+            position = count;
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrixon_common::rate_limit::RateLimit;
+
+    const ROOM: &str = "!room:matrixon.local";
+
+    fn service(rate_limit: RateLimit) -> Service {
+        Service::new(RoomWebhooksConfig { max_per_room: 2, rate_limit, ..RoomWebhooksConfig::default() }, "matrixon.local")
+    }
+
+    fn admin(_: &str, _: &str) -> bool {
+        true
+    }
+
+    fn message(sender: &str, body: &str) -> Value {
+        json!({ "room_id": ROOM, "type": "m.room.message", "sender": sender, "content": { "msgtype": "m.text", "body": body } })
+    }
+
+    #[test]
+    fn test_webhooks_are_validated_and_capped_per_room() {
+        let service = service(RoomWebhooksConfig::default().rate_limit);
+        let register = |url: &str| service.register(ROOM, "@admin:matrixon.local", url, None, WebhookFilter::default());
+        assert!(register("ftp://hooks.example.com/").is_err());
+        assert!(register("http://127.0.0.1:8080/hook").is_err());
+        assert!(register("https://[::1]/hook").is_err());
+        assert!(register("http://10.1.2.3/hook").is_err());
+        assert!(register("https://api.localhost/hook").is_err());
+
+        let webhook = register("https://hooks.example.com/a").unwrap();
+        assert_eq!(webhook.secret.len(), 32);
+        assert!(serde_json::to_value(&webhook).unwrap().get("secret").is_none());
+        register("https://hooks.example.com/b").unwrap();
+        assert!(register("https://hooks.example.com/c").is_err());
+        assert_eq!(service.webhooks(ROOM).len(), 2);
+
+        assert!(service.remove(ROOM, &webhook.webhook_id).is_some());
+        assert!(service.remove(ROOM, &webhook.webhook_id).is_none());
+        assert_eq!(service.delete_room(ROOM), 1);
+        assert!(service.is_empty());
+    }
+
+    #[test]
+    fn test_events_are_filtered_and_rate_capped() {
+        let service = service(RateLimit::SlidingWindow { limit: 2, window: Duration::from_secs(3600) });
+        let filter = WebhookFilter {
+            types: vec!["m.room.*".to_owned()],
+            senders: vec!["@alice:matrixon.local".to_owned()],
+            keywords: vec!["deploy".to_owned()],
+        };
+        service.register(ROOM, "@admin:matrixon.local", "https://hooks.example.com/", Some("secret".to_owned()), filter).unwrap();
+
+        assert!(service.deliveries(&message("@bob:matrixon.local", "Deploy now"), admin).is_empty());
+        assert!(service.deliveries(&message("@alice:matrixon.local", "hello"), admin).is_empty());
+        let reaction = json!({ "room_id": ROOM, "type": "m.reaction", "sender": "@alice:matrixon.local", "content": { "body": "deploy" } });
+        assert!(service.deliveries(&reaction, admin).is_empty());
+        let deliveries = service.deliveries(&message("@alice:matrixon.local", "Time to DEPLOY"), admin);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].secret, "secret");
+
+        // Events of other rooms are not sent, and those over the cap are dropped
+        let mut elsewhere = message("@alice:matrixon.local", "deploy");
+        elsewhere["room_id"] = json!("!other:matrixon.local");
+        assert!(service.deliveries(&elsewhere, admin).is_empty());
+        assert_eq!(service.deliveries(&message("@alice:matrixon.local", "deploy"), admin).len(), 1);
+        assert!(service.deliveries(&message("@alice:matrixon.local", "deploy"), admin).is_empty());
+        assert_eq!(service.rate_limited(), 1);
+        assert!(service.render().contains("matrixon_room_webhook_deliveries_total{outcome=\"rate_limited\"} 1\n"));
+    }
+
+    #[test]
+    fn test_webhooks_are_kept_until_their_admin_loses_power() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("room_webhooks.json");
+        let webhooks = service(RoomWebhooksConfig::default().rate_limit).with_state_file(Some(path.clone()));
+        let webhook = webhooks
+            .register(ROOM, "@admin:matrixon.local", "https://hooks.example.com/", Some("secret".to_owned()), WebhookFilter::default())
+            .unwrap();
+
+        let restarted = service(RoomWebhooksConfig::default().rate_limit).with_state_file(Some(path.clone()));
+        assert_eq!(restarted.webhooks(ROOM), vec![webhook.clone()]);
+        assert_eq!(restarted.webhooks(ROOM)[0].secret, "secret");
+
+        // Once its admin is no longer one, the webhook is removed, also from the file
+        let demoted = |_: &str, user_id: &str| user_id != "@admin:matrixon.local";
+        assert!(restarted.deliveries(&message("@alice:matrixon.local", "hello"), demoted).is_empty());
+        assert!(restarted.is_empty());
+        let restarted = service(RoomWebhooksConfig::default().rate_limit).with_state_file(Some(path));
+        assert!(restarted.is_empty());
+    }
+}
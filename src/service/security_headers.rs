@@ -0,0 +1,169 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Security Headers
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Response headers hardening browsers against content served by this
+//   server, chosen by the class of route a request is for: uploaded media is
+//   sandboxed and kept out of search engines, the admin API gets a strict
+//   policy and the rest of the APIs a default one that handlers rendering
+//   their own pages may override. HSTS is added on listeners serving TLS.
+//
+// =============================================================================
+
+use axum::http::{
+    header::{self, HeaderName},
+    HeaderMap, HeaderValue,
+};
+
+use crate::{config::SecurityHeadersConfig, Error, Result};
+
+/// Kinds of routes with their own header policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RouteClass {
+    /// Downloads and thumbnails of uploaded files
+    Media,
+    /// The `/_matrixon` and Synapse-compatible admin APIs and UI
+    Admin,
+    /// Every other API, `/_matrixon/client` extensions included
+    Api,
+}
+
+impl RouteClass {
+    /// Class of the route serving `path`, shared by every per-route policy
+    pub fn of(path: &str) -> Self {
+        if path.starts_with("/_matrixon/admin/") || path.starts_with("/_synapse/admin/") {
+            Self::Admin
+        } else if path.starts_with("/_matrix/media/")
+            || path.starts_with("/_matrix/client/v1/media/")
+            || path.starts_with("/_matrix/federation/v1/media/")
+        {
+            Self::Media
+        } else {
+            Self::Api
+        }
+    }
+}
+
+/// Security header policy, parsed from the config once
+#[derive(Debug, Clone)]
+pub struct Service {
+    media: Vec<(HeaderName, HeaderValue)>,
+    admin: Vec<(HeaderName, HeaderValue)>,
+    api_csp: HeaderValue,
+    hsts: Option<HeaderValue>,
+}
+
+impl Service {
+    pub fn new(config: &SecurityHeadersConfig) -> Result<Self> {
+        let mut media = vec![(header::CONTENT_SECURITY_POLICY, value("media_csp", &config.media_csp)?)];
+        if !config.media_robots_tag.is_empty() {
+            media.push((HeaderName::from_static("x-robots-tag"), value("media_robots_tag", &config.media_robots_tag)?));
+        }
+        let admin = vec![
+            (header::CONTENT_SECURITY_POLICY, value("admin_csp", &config.admin_csp)?),
+            (header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY")),
+            (header::REFERRER_POLICY, HeaderValue::from_static("no-referrer")),
+            (header::CACHE_CONTROL, HeaderValue::from_static("no-store")),
+        ];
+        let hsts = config.hsts.then(|| {
+            let mut hsts = format!("max-age={}", config.hsts_max_age_s);
+            if config.hsts_include_subdomains {
+                hsts.push_str("; includeSubDomains");
+            }
+            HeaderValue::from_str(&hsts).expect("HSTS header is ASCII")
+        });
+
+        Ok(Self {
+            media,
+            admin,
+            api_csp: value("api_csp", &config.api_csp)?,
+            hsts,
+        })
+    }
+
+    /// Add the headers for a response to a request for `path`, over TLS
+    /// when `tls` is set
+    pub fn apply(&self, path: &str, tls: bool, headers: &mut HeaderMap) {
+        headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+        // Inserted one by one: extending would add a second policy next to
+        // one the handler already set, and browsers enforce both
+        let policy: &[(HeaderName, HeaderValue)] = match RouteClass::of(path) {
+            RouteClass::Media => &self.media,
+            RouteClass::Admin => &self.admin,
+            RouteClass::Api => {
+                // Server-rendered pages bring their own policy
+                if !headers.contains_key(header::CONTENT_SECURITY_POLICY) {
+                    headers.insert(header::CONTENT_SECURITY_POLICY, self.api_csp.clone());
+                }
+                &[]
+            }
+        };
+        for (name, value) in policy {
+            headers.insert(name.clone(), value.clone());
+        }
+        if let (true, Some(hsts)) = (tls, &self.hsts) {
+            headers.insert(header::STRICT_TRANSPORT_SECURITY, hsts.clone());
+        }
+    }
+}
+
+fn value(option: &str, value: &str) -> Result<HeaderValue> {
+    HeaderValue::from_str(value)
+        .map_err(|_| Error::BadConfig(format!("security_headers.{} is not a valid header value", option)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_headers_by_route_class() {
+        let policy = Service::new(&SecurityHeadersConfig::default()).unwrap();
+
+        let mut media = HeaderMap::new();
+        policy.apply("/_matrix/media/v3/download/matrixon.local/abc", true, &mut media);
+        assert!(media[header::CONTENT_SECURITY_POLICY].to_str().unwrap().starts_with("sandbox"));
+        assert_eq!(media["x-robots-tag"], "noindex, nofollow, noarchive");
+        assert!(media.contains_key(header::STRICT_TRANSPORT_SECURITY));
+
+        // A policy the handler set is replaced, not joined by a second one
+        let mut media = HeaderMap::new();
+        media.insert(header::CONTENT_SECURITY_POLICY, HeaderValue::from_static("default-src *"));
+        policy.apply("/_matrix/client/v1/media/download/matrixon.local/abc", false, &mut media);
+        assert_eq!(media.get_all(header::CONTENT_SECURITY_POLICY).iter().count(), 1);
+        assert!(media[header::CONTENT_SECURITY_POLICY].to_str().unwrap().starts_with("sandbox"));
+
+        let mut admin = HeaderMap::new();
+        policy.apply("/_matrixon/admin/v1/room_stats", false, &mut admin);
+        assert_eq!(admin[header::X_FRAME_OPTIONS], "DENY");
+        assert!(!admin.contains_key("x-robots-tag"));
+        assert!(!admin.contains_key(header::STRICT_TRANSPORT_SECURITY));
+
+        // The Synapse-compatible admin API is admin too, while extensions
+        // under `/_matrixon/client` are client APIs
+        let mut synapse_admin = HeaderMap::new();
+        policy.apply("/_synapse/admin/v2/users", false, &mut synapse_admin);
+        assert_eq!(synapse_admin, admin);
+        assert_eq!(RouteClass::of("/_matrixon/client/v1/rooms/webhooks"), RouteClass::Api);
+        let mut extension = HeaderMap::new();
+        policy.apply("/_matrixon/client/v1/rooms/webhooks", false, &mut extension);
+        assert!(!extension.contains_key(header::X_FRAME_OPTIONS));
+        assert_ne!(extension[header::CONTENT_SECURITY_POLICY], admin[header::CONTENT_SECURITY_POLICY]);
+
+        let mut page = HeaderMap::new();
+        page.insert(header::CONTENT_SECURITY_POLICY, HeaderValue::from_static("form-action 'self'"));
+        policy.apply("/_matrix/client/unstable/add_threepid/email/confirm", false, &mut page);
+        assert_eq!(page[header::CONTENT_SECURITY_POLICY], "form-action 'self'");
+        assert_eq!(page[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+
+        let invalid = SecurityHeadersConfig { admin_csp: "default-src\n'none'".to_owned(), ..Default::default() };
+        assert!(Service::new(&invalid).is_err());
+    }
+}
@@ -0,0 +1,308 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Server Signing Keys
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   The server's ed25519 signing key. It is persisted so the server keeps
+//   its identity across restarts, published at /_matrix/key/v2/server and
+//   used to sign outgoing PDUs and X-Matrix request authorizations. Rotated
+//   keys stay published as `old_verify_keys` so signatures made with them
+//   can still be checked.
+//
+// =============================================================================
+
+use std::{
+    fs,
+    path::PathBuf,
+    sync::RwLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use matrixon_federation::sending::RequestSigner;
+use rand::{distributions::Alphanumeric, Rng};
+use ruma::{
+    serde::{base64::Standard, Base64},
+    signatures::Ed25519KeyPair,
+    CanonicalJsonValue, RoomVersionId,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{info, warn};
+
+use crate::{service::state_file, Error, Result};
+
+/// How long other servers may cache the published keys
+const VALIDITY_PERIOD_MS: u64 = 7 * 24 * 60 * 60 * 1000;
+
+/// A key that was rotated out
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OldKey {
+    pub key_id: String,
+    /// Unpadded base64 public key
+    pub key: String,
+    pub expired_ts: u64,
+}
+
+/// On-disk form of the keys
+#[derive(Serialize, Deserialize)]
+struct StoredKeys {
+    key_id: String,
+    /// Unpadded base64 PKCS#8 document of the current key
+    pkcs8: String,
+    #[serde(default)]
+    old_keys: Vec<OldKey>,
+}
+
+struct Keys {
+    key_pair: Ed25519KeyPair,
+    pkcs8: Vec<u8>,
+    old_keys: Vec<OldKey>,
+}
+
+/// Server signing key service
+pub struct Service {
+    server_name: String,
+    path: Option<PathBuf>,
+    keys: RwLock<Keys>,
+}
+
+impl std::fmt::Debug for Service {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Service")
+            .field("server_name", &self.server_name)
+            .field("key_id", &self.key_id())
+            .finish_non_exhaustive()
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+/// Generate a key pair with a fresh random key version
+fn generate() -> Result<(Ed25519KeyPair, Vec<u8>)> {
+    let pkcs8 = Ed25519KeyPair::generate().map_err(|e| Error::BadConfig(format!("Could not generate signing key: {}", e)))?;
+    let version: String = rand::thread_rng().sample_iter(&Alphanumeric).take(6).map(char::from).collect();
+    let key_pair = Ed25519KeyPair::from_der(&pkcs8, format!("a_{}", version))
+        .map_err(|e| Error::BadConfig(format!("Could not load signing key: {}", e)))?;
+    Ok((key_pair, pkcs8.to_vec()))
+}
+
+impl Service {
+    /// Load the signing key from `path`, generating and storing a new one
+    /// if there is none yet. Without a path the key only lives in memory.
+    pub fn load(server_name: &str, path: Option<PathBuf>) -> Result<Self> {
+        let stored = match &path {
+            Some(path) if path.exists() => {
+                let data = fs::read(path).map_err(|e| Error::BadConfig(format!("{}: {}", path.display(), e)))?;
+                Some(
+                    serde_json::from_slice::<StoredKeys>(&data)
+                        .map_err(|e| Error::BadConfig(format!("Invalid signing key file {}: {}", path.display(), e)))?,
+                )
+            }
+            _ => None,
+        };
+
+        let keys = match stored {
+            Some(stored) => {
+                let pkcs8 = Base64::<Standard>::parse(&stored.pkcs8)
+                    .map_err(|e| Error::BadConfig(format!("Invalid signing key: {}", e)))?
+                    .into_inner();
+                let version = stored.key_id.strip_prefix("ed25519:").unwrap_or(&stored.key_id).to_owned();
+                let key_pair = Ed25519KeyPair::from_der(&pkcs8, version)
+                    .map_err(|e| Error::BadConfig(format!("Invalid signing key: {}", e)))?;
+                Keys { key_pair, pkcs8, old_keys: stored.old_keys }
+            }
+            None => {
+                let (key_pair, pkcs8) = generate()?;
+                Keys { key_pair, pkcs8, old_keys: Vec::new() }
+            }
+        };
+
+        let service = Self {
+            server_name: server_name.to_owned(),
+            path,
+            keys: RwLock::new(keys),
+        };
+        service.persist()?;
+        info!("🔑 Server signing key is {}", service.key_id());
+        Ok(service)
+    }
+
+    fn persist(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let keys = self.keys.read().unwrap();
+        let stored = StoredKeys {
+            key_id: format!("ed25519:{}", keys.key_pair.version()),
+            pkcs8: Base64::<Standard>::new(keys.pkcs8.clone()).encode(),
+            old_keys: keys.old_keys.clone(),
+        };
+        drop(keys);
+        // Created with its final mode, so the key is never readable by others
+        let data = serde_json::to_vec_pretty(&stored).expect("stored keys serialize");
+        state_file::write_atomic(path, &data).map_err(|e| Error::BadConfig(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn server_name(&self) -> &str {
+        &self.server_name
+    }
+
+    /// Id of the current key, e.g. `ed25519:a_Xy12zq`
+    pub fn key_id(&self) -> String {
+        format!("ed25519:{}", self.keys.read().unwrap().key_pair.version())
+    }
+
+    /// Unpadded base64 public key of the current key
+    pub fn public_key(&self) -> String {
+        Base64::<Standard>::new(self.keys.read().unwrap().key_pair.public_key().to_vec()).encode()
+    }
+
+    /// Keys that were rotated out
+    pub fn old_keys(&self) -> Vec<OldKey> {
+        self.keys.read().unwrap().old_keys.clone()
+    }
+
+    /// Replace the signing key with a new one; the previous key is kept as
+    /// an old verify key. Returns the new key id.
+    pub fn rotate(&self) -> Result<String> {
+        let (key_pair, pkcs8) = generate()?;
+        {
+            let mut keys = self.keys.write().unwrap();
+            let old = OldKey {
+                key_id: format!("ed25519:{}", keys.key_pair.version()),
+                key: Base64::<Standard>::new(keys.key_pair.public_key().to_vec()).encode(),
+                expired_ts: now_millis(),
+            };
+            keys.old_keys.push(old);
+            keys.key_pair = key_pair;
+            keys.pkcs8 = pkcs8;
+        }
+        self.persist()?;
+        let key_id = self.key_id();
+        info!("🔑 Rotated server signing key, now {}", key_id);
+        Ok(key_id)
+    }
+
+    /// Add this server's signature to a JSON object
+    pub fn sign_json(&self, object: &mut Value) -> Result<()> {
+        let Ok(CanonicalJsonValue::Object(mut canonical)) = CanonicalJsonValue::try_from(object.clone()) else {
+            return Err(Error::BadServerResponse("Object to sign is not canonical JSON".to_owned()));
+        };
+        let keys = self.keys.read().unwrap();
+        let result = ruma::signatures::sign_json(&self.server_name, &keys.key_pair, &mut canonical);
+        *object = serde_json::to_value(&canonical).expect("canonical JSON serializes");
+        result.map_err(|e| Error::BadServerResponse(format!("Signing failed: {}", e)))
+    }
+
+    /// Prepare a local event for federation: add its content hash and this
+    /// server's signature. From room version 3 on the event id is derived
+    /// from the event itself, so it is not sent.
+    pub fn sign_pdu(&self, room_version: &str, event: &Value) -> Result<Value> {
+        let room_version = RoomVersionId::try_from(room_version).unwrap_or(RoomVersionId::V1);
+        let mut event = event.clone();
+        if let Some(object) = event.as_object_mut() {
+            object.remove("unsigned");
+            if !matches!(room_version, RoomVersionId::V1 | RoomVersionId::V2) {
+                object.remove("event_id");
+            }
+        }
+        let Ok(CanonicalJsonValue::Object(mut canonical)) = CanonicalJsonValue::try_from(event) else {
+            return Err(Error::BadServerResponse("Event is not canonical JSON".to_owned()));
+        };
+        let keys = self.keys.read().unwrap();
+        ruma::signatures::hash_and_sign_event(&self.server_name, &keys.key_pair, &mut canonical, &room_version)
+            .map_err(|e| Error::BadServerResponse(format!("Signing failed: {}", e)))?;
+        Ok(serde_json::to_value(&canonical).expect("canonical JSON serializes"))
+    }
+
+    /// The signed response of `/_matrix/key/v2/server`
+    pub fn server_keys_response(&self) -> Result<Value> {
+        let old_verify_keys: serde_json::Map<String, Value> = self
+            .old_keys()
+            .into_iter()
+            .map(|old| (old.key_id, json!({ "key": old.key, "expired_ts": old.expired_ts })))
+            .collect();
+        let mut response = json!({
+            "server_name": self.server_name,
+            "verify_keys": { self.key_id(): { "key": self.public_key() } },
+            "old_verify_keys": old_verify_keys,
+            "valid_until_ts": now_millis() + VALIDITY_PERIOD_MS,
+        });
+        self.sign_json(&mut response)?;
+        Ok(response)
+    }
+}
+
+impl RequestSigner for Service {
+    fn authorization(&self, method: &str, uri: &str, destination: &str, content: Option<&Value>) -> Option<String> {
+        let mut request = json!({
+            "method": method,
+            "uri": uri,
+            "origin": self.server_name,
+            "destination": destination,
+        });
+        if let Some(content) = content {
+            request["content"] = content.clone();
+        }
+        if let Err(e) = self.sign_json(&mut request) {
+            warn!("❌ Could not sign request to {}: {}", destination, e);
+            return None;
+        }
+
+        let key_id = self.key_id();
+        let sig = request["signatures"][&self.server_name][&key_id].as_str()?.to_owned();
+        Some(format!(
+            r#"X-Matrix origin="{}",destination="{}",key="{}",sig="{}""#,
+            self.server_name, destination, key_id, sig
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::inbound_federation::{verify_request, XMatrix};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_keys_persist_and_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("signing_key.json");
+
+        let service = Service::load("matrixon.local", Some(path.clone())).unwrap();
+        let first = service.key_id();
+        assert_eq!(Service::load("matrixon.local", Some(path.clone())).unwrap().key_id(), first);
+
+        let second = service.rotate().unwrap();
+        assert_ne!(first, second);
+        let reloaded = Service::load("matrixon.local", Some(path)).unwrap();
+        assert_eq!(reloaded.key_id(), second);
+        assert_eq!(reloaded.old_keys()[0].key_id, first);
+
+        let response = reloaded.server_keys_response().unwrap();
+        assert!(response["verify_keys"][&second]["key"].is_string());
+        assert!(response["old_verify_keys"][&first]["expired_ts"].is_u64());
+        assert!(response["signatures"]["matrixon.local"][&second].is_string());
+    }
+
+    #[test]
+    fn test_request_authorization_verifies() {
+        let service = Service::load("matrixon.local", None).unwrap();
+        let content = json!({ "pdus": [] });
+        let header = service
+            .authorization("PUT", "/_matrix/federation/v1/send/1", "remote.example", Some(&content))
+            .unwrap();
+
+        let x_matrix = XMatrix::parse(&header).unwrap();
+        let verify_keys = BTreeMap::from([(service.key_id(), service.public_key())]);
+        assert!(verify_request(&x_matrix, "PUT", "/_matrix/federation/v1/send/1", "remote.example", Some(&content), &verify_keys));
+    }
+}
@@ -0,0 +1,497 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Device Sessions
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Devices signed in to this server and their to-device inboxes. Logging a
+//   device out invalidates its access token, drops its pending to-device
+//   messages and wakes syncs waiting on it, so a long-polling client learns
+//   about the logout at once rather than when its timeout expires.
+//   To-device inboxes hold at most `MAX_INBOX_SIZE` messages, and the
+//   transaction ids of the last messages each device sent are remembered
+//   so retried requests are not delivered twice; room events sent with a
+//   transaction id are remembered with their event id the same way.
+//   Devices lazy-loading room members also remember which members they
+//   were sent, so each membership is sent once per device, up to
+//   `MAX_LAZY_LOADED` of them; the oldest are then forgotten and sent
+//   again when needed. Without a
+//   database the access tokens this server issued are kept here too, and
+//   any other token is unknown. When each user last made a request is
+//   tracked as well, as the only presence signal the server has.
+//
+// =============================================================================
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use rand::{distributions::Alphanumeric, Rng};
+use serde_json::{json, Value};
+use tokio::sync::{futures::Notified, Notify};
+use tracing::{info, warn};
+
+use crate::service::timeline;
+
+/// Granularity of the last activity of users, so that most requests only
+/// take the read lock
+const ACTIVITY_RESOLUTION: Duration = Duration::from_secs(10);
+
+/// Most to-device messages waiting for one device; later ones are dropped
+pub const MAX_INBOX_SIZE: usize = 1000;
+
+/// Transaction ids of to-device requests, and of room event sends,
+/// remembered per device
+const MAX_TXN_IDS: usize = 100;
+
+/// Memberships remembered as sent to one lazy-loading device
+const MAX_LAZY_LOADED: usize = 10_000;
+
+/// New random access token
+pub fn new_access_token() -> String {
+    format!("syt_{}", random_string(32))
+}
+
+/// New random device ID
+pub fn new_device_id() -> String {
+    random_string(10).to_uppercase()
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng().sample_iter(&Alphanumeric).take(len).map(char::from).collect()
+}
+
+/// A to-device message waiting for its device to sync
+#[derive(Debug, Clone)]
+struct Pending {
+    /// Stream count the message was queued at
+    count: u64,
+    event: Value,
+}
+
+/// Stream count each `(room, member)` membership was first sent to a
+/// device at
+type SentMembers = HashMap<(String, String), u64>;
+
+/// Device session service
+#[derive(Debug, Default)]
+pub struct Service {
+    /// Devices seen with a valid access token, by user
+    devices: RwLock<HashMap<String, HashSet<String>>>,
+    /// Sessions by access token, when they are not kept in the database
+    tokens: RwLock<HashMap<String, (String, String)>>,
+    logged_out: RwLock<HashSet<(String, String)>>,
+    inboxes: RwLock<HashMap<(String, String), Vec<Pending>>>,
+    /// Transaction ids of the last to-device requests of each device
+    to_device_txns: RwLock<HashMap<(String, String), VecDeque<String>>>,
+    /// Transaction ids of the last room events of each device, with the
+    /// ids of the events they sent
+    event_txns: RwLock<HashMap<(String, String), VecDeque<(String, String)>>>,
+    /// Memberships sent to lazy-loading devices, by device
+    lazy_loaded: RwLock<HashMap<(String, String), SentMembers>>,
+    /// When each user last authenticated a request
+    last_active: RwLock<HashMap<String, Instant>>,
+    /// Notified on logouts and queued to-device messages
+    changed: Notify,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Remember a device that authenticated a request
+    pub fn touch(&self, user_id: &str, device_id: &str) {
+        let now = Instant::now();
+        let recent = self
+            .last_active
+            .read()
+            .unwrap()
+            .get(user_id)
+            .is_some_and(|at| now.duration_since(*at) < ACTIVITY_RESOLUTION);
+        if !recent {
+            self.last_active.write().unwrap().insert(user_id.to_owned(), now);
+        }
+
+        let known = self
+            .devices
+            .read()
+            .unwrap()
+            .get(user_id)
+            .is_some_and(|devices| devices.contains(device_id));
+        if !known {
+            self.devices
+                .write()
+                .unwrap()
+                .entry(user_id.to_owned())
+                .or_default()
+                .insert(device_id.to_owned());
+        }
+    }
+
+    /// Whether the user made a request in the last `within`
+    pub fn active_within(&self, user_id: &str, within: Duration) -> bool {
+        self.last_active
+            .read()
+            .unwrap()
+            .get(user_id)
+            .is_some_and(|at| at.elapsed() < within)
+    }
+
+    /// Users who made a request since `since`
+    pub fn active_since(&self, since: Instant) -> Vec<String> {
+        self.last_active
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, at)| **at >= since)
+            .map(|(user_id, _)| user_id.clone())
+            .collect()
+    }
+
+    /// Known devices of a user
+    pub fn devices(&self, user_id: &str) -> Vec<String> {
+        let mut devices: Vec<String> = self
+            .devices
+            .read()
+            .unwrap()
+            .get(user_id)
+            .map(|devices| devices.iter().cloned().collect())
+            .unwrap_or_default();
+        devices.sort();
+        devices
+    }
+
+    /// Start a new session of a device, e.g. logging in again after it
+    /// was logged out
+    pub fn login(&self, user_id: &str, device_id: &str) {
+        self.logged_out
+            .write()
+            .unwrap()
+            .remove(&(user_id.to_owned(), device_id.to_owned()));
+        self.touch(user_id, device_id);
+    }
+
+    /// Issue an access token for a new session of a device, when sessions
+    /// are not kept in the database
+    pub fn issue_token(&self, user_id: &str, device_id: &str) -> String {
+        let token = new_access_token();
+        self.tokens
+            .write()
+            .unwrap()
+            .insert(token.clone(), (user_id.to_owned(), device_id.to_owned()));
+        self.login(user_id, device_id);
+        token
+    }
+
+    /// User and device an access token from [`Service::issue_token`]
+    /// belongs to
+    pub fn token_session(&self, token: &str) -> Option<(String, String)> {
+        self.tokens.read().unwrap().get(token).cloned()
+    }
+
+    pub fn is_logged_out(&self, user_id: &str, device_id: &str) -> bool {
+        self.logged_out
+            .read()
+            .unwrap()
+            .contains(&(user_id.to_owned(), device_id.to_owned()))
+    }
+
+    /// Log a device out: its token stops working, its to-device inbox is
+    /// dropped and waiting syncs are woken
+    pub fn logout(&self, user_id: &str, device_id: &str) {
+        info!("🔒 Logging out device {} of {}", device_id, user_id);
+        if let Some(devices) = self.devices.write().unwrap().get_mut(user_id) {
+            devices.remove(device_id);
+        }
+        let key = (user_id.to_owned(), device_id.to_owned());
+        self.tokens.write().unwrap().retain(|_, session| *session != key);
+        self.inboxes.write().unwrap().remove(&key);
+        self.to_device_txns.write().unwrap().remove(&key);
+        self.event_txns.write().unwrap().remove(&key);
+        self.lazy_loaded.write().unwrap().remove(&key);
+        self.logged_out.write().unwrap().insert(key);
+        self.changed.notify_waiters();
+    }
+
+    /// Log out every known device of a user, `current` included, returning
+    /// the devices logged out
+    pub fn logout_all(&self, user_id: &str, current: &str) -> Vec<String> {
+        let mut devices = self.devices(user_id);
+        if !devices.iter().any(|device| device == current) {
+            devices.push(current.to_owned());
+        }
+        for device_id in &devices {
+            self.logout(user_id, device_id);
+        }
+        devices
+    }
+
+    /// Send a room event for a transaction of a device, once: a retry of
+    /// a transaction sent before gets the id of the event it sent instead
+    pub fn send_event_txn(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        txn_id: &str,
+        send: impl FnOnce() -> crate::Result<String>,
+    ) -> crate::Result<String> {
+        let mut txns = self.event_txns.write().unwrap();
+        let txn_ids = txns.entry((user_id.to_owned(), device_id.to_owned())).or_default();
+        if let Some((_, event_id)) = txn_ids.iter().find(|(seen, _)| seen == txn_id) {
+            return Ok(event_id.clone());
+        }
+        let event_id = send()?;
+        if txn_ids.len() == MAX_TXN_IDS {
+            txn_ids.pop_front();
+        }
+        txn_ids.push_back((txn_id.to_owned(), event_id.clone()));
+        Ok(event_id)
+    }
+
+    /// Record the transaction id of a to-device request of a device,
+    /// returning whether it is new rather than a retry
+    pub fn first_to_device_txn(&self, user_id: &str, device_id: &str, txn_id: &str) -> bool {
+        let mut txns = self.to_device_txns.write().unwrap();
+        let txn_ids = txns.entry((user_id.to_owned(), device_id.to_owned())).or_default();
+        if txn_ids.iter().any(|seen| seen == txn_id) {
+            return false;
+        }
+        if txn_ids.len() == MAX_TXN_IDS {
+            txn_ids.pop_front();
+        }
+        txn_ids.push_back(txn_id.to_owned());
+        true
+    }
+
+    /// Queue a to-device message at the next stream count, returning
+    /// whether it was queued. Messages to logged out devices and to devices
+    /// with a full inbox are dropped.
+    pub fn queue_to_device(
+        &self,
+        timeline: &timeline::Service,
+        sender: &str,
+        user_id: &str,
+        device_id: &str,
+        event_type: &str,
+        content: Value,
+    ) -> bool {
+        if self.is_logged_out(user_id, device_id) {
+            return false;
+        }
+        let event = json!({ "sender": sender, "type": event_type, "content": content });
+        let mut inboxes = self.inboxes.write().unwrap();
+        let inbox = inboxes.entry((user_id.to_owned(), device_id.to_owned())).or_default();
+        if inbox.len() >= MAX_INBOX_SIZE {
+            warn!("⚠️ To-device inbox of {} ({}) is full, dropping {} from {}", user_id, device_id, event_type, sender);
+            return false;
+        }
+        // Advance the stream under the lock, so a sync seeing the new count
+        // also sees the message
+        let count = timeline.next_count();
+        inbox.push(Pending { count, event });
+        drop(inboxes);
+        self.changed.notify_waiters();
+        true
+    }
+
+    /// To-device messages for a sync from `since` up to stream count
+    /// `until`. Messages up to `since` were delivered by the sync that
+    /// handed out that token and are removed.
+    pub fn to_device_events(&self, user_id: &str, device_id: &str, since: Option<u64>, until: u64) -> Vec<Value> {
+        let mut inboxes = self.inboxes.write().unwrap();
+        let Some(inbox) = inboxes.get_mut(&(user_id.to_owned(), device_id.to_owned())) else {
+            return Vec::new();
+        };
+        if let Some(since) = since {
+            inbox.retain(|pending| pending.count > since);
+        }
+        inbox
+            .iter()
+            .filter(|pending| pending.count <= until)
+            .map(|pending| pending.event.clone())
+            .collect()
+    }
+
+    /// Forget the members a device was sent, for an initial sync
+    pub fn reset_lazy_loaded(&self, user_id: &str, device_id: &str) {
+        self.lazy_loaded.write().unwrap().remove(&(user_id.to_owned(), device_id.to_owned()));
+    }
+
+    /// Whether a device syncing from `since` has the membership of `member`
+    /// in a room: it was sent by a sync up to `since`, whose response the
+    /// device therefore received
+    pub fn is_lazy_loaded(&self, user_id: &str, device_id: &str, room_id: &str, member: &str, since: u64) -> bool {
+        self.lazy_loaded
+            .read()
+            .unwrap()
+            .get(&(user_id.to_owned(), device_id.to_owned()))
+            .and_then(|sent| sent.get(&(room_id.to_owned(), member.to_owned())))
+            .is_some_and(|count| *count <= since)
+    }
+
+    /// Remember that the sync up to stream count `next_batch` sent the
+    /// memberships of `members` in a room
+    pub fn mark_lazy_loaded<'a>(
+        &self,
+        user_id: &str,
+        device_id: &str,
+        room_id: &str,
+        members: impl IntoIterator<Item = &'a str>,
+        next_batch: u64,
+    ) {
+        let mut lazy_loaded = self.lazy_loaded.write().unwrap();
+        let sent = lazy_loaded.entry((user_id.to_owned(), device_id.to_owned())).or_default();
+        for member in members {
+            // Keep the first count: a sync from before it was received
+            // has to send the member again
+            sent.entry((room_id.to_owned(), member.to_owned())).or_insert(next_batch);
+        }
+        if sent.len() > MAX_LAZY_LOADED {
+            // Forget the oldest quarter at once rather than a few per sync
+            let mut counts: Vec<u64> = sent.values().copied().collect();
+            let cutoff = *counts.select_nth_unstable(MAX_LAZY_LOADED / 4).1;
+            sent.retain(|_, count| *count > cutoff);
+        }
+    }
+
+    /// Resolves on the next logout or queued to-device message. Create it
+    /// before checking for updates so none is missed in between.
+    pub fn changed(&self) -> Notified<'_> {
+        self.changed.notified()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_issued_tokens_have_sessions() {
+        let service = Service::new();
+        let token = service.issue_token("@alice:matrixon.local", "PHONE");
+        assert_eq!(service.token_session(&token), Some(("@alice:matrixon.local".to_owned(), "PHONE".to_owned())));
+        assert_eq!(service.token_session("syt_matrixon_login_1"), None);
+
+        service.logout("@alice:matrixon.local", "PHONE");
+        assert_eq!(service.token_session(&token), None);
+    }
+
+    #[tokio::test]
+    async fn test_logout_drops_inbox_and_wakes_waiters() {
+        let service = Service::new();
+        let timeline = timeline::Service::new();
+        service.touch("@alice:matrixon.local", "PHONE");
+        service.touch("@alice:matrixon.local", "LAPTOP");
+        service.queue_to_device(&timeline, "@bob:matrixon.local", "@alice:matrixon.local", "PHONE", "m.room_key_request", json!({}));
+        service.queue_to_device(&timeline, "@bob:matrixon.local", "@alice:matrixon.local", "LAPTOP", "m.room_key_request", json!({}));
+        assert_eq!(service.to_device_events("@alice:matrixon.local", "PHONE", None, 2).len(), 1);
+
+        let changed = service.changed();
+        service.logout("@alice:matrixon.local", "PHONE");
+        changed.await;
+
+        assert!(service.is_logged_out("@alice:matrixon.local", "PHONE"));
+        assert!(!service.is_logged_out("@alice:matrixon.local", "LAPTOP"));
+        assert!(service.to_device_events("@alice:matrixon.local", "PHONE", None, 2).is_empty());
+        service.queue_to_device(&timeline, "@bob:matrixon.local", "@alice:matrixon.local", "PHONE", "m.room_key_request", json!({}));
+        assert!(service.to_device_events("@alice:matrixon.local", "PHONE", None, 3).is_empty());
+
+        // Delivered messages are acknowledged by the next sync
+        assert_eq!(service.to_device_events("@alice:matrixon.local", "LAPTOP", Some(1), 3).len(), 1);
+        assert!(service.to_device_events("@alice:matrixon.local", "LAPTOP", Some(2), 3).is_empty());
+
+        assert_eq!(service.logout_all("@alice:matrixon.local", "TABLET"), vec!["LAPTOP", "TABLET"]);
+        assert!(service.is_logged_out("@alice:matrixon.local", "LAPTOP"));
+        assert!(service.devices("@alice:matrixon.local").is_empty());
+    }
+
+    #[test]
+    fn test_to_device_inboxes_and_retries_are_bounded() {
+        let service = Service::new();
+        let timeline = timeline::Service::new();
+        let (alice, bob) = ("@alice:matrixon.local", "@bob:matrixon.local");
+        for _ in 0..MAX_INBOX_SIZE {
+            assert!(service.queue_to_device(&timeline, bob, alice, "PHONE", "m.room_key_request", json!({})));
+        }
+        assert!(!service.queue_to_device(&timeline, bob, alice, "PHONE", "m.room_key_request", json!({})));
+        assert_eq!(service.to_device_events(alice, "PHONE", None, u64::MAX).len(), MAX_INBOX_SIZE);
+
+        assert!(service.first_to_device_txn(bob, "LAPTOP", "txn0"));
+        assert!(!service.first_to_device_txn(bob, "LAPTOP", "txn0"));
+        assert!(service.first_to_device_txn(bob, "PHONE", "txn0"));
+        for i in 1..=MAX_TXN_IDS {
+            assert!(service.first_to_device_txn(bob, "LAPTOP", &format!("txn{}", i)));
+        }
+        // The oldest transaction ids are forgotten
+        assert!(service.first_to_device_txn(bob, "LAPTOP", "txn0"));
+    }
+
+    #[test]
+    fn test_retried_event_transactions_return_the_first_event() {
+        let service = Service::new();
+        let bob = "@bob:matrixon.local";
+        assert_eq!(service.send_event_txn(bob, "LAPTOP", "txn0", || Ok("$first".to_owned())).unwrap(), "$first");
+        assert_eq!(service.send_event_txn(bob, "LAPTOP", "txn0", || Ok("$second".to_owned())).unwrap(), "$first");
+        assert_eq!(service.send_event_txn(bob, "PHONE", "txn0", || Ok("$third".to_owned())).unwrap(), "$third");
+
+        // Failed sends are not remembered, so they can be retried
+        assert!(service.send_event_txn(bob, "LAPTOP", "txn1", || Err(crate::Error::BadServerResponse("down".to_owned()))).is_err());
+        assert_eq!(service.send_event_txn(bob, "LAPTOP", "txn1", || Ok("$fourth".to_owned())).unwrap(), "$fourth");
+
+        service.logout(bob, "LAPTOP");
+        assert_eq!(service.send_event_txn(bob, "LAPTOP", "txn0", || Ok("$fifth".to_owned())).unwrap(), "$fifth");
+    }
+
+    #[test]
+    fn test_requests_mark_users_active() {
+        let service = Service::new();
+        let before = Instant::now();
+        assert!(!service.active_within("@alice:matrixon.local", Duration::from_secs(60)));
+
+        service.touch("@alice:matrixon.local", "PHONE");
+        assert!(service.active_within("@alice:matrixon.local", Duration::from_secs(60)));
+        assert!(!service.active_within("@alice:matrixon.local", Duration::ZERO));
+        assert_eq!(service.active_since(before), vec!["@alice:matrixon.local"]);
+        assert!(service.active_since(Instant::now() + Duration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn test_lazy_loaded_members_are_known_once_received() {
+        let service = Service::new();
+        let (alice, room) = ("@alice:matrixon.local", "!room:matrixon.local");
+        service.mark_lazy_loaded(alice, "PHONE", room, ["@bob:matrixon.local"], 5);
+        // The response up to 5 may not have arrived yet
+        assert!(!service.is_lazy_loaded(alice, "PHONE", room, "@bob:matrixon.local", 4));
+        service.mark_lazy_loaded(alice, "PHONE", room, ["@bob:matrixon.local"], 7);
+        assert!(service.is_lazy_loaded(alice, "PHONE", room, "@bob:matrixon.local", 5));
+        assert!(!service.is_lazy_loaded(alice, "LAPTOP", room, "@bob:matrixon.local", 5));
+
+        service.reset_lazy_loaded(alice, "PHONE");
+        assert!(!service.is_lazy_loaded(alice, "PHONE", room, "@bob:matrixon.local", 9));
+        service.mark_lazy_loaded(alice, "PHONE", room, ["@bob:matrixon.local"], 9);
+        service.logout(alice, "PHONE");
+        assert!(!service.is_lazy_loaded(alice, "PHONE", room, "@bob:matrixon.local", 9));
+    }
+
+    #[test]
+    fn test_lazy_loaded_members_are_capped_per_device() {
+        let service = Service::new();
+        let (alice, room) = ("@alice:matrixon.local", "!room:matrixon.local");
+        for count in 0..=MAX_LAZY_LOADED as u64 {
+            service.mark_lazy_loaded(alice, "PHONE", room, [format!("@user{}:matrixon.local", count).as_str()], count);
+        }
+        let sent = service.lazy_loaded.read().unwrap()[&(alice.to_owned(), "PHONE".to_owned())].len();
+        assert!(sent <= MAX_LAZY_LOADED);
+        let since = MAX_LAZY_LOADED as u64;
+        assert!(!service.is_lazy_loaded(alice, "PHONE", room, "@user0:matrixon.local", since));
+        assert!(service.is_lazy_loaded(alice, "PHONE", room, &format!("@user{}:matrixon.local", since), since));
+    }
+}
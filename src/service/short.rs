@@ -0,0 +1,239 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Short IDs
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Short ids: small integers standing in for (type, state key) pairs and
+//   event ids, so state can be compressed into pairs of them. Ids are drawn
+//   from PostgreSQL sequences when there is a database, and counted in
+//   memory otherwise. Both directions of every mapping are kept in LRU
+//   caches in front of the database; an id never changes once handed out.
+//
+// =============================================================================
+
+use std::{collections::HashMap, sync::RwLock};
+
+use matrixon_db::ShortIdRepo;
+use ruma::api::client::error::ErrorKind;
+
+use crate::{
+    service::cache::{Cache, CacheStats},
+    Error, Result,
+};
+
+/// Base capacities of the caches, before the capacity modifier
+const STATEKEY_CACHE_CAPACITY: usize = 100_000;
+const EVENTID_CACHE_CAPACITY: usize = 300_000;
+
+/// Every mapping, when there is no database to keep them in
+#[derive(Debug, Default)]
+struct Memory {
+    statekeys: HashMap<(String, String), u64>,
+    statekeys_by_short: HashMap<u64, (String, String)>,
+    event_ids: HashMap<String, u64>,
+    event_ids_by_short: HashMap<u64, String>,
+}
+
+#[derive(Debug)]
+struct Caches {
+    statekeyshort: Cache<(String, String), u64>,
+    shortstatekey: Cache<u64, (String, String)>,
+    eventidshort: Cache<String, u64>,
+    shorteventid: Cache<u64, String>,
+}
+
+impl Caches {
+    fn new(capacity_modifier: f64) -> Self {
+        Self {
+            statekeyshort: Cache::new("statekeyshort", STATEKEY_CACHE_CAPACITY, capacity_modifier),
+            shortstatekey: Cache::new("shortstatekey", STATEKEY_CACHE_CAPACITY, capacity_modifier),
+            eventidshort: Cache::new("eventidshort", EVENTID_CACHE_CAPACITY, capacity_modifier),
+            shorteventid: Cache::new("shorteventid", EVENTID_CACHE_CAPACITY, capacity_modifier),
+        }
+    }
+}
+
+/// Short id service
+#[derive(Debug)]
+pub struct Service {
+    caches: Caches,
+    memory: RwLock<Memory>,
+    repository: Option<ShortIdRepo>,
+}
+
+impl Default for Service {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self {
+            caches: Caches::new(1.0),
+            memory: RwLock::default(),
+            repository: None,
+        }
+    }
+
+    /// Scale the caches by `matrixon_cache_capacity_modifier`
+    pub fn with_cache_capacity_modifier(mut self, modifier: f64) -> Self {
+        self.caches = Caches::new(modifier);
+        self
+    }
+
+    /// Draw ids from the sequences of `repository` and store them there
+    pub fn with_repository(mut self, repository: ShortIdRepo) -> Self {
+        self.repository = Some(repository);
+        self
+    }
+
+    /// The caches, for exporting their statistics and the admin API
+    pub fn caches(&self) -> [&dyn CacheStats; 4] {
+        [&self.caches.statekeyshort, &self.caches.shortstatekey, &self.caches.eventidshort, &self.caches.shorteventid]
+    }
+
+    /// Short id of a (type, state key) pair, handing out a new one the
+    /// first time the pair is seen
+    pub async fn get_or_create_shortstatekey(&self, event_type: &str, state_key: &str) -> Result<u64> {
+        let key = (event_type.to_owned(), state_key.to_owned());
+        if let Some(short) = self.caches.statekeyshort.get(&key) {
+            return Ok(short);
+        }
+        let short = match &self.repository {
+            Some(repository) => repository
+                .get_or_create_shortstatekey(event_type, state_key)
+                .await
+                .map_err(|e| Error::BadDatabase(e.to_string()))?,
+            None => {
+                let mut memory = self.memory.write().unwrap();
+                match memory.statekeys.get(&key) {
+                    Some(short) => *short,
+                    None => {
+                        let short = memory.statekeys.len() as u64 + 1;
+                        memory.statekeys.insert(key.clone(), short);
+                        memory.statekeys_by_short.insert(short, key.clone());
+                        short
+                    }
+                }
+            }
+        };
+        self.caches.shortstatekey.insert(short, key.clone());
+        self.caches.statekeyshort.insert(key, short);
+        Ok(short)
+    }
+
+    /// Short id of a (type, state key) pair, if it has one
+    pub async fn get_shortstatekey(&self, event_type: &str, state_key: &str) -> Result<Option<u64>> {
+        let key = (event_type.to_owned(), state_key.to_owned());
+        if let Some(short) = self.caches.statekeyshort.get(&key) {
+            return Ok(Some(short));
+        }
+        let short = match &self.repository {
+            Some(repository) => repository
+                .get_shortstatekey(event_type, state_key)
+                .await
+                .map_err(|e| Error::BadDatabase(e.to_string()))?,
+            None => self.memory.read().unwrap().statekeys.get(&key).copied(),
+        };
+        if let Some(short) = short {
+            self.caches.statekeyshort.insert(key, short);
+        }
+        Ok(short)
+    }
+
+    /// The (type, state key) pair a short state key stands for
+    pub async fn get_statekey_from_short(&self, shortstatekey: u64) -> Result<(String, String)> {
+        if let Some(key) = self.caches.shortstatekey.get(&shortstatekey) {
+            return Ok(key);
+        }
+        let key = match &self.repository {
+            Some(repository) => repository
+                .get_statekey_from_short(shortstatekey)
+                .await
+                .map_err(|e| Error::BadDatabase(e.to_string()))?,
+            None => self.memory.read().unwrap().statekeys_by_short.get(&shortstatekey).cloned(),
+        }
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Unknown short state key"))?;
+        self.caches.shortstatekey.insert(shortstatekey, key.clone());
+        Ok(key)
+    }
+
+    /// Short id of an event, handing out a new one the first time the
+    /// event is seen
+    pub async fn get_or_create_shorteventid(&self, event_id: &str) -> Result<u64> {
+        if let Some(short) = self.caches.eventidshort.get(&event_id.to_owned()) {
+            return Ok(short);
+        }
+        let short = match &self.repository {
+            Some(repository) => repository
+                .get_or_create_shorteventid(event_id)
+                .await
+                .map_err(|e| Error::BadDatabase(e.to_string()))?,
+            None => {
+                let mut memory = self.memory.write().unwrap();
+                match memory.event_ids.get(event_id) {
+                    Some(short) => *short,
+                    None => {
+                        let short = memory.event_ids.len() as u64 + 1;
+                        memory.event_ids.insert(event_id.to_owned(), short);
+                        memory.event_ids_by_short.insert(short, event_id.to_owned());
+                        short
+                    }
+                }
+            }
+        };
+        self.caches.shorteventid.insert(short, event_id.to_owned());
+        self.caches.eventidshort.insert(event_id.to_owned(), short);
+        Ok(short)
+    }
+
+    /// The event id a short event id stands for
+    pub async fn get_eventid_from_short(&self, shorteventid: u64) -> Result<String> {
+        if let Some(event_id) = self.caches.shorteventid.get(&shorteventid) {
+            return Ok(event_id);
+        }
+        let event_id = match &self.repository {
+            Some(repository) => repository
+                .get_eventid_from_short(shorteventid)
+                .await
+                .map_err(|e| Error::BadDatabase(e.to_string()))?,
+            None => self.memory.read().unwrap().event_ids_by_short.get(&shorteventid).cloned(),
+        }
+        .ok_or(Error::BadRequest(ErrorKind::NotFound, "Unknown short event id"))?;
+        self.caches.shorteventid.insert(shorteventid, event_id.clone());
+        Ok(event_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_short_ids_are_stable_past_the_caches() {
+        let service = Service::new().with_cache_capacity_modifier(0.00001);
+        let name = service.get_or_create_shortstatekey("m.room.name", "").await.unwrap();
+        let member = service.get_or_create_shortstatekey("m.room.member", "@a:matrixon.local").await.unwrap();
+        assert_ne!(name, member);
+        assert_eq!(service.get_or_create_shortstatekey("m.room.name", "").await.unwrap(), name);
+        assert_eq!(service.get_shortstatekey("m.room.member", "@a:matrixon.local").await.unwrap(), Some(member));
+        assert_eq!(service.get_shortstatekey("m.room.topic", "").await.unwrap(), None);
+        assert_eq!(service.get_statekey_from_short(name).await.unwrap(), ("m.room.name".to_owned(), String::new()));
+        assert!(service.get_statekey_from_short(member + 1).await.is_err());
+
+        let first = service.get_or_create_shorteventid("$first").await.unwrap();
+        let second = service.get_or_create_shorteventid("$second").await.unwrap();
+        for cache in service.caches() {
+            cache.clear_entries();
+        }
+        assert_eq!(service.get_or_create_shorteventid("$first").await.unwrap(), first);
+        assert_eq!(service.get_eventid_from_short(second).await.unwrap(), "$second");
+    }
+}
@@ -0,0 +1,593 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Space Hierarchy
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   The rooms of a space and its subspaces, walked breadth first along
+//   their `m.space.child` state. Rooms hosted here are summarized from
+//   their state; rooms only known by their `via` servers are asked for over
+//   federation, a few at a time and at most `MAX_REMOTE_FETCHES` per
+//   request. Answers are cached for a few minutes and failures for one, so
+//   paging through a large space does not ask again for every page. Remote
+//   rooms that could not be fetched count toward the page size like
+//   returned rooms, and pagination tokens are the number of rooms already
+//   walked this way. Other servers get the summary of a local space with
+//   its direct children.
+//
+// =============================================================================
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ruma::api::client::error::ErrorKind;
+use serde_json::{json, Value};
+use tracing::debug;
+
+use crate::{
+    service::{cache::Cache, room_directory, timeline},
+    Error, Result,
+};
+
+/// Page size when the request sets no limit
+pub const DEFAULT_LIMIT: usize = 50;
+
+/// Most rooms returned in one page
+pub const MAX_LIMIT: usize = 100;
+
+/// Depth walked when the request sets none
+pub const DEFAULT_MAX_DEPTH: usize = 5;
+
+/// How long summaries of remote spaces are reused
+const REMOTE_CACHE_MS: u64 = 5 * 60 * 1000;
+
+/// How long a remote space that could not be fetched is not asked for again
+const FAILURE_CACHE_MS: u64 = 60 * 1000;
+
+/// Remote spaces whose summaries are cached
+const REMOTE_CACHE_CAPACITY: usize = 1000;
+
+/// Remote spaces fetched at once
+const REMOTE_CONCURRENCY: usize = 4;
+
+/// Remote spaces fetched for one request
+const MAX_REMOTE_FETCHES: usize = MAX_LIMIT;
+
+/// `via` servers asked for one remote space
+const MAX_VIA_SERVERS: usize = 3;
+
+/// Who a summary is for: rooms are only described to those who could see
+/// or join them
+#[derive(Debug, Clone, Copy)]
+pub enum Requester<'a> {
+    User(&'a str),
+    Server(&'a str),
+}
+
+/// Parameters of a client hierarchy request
+#[derive(Debug, Clone, Default)]
+pub struct HierarchyRequest {
+    pub limit: Option<usize>,
+    pub max_depth: Option<usize>,
+    pub from: Option<String>,
+    pub suggested_only: bool,
+}
+
+/// A room of the hierarchy with the children to walk next
+#[derive(Debug, Clone)]
+struct Summary {
+    chunk: Value,
+    children: Vec<(String, Vec<String>)>,
+}
+
+/// What walking a room gave
+enum Walked {
+    Room(Summary),
+    /// A remote room none of its `via` servers described
+    Failed,
+    /// A local room the requester may not see
+    Hidden,
+}
+
+/// Space hierarchy service
+#[derive(Debug)]
+pub struct Service {
+    /// Federation responses for remote spaces, `None` when fetching failed,
+    /// with when they were fetched
+    remote: Cache<String, (u64, Option<Value>)>,
+}
+
+impl Default for Service {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self { remote: Cache::new("space_hierarchies", REMOTE_CACHE_CAPACITY, 1.0) }
+    }
+
+    /// Answer the client `/hierarchy` endpoint for `user_id`. `fetch` asks
+    /// a server (second argument) for its federation hierarchy of a room.
+    pub async fn hierarchy<F, Fut>(
+        &self,
+        timeline: &timeline::Service,
+        room_id: &str,
+        user_id: &str,
+        request: &HierarchyRequest,
+        fetch: F,
+    ) -> Result<Value>
+    where
+        F: Fn(String, String) -> Fut,
+        Fut: Future<Output = Option<Value>>,
+    {
+        let limit = request.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+        let max_depth = request.max_depth.unwrap_or(DEFAULT_MAX_DEPTH);
+        let skip = match &request.from {
+            Some(from) => from
+                .parse::<usize>()
+                .map_err(|_| Error::BadRequest(ErrorKind::InvalidParam, "Invalid pagination token"))?,
+            None => 0,
+        };
+
+        let requester = Requester::User(user_id);
+        let root_via: Vec<String> = room_id.split_once(':').map(|(_, server)| server.to_owned()).into_iter().collect();
+        let mut queue = VecDeque::from([(room_id.to_owned(), root_via, 0)]);
+        // Summaries of remote rooms other servers sent along as children
+        let mut known: HashMap<String, Value> = HashMap::new();
+        let mut seen = HashSet::new();
+        let mut rooms = Vec::new();
+        // Rooms returned or failed, in walk order and on this page
+        let mut walked = 0;
+        let mut counted = 0;
+        let mut fetches = 0;
+        let mut next_batch = None;
+
+        while let Some((current, via, depth)) = queue.pop_front() {
+            if !seen.insert(current.clone()) {
+                continue;
+            }
+            let needs_fetch = |room: &str, depth: usize| {
+                !timeline.room_exists(room) && self.needs_fetch(room, depth < max_depth, &known)
+            };
+            if needs_fetch(&current, depth) {
+                if fetches >= MAX_REMOTE_FETCHES {
+                    next_batch = Some(walked.max(skip));
+                    break;
+                }
+                // Fetch the next remote rooms of the walk along with this one
+                let mut batch = vec![(current.clone(), via.clone())];
+                for (room, room_via, room_depth) in &queue {
+                    if batch.len() >= REMOTE_CONCURRENCY || fetches + batch.len() >= MAX_REMOTE_FETCHES {
+                        break;
+                    }
+                    if !seen.contains(room) && !batch.iter().any(|(batched, _)| batched == room) && needs_fetch(room, *room_depth) {
+                        batch.push((room.clone(), room_via.clone()));
+                    }
+                }
+                fetches += batch.len();
+                futures::future::join_all(
+                    batch.iter().map(|(room, via)| self.fetch_remote(room, via, request.suggested_only, &fetch)),
+                )
+                .await;
+            }
+
+            let summary = if timeline.room_exists(&current) {
+                if accessible(timeline, &current, requester) {
+                    Walked::Room(local_summary(timeline, &current, request.suggested_only))
+                } else {
+                    Walked::Hidden
+                }
+            } else {
+                self.remote_summary(&current, &via, depth < max_depth, request.suggested_only, &mut known, &fetch)
+                    .await
+                    .map_or(Walked::Failed, Walked::Room)
+            };
+            let chunk = match summary {
+                Walked::Room(summary) => {
+                    if depth < max_depth {
+                        queue.extend(summary.children.into_iter().map(|(child, via)| (child, via, depth + 1)));
+                    }
+                    Some(summary.chunk)
+                }
+                Walked::Failed | Walked::Hidden if current == room_id => {
+                    return Err(Error::BadRequest(ErrorKind::forbidden(), "You cannot see this space"));
+                }
+                Walked::Failed => None,
+                Walked::Hidden => continue,
+            };
+
+            walked += 1;
+            if walked > skip {
+                if counted == limit {
+                    next_batch = Some(walked - 1);
+                    break;
+                }
+                counted += 1;
+                rooms.extend(chunk);
+            }
+        }
+
+        let mut response = json!({ "rooms": rooms });
+        if let Some(next_batch) = next_batch {
+            response["next_batch"] = json!(next_batch.to_string());
+        }
+        Ok(response)
+    }
+
+    /// Whether the summary of the remote room `room_id` must be fetched
+    fn needs_fetch(&self, room_id: &str, with_children: bool, known: &HashMap<String, Value>) -> bool {
+        if let Some(chunk) = known.get(room_id) {
+            if !with_children || chunk["room_type"] != "m.space" {
+                return false;
+            }
+        }
+        self.cached(room_id).is_none()
+    }
+
+    /// The cached federation response for `room_id`, `Some(None)` when
+    /// fetching it failed recently
+    fn cached(&self, room_id: &str) -> Option<Option<Value>> {
+        let (fetched_at, response) = self.remote.get(&room_id.to_owned())?;
+        let ttl = if response.is_some() { REMOTE_CACHE_MS } else { FAILURE_CACHE_MS };
+        (fetched_at + ttl > now_millis()).then_some(response)
+    }
+
+    /// Summary of a room hosted elsewhere, from the summaries other servers
+    /// sent along or by asking the `via` servers
+    async fn remote_summary<F, Fut>(
+        &self,
+        room_id: &str,
+        via: &[String],
+        with_children: bool,
+        suggested_only: bool,
+        known: &mut HashMap<String, Value>,
+        fetch: &F,
+    ) -> Option<Summary>
+    where
+        F: Fn(String, String) -> Fut,
+        Fut: Future<Output = Option<Value>>,
+    {
+        if let Some(chunk) = known.get(room_id) {
+            // Only spaces have children worth asking for
+            if !with_children || chunk["room_type"] != "m.space" {
+                let mut chunk = chunk.clone();
+                chunk["children_state"] = json!([]);
+                return Some(Summary { chunk, children: Vec::new() });
+            }
+        }
+
+        let response = self.fetch_remote(room_id, via, suggested_only, fetch).await?;
+        let inaccessible: HashSet<&str> =
+            response["inaccessible_children"].as_array().into_iter().flatten().filter_map(Value::as_str).collect();
+        for child in response["children"].as_array().into_iter().flatten() {
+            if let Some(child_id) = child["room_id"].as_str() {
+                known.insert(child_id.to_owned(), child.clone());
+            }
+        }
+        let chunk = response["room"].clone();
+        let children = child_rooms(chunk["children_state"].as_array().map(Vec::as_slice).unwrap_or_default(), suggested_only)
+            .into_iter()
+            .filter(|(child, _)| !inaccessible.contains(child.as_str()))
+            .collect();
+        Some(Summary { chunk, children })
+    }
+
+    async fn fetch_remote<F, Fut>(&self, room_id: &str, via: &[String], suggested_only: bool, fetch: &F) -> Option<Value>
+    where
+        F: Fn(String, String) -> Fut,
+        Fut: Future<Output = Option<Value>>,
+    {
+        if let Some(response) = self.cached(room_id) {
+            return response;
+        }
+
+        let path = format!(
+            "/_matrix/federation/v1/hierarchy/{}?suggested_only={}",
+            url::form_urlencoded::byte_serialize(room_id.as_bytes()).collect::<String>(),
+            suggested_only
+        );
+        for server in via.iter().take(MAX_VIA_SERVERS) {
+            debug!("🌐 Asking {} for the hierarchy of {}", server, room_id);
+            let Some(response) = fetch(server.clone(), path.clone()).await else {
+                continue;
+            };
+            if response["room"]["room_id"] != room_id {
+                debug!("❌ {} returned an invalid hierarchy for {}", server, room_id);
+                continue;
+            }
+            self.remote.insert(room_id.to_owned(), (now_millis(), Some(response.clone())));
+            return Some(response);
+        }
+        self.remote.insert(room_id.to_owned(), (now_millis(), None));
+        None
+    }
+}
+
+/// Answer `GET /_matrix/federation/v1/hierarchy/{roomId}` for `server`
+pub fn federation_hierarchy(timeline: &timeline::Service, room_id: &str, server: &str, suggested_only: bool) -> Result<Value> {
+    let requester = Requester::Server(server);
+    if !timeline.room_exists(room_id) || !accessible(timeline, room_id, requester) {
+        return Err(Error::BadRequest(ErrorKind::NotFound, "Unknown room or not accessible"));
+    }
+
+    let summary = local_summary(timeline, room_id, suggested_only);
+    let mut children = Vec::new();
+    let mut inaccessible_children = Vec::new();
+    for (child, _) in summary.children {
+        if !timeline.room_exists(&child) {
+            continue;
+        }
+        if accessible(timeline, &child, requester) {
+            children.push(room_chunk(timeline, &child));
+        } else {
+            inaccessible_children.push(child);
+        }
+    }
+    Ok(json!({
+        "room": summary.chunk,
+        "children": children,
+        "inaccessible_children": inaccessible_children
+    }))
+}
+
+/// Whether `requester` may see the summary of a local room: anyone may for
+/// rooms they could join or read, otherwise only members (or servers with
+/// members), and for restricted rooms members of an allowed room too
+fn accessible(timeline: &timeline::Service, room_id: &str, requester: Requester<'_>) -> bool {
+    let content = |event_type: &str| timeline.state_event(room_id, event_type, "").map(|event| event["content"].clone());
+    let join_rules = content("m.room.join_rules").unwrap_or_default();
+    let join_rule = join_rules["join_rule"].as_str().unwrap_or("invite");
+    let world_readable = content("m.room.history_visibility")
+        .is_some_and(|content| content["history_visibility"] == "world_readable");
+    if world_readable || matches!(join_rule, "public" | "knock" | "knock_restricted") {
+        return true;
+    }
+
+    let is_member = |room_id: &str| {
+        timeline.current_state(room_id).iter().any(|event| {
+            let member = event["state_key"].as_str().unwrap_or_default();
+            let matches = match requester {
+                Requester::User(user_id) => member == user_id,
+                Requester::Server(server) => member.split_once(':').is_some_and(|(_, domain)| domain == server),
+            };
+            event["type"] == "m.room.member"
+                && matches
+                && matches!(event["content"]["membership"].as_str(), Some("join" | "invite"))
+        })
+    };
+    if is_member(room_id) {
+        return true;
+    }
+    join_rule == "restricted" && allowed_room_ids(&join_rules).iter().any(|allowed| is_member(allowed))
+}
+
+/// Rooms whose members may join a restricted room
+fn allowed_room_ids(join_rules: &Value) -> Vec<String> {
+    join_rules["allow"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|rule| rule["type"] == "m.room_membership")
+        .filter_map(|rule| rule["room_id"].as_str().map(str::to_owned))
+        .collect()
+}
+
+/// Directory entry of a room with its room type and restricted join rule
+fn room_chunk(timeline: &timeline::Service, room_id: &str) -> Value {
+    let mut chunk = room_directory::public_room(timeline, room_id);
+    if let Some(room_type) = timeline
+        .state_event(room_id, "m.room.create", "")
+        .and_then(|event| event["content"]["type"].as_str().map(str::to_owned))
+    {
+        chunk["room_type"] = json!(room_type);
+    }
+    let join_rules = timeline
+        .state_event(room_id, "m.room.join_rules", "")
+        .map(|event| event["content"].clone())
+        .unwrap_or_default();
+    let allowed = allowed_room_ids(&join_rules);
+    if !allowed.is_empty() {
+        chunk["allowed_room_ids"] = json!(allowed);
+    }
+    chunk
+}
+
+/// Summary of a local room: its entry with the stripped `m.space.child`
+/// events, and the children to walk
+fn local_summary(timeline: &timeline::Service, room_id: &str, suggested_only: bool) -> Summary {
+    let mut children_state: Vec<Value> = timeline
+        .current_state(room_id)
+        .into_iter()
+        .filter(|event| event["type"] == "m.space.child" && event["content"]["via"].as_array().is_some_and(|via| !via.is_empty()))
+        .map(|event| {
+            json!({
+                "type": event["type"],
+                "state_key": event["state_key"],
+                "content": event["content"],
+                "sender": event["sender"],
+                "origin_server_ts": event["origin_server_ts"]
+            })
+        })
+        .collect();
+    // Ordered by the `order` key, then as the spec says, by timestamp
+    children_state.sort_by(|a, b| {
+        let order = |event: &Value| event["content"]["order"].as_str().map(str::to_owned);
+        match (order(a), order(b)) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => std::cmp::Ordering::Equal,
+        }
+        .then_with(|| a["origin_server_ts"].as_u64().cmp(&b["origin_server_ts"].as_u64()))
+        .then_with(|| a["state_key"].as_str().cmp(&b["state_key"].as_str()))
+    });
+
+    let children = child_rooms(&children_state, suggested_only);
+    let mut chunk = room_chunk(timeline, room_id);
+    chunk["children_state"] = json!(children_state);
+    Summary { chunk, children }
+}
+
+/// `(room_id, via)` of the children listed in `children_state`
+fn child_rooms(children_state: &[Value], suggested_only: bool) -> Vec<(String, Vec<String>)> {
+    children_state
+        .iter()
+        .filter(|event| !suggested_only || event["content"]["suggested"] == true)
+        .filter_map(|event| {
+            let via = event["content"]["via"].as_array()?.iter().filter_map(Value::as_str).map(str::to_owned).collect();
+            Some((event["state_key"].as_str()?.to_owned(), via))
+        })
+        .collect()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: &str = "@owner:matrixon.local";
+
+    fn create_room(timeline: &timeline::Service, room_id: &str, room_type: Option<&str>, join_rule: &str) {
+        let mut create = json!({ "creator": OWNER, "room_version": "10" });
+        if let Some(room_type) = room_type {
+            create["type"] = json!(room_type);
+        }
+        timeline.append_event(room_id, OWNER, "m.room.create", Some(""), create);
+        timeline.append_event(room_id, OWNER, "m.room.member", Some(OWNER), json!({ "membership": "join" }));
+        timeline.append_event(room_id, OWNER, "m.room.join_rules", Some(""), json!({ "join_rule": join_rule }));
+    }
+
+    fn add_child(timeline: &timeline::Service, space: &str, child: &str, via: &str, suggested: bool) {
+        timeline.append_event(space, OWNER, "m.space.child", Some(child), json!({ "via": [via], "suggested": suggested }));
+    }
+
+    async fn no_federation(_: String, _: String) -> Option<Value> {
+        None
+    }
+
+    async fn remote_server(server: String, path: String) -> Option<Value> {
+        assert_eq!(server, "remote.example");
+        assert!(path.starts_with("/_matrix/federation/v1/hierarchy/%21space%3Aremote.example"));
+        Some(json!({
+            "room": {
+                "room_id": "!space:remote.example",
+                "room_type": "m.space",
+                "children_state": [{
+                    "type": "m.space.child",
+                    "state_key": "!lobby:remote.example",
+                    "content": { "via": ["remote.example"] }
+                }]
+            },
+            "children": [{ "room_id": "!lobby:remote.example", "num_joined_members": 7 }],
+            "inaccessible_children": []
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_local_hierarchy_pagination() {
+        let timeline = timeline::Service::new();
+        let service = Service::new();
+        create_room(&timeline, "!space:matrixon.local", Some("m.space"), "public");
+        create_room(&timeline, "!sub:matrixon.local", Some("m.space"), "public");
+        create_room(&timeline, "!a:matrixon.local", None, "public");
+        create_room(&timeline, "!secret:matrixon.local", None, "invite");
+        add_child(&timeline, "!space:matrixon.local", "!sub:matrixon.local", "matrixon.local", true);
+        add_child(&timeline, "!space:matrixon.local", "!secret:matrixon.local", "matrixon.local", true);
+        add_child(&timeline, "!sub:matrixon.local", "!a:matrixon.local", "matrixon.local", false);
+
+        let user = "@user:matrixon.local";
+        let request = HierarchyRequest { limit: Some(2), ..Default::default() };
+        let page = service.hierarchy(&timeline, "!space:matrixon.local", user, &request, no_federation).await.unwrap();
+        let ids = |page: &Value| page["rooms"].as_array().unwrap().iter().map(|room| room["room_id"].clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&page), vec!["!space:matrixon.local", "!sub:matrixon.local"]);
+        assert_eq!(page["rooms"][0]["room_type"], "m.space");
+        assert_eq!(page["rooms"][0]["children_state"].as_array().unwrap().len(), 2);
+
+        let request = HierarchyRequest { from: page["next_batch"].as_str().map(str::to_owned), ..request };
+        let page = service.hierarchy(&timeline, "!space:matrixon.local", user, &request, no_federation).await.unwrap();
+        // The invite-only room is left out
+        assert_eq!(ids(&page), vec!["!a:matrixon.local"]);
+        assert!(page.get("next_batch").is_none());
+
+        let request = HierarchyRequest { suggested_only: true, max_depth: Some(1), ..Default::default() };
+        let page = service.hierarchy(&timeline, "!space:matrixon.local", user, &request, no_federation).await.unwrap();
+        assert_eq!(ids(&page), vec!["!space:matrixon.local", "!sub:matrixon.local"]);
+
+        let response = federation_hierarchy(&timeline, "!space:matrixon.local", "remote.example", false).unwrap();
+        assert_eq!(response["children"][0]["room_id"], "!sub:matrixon.local");
+        assert_eq!(response["inaccessible_children"], json!(["!secret:matrixon.local"]));
+        assert!(federation_hierarchy(&timeline, "!secret:matrixon.local", "remote.example", false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_remote_children_are_stitched_in() {
+        let timeline = timeline::Service::new();
+        let service = Service::new();
+        create_room(&timeline, "!space:matrixon.local", Some("m.space"), "public");
+        add_child(&timeline, "!space:matrixon.local", "!space:remote.example", "remote.example", false);
+
+        let request = HierarchyRequest::default();
+        let page = service.hierarchy(&timeline, "!space:matrixon.local", OWNER, &request, remote_server).await.unwrap();
+        let rooms = page["rooms"].as_array().unwrap();
+        assert_eq!(rooms.len(), 3);
+        assert_eq!(rooms[1]["room_id"], "!space:remote.example");
+        assert_eq!(rooms[2]["num_joined_members"], 7);
+        assert_eq!(rooms[2]["children_state"], json!([]));
+
+        // Cached: asking again needs no federation
+        let page = service.hierarchy(&timeline, "!space:matrixon.local", OWNER, &request, no_federation).await.unwrap();
+        assert_eq!(page["rooms"].as_array().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_children_count_toward_the_limit() {
+        let timeline = timeline::Service::new();
+        let service = Service::new();
+        create_room(&timeline, "!space:matrixon.local", Some("m.space"), "public");
+        for i in 0..6 {
+            let child = format!("!room{}:down{}.example", i, i);
+            let content = json!({ "via": ["a.example", "b.example", "c.example", "d.example"], "order": format!("a{}", i) });
+            timeline.append_event("!space:matrixon.local", OWNER, "m.space.child", Some(&child), content);
+        }
+        create_room(&timeline, "!last:matrixon.local", None, "public");
+        timeline.append_event("!space:matrixon.local", OWNER, "m.space.child", Some("!last:matrixon.local"), json!({ "via": ["matrixon.local"], "order": "b" }));
+
+        let asked = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let unreachable = |_: String, _: String| {
+            let asked = asked.clone();
+            async move {
+                asked.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                None
+            }
+        };
+        let request = HierarchyRequest { limit: Some(3), ..Default::default() };
+        let page = service.hierarchy(&timeline, "!space:matrixon.local", OWNER, &request, unreachable).await.unwrap();
+        assert_eq!(page["rooms"].as_array().unwrap().len(), 1);
+        assert_eq!(page["next_batch"], "3");
+        // At most three via servers for each of a batch of rooms
+        assert!(asked.load(std::sync::atomic::Ordering::SeqCst) <= REMOTE_CONCURRENCY * MAX_VIA_SERVERS);
+
+        // Failures are cached, so later pages move on without asking again
+        let request = HierarchyRequest { from: Some("3".to_owned()), limit: Some(3), ..Default::default() };
+        let page = service.hierarchy(&timeline, "!space:matrixon.local", OWNER, &request, unreachable).await.unwrap();
+        assert!(page["rooms"].as_array().unwrap().is_empty());
+        let request = HierarchyRequest { from: page["next_batch"].as_str().map(str::to_owned), ..request };
+        let page = service.hierarchy(&timeline, "!space:matrixon.local", OWNER, &request, unreachable).await.unwrap();
+        assert_eq!(page["rooms"][0]["room_id"], "!last:matrixon.local");
+        assert!(page.get("next_batch").is_none());
+        assert_eq!(asked.load(std::sync::atomic::Ordering::SeqCst), 6 * MAX_VIA_SERVERS);
+    }
+}
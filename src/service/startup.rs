@@ -0,0 +1,314 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Startup Orchestration
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Brings up the services the server depends on before it starts
+//   listening: the database, its shards, the TCP dependencies of
+//   `startup.dependencies` such as Redis, and the IPFS gateway, in that
+//   order. Each service is checked until it answers, backing off
+//   exponentially between checks, for a number of attempts or, when waiting
+//   for dependencies, until a deadline. Startup fails on the first required
+//   service that stays unavailable; optional ones are skipped.
+//
+// =============================================================================
+
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::future::BoxFuture;
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+use crate::{
+    config::{RetryConfig, StartupConfig},
+    service::ipfs, Config,
+};
+
+/// How long connecting to a TCP dependency may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Checks whether a service is up, failing with why it is not
+type Check = Box<dyn Fn() -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+/// How a service is retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Checks before giving up, unbounded when `None`
+    pub attempts: Option<u32>,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Time after which the service is given up on
+    pub deadline: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// `retry`, or waiting until the deadline of `startup` when it waits
+    /// for dependencies
+    pub fn new(retry: RetryConfig, startup: &StartupConfig) -> Self {
+        Self {
+            attempts: (!startup.wait_for_deps).then_some(retry.attempts.max(1)),
+            initial_backoff: Duration::from_millis(retry.initial_backoff_ms),
+            max_backoff: Duration::from_millis(retry.max_backoff_ms.max(retry.initial_backoff_ms)),
+            deadline: startup.wait_for_deps.then(|| Duration::from_secs(startup.wait_timeout_s)),
+        }
+    }
+
+    /// Wait after the `failures`th failed check
+    pub fn backoff(&self, failures: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(1 << failures.saturating_sub(1).min(16))
+            .min(self.max_backoff)
+    }
+}
+
+struct Step {
+    name: String,
+    required: bool,
+    policy: RetryPolicy,
+    check: Check,
+}
+
+/// What bringing up one service did
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceReport {
+    pub name: String,
+    pub required: bool,
+    pub attempts: u32,
+    pub available: bool,
+    /// Why the last check failed
+    pub error: Option<String>,
+}
+
+/// What bringing up every service did, in startup order
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StartupReport {
+    pub services: Vec<ServiceReport>,
+}
+
+impl StartupReport {
+    /// Whether `name` came up; services that were not part of startup are
+    /// not unavailable
+    pub fn is_available(&self, name: &str) -> bool {
+        self.services.iter().find(|service| service.name == name).is_none_or(|service| service.available)
+    }
+}
+
+/// Services to bring up, in order
+#[derive(Default)]
+pub struct Startup {
+    steps: Vec<Step>,
+}
+
+impl Startup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bring up `name` after the services added before, checking it with
+    /// `check`
+    pub fn step<F, Fut>(mut self, name: impl Into<String>, required: bool, policy: RetryPolicy, check: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.steps.push(Step {
+            name: name.into(),
+            required,
+            policy,
+            check: Box::new(move || Box::pin(check())),
+        });
+        self
+    }
+
+    /// Names of the services, in startup order
+    pub fn services(&self) -> Vec<&str> {
+        self.steps.iter().map(|step| step.name.as_str()).collect()
+    }
+
+    /// Bring up every service in order, failing on the first required
+    /// service that stays unavailable
+    pub async fn run(&self) -> Result<StartupReport, String> {
+        let mut report = StartupReport::default();
+        for step in &self.steps {
+            let service = bring_up(step).await;
+            match (&service.error, service.available) {
+                (_, true) => info!("✅ {} is available", service.name),
+                (Some(error), false) if step.required => {
+                    return Err(format!(
+                        "Required service {} is unavailable after {} attempts: {}",
+                        service.name, service.attempts, error
+                    ));
+                }
+                (error, false) => warn!(
+                    "⚠️ Starting without optional service {}: {}",
+                    service.name,
+                    error.as_deref().unwrap_or("unavailable")
+                ),
+            }
+            report.services.push(service);
+        }
+        Ok(report)
+    }
+}
+
+/// Check `step` until it answers or its policy gives up
+async fn bring_up(step: &Step) -> ServiceReport {
+    let start = Instant::now();
+    let mut attempts = 0;
+    let error = loop {
+        attempts += 1;
+        let error = match (step.check)().await {
+            Ok(()) => break None,
+            Err(error) => error,
+        };
+        let backoff = step.policy.backoff(attempts);
+        let out_of_attempts = step.policy.attempts.is_some_and(|max| attempts >= max);
+        let past_deadline = step.policy.deadline.is_some_and(|deadline| start.elapsed() + backoff > deadline);
+        if out_of_attempts || past_deadline {
+            break Some(error);
+        }
+        warn!("⏳ {} is not available yet ({}), retrying in {:?}", step.name, error, backoff);
+        tokio::time::sleep(backoff).await;
+    };
+    ServiceReport {
+        name: step.name.clone(),
+        required: step.required,
+        attempts,
+        available: error.is_none(),
+        error,
+    }
+}
+
+/// The services `config` depends on, in startup order. The services are
+/// built once startup tells whether the database is up, so its
+/// `repositories` are passed in.
+pub fn plan(config: &Config, repositories: Option<&matrixon_db::Repositories>) -> Startup {
+    let startup = config.startup();
+    let policy = |name: &str| RetryPolicy::new(startup.retry(name), &startup);
+    let mut plan = Startup::new();
+
+    if let Some(repositories) = repositories {
+        let pool = repositories.database_pool().pool().clone();
+        plan = plan.step("database", startup.is_required("database"), policy("database"), move || {
+            let pool = pool.clone();
+            async move { check_database(&pool).await }
+        });
+
+        let shards = repositories.shards().shards();
+        if shards.len() > 1 {
+            let pools: Vec<_> = shards.iter().map(|shard| shard.pool().clone()).collect();
+            plan = plan.step("database_shards", startup.is_required("database_shards"), policy("database_shards"), move || {
+                let pools = pools.clone();
+                async move {
+                    for (index, pool) in pools.iter().enumerate() {
+                        check_database(pool).await.map_err(|e| format!("shard {}: {}", index, e))?;
+                    }
+                    Ok(())
+                }
+            });
+        }
+    }
+
+    for dependency in &startup.dependencies {
+        let address = dependency.address.clone();
+        plan = plan.step(&dependency.name, startup.is_required(&dependency.name), policy(&dependency.name), move || {
+            check_tcp(address.clone())
+        });
+    }
+
+    // The default public gateway is not a dependency worth waiting for
+    if let Some(gateway) = config.ipfs_gateway.clone() {
+        let ipfs = Arc::new(ipfs::Service::new());
+        plan = plan.step("ipfs", startup.is_required("ipfs"), policy("ipfs"), move || {
+            let (ipfs, gateway) = (ipfs.clone(), gateway.clone());
+            async move { ipfs.check_gateway(&gateway).await }
+        });
+    }
+    plan
+}
+
+async fn check_database(pool: &sqlx::postgres::PgPool) -> Result<(), String> {
+    sqlx::query("SELECT 1").execute(pool).await.map(|_| ()).map_err(|e| e.to_string())
+}
+
+async fn check_tcp(address: String) -> Result<(), String> {
+    match tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&address)).await {
+        Ok(Ok(_)) => Ok(()),
+        Ok(Err(e)) => Err(format!("{}: {}", address, e)),
+        Err(_) => Err(format!("{}: no answer within {:?}", address, CONNECT_TIMEOUT)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    fn policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts: Some(attempts),
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            deadline: None,
+        }
+    }
+
+    /// A check failing `failures` times before it succeeds, counting its
+    /// calls
+    fn flaky(failures: u32, calls: Arc<AtomicU32>) -> impl Fn() -> futures::future::Ready<Result<(), String>> + Send + Sync {
+        move || {
+            let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+            futures::future::ready(if call > failures { Ok(()) } else { Err(format!("down {}", call)) })
+        }
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_the_maximum() {
+        let policy = policy(10);
+        let backoffs: Vec<u128> = (1..=5).map(|failures| policy.backoff(failures).as_millis()).collect();
+        assert_eq!(backoffs, [1, 2, 4, 4, 4]);
+
+        let startup = StartupConfig { wait_for_deps: true, ..StartupConfig::default() };
+        let waiting = RetryPolicy::new(RetryConfig::default(), &startup);
+        assert_eq!((waiting.attempts, waiting.deadline), (None, Some(Duration::from_secs(300))));
+    }
+
+    #[tokio::test]
+    async fn test_services_are_retried_in_order() {
+        let (database, redis, ipfs) = (Arc::new(AtomicU32::new(0)), Arc::new(AtomicU32::new(0)), Arc::new(AtomicU32::new(0)));
+        let startup = Startup::new()
+            .step("database", true, policy(3), flaky(2, database.clone()))
+            .step("ipfs", false, policy(2), flaky(u32::MAX, ipfs.clone()))
+            .step("redis", true, policy(1), flaky(0, redis.clone()));
+        assert_eq!(startup.services(), ["database", "ipfs", "redis"]);
+
+        let report = startup.run().await.unwrap();
+        assert_eq!((database.load(Ordering::SeqCst), ipfs.load(Ordering::SeqCst)), (3, 2));
+        assert!(report.is_available("database") && report.is_available("redis"));
+        assert!(!report.is_available("ipfs"));
+        assert_eq!(report.services[1].error.as_deref(), Some("down 2"));
+
+        let calls = Arc::new(AtomicU32::new(0));
+        let failing = Startup::new()
+            .step("database", true, policy(2), flaky(u32::MAX, calls.clone()))
+            .step("redis", true, policy(1), flaky(0, redis.clone()));
+        let error = failing.run().await.unwrap_err();
+        assert_eq!(error, "Required service database is unavailable after 2 attempts: down 2");
+        // Services after a required one that is down are not started
+        assert_eq!(redis.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,167 @@
+// =============================================================================
+// Matrixon Matrix NextServer - State Files
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   JSON files services keep their in-memory state in across restarts,
+//   below `database_path`. Files are replaced atomically: the new contents
+//   are written to a temporary file, synced and renamed over the old file,
+//   so a crash leaves either the old or the new state, never a torn file.
+//   Files are only readable by the server, as some hold secrets. Services
+//   take a snapshot of their state under their own lock and write it after
+//   releasing it; snapshots are numbered, so a slow writer never replaces a
+//   newer state with an older one.
+//
+// =============================================================================
+
+use std::{
+    fs, io,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::warn;
+
+/// Replace the file at `path` with `data`, atomically and with mode 0600
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    fs::create_dir_all(dir)?;
+    let file_name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    let tmp = dir.join(format!(".{}.tmp", file_name));
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)?;
+    // The rename itself is only durable once the directory is synced
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// A state file, written from numbered snapshots
+#[derive(Debug)]
+pub struct StateFile {
+    path: PathBuf,
+    snapshots: AtomicU64,
+    written: Mutex<u64>,
+}
+
+/// State serialized under its service's lock, waiting to be written
+#[derive(Debug)]
+#[must_use = "a snapshot does nothing until it is written"]
+pub struct Snapshot {
+    number: u64,
+    data: Vec<u8>,
+}
+
+impl StateFile {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, snapshots: AtomicU64::new(0), written: Mutex::new(0) }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The state last written, if the file exists and holds a valid one
+    pub fn load<T: DeserializeOwned>(&self) -> Option<T> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("⚠️ Could not read {}: {}", self.path.display(), e);
+                return None;
+            }
+        };
+        serde_json::from_slice(&data)
+            .map_err(|e| warn!("⚠️ Ignoring invalid state file {}: {}", self.path.display(), e))
+            .ok()
+    }
+
+    /// Serialize `state`; call while holding the lock `state` is read under
+    pub fn snapshot<T: Serialize>(&self, state: &T) -> Snapshot {
+        Snapshot {
+            number: self.snapshots.fetch_add(1, Ordering::SeqCst) + 1,
+            data: serde_json::to_vec(state).expect("state serializes"),
+        }
+    }
+
+    /// Write `snapshot`, unless a newer one has been written already
+    pub fn write(&self, snapshot: Snapshot) {
+        let mut written = self.written.lock().unwrap();
+        if snapshot.number <= *written {
+            return;
+        }
+        match write_atomic(&self.path, &snapshot.data) {
+            Ok(()) => *written = snapshot.number,
+            Err(e) => warn!("⚠️ Could not write {}: {}", self.path.display(), e),
+        }
+    }
+
+    /// Snapshot `state` and write it at once
+    pub fn save<T: Serialize>(&self, state: &T) {
+        self.write(self.snapshot(state));
+    }
+}
+
+/// File name standing for `name`, which may contain any character
+pub fn escape_file_name(name: &str) -> String {
+    name.bytes()
+        .enumerate()
+        .map(|(i, byte)| match byte {
+            // A leading dot would hide the file, or name a temporary file
+            b'.' if i == 0 => format!("%{:02X}", byte),
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'-' | b'_' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshots_replace_the_file_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = StateFile::new(dir.path().join("state.json"));
+        assert_eq!(file.load::<Vec<u32>>(), None);
+
+        let older = file.snapshot(&vec![1]);
+        let newer = file.snapshot(&vec![1, 2]);
+        file.write(newer);
+        file.write(older);
+        assert_eq!(file.load::<Vec<u32>>(), Some(vec![1, 2]));
+        assert!(!dir.path().join(".state.json.tmp").exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(file.path()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let name = "@alice:example.org/..";
+        assert_eq!(escape_file_name(name), "%40alice%3Aexample.org%2F..");
+        assert!(escape_file_name(".hidden").starts_with('%'));
+    }
+}
@@ -0,0 +1,443 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Third-Party Identifiers
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Email addresses and phone numbers (3PIDs) attached to accounts. Ownership
+//   is proven through validation sessions, either run locally or delegated
+//   to an identity server, and 3PIDs can additionally be bound to a user on
+//   an identity server for discovery. Attached 3PIDs are kept in a state
+//   file when one is configured; validation sessions only live in memory.
+//
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use matrixon_email::{EmailError, Mailer, Purpose};
+
+use rand::{distributions::Alphanumeric, Rng};
+use ruma::api::client::error::ErrorKind;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::{service::state_file::StateFile, Error, Result};
+
+/// Page where the link of a validation email leads
+pub const CONFIRM_PATH: &str = "/_matrix/client/unstable/add_threepid/email/confirm";
+
+/// A third-party identifier attached to an account
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThreePid {
+    pub medium: String,
+    pub address: String,
+    pub validated_at: u64,
+    pub added_at: u64,
+}
+
+#[derive(Debug, Clone)]
+struct ValidationSession {
+    medium: String,
+    address: String,
+    client_secret: String,
+    token: String,
+    send_attempt: u64,
+    validated_at: Option<u64>,
+}
+
+/// Third-party identifier service
+#[derive(Debug, Default)]
+pub struct Service {
+    client: reqwest::Client,
+    sessions: RwLock<HashMap<String, ValidationSession>>,
+    threepids: RwLock<HashMap<String, Vec<ThreePid>>>,
+    mailer: Option<(Arc<Mailer>, String)>,
+    state_file: Option<StateFile>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep attached 3PIDs in the file at `path`, loading the ones stored
+    /// there
+    pub fn with_state_file(mut self, path: Option<PathBuf>) -> Self {
+        if let Some(path) = path {
+            let state_file = StateFile::new(path);
+            if let Some(threepids) = state_file.load() {
+                self.threepids = RwLock::new(threepids);
+            }
+            self.state_file = Some(state_file);
+        }
+        self
+    }
+
+    /// Write the attached 3PIDs, read under `threepids`, to the state file
+    fn persist(&self, threepids: std::sync::RwLockWriteGuard<'_, HashMap<String, Vec<ThreePid>>>) {
+        let snapshot = self.state_file.as_ref().map(|state_file| (state_file, state_file.snapshot(&*threepids)));
+        drop(threepids);
+        if let Some((state_file, snapshot)) = snapshot {
+            state_file.write(snapshot);
+        }
+    }
+
+    /// Email validation tokens through `mailer`, naming the server
+    /// `server_name` in the emails
+    pub fn with_mailer(mut self, mailer: Arc<Mailer>, server_name: &str) -> Self {
+        self.mailer = Some((mailer, server_name.to_owned()));
+        self
+    }
+
+    /// Start (or resume) a local validation session and return its `sid`.
+    ///
+    /// Retrying with the same client secret, address and `send_attempt`
+    /// returns the existing session without sending another token. Emails
+    /// are written in `locale`, or the default locale when unset.
+    pub fn request_token(
+        &self,
+        medium: &str,
+        address: &str,
+        client_secret: &str,
+        send_attempt: u64,
+        locale: Option<&str>,
+    ) -> Result<String> {
+        let address = normalize_address(medium, address);
+        let mut sessions = self.sessions.write().unwrap();
+
+        let existing = sessions.iter_mut().find(|(_, session)| {
+            session.medium == medium && session.address == address && session.client_secret == client_secret
+        });
+        if let Some((sid, session)) = existing {
+            if send_attempt > session.send_attempt {
+                self.deliver_token(medium, &address, sid, client_secret, &session.token, locale)?;
+                session.send_attempt = send_attempt;
+            }
+            return Ok(sid.clone());
+        }
+
+        let sid = Uuid::new_v4().simple().to_string();
+        let token: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+        self.deliver_token(medium, &address, &sid, client_secret, &token, locale)?;
+        sessions.insert(
+            sid.clone(),
+            ValidationSession {
+                medium: medium.to_owned(),
+                address,
+                client_secret: client_secret.to_owned(),
+                token,
+                send_attempt,
+                validated_at: None,
+            },
+        );
+        Ok(sid)
+    }
+
+    /// Hand a validation token to the user. Without an email (or any SMS)
+    /// transport the medium cannot be validated locally. Emails also link
+    /// to the confirmation page, which submits the token for the user.
+    fn deliver_token(
+        &self,
+        medium: &str,
+        address: &str,
+        sid: &str,
+        client_secret: &str,
+        token: &str,
+        locale: Option<&str>,
+    ) -> Result<()> {
+        let Some((mailer, server_name)) = self.mailer.as_ref().filter(|_| medium == "email") else {
+            warn!("📧 No {} delivery configured, cannot validate {}", medium, address);
+            return Err(Error::BadRequest(
+                ErrorKind::ThreepidMediumNotSupported,
+                "This server cannot send validation tokens to this medium",
+            ));
+        };
+        let link = format!(
+            "https://{}{}?{}",
+            server_name,
+            CONFIRM_PATH,
+            url::form_urlencoded::Serializer::new(String::new())
+                .append_pair("sid", sid)
+                .append_pair("client_secret", client_secret)
+                .append_pair("token", token)
+                .finish()
+        );
+        mailer
+            .send_in(
+                Purpose::Validation,
+                address,
+                locale,
+                &json!({ "server_name": server_name, "token": token, "sid": sid, "link": link }),
+            )
+            .map_err(|e| match e {
+                EmailError::RateLimited(_) | EmailError::QueueFull => {
+                    Error::BadRequest(ErrorKind::LimitExceeded { retry_after: None }, "Too many validation emails, try again later")
+                }
+                EmailError::InvalidAddress(_) => Error::BadRequest(ErrorKind::InvalidParam, "Invalid email address"),
+                e => Error::BadServerResponse(e.to_string()),
+            })
+    }
+
+    /// Complete a local validation session with the token sent to the user
+    pub fn submit_token(&self, sid: &str, client_secret: &str, token: &str) -> Result<()> {
+        let mut sessions = self.sessions.write().unwrap();
+        let session = sessions
+            .get_mut(sid)
+            .filter(|session| session.client_secret == client_secret)
+            .ok_or(Error::BadRequest(ErrorKind::ThreepidAuthFailed, "Unknown validation session"))?;
+        if session.token != token {
+            return Err(Error::BadRequest(ErrorKind::ThreepidAuthFailed, "Invalid validation token"));
+        }
+        session.validated_at.get_or_insert_with(now_millis);
+        Ok(())
+    }
+
+    /// Proxy a `requestToken` call to the identity server delegated to
+    /// validate 3PIDs of this medium. Returns its response (containing `sid`).
+    pub async fn request_token_via(&self, delegate: &str, medium: &str, body: &Value) -> Result<Value> {
+        let url = format!("{}/_matrix/identity/v2/validate/{}/requestToken", delegate.trim_end_matches('/'), medium);
+        self.client
+            .post(url)
+            .json(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| {
+                warn!("❌ Identity server requestToken failed: {}", e);
+                Error::BadServerResponse("Identity server could not send a validation token".to_owned())
+            })?
+            .json()
+            .await
+            .map_err(|_| Error::BadServerResponse("Invalid identity server response".to_owned()))
+    }
+
+    /// Attach the 3PID validated in session `sid` to an account. Sessions not
+    /// known locally are checked against `delegate`, if any.
+    pub async fn add(&self, user_id: &str, sid: &str, client_secret: &str, delegate: Option<&str>) -> Result<()> {
+        let local = self
+            .sessions
+            .read()
+            .unwrap()
+            .get(sid)
+            .filter(|session| session.client_secret == client_secret)
+            .cloned();
+
+        let (medium, address, validated_at) = match (local, delegate) {
+            (Some(session), _) => {
+                let validated_at = session
+                    .validated_at
+                    .ok_or(Error::BadRequest(ErrorKind::ThreepidAuthFailed, "Third-party identifier not validated"))?;
+                (session.medium, session.address, validated_at)
+            }
+            (None, Some(delegate)) => self.validated_at_delegate(delegate, sid, client_secret).await?,
+            (None, None) => return Err(Error::BadRequest(ErrorKind::ThreepidAuthFailed, "Unknown validation session")),
+        };
+
+        if self.find_user(&medium, &address).is_some_and(|owner| owner != user_id) {
+            return Err(Error::BadRequest(ErrorKind::ThreepidInUse, "Third-party identifier already in use"));
+        }
+
+        let mut threepids = self.threepids.write().unwrap();
+        let user_threepids = threepids.entry(user_id.to_owned()).or_default();
+        user_threepids.retain(|threepid| threepid.medium != medium || threepid.address != address);
+        info!("📧 Adding {} {} to {}", medium, address, user_id);
+        user_threepids.push(ThreePid {
+            medium,
+            address,
+            validated_at,
+            added_at: now_millis(),
+        });
+        self.persist(threepids);
+        self.sessions.write().unwrap().remove(sid);
+        Ok(())
+    }
+
+    /// 3PIDs attached to an account
+    pub fn list(&self, user_id: &str) -> Vec<ThreePid> {
+        self.threepids.read().unwrap().get(user_id).cloned().unwrap_or_default()
+    }
+
+    /// Detach a 3PID from an account. Returns whether it was attached.
+    pub fn delete(&self, user_id: &str, medium: &str, address: &str) -> bool {
+        let address = normalize_address(medium, address);
+        let mut threepids = self.threepids.write().unwrap();
+        let Some(user_threepids) = threepids.get_mut(user_id) else {
+            return false;
+        };
+        let before = user_threepids.len();
+        user_threepids.retain(|threepid| threepid.medium != medium || threepid.address != address);
+        let deleted = before != user_threepids.len();
+        if deleted {
+            self.persist(threepids);
+        }
+        deleted
+    }
+
+    /// Account a 3PID is attached to, used for 3PID login
+    pub fn find_user(&self, medium: &str, address: &str) -> Option<String> {
+        let address = normalize_address(medium, address);
+        self.threepids
+            .read()
+            .unwrap()
+            .iter()
+            .find(|(_, threepids)| threepids.iter().any(|t| t.medium == medium && t.address == address))
+            .map(|(user_id, _)| user_id.clone())
+    }
+
+    /// Bind a validated 3PID to `user_id` on an identity server
+    pub async fn bind(&self, id_server: &str, id_access_token: &str, sid: &str, client_secret: &str, user_id: &str) -> Result<()> {
+        let url = format!("{}/_matrix/identity/v2/3pid/bind", identity_server_base(id_server));
+        self.client
+            .post(url)
+            .bearer_auth(id_access_token)
+            .json(&json!({ "sid": sid, "client_secret": client_secret, "mxid": user_id }))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| {
+                warn!("❌ Binding at {} failed: {}", id_server, e);
+                Error::BadServerResponse("Identity server refused the binding".to_owned())
+            })?;
+        info!("🔗 Bound 3PID of {} at {}", user_id, id_server);
+        Ok(())
+    }
+
+    /// Remove the binding of a 3PID to `user_id` from an identity server.
+    /// Returns `false` if the identity server does not support unbinding.
+    pub async fn unbind(&self, id_server: &str, user_id: &str, medium: &str, address: &str) -> Result<bool> {
+        let url = format!("{}/_matrix/identity/v2/3pid/unbind", identity_server_base(id_server));
+        let response = self
+            .client
+            .post(url)
+            .json(&json!({ "mxid": user_id, "threepid": { "medium": medium, "address": address } }))
+            .send()
+            .await
+            .map_err(|_| Error::BadServerResponse("Identity server unreachable".to_owned()))?;
+        debug!("🔗 Unbind at {} returned {}", id_server, response.status());
+        Ok(response.status().is_success())
+    }
+
+    async fn validated_at_delegate(&self, delegate: &str, sid: &str, client_secret: &str) -> Result<(String, String, u64)> {
+        let url = format!("{}/_matrix/identity/v2/3pid/getValidated3pid", delegate.trim_end_matches('/'));
+        let response: Value = self
+            .client
+            .get(url)
+            .query(&[("sid", sid), ("client_secret", client_secret)])
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|_| Error::BadRequest(ErrorKind::ThreepidAuthFailed, "Third-party identifier not validated"))?
+            .json()
+            .await
+            .map_err(|_| Error::BadServerResponse("Invalid identity server response".to_owned()))?;
+
+        match (response["medium"].as_str(), response["address"].as_str(), response["validated_at"].as_u64()) {
+            (Some(medium), Some(address), Some(validated_at)) => {
+                Ok((medium.to_owned(), normalize_address(medium, address), validated_at))
+            }
+            _ => Err(Error::BadRequest(ErrorKind::ThreepidAuthFailed, "Third-party identifier not validated")),
+        }
+    }
+}
+
+/// Emails are compared case-insensitively
+fn normalize_address(medium: &str, address: &str) -> String {
+    match medium {
+        "email" => address.trim().to_lowercase(),
+        _ => address.trim().to_owned(),
+    }
+}
+
+/// Identity servers are given by host name in client requests
+fn identity_server_base(id_server: &str) -> String {
+    if id_server.starts_with("http://") || id_server.starts_with("https://") {
+        id_server.trim_end_matches('/').to_owned()
+    } else {
+        format!("https://{}", id_server)
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mailer(validations_per_hour: u32) -> Arc<Mailer> {
+        let config: matrixon_email::EmailConfig = serde_json::from_value(json!({
+            "smtp": { "host": "localhost", "from": "noreply@matrixon.local" },
+            "rate_limits": { "validation": validations_per_hour },
+        }))
+        .unwrap();
+        Arc::new(Mailer::new(config).unwrap())
+    }
+
+    fn service() -> Service {
+        Service::new().with_mailer(mailer(100), "matrixon.local")
+    }
+
+    fn validate(service: &Service, address: &str) -> String {
+        let sid = service.request_token("email", address, "secret", 1, None).unwrap();
+        let token = service.sessions.read().unwrap()[&sid].token.clone();
+        service.submit_token(&sid, "secret", &token).unwrap();
+        sid
+    }
+
+    #[tokio::test]
+    async fn test_add_and_find_threepid() {
+        let dir = tempfile::tempdir().unwrap();
+        let service = service().with_state_file(Some(dir.path().join("threepids.json")));
+        let sid = validate(&service, "Alice@Example.org");
+        service.add("@alice:matrixon.local", &sid, "secret", None).await.unwrap();
+
+        assert_eq!(service.find_user("email", "alice@example.org").as_deref(), Some("@alice:matrixon.local"));
+        assert_eq!(service.list("@alice:matrixon.local")[0].address, "alice@example.org");
+
+        let sid = validate(&service, "alice@example.org");
+        assert!(service.add("@mallory:matrixon.local", &sid, "secret", None).await.is_err());
+        let restarted = Service::new().with_state_file(Some(dir.path().join("threepids.json")));
+        assert_eq!(restarted.find_user("email", "alice@example.org").as_deref(), Some("@alice:matrixon.local"));
+
+        assert!(service.delete("@alice:matrixon.local", "email", "ALICE@example.org"));
+        assert!(service.find_user("email", "alice@example.org").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unvalidated_session_is_rejected() {
+        let service = service();
+        let sid = service.request_token("email", "bob@example.org", "secret", 1, None).unwrap();
+        assert!(service.submit_token(&sid, "secret", "wrong").is_err());
+        assert!(service.add("@bob:matrixon.local", &sid, "secret", None).await.is_err());
+        assert_eq!(service.request_token("email", "bob@example.org", "secret", 1, None).unwrap(), sid);
+    }
+
+    #[test]
+    fn test_emailed_tokens_are_rate_limited() {
+        let service = Service::new().with_mailer(mailer(1), "matrixon.local");
+        let sid = service.request_token("email", "bob@example.org", "secret", 1, None).unwrap();
+        assert_eq!(service.request_token("email", "bob@example.org", "secret", 1, None).unwrap(), sid);
+        assert!(matches!(
+            service.request_token("email", "bob@example.org", "secret", 2, None),
+            Err(Error::BadRequest(ErrorKind::LimitExceeded { .. }, _))
+        ));
+        assert!(service.request_token("email", "not an address", "secret", 1, None).is_err());
+        assert!(matches!(
+            Service::new().request_token("email", "bob@example.org", "secret", 1, None),
+            Err(Error::BadRequest(ErrorKind::ThreepidMediumNotSupported, _))
+        ));
+    }
+}
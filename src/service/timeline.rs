@@ -0,0 +1,1257 @@
+// =============================================================================
+// Matrixon Matrix NextServer - Timeline Service
+// =============================================================================
+//
+// Project: Matrixon - Ultra High Performance Matrix NextServer (Synapse Alternative)
+// Author: arkSong (arksong2018@gmail.com) - Founder of Matrixon Innovation Project
+// Date: 2024-12-11
+// Version: 0.11.0-alpha
+// License: Apache 2.0 / MIT
+//
+// Description:
+//   Per-room event timelines. Events are kept in arrival order and addressed
+//   by their position. Every event also gets a server-wide stream count used
+//   by /sync, and an ordinal ordering imported events sharing a count; the
+//   pair is the pagination token for /messages, which stays valid when
+//   events are imported or purged around it. Events
+//   are serialized once when appended; responses copy that JSON as is
+//   instead of serializing the event again. Positions of looked up events
+//   and state events are cached, and checked against the timeline on use.
+//   Locally created events are completed into PDUs: they reference the
+//   latest event and their auth events, carry their content hash and this
+//   server's signature, and from room version 3 on are addressed by their
+//   reference hash, so other servers accept them as they are. Batches of
+//   imported history are placed right after their anchor event and share its
+//   stream count, so /messages orders them but /sync does not replay them.
+//
+// =============================================================================
+
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ruma::{CanonicalJsonValue, RoomVersionId};
+use serde::{Serialize, Serializer};
+use serde_json::{json, value::RawValue, Value};
+use tokio::sync::{futures::Notified, Notify};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::service::{
+    cache::{Cache, CacheStats},
+    event_persistence, federation_membership, inbound_federation, server_keys,
+};
+
+/// Server name in the event ids of room versions 1 and 2 when no signing
+/// key is configured
+const LOCAL_SERVER_NAME: &str = "matrixon.local";
+
+/// Base capacities of the lookup caches, before the capacity modifier
+const PDU_CACHE_CAPACITY: usize = 150_000;
+const STATE_CACHE_CAPACITY: usize = 100_000;
+const STATE_KEY_CACHE_CAPACITY: usize = 100_000;
+
+/// Largest ordinal, so ordinals fit the database's signed 64-bit integers
+const MAX_ORDINAL: u64 = i64::MAX as u64;
+
+/// Direction for timeline pagination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Backward,
+}
+
+/// The stored JSON serialization of an event, serialized as is
+#[derive(Debug, Clone)]
+pub struct SerializedEvent(Arc<RawValue>);
+
+impl SerializedEvent {
+    fn new(event: &Value) -> Self {
+        Self(serde_json::value::to_raw_value(event).expect("JSON values serialize").into())
+    }
+
+    /// The event's JSON
+    pub fn get(&self) -> &str {
+        self.0.get()
+    }
+}
+
+impl Serialize for SerializedEvent {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+/// Pagination token: the place in a room timeline before the entry with
+/// this stream count and ordinal, rendered as `t<count>_<ordinal>`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Token {
+    count: u64,
+    ordinal: u64,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "t{}_{}", self.count, self.ordinal)
+    }
+}
+
+impl FromStr for Token {
+    type Err = ();
+
+    fn from_str(token: &str) -> Result<Self, ()> {
+        let (count, ordinal) = token.strip_prefix('t').and_then(|token| token.split_once('_')).ok_or(())?;
+        Ok(Self { count: count.parse().map_err(|_| ())?, ordinal: ordinal.parse().map_err(|_| ())? })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry {
+    /// Server-wide stream count, increasing with every appended event
+    count: u64,
+    /// Orders imported events sharing the stream count of their anchor;
+    /// 0 for appended events
+    ordinal: u64,
+    event: Value,
+    /// Serialization of `event`
+    json: SerializedEvent,
+}
+
+impl Entry {
+    fn new(count: u64, event: Value) -> Self {
+        let json = SerializedEvent::new(&event);
+        Self { count, ordinal: 0, event, json }
+    }
+
+    fn token(&self) -> Token {
+        Token { count: self.count, ordinal: self.ordinal }
+    }
+
+    /// Whether the entry was imported: an appended event comes first among
+    /// the entries sharing its stream count, with ordinal 0
+    fn is_historical(&self) -> bool {
+        self.ordinal > 0
+    }
+}
+
+/// Lookup caches of a timeline, holding positions in room timelines
+#[derive(Debug)]
+struct Caches {
+    /// (room, event id) -> position
+    pdus: Cache<(String, String), usize>,
+    /// (type, state key) -> short state key, so the state cache does not
+    /// repeat them for every room
+    shortstatekeys: Cache<(String, String), u64>,
+    /// (room, short state key) -> position of the latest such state event
+    state: Cache<(String, u64), Option<usize>>,
+    /// Next short state key to hand out. Keys are never reused, so entries
+    /// cached under a key whose mapping was evicted are only unreachable.
+    next_shortstatekey: AtomicU64,
+}
+
+impl Caches {
+    fn new(capacity_modifier: f64) -> Self {
+        Self {
+            pdus: Cache::new("pdus", PDU_CACHE_CAPACITY, capacity_modifier),
+            shortstatekeys: Cache::new("state_keys", STATE_KEY_CACHE_CAPACITY, capacity_modifier),
+            state: Cache::new("state", STATE_CACHE_CAPACITY, capacity_modifier),
+            next_shortstatekey: AtomicU64::new(0),
+        }
+    }
+
+    /// Key of the latest `event_type` / `state_key` state event of a room
+    /// in the state cache
+    fn state_key(&self, room_id: &str, event_type: &str, state_key: &str) -> (String, u64) {
+        let key = (event_type.to_owned(), state_key.to_owned());
+        let short = self.shortstatekeys.get(&key).unwrap_or_else(|| {
+            let short = self.next_shortstatekey.fetch_add(1, Ordering::Relaxed);
+            self.shortstatekeys.insert(key, short);
+            short
+        });
+        (room_id.to_owned(), short)
+    }
+}
+
+impl Default for Caches {
+    fn default() -> Self {
+        Self::new(1.0)
+    }
+}
+
+/// Room timeline storage service
+#[derive(Debug, Default)]
+pub struct Service {
+    rooms: RwLock<HashMap<String, Vec<Entry>>>,
+    last_count: AtomicU64,
+    /// Notified whenever the stream count advances
+    advanced: Notify,
+    caches: Caches,
+    /// Signs locally created events; without one they are only hashed
+    signer: Option<Arc<server_keys::Service>>,
+    /// Stores appended events in the database, if there is one
+    persistence: Option<Arc<event_persistence::Service>>,
+}
+
+impl Service {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scale the lookup caches by `matrixon_cache_capacity_modifier`
+    pub fn with_cache_capacity_modifier(mut self, modifier: f64) -> Self {
+        self.caches = Caches::new(modifier);
+        self
+    }
+
+    /// Sign locally created events with this server's key
+    pub fn with_signer(mut self, signer: Arc<server_keys::Service>) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Queue appended events for writing to the database
+    pub fn with_persistence(mut self, persistence: Arc<event_persistence::Service>) -> Self {
+        self.persistence = Some(persistence);
+        self
+    }
+
+    /// The lookup caches, for exporting their statistics
+    pub fn caches(&self) -> [&dyn CacheStats; 3] {
+        [&self.caches.pdus, &self.caches.shortstatekeys, &self.caches.state]
+    }
+
+    /// Build and append a locally created event, returning its event id
+    pub fn append_event(
+        &self,
+        room_id: &str,
+        sender: &str,
+        event_type: &str,
+        state_key: Option<&str>,
+        content: Value,
+    ) -> String {
+        let origin_server_ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let mut event = json!({
+            "room_id": room_id,
+            "sender": sender,
+            "type": event_type,
+            "content": content,
+            "origin_server_ts": origin_server_ts,
+        });
+        if let Some(state_key) = state_key {
+            event["state_key"] = json!(state_key);
+        }
+
+        let event_id = self.complete_local_event(room_id, &mut event);
+        self.append_pdu(room_id, event);
+        event_id
+    }
+
+    /// Turn a local event into a PDU: add its prev and auth events and
+    /// depth, hash and sign it, and set its event id. Returns the event id.
+    fn complete_local_event(&self, room_id: &str, event: &mut Value) -> String {
+        let latest = self.rooms.read().unwrap().get(room_id).and_then(|entries| {
+            let entry = entries.last()?;
+            Some((entry.event["event_id"].as_str()?.to_owned(), entry.event["depth"].as_u64(), entries.len() as u64))
+        });
+        let (prev_events, depth) = match latest {
+            Some((event_id, depth, len)) => (vec![event_id], depth.unwrap_or(len) + 1),
+            None => (Vec::new(), 1),
+        };
+        self.complete_event(room_id, event, prev_events, depth)
+    }
+
+    /// Like [`Self::complete_local_event`], with the given prev events and
+    /// depth instead of the latest event of the room
+    fn complete_event(&self, room_id: &str, event: &mut Value, prev_events: Vec<String>, depth: u64) -> String {
+        let room_version = if event["type"] == "m.room.create" {
+            event["content"]["room_version"].as_str().unwrap_or("1").to_owned()
+        } else {
+            federation_membership::room_version(self, room_id)
+        };
+        let room_version_id = RoomVersionId::try_from(room_version.as_str()).unwrap_or(RoomVersionId::V1);
+
+        event["prev_events"] = federation_membership::event_references(&room_version, prev_events);
+        event["auth_events"] = federation_membership::event_references(&room_version, self.auth_event_ids(room_id, event));
+        event["depth"] = json!(depth);
+
+        let server_name = self.signer.as_ref().map_or(LOCAL_SERVER_NAME, |signer| signer.server_name());
+        let random_event_id = format!("${}:{}", Uuid::new_v4().simple(), server_name);
+        if matches!(room_version_id, RoomVersionId::V1 | RoomVersionId::V2) {
+            event["event_id"] = json!(random_event_id);
+        }
+        let completed = match &self.signer {
+            Some(signer) => signer.sign_pdu(&room_version, event).map_err(|e| e.to_string()),
+            None => add_content_hash(event),
+        };
+        let event_id = completed.and_then(|pdu| {
+            let event_id = inbound_federation::reference_event_id(&pdu, &room_version_id)?;
+            *event = pdu;
+            Ok(event_id)
+        });
+        let event_id = event_id.unwrap_or_else(|e| {
+            warn!("⚠️ Could not hash local {} event in {}: {}", event["type"], room_id, e);
+            random_event_id
+        });
+        event["event_id"] = json!(event_id);
+        event_id
+    }
+
+    /// Ids of the current state events a new event is authorized by: the
+    /// create event, power levels and membership of the sender, and for
+    /// membership events the join rules and the target's membership
+    fn auth_event_ids(&self, room_id: &str, event: &Value) -> Vec<String> {
+        if event["type"] == "m.room.create" {
+            return Vec::new();
+        }
+        let sender = event["sender"].as_str().unwrap_or_default();
+        let mut keys = vec![("m.room.create", ""), ("m.room.power_levels", ""), ("m.room.member", sender)];
+        if event["type"] == "m.room.member" {
+            let membership = event["content"]["membership"].as_str().unwrap_or_default();
+            if matches!(membership, "join" | "invite" | "knock") {
+                keys.push(("m.room.join_rules", ""));
+            }
+            if let Some(target) = event["state_key"].as_str().filter(|target| *target != sender) {
+                keys.push(("m.room.member", target));
+            }
+        }
+        keys.into_iter()
+            .filter_map(|(event_type, state_key)| self.state_event(room_id, event_type, state_key))
+            .filter_map(|event| event["event_id"].as_str().map(str::to_owned))
+            .collect()
+    }
+
+    /// Put back events loaded from the database with their stream count
+    /// and ordinal, in that order, without storing them again
+    pub fn restore(&self, events: impl IntoIterator<Item = (String, u64, u64, Value)>) {
+        let mut rooms = self.rooms.write().unwrap();
+        for (room_id, count, ordinal, event) in events {
+            if let (Some(event_type), Some(state_key)) = (event["type"].as_str(), event["state_key"].as_str()) {
+                self.caches.state.remove(&self.caches.state_key(&room_id, event_type, state_key));
+            }
+            self.last_count.fetch_max(count, Ordering::SeqCst);
+            let mut entry = Entry::new(count, event);
+            entry.ordinal = ordinal;
+            rooms.entry(room_id).or_default().push(entry);
+        }
+        self.caches.pdus.clear();
+        self.advanced.notify_waiters();
+    }
+
+    /// Queue a rewritten event to replace its stored copy
+    fn persist_rewrite(&self, room_id: &str, entry: &Entry) {
+        if let Some(persistence) = &self.persistence {
+            persistence.enqueue(room_id, entry.count, entry.ordinal, &entry.event);
+        }
+    }
+
+    /// Append an already formed event, e.g. one received over federation
+    pub fn append_pdu(&self, room_id: &str, event: Value) {
+        debug!("📝 Appending {} to {}", event["event_id"], room_id);
+        let mut rooms = self.rooms.write().unwrap();
+        let count = self.last_count.fetch_add(1, Ordering::SeqCst) + 1;
+        if let (Some(event_type), Some(state_key)) = (event["type"].as_str(), event["state_key"].as_str()) {
+            self.caches.state.remove(&self.caches.state_key(room_id, event_type, state_key));
+        }
+        if let Some(persistence) = &self.persistence {
+            persistence.enqueue(room_id, count, 0, &event);
+        }
+        rooms.entry(room_id.to_owned()).or_default().push(Entry::new(count, event));
+        self.advanced.notify_waiters();
+    }
+
+    /// Import historical events into a room right after `prev_event_id`,
+    /// in the given order and ahead of any batch imported there before, so
+    /// batches are imported going back in time. The events must not be
+    /// state events. They are marked with `m.historical` and share the
+    /// stream count of the anchor event, so they show up in /messages but
+    /// not in incremental syncs or the event stream; ordinals between the
+    /// anchor's and that of the event after it order them. Returns the
+    /// imported events, or `None` if the anchor is not in the room.
+    pub fn insert_historical(&self, room_id: &str, prev_event_id: &str, events: Vec<Value>) -> Option<Vec<Value>> {
+        let (count, depth) = {
+            let rooms = self.rooms.read().unwrap();
+            let entries = rooms.get(room_id)?;
+            let anchor = &entries[self.event_position(room_id, entries, prev_event_id)?];
+            (anchor.count, anchor.event["depth"].as_u64().unwrap_or_default())
+        };
+
+        let anchor_id = prev_event_id;
+        let mut prev_event_id = prev_event_id.to_owned();
+        let mut completed = Vec::with_capacity(events.len());
+        for mut event in events {
+            event["room_id"] = json!(room_id);
+            event["content"]["m.historical"] = json!(true);
+            prev_event_id = self.complete_event(room_id, &mut event, vec![prev_event_id], depth);
+            completed.push(event);
+        }
+
+        let mut rooms = self.rooms.write().unwrap();
+        let entries = rooms.get_mut(room_id)?;
+        let anchor = entries.iter().position(|entry| entry.event["event_id"] == anchor_id)?;
+        let (first_ordinal, spread_out) = allocate_ordinals(entries, anchor, completed.len() as u64);
+        let imported = anchor + 1..anchor + 1 + completed.len();
+        for (offset, event) in completed.iter().enumerate() {
+            debug!("📜 Importing historical {} into {}", event["event_id"], room_id);
+            let mut entry = Entry::new(count, event.clone());
+            entry.ordinal = first_ordinal + offset as u64;
+            entries.insert(anchor + 1 + offset, entry);
+        }
+        // Entries given new ordinals to make room are stored again as well
+        if let Some(persistence) = &self.persistence {
+            for (position, entry) in entries.iter().enumerate() {
+                if imported.contains(&position) || (spread_out && entry.count == count) {
+                    persistence.enqueue(room_id, count, entry.ordinal, &entry.event);
+                }
+            }
+        }
+        self.caches.pdus.invalidate(room_id);
+        self.caches.state.invalidate(room_id);
+        Some(completed)
+    }
+
+    /// Advance the stream count for an update kept outside room timelines,
+    /// e.g. an invite to a remote room, so incremental syncs pick it up
+    pub fn next_count(&self) -> u64 {
+        let count = self.last_count.fetch_add(1, Ordering::SeqCst) + 1;
+        self.advanced.notify_waiters();
+        count
+    }
+
+    /// Resolves when the stream count next advances. Create it before
+    /// reading the count so no advance is missed in between.
+    pub fn advanced(&self) -> Notified<'_> {
+        self.advanced.notified()
+    }
+
+    /// Stream count of the latest event on this server
+    pub fn current_count(&self) -> u64 {
+        self.last_count.load(Ordering::SeqCst)
+    }
+
+    /// The latest `limit` events of a room appended after stream count
+    /// `since`, oldest first. Also returns whether older events after
+    /// `since` were left out, and the position of the first returned event
+    /// for paginating backwards from it.
+    pub fn events_since(&self, room_id: &str, since: u64, limit: usize) -> (Vec<Value>, bool, usize) {
+        let (events, limited, start, _) = self.entries_between(room_id, since, u64::MAX, limit, |entry| entry.event.clone());
+        (events, limited, start)
+    }
+
+    /// Like [`Service::events_since`], returning the stored serializations
+    /// of events up to stream count `until` only, and also the token to
+    /// paginate backwards from the first returned event
+    pub fn serialized_events_between(
+        &self,
+        room_id: &str,
+        since: u64,
+        until: u64,
+        limit: usize,
+    ) -> (Vec<SerializedEvent>, bool, usize, Token) {
+        self.entries_between(room_id, since, until, limit, |entry| entry.json.clone())
+    }
+
+    fn entries_between<T>(
+        &self,
+        room_id: &str,
+        since: u64,
+        until: u64,
+        limit: usize,
+        map: impl Fn(&Entry) -> T,
+    ) -> (Vec<T>, bool, usize, Token) {
+        let rooms = self.rooms.read().unwrap();
+        let Some(entries) = rooms.get(room_id) else {
+            return (Vec::new(), false, 0, Token::default());
+        };
+        let first_new = entries.partition_point(|entry| entry.count <= since);
+        let end = entries.partition_point(|entry| entry.count <= until).max(first_new);
+        let start = first_new.max(end.saturating_sub(limit));
+        let events = entries[start..end].iter().map(map).collect();
+        (events, start > first_new, start, token_before(entries, start))
+    }
+
+    /// Look up a single event by id
+    pub fn get_event(&self, room_id: &str, event_id: &str) -> Option<Value> {
+        let rooms = self.rooms.read().unwrap();
+        let entries = rooms.get(room_id)?;
+        let position = self.event_position(room_id, entries, event_id)?;
+        Some(entries[position].event.clone())
+    }
+
+    /// Position of an event among a room's entries, through the PDU cache.
+    /// Callers hold the rooms lock.
+    fn event_position(&self, room_id: &str, entries: &[Entry], event_id: &str) -> Option<usize> {
+        let key = (room_id.to_owned(), event_id.to_owned());
+        if let Some(position) = self.caches.pdus.get(&key) {
+            if entries.get(position).is_some_and(|entry| entry.event["event_id"] == event_id) {
+                return Some(position);
+            }
+        }
+        let position = entries.iter().position(|entry| entry.event["event_id"] == event_id)?;
+        self.caches.pdus.insert(key, position);
+        Some(position)
+    }
+
+    /// Replace a stored event, keeping its place in the timeline. Returns
+    /// the event replaced.
+    pub fn replace_event(&self, room_id: &str, event_id: &str, event: Value) -> Option<Value> {
+        let mut rooms = self.rooms.write().unwrap();
+        let entry = rooms.get_mut(room_id)?.iter_mut().find(|entry| entry.event["event_id"] == event_id)?;
+        entry.json = SerializedEvent::new(&event);
+        let replaced = std::mem::replace(&mut entry.event, event);
+        self.persist_rewrite(room_id, entry);
+        Some(replaced)
+    }
+
+    /// The most recent event of any room matching `predicate`
+    pub fn find_event(&self, predicate: impl Fn(&Value) -> bool) -> Option<Value> {
+        let rooms = self.rooms.read().unwrap();
+        rooms
+            .values()
+            .flatten()
+            .filter(|entry| predicate(&entry.event))
+            .max_by_key(|entry| entry.count)
+            .map(|entry| entry.event.clone())
+    }
+
+    /// Call `f` with every event of every room
+    pub fn for_each_event(&self, mut f: impl FnMut(&Value)) {
+        let rooms = self.rooms.read().unwrap();
+        rooms.values().flatten().for_each(|entry| f(&entry.event));
+    }
+
+    /// Call `f` with every event of a room, holding the lock for that room
+    /// only, so scans going room by room do not hold up appends for long
+    pub fn for_each_event_in(&self, room_id: &str, mut f: impl FnMut(&Value)) {
+        let rooms = self.rooms.read().unwrap();
+        rooms.get(room_id).into_iter().flatten().for_each(|entry| f(&entry.event));
+    }
+
+    /// Call `f` with the events of all rooms appended after stream count
+    /// `since`, holding the timeline lock so that nothing is appended until
+    /// `f` returns
+    pub fn with_events_since<R>(&self, since: u64, f: impl FnOnce(&mut dyn Iterator<Item = &Value>) -> R) -> R {
+        let rooms = self.rooms.read().unwrap();
+        let mut events = rooms.values().flat_map(|entries| {
+            let start = entries.partition_point(|entry| entry.count <= since);
+            entries[start..].iter().map(|entry| &entry.event)
+        });
+        f(&mut events)
+    }
+
+    /// State events of `event_type` appended to a room after stream count
+    /// `since`, oldest first, with the stream count of the last event in the room
+    pub fn state_events_since(&self, room_id: &str, event_type: &str, since: u64) -> (Vec<Value>, u64) {
+        self.state_events_between(room_id, event_type, since, u64::MAX)
+    }
+
+    /// Like [`Service::state_events_since`], up to stream count `until` only
+    pub fn state_events_between(&self, room_id: &str, event_type: &str, since: u64, until: u64) -> (Vec<Value>, u64) {
+        let rooms = self.rooms.read().unwrap();
+        let Some(entries) = rooms.get(room_id) else {
+            return (Vec::new(), since);
+        };
+        let start = entries.partition_point(|entry| entry.count <= since);
+        let end = entries.partition_point(|entry| entry.count <= until).max(start);
+        let events = entries[start..end]
+            .iter()
+            .filter(|entry| entry.event["type"] == event_type && entry.event.get("state_key").is_some())
+            .map(|entry| entry.event.clone())
+            .collect();
+        (events, entries.last().map_or(since, |entry| entry.count))
+    }
+
+    /// Up to `limit` events of all rooms appended after stream count `since`,
+    /// in stream order, with their stream counts. Imported events are left
+    /// out, even those imported after an event appended after `since`.
+    pub fn stream_since(&self, since: u64, limit: usize) -> Vec<(u64, Value)> {
+        let rooms = self.rooms.read().unwrap();
+        let mut events: Vec<(u64, Value)> = rooms
+            .values()
+            .flat_map(|entries| {
+                let start = entries.partition_point(|entry| entry.count <= since);
+                entries[start..]
+                    .iter()
+                    .filter(|entry| !entry.is_historical())
+                    .take(limit)
+                    .map(|entry| (entry.count, entry.event.clone()))
+            })
+            .collect();
+        events.sort_unstable_by_key(|(count, _)| *count);
+        events.truncate(limit);
+        events
+    }
+
+    /// Ids of all rooms with a timeline
+    pub fn room_ids(&self) -> Vec<String> {
+        self.rooms.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Ids of the `limit` rooms with the most recent events, latest first
+    pub fn recent_room_ids(&self, limit: usize) -> Vec<String> {
+        let rooms = self.rooms.read().unwrap();
+        let mut recent: Vec<(u64, &String)> =
+            rooms.iter().filter_map(|(room_id, entries)| Some((entries.last()?.count, room_id))).collect();
+        recent.sort_unstable_by(|a, b| b.cmp(a));
+        recent.into_iter().take(limit).map(|(_, room_id)| room_id.clone()).collect()
+    }
+
+    pub fn room_exists(&self, room_id: &str) -> bool {
+        self.rooms.read().unwrap().contains_key(room_id)
+    }
+
+    /// Latest state event of `event_type` / `state_key` in a room
+    pub fn state_event(&self, room_id: &str, event_type: &str, state_key: &str) -> Option<Value> {
+        let rooms = self.rooms.read().unwrap();
+        let entries = rooms.get(room_id)?;
+        let is_match = |entry: &Entry| entry.event["type"] == event_type && entry.event["state_key"] == state_key;
+
+        // Appending a state event evicts its key under the write lock, so a
+        // cached position is the latest one while the read lock is held
+        let key = self.caches.state_key(room_id, event_type, state_key);
+        let position = match self.caches.state.get(&key) {
+            Some(position) if position.is_none_or(|position| entries.get(position).is_some_and(is_match)) => position,
+            _ => {
+                let position = entries.iter().rposition(is_match);
+                self.caches.state.insert(key, position);
+                position
+            }
+        };
+        position.map(|position| entries[position].event.clone())
+    }
+
+    /// Current room state: the latest event for every `(type, state_key)`
+    /// pair, in the order those events were appended
+    pub fn current_state(&self, room_id: &str) -> Vec<Value> {
+        let rooms = self.rooms.read().unwrap();
+        state_of(rooms.get(room_id).map_or(&[][..], Vec::as_slice))
+    }
+
+    /// Room state in effect before the event at `position`
+    pub fn state_before(&self, room_id: &str, position: usize) -> Vec<Value> {
+        let rooms = self.rooms.read().unwrap();
+        let entries = rooms.get(room_id).map_or(&[][..], Vec::as_slice);
+        state_of(&entries[..position.min(entries.len())])
+    }
+
+    /// State event of `event_type` / `state_key` in effect before the event
+    /// at `position`
+    pub fn state_event_before(&self, room_id: &str, event_type: &str, state_key: &str, position: usize) -> Option<Value> {
+        let rooms = self.rooms.read().unwrap();
+        let entries = rooms.get(room_id)?;
+        entries[..position.min(entries.len())]
+            .iter()
+            .rev()
+            .find(|entry| entry.event["type"] == event_type && entry.event["state_key"] == state_key)
+            .map(|entry| entry.event.clone())
+    }
+
+    /// State changes a client synced up to stream count `since` missed
+    /// when its timeline starts at `position`: the latest event for every
+    /// `(type, state_key)` appended in between
+    pub fn state_between(&self, room_id: &str, since: u64, position: usize) -> Vec<Value> {
+        let rooms = self.rooms.read().unwrap();
+        let entries = rooms.get(room_id).map_or(&[][..], Vec::as_slice);
+        let end = position.min(entries.len());
+        let start = entries.partition_point(|entry| entry.count <= since).min(end);
+        state_of(&entries[start..end])
+    }
+
+    /// Position of an event in its room timeline
+    pub fn position(&self, room_id: &str, event_id: &str) -> Option<usize> {
+        let rooms = self.rooms.read().unwrap();
+        self.event_position(room_id, rooms.get(room_id)?, event_id)
+    }
+
+    /// Event at `position` in a room timeline
+    pub fn event_at(&self, room_id: &str, position: usize) -> Option<Value> {
+        let rooms = self.rooms.read().unwrap();
+        rooms.get(room_id)?.get(position).map(|entry| entry.event.clone())
+    }
+
+    /// Paginate a room timeline.
+    ///
+    /// `from` is a position; `None` starts at the beginning (forward) or the
+    /// end (backward). Returns the events and the position to continue
+    /// from. Positions move when events are imported or purged, so clients
+    /// get tokens from [`Service::paginate_serialized`] instead.
+    pub fn paginate(&self, room_id: &str, from: Option<usize>, dir: Direction, limit: usize) -> (Vec<Value>, usize) {
+        let rooms = self.rooms.read().unwrap();
+        let entries = rooms.get(room_id).map_or(&[][..], Vec::as_slice);
+        let from = from.map_or(start_of(entries, dir), |from| from.min(entries.len()));
+        page(entries, from, dir, limit, |entry| entry.event.clone())
+    }
+
+    /// Paginate a room timeline for /messages, returning the stored
+    /// serializations.
+    ///
+    /// `from` is a pagination token; `None` starts at the beginning
+    /// (forward) or the end (backward). Returns the token started from, the
+    /// events and the token to continue from. Tokens keep their place when
+    /// events are imported or purged in between.
+    pub fn paginate_serialized(
+        &self,
+        room_id: &str,
+        from: Option<Token>,
+        dir: Direction,
+        limit: usize,
+    ) -> (Token, Vec<SerializedEvent>, Token) {
+        let rooms = self.rooms.read().unwrap();
+        let entries = rooms.get(room_id).map_or(&[][..], Vec::as_slice);
+        let from = from.map_or(start_of(entries, dir), |token| entries.partition_point(|entry| entry.token() < token));
+        let (chunk, next) = page(entries, from, dir, limit, |entry| entry.json.clone());
+        (token_before(entries, from), chunk, token_before(entries, next))
+    }
+
+    /// Current position at the end of a room timeline
+    pub fn end_position(&self, room_id: &str) -> usize {
+        self.rooms.read().unwrap().get(room_id).map_or(0, Vec::len)
+    }
+
+    /// Redact, in place, every event sent by `sender` in the rooms `in_room`
+    /// accepts. Returns how many events were redacted.
+    pub fn redact_events_from(&self, sender: &str, in_room: impl Fn(&str) -> bool) -> usize {
+        let mut rooms = self.rooms.write().unwrap();
+        let mut redacted = 0;
+        for (room_id, entries) in rooms.iter_mut().filter(|(room_id, _)| in_room(room_id)) {
+            for entry in entries.iter_mut().filter(|entry| entry.event["sender"] == sender) {
+                redact_event(&mut entry.event);
+                entry.json = SerializedEvent::new(&entry.event);
+                self.persist_rewrite(room_id, entry);
+                redacted += 1;
+            }
+        }
+        redacted
+    }
+
+    /// Delete a room's timeline, returning its events
+    pub fn delete_room(&self, room_id: &str) -> Vec<Value> {
+        let entries = self.rooms.write().unwrap().remove(room_id).unwrap_or_default();
+        self.caches.pdus.invalidate(room_id);
+        self.caches.state.invalidate(room_id);
+        entries.into_iter().map(|entry| entry.event).collect()
+    }
+
+    /// Delete the events of a room sent before `before_ts`, except the
+    /// state in effect after them, the latest event of the room and the
+    /// events `keep` accepts. State events superseded before `before_ts`
+    /// are deleted with the messages. Returns the ids of the deleted
+    /// events and of the events sent before `before_ts` that are kept.
+    pub fn purge_before(&self, room_id: &str, before_ts: u64, keep: impl Fn(&Value) -> bool) -> (Vec<String>, Vec<String>) {
+        let mut rooms = self.rooms.write().unwrap();
+        let Some(entries) = rooms.get_mut(room_id) else {
+            return (Vec::new(), Vec::new());
+        };
+        let is_old = |entry: &Entry| entry.event["origin_server_ts"].as_u64().is_some_and(|ts| ts < before_ts);
+        let event_id = |entry: &Entry| entry.event["event_id"].as_str().unwrap_or_default().to_owned();
+        let Some(end) = entries[..entries.len().saturating_sub(1)].iter().rposition(is_old).map(|last| last + 1) else {
+            return (Vec::new(), entries.iter().filter(|entry| is_old(entry)).map(event_id).collect());
+        };
+
+        let state: Vec<Token> = {
+            let mut state: HashMap<(&str, &str), Token> = HashMap::new();
+            for entry in &entries[..end] {
+                if let (Some(event_type), Some(state_key)) = (entry.event["type"].as_str(), entry.event["state_key"].as_str()) {
+                    state.insert((event_type, state_key), entry.token());
+                }
+            }
+            state.into_values().collect()
+        };
+        let mut purged = Vec::new();
+        let mut position = 0;
+        entries.retain(|entry| {
+            position += 1;
+            if position > end || !is_old(entry) || state.contains(&entry.token()) || keep(&entry.event) {
+                return true;
+            }
+            purged.push(event_id(entry));
+            false
+        });
+
+        if !purged.is_empty() {
+            self.caches.pdus.invalidate(room_id);
+            self.caches.state.invalidate(room_id);
+        }
+        (purged, entries.iter().filter(|entry| is_old(entry)).map(event_id).collect())
+    }
+
+    /// Strip the content of events in a room that were redacted by
+    /// redactions sent from `from_ts` up to `before_ts`, except events
+    /// `keep` accepts. Returns the stripped events.
+    pub fn censor_redacted(&self, room_id: &str, from_ts: u64, before_ts: u64, keep: impl Fn(&Value) -> bool) -> Vec<Value> {
+        let mut rooms = self.rooms.write().unwrap();
+        let Some(entries) = rooms.get_mut(room_id) else {
+            return Vec::new();
+        };
+        let redacted: Vec<String> = entries
+            .iter()
+            .filter(|entry| entry.event["type"] == "m.room.redaction")
+            .filter(|entry| entry.event["origin_server_ts"].as_u64().is_some_and(|ts| (from_ts..before_ts).contains(&ts)))
+            .filter_map(|entry| {
+                let event = &entry.event;
+                event["content"]["redacts"].as_str().or(event["redacts"].as_str()).map(str::to_owned)
+            })
+            .collect();
+
+        let mut censored = Vec::new();
+        for entry in entries.iter_mut() {
+            let event = &mut entry.event;
+            if event["event_id"].as_str().is_some_and(|event_id| redacted.iter().any(|id| id == event_id)) && !keep(event) {
+                redact_event(event);
+                entry.json = SerializedEvent::new(event);
+                censored.push(entry.event.clone());
+                self.persist_rewrite(room_id, entry);
+            }
+        }
+        censored
+    }
+}
+
+/// Where paginating in `dir` starts without a token
+fn start_of(entries: &[Entry], dir: Direction) -> usize {
+    match dir {
+        Direction::Forward => 0,
+        Direction::Backward => entries.len(),
+    }
+}
+
+/// Up to `limit` entries from position `from` on in `dir`, with the
+/// position to continue from
+fn page<T>(entries: &[Entry], from: usize, dir: Direction, limit: usize, map: impl Fn(&Entry) -> T) -> (Vec<T>, usize) {
+    match dir {
+        Direction::Forward => {
+            let end = (from + limit).min(entries.len());
+            (entries[from..end].iter().map(map).collect(), end)
+        }
+        Direction::Backward => {
+            let start = from.saturating_sub(limit);
+            (entries[start..from].iter().rev().map(map).collect(), start)
+        }
+    }
+}
+
+/// Token of the place before the entry at `index`, or after the last entry
+fn token_before(entries: &[Entry], index: usize) -> Token {
+    match (entries.get(index), entries.last()) {
+        (Some(entry), _) => entry.token(),
+        (None, Some(last)) => Token { count: last.count, ordinal: last.ordinal + 1 },
+        (None, None) => Token::default(),
+    }
+}
+
+/// Make room for `n` entries right after the one at `anchor`, returning the
+/// ordinal of the first and whether other entries got new ordinals.
+/// Ordinals are taken from the top of the gap to the next entry of the same
+/// stream count, so batches imported ahead of earlier ones at the same
+/// anchor keep fitting; when a gap runs out, the entries of that stream
+/// count are spread out again.
+fn allocate_ordinals(entries: &mut [Entry], anchor: usize, n: u64) -> (u64, bool) {
+    let count = entries[anchor].count;
+    let upper = |entries: &[Entry]| entries.get(anchor + 1).filter(|next| next.count == count).map_or(MAX_ORDINAL, |next| next.ordinal);
+    let spread_out = upper(entries) - entries[anchor].ordinal <= n;
+    if spread_out {
+        let start = entries.partition_point(|entry| entry.count < count);
+        let end = entries.partition_point(|entry| entry.count <= count);
+        let stride = MAX_ORDINAL / ((end - start) as u64 + n + 1);
+        for (index, entry) in entries[start..end].iter_mut().enumerate() {
+            let slot = index as u64 + if start + index > anchor { n } else { 0 };
+            entry.ordinal = slot * stride;
+        }
+    }
+    (upper(entries) - n, spread_out)
+}
+
+/// The latest event for every `(type, state_key)` pair among `entries`
+fn state_of(entries: &[Entry]) -> Vec<Value> {
+    let mut state: HashMap<(&str, &str), &Entry> = HashMap::new();
+    for entry in entries {
+        if let (Some(event_type), Some(state_key)) = (entry.event["type"].as_str(), entry.event["state_key"].as_str()) {
+            state.insert((event_type, state_key), entry);
+        }
+    }
+    let mut state: Vec<&Entry> = state.into_values().collect();
+    state.sort_by_key(|entry| entry.count);
+    state.into_iter().map(|entry| entry.event.clone()).collect()
+}
+
+/// The event with its content hash added, for when there is no key to
+/// sign it with
+fn add_content_hash(event: &Value) -> Result<Value, String> {
+    let Ok(CanonicalJsonValue::Object(object)) = CanonicalJsonValue::try_from(event.clone()) else {
+        return Err("Event is not canonical JSON".to_owned());
+    };
+    let hash = ruma::signatures::content_hash(&object).map_err(|e| e.to_string())?;
+    let mut event = event.clone();
+    event["hashes"] = json!({ "sha256": hash.encode() });
+    Ok(event)
+}
+
+/// Strip an event down to the keys preserved by the Matrix redaction algorithm
+pub fn redact_event(event: &mut Value) {
+    const KEPT_KEYS: &[&str] = &[
+        "event_id",
+        "type",
+        "room_id",
+        "sender",
+        "state_key",
+        "content",
+        "hashes",
+        "signatures",
+        "depth",
+        "prev_events",
+        "auth_events",
+        "origin_server_ts",
+    ];
+
+    let Some(object) = event.as_object_mut() else {
+        return;
+    };
+    object.retain(|key, _| KEPT_KEYS.contains(&key.as_str()));
+
+    let kept_content_keys: &[&str] = match object.get("type").and_then(Value::as_str) {
+        Some("m.room.member") => &["membership", "join_authorised_via_users_server"],
+        Some("m.room.create") => &["creator", "room_version"],
+        Some("m.room.join_rules") => &["join_rule", "allow"],
+        Some("m.room.power_levels") => &[
+            "ban",
+            "events",
+            "events_default",
+            "invite",
+            "kick",
+            "redact",
+            "state_default",
+            "users",
+            "users_default",
+        ],
+        Some("m.room.history_visibility") => &["history_visibility"],
+        _ => &[],
+    };
+    if let Some(content) = object.get_mut("content").and_then(Value::as_object_mut) {
+        content.retain(|key, _| kept_content_keys.contains(&key.as_str()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paginate_both_directions() {
+        let service = Service::new();
+        for body in ["one", "two", "three"] {
+            service.append_event("!room:matrixon.local", "@a:matrixon.local", "m.room.message", None, json!({ "body": body }));
+        }
+
+        let (chunk, next) = service.paginate("!room:matrixon.local", None, Direction::Backward, 2);
+        assert_eq!(chunk[0]["content"]["body"], "three");
+        assert_eq!(chunk.len(), 2);
+        assert_eq!(next, 1);
+
+        let (chunk, next) = service.paginate("!room:matrixon.local", Some(1), Direction::Forward, 10);
+        assert_eq!(chunk.len(), 2);
+        assert_eq!(next, 3);
+    }
+
+    #[test]
+    fn test_events_since_reports_gaps() {
+        let service = Service::new();
+        for body in ["one", "two", "three"] {
+            service.append_event("!room:matrixon.local", "@a:matrixon.local", "m.room.message", None, json!({ "body": body }));
+        }
+
+        let (events, limited, prev) = service.events_since("!room:matrixon.local", 0, 2);
+        assert_eq!(events[0]["content"]["body"], "two");
+        assert!(limited);
+        assert_eq!(prev, 1);
+
+        let (events, limited, _) = service.events_since("!room:matrixon.local", 2, 2);
+        assert_eq!(events.len(), 1);
+        assert!(!limited);
+        // Events appended after a sync took its batch token wait for the next sync
+        let (events, limited, prev, token) = service.serialized_events_between("!room:matrixon.local", 0, 2, 1);
+        assert_eq!(events.len(), 1);
+        assert!(events[0].get().contains("\"two\""));
+        assert!(limited);
+        assert_eq!(prev, 1);
+        assert_eq!(token.to_string(), "t2_0");
+        assert_eq!(service.state_events_between("!room:matrixon.local", "m.room.message", 0, 2).0.len(), 0);
+    }
+
+    fn ids(events: Vec<Value>) -> Vec<String> {
+        events.into_iter().map(|event| event["event_id"].as_str().unwrap().to_owned()).collect()
+    }
+
+    #[test]
+    fn test_historical_batches_are_ordered_after_their_anchor() {
+        let service = Service::new();
+        let room = "!room:matrixon.local";
+        service.append_event(room, "@a:matrixon.local", "m.room.create", Some(""), json!({ "room_version": "10" }));
+        let anchor = service.append_event(room, "@a:matrixon.local", "m.room.message", None, json!({ "body": "anchor" }));
+        service.append_event(room, "@a:matrixon.local", "m.room.message", None, json!({ "body": "live" }));
+        let since = service.current_count();
+        let message = |body: &str| json!({ "type": "m.room.message", "sender": "@b:matrixon.local", "content": { "body": body } });
+
+        // The later batch is imported first, as when backfilling a bridge
+        let later = ids(service.insert_historical(room, &anchor, vec![message("3"), message("4")]).unwrap());
+        let earlier = ids(service.insert_historical(room, &anchor, vec![message("1"), message("2")]).unwrap());
+        assert!(service.insert_historical(room, "$missing", vec![message("5")]).is_none());
+
+        let (chunk, _) = service.paginate(room, Some(1), Direction::Forward, 10);
+        let bodies: Vec<_> = chunk.iter().map(|event| event["content"]["body"].as_str().unwrap()).collect();
+        assert_eq!(bodies, ["anchor", "1", "2", "3", "4", "live"]);
+        assert_eq!(chunk[1]["content"]["m.historical"], true);
+        assert_eq!(chunk[1]["prev_events"][0], anchor.as_str());
+        assert_eq!(chunk[3]["prev_events"][0], anchor.as_str());
+        assert_eq!(chunk[4]["event_id"], later[1].as_str());
+        assert_eq!(service.position(room, &earlier[0]), Some(2));
+
+        // Imports neither advance the stream nor show up in syncs
+        assert_eq!(service.current_count(), since);
+        assert!(service.events_since(room, since, 10).0.is_empty());
+        assert!(service.stream_since(since, 10).is_empty());
+
+        // Also when imported after an event the stream has not passed yet
+        let newest = service.append_event(room, "@a:matrixon.local", "m.room.message", None, json!({ "body": "newest" }));
+        service.insert_historical(room, &newest, vec![message("6")]).unwrap();
+        assert_eq!(service.stream_since(since, 10).len(), 1);
+    }
+
+    #[test]
+    fn test_tokens_keep_their_place_around_imports_and_purges() {
+        let service = Service::new();
+        let room = "!room:matrixon.local";
+        let message = |body: &str, ts: u64| json!({ "type": "m.room.message", "sender": "@a:matrixon.local", "origin_server_ts": ts, "content": { "body": body } });
+        for (id, ts) in [("$old", 1), ("$anchor", 2), ("$new", 10), ("$latest", 11)] {
+            let mut event = message(id, ts);
+            event["event_id"] = json!(id);
+            service.append_pdu(room, event);
+        }
+        let bodies = |from: Option<Token>, dir: Direction| {
+            let (start, chunk, next) = service.paginate_serialized(room, from, dir, 2);
+            let chunk: Vec<String> =
+                chunk.iter().map(|event| serde_json::from_str::<Value>(event.get()).unwrap()["content"]["body"].as_str().unwrap().to_owned()).collect();
+            (start, chunk, next)
+        };
+
+        let (start, chunk, next) = bodies(None, Direction::Backward);
+        assert_eq!(chunk, ["$latest", "$new"]);
+        assert_eq!(start.to_string().parse::<Token>(), Ok(start));
+        assert!("t1".parse::<Token>().is_err() && "x1_0".parse::<Token>().is_err());
+
+        // Neither imports nor purges before the token make it skip or repeat events
+        service.insert_historical(room, "$anchor", vec![message("1", 2), message("2", 2)]).unwrap();
+        service.insert_historical(room, "$anchor", vec![message("0", 2)]).unwrap();
+        assert_eq!(service.purge_before(room, 2, |_| false), (vec!["$old".to_owned()], Vec::new()));
+        let (_, chunk, next) = bodies(Some(next), Direction::Backward);
+        assert_eq!(chunk, ["2", "1"]);
+        let (_, chunk, _) = bodies(Some(next), Direction::Backward);
+        assert_eq!(chunk, ["0", "$anchor"]);
+        let (_, chunk, end) = bodies(Some(next), Direction::Forward);
+        assert_eq!(chunk, ["1", "2"]);
+        assert_eq!(bodies(Some(end), Direction::Forward).1, ["$new", "$latest"]);
+        assert_eq!(bodies(Some(start), Direction::Forward).1, Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_ordinals_are_spread_out_when_a_gap_runs_out() {
+        let service = Service::new();
+        let room = "!room:matrixon.local";
+        let anchor = service.append_event(room, "@a:matrixon.local", "m.room.message", None, json!({ "body": "anchor" }));
+        let message = |body: &str| json!({ "type": "m.room.message", "sender": "@b:matrixon.local", "content": { "body": body } });
+        let later = ids(service.insert_historical(room, &anchor, vec![message("3")]).unwrap());
+        // Importing right after the imported event leaves no gap to its successor
+        for body in ["5", "4"] {
+            service.insert_historical(room, &later[0], vec![message(body)]).unwrap();
+        }
+        service.insert_historical(room, &anchor, vec![message("1"), message("2")]).unwrap();
+
+        let rooms = service.rooms.read().unwrap();
+        let tokens: Vec<Token> = rooms[room].iter().map(Entry::token).collect();
+        assert!(tokens.windows(2).all(|pair| pair[0] < pair[1]));
+        let bodies: Vec<_> = rooms[room].iter().map(|entry| entry.event["content"]["body"].as_str().unwrap()).collect();
+        assert_eq!(bodies, ["anchor", "1", "2", "3", "4", "5"]);
+    }
+
+    #[tokio::test]
+    async fn test_imported_events_are_restored_in_order() {
+        let persistence = Arc::new(event_persistence::Service::new(100, std::time::Duration::from_secs(60), 1000));
+        let stored = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let written = stored.clone();
+        persistence.spawn_writer_with(move |batch: Vec<matrixon_db::EventRecord>| {
+            let mut written = written.lock().unwrap();
+            for record in batch {
+                written.insert(record.event_id.clone(), record);
+            }
+            async { Ok(()) }
+        });
+        let service = Service::new().with_persistence(persistence.clone());
+        let room = "!room:matrixon.local";
+        let anchor = service.append_event(room, "@a:matrixon.local", "m.room.message", None, json!({ "body": "anchor" }));
+        let message = |body: &str| json!({ "type": "m.room.message", "sender": "@b:matrixon.local", "content": { "body": body } });
+        let later = ids(service.insert_historical(room, &anchor, vec![message("3")]).unwrap());
+        // Spreads the ordinals out, moving the events imported before
+        for body in ["5", "4"] {
+            service.insert_historical(room, &later[0], vec![message(body)]).unwrap();
+        }
+        service.insert_historical(room, &anchor, vec![message("1"), message("2")]).unwrap();
+        service.append_event(room, "@a:matrixon.local", "m.room.message", None, json!({ "body": "live" }));
+        persistence.flush().await;
+
+        // Loaded like the database returns them
+        let mut records: Vec<matrixon_db::EventRecord> = stored.lock().unwrap().values().cloned().collect();
+        records.sort_by(|a, b| (a.stream_ordering, a.ordinal, &a.event_id).cmp(&(b.stream_ordering, b.ordinal, &b.event_id)));
+        let restored = Service::new();
+        restored.restore(records.into_iter().map(|record| (record.room_id, record.stream_ordering as u64, record.ordinal as u64, record.json)));
+        let (chunk, _) = restored.paginate(room, Some(0), Direction::Forward, 10);
+        let bodies: Vec<_> = chunk.iter().map(|event| event["content"]["body"].as_str().unwrap()).collect();
+        assert_eq!(bodies, ["anchor", "1", "2", "3", "4", "5", "live"]);
+    }
+
+    #[test]
+    fn test_state_between_covers_the_gap() {
+        let service = Service::new();
+        let room = "!room:matrixon.local";
+        service.append_event(room, "@a:matrixon.local", "m.room.name", Some(""), json!({ "name": "old" }));
+        let since = service.current_count();
+        service.append_event(room, "@a:matrixon.local", "m.room.name", Some(""), json!({ "name": "gap" }));
+        service.append_event(room, "@a:matrixon.local", "m.room.topic", Some(""), json!({ "topic": "gap" }));
+        service.append_event(room, "@a:matrixon.local", "m.room.message", None, json!({ "body": "gap" }));
+        service.append_event(room, "@a:matrixon.local", "m.room.name", Some(""), json!({ "name": "timeline" }));
+        service.append_event(room, "@a:matrixon.local", "m.room.message", None, json!({ "body": "timeline" }));
+
+        let (_, limited, prev) = service.events_since(room, since, 2);
+        assert!(limited);
+        let state = service.state_between(room, since, prev);
+        assert_eq!(state.len(), 2);
+        assert_eq!(state[0]["content"]["name"], "gap");
+        assert_eq!(state[1]["content"]["topic"], "gap");
+
+        // Nothing was missed when the timeline covers every new event
+        let (_, limited, prev) = service.events_since(room, since, 10);
+        assert!(!limited);
+        assert!(service.state_between(room, since, prev).is_empty());
+    }
+
+    #[test]
+    fn test_cached_lookups_follow_new_state() {
+        let service = Service::new().with_cache_capacity_modifier(0.0001);
+        let room = "!room:matrixon.local";
+        assert!(service.state_event(room, "m.room.name", "").is_none());
+        service.append_event(room, "@a:matrixon.local", "m.room.create", Some(""), json!({}));
+        assert!(service.state_event(room, "m.room.name", "").is_none());
+        let first = service.append_event(room, "@a:matrixon.local", "m.room.name", Some(""), json!({ "name": "first" }));
+        assert_eq!(service.state_event(room, "m.room.name", "").unwrap()["content"]["name"], "first");
+        service.append_event(room, "@a:matrixon.local", "m.room.name", Some(""), json!({ "name": "second" }));
+        // Appending looks up auth events too, so count from here
+        let (hits, misses) = (service.caches.state.hits(), service.caches.state.misses());
+        assert_eq!(service.state_event(room, "m.room.name", "").unwrap()["content"]["name"], "second");
+        assert_eq!(service.state_event(room, "m.room.name", "").unwrap()["content"]["name"], "second");
+
+        assert_eq!(service.get_event(room, &first).unwrap()["content"]["name"], "first");
+        assert_eq!(service.position(room, &first), Some(1));
+        assert_eq!((service.caches.pdus.hits(), service.caches.pdus.misses()), (1, 1));
+        assert_eq!((service.caches.state.hits() - hits, service.caches.state.misses() - misses), (1, 1));
+
+        // Rooms share short state keys, and state survives losing them
+        let other = "!other:matrixon.local";
+        service.append_event(other, "@a:matrixon.local", "m.room.name", Some(""), json!({ "name": "other" }));
+        assert_eq!(service.caches.state_key(room, "m.room.name", "").1, service.caches.state_key(other, "m.room.name", "").1);
+        service.caches.shortstatekeys.clear();
+        service.append_event(room, "@a:matrixon.local", "m.room.name", Some(""), json!({ "name": "third" }));
+        assert_eq!(service.state_event(room, "m.room.name", "").unwrap()["content"]["name"], "third");
+        assert_eq!(service.state_event(other, "m.room.name", "").unwrap()["content"]["name"], "other");
+    }
+
+    #[test]
+    fn test_restored_timelines_continue_the_stream() {
+        let stored = Service::new();
+        let room = "!room:matrixon.local";
+        stored.append_event(room, "@a:matrixon.local", "m.room.create", Some(""), json!({}));
+        stored.append_event(room, "@a:matrixon.local", "m.room.name", Some(""), json!({ "name": "kept" }));
+        let events: Vec<(String, u64, u64, Value)> =
+            stored.stream_since(0, 10).into_iter().map(|(count, event)| (room.to_owned(), count, 0, event)).collect();
+
+        let service = Service::new();
+        service.restore(events);
+        assert_eq!(service.current_count(), 2);
+        assert_eq!(service.state_event(room, "m.room.name", "").unwrap()["content"]["name"], "kept");
+        service.append_event(room, "@a:matrixon.local", "m.room.topic", Some(""), json!({ "topic": "new" }));
+        assert_eq!(service.stream_since(2, 10).len(), 1);
+    }
+
+    #[test]
+    fn test_serialized_events_follow_redactions() {
+        let service = Service::new();
+        service.append_event("!room:matrixon.local", "@a:matrixon.local", "m.room.message", None, json!({ "body": "one" }));
+
+        let (events, _, _, _) = service.serialized_events_between("!room:matrixon.local", 0, u64::MAX, 10);
+        let event: Value = serde_json::from_str(events[0].get()).unwrap();
+        assert_eq!(event, service.events_since("!room:matrixon.local", 0, 10).0[0]);
+        assert_eq!(serde_json::to_string(&events).unwrap(), format!("[{}]", events[0].get()));
+
+        service.append_event("!held:matrixon.local", "@a:matrixon.local", "m.room.message", None, json!({ "body": "two" }));
+        assert_eq!(service.redact_events_from("@a:matrixon.local", |room_id| room_id != "!held:matrixon.local"), 1);
+        let (_, events, _) = service.paginate_serialized("!room:matrixon.local", None, Direction::Backward, 1);
+        assert!(!events[0].get().contains("one"));
+        let (_, events, _) = service.paginate_serialized("!held:matrixon.local", None, Direction::Backward, 1);
+        assert!(events[0].get().contains("two"));
+    }
+
+    #[test]
+    fn test_redaction_keeps_membership() {
+        let mut event = json!({
+            "type": "m.room.member",
+            "sender": "@a:matrixon.local",
+            "unsigned": { "age": 1 },
+            "content": { "membership": "join", "displayname": "Alice", "avatar_url": "mxc://x/y" }
+        });
+        redact_event(&mut event);
+        assert_eq!(event["content"], json!({ "membership": "join" }));
+        assert!(event.get("unsigned").is_none());
+    }
+
+    #[test]
+    fn test_local_events_are_hashed_and_signed() {
+        let keys = Arc::new(server_keys::Service::load("matrixon.local", None).unwrap());
+        let service = Service::new().with_signer(keys.clone());
+        let room = "!room:matrixon.local";
+        let create = service.append_event(room, "@a:matrixon.local", "m.room.create", Some(""), json!({ "room_version": "10" }));
+        let join = service.append_event(room, "@a:matrixon.local", "m.room.member", Some("@a:matrixon.local"), json!({ "membership": "join" }));
+
+        let event = service.get_event(room, &join).unwrap();
+        assert_eq!(event["prev_events"], json!([create]));
+        assert_eq!(event["auth_events"], json!([create]));
+        assert_eq!(event["depth"], 2);
+
+        // As sent over federation the event id is its reference hash
+        let mut pdu = event.clone();
+        pdu.as_object_mut().unwrap().remove("event_id");
+        assert_eq!(inbound_federation::reference_event_id(&pdu, &RoomVersionId::V10).unwrap(), join);
+        let public_key = ruma::serde::Base64::parse(keys.public_key()).unwrap();
+        let public_key_map = [("matrixon.local".to_owned(), [(keys.key_id(), public_key)].into())].into();
+        let CanonicalJsonValue::Object(object) = CanonicalJsonValue::try_from(pdu).unwrap() else {
+            unreachable!()
+        };
+        assert!(ruma::signatures::verify_event(&public_key_map, &object, &RoomVersionId::V10).is_ok());
+
+        // Without a key events are still hashed, and rooms before version 3
+        // keep their own event ids and reference events with their hashes
+        let service = Service::new();
+        let create = service.append_event(room, "@a:matrixon.local", "m.room.create", Some(""), json!({ "room_version": "1" }));
+        let join = service.append_event(room, "@a:matrixon.local", "m.room.member", Some("@a:matrixon.local"), json!({ "membership": "join" }));
+        let event = service.get_event(room, &join).unwrap();
+        assert!(join.ends_with(":matrixon.local") && event["hashes"]["sha256"].is_string());
+        assert!(event.get("signatures").is_none());
+        assert_eq!(event["prev_events"][0][0], create);
+    }
+}